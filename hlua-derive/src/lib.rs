@@ -0,0 +1,125 @@
+//! Derive macros for the `hlua` crate.
+//!
+//! You shouldn't depend on this crate directly. Instead enable the `derive` feature of `hlua`,
+//! which re-exports the macros.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Error, ExprPath, Field, Fields, LitStr,
+};
+
+mod push;
+mod read;
+mod read_multi;
+
+/// Implements `Push` for a struct by pushing a table that contains its fields.
+///
+/// Named fields are stored with their name as key, and the fields of tuple structs are stored
+/// with the keys `1..=n`. The fields must be pushable without error. Two attributes change how
+/// a field is pushed:
+///
+/// - `#[lua(rename = "name")]` uses another key for a named field.
+/// - `#[lua(skip)]` leaves the field out of the table.
+#[proc_macro_derive(LuaPush, attributes(lua))]
+pub fn derive_lua_push(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    push::expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Implements `LuaReadStruct` and `LuaRead` for a struct by reading its fields from a table.
+///
+/// The keys of the fields are the same as with `#[derive(LuaPush)]`, so a value pushed with
+/// `LuaPush` can be read back. Fields of type `Option<T>` are set to `None` if they are nil, and
+/// the fields marked with `#[lua(skip)]` are set to their `Default` value. Reading the value with
+/// `Lua::read_struct` returns an error that tells which field is missing or has the wrong type.
+///
+/// Named fields marked with `#[lua(default)]` are optional: they are set to their `Default`
+/// value if they are nil, or to the value returned by a function with
+/// `#[lua(default = "path::to::function")]`. Together with `NamedArgs`, this gives callbacks
+/// that take named arguments.
+#[proc_macro_derive(LuaRead, attributes(lua))]
+pub fn derive_lua_read(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    read::expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Implements `LuaRead` for a struct by reading its fields from consecutive positions of the
+/// stack, in declaration order.
+///
+/// This is meant to be used with the multiple values returned by a Lua chunk or function; see
+/// `Lua::execute_multi`. Fields of type `Option<T>` are set to `None` if the corresponding value
+/// is missing.
+#[proc_macro_derive(LuaReadMulti)]
+pub fn derive_lua_read_multi(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    read_multi::expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Options of a field, set with `#[lua(...)]`.
+#[derive(Default)]
+struct FieldOptions {
+    rename: Option<LitStr>,
+    skip: bool,
+    // `Some(None)` for `default`, `Some(Some(path))` for `default = "path"`.
+    default: Option<Option<ExprPath>>,
+}
+
+/// Parses the `#[lua(...)]` attributes of a field.
+fn field_options(field: &Field) -> syn::Result<FieldOptions> {
+    let mut options = FieldOptions::default();
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("lua")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                options.rename = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("skip") {
+                options.skip = true;
+                Ok(())
+            } else if meta.path.is_ident("default") {
+                options.default = match meta.input.peek(syn::Token![=]) {
+                    true => Some(Some(meta.value()?.parse::<LitStr>()?.parse()?)),
+                    false => Some(None),
+                };
+                Ok(())
+            } else {
+                Err(meta.error("unknown hlua attribute, expected `rename`, `skip` or `default`"))
+            }
+        })?;
+    }
+    Ok(options)
+}
+
+/// Returns the fields of a struct, or an error for other kinds of items.
+fn struct_fields<'a>(input: &'a DeriveInput, derive: &str) -> syn::Result<&'a Fields> {
+    match &input.data {
+        Data::Struct(data) => Ok(&data.fields),
+        _ => Err(Error::new_spanned(
+            &input.ident,
+            format!("#[derive({})] only supports structs", derive),
+        )),
+    }
+}
+
+/// Builds the expression that constructs `Self` from variables named `field0`, `field1`, etc.
+fn construct_self(fields: &Fields) -> TokenStream2 {
+    let vars = (0..fields.len()).map(|n| format_ident!("field{}", n));
+
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|f| f.ident.as_ref().unwrap());
+            quote! { Self { #(#names: #vars),* } }
+        },
+        Fields::Unnamed(_) => quote! { Self(#(#vars),*) },
+        Fields::Unit => quote! { Self },
+    }
+}
+
+/// Adds the `'lua` lifetime and the `L` type parameter used by the implementations.
+fn impl_generics(input: &DeriveInput) -> syn::Generics {
+    let mut generics = input.generics.clone();
+    generics.params.insert(0, parse_quote!('__lua));
+    generics.params.push(parse_quote!(__L));
+    generics
+}
@@ -0,0 +1,71 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_quote, DeriveInput, Error, Index};
+
+use crate::{field_options, impl_generics, struct_fields};
+
+pub fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let fields = struct_fields(&input, "LuaPush")?;
+    let name = &input.ident;
+
+    let mut pushed = Vec::new();
+    for (n, field) in fields.iter().enumerate() {
+        let options = field_options(field)?;
+        if options.skip {
+            continue;
+        }
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = Index::from(n);
+                quote!(#index)
+            },
+        };
+        let set = match (&field.ident, options.rename) {
+            (Some(ident), rename) => {
+                let key = rename.map_or_else(|| ident.to_string(), |key| key.value());
+                quote!(::hlua::set_struct_field(&mut table, #key, self.#member))
+            },
+            (None, None) => {
+                let index = pushed.len() as i32 + 1;
+                quote!(::hlua::set_struct_element(&mut table, #index, self.#member))
+            },
+            (None, Some(rename)) => {
+                return Err(Error::new_spanned(rename, "only named fields can be renamed"));
+            },
+        };
+        pushed.push((field.ty.clone(), set));
+    }
+
+    let mut generics = impl_generics(&input);
+    {
+        let where_clause = generics.make_where_clause();
+        where_clause.predicates.push(parse_quote!(__L: ::hlua::AsMutLua<'__lua>));
+        for (ty, _) in &pushed {
+            where_clause.predicates.push(parse_quote!(
+                #ty: for<'__a> ::hlua::PushOne<&'__a mut ::hlua::PushGuard<__L>, Err = ::hlua::Void>
+            ));
+        }
+    }
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+
+    let count = pushed.len();
+    let sets = pushed.iter().map(|(_, set)| set);
+
+    Ok(quote! {
+        impl #impl_generics ::hlua::Push<__L> for #name #ty_generics #where_clause {
+            type Err = ::hlua::Void;
+
+            #[inline]
+            #[allow(unused_mut)]
+            fn push_to_lua(self, lua: __L) -> Result<::hlua::PushGuard<__L>, (::hlua::Void, __L)> {
+                let mut table = ::hlua::push_struct_table(lua, #count);
+                #(#sets;)*
+                Ok(table)
+            }
+        }
+
+        impl #impl_generics ::hlua::PushOne<__L> for #name #ty_generics #where_clause {}
+    })
+}
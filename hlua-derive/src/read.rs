@@ -0,0 +1,91 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_quote, DeriveInput, Error};
+
+use crate::{construct_self, field_options, impl_generics, struct_fields};
+
+pub fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let fields = struct_fields(&input, "LuaRead")?;
+    let name = &input.ident;
+
+    let mut reads = Vec::new();
+    let mut read_types = Vec::new();
+    for (n, field) in fields.iter().enumerate() {
+        let options = field_options(field)?;
+        let var = format_ident!("field{}", n);
+        if options.skip {
+            reads.push(quote!(let #var = ::std::default::Default::default();));
+            continue;
+        }
+        let read = match (&field.ident, options.rename) {
+            (Some(ident), rename) => {
+                let key = rename.map_or_else(|| ident.to_string(), |key| key.value());
+                match options.default {
+                    Some(Some(function)) => quote!(reader.field_or_else(#key, #function)?),
+                    Some(None) => {
+                        quote!(reader.field_or_else(#key, ::std::default::Default::default)?)
+                    },
+                    None => quote!(reader.field(#key)?),
+                }
+            },
+            (None, _) if options.default.is_some() => {
+                return Err(Error::new_spanned(&field.ty, "only named fields can have a default"));
+            },
+            (None, None) => {
+                let index = read_types.len() as i32 + 1;
+                quote!(reader.element(#index)?)
+            },
+            (None, Some(rename)) => {
+                return Err(Error::new_spanned(rename, "only named fields can be renamed"));
+            },
+        };
+        reads.push(quote!(let #var = #read;));
+        read_types.push(field.ty.clone());
+    }
+
+    // The fields are read with `StructReader`, and `LuaRead` is implemented on top of that.
+    let mut struct_generics = input.generics.clone();
+    {
+        let where_clause = struct_generics.make_where_clause();
+        for ty in &read_types {
+            where_clause.predicates.push(parse_quote!(
+                #ty: ::hlua::LuaRead<::hlua::FieldGuard>
+            ));
+        }
+    }
+    let (struct_impl_generics, ty_generics, struct_where_clause) = struct_generics.split_for_impl();
+
+    let mut generics = impl_generics(&input);
+    {
+        let where_clause = generics.make_where_clause();
+        where_clause.predicates.push(parse_quote!(__L: ::hlua::AsLua<'__lua>));
+        where_clause.predicates.push(parse_quote!(Self: ::hlua::LuaReadStruct));
+    }
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+    let construct = construct_self(fields);
+
+    Ok(quote! {
+        impl #struct_impl_generics ::hlua::LuaReadStruct for #name #ty_generics
+        #struct_where_clause
+        {
+            #[allow(unused_variables)]
+            fn read_struct(
+                reader: &mut ::hlua::StructReader,
+            ) -> Result<Self, ::hlua::StructReadError> {
+                #(#reads)*
+                Ok(#construct)
+            }
+        }
+
+        impl #impl_generics ::hlua::LuaRead<__L> for #name #ty_generics #where_clause {
+            #[inline]
+            fn lua_read_at_position(lua: __L, index: i32) -> Result<Self, __L> {
+                match ::hlua::read_struct_at(&lua, index) {
+                    Ok(value) => Ok(value),
+                    Err(_) => Err(lua),
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,71 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_quote, DeriveInput};
+
+use crate::{construct_self, impl_generics, struct_fields};
+
+pub fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let fields = struct_fields(&input, "LuaReadMulti")?;
+    let name = &input.ident;
+
+    let mut generics = impl_generics(&input);
+    {
+        let where_clause = generics.make_where_clause();
+        where_clause.predicates.push(parse_quote!(__L: ::hlua::AsLua<'__lua>));
+        for field in fields {
+            let ty = &field.ty;
+            where_clause
+                .predicates
+                .push(parse_quote!(#ty: for<'__a> ::hlua::LuaRead<&'__a mut __L>));
+        }
+    }
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+
+    // Same logic as the implementation for tuples.
+    let reads = (0..fields.len()).map(|n| {
+        let var = format_ident!("field{}", n);
+        quote! {
+            // Prevent wrapping around if we're reading too far into the stack (-2, -1, 0, 1, ...)
+            let read = if negative == i.is_negative() {
+                ::hlua::LuaRead::lua_read_at_position(&mut lua, i)
+            } else {
+                ::hlua::LuaRead::lua_read_out_of_bounds(&mut lua)
+            };
+            let #var = match read {
+                Ok(v) => v,
+                Err(_) => return Err(lua),
+            };
+            i += 1;
+        }
+    });
+    let missing = (0..fields.len()).map(|n| {
+        let var = format_ident!("field{}", n);
+        quote! {
+            let #var = match ::hlua::LuaRead::lua_read_out_of_bounds(&mut lua) {
+                Ok(v) => v,
+                Err(_) => return Err(lua),
+            };
+        }
+    });
+    let construct = construct_self(fields);
+
+    Ok(quote! {
+        #[allow(unused_assignments, unused_mut, unused_variables)]
+        impl #impl_generics ::hlua::LuaRead<__L> for #name #ty_generics #where_clause {
+            #[inline]
+            fn lua_read_at_position(mut lua: __L, index: i32) -> Result<Self, __L> {
+                let negative = index.is_negative();
+                let mut i = index;
+                #(#reads)*
+                Ok(#construct)
+            }
+
+            #[inline]
+            fn lua_read_out_of_bounds(mut lua: __L) -> Result<Self, __L> {
+                #(#missing)*
+                Ok(#construct)
+            }
+        }
+    })
+}
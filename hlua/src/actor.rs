@@ -0,0 +1,322 @@
+use std::{
+    error::Error,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Weak,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{Lua, LuaError, LuaFunction, LuaFunctionCallError, LuaRead, Push, PushGuard, Void};
+
+// Operation sent to the thread of an actor.
+pub(crate) type Job = Box<dyn FnOnce(&mut Lua<'static>) + Send>;
+
+/// Message that can be sent to a `LuaActor` with `LuaActor::send`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, LuaActor, LuaMessage};
+///
+/// struct AddScore(i32);
+///
+/// impl LuaMessage for AddScore {
+///     type Reply = i32;
+///
+///     fn handle(self, lua: &mut Lua<'static>) -> i32 {
+///         let score = lua.get::<i32, _>("score").unwrap_or(0) + self.0;
+///         lua.set("score", score);
+///         score
+///     }
+/// }
+///
+/// let actor = LuaActor::spawn(|_| {});
+/// actor.send(AddScore(5));
+/// assert_eq!(actor.send(AddScore(2)).wait().unwrap(), 7);
+/// ```
+pub trait LuaMessage: Send + 'static {
+    /// Value sent back to the sender of the message.
+    type Reply: Send + 'static;
+
+    /// Handles the message on the thread of the actor.
+    fn handle(self, lua: &mut Lua<'static>) -> Self::Reply;
+}
+
+/// Lua context running on its own thread, which processes the messages sent to it one at a
+/// time.
+///
+/// `Lua` can't be sent to other threads. An actor makes it possible to use a single context
+/// from a whole threaded application: the context is created on the thread of the actor and
+/// never leaves it, and the other threads send it messages. Each message returns an
+/// `ActorReply`, through which the result is sent back.
+///
+/// If handling a message panics, the sender of the message gets `ActorError::Panicked` and the
+/// actor keeps processing the following messages. Dropping the actor waits for the messages
+/// already sent to be processed, then closes the context.
+///
+/// # Example
+///
+/// ```
+/// let actor = hlua::LuaActor::spawn(|lua| {
+///     lua.openlibs();
+///     lua.execute::<()>("function greet(name) return 'hello ' .. name end").unwrap();
+/// });
+///
+/// let reply = actor.call::<String, _, _>("greet", "bob");
+/// assert_eq!(reply.wait().unwrap().unwrap(), "hello bob");
+/// ```
+pub struct LuaActor {
+    // Only `WeakActor`s share the sender, so that dropping the actor closes the channel.
+    sender: Option<Arc<Sender<Job>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Result of a message sent to a `LuaActor`, which the actor sends back once it has processed
+/// the message.
+pub struct ActorReply<R> {
+    receiver: Receiver<thread::Result<R>>,
+}
+
+/// Error returned by `ActorReply` when the actor didn't produce a result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActorError {
+    /// The actor stopped before processing the message, because its initialization panicked.
+    Stopped,
+    /// Handling the message panicked.
+    Panicked,
+}
+
+impl fmt::Display for ActorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActorError::Stopped => write!(f, "the Lua actor has stopped"),
+            ActorError::Panicked => write!(f, "the Lua actor panicked while handling the message"),
+        }
+    }
+}
+
+impl Error for ActorError {}
+
+impl fmt::Debug for LuaActor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LuaActor").finish_non_exhaustive()
+    }
+}
+
+impl<R> fmt::Debug for ActorReply<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ActorReply").finish_non_exhaustive()
+    }
+}
+
+impl LuaActor {
+    /// Starts a thread owning a new Lua context. `init` runs on that thread before any message,
+    /// and can be used to open libraries, register functions and load scripts.
+    pub fn spawn<F>(init: F) -> LuaActor
+    where
+        F: FnOnce(&mut Lua<'static>) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let thread = thread::Builder::new()
+            .name("hlua-actor".to_owned())
+            .spawn(move || {
+                let mut lua = Lua::new();
+                init(&mut lua);
+                for job in receiver {
+                    job(&mut lua);
+                }
+            })
+            .expect("failed to spawn the thread of a Lua actor");
+
+        LuaActor { sender: Some(Arc::new(sender)), thread: Some(thread) }
+    }
+
+    /// Sends a message to the actor.
+    pub fn send<M>(&self, message: M) -> ActorReply<M::Reply>
+    where
+        M: LuaMessage,
+    {
+        self.run(move |lua| message.handle(lua))
+    }
+
+    /// Runs a closure on the thread of the actor, with access to its context.
+    pub fn run<F, R>(&self, f: F) -> ActorReply<R>
+    where
+        F: FnOnce(&mut Lua<'static>) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (reply_sender, receiver) = mpsc::sync_channel(1);
+        let job: Job = Box::new(move |lua| {
+            let reply = panic::catch_unwind(AssertUnwindSafe(|| f(lua)));
+            let _ = reply_sender.send(reply);
+        });
+
+        // If the actor has stopped, the job is dropped and the reply reports it.
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(job);
+        }
+        ActorReply { receiver }
+    }
+
+    /// Returns a reference to the actor that doesn't keep it running.
+    pub(crate) fn downgrade(&self) -> WeakActor {
+        WeakActor(self.sender.as_ref().map_or_else(Weak::new, Arc::downgrade))
+    }
+
+    /// Executes some Lua code in the context of the actor.
+    pub fn execute<T>(&self, code: impl Into<String>) -> ActorReply<Result<T, LuaError>>
+    where
+        T: for<'a, 'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'static>>>>,
+        T: Send + 'static,
+    {
+        let code = code.into();
+        self.run(move |lua| lua.execute::<T>(&code))
+    }
+
+    /// Calls the global function `name` of the context of the actor.
+    ///
+    /// Multiple arguments can be passed as a tuple, and multiple results read as a tuple.
+    pub fn call<R, A, E>(&self, name: &str, args: A) -> ActorReply<Result<R, LuaError>>
+    where
+        A: for<'a, 'r> Push<&'r mut LuaFunction<PushGuard<&'a mut Lua<'static>>>, Err = E>,
+        A: Send + 'static,
+        E: Into<Void>,
+        R: for<'a, 'f> LuaRead<PushGuard<&'f mut PushGuard<&'a mut Lua<'static>>>>,
+        R: Send + 'static,
+    {
+        let name = name.to_owned();
+        self.run(move |lua| {
+            let mut function: LuaFunction<_> = match lua.get(&name[..]) {
+                Some(function) => function,
+                None => {
+                    let msg = format!("global '{}' is not a function", name);
+                    return Err(LuaError::ExecutionError(msg.into()));
+                },
+            };
+
+            match function.call_multi_with_args(args) {
+                Ok(results) => Ok(results),
+                Err(LuaFunctionCallError::LuaError(err)) => Err(err),
+                Err(LuaFunctionCallError::PushError(err)) => match err.into() {},
+            }
+        })
+    }
+}
+
+impl Drop for LuaActor {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread once it has processed the pending jobs.
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Reference to a `LuaActor` that can send it operations for as long as it is alive.
+#[derive(Clone)]
+pub(crate) struct WeakActor(Weak<Sender<Job>>);
+
+impl WeakActor {
+    /// Returns true if the actor hasn't been dropped.
+    #[inline]
+    pub(crate) fn is_alive(&self) -> bool {
+        self.0.strong_count() != 0
+    }
+
+    /// Runs `f` on the thread of the actor, and ignores its panics like `LuaActor::run`.
+    /// Returns false if the actor has been dropped.
+    pub(crate) fn run<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&mut Lua<'static>) + Send + 'static,
+    {
+        let job: Job = Box::new(move |lua| {
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| f(lua)));
+        });
+        self.0.upgrade().is_some_and(|sender| sender.send(job).is_ok())
+    }
+}
+
+impl<R> ActorReply<R> {
+    /// Blocks until the actor has processed the message, and returns the result.
+    pub fn wait(self) -> Result<R, ActorError> {
+        match self.receiver.recv() {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(ActorError::Panicked),
+            Err(_) => Err(ActorError::Stopped),
+        }
+    }
+
+    /// Returns the result if the actor has already processed the message, or gives the reply
+    /// back otherwise.
+    pub fn try_wait(self) -> Result<Result<R, ActorError>, ActorReply<R>> {
+        match self.receiver.try_recv() {
+            Ok(Ok(reply)) => Ok(Ok(reply)),
+            Ok(Err(_)) => Ok(Err(ActorError::Panicked)),
+            Err(TryRecvError::Disconnected) => Ok(Err(ActorError::Stopped)),
+            Err(TryRecvError::Empty) => Err(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use crate::{ActorError, LuaActor, LuaError};
+
+    fn actor() -> LuaActor {
+        LuaActor::spawn(|lua| {
+            lua.openlibs();
+            lua.execute::<()>(
+                "total = 0
+                 function add(a, b) total = total + a + b return total end
+                 function divmod(a, b) return math.floor(a / b), a % b end",
+            )
+            .unwrap();
+        })
+    }
+
+    #[test]
+    fn calls_from_several_threads() {
+        let actor = Arc::new(actor());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let actor = actor.clone();
+                thread::spawn(move || actor.call::<i32, _, _>("add", (1, 2)).wait().unwrap())
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+
+        let total: i32 = actor.execute("return total").wait().unwrap().unwrap();
+        assert_eq!(total, 12);
+
+        let (quotient, remainder): (i32, i32) =
+            actor.call("divmod", (7, 2)).wait().unwrap().unwrap();
+        assert_eq!((quotient, remainder), (3, 1));
+    }
+
+    #[test]
+    fn errors() {
+        let actor = actor();
+        match actor.call::<(), _, _>("missing", ()).wait().unwrap() {
+            Err(LuaError::ExecutionError(msg)) => {
+                assert!(msg.message().contains("'missing'"), "{}", msg)
+            },
+            other => panic!("{:?}", other),
+        }
+
+        let reply = actor.run(|_| -> i32 { panic!("handler failure") });
+        assert_eq!(reply.wait().unwrap_err(), ActorError::Panicked);
+        // The actor survives the panic.
+        assert_eq!(actor.run(|_| 5).wait(), Ok(5));
+
+        let stopped = LuaActor::spawn(|_| panic!("init failure"));
+        assert_eq!(stopped.run(|_| ()).wait().unwrap_err(), ActorError::Stopped);
+    }
+}
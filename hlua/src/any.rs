@@ -1,6 +1,13 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt,
+    hash::BuildHasher,
+};
+
 use crate::AsMutLua;
 
-use crate::{LuaNil, LuaRead, LuaTable, Push, PushGuard, PushOne, Void};
+use crate::{values, LuaNil, LuaRead, LuaTable, Push, PushGuard, PushOne, Void};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct AnyLuaString(pub Vec<u8>);
@@ -92,7 +99,7 @@ where
             ffi::LUA_TNUMBER => Err(raw_lua)
                 .or_else(|lua| LuaRead::lua_read_at_position(lua, index).map(Value::LuaNumber)),
             ffi::LUA_TSTRING => Err(raw_lua)
-                .or_else(|lua| LuaRead::lua_read_at_position(lua, index).map(Value::LuaString))
+                .or_else(|lua| values::read_utf8_string(lua, index).map(Value::LuaString))
                 .or_else(|lua| LuaRead::lua_read_at_position(lua, index).map(Value::LuaAnyString)),
             ffi::LUA_TTABLE => LuaTable::lua_read_at_position(raw_lua, index)
                 .map(|mut v| v.iter::<Value, Value>().flatten().collect())
@@ -161,7 +168,7 @@ where
                 .or_else(|lua| LuaRead::lua_read_at_position(lua, index).map(Value::LuaInteger))
                 .or_else(|lua| LuaRead::lua_read_at_position(lua, index).map(Value::LuaString)),
             ffi::LUA_TSTRING => Err(raw_lua)
-                .or_else(|lua| LuaRead::lua_read_at_position(lua, index).map(Value::LuaString))
+                .or_else(|lua| values::read_utf8_string(lua, index).map(Value::LuaString))
                 .or_else(|lua| LuaRead::lua_read_at_position(lua, index).map(Value::LuaAnyString)),
             ffi::LUA_TTABLE => LuaTable::lua_read_at_position(raw_lua, index)
                 .map(|mut v| v.iter::<Value, Value>().flatten().collect())
@@ -173,6 +180,724 @@ where
     }
 }
 
+/// Error returned when an `AnyLuaValue` can't be converted into another representation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnyLuaValueConversionError {
+    /// Description of what the target representation expected.
+    pub expected: &'static str,
+    /// Lua type of the value that was found instead.
+    pub found: &'static str,
+}
+
+impl fmt::Display for AnyLuaValueConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {}, found {}", self.expected, self.found)
+    }
+}
+
+impl Error for AnyLuaValueConversionError {}
+
+impl AnyLuaValue {
+    /// Returns the name of the Lua type of this value, as `type()` would in Lua.
+    #[inline]
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            AnyLuaValue::LuaString(_) | AnyLuaValue::LuaAnyString(_) => "string",
+            AnyLuaValue::LuaNumber(_) | AnyLuaValue::LuaInteger(_) => "number",
+            AnyLuaValue::LuaBoolean(_) => "boolean",
+            AnyLuaValue::LuaArray(_) => "table",
+            AnyLuaValue::LuaNil => "nil",
+            AnyLuaValue::LuaOther => "other",
+        }
+    }
+
+    /// Returns the value as an integer if it is a number without a fractional part.
+    pub(crate) fn as_integral(&self) -> Option<i64> {
+        match *self {
+            AnyLuaValue::LuaInteger(v) => Some(i64::from(v)),
+            AnyLuaValue::LuaNumber(v) if v.fract() == 0.0 && v.abs() < 9.0e15 => Some(v as i64),
+            _ => None,
+        }
+    }
+}
+
+/// If the content of a table only consists of the keys `1..=n`, returns its values in order.
+///
+/// Empty tables are not considered to be sequences.
+fn table_as_sequence(
+    content: Vec<(AnyLuaValue, AnyLuaValue)>,
+) -> Result<Vec<AnyLuaValue>, Vec<(AnyLuaValue, AnyLuaValue)>> {
+    let mut keys = Vec::with_capacity(content.len());
+    for (key, _) in &content {
+        match key.as_integral() {
+            Some(k) if k >= 1 && k as usize <= content.len() => keys.push(k as usize - 1),
+            _ => return Err(content),
+        }
+    }
+
+    let mut slots: Vec<Option<AnyLuaValue>> = vec![None; content.len()];
+    for (slot, (_, value)) in keys.into_iter().zip(content) {
+        slots[slot] = Some(value);
+    }
+
+    // Keys are unique, so every slot has been filled exactly once.
+    match slots.is_empty() {
+        true => Err(Vec::new()),
+        false => Ok(slots.into_iter().flatten().collect()),
+    }
+}
+
+impl AnyLuaValueConversionError {
+    #[inline]
+    fn new(expected: &'static str, found: &AnyLuaValue) -> AnyLuaValueConversionError {
+        AnyLuaValueConversionError { expected, found: found.type_name() }
+    }
+}
+
+// Conversions between `AnyLuaValue` and standard types.
+//
+// Integers are converted from numbers without a fractional part that fit in the target type.
+// Vectors are converted from tables whose keys are exactly `1..=n`, or from empty tables, and
+// maps from tables whose keys are all strings.
+
+macro_rules! impl_integer_conversions {
+    ($($ty:ty),*) => {$(
+        impl TryFrom<AnyLuaValue> for $ty {
+            type Error = AnyLuaValueConversionError;
+
+            #[inline]
+            fn try_from(value: AnyLuaValue) -> Result<$ty, AnyLuaValueConversionError> {
+                value
+                    .as_integral()
+                    .and_then(|v| <$ty>::try_from(v).ok())
+                    .ok_or_else(|| AnyLuaValueConversionError::new(
+                        concat!("an integer that fits in ", stringify!($ty)),
+                        &value,
+                    ))
+            }
+        }
+    )*};
+}
+
+impl_integer_conversions!(i8, i16, i32, i64, u8, u16, u32, u64);
+
+macro_rules! impl_from_small_integer {
+    ($($ty:ty),*) => {$(
+        impl From<$ty> for AnyLuaValue {
+            #[inline]
+            fn from(value: $ty) -> AnyLuaValue {
+                AnyLuaValue::LuaInteger(i32::from(value))
+            }
+        }
+    )*};
+}
+
+// `i64` and `u64` are intentionally missing, as Lua numbers can't represent all their values.
+impl_from_small_integer!(i8, i16, i32, u8, u16);
+
+impl From<u32> for AnyLuaValue {
+    #[inline]
+    fn from(value: u32) -> AnyLuaValue {
+        match i32::try_from(value) {
+            Ok(value) => AnyLuaValue::LuaInteger(value),
+            Err(_) => AnyLuaValue::LuaNumber(f64::from(value)),
+        }
+    }
+}
+
+impl From<f32> for AnyLuaValue {
+    #[inline]
+    fn from(value: f32) -> AnyLuaValue {
+        AnyLuaValue::LuaNumber(f64::from(value))
+    }
+}
+
+impl From<f64> for AnyLuaValue {
+    #[inline]
+    fn from(value: f64) -> AnyLuaValue {
+        AnyLuaValue::LuaNumber(value)
+    }
+}
+
+impl TryFrom<AnyLuaValue> for f64 {
+    type Error = AnyLuaValueConversionError;
+
+    #[inline]
+    fn try_from(value: AnyLuaValue) -> Result<f64, AnyLuaValueConversionError> {
+        match value {
+            AnyLuaValue::LuaNumber(v) => Ok(v),
+            AnyLuaValue::LuaInteger(v) => Ok(f64::from(v)),
+            v => Err(AnyLuaValueConversionError::new("a number", &v)),
+        }
+    }
+}
+
+impl TryFrom<AnyLuaValue> for f32 {
+    type Error = AnyLuaValueConversionError;
+
+    #[inline]
+    fn try_from(value: AnyLuaValue) -> Result<f32, AnyLuaValueConversionError> {
+        f64::try_from(value).map(|v| v as f32)
+    }
+}
+
+impl From<bool> for AnyLuaValue {
+    #[inline]
+    fn from(value: bool) -> AnyLuaValue {
+        AnyLuaValue::LuaBoolean(value)
+    }
+}
+
+impl TryFrom<AnyLuaValue> for bool {
+    type Error = AnyLuaValueConversionError;
+
+    #[inline]
+    fn try_from(value: AnyLuaValue) -> Result<bool, AnyLuaValueConversionError> {
+        match value {
+            AnyLuaValue::LuaBoolean(v) => Ok(v),
+            v => Err(AnyLuaValueConversionError::new("a boolean", &v)),
+        }
+    }
+}
+
+impl From<String> for AnyLuaValue {
+    #[inline]
+    fn from(value: String) -> AnyLuaValue {
+        AnyLuaValue::LuaString(value)
+    }
+}
+
+impl From<&str> for AnyLuaValue {
+    #[inline]
+    fn from(value: &str) -> AnyLuaValue {
+        AnyLuaValue::LuaString(value.to_owned())
+    }
+}
+
+impl From<AnyLuaString> for AnyLuaValue {
+    #[inline]
+    fn from(value: AnyLuaString) -> AnyLuaValue {
+        AnyLuaValue::LuaAnyString(value)
+    }
+}
+
+impl TryFrom<AnyLuaValue> for String {
+    type Error = AnyLuaValueConversionError;
+
+    #[inline]
+    fn try_from(value: AnyLuaValue) -> Result<String, AnyLuaValueConversionError> {
+        match value {
+            AnyLuaValue::LuaString(v) => Ok(v),
+            AnyLuaValue::LuaAnyString(AnyLuaString(v)) => String::from_utf8(v).map_err(|_| {
+                AnyLuaValueConversionError { expected: "a UTF-8 string", found: "string" }
+            }),
+            v => Err(AnyLuaValueConversionError::new("a string", &v)),
+        }
+    }
+}
+
+impl TryFrom<AnyLuaValue> for AnyLuaString {
+    type Error = AnyLuaValueConversionError;
+
+    #[inline]
+    fn try_from(value: AnyLuaValue) -> Result<AnyLuaString, AnyLuaValueConversionError> {
+        match value {
+            AnyLuaValue::LuaString(v) => Ok(AnyLuaString(v.into_bytes())),
+            AnyLuaValue::LuaAnyString(v) => Ok(v),
+            v => Err(AnyLuaValueConversionError::new("a string", &v)),
+        }
+    }
+}
+
+impl<T> From<Option<T>> for AnyLuaValue
+where
+    T: Into<AnyLuaValue>,
+{
+    #[inline]
+    fn from(value: Option<T>) -> AnyLuaValue {
+        value.map(Into::into).unwrap_or(AnyLuaValue::LuaNil)
+    }
+}
+
+impl From<Vec<AnyLuaValue>> for AnyLuaValue {
+    #[inline]
+    fn from(value: Vec<AnyLuaValue>) -> AnyLuaValue {
+        AnyLuaValue::LuaArray(
+            value.into_iter().zip(1..).map(|(v, i)| (AnyLuaValue::LuaInteger(i), v)).collect(),
+        )
+    }
+}
+
+impl TryFrom<AnyLuaValue> for Vec<AnyLuaValue> {
+    type Error = AnyLuaValueConversionError;
+
+    fn try_from(value: AnyLuaValue) -> Result<Vec<AnyLuaValue>, AnyLuaValueConversionError> {
+        match value {
+            AnyLuaValue::LuaArray(content) => match table_as_sequence(content) {
+                Ok(values) => Ok(values),
+                Err(content) if content.is_empty() => Ok(Vec::new()),
+                Err(_) => {
+                    Err(AnyLuaValueConversionError { expected: "a sequence", found: "table" })
+                },
+            },
+            v => Err(AnyLuaValueConversionError::new("a sequence", &v)),
+        }
+    }
+}
+
+impl<S> From<HashMap<String, AnyLuaValue, S>> for AnyLuaValue {
+    #[inline]
+    fn from(value: HashMap<String, AnyLuaValue, S>) -> AnyLuaValue {
+        AnyLuaValue::LuaArray(
+            value.into_iter().map(|(k, v)| (AnyLuaValue::LuaString(k), v)).collect(),
+        )
+    }
+}
+
+impl From<BTreeMap<String, AnyLuaValue>> for AnyLuaValue {
+    #[inline]
+    fn from(value: BTreeMap<String, AnyLuaValue>) -> AnyLuaValue {
+        AnyLuaValue::LuaArray(
+            value.into_iter().map(|(k, v)| (AnyLuaValue::LuaString(k), v)).collect(),
+        )
+    }
+}
+
+impl<S> TryFrom<AnyLuaValue> for HashMap<String, AnyLuaValue, S>
+where
+    S: BuildHasher + Default,
+{
+    type Error = AnyLuaValueConversionError;
+
+    fn try_from(
+        value: AnyLuaValue,
+    ) -> Result<HashMap<String, AnyLuaValue, S>, AnyLuaValueConversionError> {
+        string_keyed_entries(value)?.collect()
+    }
+}
+
+impl TryFrom<AnyLuaValue> for BTreeMap<String, AnyLuaValue> {
+    type Error = AnyLuaValueConversionError;
+
+    fn try_from(
+        value: AnyLuaValue,
+    ) -> Result<BTreeMap<String, AnyLuaValue>, AnyLuaValueConversionError> {
+        string_keyed_entries(value)?.collect()
+    }
+}
+
+// Returns the entries of a table, failing on the first key that isn't a string.
+fn string_keyed_entries(
+    value: AnyLuaValue,
+) -> Result<
+    impl Iterator<Item = Result<(String, AnyLuaValue), AnyLuaValueConversionError>>,
+    AnyLuaValueConversionError,
+> {
+    match value {
+        AnyLuaValue::LuaArray(content) => Ok(content.into_iter().map(|(k, v)| match k {
+            AnyLuaValue::LuaString(k) => Ok((k, v)),
+            k => Err(AnyLuaValueConversionError::new("a string key", &k)),
+        })),
+        v => Err(AnyLuaValueConversionError::new("a table", &v)),
+    }
+}
+
+// Conversions between `AnyLuaValue` and `toml::Value`.
+//
+// Lua tables whose keys are exactly `1..=n` become TOML arrays, all other tables become TOML
+// tables and must only contain string keys. Numbers without a fractional part become integers.
+#[cfg(feature = "impl-toml")]
+mod toml_impl {
+    use std::convert::TryFrom;
+
+    use toml::{map::Map, Value};
+
+    use super::{table_as_sequence, AnyLuaValue, AnyLuaValueConversionError};
+    use crate::{AnyLuaString, AsMutLua, LuaRead, Push, PushGuard, PushOne, Void};
+
+    impl From<Value> for AnyLuaValue {
+        fn from(value: Value) -> AnyLuaValue {
+            match value {
+                Value::String(v) => AnyLuaValue::LuaString(v),
+                Value::Integer(v) => match i32::try_from(v) {
+                    Ok(v) => AnyLuaValue::LuaInteger(v),
+                    Err(_) => AnyLuaValue::LuaNumber(v as f64),
+                },
+                Value::Float(v) => AnyLuaValue::LuaNumber(v),
+                Value::Boolean(v) => AnyLuaValue::LuaBoolean(v),
+                Value::Datetime(v) => AnyLuaValue::LuaString(v.to_string()),
+                Value::Array(v) => AnyLuaValue::LuaArray(
+                    v.into_iter()
+                        .zip(1..)
+                        .map(|(v, i)| (AnyLuaValue::LuaInteger(i), AnyLuaValue::from(v)))
+                        .collect(),
+                ),
+                Value::Table(v) => AnyLuaValue::LuaArray(
+                    v.into_iter()
+                        .map(|(k, v)| (AnyLuaValue::LuaString(k), AnyLuaValue::from(v)))
+                        .collect(),
+                ),
+            }
+        }
+    }
+
+    impl TryFrom<AnyLuaValue> for Value {
+        type Error = AnyLuaValueConversionError;
+
+        #[inline]
+        fn try_from(value: AnyLuaValue) -> Result<Value, AnyLuaValueConversionError> {
+            // `toml::Value` has an inherent `try_from` method, so we can't recurse through the
+            // trait with `Value::try_from`.
+            to_toml(value)
+        }
+    }
+
+    fn to_toml(value: AnyLuaValue) -> Result<Value, AnyLuaValueConversionError> {
+        let error = |expected, found: &AnyLuaValue| AnyLuaValueConversionError {
+            expected,
+            found: found.type_name(),
+        };
+
+        Ok(match value {
+            AnyLuaValue::LuaString(v) => Value::String(v),
+            AnyLuaValue::LuaAnyString(AnyLuaString(v)) => match String::from_utf8(v) {
+                Ok(v) => Value::String(v),
+                Err(_) => {
+                    return Err(AnyLuaValueConversionError {
+                        expected: "a UTF-8 string",
+                        found: "string",
+                    })
+                },
+            },
+            ref v @ (AnyLuaValue::LuaInteger(_) | AnyLuaValue::LuaNumber(_)) => {
+                match (v.as_integral(), v) {
+                    (Some(i), _) => Value::Integer(i),
+                    (None, AnyLuaValue::LuaNumber(f)) => Value::Float(*f),
+                    (None, _) => unreachable!(),
+                }
+            },
+            AnyLuaValue::LuaBoolean(v) => Value::Boolean(v),
+            AnyLuaValue::LuaArray(content) => match table_as_sequence(content) {
+                Ok(values) => {
+                    Value::Array(values.into_iter().map(to_toml).collect::<Result<_, _>>()?)
+                },
+                Err(content) => {
+                    let mut map = Map::new();
+                    for (k, v) in content {
+                        match k {
+                            AnyLuaValue::LuaString(k) => map.insert(k, to_toml(v)?),
+                            k => return Err(error("a string key", &k)),
+                        };
+                    }
+                    Value::Table(map)
+                },
+            },
+            ref v @ (AnyLuaValue::LuaNil | AnyLuaValue::LuaOther) => {
+                return Err(error("a value representable in TOML", v))
+            },
+        })
+    }
+
+    impl<'lua, L> Push<L> for Value
+    where
+        L: AsMutLua<'lua>,
+    {
+        type Err = Void;
+
+        #[inline]
+        fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+            AnyLuaValue::from(self).push_to_lua(lua)
+        }
+    }
+
+    impl<'lua, L> PushOne<L> for Value where L: AsMutLua<'lua> {}
+
+    impl<'lua, L> LuaRead<L> for Value
+    where
+        L: AsMutLua<'lua>,
+    {
+        #[inline]
+        fn lua_read_at_position(mut lua: L, index: i32) -> Result<Value, L> {
+            match AnyLuaValue::lua_read_at_position(&mut lua, index).map(to_toml) {
+                Ok(Ok(value)) => Ok(value),
+                _ => Err(lua),
+            }
+        }
+    }
+}
+
+// Conversions between `AnyLuaValue` and `serde_yaml::Value`.
+//
+// YAML sequences map to tables with the keys `1..=n` and back. Mappings may use any key that can
+// itself be converted. Tagged values are converted as if they weren't tagged.
+#[cfg(feature = "impl-serde-yaml")]
+mod serde_yaml_impl {
+    use std::convert::TryFrom;
+
+    use serde_yaml::{Mapping, Number, Value};
+
+    use super::{table_as_sequence, AnyLuaValue, AnyLuaValueConversionError};
+    use crate::{AnyLuaString, AsMutLua, LuaRead, Push, PushGuard, PushOne, Void};
+
+    impl From<Value> for AnyLuaValue {
+        fn from(value: Value) -> AnyLuaValue {
+            match value {
+                Value::Null => AnyLuaValue::LuaNil,
+                Value::Bool(v) => AnyLuaValue::LuaBoolean(v),
+                Value::Number(v) => match v.as_i64().map(i32::try_from) {
+                    Some(Ok(v)) => AnyLuaValue::LuaInteger(v),
+                    _ => AnyLuaValue::LuaNumber(v.as_f64().unwrap_or(f64::NAN)),
+                },
+                Value::String(v) => AnyLuaValue::LuaString(v),
+                Value::Sequence(v) => AnyLuaValue::LuaArray(
+                    v.into_iter()
+                        .zip(1..)
+                        .map(|(v, i)| (AnyLuaValue::LuaInteger(i), AnyLuaValue::from(v)))
+                        .collect(),
+                ),
+                Value::Mapping(v) => AnyLuaValue::LuaArray(
+                    v.into_iter()
+                        .map(|(k, v)| (AnyLuaValue::from(k), AnyLuaValue::from(v)))
+                        // Lua tables can't hold nil keys or values
+                        .filter(|(k, v)| *k != AnyLuaValue::LuaNil && *v != AnyLuaValue::LuaNil)
+                        .collect(),
+                ),
+                Value::Tagged(v) => AnyLuaValue::from(v.value),
+            }
+        }
+    }
+
+    impl TryFrom<AnyLuaValue> for Value {
+        type Error = AnyLuaValueConversionError;
+
+        fn try_from(value: AnyLuaValue) -> Result<Value, AnyLuaValueConversionError> {
+            Ok(match value {
+                AnyLuaValue::LuaString(v) => Value::String(v),
+                AnyLuaValue::LuaAnyString(AnyLuaString(v)) => match String::from_utf8(v) {
+                    Ok(v) => Value::String(v),
+                    Err(_) => {
+                        return Err(AnyLuaValueConversionError {
+                            expected: "a UTF-8 string",
+                            found: "string",
+                        })
+                    },
+                },
+                ref v @ (AnyLuaValue::LuaInteger(_) | AnyLuaValue::LuaNumber(_)) => {
+                    match (v.as_integral(), v) {
+                        (Some(i), _) => Value::Number(Number::from(i)),
+                        (None, AnyLuaValue::LuaNumber(f)) => Value::Number(Number::from(*f)),
+                        (None, _) => unreachable!(),
+                    }
+                },
+                AnyLuaValue::LuaBoolean(v) => Value::Bool(v),
+                AnyLuaValue::LuaArray(content) => match table_as_sequence(content) {
+                    Ok(values) => Value::Sequence(
+                        values.into_iter().map(Value::try_from).collect::<Result<_, _>>()?,
+                    ),
+                    Err(content) => {
+                        let mut map = Mapping::new();
+                        for (k, v) in content {
+                            map.insert(Value::try_from(k)?, Value::try_from(v)?);
+                        }
+                        Value::Mapping(map)
+                    },
+                },
+                AnyLuaValue::LuaNil => Value::Null,
+                AnyLuaValue::LuaOther => {
+                    return Err(AnyLuaValueConversionError {
+                        expected: "a value representable in YAML",
+                        found: "other",
+                    })
+                },
+            })
+        }
+    }
+
+    impl<'lua, L> Push<L> for Value
+    where
+        L: AsMutLua<'lua>,
+    {
+        type Err = Void;
+
+        #[inline]
+        fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+            AnyLuaValue::from(self).push_to_lua(lua)
+        }
+    }
+
+    impl<'lua, L> PushOne<L> for Value where L: AsMutLua<'lua> {}
+
+    impl<'lua, L> LuaRead<L> for Value
+    where
+        L: AsMutLua<'lua>,
+    {
+        #[inline]
+        fn lua_read_at_position(mut lua: L, index: i32) -> Result<Value, L> {
+            match AnyLuaValue::lua_read_at_position(&mut lua, index).map(Value::try_from) {
+                Ok(Ok(value)) => Ok(value),
+                _ => Err(lua),
+            }
+        }
+    }
+}
+
+// Conversions between `AnyLuaValue` and `serde_json::Value`.
+//
+// `null` becomes nil, arrays become tables with the keys `1..=n` and objects become tables with
+// string keys. Lua tables can't hold nil, so object members that are `null` are left out and
+// `null` array elements leave holes. When reading, a table whose keys are all positive integers
+// becomes an array with `null` in the holes, as long as at least half of the slots are filled.
+// Any other table becomes an object and must only contain string keys. Empty tables become
+// empty objects.
+#[cfg(feature = "impl-serde-json")]
+mod serde_json_impl {
+    use std::convert::TryFrom;
+
+    use serde_json::{Map, Number, Value};
+
+    use super::{AnyLuaValue, AnyLuaValueConversionError};
+    use crate::{AnyLuaString, AsMutLua, LuaRead, Push, PushGuard, PushOne, Void};
+
+    impl From<Value> for AnyLuaValue {
+        fn from(value: Value) -> AnyLuaValue {
+            match value {
+                Value::Null => AnyLuaValue::LuaNil,
+                Value::Bool(v) => AnyLuaValue::LuaBoolean(v),
+                Value::Number(v) => match v.as_i64().map(i32::try_from) {
+                    Some(Ok(v)) => AnyLuaValue::LuaInteger(v),
+                    _ => AnyLuaValue::LuaNumber(v.as_f64().unwrap_or(f64::NAN)),
+                },
+                Value::String(v) => AnyLuaValue::LuaString(v),
+                Value::Array(v) => AnyLuaValue::LuaArray(
+                    v.into_iter()
+                        .zip(1..)
+                        .filter(|(v, _)| !v.is_null())
+                        .map(|(v, i)| (AnyLuaValue::LuaInteger(i), AnyLuaValue::from(v)))
+                        .collect(),
+                ),
+                Value::Object(v) => AnyLuaValue::LuaArray(
+                    v.into_iter()
+                        .filter(|(_, v)| !v.is_null())
+                        .map(|(k, v)| (AnyLuaValue::LuaString(k), AnyLuaValue::from(v)))
+                        .collect(),
+                ),
+            }
+        }
+    }
+
+    impl TryFrom<AnyLuaValue> for Value {
+        type Error = AnyLuaValueConversionError;
+
+        #[inline]
+        fn try_from(value: AnyLuaValue) -> Result<Value, AnyLuaValueConversionError> {
+            to_json(value)
+        }
+    }
+
+    fn to_json(value: AnyLuaValue) -> Result<Value, AnyLuaValueConversionError> {
+        let error = |expected, found: &AnyLuaValue| AnyLuaValueConversionError {
+            expected,
+            found: found.type_name(),
+        };
+
+        Ok(match value {
+            AnyLuaValue::LuaString(v) => Value::String(v),
+            AnyLuaValue::LuaAnyString(AnyLuaString(v)) => match String::from_utf8(v) {
+                Ok(v) => Value::String(v),
+                Err(_) => {
+                    return Err(AnyLuaValueConversionError {
+                        expected: "a UTF-8 string",
+                        found: "string",
+                    })
+                },
+            },
+            ref v @ (AnyLuaValue::LuaInteger(_) | AnyLuaValue::LuaNumber(_)) => {
+                match (v.as_integral(), v) {
+                    (Some(i), _) => Value::Number(Number::from(i)),
+                    (None, AnyLuaValue::LuaNumber(f)) => match Number::from_f64(*f) {
+                        Some(n) => Value::Number(n),
+                        None => return Err(error("a finite number", v)),
+                    },
+                    (None, _) => unreachable!(),
+                }
+            },
+            AnyLuaValue::LuaBoolean(v) => Value::Bool(v),
+            AnyLuaValue::LuaArray(content) => match as_array(content) {
+                Ok(values) => Value::Array(
+                    values
+                        .into_iter()
+                        .map(|v| v.map_or(Ok(Value::Null), to_json))
+                        .collect::<Result<_, _>>()?,
+                ),
+                Err(content) => {
+                    let mut map = Map::new();
+                    for (k, v) in content {
+                        match k {
+                            AnyLuaValue::LuaString(k) => map.insert(k, to_json(v)?),
+                            k => return Err(error("a string key", &k)),
+                        };
+                    }
+                    Value::Object(map)
+                },
+            },
+            AnyLuaValue::LuaNil => Value::Null,
+            ref v @ AnyLuaValue::LuaOther => return Err(error("a value representable in JSON", v)),
+        })
+    }
+
+    // Returns the elements of a table whose keys are positive integers, with `None` in the holes,
+    // or gives the table back if it isn't an array or is too sparse.
+    fn as_array(
+        content: Vec<(AnyLuaValue, AnyLuaValue)>,
+    ) -> Result<Vec<Option<AnyLuaValue>>, Vec<(AnyLuaValue, AnyLuaValue)>> {
+        let max_len = content.len() * 2;
+        let mut keys = Vec::with_capacity(content.len());
+        for (key, _) in &content {
+            match key.as_integral() {
+                Some(k) if k >= 1 && k as usize <= max_len => keys.push(k as usize - 1),
+                _ => return Err(content),
+            }
+        }
+
+        let len = match keys.iter().max() {
+            Some(last) => last + 1,
+            None => return Err(content),
+        };
+        let mut slots: Vec<Option<AnyLuaValue>> = vec![None; len];
+        for (slot, (_, value)) in keys.into_iter().zip(content) {
+            slots[slot] = Some(value);
+        }
+        Ok(slots)
+    }
+
+    impl<'lua, L> Push<L> for Value
+    where
+        L: AsMutLua<'lua>,
+    {
+        type Err = Void;
+
+        #[inline]
+        fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+            AnyLuaValue::from(self).push_to_lua(lua)
+        }
+    }
+
+    impl<'lua, L> PushOne<L> for Value where L: AsMutLua<'lua> {}
+
+    impl<'lua, L> LuaRead<L> for Value
+    where
+        L: AsMutLua<'lua>,
+    {
+        #[inline]
+        fn lua_read_at_position(mut lua: L, index: i32) -> Result<Value, L> {
+            match AnyLuaValue::lua_read_at_position(&mut lua, index).map(to_json) {
+                Ok(Ok(value)) => Ok(value),
+                _ => Err(lua),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{AnyHashableLuaValue, AnyLuaString, AnyLuaValue, Lua, LuaFunction, LuaNil};
@@ -483,4 +1208,162 @@ mod tests {
             _ => panic!("Decoded to wrong variant"),
         }
     }
+
+    #[test]
+    fn standard_conversions() {
+        use std::collections::HashMap;
+
+        let mut lua = Lua::new();
+        lua.set("a", AnyLuaValue::from(vec![AnyLuaValue::from(-3i8), AnyLuaValue::from("x")]));
+        lua.execute::<()>("b = { n = 2.5, flag = true }").unwrap();
+
+        let a: AnyLuaValue = lua.get("a").unwrap();
+        let a = Vec::<AnyLuaValue>::try_from(a).unwrap();
+        assert_eq!(i16::try_from(a[0].clone()), Ok(-3));
+        assert_eq!(String::try_from(a[1].clone()).unwrap(), "x");
+
+        let b: AnyLuaValue = lua.get("b").unwrap();
+        let b = HashMap::<String, AnyLuaValue>::try_from(b).unwrap();
+        assert_eq!(f64::try_from(b["n"].clone()), Ok(2.5));
+        assert_eq!(bool::try_from(b["flag"].clone()), Ok(true));
+
+        assert_eq!(AnyLuaValue::from(None::<bool>), AnyLuaValue::LuaNil);
+        assert_eq!(AnyLuaValue::from(u32::MAX), AnyLuaValue::LuaNumber(4294967295.0));
+    }
+
+    #[test]
+    fn standard_conversion_errors() {
+        use std::collections::BTreeMap;
+
+        let err = u8::try_from(AnyLuaValue::LuaNumber(256.0)).unwrap_err();
+        assert_eq!(err.to_string(), "expected an integer that fits in u8, found number");
+        assert!(i32::try_from(AnyLuaValue::LuaNumber(1.5)).is_err());
+        assert!(bool::try_from(AnyLuaValue::LuaNil).is_err());
+
+        let table = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(2.0), AnyLuaValue::LuaNil)]);
+        assert_eq!(Vec::<AnyLuaValue>::try_from(table.clone()).unwrap_err().expected, "a sequence");
+        assert_eq!(
+            BTreeMap::<String, AnyLuaValue>::try_from(table).unwrap_err().expected,
+            "a string key"
+        );
+        assert_eq!(Vec::<AnyLuaValue>::try_from(AnyLuaValue::LuaArray(vec![])), Ok(vec![]));
+    }
+
+    #[test]
+    #[cfg(feature = "impl-toml")]
+    fn toml_roundtrip() {
+        let mut lua = Lua::new();
+
+        let config: toml::Value = toml::from_str(
+            r#"
+            name = "server"
+            ports = [80, 443]
+            ratio = 0.5
+
+            [limits]
+            connections = 1024
+        "#,
+        )
+        .unwrap();
+
+        lua.set("config", config.clone());
+
+        let name: String = lua.execute("return config.name").unwrap();
+        assert_eq!(name, "server");
+        let port: i32 = lua.execute("return config.ports[2]").unwrap();
+        assert_eq!(port, 443);
+        let connections: i32 = lua.execute("return config.limits.connections").unwrap();
+        assert_eq!(connections, 1024);
+
+        let read: toml::Value = lua.get("config").unwrap();
+        assert_eq!(read, config);
+    }
+
+    #[test]
+    #[cfg(feature = "impl-toml")]
+    fn toml_rejects_non_string_keys() {
+        use std::convert::TryFrom;
+
+        let mut lua = Lua::new();
+        lua.execute::<()>("t = { [1] = 'a', [3] = 'b' }").unwrap();
+
+        assert_eq!(lua.get::<toml::Value, _>("t"), None);
+
+        let any: AnyLuaValue = lua.get("t").unwrap();
+        let err = <toml::Value as TryFrom<_>>::try_from(any).unwrap_err();
+        assert_eq!(err.expected, "a string key");
+        assert_eq!(err.found, "number");
+    }
+
+    #[test]
+    #[cfg(feature = "impl-serde-yaml")]
+    fn yaml_roundtrip() {
+        let mut lua = Lua::new();
+
+        let config: serde_yaml::Value = serde_yaml::from_str(
+            r#"
+            name: server
+            ports: [80, 443]
+            limits:
+              connections: 1024
+              burst: 1.5
+        "#,
+        )
+        .unwrap();
+
+        lua.set("config", config.clone());
+
+        let port: i32 = lua.execute("return config.ports[1]").unwrap();
+        assert_eq!(port, 80);
+        let burst: f64 = lua.execute("return config.limits.burst").unwrap();
+        assert_eq!(burst, 1.5);
+
+        let read: serde_yaml::Value = lua.get("config").unwrap();
+        assert_eq!(read, config);
+    }
+
+    #[test]
+    #[cfg(feature = "impl-serde-json")]
+    fn json_roundtrip() {
+        let mut lua = Lua::new();
+
+        let payload = serde_json::json!({
+            "user": "ada",
+            "scores": [1, 2.5, null, 4],
+            "flags": { "admin": true },
+            "removed": null,
+        });
+
+        lua.set("payload", payload);
+
+        let score: f64 = lua.execute("return payload.scores[2]").unwrap();
+        assert_eq!(score, 2.5);
+        let hole: bool = lua.execute("return payload.scores[3] == nil").unwrap();
+        assert!(hole);
+        let admin: bool = lua.execute("return payload.flags.admin").unwrap();
+        assert!(admin);
+
+        let read: serde_json::Value = lua.get("payload").unwrap();
+        let expected = serde_json::json!({
+            "user": "ada",
+            "scores": [1, 2.5, null, 4],
+            "flags": { "admin": true },
+        });
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    #[cfg(feature = "impl-serde-json")]
+    fn json_rejects_unrepresentable_tables() {
+        use std::convert::TryFrom;
+
+        let mut lua = Lua::new();
+        lua.execute::<()>("sparse = { [1] = 'a', [10] = 'b' }; empty = {}").unwrap();
+
+        assert_eq!(lua.get::<serde_json::Value, _>("sparse"), None);
+        assert_eq!(lua.get::<serde_json::Value, _>("empty"), Some(serde_json::json!({})));
+
+        let err = serde_json::Value::try_from(AnyLuaValue::LuaNumber(f64::INFINITY)).unwrap_err();
+        assert_eq!(err.expected, "a finite number");
+    }
 }
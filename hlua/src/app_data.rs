@@ -0,0 +1,331 @@
+use std::{
+    any::{Any, TypeId},
+    cell::{Ref, RefCell, RefMut},
+    collections::HashMap,
+    error::Error,
+    ffi::CStr,
+    fmt,
+};
+
+use crate::read_struct::short_name;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{AsLua, InsideCallback, Lua, LuaContext};
+
+// Key of the registry entry holding the `AppData` of a context.
+const APP_DATA_KEY: &CStr = c"hlua.app_data";
+
+// Values stored with `Lua::set_app_data`, one per type, each in a `RefCell` so that callbacks
+// can borrow them. Dropped when the context is closed.
+#[derive(Default)]
+struct AppData(HashMap<TypeId, Box<dyn Any + Send>>);
+
+// Returns the app data of a context, creating it if `create` is true.
+unsafe fn app_data<'a>(lua: LuaContext, create: bool) -> Option<&'a mut AppData> {
+    let l = lua.as_ptr();
+    ffi::lua_getfield(l, ffi::LUA_REGISTRYINDEX, APP_DATA_KEY.as_ptr());
+    // The userdata stays alive in the registry, so the reference can be used after the pop.
+    let data = userdata_mut::<AppData>(lua, -1);
+    ffi::lua_pop(l, 1);
+    if data.is_some() || !create {
+        return data;
+    }
+
+    push_userdata(AppData::default(), lua, |_| {}).forget();
+    let data = userdata_mut::<AppData>(lua, -1);
+    ffi::lua_setfield(l, ffi::LUA_REGISTRYINDEX, APP_DATA_KEY.as_ptr());
+    data
+}
+
+impl<'lua> Lua<'lua> {
+    /// Stores a value of type `T` in the context, replacing and returning the previous one.
+    ///
+    /// There is at most one value per type, which Rust callbacks can get with
+    /// `InsideCallback::app_data`, or with the methods of the `Lua` given to the callbacks built
+    /// with `function_with_luaN`. This gives them access to the services of the application
+    /// without capturing them in every closure. The value is dropped when the context is closed.
+    ///
+    /// Like the other Rust values stored in the context, the value must be `Send`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous value is borrowed by a callback higher in the call stack.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{InsideCallback, Lua};
+    ///
+    /// struct Score(u32);
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.set_app_data(Score(0));
+    /// lua.set("add_points", hlua::function1(|points: u32| {
+    ///     InsideCallback::with_current(|lua| lua.app_data_mut::<Score>().unwrap().0 += points);
+    /// }));
+    ///
+    /// lua.execute::<()>("add_points(10) add_points(5)").unwrap();
+    /// assert_eq!(lua.app_data::<Score>().unwrap().0, 15);
+    /// ```
+    pub fn set_app_data<T: Send + 'static>(&mut self, value: T) -> Option<T> {
+        if let Some(cell) = unsafe { app_data_cell::<T>(self.lua) } {
+            // Replacing in place keeps the cell alive if a callback borrows it.
+            return Some(cell.replace(value));
+        }
+        let data = unsafe { app_data(self.lua, true).unwrap() };
+        let old = data.0.insert(TypeId::of::<T>(), Box::new(RefCell::new(value)))?;
+        old.downcast::<RefCell<T>>().ok().map(|old| old.into_inner())
+    }
+
+    /// Borrows the value of type `T` stored with `set_app_data`.
+    ///
+    /// Fails if there is no such value, or if it is mutably borrowed by a callback higher in the
+    /// call stack.
+    #[inline]
+    pub fn app_data<T: 'static>(&self) -> Result<Ref<'_, T>, AppDataError> {
+        unsafe { borrow_app_data(self.lua) }
+    }
+
+    /// Mutably borrows the value of type `T` stored with `set_app_data`.
+    ///
+    /// Fails if there is no such value, or if it is already borrowed by a callback higher in the
+    /// call stack.
+    #[inline]
+    pub fn app_data_mut<T: 'static>(&mut self) -> Result<RefMut<'_, T>, AppDataError> {
+        unsafe { borrow_app_data_mut(self.lua) }
+    }
+
+    /// Removes the value of type `T` stored with `set_app_data` and returns it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is borrowed by a callback higher in the call stack.
+    pub fn remove_app_data<T: 'static>(&mut self) -> Option<T> {
+        let cell = unsafe { app_data_cell::<T>(self.lua)? };
+        if cell.try_borrow_mut().is_err() {
+            panic!("{}", AppDataError::new::<T>(AppDataErrorKind::Borrowed));
+        }
+        let data = unsafe { app_data(self.lua, false)? };
+        let value = data.0.remove(&TypeId::of::<T>())?;
+        value.downcast::<RefCell<T>>().ok().map(|value| value.into_inner())
+    }
+}
+
+impl InsideCallback {
+    /// Borrows the value of type `T` stored with `Lua::set_app_data` in the context that called
+    /// the callback.
+    ///
+    /// Fails if there is no such value, or if it is mutably borrowed by a callback higher in
+    /// the call stack, for example when Lua code called by a callback calls another one. If the
+    /// callback returns the error, Lua gets nil and the error message.
+    pub fn app_data<T: 'static>(&self) -> Result<Ref<'_, T>, AppDataError> {
+        unsafe { borrow_app_data(self.as_lua()) }
+    }
+
+    /// Mutably borrows the value of type `T` stored with `Lua::set_app_data` in the context that
+    /// called the callback.
+    ///
+    /// Fails if there is no such value, or if it is already borrowed by a callback higher in the
+    /// call stack.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{InsideCallback, Lua};
+    ///
+    /// struct World { entities: u32 }
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.set_app_data(World { entities: 0 });
+    /// lua.set("spawn", hlua::function0(|| {
+    ///     InsideCallback::with_current(|lua| {
+    ///         let mut world = lua.app_data_mut::<World>()?;
+    ///         world.entities += 1;
+    ///         // Until `world` is dropped, the callbacks called by Lua code called from here
+    ///         // can't borrow the world.
+    ///         assert!(lua.app_data::<World>().is_err());
+    ///         Ok::<_, hlua::AppDataError>(world.entities)
+    ///     })
+    ///     .unwrap()
+    /// }));
+    ///
+    /// assert_eq!(lua.execute::<u32>("spawn() return spawn()").unwrap(), 2);
+    /// ```
+    pub fn app_data_mut<T: 'static>(&self) -> Result<RefMut<'_, T>, AppDataError> {
+        unsafe { borrow_app_data_mut(self.as_lua()) }
+    }
+}
+
+unsafe fn app_data_cell<'a, T: 'static>(lua: LuaContext) -> Option<&'a RefCell<T>> {
+    let data = app_data(lua, false)?;
+    data.0.get(&TypeId::of::<T>())?.downcast_ref()
+}
+
+// The cells are boxed and only removed by `remove_app_data`, which checks that they aren't
+// borrowed, so the guards can outlive the lookup.
+unsafe fn borrow_app_data<'a, T: 'static>(lua: LuaContext) -> Result<Ref<'a, T>, AppDataError> {
+    let cell = app_data_cell::<T>(lua);
+    let cell = cell.ok_or_else(|| AppDataError::new::<T>(AppDataErrorKind::Missing))?;
+    cell.try_borrow().map_err(|_| AppDataError::new::<T>(AppDataErrorKind::MutablyBorrowed))
+}
+
+unsafe fn borrow_app_data_mut<'a, T: 'static>(
+    lua: LuaContext,
+) -> Result<RefMut<'a, T>, AppDataError> {
+    let cell = app_data_cell::<T>(lua);
+    let cell = cell.ok_or_else(|| AppDataError::new::<T>(AppDataErrorKind::Missing))?;
+    cell.try_borrow_mut().map_err(|_| AppDataError::new::<T>(AppDataErrorKind::Borrowed))
+}
+
+/// Error returned when a callback can't borrow a value stored with `Lua::set_app_data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppDataError {
+    /// Name of the type of the value.
+    pub type_name: &'static str,
+    /// What went wrong.
+    pub kind: AppDataErrorKind,
+}
+
+/// Kind of an `AppDataError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppDataErrorKind {
+    /// There is no value of this type in the context.
+    Missing,
+    /// The value is already borrowed, so it can't be borrowed mutably.
+    Borrowed,
+    /// The value is mutably borrowed, so it can't be borrowed.
+    MutablyBorrowed,
+}
+
+impl AppDataError {
+    fn new<T>(kind: AppDataErrorKind) -> AppDataError {
+        AppDataError { type_name: short_name::<T>(), kind }
+    }
+}
+
+impl fmt::Display for AppDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            AppDataErrorKind::Missing => write!(f, "no app data of type {}", self.type_name),
+            AppDataErrorKind::Borrowed => {
+                write!(f, "app data of type {} is already borrowed", self.type_name)
+            },
+            AppDataErrorKind::MutablyBorrowed => {
+                write!(f, "app data of type {} is already mutably borrowed", self.type_name)
+            },
+        }
+    }
+}
+
+impl Error for AppDataError {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        function0, function_with_lua0, AppDataErrorKind, AsLua, InsideCallback, Lua, LuaFunction,
+        LuaRead, PushGuard,
+    };
+
+    #[test]
+    fn store_and_replace() {
+        let mut lua = Lua::new();
+        assert_eq!(lua.app_data::<u32>().unwrap_err().kind, AppDataErrorKind::Missing);
+        assert_eq!(lua.set_app_data(5u32), None);
+        assert_eq!(lua.set_app_data(6u32), Some(5));
+        lua.set_app_data(String::from("name"));
+
+        *lua.app_data_mut::<u32>().unwrap() += 1;
+        assert_eq!(*lua.app_data::<u32>().unwrap(), 7);
+        assert_eq!(&lua.app_data::<String>().unwrap()[..], "name");
+        assert_eq!(lua.remove_app_data::<u32>(), Some(7));
+        assert!(lua.app_data::<u32>().is_err());
+    }
+
+    #[test]
+    fn callbacks_and_drop() {
+        let shared = Arc::new(());
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set_app_data(shared.clone());
+        lua.set_app_data(40i32);
+
+        let answer = || InsideCallback::with_current(|lua| lua.app_data::<i32>().map(|n| *n + 2));
+        lua.set("answer", function0(move || answer().unwrap()));
+        let answer: i32 = lua.execute("return coroutine.wrap(answer)()").unwrap();
+        assert_eq!(answer, 42);
+        assert_eq!(InsideCallback::with_current(|_| ()), None);
+
+        assert_eq!(Arc::strong_count(&shared), 2);
+        drop(lua);
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    fn reentrant_borrows() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set_app_data(1u32);
+        lua.set(
+            "read",
+            function0(|| InsideCallback::with_current(|lua| lua.app_data::<u32>().map(|n| *n))),
+        );
+        // Calls `inner` while holding a mutable borrow.
+        lua.set(
+            "outer",
+            function0(|| {
+                InsideCallback::with_current(|lua| {
+                    let mut value = lua.app_data_mut::<u32>().unwrap();
+                    *value += 1;
+                    assert_eq!(
+                        lua.app_data_mut::<u32>().unwrap_err().kind,
+                        AppDataErrorKind::Borrowed
+                    );
+
+                    let raw = (&*lua).as_lua();
+                    unsafe { ffi::lua_getglobal(raw.as_ptr(), c"inner".as_ptr()) };
+                    let guard = PushGuard { lua: raw, size: 1, raw_lua: raw };
+                    let mut inner: LuaFunction<_> = LuaRead::lua_read(guard).ok().unwrap();
+                    inner.call::<String>().unwrap()
+                })
+                .unwrap()
+            }),
+        );
+
+        lua.execute::<()>("function inner() local _, err = read() return err end").unwrap();
+        let err: String = lua.execute("return outer()").unwrap();
+        assert_eq!(err, "app data of type u32 is already mutably borrowed");
+        assert_eq!(lua.execute::<u32>("return read()").unwrap(), 2);
+    }
+
+    #[test]
+    fn callback_contexts() {
+        let mut lua = Lua::new();
+        lua.set_app_data(1u32);
+        lua.set(
+            "bump",
+            function_with_lua0(|lua: &mut Lua| {
+                *lua.app_data_mut::<u32>().unwrap() += 1;
+                lua.set_app_data(String::from("bumped"));
+                // A borrow held through the callback keeps the context from aliasing it, and
+                // the other way around.
+                InsideCallback::with_current(|inside| {
+                    let _value = inside.app_data::<u32>().unwrap();
+                    assert!(lua.app_data::<u32>().is_ok());
+                    assert!(lua.app_data_mut::<u32>().is_err());
+                });
+                let mut value = lua.app_data_mut::<u32>().unwrap();
+                InsideCallback::with_current(|inside| {
+                    let err = inside.app_data_mut::<u32>().unwrap_err();
+                    assert_eq!(err.kind, AppDataErrorKind::Borrowed);
+                });
+                *value += 1;
+                *value
+            }),
+        );
+
+        assert_eq!(lua.execute::<u32>("return bump()").unwrap(), 3);
+        assert_eq!(&lua.app_data::<String>().unwrap()[..], "bumped");
+        assert_eq!(lua.remove_app_data::<u32>(), Some(3));
+    }
+}
@@ -0,0 +1,440 @@
+use std::ptr;
+
+use libc::c_int;
+
+use crate::lua_functions::{pcall, read_error};
+use crate::{Lua, LuaError, LuaRead, Push, PushGuard, PushOne, Void};
+
+/// Arithmetic operation of Lua, applied with `Lua::arith`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ArithOp {
+    /// `a + b`
+    Add,
+    /// `a - b`
+    Sub,
+    /// `a * b`
+    Mul,
+    /// `a / b`
+    Div,
+    /// `a % b`
+    Mod,
+    /// `a ^ b`
+    Pow,
+    /// `-a`
+    Unm,
+    /// `a // b`
+    #[cfg(feature = "_luaapi_54")]
+    IDiv,
+    /// `a & b`
+    #[cfg(feature = "_luaapi_54")]
+    BAnd,
+    /// `a | b`
+    #[cfg(feature = "_luaapi_54")]
+    BOr,
+    /// `a ~ b`
+    #[cfg(feature = "_luaapi_54")]
+    BXor,
+    /// `a << b`
+    #[cfg(feature = "_luaapi_54")]
+    Shl,
+    /// `a >> b`
+    #[cfg(feature = "_luaapi_54")]
+    Shr,
+    /// `~a`
+    #[cfg(feature = "_luaapi_54")]
+    BNot,
+}
+
+/// Comparison of Lua, applied with `Lua::compare`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CompareOp {
+    /// `a == b`
+    Eq,
+    /// `a < b`
+    Lt,
+    /// `a <= b`
+    Le,
+}
+
+impl ArithOp {
+    fn code(self) -> c_int {
+        match self {
+            ArithOp::Add => ffi::LUA_OPADD,
+            ArithOp::Sub => ffi::LUA_OPSUB,
+            ArithOp::Mul => ffi::LUA_OPMUL,
+            ArithOp::Div => ffi::LUA_OPDIV,
+            ArithOp::Mod => ffi::LUA_OPMOD,
+            ArithOp::Pow => ffi::LUA_OPPOW,
+            ArithOp::Unm => ffi::LUA_OPUNM,
+            #[cfg(feature = "_luaapi_54")]
+            ArithOp::IDiv => ffi::LUA_OPIDIV,
+            #[cfg(feature = "_luaapi_54")]
+            ArithOp::BAnd => ffi::LUA_OPBAND,
+            #[cfg(feature = "_luaapi_54")]
+            ArithOp::BOr => ffi::LUA_OPBOR,
+            #[cfg(feature = "_luaapi_54")]
+            ArithOp::BXor => ffi::LUA_OPBXOR,
+            #[cfg(feature = "_luaapi_54")]
+            ArithOp::Shl => ffi::LUA_OPSHL,
+            #[cfg(feature = "_luaapi_54")]
+            ArithOp::Shr => ffi::LUA_OPSHR,
+            #[cfg(feature = "_luaapi_54")]
+            ArithOp::BNot => ffi::LUA_OPBNOT,
+        }
+    }
+}
+
+impl CompareOp {
+    fn code(self) -> c_int {
+        match self {
+            CompareOp::Eq => ffi::LUA_OPEQ,
+            CompareOp::Lt => ffi::LUA_OPLT,
+            CompareOp::Le => ffi::LUA_OPLE,
+        }
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Applies an arithmetic operation to two values with the semantics of Lua, including the
+    /// conversions of strings to numbers and the metamethods, and reads the result.
+    ///
+    /// For the unary operations, `Unm` and `BNot`, `b` is ignored. Errors raised by the
+    /// operation, such as adding a table without `__add` metamethod, are returned. The
+    /// operations of Lua 5.4 on integers and bits are only available with this version.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{ArithOp, LuaRef};
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// let vector: LuaRef = lua
+    ///     .execute("return setmetatable({}, { __add = function(_, n) return n * 10 end })")
+    ///     .unwrap();
+    ///
+    /// assert_eq!(lua.arith::<_, _, i32>(ArithOp::Add, "2", 3).unwrap(), 5);
+    /// assert_eq!(lua.arith::<_, _, i32>(ArithOp::Add, &vector, 4).unwrap(), 40);
+    /// assert!(lua.arith::<_, _, i32>(ArithOp::Mul, &vector, 4).is_err());
+    /// ```
+    pub fn arith<'a, A, B, V>(&'a mut self, op: ArithOp, a: A, b: B) -> Result<V, LuaError>
+    where
+        A: for<'r> PushOne<&'r mut Lua<'lua>, Err = Void>,
+        B: for<'r> PushOne<&'r mut Lua<'lua>, Err = Void>,
+        V: LuaRead<PushGuard<&'a mut Lua<'lua>>>,
+    {
+        let result = unsafe { self.operator(arith, op.code(), |lua| push_pair(lua, a, b))? };
+        LuaRead::lua_read(result).map_err(|_| LuaError::WrongType)
+    }
+
+    /// Compares two values with the semantics of Lua, including the metamethods.
+    ///
+    /// Errors raised by the comparison, such as comparing a number with a string, are returned.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::CompareOp;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// assert!(lua.compare(CompareOp::Lt, 1, 2.5).unwrap());
+    /// assert!(lua.compare(CompareOp::Le, "abc", "abd").unwrap());
+    /// assert!(!lua.compare(CompareOp::Eq, 1, "1").unwrap());
+    /// assert!(lua.compare(CompareOp::Lt, 1, "2").is_err());
+    /// ```
+    pub fn compare<A, B>(&mut self, op: CompareOp, a: A, b: B) -> Result<bool, LuaError>
+    where
+        A: for<'r> PushOne<&'r mut Lua<'lua>, Err = Void>,
+        B: for<'r> PushOne<&'r mut Lua<'lua>, Err = Void>,
+    {
+        let result = unsafe { self.operator(compare, op.code(), |lua| push_pair(lua, a, b))? };
+        Ok(unsafe { ffi::lua_toboolean(result.raw_lua.as_ptr(), -1) != 0 })
+    }
+
+    /// Concatenates values with the `..` operator of Lua, including the conversions of numbers
+    /// to strings and the `__concat` metamethods, and reads the result.
+    ///
+    /// `values` is usually a tuple. Concatenating no values gives an empty string.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// let name: String = lua.concat(("player", 2, "_", 1.5)).unwrap();
+    /// assert_eq!(name, "player2_1.5");
+    /// assert!(lua.concat::<_, _, String>(("a", true)).is_err());
+    /// ```
+    pub fn concat<'a, A, E, V>(&'a mut self, values: A) -> Result<V, LuaError>
+    where
+        A: for<'r> Push<&'r mut Lua<'lua>, Err = E>,
+        E: Into<Void>,
+        V: LuaRead<PushGuard<&'a mut Lua<'lua>>>,
+    {
+        let push = |lua: &mut Lua<'lua>| match values.push_to_lua(lua) {
+            Ok(pushed) => unsafe { pushed.forget() },
+            Err((err, _)) => match err.into() {},
+        };
+        let result = unsafe { self.operator(concat, 0, push)? };
+        LuaRead::lua_read(result).map_err(|_| LuaError::WrongType)
+    }
+
+    /// Returns the length of a value with the `#` operator of Lua, including the `__len`
+    /// metamethod.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::LuaRef;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// let list: LuaRef = lua.execute("return { 1, 2, 3 }").unwrap();
+    /// let sized: LuaRef = lua
+    ///     .execute("return setmetatable({}, { __len = function() return 42 end })")
+    ///     .unwrap();
+    ///
+    /// assert_eq!(lua.len::<_, u32>(&list).unwrap(), 3);
+    /// assert_eq!(lua.len::<_, u32>(&sized).unwrap(), 42);
+    /// assert_eq!(lua.len::<_, u32>("hello").unwrap(), 5);
+    /// assert!(lua.len::<_, u32>(12).is_err());
+    /// ```
+    pub fn len<'a, A, V>(&'a mut self, value: A) -> Result<V, LuaError>
+    where
+        A: for<'r> PushOne<&'r mut Lua<'lua>, Err = Void>,
+        V: LuaRead<PushGuard<&'a mut Lua<'lua>>>,
+    {
+        let push = |lua: &mut Lua<'lua>| unsafe { value.push_no_err(lua).forget() };
+        let result = unsafe { self.operator(len, 0, push)? };
+        LuaRead::lua_read(result).map_err(|_| LuaError::WrongType)
+    }
+
+    /// Converts a value to a string like the `tostring` function of Lua, including the
+    /// `__tostring` metamethod, and the `__name` field of the metatable in Lua 5.4.
+    ///
+    /// Errors raised by `__tostring`, or a `__tostring` that doesn't return a string, are
+    /// returned.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::LuaRef;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// let point: LuaRef = lua
+    ///     .execute("return setmetatable({}, { __tostring = function() return '(1, 2)' end })")
+    ///     .unwrap();
+    ///
+    /// assert_eq!(lua.to_display_string(&point).unwrap(), "(1, 2)");
+    /// assert_eq!(lua.to_display_string(1.5).unwrap(), "1.5");
+    /// assert_eq!(lua.to_display_string(hlua::LuaNil).unwrap(), "nil");
+    /// ```
+    pub fn to_display_string<A>(&mut self, value: A) -> Result<String, LuaError>
+    where
+        A: for<'r> PushOne<&'r mut Lua<'lua>, Err = Void>,
+    {
+        let push = |lua: &mut Lua<'lua>| unsafe { value.push_no_err(lua).forget() };
+        let result = unsafe { self.operator(to_string, 0, push)? };
+        LuaRead::lua_read(result).map_err(|_| LuaError::WrongType)
+    }
+
+    /// Converts a value to a number like the `tonumber` function of Lua called with one
+    /// argument, including the conversion of strings. Returns `None` if the value isn't a number
+    /// or a string that can be converted.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// assert_eq!(lua.to_number(" 0x10 "), Some(16.0));
+    /// assert_eq!(lua.to_number("1e3"), Some(1000.0));
+    /// assert_eq!(lua.to_number("ten"), None);
+    /// assert_eq!(lua.to_number(true), None);
+    /// ```
+    pub fn to_number<A>(&mut self, value: A) -> Option<f64>
+    where
+        A: for<'r> PushOne<&'r mut Lua<'lua>, Err = Void>,
+    {
+        let raw_lua = self.lua.as_ptr();
+        let _pushed = value.push_no_err(&mut *self);
+        let mut is_number = 0;
+        let number = unsafe { ffi::lua_tonumberx(raw_lua, -1, &mut is_number) };
+        (is_number != 0).then_some(number as f64)
+    }
+
+    // Calls `function` with `op` as upvalue and the values pushed by `push_args` as arguments,
+    // and returns its result.
+    unsafe fn operator<F>(
+        &mut self,
+        function: extern "C" fn(*mut ffi::lua_State) -> c_int,
+        op: c_int,
+        push_args: F,
+    ) -> Result<PushGuard<&mut Lua<'lua>>, LuaError>
+    where
+        F: FnOnce(&mut Lua<'lua>) -> c_int,
+    {
+        let raw_lua = self.lua;
+        ffi::lua_pushnumber(raw_lua.as_ptr(), op as _);
+        ffi::lua_pushcclosure(raw_lua.as_ptr(), Some(function), 1);
+        let nargs = push_args(self);
+
+        let result = pcall(raw_lua, nargs, 1);
+        let guard = PushGuard { lua: self, size: 1, raw_lua };
+        match result {
+            0 => Ok(guard),
+            _ => Err(read_error(guard)),
+        }
+    }
+}
+
+// Pushes the two operands of a binary operation.
+fn push_pair<'lua, A, B>(lua: &mut Lua<'lua>, a: A, b: B) -> c_int
+where
+    A: for<'r> PushOne<&'r mut Lua<'lua>, Err = Void>,
+    B: for<'r> PushOne<&'r mut Lua<'lua>, Err = Void>,
+{
+    unsafe { a.push_no_err(&mut *lua).forget() + b.push_no_err(&mut *lua).forget() }
+}
+
+// Applies the arithmetic operation of the upvalue to the arguments.
+extern "C" fn arith(lua: *mut ffi::lua_State) -> c_int {
+    unsafe {
+        let op = ffi::lua_tonumberx(lua, ffi::lua_upvalueindex(1), ptr::null_mut()) as c_int;
+        if op == ffi::LUA_OPUNM || is_bnot(op) {
+            ffi::lua_settop(lua, 1);
+        }
+        ffi::lua_arith(lua, op);
+        1
+    }
+}
+
+#[cfg(feature = "_luaapi_54")]
+#[inline]
+fn is_bnot(op: c_int) -> bool {
+    op == ffi::LUA_OPBNOT
+}
+
+#[cfg(not(feature = "_luaapi_54"))]
+#[inline]
+fn is_bnot(_: c_int) -> bool {
+    false
+}
+
+// Compares the two arguments with the comparison of the upvalue.
+extern "C" fn compare(lua: *mut ffi::lua_State) -> c_int {
+    unsafe {
+        let op = ffi::lua_tonumberx(lua, ffi::lua_upvalueindex(1), ptr::null_mut()) as c_int;
+        let result = ffi::lua_compare(lua, 1, 2, op);
+        ffi::lua_pushboolean(lua, result);
+        1
+    }
+}
+
+// Concatenates the arguments.
+extern "C" fn concat(lua: *mut ffi::lua_State) -> c_int {
+    unsafe {
+        ffi::lua_concat(lua, ffi::lua_gettop(lua));
+        1
+    }
+}
+
+// Pushes the length of the argument.
+extern "C" fn len(lua: *mut ffi::lua_State) -> c_int {
+    unsafe {
+        ffi::lua_len(lua, 1);
+        1
+    }
+}
+
+// Converts the argument to a string like `tostring`.
+extern "C" fn to_string(lua: *mut ffi::lua_State) -> c_int {
+    unsafe {
+        ffi::luaL_tolstring(lua, 1, ptr::null_mut());
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ArithOp, CompareOp, Lua, LuaError, LuaNil, LuaRef, OwnedLuaFunction};
+
+    #[test]
+    fn arithmetic_and_metamethods() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        assert_eq!(lua.arith::<_, _, f64>(ArithOp::Pow, 2, 10).unwrap(), 1024.0);
+        assert_eq!(lua.arith::<_, _, i32>(ArithOp::Unm, 7, LuaNil).unwrap(), -7);
+        #[cfg(feature = "_luaapi_54")]
+        assert_eq!(lua.arith::<_, _, i32>(ArithOp::IDiv, 7, 2).unwrap(), 3);
+
+        let money: OwnedLuaFunction = lua
+            .execute(
+                "local mt = {}
+                 mt.__sub = function(a, b) return setmetatable({ v = a.v - b.v }, mt) end
+                 mt.__lt = function(a, b) return a.v < b.v end
+                 mt.__eq = function(a, b) return a.v == b.v end
+                 return function(v) return setmetatable({ v = v }, mt) end",
+            )
+            .unwrap();
+        let mut make = |v: i32| -> LuaRef { money.call_with_args(&mut lua, v).unwrap() };
+        let (ten, three, seven) = (make(10), make(3), make(7));
+
+        let diff: LuaRef = lua.arith(ArithOp::Sub, &ten, &three).unwrap();
+        assert!(lua.compare(CompareOp::Eq, &diff, &seven).unwrap());
+        assert!(lua.compare(CompareOp::Lt, &three, &seven).unwrap());
+        assert!(!lua.compare(CompareOp::Lt, &ten, &seven).unwrap());
+
+        match lua.arith::<_, _, LuaRef>(ArithOp::Add, &ten, 1) {
+            Err(LuaError::ExecutionError(msg)) => {
+                assert!(msg.message().contains("arithmetic"), "{}", msg)
+            },
+            _ => panic!("adding a table without __add succeeded"),
+        }
+        assert_eq!(lua.execute::<i32>("return 1").unwrap(), 1);
+    }
+
+    #[test]
+    fn concat_and_len() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let tag: LuaRef = lua
+            .execute(
+                "return setmetatable({}, {
+                     __concat = function(a, b) return 'tag' end,
+                     __len = function() return 7 end,
+                 })",
+            )
+            .unwrap();
+
+        assert_eq!(lua.concat::<_, _, String>(()).unwrap(), "");
+        assert_eq!(lua.concat::<_, _, String>(("x", &tag)).unwrap(), "tag");
+        assert_eq!(lua.concat::<_, _, String>(("x", 1, "y")).unwrap(), "x1y");
+        assert_eq!(lua.len::<_, i32>(&tag).unwrap(), 7);
+        assert!(lua.len::<_, i32>(true).is_err());
+    }
+
+    #[test]
+    fn string_and_number_conversions() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let broken: LuaRef = lua
+            .execute("return setmetatable({}, { __tostring = function() error('no name') end })")
+            .unwrap();
+        let named: LuaRef = lua.execute("return setmetatable({}, { __name = 'Point' })").unwrap();
+
+        assert_eq!(lua.to_display_string(true).unwrap(), "true");
+        #[cfg(feature = "_luaapi_54")]
+        assert!(lua.to_display_string(&named).unwrap().starts_with("Point: "));
+        match lua.to_display_string(&broken) {
+            Err(LuaError::ExecutionError(msg)) => {
+                assert!(msg.message().contains("no name"), "{}", msg)
+            },
+            _ => panic!("__tostring didn't fail"),
+        }
+
+        assert_eq!(lua.to_number(12), Some(12.0));
+        assert_eq!(lua.to_number("  -2.5"), Some(-2.5));
+        assert_eq!(lua.to_number(&named), None);
+        assert_eq!(lua.execute::<i32>("return 3").unwrap(), 3);
+    }
+}
@@ -0,0 +1,603 @@
+use std::ffi::CStr;
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsLua, AsMutLua, LuaContext, LuaRead, Push, PushGuard, PushOne, Void};
+
+// Maximum number of dimensions of an array read from nested tables. Prevents reading a table
+// that contains itself forever.
+const MAX_DIMENSIONS: usize = 32;
+
+type Method = (&'static CStr, ffi::lua_CFunction);
+
+const METHODS: [Method; 3] = [(c"get", Some(get)), (c"set", Some(set)), (c"size", Some(size))];
+
+/// Array of numbers with any number of dimensions, stored in row-major order.
+///
+/// This is the layout of the arrays of the `ndarray` crate. With the `impl-ndarray` feature, an
+/// `ndarray::ArrayD<f64>` converts to and from a `LuaArrayD` with `From`, without copying the
+/// elements if it is in standard layout, and can be pushed and read directly.
+///
+/// In Lua, an array is made of nested tables: a two-dimensional array is a table of rows, each
+/// row being a table of numbers. An array without dimensions is a single number. Reading nested
+/// tables fails if they don't all have the same length at a given depth.
+///
+/// Large arrays can instead be pushed as a `PackedLuaArrayD`, which keeps the elements in Rust.
+///
+/// # Example
+///
+/// ```
+/// use hlua::LuaArrayD;
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("grid", LuaArrayD::new(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]));
+/// let corner: f64 = lua.execute("return grid[2][3]").unwrap();
+/// assert_eq!(corner, 6.0);
+///
+/// let read: LuaArrayD = lua.execute("return { { 1, 2 }, { 3, 4 }, { 5, 6 } }").unwrap();
+/// assert_eq!(read.shape(), &[3, 2]);
+/// assert_eq!(read.get(&[2, 0]), Some(5.0));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct LuaArrayD {
+    shape: Vec<usize>,
+    data: Vec<f64>,
+}
+
+/// Wrapper around a `LuaArrayD` that is pushed to Lua as a userdata instead of nested tables.
+///
+/// Pushing and reading the array doesn't convert the elements. Scripts access them through the
+/// following fields and methods, where indices start from 1:
+///
+/// - `array.shape` is a table containing the length of each dimension, and `array.ndim` the
+///   number of dimensions. `#array` is the length of the first dimension.
+/// - `array:get(i, j, ...)` returns an element, and `array:set(i, j, ..., value)` modifies it.
+///   `array:size()` returns the total number of elements.
+/// - For one-dimensional arrays, `array[i]` reads an element and `array[i] = value` modifies
+///   it.
+///
+/// Reading a `LuaArrayD` accepts both forms.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{LuaArrayD, PackedLuaArrayD};
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("image", PackedLuaArrayD(LuaArrayD::zeros(vec![480, 640])));
+/// lua.execute::<()>("image:set(1, 2, image.shape[1])").unwrap();
+///
+/// let image: LuaArrayD = lua.get("image").unwrap();
+/// assert_eq!(image.get(&[0, 1]), Some(480.0));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackedLuaArrayD(pub LuaArrayD);
+
+impl LuaArrayD {
+    /// Builds an array from its shape and its elements, in row-major order.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the number of elements doesn't match the shape.
+    #[inline]
+    pub fn new(shape: Vec<usize>, data: Vec<f64>) -> LuaArrayD {
+        assert_eq!(
+            data.len(),
+            shape.iter().product::<usize>(),
+            "wrong number of elements for the shape"
+        );
+        LuaArrayD { shape, data }
+    }
+
+    /// Builds an array whose elements are all 0.
+    #[inline]
+    pub fn zeros(shape: Vec<usize>) -> LuaArrayD {
+        let data = vec![0.0; shape.iter().product()];
+        LuaArrayD { shape, data }
+    }
+
+    /// Returns the length of each dimension.
+    #[inline]
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    /// Returns the number of dimensions.
+    #[inline]
+    pub fn ndim(&self) -> usize {
+        self.shape.len()
+    }
+
+    /// Returns the element at the given indices, starting from 0, or `None` if the number of
+    /// indices is wrong or if they are out of bounds.
+    #[inline]
+    pub fn get(&self, indices: &[usize]) -> Option<f64> {
+        self.position(indices).map(|position| self.data[position])
+    }
+
+    /// Returns a mutable reference to the element at the given indices, starting from 0.
+    #[inline]
+    pub fn get_mut(&mut self, indices: &[usize]) -> Option<&mut f64> {
+        self.position(indices).map(move |position| &mut self.data[position])
+    }
+
+    /// Returns the elements, in row-major order.
+    #[inline]
+    pub fn as_slice(&self) -> &[f64] {
+        &self.data
+    }
+
+    /// Returns the elements, in row-major order.
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [f64] {
+        &mut self.data
+    }
+
+    /// Destroys the array and returns its shape and its elements.
+    #[inline]
+    pub fn into_raw_parts(self) -> (Vec<usize>, Vec<f64>) {
+        (self.shape, self.data)
+    }
+
+    fn position(&self, indices: &[usize]) -> Option<usize> {
+        if indices.len() != self.shape.len() {
+            return None;
+        }
+        indices
+            .iter()
+            .zip(&self.shape)
+            .try_fold(0, |position, (&index, &len)| (index < len).then(|| position * len + index))
+    }
+}
+
+impl<'lua, L> Push<L> for LuaArrayD
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        (&self).push_to_lua(lua)
+    }
+}
+
+impl<'lua, L> PushOne<L> for LuaArrayD where L: AsMutLua<'lua> {}
+
+impl<'lua, L> Push<L> for &LuaArrayD
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        unsafe {
+            let raw_lua = lua.as_mut_lua();
+            ffi::lua_checkstack(raw_lua.as_ptr(), self.shape.len() as libc::c_int + 2);
+            push_nested(raw_lua, &self.shape, &self.data);
+            Ok(PushGuard { lua, size: 1, raw_lua })
+        }
+    }
+}
+
+impl<'lua, L> PushOne<L> for &LuaArrayD where L: AsMutLua<'lua> {}
+
+impl<'lua, L> Push<L> for PackedLuaArrayD
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        let guard = push_userdata(self.0, lua, |mut metatable| unsafe {
+            let raw_lua = metatable.as_mut_lua().as_ptr();
+            ffi::lua_pushcfunction(raw_lua, Some(index));
+            ffi::lua_setfield(raw_lua, -2, c"__index".as_ptr());
+            ffi::lua_pushcfunction(raw_lua, Some(new_index));
+            ffi::lua_setfield(raw_lua, -2, c"__newindex".as_ptr());
+            ffi::lua_pushcfunction(raw_lua, Some(len));
+            ffi::lua_setfield(raw_lua, -2, c"__len".as_ptr());
+        });
+        Ok(guard)
+    }
+}
+
+impl<'lua, L> PushOne<L> for PackedLuaArrayD where L: AsMutLua<'lua> {}
+
+impl<'lua, L> LuaRead<L> for LuaArrayD
+where
+    L: AsLua<'lua>,
+{
+    fn lua_read_at_position(lua: L, index: i32) -> Result<LuaArrayD, L> {
+        let raw_lua = lua.as_lua();
+        if let Some(array) = unsafe { userdata_mut::<LuaArrayD>(raw_lua, index) } {
+            return Ok(array.clone());
+        }
+
+        match unsafe { read_nested(raw_lua, index) } {
+            Some(array) => Ok(array),
+            None => Err(lua),
+        }
+    }
+}
+
+impl<'lua, L> LuaRead<L> for PackedLuaArrayD
+where
+    L: AsLua<'lua>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<PackedLuaArrayD, L> {
+        LuaArrayD::lua_read_at_position(lua, index).map(PackedLuaArrayD)
+    }
+}
+
+// Pushes the elements of `data`, whose shape is `shape`, as nested tables.
+unsafe fn push_nested(lua: LuaContext, shape: &[usize], data: &[f64]) {
+    let l = lua.as_ptr();
+    let (len, inner_shape) = match shape.split_first() {
+        Some(split) => split,
+        None => {
+            ffi::lua_pushnumber(l, data[0]);
+            return;
+        },
+    };
+
+    ffi::lua_createtable(l, *len as _, 0);
+    let chunk = inner_shape.iter().product::<usize>();
+    for position in 0..*len {
+        match inner_shape.is_empty() {
+            true => ffi::lua_pushnumber(l, data[position]),
+            false => push_nested(lua, inner_shape, &data[position * chunk..][..chunk]),
+        }
+        ffi::lua_rawseti(l, -2, (position + 1) as _);
+    }
+}
+
+// Reads an array made of nested tables, or of a single number. The stack is left unchanged.
+unsafe fn read_nested(lua: LuaContext, index: i32) -> Option<LuaArrayD> {
+    let l = lua.as_ptr();
+    ffi::lua_checkstack(l, MAX_DIMENSIONS as libc::c_int + 2);
+
+    // The shape is given by the first element at each depth.
+    let top = ffi::lua_gettop(l);
+    let mut shape = Vec::new();
+    ffi::lua_pushvalue(l, index);
+    while ffi::lua_istable(l, -1) {
+        if shape.len() == MAX_DIMENSIONS {
+            ffi::lua_settop(l, top);
+            return None;
+        }
+        let len = ffix::lua_rawlen(lua, -1);
+        shape.push(len);
+        if len == 0 {
+            break;
+        }
+        ffi::lua_rawgeti(l, -1, 1);
+    }
+    ffi::lua_settop(l, top);
+
+    let mut data = Vec::with_capacity(shape.iter().product());
+    ffi::lua_pushvalue(l, index);
+    let valid = read_elements(lua, &shape, &mut data);
+    ffi::lua_pop(l, 1);
+
+    valid.then_some(LuaArrayD { shape, data })
+}
+
+// Appends the elements of the value at the top of the stack, which must have the given shape.
+unsafe fn read_elements(lua: LuaContext, shape: &[usize], data: &mut Vec<f64>) -> bool {
+    let l = lua.as_ptr();
+    let (len, inner_shape) = match shape.split_first() {
+        Some(split) => split,
+        None => match ffi::lua_type(l, -1) == ffi::LUA_TNUMBER {
+            true => {
+                data.push(ffi::lua_tonumberx(l, -1, std::ptr::null_mut()));
+                return true;
+            },
+            false => return false,
+        },
+    };
+
+    if !ffi::lua_istable(l, -1) || ffix::lua_rawlen(lua, -1) != *len {
+        return false;
+    }
+    for position in 1..=*len {
+        ffi::lua_rawgeti(l, -1, position as _);
+        let valid = read_elements(lua, inner_shape, data);
+        ffi::lua_pop(l, 1);
+        if !valid {
+            return false;
+        }
+    }
+    true
+}
+
+unsafe fn number_arg(lua: *mut ffi::lua_State, index: libc::c_int) -> Option<f64> {
+    let mut success = 0;
+    let value = ffi::lua_tonumberx(lua, index, &mut success);
+    (success != 0).then_some(value)
+}
+
+// Reads the 1-based indices starting at `first`, up to `last`, as 0-based indices.
+unsafe fn indices_args(
+    lua: *mut ffi::lua_State,
+    first: libc::c_int,
+    last: libc::c_int,
+) -> Result<Vec<usize>, String> {
+    (first..=last)
+        .map(|arg| match number_arg(lua, arg) {
+            Some(value) if value >= 1.0 && value.fract() == 0.0 => Ok(value as usize - 1),
+            _ => Err(format!("bad argument #{} (positive integer index expected)", arg - 1)),
+        })
+        .collect()
+}
+
+unsafe fn finish(lua: *mut ffi::lua_State, result: Result<libc::c_int, String>) -> libc::c_int {
+    match result {
+        Ok(count) => count,
+        Err(msg) => {
+            msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+            ffix::lua_error(lua);
+        },
+    }
+}
+
+unsafe fn array_arg<'a>(lua: *mut ffi::lua_State) -> Result<&'a mut LuaArrayD, String> {
+    match userdata_mut::<LuaArrayD>(LuaContext::new_unchecked(lua), 1) {
+        Some(array) => Ok(array),
+        None => Err("bad argument #1 (array expected, use the ':' syntax)".to_owned()),
+    }
+}
+
+extern "C" fn index(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let array = array_arg(lua)?;
+        if ffi::lua_type(lua, 2) == ffi::LUA_TNUMBER {
+            let value = match array.ndim() {
+                1 => indices_args(lua, 2, 2).ok().and_then(|indices| array.get(&indices)),
+                _ => None,
+            };
+            match value {
+                Some(value) => ffi::lua_pushnumber(lua, value),
+                None => ffi::lua_pushnil(lua),
+            }
+            return Ok(1);
+        }
+
+        let key = match ffi::lua_type(lua, 2) == ffi::LUA_TSTRING {
+            true => CStr::from_ptr(ffi::lua_tolstring(lua, 2, std::ptr::null_mut())),
+            false => return Ok(0),
+        };
+        if key == c"shape" {
+            ffi::lua_createtable(lua, array.ndim() as _, 0);
+            for (position, &len) in array.shape().iter().enumerate() {
+                ffi::lua_pushinteger(lua, len as ffi::lua_Integer);
+                ffi::lua_rawseti(lua, -2, (position + 1) as _);
+            }
+        } else if key == c"ndim" {
+            ffi::lua_pushinteger(lua, array.ndim() as ffi::lua_Integer);
+        } else {
+            match METHODS.iter().find(|(name, _)| *name == key) {
+                Some((_, method)) => ffi::lua_pushcfunction(lua, *method),
+                None => ffi::lua_pushnil(lua),
+            }
+        }
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn new_index(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let array = array_arg(lua)?;
+        if array.ndim() != 1 {
+            return Err("only one-dimensional arrays can be indexed, use set".to_owned());
+        }
+        store(lua, array, 2)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn len(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let array = array_arg(lua)?;
+        let len = array.shape().first().copied().unwrap_or(0);
+        ffi::lua_pushinteger(lua, len as ffi::lua_Integer);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn get(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let array = array_arg(lua)?;
+        let indices = indices_args(lua, 2, ffi::lua_gettop(lua))?;
+        match array.get(&indices) {
+            Some(value) => ffi::lua_pushnumber(lua, value),
+            None => return Err(out_of_bounds(array, &indices)),
+        }
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn set(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let array = array_arg(lua)?;
+        store(lua, array, 2)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn size(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let array = array_arg(lua)?;
+        ffi::lua_pushinteger(lua, array.as_slice().len() as ffi::lua_Integer);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+// Stores the value at the top of the stack at the indices starting at `first`.
+unsafe fn store(
+    lua: *mut ffi::lua_State,
+    array: &mut LuaArrayD,
+    first: libc::c_int,
+) -> Result<libc::c_int, String> {
+    let top = ffi::lua_gettop(lua);
+    let value = match number_arg(lua, top) {
+        Some(value) if top > first => value,
+        _ => return Err("the value stored in an array must be a number".to_owned()),
+    };
+    let indices = indices_args(lua, first, top - 1)?;
+    match array.get_mut(&indices) {
+        Some(element) => *element = value,
+        None => return Err(out_of_bounds(array, &indices)),
+    }
+    Ok(0)
+}
+
+fn out_of_bounds(array: &LuaArrayD, indices: &[usize]) -> String {
+    let indices: Vec<String> = indices.iter().map(|index| (index + 1).to_string()).collect();
+    format!("index ({}) out of bounds for an array of shape {:?}", indices.join(", "), array.shape)
+}
+
+// Conversions between `LuaArrayD` and `ndarray::ArrayD<f64>`, which is pushed and read as nested
+// tables like a `LuaArrayD`.
+#[cfg(feature = "impl-ndarray")]
+mod ndarray_impl {
+    use ndarray::{ArrayD, IxDyn};
+
+    use super::LuaArrayD;
+    use crate::{AsLua, AsMutLua, LuaRead, Push, PushGuard, PushOne, Void};
+
+    impl From<ArrayD<f64>> for LuaArrayD {
+        fn from(array: ArrayD<f64>) -> LuaArrayD {
+            let shape = array.shape().to_vec();
+            let len = array.len();
+            let data = match array.is_standard_layout() {
+                // The elements are contiguous, but may be preceded and followed by others if the
+                // array is a slice of a larger one.
+                true => match array.into_raw_vec_and_offset() {
+                    (data, Some(0)) if data.len() == len => data,
+                    (data, offset) => data[offset.unwrap_or(0)..][..len].to_vec(),
+                },
+                false => array.iter().copied().collect(),
+            };
+            LuaArrayD { shape, data }
+        }
+    }
+
+    impl From<LuaArrayD> for ArrayD<f64> {
+        #[inline]
+        fn from(array: LuaArrayD) -> ArrayD<f64> {
+            ArrayD::from_shape_vec(IxDyn(&array.shape), array.data)
+                .expect("the number of elements of a LuaArrayD matches its shape")
+        }
+    }
+
+    impl<'lua, L> Push<L> for ArrayD<f64>
+    where
+        L: AsMutLua<'lua>,
+    {
+        type Err = Void;
+
+        #[inline]
+        fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+            LuaArrayD::from(self).push_to_lua(lua)
+        }
+    }
+
+    impl<'lua, L> PushOne<L> for ArrayD<f64> where L: AsMutLua<'lua> {}
+
+    impl<'lua, L> LuaRead<L> for ArrayD<f64>
+    where
+        L: AsLua<'lua>,
+    {
+        #[inline]
+        fn lua_read_at_position(lua: L, index: i32) -> Result<ArrayD<f64>, L> {
+            LuaArrayD::lua_read_at_position(lua, index).map(ArrayD::from)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, LuaArrayD, LuaError, PackedLuaArrayD};
+
+    #[test]
+    fn nested_tables() {
+        let mut lua = Lua::new();
+        let array = LuaArrayD::new(vec![2, 1, 3], (1..=6).map(f64::from).collect());
+        lua.set("a", &array);
+        let element: f64 = lua.execute("return a[2][1][1]").unwrap();
+        assert_eq!(element, 4.0);
+        assert_eq!(lua.get::<LuaArrayD, _>("a"), Some(array));
+
+        let scalar: LuaArrayD = lua.execute("return 2.5").unwrap();
+        assert_eq!((scalar.ndim(), scalar.as_slice()), (0, &[2.5][..]));
+        let empty: LuaArrayD = lua.execute("return {}").unwrap();
+        assert_eq!(empty.shape(), &[0]);
+
+        for code in [
+            "return { { 1, 2 }, { 3 } }",
+            "return { { 1, 2 }, 3 }",
+            "return { 1, '2' }",
+            "local t = {} t[1] = t return t",
+        ] {
+            assert!(lua.execute::<LuaArrayD>(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn packed() {
+        let mut lua = Lua::new();
+        let mut array = LuaArrayD::zeros(vec![2, 3]);
+        *array.get_mut(&[1, 2]).unwrap() = 7.0;
+        lua.set("a", PackedLuaArrayD(array));
+        lua.set("v", PackedLuaArrayD(LuaArrayD::new(vec![3], vec![1.0, 2.0, 3.0])));
+
+        let code = "a:set(1, 1, a:get(2, 3) + #a + a.ndim + a:size())
+                    v[2] = v[1] + v[3]
+                    return a.shape[2] * 10 + #v";
+        assert_eq!(lua.execute::<i32>(code).unwrap(), 33);
+        assert_eq!(lua.get::<LuaArrayD, _>("a").unwrap().get(&[0, 0]), Some(17.0));
+        assert_eq!(lua.get::<LuaArrayD, _>("v").unwrap().as_slice(), &[1.0, 4.0, 3.0]);
+        assert_eq!(lua.execute::<Option<f64>>("return v[4]").unwrap(), None);
+
+        match lua.execute::<()>("a:get(3, 1)") {
+            Err(LuaError::ExecutionError(msg)) => {
+                assert!(msg
+                    .message()
+                    .ends_with("index (3, 1) out of bounds for an array of shape [2, 3]"));
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(lua.execute::<()>("a[1] = 5").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "impl-ndarray")]
+    fn ndarray() {
+        use ndarray::{s, ArrayD, IxDyn};
+
+        let mut lua = Lua::new();
+        let array = ArrayD::from_shape_fn(IxDyn(&[3, 2]), |i| (i[0] * 2 + i[1]) as f64 + 0.5);
+        lua.set("a", array.clone());
+        assert_eq!(lua.execute::<f64>("return a[3][2]").unwrap(), 5.5);
+        assert_eq!(lua.get::<ArrayD<f64>, _>("a"), Some(array.clone()));
+
+        // Slices and transposed arrays keep their logical order.
+        let mut rows = array.clone();
+        rows.slice_collapse(s![1.., ..]);
+        assert_eq!(LuaArrayD::from(rows).as_slice(), &[2.5, 3.5, 4.5, 5.5]);
+        let packed = PackedLuaArrayD(LuaArrayD::from(array.reversed_axes()));
+        lua.set("t", packed);
+        assert_eq!(lua.execute::<f64>("return t:get(2, 3)").unwrap(), 5.5);
+    }
+}
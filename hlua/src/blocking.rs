@@ -0,0 +1,291 @@
+use std::{
+    fmt,
+    future::Future,
+    ops::{Deref, DerefMut},
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use crate::{Lua, LuaError, LuaRead, PushGuard};
+
+// Lets a `Lua` be stored in a `Mutex` that is shared between threads. See `SendLua::new`.
+struct AssertSend(Lua<'static>);
+
+// SAFETY: The Lua state itself isn't tied to a thread, and the `Mutex` of `SendLua` ensures that
+// a single thread uses it at a time. `Lua` isn't `Send` because of the Rust values stored in it,
+// which the caller of `SendLua::new` guarantees can be used from any thread, and which can't
+// borrow the stack of the creating thread since the context is `Lua<'static>`. `LuaHandle`s
+// check that they are used from the thread that created the context, so they refuse to access
+// it once it has moved.
+unsafe impl Send for AssertSend {}
+
+/// Lua context that can be moved to and used from other threads, one at a time.
+///
+/// `Lua` isn't `Send`, because the context can contain Rust values, such as the closures passed
+/// to `function0` and similar functions, that must stay on the thread that created them. A
+/// `SendLua` wraps a context whose owner guarantees it doesn't contain any. Clones of a
+/// `SendLua` refer to the same context, and accesses from several threads are serialized.
+///
+/// Its main use is `spawn_blocking_execute`, which lets async code await the result of a script
+/// without blocking the executor.
+///
+/// `LuaHandle`s access the context from the thread that created it, so they stop working once
+/// the context is used from another thread.
+#[derive(Clone)]
+pub struct SendLua {
+    lua: Arc<Mutex<AssertSend>>,
+}
+
+/// Exclusive access to the context of a `SendLua`, returned by `SendLua::lock`.
+pub struct SendLuaGuard<'a> {
+    guard: MutexGuard<'a, AssertSend>,
+}
+
+impl fmt::Debug for SendLua {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SendLua").finish_non_exhaustive()
+    }
+}
+
+impl SendLua {
+    /// Wraps a context so that it can be used from other threads.
+    ///
+    /// # Safety
+    ///
+    /// All the Rust values stored in the context, now or later, must be safe to use from another
+    /// thread, as if they were `Send`. For example, the context must not contain closures that
+    /// capture an `Rc`, or that use thread-local variables.
+    #[inline]
+    pub unsafe fn new(lua: Lua<'static>) -> SendLua {
+        SendLua { lua: Arc::new(Mutex::new(AssertSend(lua))) }
+    }
+
+    /// Gives access to the context, waiting for other threads using it to be done.
+    #[inline]
+    pub fn lock(&self) -> SendLuaGuard<'_> {
+        let guard = self.lua.lock().unwrap_or_else(|err| err.into_inner());
+        SendLuaGuard { guard }
+    }
+
+    /// Executes some Lua code on another thread.
+    ///
+    /// With the `tokio` feature, the code runs on the blocking thread pool of the Tokio runtime
+    /// this method is called from, with `tokio::task::spawn_blocking`. Otherwise, or when it is
+    /// called outside of a runtime, the code runs on a new thread.
+    ///
+    /// The returned future resolves to the result of the code, like the one of `Lua::execute`.
+    /// It can be awaited on any executor, and doesn't block it while the code runs. The code
+    /// keeps running if the future is dropped. Executions started from clones of the same
+    /// `SendLua` run one after the other.
+    ///
+    /// If the execution panics, the panic is resumed when the future is polled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    /// #     use std::task::{Context, Poll, Wake, Waker};
+    /// #     struct Thread(std::thread::Thread);
+    /// #     impl Wake for Thread {
+    /// #         fn wake(self: std::sync::Arc<Self>) { self.0.unpark() }
+    /// #     }
+    /// #     let waker = Waker::from(std::sync::Arc::new(Thread(std::thread::current())));
+    /// #     let mut future = std::pin::pin!(future);
+    /// #     loop {
+    /// #         match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+    /// #             Poll::Ready(output) => return output,
+    /// #             Poll::Pending => std::thread::park(),
+    /// #         }
+    /// #     }
+    /// # }
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// // Safe because the context doesn't contain any Rust value.
+    /// let lua = unsafe { hlua::SendLua::new(lua) };
+    ///
+    /// let code = "local n = 0 for i = 1, 100 do n = n + i end return n";
+    /// let future = lua.spawn_blocking_execute::<i32>(code);
+    /// // With an async runtime: `let sum = future.await.unwrap();`
+    /// let sum = block_on(future).unwrap();
+    /// assert_eq!(sum, 5050);
+    /// ```
+    pub fn spawn_blocking_execute<T>(&self, code: impl Into<String>) -> BlockingExecute<T>
+    where
+        T: for<'a, 'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'static>>>>,
+        T: Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(Shared { output: None, waker: None }));
+        let lua = self.clone();
+        let code = code.into();
+
+        let sender = shared.clone();
+        spawn_blocking(move || {
+            let output = panic::catch_unwind(AssertUnwindSafe(|| lua.lock().execute::<T>(&code)));
+
+            let mut shared = sender.lock().unwrap_or_else(|err| err.into_inner());
+            shared.output = Some(output);
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        });
+
+        BlockingExecute { shared }
+    }
+}
+
+// Runs `f` on the blocking threads of the current Tokio runtime if there is one, otherwise on a
+// new thread.
+fn spawn_blocking(f: impl FnOnce() + Send + 'static) {
+    #[cfg(feature = "tokio")]
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        drop(runtime.spawn_blocking(f));
+        return;
+    }
+    thread::spawn(f);
+}
+
+impl<'a> Deref for SendLuaGuard<'a> {
+    type Target = Lua<'static>;
+
+    #[inline]
+    fn deref(&self) -> &Lua<'static> {
+        &self.guard.0
+    }
+}
+
+impl<'a> DerefMut for SendLuaGuard<'a> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Lua<'static> {
+        &mut self.guard.0
+    }
+}
+
+impl<'a> fmt::Debug for SendLuaGuard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SendLuaGuard").finish_non_exhaustive()
+    }
+}
+
+// State shared between a `BlockingExecute` and the thread running the code.
+struct Shared<T> {
+    output: Option<thread::Result<Result<T, LuaError>>>,
+    waker: Option<Waker>,
+}
+
+/// Future returned by `SendLua::spawn_blocking_execute`.
+#[must_use = "futures do nothing unless polled"]
+pub struct BlockingExecute<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> fmt::Debug for BlockingExecute<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlockingExecute").finish_non_exhaustive()
+    }
+}
+
+impl<T> Future for BlockingExecute<T> {
+    type Output = Result<T, LuaError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, LuaError>> {
+        let mut shared = self.shared.lock().unwrap_or_else(|err| err.into_inner());
+        match shared.output.take() {
+            Some(Ok(output)) => Poll::Ready(output),
+            Some(Err(payload)) => {
+                drop(shared);
+                panic::resume_unwind(payload)
+            },
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, Wake, Waker},
+        thread::{self, Thread},
+    };
+
+    use crate::{Lua, LuaError, SendLua};
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut future = pin!(future);
+        loop {
+            match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn state_is_kept() {
+        let lua = unsafe { SendLua::new(Lua::new()) };
+        block_on(lua.spawn_blocking_execute::<()>("counter = 1")).unwrap();
+
+        let futures: Vec<_> =
+            (0..4).map(|_| lua.spawn_blocking_execute::<()>("counter = counter * 2")).collect();
+        for future in futures {
+            block_on(future).unwrap();
+        }
+
+        let counter: i32 = lua.lock().get("counter").unwrap();
+        assert_eq!(counter, 16);
+    }
+
+    #[test]
+    fn errors() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let lua = unsafe { SendLua::new(lua) };
+        match block_on(lua.spawn_blocking_execute::<()>("error('boom', 0)")) {
+            Err(LuaError::ExecutionError(msg)) => assert_eq!(msg.message(), "boom"),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            block_on(lua.spawn_blocking_execute::<i32>("return 'x'")),
+            Err(LuaError::WrongType)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn tokio_blocking_pool() {
+        let mut lua = Lua::new();
+        let thread_name = || thread::current().name().map(str::to_owned);
+        lua.set("thread_name", crate::function0(thread_name));
+        // Safe because the function can be called from any thread.
+        let lua = unsafe { SendLua::new(lua) };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .thread_name("script-pool")
+            .build()
+            .unwrap();
+        let code = "return thread_name()";
+        let name = runtime.block_on(async { lua.spawn_blocking_execute::<String>(code).await });
+        assert_eq!(name.unwrap(), "script-pool");
+
+        // Outside of the runtime, the code runs on a new thread.
+        let name = runtime.block_on(lua.spawn_blocking_execute::<Option<String>>(code));
+        assert_eq!(name.unwrap(), None);
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::actor::WeakActor;
+use crate::{AnyLuaValue, Lua, LuaActor, LuaError, LuaFunction, LuaFunctionCallError, LuaHandle};
+
+type ErrorHandler = Arc<dyn Fn(&str, &LuaError) + Send + Sync>;
+
+/// Delivers the messages published on named topics to the Lua contexts that subscribed to them.
+///
+/// A subscription names a global function of a context, which is called with the payload and
+/// the topic for every message published on the topic. The contexts can be on any thread: each
+/// handler runs on the thread owning its context, through `LuaHandle::defer` for the contexts
+/// subscribed with `subscribe`, and as a message to the actor for the ones subscribed with
+/// `subscribe_actor`. This means that a handler runs as soon as the context is neither busy nor
+/// waiting for another thread, and that the messages of a topic reach each context in the
+/// order in which they were published.
+///
+/// The payload is an `AnyLuaValue`, which each context receives a copy of. With the `serde`
+/// feature, `to_lua` turns any serializable value into one. The contexts that are closed are
+/// unsubscribed the next time a message is published.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{AnyLuaValue, Broadcast, LuaActor};
+///
+/// let hub = Broadcast::new();
+/// let world = LuaActor::spawn(|lua| {
+///     lua.execute::<()>("reloads = 0 function on_reload(path) reloads = reloads + 1 end")
+///         .unwrap();
+/// });
+/// hub.subscribe_actor(&world, "reload_assets", "on_reload");
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.execute::<()>("function on_reload(path, topic) last = topic .. ':' .. path end").unwrap();
+/// hub.subscribe(&mut lua, "reload_assets", "on_reload");
+///
+/// let path = AnyLuaValue::LuaString("textures/".to_owned());
+/// assert_eq!(hub.publish("reload_assets", path), 2);
+///
+/// assert_eq!(lua.get::<String, _>("last").unwrap(), "reload_assets:textures/");
+/// assert_eq!(world.execute::<i32>("return reloads").wait().unwrap().unwrap(), 1);
+/// ```
+#[derive(Clone, Default)]
+pub struct Broadcast {
+    hub: Arc<Mutex<Hub>>,
+}
+
+/// Identifies a subscription, to cancel it with `Broadcast::unsubscribe`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+#[derive(Default)]
+struct Hub {
+    topics: HashMap<String, Vec<Subscriber>>,
+    next_id: u64,
+    on_error: Option<ErrorHandler>,
+}
+
+struct Subscriber {
+    id: SubscriptionId,
+    target: Target,
+    handler: String,
+}
+
+#[derive(Clone)]
+enum Target {
+    Handle(LuaHandle),
+    Actor(WeakActor),
+}
+
+impl Target {
+    fn is_alive(&self) -> bool {
+        match self {
+            Target::Handle(handle) => !handle.is_closed(),
+            Target::Actor(actor) => actor.is_alive(),
+        }
+    }
+}
+
+impl Broadcast {
+    /// Creates a hub without subscriptions. Clones of the hub share its subscriptions.
+    #[inline]
+    pub fn new() -> Broadcast {
+        Broadcast::default()
+    }
+
+    /// Makes the global function `handler` of `lua` receive the messages published on `topic`.
+    ///
+    /// The function is looked up when a message arrives, so it can be redefined afterwards.
+    pub fn subscribe(&self, lua: &mut Lua, topic: &str, handler: &str) -> SubscriptionId {
+        self.add(topic, Target::Handle(lua.handle()), handler)
+    }
+
+    /// Makes the global function `handler` of the context of `actor` receive the messages
+    /// published on `topic`. The subscription doesn't keep the actor running.
+    pub fn subscribe_actor(&self, actor: &LuaActor, topic: &str, handler: &str) -> SubscriptionId {
+        self.add(topic, Target::Actor(actor.downgrade()), handler)
+    }
+
+    /// Cancels a subscription. Returns false if it had already been cancelled.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut hub = self.lock();
+        for subscribers in hub.topics.values_mut() {
+            if let Some(position) = subscribers.iter().position(|s| s.id == id) {
+                subscribers.remove(position);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Sets a function called with the topic and the error when a handler raises an error or
+    /// doesn't exist. It is called on the thread of the context of the handler.
+    pub fn on_error(&self, on_error: impl Fn(&str, &LuaError) + Send + Sync + 'static) {
+        self.lock().on_error = Some(Arc::new(on_error));
+    }
+
+    /// Sends `payload` to the handlers subscribed to `topic`, and returns how many there are.
+    ///
+    /// The handlers of the contexts owned by the current thread that aren't busy run before
+    /// this returns. The others run later.
+    pub fn publish(&self, topic: &str, payload: AnyLuaValue) -> usize {
+        // The handlers are started once the hub is unlocked, since they can publish too.
+        let (deliveries, on_error) = {
+            let mut hub = self.lock();
+            let on_error = hub.on_error.clone();
+            let deliveries: Vec<_> = match hub.topics.get_mut(topic) {
+                Some(subscribers) => {
+                    subscribers.retain(|subscriber| subscriber.target.is_alive());
+                    subscribers.iter().map(|s| (s.target.clone(), s.handler.clone())).collect()
+                },
+                None => Vec::new(),
+            };
+            (deliveries, on_error)
+        };
+
+        let count = deliveries.len();
+        for (target, handler) in deliveries {
+            let topic = topic.to_owned();
+            let payload = payload.clone();
+            let on_error = on_error.clone();
+            let deliver = move |lua: &mut Lua<'static>| {
+                if let Err(err) = call_handler(lua, &handler, &topic, payload) {
+                    if let Some(on_error) = on_error {
+                        on_error(&topic, &err);
+                    }
+                }
+            };
+
+            match target {
+                Target::Handle(handle) => handle.defer(deliver),
+                Target::Actor(actor) => {
+                    actor.run(deliver);
+                },
+            }
+        }
+        count
+    }
+
+    fn add(&self, topic: &str, target: Target, handler: &str) -> SubscriptionId {
+        let mut hub = self.lock();
+        let id = SubscriptionId(hub.next_id);
+        hub.next_id += 1;
+        let subscriber = Subscriber { id, target, handler: handler.to_owned() };
+        hub.topics.entry(topic.to_owned()).or_default().push(subscriber);
+        id
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Hub> {
+        // The hub is never left in an inconsistent state, so a panic while it was locked
+        // doesn't matter.
+        self.hub.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for Broadcast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hub = self.lock();
+        let mut topics: Vec<_> = hub.topics.iter().map(|(t, s)| (t, s.len())).collect();
+        topics.sort();
+        f.debug_struct("Broadcast").field("topics", &topics).finish_non_exhaustive()
+    }
+}
+
+fn call_handler(
+    lua: &mut Lua<'static>,
+    handler: &str,
+    topic: &str,
+    payload: AnyLuaValue,
+) -> Result<(), LuaError> {
+    let mut function: LuaFunction<_> = match lua.get(handler) {
+        Some(function) => function,
+        None => {
+            let msg = format!("global '{}' is not a function", handler);
+            return Err(LuaError::ExecutionError(msg.into()));
+        },
+    };
+
+    match function.call_with_args::<(), _, _>((payload, topic)) {
+        Ok(()) => Ok(()),
+        Err(LuaFunctionCallError::LuaError(err)) => Err(err),
+        Err(LuaFunctionCallError::PushError(_)) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{AnyLuaValue, Broadcast, Lua, LuaActor};
+
+    #[test]
+    fn subscriptions() {
+        let hub = Broadcast::new();
+        let mut lua = Lua::new();
+        lua.execute::<()>("count = 0 function on_tick(n) count = count + n end").unwrap();
+        let id = hub.subscribe(&mut lua, "tick", "on_tick");
+        hub.subscribe(&mut lua, "other", "on_tick");
+
+        assert_eq!(hub.publish("tick", AnyLuaValue::LuaInteger(2)), 1);
+        assert_eq!(hub.publish("nobody", AnyLuaValue::LuaNil), 0);
+        assert!(hub.unsubscribe(id));
+        assert!(!hub.unsubscribe(id));
+        assert_eq!(hub.publish("tick", AnyLuaValue::LuaInteger(5)), 0);
+        assert_eq!(lua.get::<i32, _>("count").unwrap(), 2);
+
+        drop(lua);
+        assert_eq!(hub.publish("other", AnyLuaValue::LuaInteger(1)), 0);
+    }
+
+    #[test]
+    fn actors_and_errors() {
+        let hub = Broadcast::new();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let sink = errors.clone();
+        hub.on_error(move |topic, err| sink.lock().unwrap().push(format!("{}: {}", topic, err)));
+
+        let actor = LuaActor::spawn(|lua| {
+            lua.openlibs();
+            lua.execute::<()>("function fail() error('handler failed') end").unwrap();
+        });
+        hub.subscribe_actor(&actor, "event", "fail");
+        hub.subscribe_actor(&actor, "event", "missing");
+
+        assert_eq!(hub.publish("event", AnyLuaValue::LuaNil), 2);
+        // The actor processes its messages in order, so the handlers have run after this.
+        actor.run(|_| ()).wait().unwrap();
+        let errors = errors.lock().unwrap().clone();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("handler failed"), "{:?}", errors);
+        assert!(errors[1].contains("'missing'"), "{:?}", errors);
+
+        drop(actor);
+        assert_eq!(hub.publish("event", AnyLuaValue::LuaNil), 0);
+    }
+}
@@ -0,0 +1,512 @@
+use std::{
+    ffi::CStr,
+    mem,
+    ops::{BitOr, BitOrAssign},
+    ptr,
+};
+
+use crate::init;
+use crate::lua_functions::GuardedCalls;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, values, Lua, LuaContext, Push};
+
+// Key of the registry entry holding the `InstructionLimit` of a context.
+const INSTRUCTION_LIMIT_KEY: &CStr = c"hlua.instruction_limit";
+
+/// Set of standard libraries, opened by `LuaOptions::openlibs`.
+///
+/// Sets are combined with `|`. The libraries that don't exist in the Lua version that hlua is
+/// built with, such as `utf8` before Lua 5.3, are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LibSet(u16);
+
+impl LibSet {
+    /// No library.
+    pub const NONE: LibSet = LibSet(0);
+    /// The base library: `print`, `pairs`, `pcall`, `tostring`, etc.
+    pub const BASE: LibSet = LibSet(1 << 0);
+    /// The `coroutine` library. Part of the base library in Lua 5.1.
+    pub const COROUTINE: LibSet = LibSet(1 << 1);
+    /// The `table` library.
+    pub const TABLE: LibSet = LibSet(1 << 2);
+    /// The `io` library.
+    pub const IO: LibSet = LibSet(1 << 3);
+    /// The `os` library.
+    pub const OS: LibSet = LibSet(1 << 4);
+    /// The `string` library.
+    pub const STRING: LibSet = LibSet(1 << 5);
+    /// The `math` library.
+    pub const MATH: LibSet = LibSet(1 << 6);
+    /// The `utf8` library of Lua 5.4.
+    pub const UTF8: LibSet = LibSet(1 << 7);
+    /// The `debug` library.
+    pub const DEBUG: LibSet = LibSet(1 << 8);
+    /// The `package` library, with `require`.
+    pub const PACKAGE: LibSet = LibSet(1 << 9);
+    /// The `bit32` library of Lua 5.2, or the `bit` library of LuaJIT.
+    pub const BIT: LibSet = LibSet(1 << 10);
+    /// The libraries that don't give access to the file system, the process or the internals of
+    /// the interpreter: the base library, `coroutine`, `table`, `string`, `math`, `utf8` and
+    /// `bit32`.
+    ///
+    /// Note that `dofile` and `loadfile` of the base library can still read files. Remove them
+    /// for a complete sandbox.
+    pub const SAFE: LibSet = LibSet(
+        LibSet::BASE.0
+            | LibSet::COROUTINE.0
+            | LibSet::TABLE.0
+            | LibSet::STRING.0
+            | LibSet::MATH.0
+            | LibSet::UTF8.0
+            | LibSet::BIT.0,
+    );
+    /// All the libraries, like `Lua::openlibs`.
+    pub const ALL: LibSet = LibSet((1 << 11) - 1);
+
+    /// Returns true if all the libraries of `other` are in `self`.
+    #[inline]
+    pub fn contains(self, other: LibSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for LibSet {
+    type Output = LibSet;
+
+    #[inline]
+    fn bitor(self, other: LibSet) -> LibSet {
+        LibSet(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for LibSet {
+    #[inline]
+    fn bitor_assign(&mut self, other: LibSet) {
+        self.0 |= other.0;
+    }
+}
+
+/// Options of a new Lua context, created with `Lua::builder`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{LibSet, Lua};
+///
+/// let mut lua = Lua::builder()
+///     .openlibs(LibSet::SAFE)
+///     .memory_limit(16 * 1024 * 1024)
+///     .instruction_limit(1_000_000)
+///     .strict_globals(true)
+///     .build();
+///
+/// assert_eq!(lua.execute::<String>("return string.rep('a', 3)").unwrap(), "aaa");
+/// assert!(lua.execute::<()>("while true do end").is_err());
+/// assert!(lua.execute::<()>("return undefined_variable").is_err());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LuaOptions {
+    libs: LibSet,
+    memory_limit: Option<usize>,
+    instruction_limit: Option<u32>,
+    strict_globals: bool,
+}
+
+impl<'lua> Lua<'lua> {
+    /// Starts building a new context with options. `Lua::builder().build()` is the same as
+    /// `Lua::new()`.
+    #[inline]
+    pub fn builder() -> LuaOptions {
+        LuaOptions::default()
+    }
+}
+
+impl LuaOptions {
+    /// Opens the libraries of `libs`, in addition to the ones of the previous calls.
+    #[inline]
+    pub fn openlibs(mut self, libs: LibSet) -> LuaOptions {
+        self.libs |= libs;
+        self
+    }
+
+    /// Limits the memory that the context can allocate to `bytes`.
+    ///
+    /// When the limit is reached, the code that tried to allocate fails with a "not enough
+    /// memory" error, which is returned as a `LuaError::MemoryLimitExceeded`. Allocations made
+    /// outside of Lua code, for example when pushing a large value with `Lua::set`, panic
+    /// instead. The limit can be changed later with `Lua::set_memory_limit`.
+    ///
+    /// Not available with LuaJIT, which doesn't support custom allocators on 64 bits platforms.
+    #[inline]
+    #[cfg(not(feature = "_luaapi_lj2"))]
+    pub fn memory_limit(mut self, bytes: usize) -> LuaOptions {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Limits the number of virtual machine instructions that each call into Lua can run, such
+    /// as `Lua::execute` or `LuaFunction::call`. Calls that run longer fail with an
+    /// "instruction limit exceeded" error.
+    ///
+    /// Calls made from Rust callbacks while a call is running share its budget. With LuaJIT,
+    /// compiled code isn't counted.
+    #[inline]
+    pub fn instruction_limit(mut self, instructions: u32) -> LuaOptions {
+        self.instruction_limit = Some(instructions);
+        self
+    }
+
+    /// If true, reading a global variable that doesn't exist from Lua code raises an error
+    /// instead of returning nil. This catches typos in variable names.
+    ///
+    /// Reading it from Rust, for example with `Lua::get`, still returns `None`.
+    #[inline]
+    pub fn strict_globals(mut self, strict: bool) -> LuaOptions {
+        self.strict_globals = strict;
+        self
+    }
+
+    /// Creates the context.
+    ///
+    /// The function set with `set_global_init` runs after the options are applied.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the context can't be allocated.
+    pub fn build<'lua>(self) -> Lua<'lua> {
+        let mut lua = unsafe { Lua::from_new_state(new_state(self.memory_limit)) };
+
+        unsafe {
+            open_libs(lua.lua, self.libs);
+            if let Some(limit) = self.instruction_limit {
+                let count = limit.min(i32::MAX as u32) as i32;
+                let limit = InstructionLimit { count, depth: 0, _guarded: GuardedCalls::new() };
+                push_userdata(limit, lua.lua, |_| {}).forget();
+                let raw_lua = lua.lua.as_ptr();
+                ffi::lua_setfield(raw_lua, ffi::LUA_REGISTRYINDEX, INSTRUCTION_LIMIT_KEY.as_ptr());
+            }
+            if self.strict_globals {
+                set_strict_globals(lua.lua);
+            }
+        }
+
+        init::run_global_init(&mut lua);
+        lua
+    }
+}
+
+unsafe fn open_libs(lua: LuaContext, libs: LibSet) {
+    if libs.contains(LibSet::ALL) {
+        ffi::luaL_openlibs(lua.as_ptr());
+        return;
+    }
+
+    let mut opened: Vec<(LibSet, &CStr, ffi::lua_CFunction)> = vec![
+        (LibSet::BASE, c"_G", Some(ffi::luaopen_base)),
+        (LibSet::PACKAGE, c"package", Some(ffi::luaopen_package)),
+        (LibSet::TABLE, c"table", Some(ffi::luaopen_table)),
+        (LibSet::IO, c"io", Some(ffi::luaopen_io)),
+        (LibSet::OS, c"os", Some(ffi::luaopen_os)),
+        (LibSet::STRING, c"string", Some(ffi::luaopen_string)),
+        (LibSet::MATH, c"math", Some(ffi::luaopen_math)),
+        (LibSet::DEBUG, c"debug", Some(ffi::luaopen_debug)),
+    ];
+    #[cfg(not(feature = "_luaapi_51"))]
+    opened.push((LibSet::COROUTINE, c"coroutine", Some(ffi::luaopen_coroutine)));
+    #[cfg(feature = "_luaapi_52")]
+    opened.push((LibSet::BIT, c"bit32", Some(ffi::luaopen_bit32)));
+    #[cfg(feature = "_luaapi_lj2")]
+    opened.push((LibSet::BIT, c"bit", Some(ffi::luaopen_bit)));
+    #[cfg(feature = "_luaapi_54")]
+    opened.push((LibSet::UTF8, c"utf8", Some(ffi::luaopen_utf8)));
+
+    for (lib, name, open) in opened {
+        if !libs.contains(lib) {
+            continue;
+        }
+        match () {
+            #[cfg(feature = "_luaapi_51")]
+            () => {
+                ffi::lua_pushcfunction(lua.as_ptr(), open);
+                ffi::lua_pushstring(lua.as_ptr(), name.as_ptr());
+                ffi::lua_call(lua.as_ptr(), 1, 0);
+            },
+            #[cfg(not(feature = "_luaapi_51"))]
+            () => {
+                ffi::luaL_requiref(lua.as_ptr(), name.as_ptr(), open, 1);
+                ffi::lua_pop(lua.as_ptr(), 1);
+            },
+        }
+    }
+}
+
+// Installs a metatable on the globals table whose `__index` raises an error when called from Lua.
+unsafe fn set_strict_globals(lua: LuaContext) {
+    let l = lua.as_ptr();
+    ffix::lua_pushglobaltable(lua);
+    ffi::lua_createtable(l, 0, 1);
+    ffi::lua_pushcfunction(l, Some(strict_index));
+    ffi::lua_setfield(l, -2, c"__index".as_ptr());
+    ffi::lua_setmetatable(l, -2);
+    ffi::lua_pop(l, 1);
+}
+
+extern "C" fn strict_index(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let mut debug: ffi::lua_Debug = mem::zeroed();
+        let from_lua = ffi::lua_getstack(lua, 1, &mut debug) != 0
+            && ffi::lua_getinfo(lua, c"S".as_ptr(), &mut debug) != 0
+            && CStr::from_ptr(debug.what).to_bytes() != b"C";
+        if !from_lua {
+            ffi::lua_pushnil(lua);
+            return 1;
+        }
+
+        let ctx = LuaContext::new_unchecked(lua);
+        let name = match values::string_bytes(ctx, 2) {
+            Some(name) => String::from_utf8_lossy(name).into_owned(),
+            None => "?".to_owned(),
+        };
+        let msg = format!("undefined global variable '{}'", name);
+        ffi::luaL_where(lua, 1);
+        msg.push_no_err(ctx).forget();
+        ffi::lua_concat(lua, 2);
+        ffix::lua_error(lua);
+    }
+}
+
+// `count` is the number of instructions that a call can run, and `depth` the number of calls
+// currently running, so that only the outermost one resets the budget.
+struct InstructionLimit {
+    count: libc::c_int,
+    depth: usize,
+    _guarded: GuardedCalls,
+}
+
+/// Gives a new instruction budget to a call into Lua, if the context has an instruction limit
+/// and no other call is running.
+pub(crate) struct InstructionBudget {
+    limit: Option<*mut InstructionLimit>,
+}
+
+impl InstructionBudget {
+    pub(crate) unsafe fn enter(lua: LuaContext, thread: LuaContext) -> InstructionBudget {
+        if !GuardedCalls::any() {
+            return InstructionBudget { limit: None };
+        }
+        let l = lua.as_ptr();
+        ffi::lua_getfield(l, ffi::LUA_REGISTRYINDEX, INSTRUCTION_LIMIT_KEY.as_ptr());
+        // The userdata stays alive in the registry, so the pointer can be used after the pop.
+        let limit =
+            userdata_mut::<InstructionLimit>(lua, -1).map(|limit| limit as *mut InstructionLimit);
+        ffi::lua_pop(l, 1);
+
+        if let Some(limit) = limit {
+            if (*limit).depth == 0 {
+                // Setting the hook resets its instruction count.
+                let mask = ffi::LUA_MASKCOUNT;
+                ffi::lua_sethook(thread.as_ptr(), Some(instruction_hook), mask, (*limit).count);
+            }
+            (*limit).depth += 1;
+        }
+        InstructionBudget { limit }
+    }
+}
+
+impl Drop for InstructionBudget {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(limit) = self.limit {
+            unsafe { (*limit).depth -= 1 };
+        }
+    }
+}
+
+unsafe extern "C" fn instruction_hook(lua: *mut ffi::lua_State, _: *mut ffi::lua_Debug) {
+    "instruction limit exceeded".push_no_err(LuaContext::new_unchecked(lua)).forget();
+    ffix::lua_error(lua);
+}
+
+// Memory used by a context created by hlua, owned by the context.
+struct MemoryLimit {
+    used: usize,
+    limit: usize,
+}
+
+impl<'lua> Lua<'lua> {
+    /// Limits the memory that the context can allocate to `bytes`, replacing the previous limit.
+    /// Pass `usize::MAX` to remove the limit.
+    ///
+    /// This works like `LuaOptions::memory_limit`: Lua code that allocates beyond the limit
+    /// fails with `LuaError::MemoryLimitExceeded`. If the context already uses more than `bytes`,
+    /// it can still run code that doesn't allocate more, and the garbage collector can free some
+    /// memory.
+    ///
+    /// Returns false without changing anything if the context wasn't created by hlua, for
+    /// example with `Lua::from_existing_state`.
+    ///
+    /// Not available with LuaJIT, which doesn't support custom allocators on 64 bits platforms.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, LuaError};
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.openlibs();
+    /// lua.set_memory_limit(1024 * 1024);
+    ///
+    /// let result = lua.execute::<()>("local t = {} for i = 1, 1e7 do t[i] = i end");
+    /// assert!(matches!(result, Err(LuaError::MemoryLimitExceeded)));
+    /// ```
+    #[cfg(not(feature = "_luaapi_lj2"))]
+    pub fn set_memory_limit(&mut self, bytes: usize) -> bool {
+        match unsafe { memory_limit(self.lua) } {
+            Some(state) => {
+                unsafe { (*state).limit = bytes };
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns the limit set with `Lua::set_memory_limit` or `LuaOptions::memory_limit`, if any.
+    pub fn memory_limit(&self) -> Option<usize> {
+        let state = unsafe { memory_limit(self.lua)? };
+        match unsafe { (*state).limit } {
+            usize::MAX => None,
+            limit => Some(limit),
+        }
+    }
+}
+
+// Returns the allocator state of the context, if it was created by hlua.
+unsafe fn memory_limit(lua: LuaContext) -> Option<*mut MemoryLimit> {
+    let mut ud = ptr::null_mut();
+    let alloc = ffi::lua_getallocf(lua.as_ptr(), &mut ud);
+    match alloc.map(|alloc| alloc as *const ()) == Some(limited_alloc as *const ()) {
+        true => Some(ud.cast::<MemoryLimit>()),
+        false => None,
+    }
+}
+
+/// Creates a state whose memory can be limited, or without limit with LuaJIT.
+pub(crate) unsafe fn new_state(limit: Option<usize>) -> *mut ffi::lua_State {
+    // LuaJIT doesn't support custom allocators on 64 bits platforms.
+    if cfg!(feature = "_luaapi_lj2") && limit.is_none() {
+        return ffi::luaL_newstate();
+    }
+    new_limited_state(limit.unwrap_or(usize::MAX))
+}
+
+unsafe fn new_limited_state(limit: usize) -> *mut ffi::lua_State {
+    let state = Box::into_raw(Box::new(MemoryLimit { used: 0, limit }));
+    let lua = ffi::lua_newstate(Some(limited_alloc), state.cast());
+    if lua.is_null() {
+        drop(Box::from_raw(state));
+    }
+    lua
+}
+
+unsafe extern "C" fn limited_alloc(
+    ud: *mut libc::c_void,
+    ptr: *mut libc::c_void,
+    osize: libc::size_t,
+    nsize: libc::size_t,
+) -> *mut libc::c_void {
+    let state = &mut *ud.cast::<MemoryLimit>();
+    // When `ptr` is null, `osize` is the kind of object being allocated, not a size.
+    let osize = if ptr.is_null() { 0 } else { osize };
+
+    if nsize == 0 {
+        libc::free(ptr);
+        state.used -= osize;
+        return ptr::null_mut();
+    }
+    if nsize > osize && state.used - osize + nsize > state.limit {
+        return ptr::null_mut();
+    }
+    let new = libc::realloc(ptr, nsize);
+    if !new.is_null() {
+        state.used = state.used - osize + nsize;
+    }
+    new
+}
+
+/// Closes a context, and frees its allocator state if it was created by hlua.
+pub(crate) unsafe fn close(lua: LuaContext) {
+    let state = memory_limit(lua);
+    ffi::lua_close(lua.as_ptr());
+    if let Some(state) = state {
+        drop(Box::from_raw(state));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{LibSet, Lua, LuaError};
+
+    #[test]
+    fn libraries() {
+        let mut lua = Lua::builder().openlibs(LibSet::BASE | LibSet::STRING).build();
+        assert_eq!(lua.execute::<String>("return type(string) .. type(io)").unwrap(), "tablenil");
+        assert!(LibSet::SAFE.contains(LibSet::MATH));
+        assert!(!LibSet::SAFE.contains(LibSet::IO));
+    }
+
+    #[test]
+    fn instruction_limit() {
+        let mut lua = Lua::builder().openlibs(LibSet::BASE).instruction_limit(10_000).build();
+        match lua.execute::<()>("while true do end") {
+            Err(LuaError::ExecutionError(msg)) => {
+                assert!(msg.message().contains("instruction limit"))
+            },
+            other => panic!("{:?}", other),
+        }
+        // Each call gets a new budget.
+        for _ in 0..5 {
+            lua.execute::<()>("local n = 0 for i = 1, 1000 do n = n + i end").unwrap();
+        }
+    }
+
+    #[cfg(not(feature = "_luaapi_lj2"))]
+    #[test]
+    fn memory_limit_and_strict_globals() {
+        let mut lua = Lua::builder()
+            .openlibs(LibSet::SAFE)
+            .memory_limit(1 << 20)
+            .strict_globals(true)
+            .build();
+        let fill = "local t = {} for i = 1, 1e7 do t[i] = i end";
+        assert!(matches!(lua.execute::<()>(fill), Err(LuaError::MemoryLimitExceeded)));
+        assert_eq!(lua.execute::<i32>("return 1 + 1").unwrap(), 2);
+        assert_eq!(lua.memory_limit(), Some(1 << 20));
+
+        match lua.execute::<()>("local x = misspelled") {
+            Err(LuaError::ExecutionError(msg)) => assert!(msg.message().contains("'misspelled'")),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(lua.get::<i32, _>("misspelled"), None);
+        lua.execute::<()>("defined = 1; x = defined").unwrap();
+    }
+
+    #[cfg(not(feature = "_luaapi_lj2"))]
+    #[test]
+    fn set_memory_limit() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        assert_eq!(lua.memory_limit(), None);
+        assert!(lua.set_memory_limit(1 << 20));
+
+        let grow = "local t = {} for i = 1, 1e6 do t[i] = i end";
+        assert!(matches!(lua.execute::<()>(grow), Err(LuaError::MemoryLimitExceeded)));
+        assert!(lua.set_memory_limit(usize::MAX));
+        lua.execute::<()>(grow).unwrap();
+
+        let mut other = unsafe { Lua::from_existing_state(lua.lua.as_ptr(), false) };
+        assert!(other.set_memory_limit(1 << 20));
+        let mut foreign = unsafe { Lua::from_existing_state(crate::ffi::luaL_newstate(), true) };
+        assert!(!foreign.set_memory_limit(1 << 20));
+        assert_eq!(foreign.memory_limit(), None);
+    }
+}
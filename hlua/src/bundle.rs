@@ -0,0 +1,514 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    ffi::CString,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{
+    ffix, values, Lua, LuaContext, LuaError, LuaFunction, LuaFunctionCallError, LuaRead, LuaRef,
+    Push, PushGuard,
+};
+
+// First line of the serialized form of a bundle.
+const MAGIC: &str = "HLUA-BUNDLE 1\n";
+
+// Receives the searcher of a bundle and puts it before the searchers of the file system.
+const SEARCHER_GLUE: &str = r#"
+local searcher = ...
+local searchers = package.searchers or package.loaders
+table.insert(searchers, 2, searcher)
+"#;
+
+// Stores the value returned by a file of a bundle as the value of its module for `require`.
+const MARK_LOADED: &str = r#"
+local name, value = ...
+if value == nil then value = true end
+if package and package.loaded then package.loaded[name] = value end
+"#;
+
+// Prefix of the comments that declare the dependencies of a file.
+const REQUIRES: &str = "--@requires";
+
+/// Set of Lua source files, distributed together and loaded with `require`.
+///
+/// Files are identified by their path inside the bundle, with `/` as separator, such as
+/// `core/util.lua`. Once the bundle is registered with `Lua::register_bundle`, `require
+/// "core.util"` loads `core/util.lua`, or `core/util/init.lua`. The chunks are named after
+/// their path, so error messages and tracebacks show the original file names and lines.
+///
+/// A bundle can be built from a directory with `from_dir`, and saved with `to_bytes` to be
+/// embedded in the application with `include_bytes!` or loaded at runtime with `from_bytes`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, ScriptBundle};
+///
+/// let bundle = ScriptBundle::new()
+///     .file("core/util.lua", "return { double = function(x) return x * 2 end }")
+///     .file("main.lua", "local util = require 'core.util'\nreturn util.double(21)");
+/// let bundle = ScriptBundle::from_bytes(&bundle.to_bytes()).unwrap();
+///
+/// let mut lua = Lua::new();
+/// lua.openlibs();
+/// lua.register_bundle(bundle).unwrap();
+/// assert_eq!(lua.execute::<i32>("return require 'main'").unwrap(), 42);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScriptBundle {
+    files: BTreeMap<String, String>,
+}
+
+impl ScriptBundle {
+    /// Creates an empty bundle.
+    #[inline]
+    pub fn new() -> ScriptBundle {
+        ScriptBundle::default()
+    }
+
+    /// Adds a file to the bundle, replacing the file with the same path if there is one.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `path` is empty or contains a newline.
+    pub fn file(mut self, path: &str, source: impl Into<String>) -> ScriptBundle {
+        assert!(!path.is_empty() && !path.contains('\n'), "invalid bundle path {:?}", path);
+        self.files.insert(path.to_owned(), source.into());
+        self
+    }
+
+    /// Builds a bundle from the `.lua` files of a directory and its subdirectories.
+    pub fn from_dir(dir: impl AsRef<Path>) -> io::Result<ScriptBundle> {
+        let mut bundle = ScriptBundle::new();
+        let mut pending = vec![(dir.as_ref().to_owned(), String::new())];
+
+        while let Some((dir, prefix)) = pending.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let path: PathBuf = entry.path();
+                if entry.file_type()?.is_dir() {
+                    pending.push((path, format!("{}{}/", prefix, name)));
+                } else if name.ends_with(".lua") {
+                    let source = fs::read_to_string(&path)?;
+                    bundle = bundle.file(&format!("{}{}", prefix, name), source);
+                }
+            }
+        }
+        Ok(bundle)
+    }
+
+    /// Reads a bundle saved with `to_bytes`.
+    ///
+    /// Fails with `io::ErrorKind::InvalidData` if `data` isn't a valid bundle.
+    pub fn from_bytes(data: &[u8]) -> io::Result<ScriptBundle> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid script bundle");
+        let data = std::str::from_utf8(data).map_err(|_| invalid())?;
+        let data = data.strip_prefix(MAGIC).ok_or_else(invalid)?;
+        let (manifest, mut sources) = data.split_once("\n\n").ok_or_else(invalid)?;
+
+        let mut bundle = ScriptBundle::new();
+        for line in manifest.lines() {
+            let (len, path) = line.split_once(' ').ok_or_else(invalid)?;
+            let len: usize = len.parse().map_err(|_| invalid())?;
+            if path.is_empty() || len > sources.len() || !sources.is_char_boundary(len) {
+                return Err(invalid());
+            }
+            let (source, rest) = sources.split_at(len);
+            bundle = bundle.file(path, source);
+            sources = rest;
+        }
+        match sources.is_empty() {
+            true => Ok(bundle),
+            false => Err(invalid()),
+        }
+    }
+
+    /// Serializes the bundle: a manifest with the path and size of each file, followed by the
+    /// sources.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = String::from(MAGIC);
+        for (path, source) in &self.files {
+            out.push_str(&format!("{} {}\n", source.len(), path));
+        }
+        out.push('\n');
+        for source in self.files.values() {
+            out.push_str(source);
+        }
+        out.into_bytes()
+    }
+
+    /// Returns the source of the file at `path`.
+    #[inline]
+    pub fn source(&self, path: &str) -> Option<&str> {
+        self.files.get(path).map(String::as_str)
+    }
+
+    /// Returns the paths of the files, in alphabetical order.
+    #[inline]
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+
+    /// Returns the modules that the file at `path` declares as dependencies, with comments such
+    /// as `--@requires core.util`. A comment can declare several modules, separated by spaces.
+    pub fn dependencies(&self, path: &str) -> Vec<&str> {
+        let source = self.source(path).unwrap_or_default();
+        source
+            .lines()
+            .filter_map(|line| line.trim_start().strip_prefix(REQUIRES))
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .flat_map(str::split_whitespace)
+            .collect()
+    }
+
+    /// Returns the paths of the files sorted so that each file comes after the files of the
+    /// modules it depends on. Files without dependencies between them stay in alphabetical order.
+    ///
+    /// Fails if a file depends on a module that isn't in the bundle, or if files depend on each
+    /// other in a cycle.
+    pub fn load_order(&self) -> Result<Vec<&str>, BundleError> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum State {
+            Visiting,
+            Done,
+        }
+
+        fn visit<'a>(
+            bundle: &'a ScriptBundle,
+            path: &'a str,
+            states: &mut HashMap<&'a str, State>,
+            stack: &mut Vec<&'a str>,
+            order: &mut Vec<&'a str>,
+        ) -> Result<(), BundleError> {
+            match states.get(path) {
+                Some(State::Done) => return Ok(()),
+                Some(State::Visiting) => {
+                    let start = stack.iter().position(|p| *p == path).unwrap_or(0);
+                    let mut cycle: Vec<String> =
+                        stack[start..].iter().map(|p| p.to_string()).collect();
+                    cycle.push(path.to_owned());
+                    return Err(BundleError::Cycle(cycle));
+                },
+                None => {},
+            }
+
+            states.insert(path, State::Visiting);
+            stack.push(path);
+            for module in bundle.dependencies(path) {
+                let dependency =
+                    bundle.module_path(module).ok_or_else(|| BundleError::MissingModule {
+                        module: module.to_owned(),
+                        required_by: path.to_owned(),
+                    })?;
+                visit(bundle, dependency, states, stack, order)?;
+            }
+            stack.pop();
+            states.insert(path, State::Done);
+            order.push(path);
+            Ok(())
+        }
+
+        let mut states = HashMap::new();
+        let mut order = Vec::with_capacity(self.files.len());
+        for path in self.paths() {
+            visit(self, path, &mut states, &mut Vec::new(), &mut order)?;
+        }
+        Ok(order)
+    }
+
+    /// Returns the path of the file loaded by `require(module)`, if it is in the bundle.
+    pub fn module_path(&self, module: &str) -> Option<&str> {
+        let base = module.replace('.', "/");
+        [format!("{}.lua", base), format!("{}/init.lua", base)]
+            .into_iter()
+            .find_map(|path| self.files.get_key_value(&path).map(|(path, _)| path.as_str()))
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Makes `require` find the modules of `bundle`, before the ones of the file system.
+    ///
+    /// The `package` library must be open. Bundles registered later are searched first.
+    pub fn register_bundle(&mut self, bundle: ScriptBundle) -> Result<(), LuaError> {
+        let searcher: LuaRef = unsafe {
+            push_userdata(bundle, self.lua, |_| {}).forget();
+            ffi::lua_pushcclosure(self.lua.as_ptr(), Some(searcher), 1);
+            match LuaRead::lua_read(PushGuard::new(&mut *self, 1)) {
+                Ok(searcher) => searcher,
+                Err(_) => unreachable!("reading a LuaRef never fails"),
+            }
+        };
+        let mut glue = LuaFunction::load_named(&mut *self, "=bundle", SEARCHER_GLUE)?;
+        Ok(glue.call_with_args(&searcher)?)
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Executes all the files of `bundle`, in the order given by `ScriptBundle::load_order`.
+    ///
+    /// Each file receives its module name and its path as arguments, like with `require`. If
+    /// the `package` library is open, the values returned by the files are stored in
+    /// `package.loaded`, so that requiring them afterwards doesn't run them again. Stops at the
+    /// first file that fails.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, ScriptBundle};
+    ///
+    /// let bundle = ScriptBundle::new()
+    ///     .file("a_game.lua", "--@requires core.log\nlog('game loaded')")
+    ///     .file("core/log.lua", "lines = {}\nfunction log(line) table.insert(lines, line) end");
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.openlibs();
+    /// lua.execute_bundle(&bundle).unwrap();
+    /// assert_eq!(lua.execute::<String>("return lines[1]").unwrap(), "game loaded");
+    /// ```
+    pub fn execute_bundle(&mut self, bundle: &ScriptBundle) -> Result<(), BundleError> {
+        for path in bundle.load_order()? {
+            let module = module_name(path);
+            self.execute_bundle_file(bundle, path, &module)
+                .map_err(|error| BundleError::Execution { path: path.to_owned(), error })?;
+        }
+        Ok(())
+    }
+
+    fn execute_bundle_file(
+        &mut self,
+        bundle: &ScriptBundle,
+        path: &str,
+        module: &str,
+    ) -> Result<(), LuaError> {
+        let source = &bundle.files[path];
+        let mut chunk = LuaFunction::load_named(&mut *self, &format!("@{}", path), source)?;
+        let value: LuaRef = chunk.call_with_args((module, path)).map_err(call_error)?;
+        drop(chunk);
+        let mut mark = LuaFunction::load_named(&mut *self, "=bundle", MARK_LOADED)?;
+        mark.call_with_args((module, &value)).map_err(call_error)
+    }
+}
+
+// The arguments of the calls of this module can't fail to be pushed.
+fn call_error<E>(err: LuaFunctionCallError<E>) -> LuaError {
+    match err {
+        LuaFunctionCallError::LuaError(err) => err,
+        LuaFunctionCallError::PushError(_) => unreachable!("pushing strings never fails"),
+    }
+}
+
+// Returns the name of the module of a file, the reverse of `ScriptBundle::module_path`.
+fn module_name(path: &str) -> String {
+    let module = path.strip_suffix(".lua").unwrap_or(path);
+    let module = module.strip_suffix("/init").unwrap_or(module);
+    module.replace('/', ".")
+}
+
+/// Error returned when the files of a `ScriptBundle` can't be sorted or executed.
+#[derive(Debug)]
+pub enum BundleError {
+    /// A file depends on a module that isn't in the bundle.
+    MissingModule {
+        /// Name of the module.
+        module: String,
+        /// Path of the file that depends on it.
+        required_by: String,
+    },
+    /// Files depend on each other. Contains the paths of the files of the cycle, starting and
+    /// ending with the same one.
+    Cycle(Vec<String>),
+    /// A file failed to compile or to run.
+    Execution {
+        /// Path of the file.
+        path: String,
+        /// Error raised by the file.
+        error: LuaError,
+    },
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundleError::MissingModule { module, required_by } => {
+                write!(f, "module '{}' required by '{}' is not in the bundle", module, required_by)
+            },
+            BundleError::Cycle(paths) => write!(f, "dependency cycle: {}", paths.join(" -> ")),
+            BundleError::Execution { path, error } => write!(f, "{}: {}", path, error),
+        }
+    }
+}
+
+impl Error for BundleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BundleError::Execution { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// Result of the bundle searcher.
+enum Search {
+    // The loader is on the stack, and the path is its second value.
+    Found(String),
+    NotFound(String),
+    // The file doesn't compile.
+    Failed(String),
+}
+
+// Searcher of the `package` library, with the bundle as upvalue.
+extern "C" fn searcher(lua: *mut ffi::lua_State) -> libc::c_int {
+    // Rust values must be dropped before raising the error.
+    let search = unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        let bundle = userdata_mut::<ScriptBundle>(raw_lua, ffi::lua_upvalueindex(1))
+            .expect("the upvalue of the bundle searcher is a ScriptBundle");
+        let module = values::string_bytes(raw_lua, 1).map(String::from_utf8_lossy);
+        let module = module.unwrap_or_default();
+
+        match bundle.module_path(&module) {
+            None => {
+                Search::NotFound(format!("\n\tno file '{}' in bundle", module.replace('.', "/")))
+            },
+            Some(path) => {
+                let source = &bundle.files[path];
+                let chunk_name = CString::new(format!("@{}", path)).unwrap();
+                let status = ffi::luaL_loadbufferx(
+                    lua,
+                    source.as_ptr().cast(),
+                    source.len(),
+                    chunk_name.as_ptr(),
+                    c"t".as_ptr(),
+                );
+                if status == 0 {
+                    Search::Found(path.to_owned())
+                } else {
+                    let msg = values::string_bytes(raw_lua, -1).unwrap_or_default();
+                    let msg = String::from_utf8_lossy(msg).into_owned();
+                    ffi::lua_pop(lua, 1);
+                    Search::Failed(format!(
+                        "error loading module '{}' from file '{}':\n\t{}",
+                        module, path, msg
+                    ))
+                }
+            },
+        }
+    };
+
+    unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        match search {
+            Search::Found(path) => {
+                path.push_no_err(raw_lua).forget();
+                2
+            },
+            Search::NotFound(msg) => {
+                msg.push_no_err(raw_lua).forget();
+                1
+            },
+            Search::Failed(msg) => {
+                msg.push_no_err(raw_lua).forget();
+                ffix::lua_error(lua);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+
+    use crate::{BundleError, Lua, LuaError, ScriptBundle};
+
+    #[test]
+    fn serialization() {
+        let bundle = ScriptBundle::new()
+            .file("a.lua", "return 'é'")
+            .file("b/init.lua", "")
+            .file("b/c.lua", "return 1\n\nreturn 2");
+        let bytes = bundle.to_bytes();
+        assert_eq!(ScriptBundle::from_bytes(&bytes).unwrap(), bundle);
+        assert_eq!(bundle.module_path("b"), Some("b/init.lua"));
+        assert_eq!(bundle.module_path("b.c"), Some("b/c.lua"));
+        assert_eq!(bundle.module_path("c"), None);
+
+        let err = ScriptBundle::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn require_from_dir() {
+        let dir = std::env::temp_dir().join(format!("hlua_bundle_{}", std::process::id()));
+        fs::create_dir_all(dir.join("core")).unwrap();
+        fs::write(
+            dir.join("core/util.lua"),
+            "local M = {}\nfunction M.fail()\n  error('oops')\nend\nreturn M",
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "ignored").unwrap();
+        let bundle = ScriptBundle::from_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(bundle.paths().collect::<Vec<_>>(), ["core/util.lua"]);
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.register_bundle(bundle.file("broken.lua", "return +")).unwrap();
+        match lua.execute::<()>("require('core.util').fail()") {
+            Err(LuaError::ExecutionError(msg)) => {
+                assert!(msg.message().starts_with("core/util.lua:3: oops"))
+            },
+            other => panic!("{:?}", other),
+        }
+        match lua.execute::<()>("require 'broken'") {
+            Err(LuaError::ExecutionError(msg)) => {
+                assert!(msg.message().contains("from file 'broken.lua'"))
+            },
+            other => panic!("{:?}", other),
+        }
+        let missing: String = lua.execute("return select(2, pcall(require, 'missing'))").unwrap();
+        assert!(missing.contains("no file 'missing' in bundle"));
+    }
+
+    #[test]
+    fn load_order() {
+        let bundle = ScriptBundle::new()
+            .file("app.lua", "--@requires ui core\nreturn 'app'")
+            .file("core/init.lua", "order = { 'core' }")
+            .file("ui.lua", "  --@requires  core.util\ntable.insert(order, 'ui')")
+            .file("core/util.lua", "--@requires core\ntable.insert(order, 'util')\nreturn 5");
+        assert_eq!(bundle.dependencies("app.lua"), ["ui", "core"]);
+        assert_eq!(
+            bundle.load_order().unwrap(),
+            ["core/init.lua", "core/util.lua", "ui.lua", "app.lua"]
+        );
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.execute_bundle(&bundle).unwrap();
+        assert_eq!(
+            lua.execute::<String>("return table.concat(order, ' ')").unwrap(),
+            "core util ui"
+        );
+        assert_eq!(lua.execute::<i32>("return require 'core.util'").unwrap(), 5);
+
+        let cyclic = bundle.clone().file("core/init.lua", "--@requires app");
+        match cyclic.load_order() {
+            Err(BundleError::Cycle(cycle)) => {
+                assert_eq!(
+                    cycle,
+                    ["app.lua", "ui.lua", "core/util.lua", "core/init.lua", "app.lua"]
+                )
+            },
+            other => panic!("{:?}", other),
+        }
+        let missing = bundle.file("ui.lua", "--@requires widgets");
+        assert_eq!(
+            missing.load_order().unwrap_err().to_string(),
+            "module 'widgets' required by 'ui.lua' is not in the bundle"
+        );
+    }
+}
@@ -0,0 +1,561 @@
+use std::{
+    ffi::CStr,
+    fmt,
+    ops::Range,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsLua, AsMutLua, LuaContext, LuaRead, Push, PushGuard, PushOne, Void};
+
+type Method = (&'static CStr, ffi::lua_CFunction);
+
+const METHODS: [Method; 35] = [
+    (c"len", Some(len)),
+    (c"slice", Some(slice)),
+    (c"string", Some(string)),
+    (c"get_u8", Some(get::<u8, true>)),
+    (c"get_i8", Some(get::<i8, true>)),
+    (c"get_u16le", Some(get::<u16, true>)),
+    (c"get_u16be", Some(get::<u16, false>)),
+    (c"get_i16le", Some(get::<i16, true>)),
+    (c"get_i16be", Some(get::<i16, false>)),
+    (c"get_u32le", Some(get::<u32, true>)),
+    (c"get_u32be", Some(get::<u32, false>)),
+    (c"get_i32le", Some(get::<i32, true>)),
+    (c"get_i32be", Some(get::<i32, false>)),
+    (c"get_i64le", Some(get::<i64, true>)),
+    (c"get_i64be", Some(get::<i64, false>)),
+    (c"get_f32le", Some(get::<f32, true>)),
+    (c"get_f32be", Some(get::<f32, false>)),
+    (c"get_f64le", Some(get::<f64, true>)),
+    (c"get_f64be", Some(get::<f64, false>)),
+    (c"set_u8", Some(set::<u8, true>)),
+    (c"set_i8", Some(set::<i8, true>)),
+    (c"set_u16le", Some(set::<u16, true>)),
+    (c"set_u16be", Some(set::<u16, false>)),
+    (c"set_i16le", Some(set::<i16, true>)),
+    (c"set_i16be", Some(set::<i16, false>)),
+    (c"set_u32le", Some(set::<u32, true>)),
+    (c"set_u32be", Some(set::<u32, false>)),
+    (c"set_i32le", Some(set::<i32, true>)),
+    (c"set_i32be", Some(set::<i32, false>)),
+    (c"set_i64le", Some(set::<i64, true>)),
+    (c"set_i64be", Some(set::<i64, false>)),
+    (c"set_f32le", Some(set::<f32, true>)),
+    (c"set_f32be", Some(set::<f32, false>)),
+    (c"set_f64le", Some(set::<f64, true>)),
+    (c"set_f64be", Some(set::<f64, false>)),
+];
+
+/// Buffer of bytes pushed to Lua as a userdata, through which scripts read and write binary
+/// data without converting it to strings.
+///
+/// The buffer either owns a `Vec<u8>`, which scripts can modify, or shares read-only bytes of
+/// any type that implements `AsRef<[u8]>`, such as `bytes::Bytes` or a memory map. Clones and
+/// slices of a buffer share its bytes, so a buffer kept on the Rust side sees the writes of the
+/// scripts. Scripts can't change the length of a buffer.
+///
+/// In Lua, offsets start from 1 and the values are converted one by one:
+///
+/// - `#buffer` and `buffer:len()` are the number of bytes.
+/// - `buffer:get_u8(offset)` and `buffer:get_i8(offset)` read a byte, and
+///   `buffer:get_u32le(offset)` and its variants read the bytes at `offset` and after it as a
+///   little-endian (`le`) or big-endian (`be`) value of type `u16`, `i16`, `u32`, `i32`, `i64`,
+///   `f32` or `f64`. With Lua 5.4, `i64` values are read as integers, and otherwise as floats.
+/// - `buffer:set_u8(offset, value)` and the `set_` variants of the above write a value, which
+///   must fit in the type. Writing to a read-only buffer raises an error.
+/// - `buffer:slice(first, last)` returns a buffer sharing the bytes from `first` to `last`
+///   included, or to the end if `last` is omitted, like `string.sub`.
+/// - `buffer:string(first, last)` copies the same bytes into a Lua string.
+///
+/// Offsets that are out of the buffer raise an error.
+///
+/// # Example
+///
+/// ```
+/// use hlua::ByteBuffer;
+///
+/// let mut lua = hlua::Lua::new();
+/// let packet = ByteBuffer::new(vec![0x01, 0x00, 0x2a, 0, 0, 0, 0, 0]);
+/// lua.set("packet", &packet);
+///
+/// let code = "local kind = packet:get_u8(1)
+///             local length = packet:get_u16be(2)
+///             packet:slice(5):set_f32le(1, 1.5)
+///             return kind + length";
+/// assert_eq!(lua.execute::<i32>(code).unwrap(), 43);
+/// assert_eq!(packet.to_vec()[4..], 1.5f32.to_le_bytes());
+/// ```
+#[derive(Clone)]
+pub struct ByteBuffer {
+    storage: Arc<Storage>,
+    start: usize,
+    len: usize,
+}
+
+enum Storage {
+    Owned(Mutex<Vec<u8>>),
+    Shared(Box<dyn AsRef<[u8]> + Send + Sync>),
+}
+
+impl ByteBuffer {
+    /// Creates a buffer that owns `bytes`.
+    #[inline]
+    pub fn new(bytes: Vec<u8>) -> ByteBuffer {
+        let len = bytes.len();
+        ByteBuffer { storage: Arc::new(Storage::Owned(Mutex::new(bytes))), start: 0, len }
+    }
+
+    /// Creates a buffer of `len` zero bytes.
+    #[inline]
+    pub fn zeroed(len: usize) -> ByteBuffer {
+        ByteBuffer::new(vec![0; len])
+    }
+
+    /// Creates a read-only buffer that shares `bytes`, such as a `bytes::Bytes` or a memory map.
+    pub fn read_only<B>(bytes: B) -> ByteBuffer
+    where
+        B: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let len = bytes.as_ref().len();
+        ByteBuffer { storage: Arc::new(Storage::Shared(Box::new(bytes))), start: 0, len }
+    }
+
+    /// Returns the number of bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the buffer contains no bytes.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns true if the bytes can't be modified.
+    #[inline]
+    pub fn is_read_only(&self) -> bool {
+        matches!(*self.storage, Storage::Shared(_))
+    }
+
+    /// Returns a buffer sharing the bytes in `range`, or `None` if it is out of the buffer.
+    pub fn slice(&self, range: Range<usize>) -> Option<ByteBuffer> {
+        if range.start > range.end || range.end > self.len {
+            return None;
+        }
+        let start = self.start + range.start;
+        Some(ByteBuffer { storage: self.storage.clone(), start, len: range.len() })
+    }
+
+    /// Calls `f` with the bytes, and returns its result.
+    pub fn with_bytes<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        let range = self.start..self.start + self.len;
+        match &*self.storage {
+            Storage::Owned(bytes) => {
+                f(&bytes.lock().unwrap_or_else(PoisonError::into_inner)[range])
+            },
+            Storage::Shared(bytes) => f(&(**bytes).as_ref()[range]),
+        }
+    }
+
+    /// Calls `f` with the bytes, which it can modify, and returns its result. Returns `None` for
+    /// read-only buffers.
+    ///
+    /// The modifications are seen by all the buffers that share the bytes.
+    pub fn with_bytes_mut<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> Option<R> {
+        let range = self.start..self.start + self.len;
+        match &*self.storage {
+            Storage::Owned(bytes) => {
+                Some(f(&mut bytes.lock().unwrap_or_else(PoisonError::into_inner)[range]))
+            },
+            Storage::Shared(_) => None,
+        }
+    }
+
+    /// Copies the bytes into a `Vec`.
+    #[inline]
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.with_bytes(<[u8]>::to_vec)
+    }
+}
+
+impl From<Vec<u8>> for ByteBuffer {
+    #[inline]
+    fn from(bytes: Vec<u8>) -> ByteBuffer {
+        ByteBuffer::new(bytes)
+    }
+}
+
+impl fmt::Debug for ByteBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ByteBuffer")
+            .field("len", &self.len)
+            .field("read_only", &self.is_read_only())
+            .finish()
+    }
+}
+
+impl<'lua, L> Push<L> for ByteBuffer
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        let guard = push_userdata(self, lua, |mut metatable| unsafe {
+            let raw_lua = metatable.as_mut_lua().as_ptr();
+            ffi::lua_pushcfunction(raw_lua, Some(index));
+            ffi::lua_setfield(raw_lua, -2, c"__index".as_ptr());
+            ffi::lua_pushcfunction(raw_lua, Some(len));
+            ffi::lua_setfield(raw_lua, -2, c"__len".as_ptr());
+        });
+        Ok(guard)
+    }
+}
+
+impl<'lua, L> PushOne<L> for ByteBuffer where L: AsMutLua<'lua> {}
+
+impl<'lua, L> Push<L> for &ByteBuffer
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        self.clone().push_to_lua(lua)
+    }
+}
+
+impl<'lua, L> PushOne<L> for &ByteBuffer where L: AsMutLua<'lua> {}
+
+impl<'lua, L> LuaRead<L> for ByteBuffer
+where
+    L: AsLua<'lua>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<ByteBuffer, L> {
+        match unsafe { userdata_mut::<ByteBuffer>(lua.as_lua(), index) } {
+            Some(buffer) => Ok(buffer.clone()),
+            None => Err(lua),
+        }
+    }
+}
+
+/// Value that can be read from and written to a `ByteBuffer` by scripts.
+trait Element: Sized {
+    const NAME: &'static str;
+    const SIZE: usize;
+
+    fn decode(bytes: &[u8], little_endian: bool) -> Self;
+    fn encode(self, bytes: &mut [u8], little_endian: bool);
+    unsafe fn push(self, lua: *mut ffi::lua_State);
+    unsafe fn read(lua: *mut ffi::lua_State, index: libc::c_int) -> Option<Self>;
+}
+
+macro_rules! element_impl {
+    ($t:ident, $push:ident, $read:ident) => {
+        impl Element for $t {
+            const NAME: &'static str = stringify!($t);
+            const SIZE: usize = std::mem::size_of::<$t>();
+
+            #[inline]
+            fn decode(bytes: &[u8], little_endian: bool) -> $t {
+                let bytes = bytes.try_into().unwrap();
+                match little_endian {
+                    true => $t::from_le_bytes(bytes),
+                    false => $t::from_be_bytes(bytes),
+                }
+            }
+
+            #[inline]
+            fn encode(self, bytes: &mut [u8], little_endian: bool) {
+                match little_endian {
+                    true => bytes.copy_from_slice(&self.to_le_bytes()),
+                    false => bytes.copy_from_slice(&self.to_be_bytes()),
+                }
+            }
+
+            #[inline]
+            unsafe fn push(self, lua: *mut ffi::lua_State) {
+                $push(lua, self.into())
+            }
+
+            #[inline]
+            unsafe fn read(lua: *mut ffi::lua_State, index: libc::c_int) -> Option<$t> {
+                $read(lua, index).and_then(|value| value.try_into().ok())
+            }
+        }
+    };
+}
+
+element_impl!(u8, push_integer, read_integer);
+element_impl!(i8, push_integer, read_integer);
+element_impl!(u16, push_integer, read_integer);
+element_impl!(i16, push_integer, read_integer);
+element_impl!(u32, push_integer, read_integer);
+element_impl!(i32, push_integer, read_integer);
+element_impl!(i64, push_integer, read_integer);
+element_impl!(f64, push_number, read_number);
+
+impl Element for f32 {
+    const NAME: &'static str = "f32";
+    const SIZE: usize = 4;
+
+    #[inline]
+    fn decode(bytes: &[u8], little_endian: bool) -> f32 {
+        f32::from_bits(u32::decode(bytes, little_endian))
+    }
+
+    #[inline]
+    fn encode(self, bytes: &mut [u8], little_endian: bool) {
+        self.to_bits().encode(bytes, little_endian)
+    }
+
+    #[inline]
+    unsafe fn push(self, lua: *mut ffi::lua_State) {
+        push_number(lua, self.into())
+    }
+
+    #[inline]
+    unsafe fn read(lua: *mut ffi::lua_State, index: libc::c_int) -> Option<f32> {
+        // Like other float conversions, this rounds the values that `f32` can't represent.
+        read_number(lua, index).map(|value| value as f32)
+    }
+}
+
+// Only Lua 5.4 has integers that hold all of the `i64` values.
+unsafe fn push_integer(lua: *mut ffi::lua_State, value: i64) {
+    match () {
+        #[cfg(feature = "_luaapi_54")]
+        () => ffi::lua_pushinteger(lua, value as ffi::lua_Integer),
+        #[cfg(not(feature = "_luaapi_54"))]
+        () => ffi::lua_pushnumber(lua, value as ffi::lua_Number),
+    }
+}
+
+unsafe fn read_integer(lua: *mut ffi::lua_State, index: libc::c_int) -> Option<i64> {
+    if ffi::lua_type(lua, index) != ffi::LUA_TNUMBER {
+        return None;
+    }
+    match () {
+        #[cfg(feature = "_luaapi_54")]
+        () => {
+            let mut success = 0;
+            let value = ffi::lua_tointegerx(lua, index, &mut success);
+            (success != 0).then_some(value as i64)
+        },
+        #[cfg(not(feature = "_luaapi_54"))]
+        () => {
+            let value = ffi::lua_tonumberx(lua, index, std::ptr::null_mut());
+            let in_range = value >= i64::MIN as f64 && value < i64::MAX as f64;
+            (value.fract() == 0.0 && in_range).then_some(value as i64)
+        },
+    }
+}
+
+unsafe fn push_number(lua: *mut ffi::lua_State, value: f64) {
+    ffi::lua_pushnumber(lua, value)
+}
+
+unsafe fn read_number(lua: *mut ffi::lua_State, index: libc::c_int) -> Option<f64> {
+    match ffi::lua_type(lua, index) == ffi::LUA_TNUMBER {
+        true => Some(ffi::lua_tonumberx(lua, index, std::ptr::null_mut())),
+        false => None,
+    }
+}
+
+unsafe fn finish(lua: *mut ffi::lua_State, result: Result<libc::c_int, String>) -> libc::c_int {
+    match result {
+        Ok(count) => count,
+        Err(msg) => {
+            msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+            ffix::lua_error(lua);
+        },
+    }
+}
+
+unsafe fn buffer_arg<'a>(lua: *mut ffi::lua_State) -> Result<&'a ByteBuffer, String> {
+    match userdata_mut::<ByteBuffer>(LuaContext::new_unchecked(lua), 1) {
+        Some(buffer) => Ok(buffer),
+        None => Err("bad argument #1 (byte buffer expected, use the ':' syntax)".to_owned()),
+    }
+}
+
+// Reads the 1-based offset at `index`, which must be followed by `size` bytes in the buffer, and
+// returns the 0-based range of these bytes.
+unsafe fn range_arg(
+    lua: *mut ffi::lua_State,
+    index: libc::c_int,
+    size: usize,
+    len: usize,
+) -> Result<Range<usize>, String> {
+    let offset = match read_integer(lua, index) {
+        Some(offset) if offset >= 1 => offset as usize - 1,
+        _ => return Err(format!("bad argument #{} (positive integer offset expected)", index - 1)),
+    };
+    match offset.checked_add(size) {
+        Some(end) if end <= len => Ok(offset..end),
+        _ => Err(format!("{} bytes at offset {} are out of a buffer of {}", size, offset + 1, len)),
+    }
+}
+
+// Reads the `first` and optional `last` arguments of `slice` and `string`.
+unsafe fn sub_range_arg(lua: *mut ffi::lua_State, len: usize) -> Result<Range<usize>, String> {
+    let first = match read_integer(lua, 2) {
+        Some(first) if first >= 1 => first as usize - 1,
+        _ => return Err("bad argument #1 (positive integer offset expected)".to_owned()),
+    };
+    let end = match ffi::lua_type(lua, 3) {
+        ffi::LUA_TNONE | ffi::LUA_TNIL => len,
+        _ => match read_integer(lua, 3) {
+            Some(last) if last >= 0 => last as usize,
+            _ => return Err("bad argument #2 (integer offset expected)".to_owned()),
+        },
+    };
+    match first <= end && end <= len {
+        true => Ok(first..end),
+        false => Err(format!("range {}..{} is out of a buffer of {}", first + 1, end, len)),
+    }
+}
+
+extern "C" fn index(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        buffer_arg(lua)?;
+        let method = match ffi::lua_type(lua, 2) == ffi::LUA_TSTRING {
+            true => {
+                let key = CStr::from_ptr(ffi::lua_tolstring(lua, 2, std::ptr::null_mut()));
+                METHODS.iter().find(|(name, _)| *name == key)
+            },
+            false => None,
+        };
+        match method {
+            Some((_, method)) => ffi::lua_pushcfunction(lua, *method),
+            None => ffi::lua_pushnil(lua),
+        }
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn len(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let buffer = buffer_arg(lua)?;
+        push_integer(lua, buffer.len as i64);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn slice(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let buffer = buffer_arg(lua)?;
+        let range = sub_range_arg(lua, buffer.len)?;
+        let slice = buffer.slice(range).unwrap();
+        slice.push_no_err(LuaContext::new_unchecked(lua)).forget();
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn string(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let buffer = buffer_arg(lua)?;
+        let range = sub_range_arg(lua, buffer.len)?;
+        buffer.with_bytes(|bytes| {
+            let bytes = &bytes[range];
+            ffi::lua_pushlstring(lua, bytes.as_ptr().cast(), bytes.len());
+        });
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn get<T: Element, const LITTLE_ENDIAN: bool>(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner<T: Element, const LITTLE_ENDIAN: bool>(
+        lua: *mut ffi::lua_State,
+    ) -> Result<libc::c_int, String> {
+        let buffer = buffer_arg(lua)?;
+        let range = range_arg(lua, 2, T::SIZE, buffer.len)?;
+        let value = buffer.with_bytes(|bytes| T::decode(&bytes[range], LITTLE_ENDIAN));
+        value.push(lua);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner::<T, LITTLE_ENDIAN>(lua)) }
+}
+
+extern "C" fn set<T: Element, const LITTLE_ENDIAN: bool>(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner<T: Element, const LITTLE_ENDIAN: bool>(
+        lua: *mut ffi::lua_State,
+    ) -> Result<libc::c_int, String> {
+        let buffer = buffer_arg(lua)?;
+        let range = range_arg(lua, 2, T::SIZE, buffer.len)?;
+        let value = match T::read(lua, 3) {
+            Some(value) => value,
+            None => return Err(format!("bad argument #2 ({} expected)", T::NAME)),
+        };
+        match buffer.with_bytes_mut(|bytes| value.encode(&mut bytes[range], LITTLE_ENDIAN)) {
+            Some(()) => Ok(0),
+            None => Err("byte buffer is read-only".to_owned()),
+        }
+    }
+
+    unsafe { finish(lua, inner::<T, LITTLE_ENDIAN>(lua)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ByteBuffer, Lua};
+
+    #[test]
+    fn integers_and_floats() {
+        let mut lua = Lua::new();
+        let mut bytes = vec![0; 18];
+        bytes[2..6].copy_from_slice(&(-2i32).to_be_bytes());
+        bytes[6..14].copy_from_slice(&(-5i64).to_le_bytes());
+        let buffer = ByteBuffer::new(bytes);
+        lua.set("buffer", &buffer);
+
+        let code = "buffer:set_u16le(1, 0xbeef)
+                    buffer:set_f32be(15, 0.5)
+                    return buffer:get_u16le(1) + buffer:get_i32be(3) + buffer:get_i64le(7)";
+        assert_eq!(lua.execute::<i32>(code).unwrap(), 0xbeef - 7);
+        assert_eq!(lua.execute::<f64>("return buffer:get_f32be(15)").unwrap(), 0.5);
+        assert_eq!(lua.execute::<i32>("return buffer:get_i8(3)").unwrap(), -1);
+        assert_eq!(lua.execute::<i32>("return #buffer:slice(3, 6)").unwrap(), 4);
+        assert_eq!(lua.execute::<i32>("return #buffer:slice(3):string(2, 3)").unwrap(), 2);
+        lua.execute::<()>("buffer:slice(3):set_i32be(1, 300)").unwrap();
+
+        let bytes = buffer.to_vec();
+        assert_eq!(bytes[..2], [0xef, 0xbe]);
+        assert_eq!(bytes[2..6], 300i32.to_be_bytes());
+        let read: ByteBuffer = lua.get("buffer").unwrap();
+        assert_eq!(read.slice(14..18).unwrap().to_vec(), 0.5f32.to_be_bytes());
+    }
+
+    #[test]
+    fn bounds_and_read_only() {
+        let mut lua = Lua::new();
+        lua.set("data", ByteBuffer::read_only(b"\x01\x02\x03".as_slice()));
+        lua.set("owned", ByteBuffer::zeroed(2));
+
+        let mut error = |code: &str| lua.execute::<()>(code).unwrap_err().to_string();
+        let out = "2 bytes at offset 3 are out of a buffer of 3";
+        assert!(error("data:get_u16le(3)").contains(out));
+        assert!(error("data:get_u8(0)").contains("positive integer offset expected"));
+        assert!(error("data:set_u8(1, 4)").contains("byte buffer is read-only"));
+        assert!(error("owned:set_u8(1, 256)").contains("bad argument #2 (u8 expected)"));
+        assert!(error("owned:set_i8(1, 1.5)").contains("bad argument #2 (i8 expected)"));
+        assert!(error("data:slice(3, 4)").contains("range 3..4 is out of a buffer of 3"));
+        assert!(error("data:missing()").contains("attempt to call"));
+        assert_eq!(lua.execute::<String>("return data:string(2)").unwrap(), "\x02\x03");
+        assert_eq!(lua.execute::<i32>("return #data:slice(4)").unwrap(), 0);
+    }
+}
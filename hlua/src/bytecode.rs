@@ -0,0 +1,277 @@
+use std::{error::Error, fmt, sync::OnceLock};
+
+use crate::{Lua, LuaFunction};
+
+/// Reason why precompiled bytecode can't be loaded by the Lua runtime of this build.
+///
+/// Returned by `verify_bytecode`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytecodeError {
+    /// The data doesn't start with the signature of Lua bytecode, so it is probably source code.
+    NotBytecode,
+    /// The data ends in the middle of the header.
+    Truncated,
+    /// The bytecode was produced by another version of Lua. Versions are encoded as
+    /// `major * 16 + minor` for Lua, and as the bytecode version for LuaJIT.
+    Version {
+        /// Version of the runtime.
+        expected: u8,
+        /// Version of the bytecode.
+        found: u8,
+    },
+    /// The bytecode uses a format that the runtime doesn't know.
+    Format {
+        /// Format of the runtime.
+        expected: u8,
+        /// Format of the bytecode.
+        found: u8,
+    },
+    /// The control bytes of the header are modified, which usually happens when the file is
+    /// transferred in text mode.
+    Corrupted,
+    /// A type has a different size in the runtime that produced the bytecode.
+    Size {
+        /// Name of the C type that differs, such as `lua_Number`.
+        name: &'static str,
+        /// Size of the type in the runtime.
+        expected: u8,
+        /// Size of the type in the bytecode.
+        found: u8,
+    },
+    /// The bytecode was produced on a platform with a different byte order.
+    Endianness,
+    /// Numbers are represented differently, for example as integers instead of floats.
+    NumberFormat,
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BytecodeError::NotBytecode => write!(f, "Data isn't Lua bytecode"),
+            BytecodeError::Truncated => write!(f, "Bytecode header is truncated"),
+            BytecodeError::Version { expected, found } => {
+                write!(f, "Bytecode version is {:#x}, expected {:#x}", found, expected)
+            },
+            BytecodeError::Format { expected, found } => {
+                write!(f, "Bytecode format is {}, expected {}", found, expected)
+            },
+            BytecodeError::Corrupted => write!(f, "Bytecode header is corrupted"),
+            BytecodeError::Size { name, expected, found } => {
+                write!(f, "Size of {} is {} in the bytecode, expected {}", name, found, expected)
+            },
+            BytecodeError::Endianness => write!(f, "Bytecode has a different byte order"),
+            BytecodeError::NumberFormat => write!(f, "Bytecode has a different number format"),
+        }
+    }
+}
+
+impl Error for BytecodeError {}
+
+/// Checks that the header of precompiled bytecode matches the Lua runtime of this build.
+///
+/// Lua only reports a generic error when loading incompatible bytecode, for example bytecode
+/// produced by another Lua version or on a platform with different type sizes. This function
+/// tells which part of the header doesn't match, which helps diagnosing precompiled scripts
+/// shipped to several platforms. Only the header is checked, so a valid header doesn't guarantee
+/// that the rest of the bytecode is valid.
+///
+/// Bytecode produced by `LuaFunction::dump_to` is deterministic: dumping the same code with the
+/// same runtime always gives the same bytes.
+///
+/// # Example
+///
+/// ```
+/// let mut lua = hlua::Lua::new();
+/// let mut bytecode = Vec::new();
+/// hlua::LuaFunction::load(&mut lua, "return 1").unwrap().dump_to(&mut bytecode, true).unwrap();
+/// assert_eq!(hlua::verify_bytecode(&bytecode), Ok(()));
+///
+/// assert_eq!(hlua::verify_bytecode(b"return 1"), Err(hlua::BytecodeError::NotBytecode));
+/// ```
+pub fn verify_bytecode(bytecode: &[u8]) -> Result<(), BytecodeError> {
+    let mut header = Header { data: bytecode, reference: reference_header() };
+    header.verify()
+}
+
+// Returns bytecode produced by the runtime, whose header is compared with the verified one. The
+// sizes of the types are taken from there, since they depend on how the C library was built.
+fn reference_header() -> &'static [u8] {
+    static REFERENCE: OnceLock<Vec<u8>> = OnceLock::new();
+    REFERENCE.get_or_init(|| {
+        let mut lua = Lua::new();
+        let mut bytecode = Vec::new();
+        let mut function = LuaFunction::load(&mut lua, "").expect("the empty chunk is valid");
+        function.dump_to(&mut bytecode, true).expect("writing to a Vec doesn't fail");
+        bytecode
+    })
+}
+
+// Reader of the fields of a header, along with the same fields of the reference header.
+struct Header<'a> {
+    data: &'a [u8],
+    reference: &'static [u8],
+}
+
+impl<'a> Header<'a> {
+    fn take(&mut self, len: usize) -> Result<(&'a [u8], &'static [u8]), BytecodeError> {
+        if self.data.len() < len {
+            return Err(BytecodeError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        let (expected, reference_rest) = self.reference.split_at(len);
+        self.data = rest;
+        self.reference = reference_rest;
+        Ok((taken, expected))
+    }
+
+    fn byte(&mut self) -> Result<(u8, u8), BytecodeError> {
+        self.take(1).map(|(found, expected)| (found[0], expected[0]))
+    }
+
+    fn check(&mut self, len: usize, error: BytecodeError) -> Result<(), BytecodeError> {
+        match self.take(len)? {
+            (found, expected) if found == expected => Ok(()),
+            _ => Err(error),
+        }
+    }
+
+    // Checks the size of a type, and returns it.
+    fn size(&mut self, name: &'static str) -> Result<usize, BytecodeError> {
+        match self.byte()? {
+            (found, expected) if found == expected => Ok(found as usize),
+            (found, expected) => Err(BytecodeError::Size { name, expected, found }),
+        }
+    }
+
+    fn version(&mut self) -> Result<(), BytecodeError> {
+        match self.byte()? {
+            (found, expected) if found == expected => Ok(()),
+            (found, expected) => Err(BytecodeError::Version { expected, found }),
+        }
+    }
+
+    fn format(&mut self) -> Result<(), BytecodeError> {
+        match self.byte()? {
+            (found, expected) if found == expected => Ok(()),
+            (found, expected) => Err(BytecodeError::Format { expected, found }),
+        }
+    }
+
+    // Header of `ldump.c` in Lua 5.4.
+    #[cfg(feature = "_luaapi_54")]
+    fn verify(&mut self) -> Result<(), BytecodeError> {
+        self.check(4, BytecodeError::NotBytecode)?;
+        self.version()?;
+        self.format()?;
+        self.check(6, BytecodeError::Corrupted)?;
+        self.size("Instruction")?;
+        let integer_size = self.size("lua_Integer")?;
+        let number_size = self.size("lua_Number")?;
+
+        // The integer 0x5678, in the byte order of the platform.
+        let (integer, expected) = self.take(integer_size)?;
+        if integer != expected {
+            let swapped = integer.iter().rev().eq(expected.iter());
+            return Err(match swapped {
+                true => BytecodeError::Endianness,
+                false => BytecodeError::Corrupted,
+            });
+        }
+        self.check(number_size, BytecodeError::NumberFormat)
+    }
+
+    // Header of `ldump.c` in Lua 5.2.
+    #[cfg(feature = "_luaapi_52")]
+    fn verify(&mut self) -> Result<(), BytecodeError> {
+        self.check(4, BytecodeError::NotBytecode)?;
+        self.version()?;
+        self.format()?;
+        self.check(1, BytecodeError::Endianness)?;
+        self.size("int")?;
+        self.size("size_t")?;
+        self.size("Instruction")?;
+        self.size("lua_Number")?;
+        self.check(1, BytecodeError::NumberFormat)?;
+        self.check(6, BytecodeError::Corrupted)
+    }
+
+    // Header of `lj_bcdump.h` in LuaJIT 2. The flags also indicate whether the code was
+    // stripped, which doesn't matter.
+    #[cfg(feature = "_luaapi_lj2")]
+    fn verify(&mut self) -> Result<(), BytecodeError> {
+        const FLAG_BE: u8 = 0x01;
+        const FLAG_STRIP: u8 = 0x02;
+
+        self.check(3, BytecodeError::NotBytecode)?;
+        self.version()?;
+        let (flags, expected) = self.byte()?;
+        if (flags ^ expected) & FLAG_BE != 0 {
+            return Err(BytecodeError::Endianness);
+        }
+        match (flags ^ expected) & !FLAG_STRIP {
+            0 => Ok(()),
+            _ => Err(BytecodeError::Format { expected, found: flags }),
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "_luaapi_lj2")))]
+mod tests {
+    use crate::{verify_bytecode, BytecodeError, Lua, LuaFunction};
+
+    fn bytecode() -> Vec<u8> {
+        let mut lua = Lua::new();
+        let mut bytecode = Vec::new();
+        let mut f = LuaFunction::load(&mut lua, "local a = ... return a * 2").unwrap();
+        f.dump_to(&mut bytecode, false).unwrap();
+        bytecode
+    }
+
+    #[test]
+    fn valid_and_deterministic() {
+        let bytecode = bytecode();
+        assert_eq!(verify_bytecode(&bytecode), Ok(()));
+        assert_eq!(bytecode, self::bytecode());
+    }
+
+    #[test]
+    fn incompatible_headers() {
+        let bytecode = bytecode();
+        let modified = |position: usize, value: u8| {
+            let mut modified = bytecode.clone();
+            modified[position] = value;
+            verify_bytecode(&modified)
+        };
+
+        assert_eq!(verify_bytecode(b"\x1bLu"), Err(BytecodeError::Truncated));
+        assert_eq!(verify_bytecode(&bytecode[..10]), Err(BytecodeError::Truncated));
+        assert_eq!(modified(0, b'-'), Err(BytecodeError::NotBytecode));
+        assert!(matches!(modified(4, 0x53), Err(BytecodeError::Version { found: 0x53, .. })));
+        assert!(matches!(modified(5, 1), Err(BytecodeError::Format { expected: 0, found: 1 })));
+
+        #[cfg(feature = "_luaapi_54")]
+        {
+            // Conversion of "\r\n" to "\n".
+            let mut converted = bytecode.clone();
+            converted.remove(8);
+            assert_eq!(verify_bytecode(&converted), Err(BytecodeError::Corrupted));
+            assert!(matches!(
+                modified(14, 4),
+                Err(BytecodeError::Size { name: "lua_Number", expected: 8, found: 4 })
+            ));
+
+            let integer_size = bytecode[13] as usize;
+            let mut swapped = bytecode.clone();
+            swapped[15..15 + integer_size].reverse();
+            assert_eq!(verify_bytecode(&swapped), Err(BytecodeError::Endianness));
+            let number_end = 15 + integer_size + bytecode[14] as usize;
+            assert_eq!(modified(number_end - 1, 0), Err(BytecodeError::NumberFormat));
+        }
+        #[cfg(feature = "_luaapi_52")]
+        {
+            assert_eq!(modified(6, 1 - bytecode[6]), Err(BytecodeError::Endianness));
+            assert!(matches!(modified(8, 4), Err(BytecodeError::Size { name: "size_t", .. })));
+            assert_eq!(modified(11, 1), Err(BytecodeError::NumberFormat));
+        }
+    }
+}
@@ -0,0 +1,869 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    ffi::CStr,
+    fmt,
+    str::{self, FromStr},
+    sync::{Arc, Mutex, MutexGuard, TryLockError},
+};
+
+use crate::patterns::string_arg;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsMutLua, Lua, LuaContext, OrDefault, Push};
+
+/// Console commands that can be run from command lines, by Rust and by Lua.
+///
+/// Each command is a Rust closure whose parameters implement `CommandArg`. A command line is the
+/// name of a command followed by its arguments, separated by whitespace. Arguments that contain
+/// whitespace can be quoted with `"` or `'`, and a backslash escapes the next character inside
+/// quotes. The arguments are parsed into the types of the parameters of the closure before it
+/// is called, so the closure only runs with valid arguments.
+///
+/// The registry also generates the help text of the commands from their parameters, and
+/// returns the candidates for completing a partial command line, which is what an in-game
+/// console needs. Clones of the registry share its commands, and `Lua::open_commands` makes
+/// them available to scripts.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{CommandArg, CommandRegistry};
+///
+/// #[derive(Debug, Clone, Copy)]
+/// enum Difficulty { Easy, Hard }
+///
+/// impl CommandArg for Difficulty {
+///     fn type_name() -> &'static str { "difficulty" }
+///
+///     fn parse(word: &str) -> Result<Difficulty, String> {
+///         match word {
+///             "easy" => Ok(Difficulty::Easy),
+///             "hard" => Ok(Difficulty::Hard),
+///             _ => Err("expected easy or hard".to_owned()),
+///         }
+///     }
+///
+///     fn completions() -> Vec<String> {
+///         vec!["easy".to_owned(), "hard".to_owned()]
+///     }
+/// }
+///
+/// let commands = CommandRegistry::new();
+/// commands.register("spawn name count", "Spawns monsters.", |name: String, count: Option<u32>| {
+///     format!("spawned {} {}", count.unwrap_or(1), name)
+/// });
+/// commands.register("difficulty level", "Sets the difficulty.", |level: Difficulty| {
+///     format!("difficulty is now {:?}", level)
+/// });
+///
+/// assert_eq!(commands.dispatch("spawn 'cave troll' 3").unwrap(), "spawned 3 cave troll");
+/// assert_eq!(commands.usage("spawn").unwrap(), "spawn <name: string> [count: integer]");
+/// assert_eq!(commands.complete("difficulty e").candidates, ["easy"]);
+///
+/// let err = commands.dispatch("difficulty normal").unwrap_err();
+/// assert_eq!(err.to_string(), "bad argument 'level' to 'difficulty' (expected easy or hard)");
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.open_commands(commands.clone());
+/// let output: String = lua.execute("return commands.dispatch('difficulty hard')").unwrap();
+/// assert_eq!(output, "difficulty is now Hard");
+/// ```
+#[derive(Clone, Default)]
+pub struct CommandRegistry {
+    commands: Arc<Mutex<BTreeMap<String, Arc<Command>>>>,
+}
+
+struct Command {
+    name: String,
+    help: String,
+    params: Vec<Param>,
+    handler: Mutex<Box<BoxedHandler>>,
+}
+
+type BoxedHandler = dyn FnMut(&[String]) -> Result<String, CommandError> + Send;
+
+// The type name, whether the parameter is optional, and its completions.
+type ParamSpec = (&'static str, bool, fn() -> Vec<String>);
+
+struct Param {
+    name: String,
+    type_name: &'static str,
+    optional: bool,
+    completions: fn() -> Vec<String>,
+}
+
+/// Type of a parameter of a command, parsed from one word of a command line.
+pub trait CommandArg: Sized {
+    /// Name of the type, shown in the help text of the commands.
+    fn type_name() -> &'static str;
+
+    /// Parses a word of a command line. The error explains what was expected.
+    fn parse(word: &str) -> Result<Self, String>;
+
+    /// Returns the value of the parameter when the command line stops before it, or `None` if
+    /// the argument is required, which is the default.
+    #[inline]
+    fn missing() -> Option<Self> {
+        None
+    }
+
+    /// Returns the words that can be given for this parameter, to complete command lines.
+    /// Returns nothing by default.
+    #[inline]
+    fn completions() -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Closures that can be registered as commands, whose parameters are `P`.
+///
+/// This is implemented for the closures of up to 8 parameters that implement `CommandArg` and
+/// that return a `CommandOutput`.
+pub trait CommandHandler<P>: Send + 'static {
+    #[doc(hidden)]
+    fn params() -> Vec<ParamSpec>;
+
+    #[doc(hidden)]
+    fn run(
+        &mut self,
+        command: &str,
+        names: &[String],
+        args: &[String],
+    ) -> Result<String, CommandError>;
+}
+
+/// Values that commands can return, converted to the output of the command.
+pub trait CommandOutput {
+    /// Returns the output of the command, or the error that it failed with.
+    fn into_output(self) -> Result<String, String>;
+}
+
+impl CommandOutput for () {
+    #[inline]
+    fn into_output(self) -> Result<String, String> {
+        Ok(String::new())
+    }
+}
+
+impl CommandOutput for String {
+    #[inline]
+    fn into_output(self) -> Result<String, String> {
+        Ok(self)
+    }
+}
+
+impl CommandOutput for &str {
+    #[inline]
+    fn into_output(self) -> Result<String, String> {
+        Ok(self.to_owned())
+    }
+}
+
+impl<T, E> CommandOutput for Result<T, E>
+where
+    T: CommandOutput,
+    E: fmt::Display,
+{
+    #[inline]
+    fn into_output(self) -> Result<String, String> {
+        self.map_err(|err| err.to_string()).and_then(T::into_output)
+    }
+}
+
+/// Candidates for completing the last word of a command line. See `CommandRegistry::complete`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Completion {
+    /// The words that start like the last word, sorted. Each of them replaces the whole word.
+    pub candidates: Vec<String>,
+    /// Name and type of the parameter that the last word is for, such as `count: integer`, or
+    /// `None` if it is the name of the command or if there is no such parameter.
+    pub parameter: Option<String>,
+}
+
+/// Error that can happen when running a command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// A quoted argument isn't closed.
+    UnterminatedQuote,
+    /// There is no command with this name.
+    UnknownCommand(String),
+    /// A required argument isn't given.
+    MissingArgument {
+        /// The usage of the command, as returned by `CommandRegistry::usage`.
+        usage: String,
+        /// The name of the parameter.
+        parameter: String,
+    },
+    /// There are more arguments than parameters.
+    TooManyArguments {
+        /// The usage of the command, as returned by `CommandRegistry::usage`.
+        usage: String,
+    },
+    /// An argument can't be parsed into the type of its parameter.
+    BadArgument {
+        /// The name of the command.
+        command: String,
+        /// The name of the parameter.
+        parameter: String,
+        /// The error returned by `CommandArg::parse`.
+        message: String,
+    },
+    /// The command is already running, and called itself.
+    AlreadyRunning(String),
+    /// The command ran and returned an error.
+    Failed(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::UnterminatedQuote => write!(f, "unterminated quote"),
+            CommandError::UnknownCommand(name) => write!(f, "unknown command '{}'", name),
+            CommandError::MissingArgument { usage, parameter } => {
+                write!(f, "missing argument '{}' (usage: {})", parameter, usage)
+            },
+            CommandError::TooManyArguments { usage } => {
+                write!(f, "too many arguments (usage: {})", usage)
+            },
+            CommandError::BadArgument { command, parameter, message } => {
+                write!(f, "bad argument '{}' to '{}' ({})", parameter, command, message)
+            },
+            CommandError::AlreadyRunning(name) => {
+                write!(f, "command '{}' is already running", name)
+            },
+            CommandError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+impl CommandRegistry {
+    /// Creates a registry without commands.
+    #[inline]
+    pub fn new() -> CommandRegistry {
+        CommandRegistry::default()
+    }
+
+    /// Registers a command, replacing the command with the same name if there is one.
+    ///
+    /// `usage` is the name of the command followed by the names of its parameters, separated by
+    /// whitespace, for example `"spawn name count"`. `help` describes the command.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the number of parameter names isn't the number of parameters of `handler`.
+    pub fn register<P, F>(&self, usage: &str, help: &str, mut handler: F)
+    where
+        F: CommandHandler<P>,
+    {
+        let mut words = usage.split_whitespace();
+        let name = words.next().expect("the usage of a command starts with its name").to_owned();
+        let names: Vec<&str> = words.collect();
+        let specs = F::params();
+        assert_eq!(
+            names.len(),
+            specs.len(),
+            "the usage of command '{}' doesn't name each of its {} parameters",
+            name,
+            specs.len()
+        );
+
+        let params = names
+            .iter()
+            .zip(specs)
+            .map(|(name, (type_name, optional, completions))| Param {
+                name: (*name).to_owned(),
+                type_name,
+                optional,
+                completions,
+            })
+            .collect();
+        let names: Vec<String> = names.iter().map(|name| (*name).to_owned()).collect();
+        let command_name = name.clone();
+        let handler: Box<BoxedHandler> =
+            Box::new(move |args: &[String]| handler.run(&command_name, &names, args));
+
+        let command = Command {
+            name: name.clone(),
+            help: help.to_owned(),
+            params,
+            handler: Mutex::new(handler),
+        };
+        self.lock().insert(name, Arc::new(command));
+    }
+
+    /// Removes a command. Returns false if there was no command with this name.
+    #[inline]
+    pub fn unregister(&self, name: &str) -> bool {
+        self.lock().remove(name).is_some()
+    }
+
+    /// Returns the names of the commands, sorted.
+    pub fn names(&self) -> Vec<String> {
+        self.lock().keys().cloned().collect()
+    }
+
+    /// Runs a command line, and returns the output of the command.
+    ///
+    /// An empty command line does nothing and returns an empty output. Commands can run command
+    /// lines too, except for themselves.
+    pub fn dispatch(&self, line: &str) -> Result<String, CommandError> {
+        let words = split_words(line);
+        if words.unterminated {
+            return Err(CommandError::UnterminatedQuote);
+        }
+        let (name, args) = match words.words.split_first() {
+            Some(split) => split,
+            None => return Ok(String::new()),
+        };
+
+        // The registry is unlocked while the command runs, since it can use the registry too.
+        let command =
+            self.command(name).ok_or_else(|| CommandError::UnknownCommand(name.clone()))?;
+        let mut handler = match command.handler.try_lock() {
+            Ok(handler) => handler,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                return Err(CommandError::AlreadyRunning(name.clone()));
+            },
+        };
+        handler(args).map_err(|err| match err {
+            CommandError::MissingArgument { parameter, .. } => {
+                CommandError::MissingArgument { usage: command.usage(), parameter }
+            },
+            CommandError::TooManyArguments { .. } => {
+                CommandError::TooManyArguments { usage: command.usage() }
+            },
+            err => err,
+        })
+    }
+
+    /// Returns the usage of a command, for example `spawn <name: string> [count: integer]`,
+    /// where optional parameters are between brackets.
+    pub fn usage(&self, name: &str) -> Option<String> {
+        self.command(name).map(|command| command.usage())
+    }
+
+    /// Returns the help text of a command: its usage, followed by its description on the next
+    /// line if it has one.
+    pub fn help(&self, name: &str) -> Option<String> {
+        self.command(name).map(|command| match command.help.as_str() {
+            "" => command.usage(),
+            help => format!("{}\n    {}", command.usage(), help),
+        })
+    }
+
+    /// Returns the help text of all the commands, sorted by name: one line per command,
+    /// containing its usage and the first line of its description.
+    pub fn help_all(&self) -> String {
+        let commands = self.lock();
+        let lines: Vec<_> = commands
+            .values()
+            .map(|command| match command.help.lines().next() {
+                Some(summary) => format!("{} - {}", command.usage(), summary),
+                None => command.usage(),
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    /// Returns the candidates for completing the last word of a partial command line.
+    ///
+    /// If the line ends with whitespace, the candidates are for the next word. The first word is
+    /// completed with the names of the commands, and the others with the
+    /// `CommandArg::completions` of their parameter.
+    pub fn complete(&self, line: &str) -> Completion {
+        let mut words = split_words(line);
+        if words.words.is_empty() || (words.ends_with_space && !words.unterminated) {
+            words.words.push(String::new());
+        }
+        let (last, previous) = words.words.split_last().expect("there is at least one word");
+        let matching = |candidates: Vec<String>| -> Vec<String> {
+            candidates.into_iter().filter(|c| c.starts_with(last.as_str())).collect()
+        };
+
+        let (name, args) = match previous.split_first() {
+            Some(split) => split,
+            None => return Completion { candidates: matching(self.names()), parameter: None },
+        };
+        let param = self.command(name).and_then(|command| {
+            let param = command.params.get(args.len())?;
+            Some((format!("{}: {}", param.name, param.type_name), param.completions))
+        });
+        match param {
+            Some((parameter, completions)) => {
+                let mut candidates = matching(completions());
+                candidates.sort();
+                candidates.dedup();
+                Completion { candidates, parameter: Some(parameter) }
+            },
+            None => Completion::default(),
+        }
+    }
+
+    fn command(&self, name: &str) -> Option<Arc<Command>> {
+        self.lock().get(name).cloned()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Arc<Command>>> {
+        // The map is never left in an inconsistent state, so a panic while it was locked
+        // doesn't matter.
+        self.commands.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for CommandRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CommandRegistry").field("commands", &self.names()).finish()
+    }
+}
+
+impl Command {
+    fn usage(&self) -> String {
+        let mut usage = self.name.clone();
+        for param in &self.params {
+            let (open, close) = if param.optional { ('[', ']') } else { ('<', '>') };
+            usage.push_str(&format!(" {}{}: {}{}", open, param.name, param.type_name, close));
+        }
+        usage
+    }
+}
+
+struct Words {
+    words: Vec<String>,
+    unterminated: bool,
+    ends_with_space: bool,
+}
+
+fn split_words(line: &str) -> Words {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    let mut unterminated = false;
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let first = match chars.peek() {
+            Some(&first) => first,
+            None => break,
+        };
+
+        let mut word = String::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            unterminated = true;
+            while let Some(c) = chars.next() {
+                match c {
+                    c if c == first => {
+                        unterminated = false;
+                        break;
+                    },
+                    '\\' => word.extend(chars.next()),
+                    c => word.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+
+    let ends_with_space = line.ends_with(char::is_whitespace);
+    Words { words, unterminated, ends_with_space }
+}
+
+impl CommandArg for String {
+    #[inline]
+    fn type_name() -> &'static str {
+        "string"
+    }
+
+    #[inline]
+    fn parse(word: &str) -> Result<String, String> {
+        Ok(word.to_owned())
+    }
+}
+
+impl CommandArg for bool {
+    #[inline]
+    fn type_name() -> &'static str {
+        "boolean"
+    }
+
+    fn parse(word: &str) -> Result<bool, String> {
+        match word {
+            "true" | "on" | "yes" | "1" => Ok(true),
+            "false" | "off" | "no" | "0" => Ok(false),
+            _ => Err("boolean expected".to_owned()),
+        }
+    }
+
+    fn completions() -> Vec<String> {
+        vec!["false".to_owned(), "true".to_owned()]
+    }
+}
+
+macro_rules! impl_number_arg {
+    ($type_name:expr, $expected:expr; $($ty:ty),+) => {
+        $(
+            impl CommandArg for $ty {
+                #[inline]
+                fn type_name() -> &'static str {
+                    $type_name
+                }
+
+                #[inline]
+                fn parse(word: &str) -> Result<$ty, String> {
+                    <$ty as FromStr>::from_str(word).map_err(|_| $expected.to_owned())
+                }
+            }
+        )+
+    };
+}
+
+impl_number_arg!("integer", "integer expected"; i8, i16, i32, i64, u8, u16, u32, u64, usize);
+impl_number_arg!("number", "number expected"; f32, f64);
+
+impl<T> CommandArg for Option<T>
+where
+    T: CommandArg,
+{
+    #[inline]
+    fn type_name() -> &'static str {
+        T::type_name()
+    }
+
+    #[inline]
+    fn parse(word: &str) -> Result<Option<T>, String> {
+        T::parse(word).map(Some)
+    }
+
+    #[inline]
+    fn missing() -> Option<Option<T>> {
+        Some(None)
+    }
+
+    #[inline]
+    fn completions() -> Vec<String> {
+        T::completions()
+    }
+}
+
+impl<T> CommandArg for OrDefault<T>
+where
+    T: CommandArg + Default,
+{
+    #[inline]
+    fn type_name() -> &'static str {
+        T::type_name()
+    }
+
+    #[inline]
+    fn parse(word: &str) -> Result<OrDefault<T>, String> {
+        T::parse(word).map(OrDefault)
+    }
+
+    #[inline]
+    fn missing() -> Option<OrDefault<T>> {
+        Some(OrDefault(T::default()))
+    }
+
+    #[inline]
+    fn completions() -> Vec<String> {
+        T::completions()
+    }
+}
+
+fn parse_arg<T: CommandArg>(
+    command: &str,
+    name: &str,
+    arg: Option<&String>,
+) -> Result<T, CommandError> {
+    match arg {
+        Some(word) => T::parse(word).map_err(|message| CommandError::BadArgument {
+            command: command.to_owned(),
+            parameter: name.to_owned(),
+            message,
+        }),
+        None => T::missing().ok_or_else(|| CommandError::MissingArgument {
+            usage: String::new(),
+            parameter: name.to_owned(),
+        }),
+    }
+}
+
+macro_rules! impl_command_handler {
+    ($($p:ident $i:tt),*) => {
+        impl<Z, R $(, $p)*> CommandHandler<($($p,)*)> for Z
+        where
+            Z: FnMut($($p),*) -> R + Send + 'static,
+            R: CommandOutput,
+            $($p: CommandArg,)*
+        {
+            fn params() -> Vec<ParamSpec> {
+                vec![$(($p::type_name(), $p::missing().is_some(), $p::completions as fn() -> _)),*]
+            }
+
+            #[allow(unused_variables)]
+            fn run(&mut self, command: &str, names: &[String], args: &[String])
+                -> Result<String, CommandError>
+            {
+                const INDICES: &[usize] = &[$($i),*];
+                if args.len() > INDICES.len() {
+                    return Err(CommandError::TooManyArguments { usage: String::new() });
+                }
+                $(
+                    #[allow(non_snake_case)]
+                    let $p: $p = parse_arg(command, &names[$i], args.get($i))?;
+                )*
+                self($($p),*).into_output().map_err(CommandError::Failed)
+            }
+        }
+    };
+}
+
+impl_command_handler!();
+impl_command_handler!(A 0);
+impl_command_handler!(A 0, B 1);
+impl_command_handler!(A 0, B 1, C 2);
+impl_command_handler!(A 0, B 1, C 2, D 3);
+impl_command_handler!(A 0, B 1, C 2, D 3, E 4);
+impl_command_handler!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_command_handler!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_command_handler!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+type Method = (&'static CStr, ffi::lua_CFunction);
+
+impl<'lua> Lua<'lua> {
+    /// Opens a `commands` library that lets scripts run the commands of `registry`.
+    ///
+    /// - `commands.dispatch(line)` runs a command line and returns its output, or nil and the
+    ///   error message.
+    /// - `commands.help([name])` returns the help text of a command, or nil if there is no such
+    ///   command, or the help text of all the commands without a name.
+    /// - `commands.complete(line)` returns an array of candidates for completing the last word of
+    ///   the line, and the parameter that it is for, if any.
+    /// - `commands.names()` returns an array of the names of the commands.
+    ///
+    /// The library shares the commands of `registry`, so the commands registered afterwards can
+    /// be used too.
+    pub fn open_commands(&mut self, registry: CommandRegistry) {
+        let functions: [Method; 4] = [
+            (c"dispatch", Some(dispatch)),
+            (c"help", Some(help)),
+            (c"complete", Some(complete)),
+            (c"names", Some(names)),
+        ];
+
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_createtable(l, 0, functions.len() as _);
+            push_userdata(registry, raw_lua, |_| {}).forget();
+            for (name, function) in functions {
+                ffi::lua_pushvalue(l, -1);
+                ffi::lua_pushcclosure(l, function, 1);
+                ffi::lua_setfield(l, -3, name.as_ptr());
+            }
+            ffi::lua_pop(l, 1);
+
+            ffi::lua_getfield(l, -2, c"package".as_ptr());
+            if ffi::lua_istable(l, -1) {
+                ffi::lua_getfield(l, -1, c"loaded".as_ptr());
+                if ffi::lua_istable(l, -1) {
+                    ffi::lua_pushvalue(l, -3);
+                    ffi::lua_setfield(l, -2, c"commands".as_ptr());
+                }
+                ffi::lua_pop(l, 1);
+            }
+            ffi::lua_pop(l, 1);
+
+            ffi::lua_setfield(l, -2, c"commands".as_ptr());
+            ffi::lua_pop(l, 1);
+        }
+    }
+}
+
+unsafe fn registry(lua: *mut ffi::lua_State) -> CommandRegistry {
+    let raw_lua = LuaContext::new_unchecked(lua);
+    userdata_mut::<CommandRegistry>(raw_lua, ffi::lua_upvalueindex(1))
+        .expect("the upvalue of the commands functions is a CommandRegistry")
+        .clone()
+}
+
+unsafe fn push_str(lua: *mut ffi::lua_State, value: &str) {
+    ffi::lua_pushlstring(lua, value.as_ptr().cast(), value.len());
+}
+
+unsafe fn push_strings(lua: *mut ffi::lua_State, values: &[String]) {
+    ffi::lua_createtable(lua, values.len() as _, 0);
+    for (index, value) in values.iter().enumerate() {
+        push_str(lua, value);
+        ffi::lua_rawseti(lua, -2, (index + 1) as _);
+    }
+}
+
+unsafe fn line_arg<'a>(lua: *mut ffi::lua_State, function: &str) -> &'a str {
+    match string_arg(lua, 1).map(str::from_utf8) {
+        Some(Ok(line)) => line,
+        _ => {
+            format!("bad argument #1 to '{}' (string expected)", function)
+                .push_no_err(LuaContext::new_unchecked(lua))
+                .forget();
+            ffix::lua_error(lua);
+        },
+    }
+}
+
+extern "C" fn dispatch(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let line = line_arg(lua, "dispatch").to_owned();
+        match registry(lua).dispatch(&line) {
+            Ok(output) => {
+                push_str(lua, &output);
+                1
+            },
+            Err(err) => {
+                ffi::lua_pushnil(lua);
+                push_str(lua, &err.to_string());
+                2
+            },
+        }
+    }
+}
+
+extern "C" fn help(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let help = match ffi::lua_isnoneornil(lua, 1) {
+            true => Some(registry(lua).help_all()),
+            false => registry(lua).help(line_arg(lua, "help")),
+        };
+        match help {
+            Some(help) => push_str(lua, &help),
+            None => ffi::lua_pushnil(lua),
+        }
+        1
+    }
+}
+
+extern "C" fn complete(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let completion = registry(lua).complete(line_arg(lua, "complete"));
+        push_strings(lua, &completion.candidates);
+        match completion.parameter {
+            Some(parameter) => push_str(lua, &parameter),
+            None => ffi::lua_pushnil(lua),
+        }
+        2
+    }
+}
+
+extern "C" fn names(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        push_strings(lua, &registry(lua).names());
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CommandError, CommandRegistry, Lua, OrDefault};
+
+    fn registry() -> CommandRegistry {
+        let commands = CommandRegistry::new();
+        commands.register("add a b", "Adds two numbers.\nBoth are integers.", |a: i32, b: i32| {
+            (a + b).to_string()
+        });
+        commands.register("echo text times", "", |text: String, times: OrDefault<u8>| {
+            text.repeat(times.0.max(1) as usize)
+        });
+        commands.register("god enabled", "Toggles god mode.", |enabled: bool| {
+            if enabled {
+                Ok("god mode on")
+            } else {
+                Err("can't leave god mode")
+            }
+        });
+        let inner = commands.clone();
+        commands.register("run line", "Runs a command line.", move |line: String| {
+            inner.dispatch(&line).map_err(|err| err.to_string())
+        });
+        commands
+    }
+
+    #[test]
+    fn dispatch_and_errors() {
+        let commands = registry();
+        assert_eq!(commands.dispatch("add 2 3").unwrap(), "5");
+        assert_eq!(commands.dispatch("  echo \"a \\\"b\\\"\" 2 ").unwrap(), "a \"b\"a \"b\"");
+        assert_eq!(commands.dispatch("echo 'x y'").unwrap(), "x y");
+        assert_eq!(commands.dispatch("run 'add 1 1'").unwrap(), "2");
+        assert_eq!(commands.dispatch("").unwrap(), "");
+
+        assert_eq!(commands.dispatch("echo 'open"), Err(CommandError::UnterminatedQuote));
+        assert_eq!(commands.dispatch("nope"), Err(CommandError::UnknownCommand("nope".into())));
+        let err = commands.dispatch("add 1").unwrap_err();
+        assert_eq!(err.to_string(), "missing argument 'b' (usage: add <a: integer> <b: integer>)");
+        let err = commands.dispatch("add 1 2 3").unwrap_err();
+        assert_eq!(err.to_string(), "too many arguments (usage: add <a: integer> <b: integer>)");
+        let err = commands.dispatch("add 1 two").unwrap_err();
+        assert_eq!(err.to_string(), "bad argument 'b' to 'add' (integer expected)");
+        assert_eq!(
+            commands.dispatch("god no"),
+            Err(CommandError::Failed("can't leave god mode".into()))
+        );
+        let err = commands.dispatch("run 'run x'").unwrap_err();
+        assert_eq!(err, CommandError::Failed("command 'run' is already running".into()));
+
+        assert!(commands.unregister("add"));
+        assert!(!commands.unregister("add"));
+        assert_eq!(commands.names(), ["echo", "god", "run"]);
+    }
+
+    #[test]
+    fn help_and_completion() {
+        let commands = registry();
+        assert_eq!(
+            commands.help("add").unwrap(),
+            "add <a: integer> <b: integer>\n    Adds two numbers.\nBoth are integers."
+        );
+        assert_eq!(commands.help("echo").unwrap(), "echo <text: string> [times: integer]");
+        assert_eq!(commands.help("nope"), None);
+        let expected = "add <a: integer> <b: integer> - Adds two numbers.\n\
+                        echo <text: string> [times: integer]\n\
+                        god <enabled: boolean> - Toggles god mode.\n\
+                        run <line: string> - Runs a command line.";
+        assert_eq!(commands.help_all(), expected);
+
+        assert_eq!(commands.complete("").candidates, ["add", "echo", "god", "run"]);
+        assert_eq!(commands.complete("e").candidates, ["echo"]);
+        let completion = commands.complete("god ");
+        assert_eq!(completion.candidates, ["false", "true"]);
+        assert_eq!(completion.parameter.as_deref(), Some("enabled: boolean"));
+        assert_eq!(commands.complete("god 't").candidates, ["true"]);
+        assert_eq!(commands.complete("add 1 ").parameter.as_deref(), Some("b: integer"));
+        assert_eq!(commands.complete("add 1 2 3"), Default::default());
+        assert_eq!(commands.complete("nope "), Default::default());
+    }
+
+    #[test]
+    fn lua_library() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_commands(registry());
+
+        let code = "local out, err = commands.dispatch('add 1 x')
+                    local candidates, parameter = commands.complete('god f')
+                    return table.concat({ commands.dispatch('add 4 5'), err,
+                                          table.concat(candidates, ','), parameter,
+                                          table.concat(commands.names(), ','),
+                                          tostring(commands.help('nope')),
+                                          commands.help('god') }, '|')";
+        let expected = "9|bad argument 'b' to 'add' (integer expected)|false|enabled: boolean|\
+                        add,echo,god,run|nil|god <enabled: boolean>\n    Toggles god mode.";
+        assert_eq!(lua.execute::<String>(code).unwrap(), expected);
+        assert!(lua.execute::<()>("commands.dispatch({})").is_err());
+    }
+}
@@ -0,0 +1,257 @@
+#[cfg(not(feature = "_luaapi_51"))]
+use std::{ffi::CStr, mem};
+
+use crate::Lua;
+#[cfg(not(feature = "_luaapi_51"))]
+use crate::{ffix, AsMutLua, LuaContext, Push};
+
+// Shims written in Lua. Each one is only defined if the function is missing, and only if the
+// library it belongs to is opened.
+#[cfg(not(feature = "_luaapi_51"))]
+const LUA_SHIMS: &str = r#"
+local load, pairs, type, table, math = load, pairs, type, table, math
+
+loadstring = loadstring or load
+
+if table then
+    unpack = unpack or table.unpack
+    table.getn = table.getn or function(t) return #t end
+    table.maxn = table.maxn or function(t)
+        local max = 0
+        for key in pairs(t) do
+            if type(key) == 'number' and key > max then max = key end
+        end
+        return max
+    end
+end
+
+if math then
+    math.pow = math.pow or function(x, y) return x ^ y end
+    math.log10 = math.log10 or function(x) return math.log(x, 10) end
+    math.ldexp = math.ldexp or function(m, e) return m * 2.0 ^ e end
+end
+"#;
+
+// Returns a function that returns its argument, used to give `_ENV` a new value without
+// modifying the upvalue shared with other functions.
+#[cfg(not(feature = "_luaapi_51"))]
+const ENV_FACTORY: &str = "local env = ... return function() return env end";
+
+impl<'lua> Lua<'lua> {
+    /// Defines the Lua 5.1 functions that later versions removed, so that scripts written for
+    /// Lua 5.1 can run while they are being migrated.
+    ///
+    /// The following functions are defined if they don't exist:
+    ///
+    /// - `unpack`, as `table.unpack`, and `loadstring`, as `load`.
+    /// - `table.getn`, `table.maxn`, `math.pow`, `math.log10` and `math.ldexp`, if the
+    ///   corresponding library is opened.
+    /// - `setfenv(f, env)` and `getfenv([f])`, emulated by replacing the `_ENV` upvalue of the
+    ///   function. `f` can be a function or a stack level. Contrary to Lua 5.1, the environment
+    ///   of functions that don't use any global variable can't be changed, and level 0 isn't
+    ///   supported.
+    ///
+    /// This should be called after opening the standard libraries. With LuaJIT, which
+    /// implements Lua 5.1, this does nothing.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// lua.open_compat51();
+    ///
+    /// let code = "
+    ///     local function f() return x end
+    ///     setfenv(f, { x = 'sandboxed' })
+    ///     return f() .. ' ' .. select('#', unpack({ 1, 2, 3 }))
+    /// ";
+    /// assert_eq!(lua.execute::<String>(code).unwrap(), "sandboxed 3");
+    /// ```
+    pub fn open_compat51(&mut self) {
+        #[cfg(not(feature = "_luaapi_51"))]
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_pushcfunction(l, Some(setfenv));
+            ffi::lua_setfield(l, -2, c"setfenv".as_ptr());
+            ffi::lua_pushcfunction(l, Some(getfenv));
+            ffi::lua_setfield(l, -2, c"getfenv".as_ptr());
+            ffi::lua_pop(l, 1);
+
+            let status = ffi::luaL_loadbufferx(
+                l,
+                LUA_SHIMS.as_ptr().cast(),
+                LUA_SHIMS.len(),
+                c"=compat51".as_ptr(),
+                std::ptr::null(),
+            );
+            assert_eq!(status, 0, "the compatibility shims are valid Lua code");
+            if ffi::lua_pcall(l, 0, 0, 0) != 0 {
+                ffi::lua_pop(l, 1);
+            }
+        }
+    }
+}
+
+// Raises an error from `setfenv` or `getfenv`.
+#[cfg(not(feature = "_luaapi_51"))]
+unsafe fn raise(lua: *mut ffi::lua_State, msg: &str) -> ! {
+    msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+    ffix::lua_error(lua);
+}
+
+// Pushes the function designated by the argument at `index`, either a function or a stack
+// level where 1 is the function calling `setfenv` or `getfenv`.
+#[cfg(not(feature = "_luaapi_51"))]
+unsafe fn push_target(lua: *mut ffi::lua_State, index: libc::c_int, name: &str) {
+    if ffi::lua_isfunction(lua, index) {
+        ffi::lua_pushvalue(lua, index);
+        return;
+    }
+
+    let mut success = 0;
+    let level = match ffi::lua_isnoneornil(lua, index) {
+        true => 1,
+        false => ffi::lua_tointegerx(lua, index, &mut success) as libc::c_int,
+    };
+    if level < 0 || (success == 0 && !ffi::lua_isnoneornil(lua, index)) {
+        raise(lua, &format!("bad argument #1 to '{}' (function or level expected)", name));
+    }
+    if level == 0 {
+        raise(lua, &format!("'{}' doesn't support level 0", name));
+    }
+
+    let mut debug: ffi::lua_Debug = mem::zeroed();
+    if ffi::lua_getstack(lua, level, &mut debug) == 0 {
+        raise(lua, &format!("bad argument #1 to '{}' (invalid level)", name));
+    }
+    ffi::lua_getinfo(lua, c"f".as_ptr(), &mut debug);
+}
+
+// Returns the number of the `_ENV` upvalue of the function at the top of the stack.
+#[cfg(not(feature = "_luaapi_51"))]
+unsafe fn env_upvalue(lua: *mut ffi::lua_State) -> Option<libc::c_int> {
+    for upvalue in 1.. {
+        let name = ffi::lua_getupvalue(lua, -1, upvalue);
+        if name.is_null() {
+            break;
+        }
+        ffi::lua_pop(lua, 1);
+        if CStr::from_ptr(name).to_bytes() == b"_ENV" {
+            return Some(upvalue);
+        }
+    }
+    None
+}
+
+#[cfg(not(feature = "_luaapi_51"))]
+extern "C" fn setfenv(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        if !ffi::lua_istable(lua, 2) {
+            raise(lua, "bad argument #2 to 'setfenv' (table expected)");
+        }
+        ffi::lua_settop(lua, 2);
+        push_target(lua, 1, "setfenv");
+        if ffi::lua_iscfunction(lua, -1) != 0 {
+            raise(lua, "'setfenv' cannot change the environment of this object");
+        }
+
+        if let Some(upvalue) = env_upvalue(lua) {
+            let status = ffi::luaL_loadbufferx(
+                lua,
+                ENV_FACTORY.as_ptr().cast(),
+                ENV_FACTORY.len(),
+                c"=setfenv".as_ptr(),
+                std::ptr::null(),
+            );
+            assert_eq!(status, 0, "the environment factory is valid Lua code");
+            ffi::lua_pushvalue(lua, 2);
+            ffi::lua_call(lua, 1, 1);
+            ffi::lua_upvaluejoin(lua, -2, upvalue, -1, 1);
+            ffi::lua_pop(lua, 1);
+        }
+        1
+    }
+}
+
+#[cfg(not(feature = "_luaapi_51"))]
+extern "C" fn getfenv(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        ffi::lua_settop(lua, 1);
+        push_target(lua, 1, "getfenv");
+        match env_upvalue(lua) {
+            Some(upvalue) => {
+                ffi::lua_getupvalue(lua, -1, upvalue);
+            },
+            None => ffix::lua_pushglobaltable(LuaContext::new_unchecked(lua)),
+        }
+        1
+    }
+}
+
+#[cfg(all(test, not(feature = "_luaapi_51")))]
+mod tests {
+    use crate::Lua;
+
+    fn lua() -> Lua<'static> {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_compat51();
+        lua
+    }
+
+    #[test]
+    fn library_shims() {
+        let mut lua = lua();
+        let code = "return table.concat({ unpack({ 'a', 'b' }) })
+                        .. loadstring('return 1')()
+                        .. table.getn({ 1, 2, 3 })
+                        .. table.maxn({ [7] = true, 2 })
+                        .. math.floor(math.pow(2, 10))
+                        .. math.floor(math.log10(1000) + 0.5)
+                        .. math.floor(math.ldexp(3, 2))";
+        assert_eq!(lua.execute::<String>(code).unwrap(), "ab1371024312");
+    }
+
+    #[test]
+    fn existing_functions_are_kept() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.execute::<()>("unpack = function() return 'custom' end").unwrap();
+        lua.open_compat51();
+        assert_eq!(lua.execute::<String>("return unpack({})").unwrap(), "custom");
+
+        // Without the libraries, only the shims that don't depend on them are defined.
+        let mut lua = Lua::new();
+        lua.open_compat51();
+        assert!(lua.execute::<bool>("return unpack == nil and setfenv ~= nil").unwrap());
+    }
+
+    #[test]
+    fn setfenv_and_getfenv() {
+        let mut lua = lua();
+        let code = "
+            x = 'global'
+            local function a() return x end
+            local function b() return x end
+            local env = { x = 'private' }
+            assert(setfenv(a, env) == a)
+            assert(getfenv(a) == env and getfenv(b) == _G and getfenv() == _G)
+            return a() .. ' ' .. b()
+        ";
+        assert_eq!(lua.execute::<String>(code).unwrap(), "private global");
+
+        let code = "
+            local function f()
+                setfenv(1, { y = 'level' })
+                return y
+            end
+            return f()
+        ";
+        assert_eq!(lua.execute::<String>(code).unwrap(), "level");
+        assert!(lua.execute::<()>("setfenv(print, {})").is_err());
+        assert!(lua.execute::<()>("setfenv(0, {})").is_err());
+    }
+}
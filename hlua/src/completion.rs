@@ -0,0 +1,361 @@
+use std::{collections::BTreeMap, slice, str};
+
+use crate::{ffix, AsMutLua, Lua, Push, Session};
+
+// Maximum number of `__index` tables followed when looking up a name.
+const MAX_INDEX_CHAIN: usize = 8;
+
+/// What a completion candidate refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CompletionKind {
+    /// A Lua or Rust function.
+    Function,
+    /// A table, whose fields can be completed after a `.`.
+    Table,
+    /// Any other value.
+    Value,
+}
+
+/// Name that can complete the word before the cursor. See `Lua::complete`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletionCandidate {
+    /// The name, which replaces the part of the word after the last `.` or `:`.
+    pub name: String,
+    /// What the name refers to.
+    pub kind: CompletionKind,
+    /// Description of the name from the `ApiSchema` of the context, if any.
+    pub doc: Option<String>,
+}
+
+/// Description of the API exposed to scripts, used by `Lua::complete` to document candidates
+/// and to complete names that don't exist yet, such as the fields of a table created lazily.
+///
+/// The schema is stored in the context with `Lua::set_app_data`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{ApiSchema, CompletionKind, Lua};
+///
+/// let mut lua = Lua::new();
+/// lua.set_app_data(
+///     ApiSchema::new()
+///         .entry("player.move", CompletionKind::Function, "move(dx, dy): moves the player")
+///         .entry("player.name", CompletionKind::Value, "name of the player"),
+/// );
+///
+/// let candidates = lua.complete("player.m", 8);
+/// assert_eq!(candidates[0].name, "move");
+/// assert_eq!(candidates[0].doc.as_deref(), Some("move(dx, dy): moves the player"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ApiSchema {
+    entries: BTreeMap<String, (CompletionKind, String)>,
+}
+
+impl ApiSchema {
+    /// Creates an empty schema.
+    #[inline]
+    pub fn new() -> ApiSchema {
+        ApiSchema::default()
+    }
+
+    /// Describes the value at `path`, a dotted name such as `player.move`.
+    ///
+    /// The tables along the path don't need to be described.
+    pub fn entry(mut self, path: &str, kind: CompletionKind, doc: impl Into<String>) -> ApiSchema {
+        self.entries.insert(path.to_owned(), (kind, doc.into()));
+        self
+    }
+
+    // Adds the children of the table at `parent` whose name starts with `partial`.
+    fn complete(
+        &self,
+        parent: &str,
+        partial: &str,
+        methods: bool,
+        out: &mut BTreeMap<String, CompletionCandidate>,
+    ) {
+        for (path, (kind, doc)) in &self.entries {
+            let rest = match parent {
+                "" => &path[..],
+                _ => match path.strip_prefix(parent).and_then(|r| r.strip_prefix('.')) {
+                    Some(rest) => rest,
+                    None => continue,
+                },
+            };
+            if !rest.starts_with(partial) {
+                continue;
+            }
+
+            let (name, kind, doc) = match rest.split_once('.') {
+                Some((name, _)) => (name, CompletionKind::Table, None),
+                None => (rest, *kind, Some(doc)),
+            };
+            if methods && kind != CompletionKind::Function {
+                continue;
+            }
+
+            let candidate = out.entry(name.to_owned()).or_insert_with(|| CompletionCandidate {
+                name: name.to_owned(),
+                kind,
+                doc: None,
+            });
+            if candidate.doc.is_none() {
+                candidate.doc = doc.cloned();
+            }
+        }
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Returns the names that can complete the word before `cursor`, a byte offset in `line`,
+    /// for the console of an application.
+    ///
+    /// The word can be a global variable or a path such as `player.inventory.it` or
+    /// `player:mo`, whose last part is completed with the fields of the table it designates.
+    /// The fields of the `__index` tables of metatables, such as the methods of user data, are
+    /// included, and only functions are proposed after a `:`. The names described by the
+    /// `ApiSchema` stored in the context are added, with their documentation.
+    ///
+    /// No Lua code is run: `__index` functions are ignored. The candidates are sorted by name.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{CompletionKind, Lua};
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.execute::<()>("player = { name = 'ada', play = function() end }").unwrap();
+    ///
+    /// let candidates = lua.complete("pl", 2);
+    /// assert_eq!(candidates.len(), 1);
+    /// assert_eq!(candidates[0].name, "player");
+    /// assert_eq!(candidates[0].kind, CompletionKind::Table);
+    ///
+    /// let names: Vec<_> = lua.complete("player.", 7).into_iter().map(|c| c.name).collect();
+    /// assert_eq!(names, ["name", "play"]);
+    /// ```
+    pub fn complete(&mut self, line: &str, cursor: usize) -> Vec<CompletionCandidate> {
+        unsafe { ffix::lua_pushglobaltable(self.as_mut_lua()) };
+        complete_from_top(self, line, cursor)
+    }
+}
+
+impl Session {
+    /// Returns the names that can complete the word before `cursor`, including the variables of
+    /// the session. See `Lua::complete`.
+    pub fn complete(&self, lua: &mut Lua, line: &str, cursor: usize) -> Vec<CompletionCandidate> {
+        unsafe { self.env().push_no_err(&mut *lua).forget() };
+        complete_from_top(lua, line, cursor)
+    }
+}
+
+// Completes the word before `cursor` with the fields of the table at the top of the stack, which
+// is popped.
+fn complete_from_top(lua: &mut Lua, line: &str, cursor: usize) -> Vec<CompletionCandidate> {
+    let l = lua.as_mut_lua().as_ptr();
+    let mut candidates = BTreeMap::new();
+
+    if let Some((path, partial, methods)) = split_word(line, cursor) {
+        unsafe {
+            if path.iter().all(|segment| replace_with_field(l, segment)) {
+                add_fields(l, partial, methods, &mut candidates);
+            }
+            ffi::lua_pop(l, 1);
+        }
+        if let Ok(schema) = lua.app_data::<ApiSchema>() {
+            schema.complete(&path.join("."), partial, methods, &mut candidates);
+        }
+    } else {
+        unsafe { ffi::lua_pop(l, 1) };
+    }
+
+    candidates.into_values().collect()
+}
+
+// Splits the word before `cursor` into the path of the table to complete, the beginning of the
+// name, and whether it follows a `:`.
+fn split_word(line: &str, cursor: usize) -> Option<(Vec<&str>, &str, bool)> {
+    let before = line.get(..cursor.min(line.len()))?;
+    let start = before
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == ':')
+        .last()
+        .map_or(before.len(), |(i, _)| i);
+    let word = &before[start..];
+
+    let (parent, partial, methods) = match word.rfind(['.', ':']) {
+        Some(i) => (&word[..i], &word[i + 1..], word.as_bytes()[i] == b':'),
+        None => ("", word, false),
+    };
+    let path: Vec<&str> = match parent {
+        "" => Vec::new(),
+        _ => parent.split('.').collect(),
+    };
+
+    let identifier = |s: &str| !s.is_empty() && !s.starts_with(|c: char| c.is_ascii_digit());
+    let valid = path.iter().all(|s| identifier(s) && !s.contains(':'))
+        && (partial.is_empty() || identifier(partial))
+        && !(methods && path.is_empty());
+    valid.then_some((path, partial, methods))
+}
+
+// Replaces the value at the top of the stack with its field `name`, looked up without calling
+// metamethods. Returns false, leaving a value in place, if there is no such field.
+unsafe fn replace_with_field(l: *mut ffi::lua_State, name: &str) -> bool {
+    let mut pushed = 0;
+    let mut found = false;
+
+    for _ in 0..MAX_INDEX_CHAIN {
+        if ffi::lua_istable(l, -1) {
+            ffi::lua_pushlstring(l, name.as_ptr().cast(), name.len());
+            ffi::lua_rawget(l, -2);
+            if !ffi::lua_isnil(l, -1) {
+                found = true;
+                break;
+            }
+            ffi::lua_pop(l, 1);
+        }
+        if !push_index_table(l) {
+            break;
+        }
+        pushed += 1;
+    }
+
+    if found {
+        ffix::lua_insert(l, -(pushed + 2));
+        ffi::lua_pop(l, pushed + 1);
+    } else {
+        ffi::lua_pop(l, pushed);
+    }
+    found
+}
+
+// Pushes the `__index` field of the metatable of the value at the top of the stack if it is a
+// table, and returns whether it did.
+unsafe fn push_index_table(l: *mut ffi::lua_State) -> bool {
+    if ffi::lua_getmetatable(l, -1) == 0 {
+        return false;
+    }
+    ffi::lua_pushstring(l, c"__index".as_ptr());
+    ffi::lua_rawget(l, -2);
+    ffix::lua_remove(l, -2);
+    if ffi::lua_istable(l, -1) {
+        return true;
+    }
+    ffi::lua_pop(l, 1);
+    false
+}
+
+// Adds the fields of the value at the top of the stack, and of its chain of `__index` tables,
+// whose name starts with `partial`.
+unsafe fn add_fields(
+    l: *mut ffi::lua_State,
+    partial: &str,
+    methods: bool,
+    out: &mut BTreeMap<String, CompletionCandidate>,
+) {
+    let mut pushed = 0;
+
+    for _ in 0..MAX_INDEX_CHAIN {
+        if ffi::lua_istable(l, -1) {
+            ffi::lua_pushnil(l);
+            while ffi::lua_next(l, -2) != 0 {
+                if let Some(name) = string_key(l, -2) {
+                    let kind = match ffi::lua_type(l, -1) {
+                        ffi::LUA_TFUNCTION => CompletionKind::Function,
+                        ffi::LUA_TTABLE => CompletionKind::Table,
+                        _ => CompletionKind::Value,
+                    };
+                    let hidden = name.starts_with("__") && !partial.starts_with("__");
+                    let wanted = !methods || kind == CompletionKind::Function;
+                    if name.starts_with(partial) && !hidden && wanted && !out.contains_key(name) {
+                        let name = name.to_owned();
+                        out.insert(name.clone(), CompletionCandidate { name, kind, doc: None });
+                    }
+                }
+                ffi::lua_pop(l, 1);
+            }
+        }
+        if !push_index_table(l) {
+            break;
+        }
+        pushed += 1;
+    }
+
+    ffi::lua_pop(l, pushed);
+}
+
+// Returns the key at `index` if it is a string that is a valid identifier.
+unsafe fn string_key<'a>(l: *mut ffi::lua_State, index: libc::c_int) -> Option<&'a str> {
+    if ffi::lua_type(l, index) != ffi::LUA_TSTRING {
+        return None;
+    }
+    let mut len = 0;
+    let data = ffi::lua_tolstring(l, index, &mut len);
+    let name = str::from_utf8(slice::from_raw_parts(data.cast::<u8>(), len)).ok()?;
+    let identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_');
+    identifier.then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ApiSchema, CompletionKind, Lua, Session};
+
+    fn names(candidates: Vec<crate::CompletionCandidate>) -> Vec<String> {
+        candidates.into_iter().map(|c| c.name).collect()
+    }
+
+    #[test]
+    fn globals_fields_and_methods() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.execute::<()>(
+            "
+            Player = {}
+            Player.__index = Player
+            function Player:move() end
+            obj = setmetatable({ x = 1 }, Player)
+            hero = { name = 'ada', stats = { hp = 1 }, __secret = 1 }
+            hello = 'world'
+        ",
+        )
+        .unwrap();
+
+        assert_eq!(names(lua.complete("print(he", 8)), ["hello", "hero"]);
+        assert_eq!(names(lua.complete("hero.", 5)), ["name", "stats"]);
+        assert_eq!(names(lua.complete("hero.stats.h", 12)), ["hp"]);
+        assert_eq!(lua.complete("hero.st", 7)[0].kind, CompletionKind::Table);
+        assert_eq!(names(lua.complete("obj.", 4)), ["move", "x"]);
+        assert_eq!(names(lua.complete("obj:", 4)), ["move"]);
+        assert!(lua.complete("hero.missing.", 13).is_empty());
+        assert!(lua.complete("3.1", 3).is_empty());
+    }
+
+    #[test]
+    fn schema_and_session() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("net = {}").unwrap();
+        lua.set_app_data(
+            ApiSchema::new().entry("net.http.get", CompletionKind::Function, "get(url)").entry(
+                "net",
+                CompletionKind::Table,
+                "networking",
+            ),
+        );
+
+        let candidates = lua.complete("ne", 2);
+        assert_eq!(candidates[0].doc.as_deref(), Some("networking"));
+        let candidates = lua.complete("net.h", 5);
+        assert_eq!((&candidates[0].name[..], candidates[0].kind), ("http", CompletionKind::Table));
+        assert_eq!(lua.complete("net.http.", 9)[0].doc.as_deref(), Some("get(url)"));
+
+        let session = Session::new(&mut lua);
+        session.execute::<()>(&mut lua, "network_speed = 5").unwrap();
+        assert_eq!(names(session.complete(&mut lua, "ne", 2)), ["net", "network_speed"]);
+        assert_eq!(names(lua.complete("ne", 2)), ["net"]);
+    }
+}
@@ -0,0 +1,513 @@
+use std::ptr::NonNull;
+#[cfg(feature = "async")]
+use std::{
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    ptr,
+    task::{Context, Poll},
+};
+
+use crate::builder::InstructionBudget;
+use crate::exit;
+#[cfg(feature = "async")]
+use crate::functions_write::{PendingCall, PushResults, ASYNC_CALL_MARKER};
+use crate::handle::BusyGuard;
+use crate::locale::NumericLocaleGuard;
+use crate::lua_functions::read_error;
+#[cfg(feature = "async")]
+use crate::userdata::userdata_mut;
+use crate::userdata::BorrowWatermark;
+use crate::{
+    ffix, AsLua, AsMutLua, LuaContext, LuaError, LuaFunction, LuaFunctionCallError, LuaRead, Push,
+    PushGuard,
+};
+
+/// Handle to a coroutine in the Lua context.
+///
+/// A coroutine is created from a `LuaFunction` with `LuaCoroutine::new`, or read from the Lua
+/// context like any other value, for example after a script called `coroutine.create`. It is
+/// then run with `resume`, which returns the values that it yields or returns.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{CoroutineResult, LuaCoroutine, LuaFunction};
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.openlibs();
+/// let counter = LuaFunction::load(&mut lua, "
+///     local n = ...
+///     for i = 1, n do coroutine.yield(i) end
+///     return 'done'
+/// ").unwrap();
+///
+/// let mut coroutine = LuaCoroutine::new(counter);
+/// assert_eq!(coroutine.resume::<i32, _, _>(2).unwrap(), CoroutineResult::Yielded(1));
+/// assert_eq!(coroutine.resume::<i32, _, _>(()).unwrap(), CoroutineResult::Yielded(2));
+/// assert_eq!(
+///     coroutine.resume::<String, _, _>(()).unwrap(),
+///     CoroutineResult::Returned("done".to_owned())
+/// );
+/// assert!(coroutine.is_dead());
+/// ```
+#[derive(Debug)]
+pub struct LuaCoroutine<L> {
+    variable: L,
+    thread: LuaContext,
+}
+
+/// Values produced by one run of a coroutine, returned by `LuaCoroutine::resume`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineResult<V> {
+    /// The coroutine called `coroutine.yield` with these values, and can be resumed.
+    Yielded(V),
+    /// The function of the coroutine returned these values. The coroutine is now dead.
+    Returned(V),
+}
+
+impl<V> CoroutineResult<V> {
+    /// Returns the values, whether they were yielded or returned.
+    #[inline]
+    pub fn into_inner(self) -> V {
+        match self {
+            CoroutineResult::Yielded(value) | CoroutineResult::Returned(value) => value,
+        }
+    }
+}
+
+unsafe impl<'lua, L> AsLua<'lua> for LuaCoroutine<L>
+where
+    L: AsLua<'lua>,
+{
+    #[inline]
+    fn as_lua(&self) -> LuaContext {
+        self.variable.as_lua()
+    }
+}
+
+unsafe impl<'lua, L> AsMutLua<'lua> for LuaCoroutine<L>
+where
+    L: AsMutLua<'lua>,
+{
+    #[inline]
+    fn as_mut_lua(&mut self) -> LuaContext {
+        self.variable.as_mut_lua()
+    }
+}
+
+impl<'lua, L> LuaCoroutine<L>
+where
+    L: AsMutLua<'lua>,
+{
+    /// Creates a coroutine that runs `function`.
+    ///
+    /// The function isn't called until the first call to `resume`, whose arguments become the
+    /// arguments of the function.
+    pub fn new(mut function: LuaFunction<L>) -> LuaCoroutine<PushGuard<LuaFunction<L>>> {
+        unsafe {
+            let raw_lua = function.as_mut_lua();
+            let thread = ffi::lua_newthread(raw_lua.as_ptr());
+            ffi::lua_pushvalue(raw_lua.as_ptr(), -2);
+            ffi::lua_xmove(raw_lua.as_ptr(), thread, 1);
+            LuaCoroutine {
+                variable: PushGuard::new(function, 1),
+                thread: NonNull::new(thread).expect("lua_newthread returned null"),
+            }
+        }
+    }
+
+    /// Returns true if the coroutine can't be resumed anymore, either because its function
+    /// returned or because it raised an error.
+    #[inline]
+    pub fn is_dead(&self) -> bool {
+        unsafe {
+            let thread = self.thread.as_ptr();
+            match ffi::lua_status(thread) {
+                ffi::LUA_YIELD => false,
+                0 => ffi::lua_gettop(thread) == 0,
+                _ => true,
+            }
+        }
+    }
+
+    /// Starts or resumes the coroutine.
+    ///
+    /// The first time, `args` are passed to the function of the coroutine. The following times,
+    /// they are returned by the call to `coroutine.yield` that suspended the coroutine. Like
+    /// with `LuaFunction::call_multi_with_args`, the values yielded or returned by the coroutine
+    /// are read starting from the first one, so they can be read as a tuple.
+    ///
+    /// Returns an error if the coroutine raises an error, in which case it is dead, if it was
+    /// already dead, or if the values can't be read as `V`.
+    pub fn resume<'a, V, A, E>(
+        &'a mut self,
+        args: A,
+    ) -> Result<CoroutineResult<V>, LuaFunctionCallError<E>>
+    where
+        A: for<'r> Push<&'r mut LuaCoroutine<L>, Err = E>,
+        V: LuaRead<PushGuard<&'a mut L>>,
+    {
+        if self.is_dead() {
+            let msg = "cannot resume dead coroutine".to_owned();
+            return Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(msg.into())));
+        }
+
+        let num_pushed = match args.push_to_lua(&mut *self) {
+            Ok(g) => g.forget_internal(),
+            Err((err, _)) => return Err(LuaFunctionCallError::PushError(err)),
+        };
+        unsafe {
+            let raw_lua = self.variable.as_mut_lua();
+            ffi::lua_xmove(raw_lua.as_ptr(), self.thread.as_ptr(), num_pushed);
+            let (status, num_results) = self.resume_thread(num_pushed);
+            self.read_results(status, num_results)
+        }
+    }
+
+    /// Starts or resumes the coroutine, awaiting the futures of the `AsyncFunction`s that it
+    /// calls.
+    ///
+    /// This is like `resume`, except that when the coroutine calls a function created with one
+    /// of the `function_asyncN` functions, the returned future awaits the future of the call and
+    /// then resumes the coroutine with its output. The returned future resolves when the
+    /// coroutine returns, raises an error, or yields from Lua code.
+    ///
+    /// If the returned future is dropped while a call is pending, the call is abandoned and the
+    /// coroutine stays suspended. Resuming it again returns no value from the call.
+    #[cfg(feature = "async")]
+    pub fn resume_async<V, A, E>(&mut self, args: A) -> ResumeAsync<'_, L, V, E>
+    where
+        A: for<'r> Push<&'r mut LuaCoroutine<L>, Err = E>,
+        V: for<'a> LuaRead<PushGuard<&'a mut L>>,
+    {
+        if self.is_dead() {
+            let msg = "cannot resume dead coroutine".to_owned();
+            let error = LuaFunctionCallError::LuaError(LuaError::ExecutionError(msg.into()));
+            return ResumeAsync::failed(self, error);
+        }
+
+        let pushed = args.push_to_lua(&mut *self).map(|g| g.forget_internal());
+        let num_pushed = match pushed.map_err(|(err, _)| err) {
+            Ok(num_pushed) => num_pushed,
+            Err(err) => return ResumeAsync::failed(self, LuaFunctionCallError::PushError(err)),
+        };
+        unsafe {
+            let raw_lua = self.variable.as_mut_lua();
+            ffi::lua_xmove(raw_lua.as_ptr(), self.thread.as_ptr(), num_pushed);
+        }
+        ResumeAsync {
+            coroutine: self,
+            num_args: num_pushed,
+            pending: None,
+            error: None,
+            marker: PhantomData,
+        }
+    }
+
+    // Resumes the thread with the `num_args` values on top of its stack.
+    unsafe fn resume_thread(&mut self, num_args: i32) -> (libc::c_int, libc::c_int) {
+        let raw_lua = self.variable.as_mut_lua();
+        let _busy = BusyGuard::enter(raw_lua);
+        let _locale = NumericLocaleGuard::enter(raw_lua);
+        let _budget = InstructionBudget::enter(raw_lua, self.thread);
+        let _borrows = BorrowWatermark::enter();
+        let exit_pending = exit::exit_pending(raw_lua);
+        let (status, num_results) = ffix::lua_resume(self.thread, raw_lua, num_args);
+
+        // Same as in `pcall_with_handler`, the coroutine may have caught an exit request.
+        if exit_pending {
+            return (status, num_results);
+        }
+        let count = match status {
+            0 | ffi::LUA_YIELD => num_results,
+            _ => 1,
+        };
+        (exit::take_swallowed_exit(self.thread, status, count), num_results)
+    }
+
+    // Moves the results of `lua_resume` to the stack of the parent and reads them.
+    unsafe fn read_results<'a, V, E>(
+        &'a mut self,
+        status: libc::c_int,
+        num_results: libc::c_int,
+    ) -> Result<CoroutineResult<V>, LuaFunctionCallError<E>>
+    where
+        V: LuaRead<PushGuard<&'a mut L>>,
+    {
+        let raw_lua = self.variable.as_mut_lua();
+        let size = match status {
+            0 | ffi::LUA_YIELD => num_results,
+            _ => 1,
+        };
+        ffi::lua_xmove(self.thread.as_ptr(), raw_lua.as_ptr(), size);
+        let pushed_value = PushGuard { lua: &mut self.variable, size, raw_lua };
+
+        match status {
+            0 | ffi::LUA_YIELD => {
+                let read = match pushed_value.size {
+                    0 => LuaRead::lua_read_out_of_bounds(pushed_value),
+                    size => LuaRead::lua_read_at_position(pushed_value, -size),
+                };
+                let value =
+                    read.map_err(|_| LuaFunctionCallError::LuaError(LuaError::WrongType))?;
+                Ok(match status {
+                    0 => CoroutineResult::Returned(value),
+                    _ => CoroutineResult::Yielded(value),
+                })
+            },
+            ffi::LUA_ERRMEM => Err(LuaFunctionCallError::LuaError(LuaError::MemoryLimitExceeded)),
+            ffi::LUA_ERRRUN => Err(LuaFunctionCallError::LuaError(read_error(pushed_value))),
+            _ => panic!("Unknown error code returned by lua_resume: {}", status),
+        }
+    }
+
+    // Takes the future of the `AsyncFunction` call that the coroutine yielded, if it did.
+    #[cfg(feature = "async")]
+    unsafe fn take_pending_call(
+        &mut self,
+        status: libc::c_int,
+        num_results: libc::c_int,
+    ) -> Option<Pin<Box<dyn Future<Output = PushResults>>>> {
+        let thread = self.thread;
+        let marker = ptr::addr_of!(ASYNC_CALL_MARKER) as *mut libc::c_void;
+        if status != ffi::LUA_YIELD
+            || num_results != 2
+            || ffi::lua_touserdata(thread.as_ptr(), -2) != marker
+        {
+            return None;
+        }
+        let future = userdata_mut::<PendingCall>(thread, -1).and_then(|call| call.future.take());
+        ffi::lua_pop(thread.as_ptr(), 2);
+        future
+    }
+}
+
+/// Future returned by `LuaCoroutine::resume_async`.
+#[cfg(feature = "async")]
+#[must_use = "futures do nothing unless polled"]
+pub struct ResumeAsync<'c, L, V, E> {
+    coroutine: &'c mut LuaCoroutine<L>,
+    // Number of values on top of the stack of the thread to resume it with.
+    num_args: libc::c_int,
+    pending: Option<Pin<Box<dyn Future<Output = PushResults>>>>,
+    error: Option<LuaFunctionCallError<E>>,
+    marker: PhantomData<fn() -> V>,
+}
+
+#[cfg(feature = "async")]
+impl<'c, L, V, E> ResumeAsync<'c, L, V, E> {
+    fn failed(coroutine: &'c mut LuaCoroutine<L>, error: LuaFunctionCallError<E>) -> Self {
+        ResumeAsync {
+            coroutine,
+            num_args: 0,
+            pending: None,
+            error: Some(error),
+            marker: PhantomData,
+        }
+    }
+}
+
+// The fields are never pinned: the pending future is boxed.
+#[cfg(feature = "async")]
+impl<'c, L, V, E> Unpin for ResumeAsync<'c, L, V, E> {}
+
+#[cfg(feature = "async")]
+impl<'c, L, V, E> fmt::Debug for ResumeAsync<'c, L, V, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResumeAsync").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'c, 'lua, L, V, E> Future for ResumeAsync<'c, L, V, E>
+where
+    L: AsMutLua<'lua>,
+    V: for<'a> LuaRead<PushGuard<&'a mut L>>,
+{
+    type Output = Result<CoroutineResult<V>, LuaFunctionCallError<E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some(error) = this.error.take() {
+            return Poll::Ready(Err(error));
+        }
+
+        loop {
+            if let Some(pending) = &mut this.pending {
+                let push_results = match pending.as_mut().poll(cx) {
+                    Poll::Ready(push_results) => push_results,
+                    Poll::Pending => return Poll::Pending,
+                };
+                this.pending = None;
+                this.num_args = push_results(this.coroutine.thread);
+            }
+
+            unsafe {
+                let (status, num_results) = this.coroutine.resume_thread(this.num_args);
+                this.num_args = 0;
+                match this.coroutine.take_pending_call(status, num_results) {
+                    Some(future) => this.pending = Some(future),
+                    None => return Poll::Ready(this.coroutine.read_results(status, num_results)),
+                }
+            }
+        }
+    }
+}
+
+impl<'lua, L> LuaRead<L> for LuaCoroutine<L>
+where
+    L: AsMutLua<'lua>,
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<LuaCoroutine<L>, L> {
+        assert!(index == -1); // FIXME:
+        let thread = unsafe { ffi::lua_tothread(lua.as_mut_lua().as_ptr(), -1) };
+        match NonNull::new(thread) {
+            Some(thread) => Ok(LuaCoroutine { variable: lua, thread }),
+            None => Err(lua),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CoroutineResult, Lua, LuaCoroutine, LuaError, LuaFunction, LuaFunctionCallError};
+
+    #[test]
+    fn values_passed_to_yield() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let f = LuaFunction::load(
+            &mut lua,
+            "local a, b = ...
+             local c = coroutine.yield(a + b, a - b)
+             return c * 2",
+        )
+        .unwrap();
+        let mut coroutine = LuaCoroutine::new(f);
+
+        let yielded = coroutine.resume::<(i32, i32), _, _>((5, 3)).unwrap();
+        assert_eq!(yielded, CoroutineResult::Yielded((8, 2)));
+        assert!(!coroutine.is_dead());
+        let returned = coroutine.resume::<i32, _, _>(21).unwrap();
+        assert_eq!(returned.into_inner(), 42);
+        assert!(coroutine.is_dead());
+
+        match coroutine.resume::<(), _, _>(()) {
+            Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(msg))) => {
+                assert!(msg.message().contains("dead"))
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_kill_the_coroutine() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let f = LuaFunction::load(&mut lua, "coroutine.yield() error('boom')").unwrap();
+        let mut coroutine = LuaCoroutine::new(f);
+
+        assert_eq!(coroutine.resume::<(), _, _>(()).unwrap(), CoroutineResult::Yielded(()));
+        match coroutine.resume::<(), _, _>(()) {
+            Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(msg))) => {
+                assert!(msg.message().contains("boom"))
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(coroutine.is_dead());
+    }
+
+    #[test]
+    fn caught_exit_requests() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.intercept_exit();
+        let f = LuaFunction::load(&mut lua, "pcall(os.exit, 3) coroutine.yield(1)").unwrap();
+        let mut coroutine = LuaCoroutine::new(f);
+
+        match coroutine.resume::<i32, _, _>(()) {
+            Err(LuaFunctionCallError::LuaError(LuaError::ExitRequested(3))) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_from_lua() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.execute::<()>("co = coroutine.create(function(x) coroutine.yield(x .. '!') end)")
+            .unwrap();
+
+        let mut coroutine: LuaCoroutine<_> = lua.get("co").unwrap();
+        let yielded = coroutine.resume::<String, _, _>("hi").unwrap();
+        assert_eq!(yielded, CoroutineResult::Yielded("hi!".to_owned()));
+        assert_eq!(coroutine.resume::<(), _, _>(()).unwrap(), CoroutineResult::Returned(()));
+        drop(coroutine);
+
+        assert!(lua.get::<LuaCoroutine<_>, _>("coroutine").is_none());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn awaits_async_functions() {
+        use std::{
+            future::Future,
+            pin::{pin, Pin},
+            sync::Arc,
+            task::{Context, Poll, Wake, Waker},
+        };
+
+        struct Noop;
+        impl Wake for Noop {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        // Future that is pending the first time it's polled, to check that the coroutine waits.
+        struct Later(Option<i32>);
+        impl Future for Later {
+            type Output = i32;
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<i32> {
+                match self.0.take() {
+                    Some(value) => Poll::Ready(value),
+                    None => {
+                        self.0 = Some(2);
+                        cx.waker().wake_by_ref();
+                        Poll::Pending
+                    },
+                }
+            }
+        }
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set("twice", crate::function_async1(|x: i32| async move { x * Later(None).await }));
+        let f = LuaFunction::load(
+            &mut lua,
+            "local a = twice(5)
+             coroutine.yield(a)
+             return twice(a) + 1",
+        )
+        .unwrap();
+        let mut coroutine = LuaCoroutine::new(f);
+
+        let waker = Waker::from(Arc::new(Noop));
+        let mut cx = Context::from_waker(&waker);
+        let mut resume = pin!(coroutine.resume_async::<i32, _, _>(()));
+        assert!(resume.as_mut().poll(&mut cx).is_pending());
+        let result = resume.as_mut().poll(&mut cx).map(Result::unwrap);
+        assert_eq!(result, Poll::Ready(CoroutineResult::Yielded(10)));
+
+        let mut resume = pin!(coroutine.resume_async::<i32, _, _>(()));
+        assert!(resume.as_mut().poll(&mut cx).is_pending());
+        let result = resume.as_mut().poll(&mut cx).map(Result::unwrap);
+        assert_eq!(result, Poll::Ready(CoroutineResult::Returned(21)));
+        assert!(coroutine.is_dead());
+        drop(coroutine);
+
+        let err = lua.execute::<i32>("return twice(1)").unwrap_err();
+        assert!(matches!(err, LuaError::ExecutionError(_)));
+    }
+}
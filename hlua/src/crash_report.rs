@@ -0,0 +1,301 @@
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    fmt, mem,
+    sync::{Arc, Mutex},
+};
+
+use crate::{AnyLuaValue, ErrorContext, Lua, LuaError, LuaRead, PushGuard, RecoveryAction};
+
+/// Collects the information needed to investigate script errors.
+///
+/// Use `attach` to start recording the output of `print`, then run code with `execute`. If the
+/// code fails, the returned `CrashReport` contains the error alongside the state of the script
+/// at the moment the error was raised.
+///
+/// # Example
+///
+/// ```
+/// let mut lua = hlua::Lua::new();
+/// let reporter = hlua::CrashReporter::new().global("level");
+/// reporter.attach(&mut lua);
+///
+/// lua.set("level", 3);
+/// let report = reporter
+///     .execute::<()>(&mut lua, "print('loading'); local enemies = nil; return #enemies")
+///     .unwrap_err();
+///
+/// assert_eq!(report.output, vec!["loading"]);
+/// assert_eq!(report.globals[0].0, "level");
+/// assert!(report.frames[0].locals.iter().any(|(name, _)| name == "enemies"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct CrashReporter {
+    output: Arc<Mutex<VecDeque<String>>>,
+    output_capacity: usize,
+    max_frames: usize,
+    globals: Vec<String>,
+}
+
+/// Information about a script error, produced by `CrashReporter::execute`.
+#[derive(Debug)]
+pub struct CrashReport {
+    /// The error returned by the code.
+    pub error: LuaError,
+    /// Traceback of the stack when the error was raised. `None` for syntax errors.
+    pub traceback: Option<String>,
+    /// The innermost stack frames, starting with the one that raised the error.
+    pub frames: Vec<CrashFrame>,
+    /// Values of the globals registered with `CrashReporter::global`, when the error was raised.
+    pub globals: Vec<(String, AnyLuaValue)>,
+    /// The most recent lines printed by the scripts, oldest first.
+    pub output: Vec<String>,
+    /// Version of hlua.
+    pub hlua_version: &'static str,
+    /// Version of the Lua interpreter.
+    pub lua_version: &'static str,
+}
+
+/// A stack frame of a `CrashReport`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashFrame {
+    /// Short description of the chunk the function was defined in.
+    pub source: String,
+    /// Line being executed, if known.
+    pub line: Option<u32>,
+    /// Name of the function, if known.
+    pub function: Option<String>,
+    /// Names and values of the local variables of the function.
+    pub locals: Vec<(String, AnyLuaValue)>,
+}
+
+impl CrashReporter {
+    /// Creates a reporter that keeps the last 64 printed lines and the 8 innermost stack frames.
+    #[inline]
+    pub fn new() -> CrashReporter {
+        CrashReporter {
+            output: Arc::new(Mutex::new(VecDeque::new())),
+            output_capacity: 64,
+            max_frames: 8,
+            globals: Vec::new(),
+        }
+    }
+
+    /// Sets the number of printed lines to keep.
+    #[inline]
+    pub fn output_capacity(mut self, capacity: usize) -> CrashReporter {
+        self.output_capacity = capacity;
+        self
+    }
+
+    /// Sets the maximum number of stack frames to include in reports.
+    #[inline]
+    pub fn max_frames(mut self, frames: usize) -> CrashReporter {
+        self.max_frames = frames;
+        self
+    }
+
+    /// Adds a global variable whose value is included in reports.
+    #[inline]
+    pub fn global(mut self, name: &str) -> CrashReporter {
+        self.globals.push(name.to_owned());
+        self
+    }
+
+    /// Records the output of `print` in `lua`, which is then included in reports.
+    ///
+    /// This replaces the `print` function of the context; see `Lua::redirect_print`.
+    pub fn attach(&self, lua: &mut Lua) {
+        let output = self.output.clone();
+        let capacity = self.output_capacity;
+
+        lua.redirect_print(move |line| {
+            if let Ok(mut output) = output.lock() {
+                if output.len() >= capacity {
+                    output.pop_front();
+                }
+                if capacity > 0 {
+                    output.push_back(line.to_owned());
+                }
+            }
+        });
+    }
+
+    /// Executes some Lua code, producing a report if it fails.
+    pub fn execute<'a, 'lua, T>(
+        &self,
+        lua: &'a mut Lua<'lua>,
+        code: &str,
+    ) -> Result<T, Box<CrashReport>>
+    where
+        T: for<'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'lua>>>>,
+    {
+        let mut captured = None;
+        let result = lua.execute_protected(code, |err| {
+            captured = Some(self.capture(err));
+            RecoveryAction::Propagate
+        });
+
+        result.map_err(|error| {
+            let (traceback, frames, globals) = match captured {
+                Some((traceback, frames, globals)) => (Some(traceback), frames, globals),
+                None => (None, Vec::new(), Vec::new()),
+            };
+
+            Box::new(CrashReport {
+                error,
+                traceback,
+                frames,
+                globals,
+                output: self.output.lock().map(|o| o.iter().cloned().collect()).unwrap_or_default(),
+                hlua_version: env!("CARGO_PKG_VERSION"),
+                lua_version: lua_version(),
+            })
+        })
+    }
+
+    // Captures the parts of the report that are only available before the stack unwinds.
+    #[allow(clippy::type_complexity)]
+    fn capture(&self, err: &ErrorContext) -> (String, Vec<CrashFrame>, Vec<(String, AnyLuaValue)>) {
+        let frames = (1..=self.max_frames as i32).map_while(|level| frame(err, level)).collect();
+
+        let mut lua = unsafe { Lua::from_existing_state(err.lua.as_ptr(), false) };
+        let globals = self
+            .globals
+            .iter()
+            .map(|name| (name.clone(), lua.get(&name[..]).unwrap_or(AnyLuaValue::LuaNil)))
+            .collect();
+
+        (err.traceback(), frames, globals)
+    }
+}
+
+impl Default for CrashReporter {
+    #[inline]
+    fn default() -> CrashReporter {
+        CrashReporter::new()
+    }
+}
+
+// Returns information about the stack frame at `level`.
+fn frame(err: &ErrorContext, level: i32) -> Option<CrashFrame> {
+    unsafe {
+        let raw_lua = err.lua.as_ptr();
+        let mut ar: ffi::lua_Debug = mem::zeroed();
+        if ffi::lua_getstack(raw_lua, level, &mut ar) == 0 {
+            return None;
+        }
+        ffi::lua_getinfo(raw_lua, c"Sln".as_ptr(), &mut ar);
+
+        Some(CrashFrame {
+            source: CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy().into_owned(),
+            line: u32::try_from(ar.currentline).ok(),
+            function: match ar.name.is_null() {
+                true => None,
+                false => Some(CStr::from_ptr(ar.name).to_string_lossy().into_owned()),
+            },
+            locals: err.locals(level).unwrap_or_default(),
+        })
+    }
+}
+
+fn lua_version() -> &'static str {
+    CStr::from_bytes_with_nul(ffi::LUA_RELEASE).ok().and_then(|v| v.to_str().ok()).unwrap_or("")
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.error)?;
+        writeln!(f, "hlua {}, {}", self.hlua_version, self.lua_version)?;
+
+        for (n, frame) in self.frames.iter().enumerate() {
+            let line = frame.line.map(|l| l.to_string()).unwrap_or_else(|| "?".to_owned());
+            let function = frame.function.as_deref().unwrap_or("?");
+            writeln!(f, "#{} {}:{} in {}", n, frame.source, line, function)?;
+            for (name, value) in &frame.locals {
+                writeln!(f, "    {} = {:?}", name, value)?;
+            }
+        }
+
+        if !self.globals.is_empty() {
+            writeln!(f, "globals:")?;
+            for (name, value) in &self.globals {
+                writeln!(f, "    {} = {:?}", name, value)?;
+            }
+        }
+
+        if !self.output.is_empty() {
+            writeln!(f, "output:")?;
+            for line in &self.output {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AnyLuaValue, CrashReporter, Lua, LuaError};
+
+    #[test]
+    fn full_report() {
+        let mut lua = Lua::new();
+        let reporter = CrashReporter::new().output_capacity(2).global("score").global("missing");
+        reporter.attach(&mut lua);
+        lua.set("score", 12);
+
+        let report = reporter
+            .execute::<()>(
+                &mut lua,
+                "print('one') print('two') print('three')
+                 local function update(dt)
+                     local speed = dt * 2
+                     return speed + nil
+                 end
+                 update(4)",
+            )
+            .unwrap_err();
+
+        assert!(matches!(report.error, LuaError::ExecutionError(_)));
+        assert_eq!(report.output, vec!["two", "three"]);
+        assert_eq!(
+            report.globals,
+            vec![
+                ("score".to_owned(), AnyLuaValue::LuaNumber(12.0)),
+                ("missing".to_owned(), AnyLuaValue::LuaNil),
+            ]
+        );
+
+        let top = &report.frames[0];
+        assert_eq!(top.line, Some(4));
+        assert_eq!(top.function.as_deref(), Some("update"));
+        assert_eq!(
+            top.locals,
+            vec![
+                ("dt".to_owned(), AnyLuaValue::LuaNumber(4.0)),
+                ("speed".to_owned(), AnyLuaValue::LuaNumber(8.0)),
+            ]
+        );
+        assert!(report.traceback.unwrap().contains("update"));
+        assert!(report.lua_version.starts_with("Lua 5"));
+    }
+
+    #[test]
+    fn syntax_error() {
+        let mut lua = Lua::new();
+        let report = CrashReporter::new().execute::<()>(&mut lua, "a = ").unwrap_err();
+
+        assert!(matches!(report.error, LuaError::SyntaxError(_)));
+        assert!(report.traceback.is_none());
+        assert!(report.frames.is_empty());
+    }
+
+    #[test]
+    fn success() {
+        let mut lua = Lua::new();
+        let value: i32 = CrashReporter::new().execute(&mut lua, "return 3").unwrap();
+        assert_eq!(value, 3);
+    }
+}
@@ -0,0 +1,476 @@
+use std::{collections::BTreeMap, slice};
+
+use crate::{AsLua, AsMutLua, LuaContext, LuaRead, Push, PushGuard, PushOne, Void};
+
+/// Value of a database column or query parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    /// `NULL`, which is `nil` in Lua.
+    Null,
+    /// An integer.
+    Integer(i64),
+    /// A floating-point number.
+    Real(f64),
+    /// A string.
+    Text(String),
+    /// Binary data, which is a string in Lua.
+    Blob(Vec<u8>),
+    /// A boolean.
+    Boolean(bool),
+}
+
+/// Row returned by a database query, which can be pushed to Lua as a table with `RowTable`.
+///
+/// This trait is implemented for the rows of `rusqlite` and `postgres` with the `impl-rusqlite`
+/// and `impl-postgres` features. Implementing it for the rows of another database crate only
+/// requires forwarding to its own accessors.
+pub trait DatabaseRow {
+    /// Returns the number of columns of the row.
+    fn column_count(&self) -> usize;
+
+    /// Returns the name of the column at `index`, starting from 0.
+    fn column_name(&self, index: usize) -> &str;
+
+    /// Returns the value of the column at `index`, starting from 0.
+    fn value(&self, index: usize) -> SqlValue;
+}
+
+impl<R> DatabaseRow for &R
+where
+    R: DatabaseRow + ?Sized,
+{
+    #[inline]
+    fn column_count(&self) -> usize {
+        (**self).column_count()
+    }
+
+    #[inline]
+    fn column_name(&self, index: usize) -> &str {
+        (**self).column_name(index)
+    }
+
+    #[inline]
+    fn value(&self, index: usize) -> SqlValue {
+        (**self).value(index)
+    }
+}
+
+impl DatabaseRow for [(String, SqlValue)] {
+    #[inline]
+    fn column_count(&self) -> usize {
+        self.len()
+    }
+
+    #[inline]
+    fn column_name(&self, index: usize) -> &str {
+        &self[index].0
+    }
+
+    #[inline]
+    fn value(&self, index: usize) -> SqlValue {
+        self[index].1.clone()
+    }
+}
+
+impl DatabaseRow for Vec<(String, SqlValue)> {
+    #[inline]
+    fn column_count(&self) -> usize {
+        self.len()
+    }
+
+    #[inline]
+    fn column_name(&self, index: usize) -> &str {
+        &self[index].0
+    }
+
+    #[inline]
+    fn value(&self, index: usize) -> SqlValue {
+        self[index].1.clone()
+    }
+}
+
+/// Wrapper that pushes a `DatabaseRow` as a table associating the name of each column to its
+/// value. `NULL` columns are absent from the table.
+///
+/// A `Vec` of rows is pushed as an array of tables.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{RowTable, SqlValue};
+///
+/// let rows = vec![
+///     RowTable(vec![("name".to_owned(), SqlValue::Text("ada".into())),
+///                   ("age".to_owned(), SqlValue::Integer(36))]),
+///     RowTable(vec![("name".to_owned(), SqlValue::Text("alan".into())),
+///                   ("age".to_owned(), SqlValue::Null)]),
+/// ];
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("rows", rows);
+/// let (name, age): (String, Option<i32>) =
+///     lua.execute_multi("return rows[2].name, rows[2].age").unwrap();
+/// assert_eq!((name.as_str(), age), ("alan", None));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowTable<R>(pub R);
+
+/// Parameters of a database query, read from a Lua table.
+///
+/// The elements of the array part of the table are the positional parameters, and the elements
+/// with a string key are the named parameters. Tables can't contain `nil`, so named parameters
+/// that are `NULL` are simply left out.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{QueryParams, SqlValue};
+///
+/// let mut lua = hlua::Lua::new();
+/// let params: QueryParams = lua.execute("return { 'ada', 36, limit = 10 }").unwrap();
+/// assert_eq!(params.positional(), &[SqlValue::Text("ada".into()), SqlValue::Integer(36)]);
+/// assert_eq!(params.named().collect::<Vec<_>>(), [("limit", &SqlValue::Integer(10))]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryParams {
+    positional: Vec<SqlValue>,
+    named: BTreeMap<String, SqlValue>,
+}
+
+impl QueryParams {
+    /// Returns the positional parameters, in order.
+    #[inline]
+    pub fn positional(&self) -> &[SqlValue] {
+        &self.positional
+    }
+
+    /// Returns the named parameters, sorted by name.
+    #[inline]
+    pub fn named(&self) -> impl Iterator<Item = (&str, &SqlValue)> {
+        self.named.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// Returns the named parameter `name`.
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<&SqlValue> {
+        self.named.get(name)
+    }
+
+    /// Returns true if there is no parameter.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.positional.is_empty() && self.named.is_empty()
+    }
+}
+
+impl<'lua, L> Push<L> for SqlValue
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        let raw_lua = lua.as_mut_lua();
+        unsafe { push_value(raw_lua, &self) };
+        Ok(PushGuard { lua, size: 1, raw_lua })
+    }
+}
+
+impl<'lua, L> PushOne<L> for SqlValue where L: AsMutLua<'lua> {}
+
+impl<'lua, L> LuaRead<L> for SqlValue
+where
+    L: AsLua<'lua>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<SqlValue, L> {
+        match unsafe { read_value(lua.as_lua(), index) } {
+            Some(value) => Ok(value),
+            None => Err(lua),
+        }
+    }
+}
+
+impl<'lua, L, R> Push<L> for RowTable<R>
+where
+    L: AsMutLua<'lua>,
+    R: DatabaseRow,
+{
+    type Err = Void;
+
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        unsafe {
+            let raw_lua = lua.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            let row = &self.0;
+            ffi::lua_createtable(l, 0, row.column_count() as _);
+            for index in 0..row.column_count() {
+                let name = row.column_name(index);
+                ffi::lua_pushlstring(l, name.as_ptr().cast(), name.len());
+                push_value(raw_lua, &row.value(index));
+                ffi::lua_rawset(l, -3);
+            }
+            Ok(PushGuard { lua, size: 1, raw_lua })
+        }
+    }
+}
+
+impl<'lua, L, R> PushOne<L> for RowTable<R>
+where
+    L: AsMutLua<'lua>,
+    R: DatabaseRow,
+{
+}
+
+impl<'lua, L> LuaRead<L> for QueryParams
+where
+    L: AsLua<'lua>,
+{
+    fn lua_read_at_position(lua: L, index: i32) -> Result<QueryParams, L> {
+        match unsafe { read_params(lua.as_lua(), index) } {
+            Some(params) => Ok(params),
+            None => Err(lua),
+        }
+    }
+}
+
+unsafe fn push_value(lua: LuaContext, value: &SqlValue) {
+    let l = lua.as_ptr();
+    match value {
+        SqlValue::Null => ffi::lua_pushnil(l),
+        SqlValue::Integer(value) => ffi::lua_pushinteger(l, *value as ffi::lua_Integer),
+        SqlValue::Real(value) => ffi::lua_pushnumber(l, *value),
+        SqlValue::Text(value) => {
+            ffi::lua_pushlstring(l, value.as_ptr().cast(), value.len());
+        },
+        SqlValue::Blob(value) => {
+            ffi::lua_pushlstring(l, value.as_ptr().cast(), value.len());
+        },
+        SqlValue::Boolean(value) => ffi::lua_pushboolean(l, *value as libc::c_int),
+    }
+}
+
+// Numbers without a fractional part are read as integers. Strings that aren't valid UTF-8 are
+// read as blobs. Other types can't be converted.
+unsafe fn read_value(lua: LuaContext, index: i32) -> Option<SqlValue> {
+    let l = lua.as_ptr();
+    match ffi::lua_type(l, index) {
+        ffi::LUA_TNIL => Some(SqlValue::Null),
+        ffi::LUA_TBOOLEAN => Some(SqlValue::Boolean(ffi::lua_toboolean(l, index) != 0)),
+        ffi::LUA_TNUMBER => {
+            let value = ffi::lua_tonumberx(l, index, std::ptr::null_mut());
+            let integral = value.fract() == 0.0 && value.abs() < i64::MAX as f64;
+            match integral {
+                true => {
+                    let value = ffi::lua_tointegerx(l, index, std::ptr::null_mut());
+                    Some(SqlValue::Integer(value as i64))
+                },
+                false => Some(SqlValue::Real(value)),
+            }
+        },
+        ffi::LUA_TSTRING => {
+            let mut len = 0;
+            let data = ffi::lua_tolstring(l, index, &mut len);
+            let bytes = slice::from_raw_parts(data.cast::<u8>(), len).to_vec();
+            match String::from_utf8(bytes) {
+                Ok(text) => Some(SqlValue::Text(text)),
+                Err(err) => Some(SqlValue::Blob(err.into_bytes())),
+            }
+        },
+        _ => None,
+    }
+}
+
+unsafe fn read_params(lua: LuaContext, index: i32) -> Option<QueryParams> {
+    let l = lua.as_ptr();
+    if !ffi::lua_istable(l, index) {
+        return None;
+    }
+    let table = match index < 0 && index > ffi::LUA_REGISTRYINDEX {
+        true => ffi::lua_gettop(l) + index + 1,
+        false => index,
+    };
+
+    let mut positional = BTreeMap::new();
+    let mut named = BTreeMap::new();
+    ffi::lua_pushnil(l);
+    while ffi::lua_next(l, table) != 0 {
+        let value = read_value(lua, -1);
+        let key = match ffi::lua_type(l, -2) {
+            ffi::LUA_TSTRING => read_value(lua, -2).map(Err),
+            ffi::LUA_TNUMBER => match read_value(lua, -2) {
+                Some(SqlValue::Integer(key)) if key >= 1 => Some(Ok(key as usize)),
+                _ => None,
+            },
+            _ => None,
+        };
+        ffi::lua_pop(l, 1);
+
+        match (key, value) {
+            (Some(Ok(position)), Some(value)) => {
+                positional.insert(position, value);
+            },
+            (Some(Err(SqlValue::Text(name))), Some(value)) => {
+                named.insert(name, value);
+            },
+            _ => {
+                ffi::lua_pop(l, 1);
+                return None;
+            },
+        }
+    }
+
+    // Positional parameters must form a sequence.
+    if positional.keys().enumerate().any(|(expected, &position)| position != expected + 1) {
+        return None;
+    }
+    Some(QueryParams { positional: positional.into_values().collect(), named })
+}
+
+// Text that isn't valid UTF-8 is given as a blob, like strings read from Lua.
+#[cfg(feature = "impl-rusqlite")]
+mod rusqlite_impl {
+    use rusqlite::{types::ValueRef, Row};
+
+    use super::{DatabaseRow, SqlValue};
+
+    impl DatabaseRow for Row<'_> {
+        #[inline]
+        fn column_count(&self) -> usize {
+            self.as_ref().column_count()
+        }
+
+        #[inline]
+        fn column_name(&self, index: usize) -> &str {
+            self.as_ref().column_name(index).unwrap_or_default()
+        }
+
+        fn value(&self, index: usize) -> SqlValue {
+            match self.get_ref(index) {
+                Ok(ValueRef::Integer(value)) => SqlValue::Integer(value),
+                Ok(ValueRef::Real(value)) => SqlValue::Real(value),
+                Ok(ValueRef::Text(text)) => match String::from_utf8(text.to_vec()) {
+                    Ok(text) => SqlValue::Text(text),
+                    Err(err) => SqlValue::Blob(err.into_bytes()),
+                },
+                Ok(ValueRef::Blob(data)) => SqlValue::Blob(data.to_vec()),
+                Ok(ValueRef::Null) | Err(_) => SqlValue::Null,
+            }
+        }
+    }
+}
+
+// Columns whose type has no equivalent in `SqlValue`, such as dates or JSON, are `NULL` unless
+// they can be read as text.
+#[cfg(feature = "impl-postgres")]
+mod postgres_impl {
+    use postgres::{
+        types::{FromSql, Type},
+        Row,
+    };
+
+    use super::{DatabaseRow, SqlValue};
+
+    // Returns the value of a column, or `None` if it is `NULL` or can't be converted to `T`.
+    fn get<'a, T: FromSql<'a>>(row: &'a Row, index: usize) -> Option<T> {
+        row.try_get::<_, Option<T>>(index).ok().flatten()
+    }
+
+    impl DatabaseRow for Row {
+        #[inline]
+        fn column_count(&self) -> usize {
+            self.len()
+        }
+
+        #[inline]
+        fn column_name(&self, index: usize) -> &str {
+            self.columns()[index].name()
+        }
+
+        fn value(&self, index: usize) -> SqlValue {
+            let value = match *self.columns()[index].type_() {
+                Type::BOOL => get(self, index).map(SqlValue::Boolean),
+                Type::INT2 => get::<i16>(self, index).map(|value| SqlValue::Integer(value.into())),
+                Type::INT4 => get::<i32>(self, index).map(|value| SqlValue::Integer(value.into())),
+                Type::INT8 => get(self, index).map(SqlValue::Integer),
+                Type::OID => get::<u32>(self, index).map(|value| SqlValue::Integer(value.into())),
+                Type::FLOAT4 => get::<f32>(self, index).map(|value| SqlValue::Real(value.into())),
+                Type::FLOAT8 => get(self, index).map(SqlValue::Real),
+                Type::BYTEA => get(self, index).map(SqlValue::Blob),
+                _ => get(self, index).map(SqlValue::Text),
+            };
+            value.unwrap_or(SqlValue::Null)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, QueryParams, RowTable, SqlValue};
+
+    #[test]
+    fn rows_as_tables() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let row = vec![
+            ("id".to_owned(), SqlValue::Integer(7)),
+            ("ratio".to_owned(), SqlValue::Real(0.5)),
+            ("data".to_owned(), SqlValue::Blob(vec![0xff, 0])),
+            ("active".to_owned(), SqlValue::Boolean(true)),
+            ("comment".to_owned(), SqlValue::Null),
+        ];
+        lua.set("row", RowTable(row));
+
+        let code = "return table.concat({ row.id, row.ratio, #row.data, tostring(row.active),
+                                          tostring(row.comment) }, ' ')";
+        let summary: String = lua.execute(code).unwrap();
+        assert_eq!(summary, "7 0.5 2 true nil");
+        assert_eq!(
+            lua.execute::<SqlValue>("return row.data").unwrap(),
+            SqlValue::Blob(vec![0xff, 0])
+        );
+    }
+
+    #[test]
+    fn query_params() {
+        let mut lua = Lua::new();
+        let params: QueryParams =
+            lua.execute("return { 2.5, true, name = 'x', [3] = 'three' }").unwrap();
+        let positional =
+            [SqlValue::Real(2.5), SqlValue::Boolean(true), SqlValue::Text("three".to_owned())];
+        assert_eq!(params.positional(), &positional);
+        assert_eq!(params.get("name"), Some(&SqlValue::Text("x".to_owned())));
+        assert!(lua.execute::<QueryParams>("return {}").unwrap().is_empty());
+
+        for code in [
+            "return { [2] = 1 }",
+            "return { [{}] = 1 }",
+            "return { f = function() end }",
+            "return 1",
+        ] {
+            assert!(lua.execute::<QueryParams>(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "impl-rusqlite")]
+    fn rusqlite_rows() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER, name TEXT, score REAL, data BLOB, note TEXT);
+             INSERT INTO users VALUES (1, 'ada', 2.5, x'ff00', NULL);",
+        )
+        .unwrap();
+        let mut statement = conn.prepare("SELECT * FROM users").unwrap();
+        let mut rows = statement.query([]).unwrap();
+        let row = rows.next().unwrap().unwrap();
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set("row", RowTable(row));
+        let code = "return table.concat({ row.id, row.name, row.score, #row.data,
+                                          tostring(row.note) }, ' ')";
+        assert_eq!(lua.execute::<String>(code).unwrap(), "1 ada 2.5 2 nil");
+    }
+}
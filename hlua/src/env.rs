@@ -0,0 +1,216 @@
+use std::{collections::BTreeMap, ffi::CStr, str};
+
+use crate::patterns::string_arg;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsMutLua, Lua, LuaContext, Push};
+
+/// Environment variables that scripts can read through the `env` library.
+///
+/// Variables are either allowed, in which case their current value in the environment of the
+/// process is returned, or set to a fixed value that doesn't depend on the environment. All the
+/// other variables are invisible to scripts.
+///
+/// # Example
+///
+/// ```
+/// let vars = hlua::EnvVars::new()
+///     .allow("HOME")
+///     .allow_prefix("MYAPP_")
+///     .set("REGION", "eu-west");
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.open_env(vars);
+/// assert_eq!(lua.execute::<String>("return env.get('REGION')").unwrap(), "eu-west");
+/// assert!(lua.execute::<bool>("return env.get('PATH') == nil").unwrap());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvVars {
+    allowed: Vec<String>,
+    prefixes: Vec<String>,
+    fixed: BTreeMap<String, String>,
+}
+
+impl EnvVars {
+    /// Builds a list that doesn't make any variable visible.
+    #[inline]
+    pub fn new() -> EnvVars {
+        EnvVars::default()
+    }
+
+    /// Makes the variable `name` of the environment of the process visible.
+    #[inline]
+    pub fn allow(mut self, name: &str) -> EnvVars {
+        self.allowed.push(name.to_owned());
+        self
+    }
+
+    /// Makes all the variables of the environment of the process whose name starts with
+    /// `prefix` visible.
+    #[inline]
+    pub fn allow_prefix(mut self, prefix: &str) -> EnvVars {
+        self.prefixes.push(prefix.to_owned());
+        self
+    }
+
+    /// Makes the variable `name` visible with the given value, whatever its value in the
+    /// environment of the process is.
+    #[inline]
+    pub fn set(mut self, name: &str, value: &str) -> EnvVars {
+        self.fixed.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Returns true if scripts can see the variable `name`.
+    #[inline]
+    pub fn is_visible(&self, name: &str) -> bool {
+        self.fixed.contains_key(name)
+            || self.allowed.iter().any(|allowed| allowed == name)
+            || self.prefixes.iter().any(|prefix| name.starts_with(prefix.as_str()))
+    }
+
+    /// Returns the value of the variable `name` as seen by scripts.
+    ///
+    /// Variables whose value isn't valid UTF-8 are considered absent.
+    pub fn get(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.fixed.get(name) {
+            return Some(value.clone());
+        }
+        match self.is_visible(name) {
+            true => std::env::var(name).ok(),
+            false => None,
+        }
+    }
+
+    /// Returns all the variables seen by scripts, sorted by name.
+    pub fn vars(&self) -> BTreeMap<String, String> {
+        let mut vars: BTreeMap<String, String> = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .filter(|(name, _)| self.is_visible(name))
+            .collect();
+        vars.extend(self.fixed.iter().map(|(name, value)| (name.clone(), value.clone())));
+        vars
+    }
+}
+
+type Method = (&'static CStr, ffi::lua_CFunction);
+
+impl<'lua> Lua<'lua> {
+    /// Opens an `env` library that lets scripts read the environment variables of `vars`.
+    ///
+    /// - `env.get(name)` returns the value of a variable, or nil if it isn't visible or isn't
+    ///   set.
+    /// - `env.vars()` returns a table containing all the visible variables.
+    ///
+    /// This gives scripts access to their configuration without opening the `os` library.
+    pub fn open_env(&mut self, vars: EnvVars) {
+        let functions: [Method; 2] = [(c"get", Some(get)), (c"vars", Some(all_vars))];
+
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_createtable(l, 0, functions.len() as _);
+            push_userdata(vars, raw_lua, |_| {}).forget();
+            for (name, function) in functions {
+                ffi::lua_pushvalue(l, -1);
+                ffi::lua_pushcclosure(l, function, 1);
+                ffi::lua_setfield(l, -3, name.as_ptr());
+            }
+            ffi::lua_pop(l, 1);
+
+            ffi::lua_getfield(l, -2, c"package".as_ptr());
+            if ffi::lua_istable(l, -1) {
+                ffi::lua_getfield(l, -1, c"loaded".as_ptr());
+                if ffi::lua_istable(l, -1) {
+                    ffi::lua_pushvalue(l, -3);
+                    ffi::lua_setfield(l, -2, c"env".as_ptr());
+                }
+                ffi::lua_pop(l, 1);
+            }
+            ffi::lua_pop(l, 1);
+
+            ffi::lua_setfield(l, -2, c"env".as_ptr());
+            ffi::lua_pop(l, 1);
+        }
+    }
+}
+
+unsafe fn env_vars<'a>(lua: *mut ffi::lua_State) -> &'a EnvVars {
+    let raw_lua = LuaContext::new_unchecked(lua);
+    userdata_mut::<EnvVars>(raw_lua, ffi::lua_upvalueindex(1))
+        .expect("the upvalue of the env functions is an EnvVars")
+}
+
+unsafe fn push_str(lua: *mut ffi::lua_State, value: &str) {
+    ffi::lua_pushlstring(lua, value.as_ptr().cast(), value.len());
+}
+
+extern "C" fn get(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let name = match string_arg(lua, 1).map(str::from_utf8) {
+            Some(Ok(name)) => name,
+            _ => {
+                "bad argument #1 to 'get' (string expected)"
+                    .push_no_err(LuaContext::new_unchecked(lua))
+                    .forget();
+                ffix::lua_error(lua);
+            },
+        };
+        match env_vars(lua).get(name) {
+            Some(value) => push_str(lua, &value),
+            None => ffi::lua_pushnil(lua),
+        }
+        1
+    }
+}
+
+extern "C" fn all_vars(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let vars = env_vars(lua).vars();
+        ffi::lua_createtable(lua, 0, vars.len() as _);
+        for (name, value) in &vars {
+            push_str(lua, name);
+            push_str(lua, value);
+            ffi::lua_rawset(lua, -3);
+        }
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{EnvVars, Lua};
+
+    #[test]
+    fn visible_variables() {
+        std::env::set_var("HLUA_ENV_TEST_VISIBLE", "yes");
+        std::env::set_var("HLUA_ENV_TEST_HIDDEN", "no");
+        std::env::set_var("HLUA_ENV_PREFIX_A", "a");
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let vars = EnvVars::new()
+            .allow("HLUA_ENV_TEST_VISIBLE")
+            .allow("HLUA_ENV_TEST_UNSET")
+            .allow_prefix("HLUA_ENV_PREFIX_")
+            .set("HLUA_ENV_FIXED", "fixed");
+        lua.open_env(vars);
+
+        let code = "return table.concat({ env.get('HLUA_ENV_TEST_VISIBLE'),
+                                          tostring(env.get('HLUA_ENV_TEST_HIDDEN')),
+                                          tostring(env.get('HLUA_ENV_TEST_UNSET')),
+                                          env.get('HLUA_ENV_PREFIX_A'),
+                                          env.get('HLUA_ENV_FIXED') }, ' ')";
+        assert_eq!(lua.execute::<String>(code).unwrap(), "yes nil nil a fixed");
+
+        let code = "local names = {}
+                    for name in pairs(env.vars()) do names[#names + 1] = name end
+                    table.sort(names)
+                    return table.concat(names, ' ')";
+        assert_eq!(
+            lua.execute::<String>(code).unwrap(),
+            "HLUA_ENV_FIXED HLUA_ENV_PREFIX_A HLUA_ENV_TEST_VISIBLE"
+        );
+        assert!(lua.execute::<()>("env.get({})").is_err());
+    }
+}
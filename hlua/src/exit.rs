@@ -0,0 +1,198 @@
+use std::ffi::CStr;
+
+use crate::lua_functions::GuardedCalls;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsMutLua, Lua, LuaContext, Push};
+
+// Key of the registry entry set to a `PendingExit` by the `os.exit` installed by
+// `intercept_exit`, until the call from Rust that ran it returns.
+const PENDING_KEY: &CStr = c"hlua.pending_exit";
+
+// Error object raised by the `os.exit` installed by `intercept_exit`.
+#[derive(Debug, Clone, Copy)]
+struct ExitRequest(i32);
+
+// Exit request recorded outside of the error object, which Lua code can catch.
+struct PendingExit {
+    code: i32,
+    _guarded: GuardedCalls,
+}
+
+impl<'lua> Lua<'lua> {
+    /// Replaces `os.exit` with a function that stops the script instead of the process.
+    ///
+    /// Calling `os.exit([code])` then raises an error that the function or code being executed
+    /// returns as `LuaError::ExitRequested(code)`. Like with the standard `os.exit`, the code is
+    /// 0 by default or if it is `true`, and 1 if it is `false`.
+    ///
+    /// The request is also recorded outside of Lua, so a script can't ignore it by catching the
+    /// error with `pcall` or `xpcall`: the code then runs until the call from Rust returns, and
+    /// the call returns `LuaError::ExitRequested` instead of its results or error.
+    ///
+    /// This has no effect if the `os` library isn't opened, and opening it again restores the
+    /// standard `os.exit`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::LuaError;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// lua.intercept_exit();
+    ///
+    /// match lua.execute::<()>("os.exit(3)") {
+    ///     Err(LuaError::ExitRequested(code)) => assert_eq!(code, 3),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn intercept_exit(&mut self) {
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_getfield(l, -1, c"os".as_ptr());
+            if ffi::lua_istable(l, -1) {
+                ffi::lua_pushcfunction(l, Some(exit));
+                ffi::lua_setfield(l, -2, c"exit".as_ptr());
+            }
+            ffi::lua_pop(l, 2);
+        }
+    }
+}
+
+/// Returns the exit code if the value at `index` was raised by the `os.exit` installed by
+/// `intercept_exit`.
+pub(crate) unsafe fn requested_exit(lua: LuaContext, index: libc::c_int) -> Option<i32> {
+    userdata_mut::<ExitRequest>(lua, index).map(|request| request.0)
+}
+
+/// Returns true if an exit request wasn't taken yet with `take_swallowed_exit`.
+pub(crate) unsafe fn exit_pending(lua: LuaContext) -> bool {
+    if !GuardedCalls::any() {
+        return false;
+    }
+    ffi::lua_getfield(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, PENDING_KEY.as_ptr());
+    let pending = !ffi::lua_isnil(lua.as_ptr(), -1);
+    ffi::lua_pop(lua.as_ptr(), 1);
+    pending
+}
+
+/// Called after a call into Lua that started with no exit request pending. If the code asked to
+/// exit, replaces the `count` values that the call left at the top of the stack, its results or
+/// its error, with the exit request and returns the status of an error. Returns `status`
+/// otherwise.
+pub(crate) unsafe fn take_swallowed_exit(
+    lua: LuaContext,
+    status: libc::c_int,
+    count: libc::c_int,
+) -> libc::c_int {
+    if !GuardedCalls::any() {
+        return status;
+    }
+    let l = lua.as_ptr();
+    ffi::lua_getfield(l, ffi::LUA_REGISTRYINDEX, PENDING_KEY.as_ptr());
+    let code = userdata_mut::<PendingExit>(lua, -1).map(|pending| pending.code);
+    ffi::lua_pop(l, 1);
+    let Some(code) = code else {
+        return status;
+    };
+    ffi::lua_pushnil(l);
+    ffi::lua_setfield(l, ffi::LUA_REGISTRYINDEX, PENDING_KEY.as_ptr());
+
+    ffi::lua_pop(l, count);
+    push_userdata(ExitRequest(code), lua, |_| {}).forget();
+    ffi::LUA_ERRRUN
+}
+
+extern "C" fn exit(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let code = match ffi::lua_type(lua, 1) {
+            ffi::LUA_TNONE | ffi::LUA_TNIL => 0,
+            ffi::LUA_TBOOLEAN => (ffi::lua_toboolean(lua, 1) == 0) as i32,
+            _ => {
+                let mut success = 0;
+                let code = ffi::lua_tointegerx(lua, 1, &mut success);
+                if success == 0 {
+                    let msg = "bad argument #1 to 'exit' (number expected)";
+                    msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+                    ffix::lua_error(lua);
+                }
+                code as i32
+            },
+        };
+
+        // The first request is kept if the script caught it and asked again.
+        let context = LuaContext::new_unchecked(lua);
+        if !exit_pending(context) {
+            let pending = PendingExit { code, _guarded: GuardedCalls::new() };
+            push_userdata(pending, context, |_| {}).forget();
+            ffi::lua_setfield(lua, ffi::LUA_REGISTRYINDEX, PENDING_KEY.as_ptr());
+        }
+
+        push_userdata(ExitRequest(code), context, |_| {}).forget();
+        ffix::lua_error(lua);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, LuaError, LuaFunction};
+
+    #[test]
+    fn exit_codes() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.intercept_exit();
+
+        for (code, expected) in
+            [("os.exit()", 0), ("os.exit(true)", 0), ("os.exit(false)", 1), ("os.exit(42)", 42)]
+        {
+            match lua.execute::<()>(code) {
+                Err(LuaError::ExitRequested(exit_code)) => {
+                    assert_eq!(exit_code, expected, "{}", code)
+                },
+                other => panic!("unexpected result for {}: {:?}", code, other),
+            }
+        }
+
+        lua.execute::<()>("function stop() local x = 1 os.exit(x + 1) end").unwrap();
+        {
+            let mut stop: LuaFunction<_> = lua.get("stop").unwrap();
+            assert!(matches!(stop.call::<()>(), Err(LuaError::ExitRequested(2))));
+        }
+        assert!(matches!(lua.execute::<()>("os.exit('x')"), Err(LuaError::ExecutionError(_))));
+    }
+
+    #[test]
+    fn caught_requests() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.intercept_exit();
+
+        for code in [
+            "pcall(os.exit, 3)",
+            "xpcall(os.exit, function() return 'ignored' end, 3)",
+            "pcall(os.exit, 3) pcall(os.exit, 4) return 5",
+            "pcall(os.exit, 3) error('after the exit')",
+        ] {
+            match lua.execute::<Option<i32>>(code) {
+                Err(LuaError::ExitRequested(3)) => (),
+                other => panic!("unexpected result for {}: {:?}", code, other),
+            }
+        }
+
+        // The request is taken by the call that ran it.
+        assert_eq!(lua.execute::<i32>("return 1").unwrap(), 1);
+        lua.execute::<()>("function stop() pcall(os.exit, 6) return 1 end").unwrap();
+        let mut stop: LuaFunction<_> = lua.get("stop").unwrap();
+        assert!(matches!(stop.call::<i32>(), Err(LuaError::ExitRequested(6))));
+    }
+
+    #[test]
+    fn without_os_library() {
+        let mut lua = Lua::new();
+        lua.intercept_exit();
+        assert!(lua.execute::<bool>("return os == nil").unwrap());
+    }
+}
@@ -0,0 +1,183 @@
+use std::ffi::CStr;
+
+use crate::{Lua, LuaError, LuaFunction, LuaFunctionCallError, LuaRead, LuaRef, Push, PushGuard};
+
+// Key of the registry entry holding the Fennel module.
+const FENNEL_KEY: &CStr = c"hlua.fennel";
+
+// Receives the Fennel module, a chunk of Fennel code and its file name, and returns the Lua code
+// it compiles to.
+const COMPILE_GLUE: &str = r#"
+local fennel, code, name = ...
+return fennel.compileString(code, { filename = name })
+"#;
+
+// Receives the Fennel module and adds its searcher to the searchers used by `require`.
+const SEARCHER_GLUE: &str = r#"
+local fennel = ...
+local searchers = package.searchers or package.loaders
+local searcher = fennel.searcher or fennel.makeSearcher()
+for _, existing in ipairs(searchers) do
+    if existing == searcher then
+        return
+    end
+end
+table.insert(searchers, searcher)
+"#;
+
+impl<'lua> Lua<'lua> {
+    /// Loads the Fennel compiler, which makes it possible to run Fennel code with
+    /// `execute_fennel`.
+    ///
+    /// `compiler` is the source of the `fennel.lua` module distributed with Fennel, for example
+    /// obtained with `include_str!`. The module is kept out of the global variables, so that
+    /// scripts can't access it unless they `require` it. The compiler requires the standard
+    /// libraries to be open.
+    pub fn open_fennel(&mut self, compiler: &str) -> Result<(), LuaError> {
+        let fennel: LuaRef =
+            LuaFunction::load_named(&mut *self, "@fennel.lua", compiler)?.call()?;
+
+        unsafe {
+            (&fennel).push_no_err(self.lua).forget();
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, FENNEL_KEY.as_ptr());
+        }
+        Ok(())
+    }
+
+    /// Compiles some Fennel code and returns the equivalent Lua code.
+    ///
+    /// `name` is the file name used in error messages.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `open_fennel` hasn't been called.
+    pub fn compile_fennel(&mut self, name: &str, code: &str) -> Result<String, LuaError> {
+        let fennel = self.fennel_module();
+        let mut glue = LuaFunction::load_named(&mut *self, "=fennel", COMPILE_GLUE)?;
+        match glue.call_with_args((&fennel, code, name)) {
+            Ok(code) => Ok(code),
+            Err(LuaFunctionCallError::LuaError(err)) => Err(err),
+            Err(LuaFunctionCallError::PushError(_)) => unreachable!("pushing strings never fails"),
+        }
+    }
+
+    /// Compiles and executes some Fennel code.
+    ///
+    /// `name` is the file name used in error messages.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `open_fennel` hasn't been called.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// lua.open_fennel(&std::fs::read_to_string("fennel.lua").unwrap()).unwrap();
+    ///
+    /// let value: i32 = lua.execute_fennel("main.fnl", "(let [a 3] (* a 2))").unwrap();
+    /// assert_eq!(value, 6);
+    /// ```
+    pub fn execute_fennel<'a, T>(&'a mut self, name: &str, code: &str) -> Result<T, LuaError>
+    where
+        T: for<'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'lua>>>>,
+    {
+        let code = self.compile_fennel(name, code)?;
+        self.execute_named(&format!("@{}", name), &code)
+    }
+
+    /// Makes `require` find `.fnl` files, by adding the searcher of Fennel to the searchers of
+    /// the `package` library. The `package` library must be open.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `open_fennel` hasn't been called.
+    pub fn register_fennel_searcher(&mut self) -> Result<(), LuaError> {
+        let fennel = self.fennel_module();
+        let mut glue = LuaFunction::load_named(&mut *self, "=fennel", SEARCHER_GLUE)?;
+        Ok(glue.call_with_args(&fennel)?)
+    }
+
+    // Returns the module loaded by `open_fennel`.
+    fn fennel_module(&mut self) -> LuaRef {
+        unsafe {
+            ffi::lua_getfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, FENNEL_KEY.as_ptr());
+            if ffi::lua_isnil(self.lua.as_ptr(), -1) {
+                ffi::lua_pop(self.lua.as_ptr(), 1);
+                panic!("the Fennel compiler hasn't been opened");
+            }
+            match LuaRead::lua_read(PushGuard::new(&mut *self, 1)) {
+                Ok(fennel) => fennel,
+                Err(_) => unreachable!("reading a LuaRef never fails"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, LuaError};
+
+    // Minimal stand-in for the Fennel compiler, which only understands additions of two numbers.
+    const FAKE_FENNEL: &str = r#"
+        local fennel = {}
+        function fennel.compileString(code, options)
+            local a, b = code:match("^%(%+ (%d+) (%d+)%)$")
+            if not a then
+                error(options.filename .. ":1: Compile error: unknown form", 0)
+            end
+            return "return " .. a .. " + " .. b
+        end
+        function fennel.searcher(name)
+            if name == "answer" then
+                return function() return 42 end
+            end
+        end
+        return fennel
+    "#;
+
+    fn lua() -> Lua<'static> {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_fennel(FAKE_FENNEL).unwrap();
+        lua
+    }
+
+    #[test]
+    fn execute() {
+        let mut lua = lua();
+        let value: i32 = lua.execute_fennel("main.fnl", "(+ 1 2)").unwrap();
+        assert_eq!(value, 3);
+
+        match lua.execute_fennel::<()>("main.fnl", "(launch)") {
+            Err(LuaError::ExecutionError(msg)) => assert!(msg.message().starts_with("main.fnl:1:")),
+            other => panic!("{:?}", other),
+        }
+
+        let hidden: bool = lua.execute("return fennel == nil").unwrap();
+        assert!(hidden);
+    }
+
+    #[test]
+    fn searcher() {
+        let mut lua = lua();
+        lua.register_fennel_searcher().unwrap();
+        lua.register_fennel_searcher().unwrap();
+
+        let value: i32 = lua.execute("return require('answer')").unwrap();
+        assert_eq!(value, 42);
+        let count: i32 = lua.execute("return #(package.searchers or package.loaders)").unwrap();
+        let mut fresh = Lua::new();
+        fresh.openlibs();
+        let default: i32 = fresh.execute("return #(package.searchers or package.loaders)").unwrap();
+        assert_eq!(count, default + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "the Fennel compiler hasn't been opened")]
+    fn not_opened() {
+        let mut lua = Lua::new();
+        let _ = lua.compile_fennel("main.fnl", "(+ 1 2)");
+    }
+}
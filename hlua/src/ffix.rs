@@ -1,12 +1,15 @@
-/// Thin wrappers around FFI functions
+//! Thin wrappers around FFI functions whose signature differs between Lua versions.
 use crate::LuaContext;
 
+/// Raises the error at the top of the stack. Never returns.
 #[inline(always)]
 pub unsafe fn lua_error(l: *mut ffi::lua_State) -> ! {
     ffi::lua_error(l);
     std::hint::unreachable_unchecked();
 }
 
+/// Returns the raw length of the value at `index`, as the `#` operator would without calling
+/// metamethods.
 #[inline(always)]
 pub unsafe fn lua_rawlen(lua: LuaContext, index: libc::c_int) -> usize {
     match () {
@@ -19,6 +22,32 @@ pub unsafe fn lua_rawlen(lua: LuaContext, index: libc::c_int) -> usize {
     }
 }
 
+/// Moves the value at the top of the stack to `index`, shifting the values above it up.
+#[inline(always)]
+pub unsafe fn lua_insert(l: *mut ffi::lua_State, index: libc::c_int) {
+    match () {
+        #[cfg(not(feature = "_luaapi_54"))]
+        () => ffi::lua_insert(l, index),
+        #[cfg(feature = "_luaapi_54")]
+        () => ffi::lua_rotate(l, index, 1),
+    }
+}
+
+/// Removes the value at `index`, shifting the values above it down.
+#[inline(always)]
+pub unsafe fn lua_remove(l: *mut ffi::lua_State, index: libc::c_int) {
+    match () {
+        #[cfg(not(feature = "_luaapi_54"))]
+        () => ffi::lua_remove(l, index),
+        #[cfg(feature = "_luaapi_54")]
+        () => {
+            ffi::lua_rotate(l, index, -1);
+            ffi::lua_pop(l, 1);
+        },
+    }
+}
+
+/// Pushes the table of global variables.
 #[inline(always)]
 pub unsafe fn lua_pushglobaltable(lua: LuaContext) {
     match () {
@@ -30,3 +59,94 @@ pub unsafe fn lua_pushglobaltable(lua: LuaContext) {
         () => ffi::lua_pushglobaltable(lua.as_ptr()),
     };
 }
+
+/// Starts or resumes the coroutine `thread` with the `nargs` values at the top of its stack.
+///
+/// Returns the status code, and the number of values yielded or returned by the coroutine. The
+/// stack of the coroutine must only contain these arguments, and its function for the first
+/// call.
+#[inline(always)]
+pub unsafe fn lua_resume(
+    thread: LuaContext,
+    from: LuaContext,
+    nargs: libc::c_int,
+) -> (libc::c_int, libc::c_int) {
+    match () {
+        #[cfg(feature = "_luaapi_51")]
+        () => {
+            let _ = from;
+            let status = ffi::lua_resume(thread.as_ptr(), nargs);
+            (status, ffi::lua_gettop(thread.as_ptr()))
+        },
+        #[cfg(feature = "_luaapi_52")]
+        () => {
+            let status = ffi::lua_resume(thread.as_ptr(), from.as_ptr(), nargs);
+            (status, ffi::lua_gettop(thread.as_ptr()))
+        },
+        #[cfg(feature = "_luaapi_54")]
+        () => {
+            let mut nresults = 0;
+            let status = ffi::lua_resume(thread.as_ptr(), from.as_ptr(), nargs, &mut nresults);
+            (status, nresults)
+        },
+    }
+}
+
+/// Pushes the user value of the full userdata at the absolute index `index`, or nil if it has
+/// none.
+#[inline(always)]
+pub unsafe fn lua_getuservalue(lua: LuaContext, index: libc::c_int) {
+    match () {
+        #[cfg(feature = "_luaapi_51")]
+        () => {
+            // The environment of new userdata is the table of globals, which stands for nil.
+            ffi::lua_getfenv(lua.as_ptr(), index);
+            lua_pushglobaltable(lua);
+            let unset = ffi::lua_rawequal(lua.as_ptr(), -1, -2) != 0;
+            ffi::lua_pop(lua.as_ptr(), 1);
+            if unset {
+                ffi::lua_pop(lua.as_ptr(), 1);
+                ffi::lua_pushnil(lua.as_ptr());
+            }
+        },
+        #[cfg(feature = "_luaapi_52")]
+        () => ffi::lua_getuservalue(lua.as_ptr(), index),
+        #[cfg(feature = "_luaapi_54")]
+        () => {
+            ffi::lua_getiuservalue(lua.as_ptr(), index, 1);
+        },
+    }
+}
+
+/// Pops a value and makes it the user value of the full userdata at the absolute index `index`.
+///
+/// Returns false, and only pops the value, if the version of Lua can't store it: Lua 5.2 and
+/// LuaJIT only accept tables and nil.
+#[inline(always)]
+pub unsafe fn lua_setuservalue(lua: LuaContext, index: libc::c_int) -> bool {
+    let raw_lua = lua.as_ptr();
+    match () {
+        #[cfg(feature = "_luaapi_51")]
+        () => {
+            if ffi::lua_isnil(raw_lua, -1) {
+                ffi::lua_pop(raw_lua, 1);
+                lua_pushglobaltable(lua);
+            } else if !ffi::lua_istable(raw_lua, -1) {
+                ffi::lua_pop(raw_lua, 1);
+                return false;
+            }
+            ffi::lua_setfenv(raw_lua, index) != 0
+        },
+        #[cfg(feature = "_luaapi_52")]
+        () => {
+            if !ffi::lua_isnil(raw_lua, -1) && !ffi::lua_istable(raw_lua, -1) {
+                ffi::lua_pop(raw_lua, 1);
+                return false;
+            }
+            ffi::lua_setuservalue(raw_lua, index);
+            true
+        },
+        #[cfg(feature = "_luaapi_54")]
+        () => ffi::lua_setiuservalue(raw_lua, index, 1) != 0,
+    }
+}
@@ -0,0 +1,265 @@
+use std::{error::Error, fmt};
+
+use bitflags::Flags;
+
+use crate::{AnyLuaValue, AsMutLua, LuaRead, Push, PushGuard, PushOne, Void};
+
+/// Wrapper that pushes a bitflags value as an integer.
+///
+/// When reading, both integers and tables of flag names are accepted. See `read_flags`.
+///
+/// # Example
+///
+/// ```
+/// bitflags::bitflags! {
+///     #[derive(Debug, PartialEq)]
+///     struct Access: u32 {
+///         const READ = 1;
+///         const WRITE = 2;
+///     }
+/// }
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("mode", hlua::FlagBits(Access::READ | Access::WRITE));
+/// assert_eq!(lua.get::<u32, _>("mode"), Some(3));
+///
+/// let hlua::FlagBits(mode): hlua::FlagBits<Access> = lua.execute("return { 'WRITE' }").unwrap();
+/// assert_eq!(mode, Access::WRITE);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlagBits<T>(pub T);
+
+/// Wrapper that pushes a bitflags value as a table containing the names of the flags, for
+/// example `{ "READ", "WRITE" }`.
+///
+/// Bits that don't correspond to a named flag aren't pushed. When reading, both integers and
+/// tables of flag names are accepted. See `read_flags`.
+///
+/// # Example
+///
+/// ```
+/// bitflags::bitflags! {
+///     struct Access: u8 {
+///         const READ = 1;
+///         const WRITE = 2;
+///     }
+/// }
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("mode", hlua::FlagNames(Access::WRITE));
+/// let name: String = lua.execute("return mode[1]").unwrap();
+/// assert_eq!(name, "WRITE");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlagNames<T>(pub T);
+
+/// Error that can happen when converting a Lua value to bitflags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlagsError {
+    /// The table contains a name that isn't the name of a flag.
+    UnknownFlag(String),
+    /// The integer contains bits that don't correspond to any flag.
+    UnknownBits(u64),
+    /// The value is neither a non-negative integer nor a table of strings.
+    WrongType,
+}
+
+impl fmt::Display for FlagsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlagsError::UnknownFlag(name) => write!(f, "unknown flag '{}'", name),
+            FlagsError::UnknownBits(bits) => write!(f, "unknown flag bits {:#x}", bits),
+            FlagsError::WrongType => write!(f, "expected an integer or a table of flag names"),
+        }
+    }
+}
+
+impl Error for FlagsError {}
+
+/// Converts a Lua value to bitflags.
+///
+/// The value can either be an integer containing the bits of the flags, or a table whose values
+/// are the names of the flags. Contrary to reading `FlagBits` or `FlagNames`, the error tells
+/// which flag is invalid, which makes it possible to report it to the script.
+///
+/// # Example
+///
+/// ```
+/// bitflags::bitflags! {
+///     #[derive(Debug)]
+///     struct Access: u32 {
+///         const READ = 1;
+///     }
+/// }
+///
+/// let mut lua = hlua::Lua::new();
+/// let value: hlua::AnyLuaValue = lua.execute("return { 'READ', 'EXECUTE' }").unwrap();
+///
+/// let err = hlua::read_flags::<Access>(&value).unwrap_err();
+/// assert_eq!(err, hlua::FlagsError::UnknownFlag("EXECUTE".to_owned()));
+/// ```
+pub fn read_flags<T>(value: &AnyLuaValue) -> Result<T, FlagsError>
+where
+    T: Flags,
+    T::Bits: Into<u64> + TryFrom<u64>,
+{
+    match *value {
+        AnyLuaValue::LuaInteger(bits) if bits >= 0 => flags_from_bits(bits as u64),
+        AnyLuaValue::LuaNumber(bits) if bits >= 0.0 && bits.fract() == 0.0 => {
+            flags_from_bits(bits as u64)
+        },
+        AnyLuaValue::LuaArray(ref entries) => {
+            let mut flags = T::empty();
+            for (_, name) in entries {
+                let name = match name {
+                    AnyLuaValue::LuaString(name) => name,
+                    _ => return Err(FlagsError::WrongType),
+                };
+                match T::from_name(name) {
+                    Some(flag) => flags.insert(flag),
+                    None => return Err(FlagsError::UnknownFlag(name.clone())),
+                }
+            }
+            Ok(flags)
+        },
+        _ => Err(FlagsError::WrongType),
+    }
+}
+
+fn flags_from_bits<T>(bits: u64) -> Result<T, FlagsError>
+where
+    T: Flags,
+    T::Bits: Into<u64> + TryFrom<u64>,
+{
+    T::Bits::try_from(bits).ok().and_then(T::from_bits).ok_or(FlagsError::UnknownBits(bits))
+}
+
+impl<'lua, L, T> Push<L> for FlagBits<T>
+where
+    L: AsMutLua<'lua>,
+    T: Flags,
+    T::Bits: Into<u64>,
+{
+    type Err = Void; // TODO: use `!` instead (https://github.com/rust-lang/rust/issues/35121)
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        let bits: u64 = self.0.bits().into();
+
+        // Flags that don't fit in 32 bits are pushed as floats, which are exact up to 2^53.
+        match u32::try_from(bits) {
+            Ok(bits) => bits.push_to_lua(lua),
+            Err(_) => (bits as f64).push_to_lua(lua),
+        }
+    }
+}
+
+impl<'lua, L, T> PushOne<L> for FlagBits<T>
+where
+    L: AsMutLua<'lua>,
+    T: Flags,
+    T::Bits: Into<u64>,
+{
+}
+
+impl<'lua, L, T> Push<L> for FlagNames<T>
+where
+    L: AsMutLua<'lua>,
+    T: Flags,
+{
+    type Err = Void; // TODO: use `!` instead (https://github.com/rust-lang/rust/issues/35121)
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        let names: Vec<&'static str> = self.0.iter_names().map(|(name, _)| name).collect();
+        names.push_to_lua(lua)
+    }
+}
+
+impl<'lua, L, T> PushOne<L> for FlagNames<T>
+where
+    L: AsMutLua<'lua>,
+    T: Flags,
+{
+}
+
+impl<'lua, L, T> LuaRead<L> for FlagBits<T>
+where
+    L: AsMutLua<'lua>,
+    T: Flags,
+    T::Bits: Into<u64> + TryFrom<u64>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<FlagBits<T>, L> {
+        read_flags_at(lua, index).map(FlagBits)
+    }
+}
+
+impl<'lua, L, T> LuaRead<L> for FlagNames<T>
+where
+    L: AsMutLua<'lua>,
+    T: Flags,
+    T::Bits: Into<u64> + TryFrom<u64>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<FlagNames<T>, L> {
+        read_flags_at(lua, index).map(FlagNames)
+    }
+}
+
+fn read_flags_at<'lua, L, T>(mut lua: L, index: i32) -> Result<T, L>
+where
+    L: AsMutLua<'lua>,
+    T: Flags,
+    T::Bits: Into<u64> + TryFrom<u64>,
+{
+    let value: AnyLuaValue = match LuaRead::lua_read_at_position(&mut lua, index) {
+        Ok(value) => value,
+        Err(_) => return Err(lua),
+    };
+
+    read_flags(&value).map_err(|_| lua)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{read_flags, AnyLuaValue, FlagBits, FlagNames, FlagsError, Lua};
+
+    bitflags::bitflags! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        struct Options: u64 {
+            const VSYNC = 1;
+            const FULLSCREEN = 1 << 1;
+            const HDR = 1 << 40;
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut lua = Lua::new();
+
+        lua.set("a", FlagBits(Options::VSYNC | Options::HDR));
+        let FlagBits(a): FlagBits<Options> = lua.get("a").unwrap();
+        assert_eq!(a, Options::VSYNC | Options::HDR);
+
+        lua.set("b", FlagNames(Options::FULLSCREEN | Options::HDR));
+        let count: i32 = lua.execute("return #b").unwrap();
+        assert_eq!(count, 2);
+        let FlagNames(b): FlagNames<Options> = lua.get("b").unwrap();
+        assert_eq!(b, Options::FULLSCREEN | Options::HDR);
+    }
+
+    #[test]
+    fn validation_errors() {
+        let mut lua = Lua::new();
+
+        let unknown_bits: AnyLuaValue = lua.execute("return 4").unwrap();
+        assert_eq!(read_flags::<Options>(&unknown_bits), Err(FlagsError::UnknownBits(4)));
+
+        let wrong_type: AnyLuaValue = lua.execute("return { 1 }").unwrap();
+        assert_eq!(read_flags::<Options>(&wrong_type), Err(FlagsError::WrongType));
+
+        lua.execute::<()>("c = { 'VSYNC', 'VSYNK' }").unwrap();
+        assert!(lua.get::<FlagNames<Options>, _>("c").is_none());
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, print, raise, Lua, LuaContext};
+
+// Key of the registry entry holding the `RecorderHolder` of a context.
+const RECORDER_KEY: &CStr = c"hlua.flight_recorder";
+
+// Number of contexts with a flight recorder. Lets `record` skip the registry lookup in the common
+// case where no recorder is enabled.
+static ACTIVE_RECORDERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Something that happened in a Lua context, recorded by a `FlightRecorder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlightEvent {
+    /// A line printed with `print`.
+    Print(String),
+    /// An error raised while loading or running Lua code.
+    Error(String),
+    /// A chunk of code has been loaded. Contains the name of the chunk.
+    Chunk(String),
+    /// A Rust callback has been called. Contains the name of the function, if Lua knows it.
+    Callback(Option<String>),
+}
+
+/// An event and the time at which it was recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlightRecord {
+    /// When the event happened.
+    pub time: SystemTime,
+    /// What happened.
+    pub event: FlightEvent,
+}
+
+/// Keeps the most recent events of a Lua context, created with `Lua::enable_flight_recorder`.
+///
+/// The recorder can be cloned and sent to other threads, which makes it possible to retrieve the
+/// events when building a bug report, even if the context itself isn't accessible.
+#[derive(Debug, Clone)]
+pub struct FlightRecorder {
+    inner: Arc<Mutex<Records>>,
+}
+
+#[derive(Debug)]
+struct Records {
+    capacity: usize,
+    records: VecDeque<FlightRecord>,
+}
+
+impl FlightRecorder {
+    /// Returns the recorded events, oldest first.
+    pub fn records(&self) -> Vec<FlightRecord> {
+        match self.inner.lock() {
+            Ok(inner) => inner.records.iter().cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Removes all the recorded events.
+    pub fn clear(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.records.clear();
+        }
+    }
+
+    /// Returns the maximum number of events kept by the recorder.
+    pub fn capacity(&self) -> usize {
+        self.inner.lock().map(|inner| inner.capacity).unwrap_or(0)
+    }
+
+    fn push(&self, event: FlightEvent) {
+        if let Ok(mut inner) = self.inner.lock() {
+            if inner.capacity == 0 {
+                return;
+            }
+            if inner.records.len() >= inner.capacity {
+                inner.records.pop_front();
+            }
+            inner.records.push_back(FlightRecord { time: SystemTime::now(), event });
+        }
+    }
+}
+
+// Stored in the registry by `enable_flight_recorder`.
+struct RecorderHolder(FlightRecorder);
+
+impl Drop for RecorderHolder {
+    #[inline]
+    fn drop(&mut self) {
+        ACTIVE_RECORDERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Starts recording the recent events of the context: printed lines, errors, loaded chunks
+    /// and calls to Rust callbacks.
+    ///
+    /// Only the last `capacity` events are kept. Enabling the recorder again replaces the
+    /// previous one. The standard `print` function, or the one installed with `redirect_print`,
+    /// keeps working as before.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::FlightEvent;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// let recorder = lua.enable_flight_recorder(16);
+    ///
+    /// lua.set("spawn", hlua::function0(|| {}));
+    /// lua.execute::<()>("print('starting') spawn()").unwrap();
+    ///
+    /// let events: Vec<_> = recorder.records().into_iter().map(|r| r.event).collect();
+    /// assert_eq!(events, vec![
+    ///     FlightEvent::Chunk("chunk".to_owned()),
+    ///     FlightEvent::Print("starting".to_owned()),
+    ///     FlightEvent::Callback(Some("spawn".to_owned())),
+    /// ]);
+    /// ```
+    pub fn enable_flight_recorder(&mut self, capacity: usize) -> FlightRecorder {
+        let recorder = FlightRecorder {
+            inner: Arc::new(Mutex::new(Records { capacity, records: VecDeque::new() })),
+        };
+
+        unsafe {
+            let raw_lua = self.lua;
+            ACTIVE_RECORDERS.fetch_add(1, Ordering::Relaxed);
+            push_userdata(RecorderHolder(recorder.clone()), raw_lua, |_| {}).forget();
+            ffi::lua_setfield(raw_lua.as_ptr(), ffi::LUA_REGISTRYINDEX, RECORDER_KEY.as_ptr());
+            install_recording_print(raw_lua);
+        }
+
+        recorder
+    }
+
+    /// Returns the flight recorder of the context, if it has been enabled.
+    pub fn flight_recorder(&self) -> Option<FlightRecorder> {
+        unsafe { with_recorder(self.lua, |recorder| recorder.clone()) }
+    }
+
+    /// Stops recording the events of the context.
+    ///
+    /// Existing `FlightRecorder`s keep the events recorded so far.
+    pub fn disable_flight_recorder(&mut self) {
+        unsafe {
+            ffi::lua_pushnil(self.lua.as_ptr());
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, RECORDER_KEY.as_ptr());
+        }
+    }
+}
+
+// Calls `f` with the recorder of the context, if any.
+unsafe fn with_recorder<R>(lua: LuaContext, f: impl FnOnce(&FlightRecorder) -> R) -> Option<R> {
+    let raw_lua = lua.as_ptr();
+    ffi::lua_getfield(raw_lua, ffi::LUA_REGISTRYINDEX, RECORDER_KEY.as_ptr());
+    let result = userdata_mut::<RecorderHolder>(lua, -1).map(|holder| f(&holder.0));
+    ffi::lua_pop(raw_lua, 1);
+    result
+}
+
+/// Records an event if the context has a flight recorder. `event` is only called if it does.
+#[inline]
+pub(crate) unsafe fn record(lua: LuaContext, event: impl FnOnce() -> FlightEvent) {
+    if ACTIVE_RECORDERS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    // `event` can inspect the stack, so the recorder must not be on it at that point.
+    if let Some(recorder) = with_recorder(lua, |recorder| recorder.clone()) {
+        recorder.push(event());
+    }
+}
+
+/// Records a call to the Rust function currently running.
+#[inline]
+pub(crate) unsafe fn record_callback(lua: LuaContext) {
+    record(lua, || {
+        let mut ar: ffi::lua_Debug = mem::zeroed();
+        if ffi::lua_getstack(lua.as_ptr(), 0, &mut ar) == 0 {
+            return FlightEvent::Callback(None);
+        }
+        ffi::lua_getinfo(lua.as_ptr(), c"n".as_ptr(), &mut ar);
+
+        FlightEvent::Callback(match ar.name.is_null() {
+            true => None,
+            false => Some(CStr::from_ptr(ar.name).to_string_lossy().into_owned()),
+        })
+    })
+}
+
+/// Returns the error message at the top of the stack, without calling any metamethod.
+pub(crate) unsafe fn error_message(lua: LuaContext) -> String {
+    let raw_lua = lua.as_ptr();
+    if let Some(err) = raise::raised_error(lua, -1) {
+        return err.to_string();
+    }
+    match ffi::lua_type(raw_lua, -1) {
+        ffi::LUA_TSTRING | ffi::LUA_TNUMBER => print::to_display_string(lua, -1),
+        ty => {
+            let name = CStr::from_ptr(ffi::lua_typename(raw_lua, ty)).to_string_lossy();
+            format!("error object is a {} value", name)
+        },
+    }
+}
+
+// Makes sure that the global `print` records its output. The function installed by
+// `redirect_print` already does, otherwise the current function is wrapped.
+unsafe fn install_recording_print(lua: LuaContext) {
+    let raw_lua = lua.as_ptr();
+    ffix::lua_pushglobaltable(lua);
+    ffi::lua_getfield(raw_lua, -1, c"print".as_ptr());
+
+    if print::is_redirected(lua, -1) || print::is_cfunction(lua, -1, recording_print) {
+        ffi::lua_pop(raw_lua, 2);
+        return;
+    }
+
+    // The previous `print` function is kept as upvalue.
+    ffi::lua_pushcclosure(raw_lua, Some(recording_print), 1);
+    ffi::lua_setfield(raw_lua, -2, c"print".as_ptr());
+    ffi::lua_pop(raw_lua, 1);
+}
+
+extern "C" fn recording_print(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        record(raw_lua, || FlightEvent::Print(print::format_arguments(raw_lua, 1)));
+
+        let nargs = ffi::lua_gettop(lua);
+        if ffi::lua_isfunction(lua, ffi::lua_upvalueindex(1)) {
+            ffi::lua_pushvalue(lua, ffi::lua_upvalueindex(1));
+            for index in 1..=nargs {
+                ffi::lua_pushvalue(lua, index);
+            }
+            ffi::lua_call(lua, nargs, 0);
+        }
+
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{function1, FlightEvent, Lua, LuaFunction};
+
+    fn events(lua: &Lua) -> Vec<FlightEvent> {
+        lua.flight_recorder().unwrap().records().into_iter().map(|r| r.event).collect()
+    }
+
+    #[test]
+    fn errors_and_named_chunks() {
+        let mut lua = Lua::new();
+        lua.enable_flight_recorder(8);
+
+        let mut f = LuaFunction::load_named(&mut lua, "=update", "local a = nil; a()").unwrap();
+        f.call::<()>().unwrap_err();
+        drop(f);
+        lua.execute::<()>("a = ").unwrap_err();
+
+        let events = events(&lua);
+        assert_eq!(events[0], FlightEvent::Chunk("=update".to_owned()));
+        assert!(matches!(&events[1], FlightEvent::Error(msg) if msg.starts_with("update:1:")));
+        assert!(matches!(&events[2], FlightEvent::Error(_)));
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn capacity_and_redirected_print() {
+        let mut lua = Lua::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let sink = output.clone();
+        lua.redirect_print(move |line| sink.lock().unwrap().push(line.to_owned()));
+        let recorder = lua.enable_flight_recorder(2);
+
+        lua.set("double", function1(|a: i32| a * 2));
+        lua.execute::<()>("print(double(1)) print(double(2))").unwrap();
+
+        assert_eq!(*output.lock().unwrap(), vec!["2", "4"]);
+        assert_eq!(
+            events(&lua),
+            vec![
+                FlightEvent::Callback(Some("double".to_owned())),
+                FlightEvent::Print("4".to_owned())
+            ]
+        );
+
+        recorder.clear();
+        assert!(recorder.records().is_empty());
+    }
+
+    #[test]
+    fn disable() {
+        let mut lua = Lua::new();
+        let recorder = lua.enable_flight_recorder(4);
+        lua.disable_flight_recorder();
+        lua.execute::<()>("a = 1").unwrap();
+
+        assert!(lua.flight_recorder().is_none());
+        assert!(recorder.records().is_empty());
+    }
+}
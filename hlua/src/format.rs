@@ -0,0 +1,527 @@
+use std::{error::Error, ffi::CString, fmt, ptr};
+
+use crate::locale::NumericLocaleGuard;
+use crate::patterns::string_arg;
+use crate::print::to_display_string;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsMutLua, Lua, LuaContext, Push, Void};
+
+/// Error returned by `Lua::format`, or raised by `string.format` after a call to
+/// `Lua::replace_string_format`.
+///
+/// Values are numbered from 1, not counting the format string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    /// The format contains an invalid conversion specification, such as `%y` or `%100d`.
+    InvalidConversion {
+        /// The invalid specification.
+        spec: String,
+    },
+    /// There is no value for a conversion specification.
+    MissingValue {
+        /// Number of the missing value.
+        value: usize,
+        /// The specification without value.
+        spec: String,
+    },
+    /// A value has the wrong type for its conversion specification.
+    WrongType {
+        /// Number of the value.
+        value: usize,
+        /// The specification of the value.
+        spec: String,
+        /// Type required by the specification.
+        expected: &'static str,
+        /// Type of the value.
+        found: String,
+    },
+    /// A value formatted as an integer is a number with a fractional part, or is too large.
+    NotAnInteger {
+        /// Number of the value.
+        value: usize,
+        /// The specification of the value.
+        spec: String,
+    },
+    /// There are more values than conversion specifications.
+    TooManyValues {
+        /// Number of conversion specifications.
+        expected: usize,
+        /// Number of values.
+        found: usize,
+    },
+    /// The `__tostring` metamethod of a value formatted with `%s` raised an error.
+    ToStringFailed(String),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::InvalidConversion { spec } => {
+                write!(f, "Invalid conversion '{}' in format", spec)
+            },
+            FormatError::MissingValue { value, spec } => {
+                write!(f, "Missing value {} for '{}'", value, spec)
+            },
+            FormatError::WrongType { value, spec, expected, found } => {
+                write!(f, "Value {} for '{}' must be a {}, got {}", value, spec, expected, found)
+            },
+            FormatError::NotAnInteger { value, spec } => {
+                write!(f, "Value {} for '{}' has no integer representation", value, spec)
+            },
+            FormatError::TooManyValues { expected, found } => {
+                write!(f, "Format expects {} values, got {}", expected, found)
+            },
+            FormatError::ToStringFailed(msg) => write!(f, "Error in __tostring: {}", msg),
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+impl FormatError {
+    // Message raised by the `string.format` replacement, in the style of the Lua libraries.
+    fn lua_message(&self) -> String {
+        match self {
+            FormatError::InvalidConversion { spec } => {
+                format!("invalid conversion '{}' to 'format'", spec)
+            },
+            FormatError::MissingValue { value, spec } => {
+                format!("bad argument #{} to 'format' (no value for '{}')", value + 1, spec)
+            },
+            FormatError::WrongType { value, spec, expected, found } => format!(
+                "bad argument #{} to 'format' ({} expected for '{}', got {})",
+                value + 1,
+                expected,
+                spec,
+                found
+            ),
+            FormatError::NotAnInteger { value, spec } => format!(
+                "bad argument #{} to 'format' (number has no integer representation for '{}')",
+                value + 1,
+                spec
+            ),
+            FormatError::TooManyValues { expected, found } => format!(
+                "bad argument #{} to 'format' (format expects {} values, got {})",
+                expected + 2,
+                expected,
+                found
+            ),
+            FormatError::ToStringFailed(msg) => msg.clone(),
+        }
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Formats values like `string.format` does, checking that they match the format.
+    ///
+    /// The conversions of `string.format` are supported. Contrary to `string.format`, there must
+    /// be exactly one value per conversion, and integer conversions such as `%d` only accept
+    /// numbers that have an integer representation. This works even if the `string` library
+    /// isn't opened. Bytes that aren't valid UTF-8 are replaced in the result.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::FormatError;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// let text = lua.format("Hello %s, you scored %d", ("Ana", 42)).unwrap();
+    /// assert_eq!(text, "Hello Ana, you scored 42");
+    ///
+    /// match lua.format("Hello %s, you scored %d", ("Ana", "many")) {
+    ///     Err(FormatError::WrongType { value: 2, expected: "number", .. }) => {},
+    ///     other => panic!("{:?}", other),
+    /// }
+    /// ```
+    pub fn format<A, E>(&mut self, format: &str, values: A) -> Result<String, FormatError>
+    where
+        A: for<'a> Push<&'a mut Lua<'lua>, Err = E>,
+        E: Into<Void>,
+    {
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffi::lua_pushcfunction(l, Some(protected_format));
+            ffi::lua_pushlstring(l, format.as_ptr().cast(), format.len());
+            let count = match values.push_to_lua(&mut *self) {
+                Ok(pushed) => pushed.forget_internal(),
+                Err(_) => unreachable!(),
+            };
+
+            let status = {
+                let _locale = NumericLocaleGuard::enter(raw_lua);
+                ffi::lua_pcall(l, count + 1, 1, 0)
+            };
+            let result = match status {
+                0 => match userdata_mut::<FormatError>(raw_lua, -1) {
+                    Some(error) => Err(error.clone()),
+                    None => Ok(to_display_string(raw_lua, -1)),
+                },
+                _ => Err(FormatError::ToStringFailed(to_display_string(raw_lua, -1))),
+            };
+            ffi::lua_pop(l, 1);
+            result
+        }
+    }
+
+    /// Replaces `string.format` with a function that checks its arguments like `Lua::format`.
+    ///
+    /// Invalid arguments then raise an error that tells which value is wrong, instead of being
+    /// silently ignored or converted. This has no effect if the `string` library isn't opened.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// lua.replace_string_format();
+    ///
+    /// let text: String = lua.execute("return ('%5.1f%%'):format(99.44)").unwrap();
+    /// assert_eq!(text, " 99.4%");
+    /// assert!(lua.execute::<String>("return string.format('%d items', 1.5)").is_err());
+    /// ```
+    pub fn replace_string_format(&mut self) {
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_getfield(l, -1, c"string".as_ptr());
+            if ffi::lua_istable(l, -1) {
+                ffi::lua_pushcfunction(l, Some(checked_format));
+                ffi::lua_setfield(l, -2, c"format".as_ptr());
+            }
+            ffi::lua_pop(l, 2);
+        }
+    }
+}
+
+// Formats the values above the format string at index 1, and returns the string or the
+// `FormatError`. Called in protected mode, since `__tostring` metamethods can raise errors.
+extern "C" fn protected_format(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        let format = string_arg(lua, 1).unwrap_or_default();
+        match format_values(raw_lua, format, 2) {
+            Ok(text) => {
+                ffi::lua_pushlstring(lua, text.as_ptr().cast(), text.len());
+            },
+            Err(error) => {
+                push_userdata(error, raw_lua, |_| {}).forget();
+            },
+        }
+        1
+    }
+}
+
+extern "C" fn checked_format(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        let result = match string_arg(lua, 1) {
+            Some(format) => format_values(raw_lua, format, 2).map_err(|e| e.lua_message()),
+            None => Err("bad argument #1 to 'format' (string expected)".to_owned()),
+        };
+        match result {
+            Ok(text) => {
+                ffi::lua_pushlstring(lua, text.as_ptr().cast(), text.len());
+                1
+            },
+            Err(msg) => {
+                msg.push_no_err(raw_lua).forget();
+                ffix::lua_error(lua);
+            },
+        }
+    }
+}
+
+// A conversion specification of a format, such as `%-5.2f`.
+struct Spec<'a> {
+    // The whole specification, for error messages.
+    text: &'a [u8],
+    // The flags, width and precision.
+    options: &'a [u8],
+    conversion: u8,
+}
+
+// Parses the specification starting after the `%` at `format[start - 1]`, following the rules
+// of `string.format`: at most five flags, and two digits for the width and the precision.
+fn parse_spec(format: &[u8], start: usize) -> Result<Spec<'_>, FormatError> {
+    let skip = |from: usize, max: usize, allowed: fn(&u8) -> bool| {
+        from + format[from..].iter().take(max).take_while(|b| allowed(b)).count()
+    };
+    let mut end = skip(start, 5, |b| b"-+ #0".contains(b));
+    end = skip(end, 2, u8::is_ascii_digit);
+    if format.get(end) == Some(&b'.') {
+        end = skip(end + 1, 2, u8::is_ascii_digit);
+    }
+
+    let conversion = format.get(end).copied();
+    let text = &format[start - 1..(end + 1).min(format.len())];
+    match conversion {
+        Some(conversion) if b"cdioxXaAeEfFgGqs".contains(&conversion) => {
+            if conversion == b'q' && end != start {
+                return Err(FormatError::InvalidConversion { spec: lossy(text) });
+            }
+            Ok(Spec { text, options: &format[start..end], conversion })
+        },
+        _ => Err(FormatError::InvalidConversion { spec: lossy(text) }),
+    }
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+// Formats the values from the absolute index `first` to the top of the stack.
+unsafe fn format_values(
+    lua: LuaContext,
+    format: &[u8],
+    first: libc::c_int,
+) -> Result<Vec<u8>, FormatError> {
+    let l = lua.as_ptr();
+    let count = (ffi::lua_gettop(l) - first + 1).max(0) as usize;
+    let mut output = Vec::with_capacity(format.len());
+    let mut value = 0;
+    let mut position = 0;
+
+    while let Some(offset) = format[position..].iter().position(|&b| b == b'%') {
+        output.extend_from_slice(&format[position..position + offset]);
+        position += offset + 1;
+        if format.get(position) == Some(&b'%') {
+            output.push(b'%');
+            position += 1;
+            continue;
+        }
+
+        let spec = parse_spec(format, position)?;
+        position += spec.text.len() - 1;
+        value += 1;
+        if value > count {
+            return Err(FormatError::MissingValue { value, spec: lossy(spec.text) });
+        }
+        format_value(lua, &spec, value, first + value as libc::c_int - 1, &mut output)?;
+    }
+    output.extend_from_slice(&format[position..]);
+
+    if count > value {
+        return Err(FormatError::TooManyValues { expected: value, found: count });
+    }
+    Ok(output)
+}
+
+unsafe fn format_value(
+    lua: LuaContext,
+    spec: &Spec,
+    value: usize,
+    index: libc::c_int,
+    output: &mut Vec<u8>,
+) -> Result<(), FormatError> {
+    let l = lua.as_ptr();
+    let wrong_type = |expected| FormatError::WrongType {
+        value,
+        spec: lossy(spec.text),
+        expected,
+        found: type_name(l, index),
+    };
+
+    let number = || {
+        let mut success = 0;
+        let number = ffi::lua_tonumberx(l, index, &mut success);
+        match success {
+            0 => Err(wrong_type("number")),
+            _ => Ok(number),
+        }
+    };
+    let integer = || {
+        let number = number()?;
+        // 2^63 doesn't fit in an i64, but is exactly representable as a float.
+        match number.fract() == 0.0 && number >= -(2f64.powi(63)) && number < 2f64.powi(63) {
+            true => Ok(number as i64),
+            false => Err(FormatError::NotAnInteger { value, spec: lossy(spec.text) }),
+        }
+    };
+
+    match spec.conversion {
+        b'd' | b'i' | b'o' | b'x' | b'X' => {
+            let c_spec = c_spec(spec.options, b"ll", spec.conversion);
+            let value = integer()? as libc::c_longlong;
+            c_format(output, |buf, len| libc::snprintf(buf, len, c_spec.as_ptr(), value));
+        },
+        b'c' => {
+            let c_spec = c_spec(spec.options, b"", b'c');
+            let value = integer()? as libc::c_int;
+            c_format(output, |buf, len| libc::snprintf(buf, len, c_spec.as_ptr(), value));
+        },
+        b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+            let c_spec = c_spec(spec.options, b"", spec.conversion);
+            let value = number()?;
+            c_format(output, |buf, len| libc::snprintf(buf, len, c_spec.as_ptr(), value));
+        },
+        b'q' => match ffi::lua_type(l, index) {
+            ffi::LUA_TSTRING => quote(string_arg(l, index).unwrap_or_default(), output),
+            ffi::LUA_TNUMBER => {
+                let number = number()?;
+                let literal = match number {
+                    n if n.is_nan() => "(0/0)".to_owned(),
+                    n if n == f64::INFINITY => "1e9999".to_owned(),
+                    n if n == f64::NEG_INFINITY => "-1e9999".to_owned(),
+                    _ => to_display_string(lua, index),
+                };
+                output.extend_from_slice(literal.as_bytes());
+            },
+            ffi::LUA_TNIL | ffi::LUA_TBOOLEAN => {
+                output.extend_from_slice(to_display_string(lua, index).as_bytes())
+            },
+            _ => return Err(wrong_type("string")),
+        },
+        _ => {
+            let string = match ffi::lua_type(l, index) {
+                ffi::LUA_TSTRING => string_arg(l, index).unwrap_or_default().to_vec(),
+                _ => to_display_string(lua, index).into_bytes(),
+            };
+            pad(spec.options, &string, output);
+        },
+    }
+    Ok(())
+}
+
+pub(crate) fn type_name(lua: *mut ffi::lua_State, index: libc::c_int) -> String {
+    unsafe {
+        let name = ffi::lua_typename(lua, ffi::lua_type(lua, index));
+        std::ffi::CStr::from_ptr(name).to_string_lossy().into_owned()
+    }
+}
+
+// Builds the specification passed to `snprintf`, which only contains ASCII characters.
+fn c_spec(options: &[u8], length: &[u8], conversion: u8) -> CString {
+    let spec = [b"%", options, length, &[conversion]].concat();
+    CString::new(spec).expect("the specification doesn't contain nul bytes")
+}
+
+// Appends the output of `print`, a call to `snprintf` into a buffer of the given size.
+fn c_format<F>(output: &mut Vec<u8>, mut print: F)
+where
+    F: FnMut(*mut libc::c_char, libc::size_t) -> libc::c_int,
+{
+    let len = print(ptr::null_mut(), 0);
+    assert!(len >= 0, "snprintf failed");
+    let start = output.len();
+    output.resize(start + len as usize + 1, 0);
+    print(output[start..].as_mut_ptr().cast(), len as usize + 1);
+    output.truncate(start + len as usize);
+}
+
+// Appends `string` with the width and precision of `options`, as `%s` does.
+fn pad(options: &[u8], string: &[u8], output: &mut Vec<u8>) {
+    let left = options.contains(&b'-');
+    let options: Vec<u8> =
+        options.iter().copied().filter(|&b| b.is_ascii_digit() || b == b'.').collect();
+    let mut parts = options.splitn(2, |&b| b == b'.');
+    let number = |part: Option<&[u8]>| {
+        part.and_then(|digits| std::str::from_utf8(digits).ok()?.parse::<usize>().ok())
+    };
+    let width = number(parts.next()).unwrap_or(0);
+    let string = match parts.next() {
+        Some(precision) => &string[..number(Some(precision)).unwrap_or(0).min(string.len())],
+        None => string,
+    };
+
+    let padding = width.saturating_sub(string.len());
+    if !left {
+        output.extend(std::iter::repeat_n(b' ', padding));
+    }
+    output.extend_from_slice(string);
+    if left {
+        output.extend(std::iter::repeat_n(b' ', padding));
+    }
+}
+
+// Appends `string` as a Lua string literal, as `%q` does.
+fn quote(string: &[u8], output: &mut Vec<u8>) {
+    output.push(b'"');
+    for (i, &byte) in string.iter().enumerate() {
+        match byte {
+            b'"' | b'\\' | b'\n' => output.extend_from_slice(&[b'\\', byte]),
+            b'\r' => output.extend_from_slice(b"\\r"),
+            _ if byte.is_ascii_control() => {
+                let next_is_digit = string.get(i + 1).is_some_and(u8::is_ascii_digit);
+                let escape = match next_is_digit {
+                    true => format!("\\{:03}", byte),
+                    false => format!("\\{}", byte),
+                };
+                output.extend_from_slice(escape.as_bytes());
+            },
+            _ => output.push(byte),
+        }
+    }
+    output.push(b'"');
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FormatError, Lua};
+
+    #[test]
+    fn conversions() {
+        let mut lua = Lua::new();
+        let text = lua
+            .format("%d|%5.2f|%-4s|%.2s|%x|%c|%q|%%", (-12, 3.14159, "ab", "xyz", 255, 65, "a\"\n"))
+            .unwrap();
+        assert_eq!(text, "-12| 3.14|ab  |xy|ff|A|\"a\\\"\\\n\"|%");
+        assert_eq!(lua.format("%s %s", (true, 2.5)).unwrap(), "true 2.5");
+        assert_eq!(lua.format("no conversions", ()).unwrap(), "no conversions");
+    }
+
+    #[test]
+    fn errors() {
+        let mut lua = Lua::new();
+        assert_eq!(
+            lua.format("%y", 1),
+            Err(FormatError::InvalidConversion { spec: "%y".to_owned() })
+        );
+        assert_eq!(
+            lua.format("%123d", 1),
+            Err(FormatError::InvalidConversion { spec: "%123".to_owned() })
+        );
+        assert_eq!(
+            lua.format("%d %e", 1),
+            Err(FormatError::MissingValue { value: 2, spec: "%e".to_owned() })
+        );
+        assert_eq!(
+            lua.format("%d", 1.5),
+            Err(FormatError::NotAnInteger { value: 1, spec: "%d".to_owned() })
+        );
+        assert_eq!(
+            lua.format("%s", ("a", "b")),
+            Err(FormatError::TooManyValues { expected: 1, found: 2 })
+        );
+        assert_eq!(
+            lua.format("%s %f", ("a", false)),
+            Err(FormatError::WrongType {
+                value: 2,
+                spec: "%f".to_owned(),
+                expected: "number",
+                found: "boolean".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn replaced_string_format() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.replace_string_format();
+
+        let text: String = lua.execute("return string.format('%s=%03d', 'n', 7)").unwrap();
+        assert_eq!(text, "n=007");
+        let code = "local ok, msg = pcall(string.format, '%s, you scored %d', 'Ana', 'many')
+                    return msg";
+        assert_eq!(
+            lua.execute::<String>(code).unwrap(),
+            "bad argument #3 to 'format' (number expected for '%d', got string)"
+        );
+        let code = "local mt = { __tostring = function() error('no way', 0) end }
+                    local ok, msg = pcall(string.format, '%s', setmetatable({}, mt))
+                    return msg";
+        assert_eq!(lua.execute::<String>(code).unwrap(), "no way");
+    }
+}
@@ -0,0 +1,623 @@
+use std::{
+    ffi::CStr,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    str,
+};
+
+use crate::patterns::string_arg;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsMutLua, Lua, LuaContext, Push};
+
+/// Directories that scripts can access through the `fs` library.
+///
+/// Each root has a name, which is the first component of the paths used by scripts. For
+/// example, with a root named `assets`, the script path `assets/images/logo.png` designates the
+/// file `images/logo.png` inside the directory of the root. Paths can't leave their root, even
+/// through `..` or symbolic links.
+///
+/// # Example
+///
+/// ```no_run
+/// let roots = hlua::FsRoots::new()
+///     .root("assets", "/srv/game/assets")
+///     .writable_root("saves", "/var/lib/game/saves");
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.open_fs(roots);
+/// lua.execute::<()>("fs.write('saves/slot1', fs.read('assets/new_game'))").unwrap();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsRoots {
+    roots: Vec<FsRoot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FsRoot {
+    name: String,
+    path: PathBuf,
+    writable: bool,
+}
+
+impl FsRoots {
+    /// Builds an empty list of roots, which doesn't give access to any file.
+    #[inline]
+    pub fn new() -> FsRoots {
+        FsRoots::default()
+    }
+
+    /// Adds a root whose files can only be read.
+    #[inline]
+    pub fn root(self, name: &str, path: impl Into<PathBuf>) -> FsRoots {
+        self.add(name, path.into(), false)
+    }
+
+    /// Adds a root whose files can be read, created, modified and removed.
+    #[inline]
+    pub fn writable_root(self, name: &str, path: impl Into<PathBuf>) -> FsRoots {
+        self.add(name, path.into(), true)
+    }
+
+    fn add(mut self, name: &str, path: PathBuf, writable: bool) -> FsRoots {
+        assert!(
+            !name.is_empty() && !name.contains(['/', '\\']) && name != "." && name != "..",
+            "invalid root name {:?}",
+            name
+        );
+        self.roots.retain(|root| root.name != name);
+        self.roots.push(FsRoot { name: name.to_owned(), path, writable });
+        self
+    }
+
+    /// Returns the path on the host of the script path `path`, or an error if it is outside of
+    /// the roots. If `write` is true, the root must also be writable.
+    ///
+    /// The file doesn't need to exist, but its parent directory does.
+    pub fn resolve(&self, path: &str, write: bool) -> Result<PathBuf, String> {
+        if path.contains(['\\', '\0']) {
+            return Err(format!("invalid path {}", path));
+        }
+
+        // `..` is resolved before looking at the file system, so that it can't climb out of
+        // the root.
+        let mut components: Vec<&str> = Vec::new();
+        for component in path.split('/') {
+            match component {
+                "" | "." => {},
+                ".." if components.len() <= 1 => {
+                    return Err(format!("path {} is outside of the roots", path))
+                },
+                ".." => {
+                    components.pop();
+                },
+                component => components.push(component),
+            }
+        }
+        if path.starts_with('/') || components.is_empty() {
+            return Err(format!("path {} is outside of the roots", path));
+        }
+
+        let root = self
+            .roots
+            .iter()
+            .find(|root| root.name == components[0])
+            .ok_or_else(|| format!("unknown root {}", components[0]))?;
+        if write && !root.writable {
+            return Err(format!("root {} is read-only", root.name));
+        }
+
+        let base = root
+            .path
+            .canonicalize()
+            .map_err(|err| format!("root {} is unavailable: {}", root.name, err))?;
+        let mut resolved = base.clone();
+        resolved.extend(&components[1..]);
+
+        // Symbolic links are followed, and must stay inside of the root. Only the parent needs
+        // to exist, so that files can be created, but not through a dangling link that writing
+        // would follow.
+        let checked = match resolved.canonicalize() {
+            Ok(checked) => checked,
+            Err(_) if fs::symlink_metadata(&resolved).is_ok() => {
+                return Err(format!("path {} is outside of the roots", path))
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound && components.len() > 1 => {
+                let name = resolved.file_name().map(ToOwned::to_owned);
+                let parent = resolved.parent().unwrap_or(&base).canonicalize();
+                match (parent, name) {
+                    (Ok(parent), Some(name)) => parent.join(name),
+                    (Err(err), _) => return Err(format!("{}: {}", path, err)),
+                    (Ok(_), None) => return Err(format!("invalid path {}", path)),
+                }
+            },
+            Err(err) => return Err(format!("{}: {}", path, err)),
+        };
+        let inside = checked.starts_with(&base)
+            && checked
+                .components()
+                .skip(base.components().count())
+                .all(|c| matches!(c, Component::Normal(_)));
+        match inside {
+            true => Ok(checked),
+            false => Err(format!("path {} is outside of the roots", path)),
+        }
+    }
+}
+
+// Stored as an upvalue of the functions of the library.
+#[derive(Debug)]
+struct FsContext {
+    roots: FsRoots,
+}
+
+// Userdata returned by `fs.open`. The file is `None` once closed.
+#[derive(Debug)]
+struct FsFile {
+    file: Option<File>,
+}
+
+type Method = (&'static CStr, ffi::lua_CFunction);
+
+impl<'lua> Lua<'lua> {
+    /// Opens an `fs` library that lets scripts access the files inside of `roots`.
+    ///
+    /// - `fs.read(path)` returns the content of a file.
+    /// - `fs.write(path, data)` replaces the content of a file, creating it if needed.
+    /// - `fs.list(path)` returns the sorted names of the entries of a directory.
+    /// - `fs.exists(path)` returns true if the file or directory exists.
+    /// - `fs.remove(path)` removes a file or an empty directory.
+    /// - `fs.mkdir(path)` creates a directory and its missing parents.
+    /// - `fs.open(path [, mode])` opens a file in mode `r` (the default), `w` or `a`, and
+    ///   returns an object with the methods `read([count])`, `write(data)` and `close()`.
+    ///
+    /// Write operations require a writable root. When a path is outside of the roots or the
+    /// operation fails, the functions return nil and a message, like the `io` library does.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let dir = std::env::temp_dir().join("hlua_open_fs_doc");
+    /// std::fs::create_dir_all(&dir).unwrap();
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// lua.open_fs(hlua::FsRoots::new().writable_root("tmp", &dir));
+    /// lua.execute::<()>("fs.write('tmp/a.txt', 'hello')").unwrap();
+    ///
+    /// let code = "local content, err = fs.read('tmp/../etc/passwd') return err";
+    /// let err: String = lua.execute(code).unwrap();
+    /// assert_eq!(err, "path tmp/../etc/passwd is outside of the roots");
+    /// assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "hello");
+    /// ```
+    pub fn open_fs(&mut self, roots: FsRoots) {
+        let functions: [Method; 7] = [
+            (c"read", Some(read)),
+            (c"write", Some(write)),
+            (c"list", Some(list)),
+            (c"exists", Some(exists)),
+            (c"remove", Some(remove)),
+            (c"mkdir", Some(mkdir)),
+            (c"open", Some(open)),
+        ];
+
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_createtable(l, 0, functions.len() as _);
+            push_userdata(FsContext { roots }, raw_lua, |_| {}).forget();
+            for (name, function) in functions {
+                ffi::lua_pushvalue(l, -1);
+                ffi::lua_pushcclosure(l, function, 1);
+                ffi::lua_setfield(l, -3, name.as_ptr());
+            }
+            ffi::lua_pop(l, 1);
+
+            ffi::lua_getfield(l, -2, c"package".as_ptr());
+            if ffi::lua_istable(l, -1) {
+                ffi::lua_getfield(l, -1, c"loaded".as_ptr());
+                if ffi::lua_istable(l, -1) {
+                    ffi::lua_pushvalue(l, -3);
+                    ffi::lua_setfield(l, -2, c"fs".as_ptr());
+                }
+                ffi::lua_pop(l, 1);
+            }
+            ffi::lua_pop(l, 1);
+
+            ffi::lua_setfield(l, -2, c"fs".as_ptr());
+            ffi::lua_pop(l, 1);
+        }
+    }
+}
+
+// Returns the number of results of a function, or raises the error.
+unsafe fn finish(lua: *mut ffi::lua_State, result: Result<libc::c_int, String>) -> libc::c_int {
+    match result {
+        Ok(count) => count,
+        Err(msg) => {
+            msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+            ffix::lua_error(lua);
+        },
+    }
+}
+
+// Pushes the result of an operation: one value on success, or nil and the message.
+unsafe fn results(lua: *mut ffi::lua_State, result: Result<libc::c_int, String>) -> libc::c_int {
+    match result {
+        Ok(count) => count,
+        Err(msg) => {
+            ffi::lua_pushnil(lua);
+            msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+            2
+        },
+    }
+}
+
+unsafe fn path_arg<'a>(lua: *mut ffi::lua_State, index: libc::c_int) -> Result<&'a str, String> {
+    match string_arg(lua, index).map(str::from_utf8) {
+        Some(Ok(path)) => Ok(path),
+        _ => Err(format!("bad argument #{} (path expected)", index)),
+    }
+}
+
+// Resolves the path at `index` against the roots of the library.
+unsafe fn resolve_arg(
+    lua: *mut ffi::lua_State,
+    index: libc::c_int,
+    write: bool,
+) -> Result<Result<PathBuf, String>, String> {
+    let path = path_arg(lua, index)?;
+    let raw_lua = LuaContext::new_unchecked(lua);
+    let context = userdata_mut::<FsContext>(raw_lua, ffi::lua_upvalueindex(1))
+        .expect("the upvalue of the fs functions is an FsContext");
+    Ok(context.roots.resolve(path, write))
+}
+
+// Opens a file without following a symbolic link in its last component, which could have been
+// created since `resolve` checked the path.
+fn open_file(options: &mut OpenOptions, path: &Path) -> io::Result<File> {
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(options, libc::O_NOFOLLOW);
+    options.open(path)
+}
+
+fn io_error(path: &Path, err: io::Error) -> String {
+    format!("{}: {}", path.file_name().unwrap_or_default().to_string_lossy(), err)
+}
+
+unsafe fn push_bytes(lua: *mut ffi::lua_State, data: &[u8]) {
+    ffi::lua_pushlstring(lua, data.as_ptr().cast(), data.len());
+}
+
+extern "C" fn read(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let result = resolve_arg(lua, 1, false)?.and_then(|path| {
+            let mut data = Vec::new();
+            open_file(OpenOptions::new().read(true), &path)
+                .and_then(|mut file| file.read_to_end(&mut data))
+                .map_err(|err| io_error(&path, err))?;
+            push_bytes(lua, &data);
+            Ok(1)
+        });
+        Ok(results(lua, result))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn write(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let data = string_arg(lua, 2).ok_or("bad argument #2 to 'write' (string expected)")?;
+        let result = resolve_arg(lua, 1, true)?.and_then(|path| {
+            open_file(OpenOptions::new().write(true).create(true).truncate(true), &path)
+                .and_then(|mut file| file.write_all(data))
+                .map_err(|err| io_error(&path, err))?;
+            ffi::lua_pushboolean(lua, 1);
+            Ok(1)
+        });
+        Ok(results(lua, result))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn list(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let result = resolve_arg(lua, 1, false)?.and_then(|path| {
+            let mut names = fs::read_dir(&path)
+                .and_then(|entries| {
+                    entries
+                        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into()))
+                        .collect::<io::Result<Vec<String>>>()
+                })
+                .map_err(|err| io_error(&path, err))?;
+            names.sort();
+
+            ffi::lua_createtable(lua, names.len() as _, 0);
+            for (position, name) in names.iter().enumerate() {
+                push_bytes(lua, name.as_bytes());
+                ffi::lua_rawseti(lua, -2, (position + 1) as _);
+            }
+            Ok(1)
+        });
+        Ok(results(lua, result))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn exists(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let result = resolve_arg(lua, 1, false)?.map(|path| {
+            ffi::lua_pushboolean(lua, path.exists() as libc::c_int);
+            1
+        });
+        Ok(results(lua, result))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn remove(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let result = resolve_arg(lua, 1, true)?.and_then(|path| {
+            let removed = match path.is_dir() {
+                true => fs::remove_dir(&path),
+                false => fs::remove_file(&path),
+            };
+            removed.map_err(|err| io_error(&path, err))?;
+            ffi::lua_pushboolean(lua, 1);
+            Ok(1)
+        });
+        Ok(results(lua, result))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn mkdir(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        // The parent may not exist yet, so the path is resolved one component at a time.
+        let path = path_arg(lua, 1)?.to_owned();
+        let raw_lua = LuaContext::new_unchecked(lua);
+        let context = userdata_mut::<FsContext>(raw_lua, ffi::lua_upvalueindex(1))
+            .expect("the upvalue of the fs functions is an FsContext");
+
+        let mut partial = String::new();
+        let mut result = Ok(1);
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if !partial.is_empty() {
+                partial.push('/');
+            }
+            partial.push_str(component);
+            result = context.roots.resolve(&partial, true).and_then(|resolved| {
+                match resolved.is_dir() {
+                    true => Ok(1),
+                    false => {
+                        fs::create_dir(&resolved).map(|_| 1).map_err(|err| io_error(&resolved, err))
+                    },
+                }
+            });
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_ok() {
+            ffi::lua_pushboolean(lua, 1);
+        }
+        Ok(results(lua, result))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn open(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let mode = match ffi::lua_isnoneornil(lua, 2) {
+            true => &b"r"[..],
+            false => string_arg(lua, 2).ok_or("bad argument #2 to 'open' (string expected)")?,
+        };
+        let mut options = OpenOptions::new();
+        match mode {
+            b"r" => options.read(true),
+            b"w" => options.write(true).create(true).truncate(true),
+            b"a" => options.append(true).create(true),
+            _ => return Err("bad argument #2 to 'open' (invalid mode)".to_owned()),
+        };
+
+        let result = resolve_arg(lua, 1, mode != b"r")?.and_then(|path| {
+            let file = open_file(&mut options, &path).map_err(|err| io_error(&path, err))?;
+            push_file(lua, file);
+            Ok(1)
+        });
+        Ok(results(lua, result))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+unsafe fn push_file(lua: *mut ffi::lua_State, file: File) {
+    let raw_lua = LuaContext::new_unchecked(lua);
+    push_userdata(FsFile { file: Some(file) }, raw_lua, |mut metatable| {
+        let l = metatable.as_mut_lua().as_ptr();
+        let methods: [Method; 3] = [
+            (c"read", Some(file_read)),
+            (c"write", Some(file_write)),
+            (c"close", Some(file_close)),
+        ];
+        ffi::lua_createtable(l, 0, methods.len() as _);
+        for (name, method) in methods {
+            ffi::lua_pushcfunction(l, method);
+            ffi::lua_setfield(l, -2, name.as_ptr());
+        }
+        ffi::lua_setfield(l, -2, c"__index".as_ptr());
+    })
+    .forget();
+}
+
+unsafe fn file_arg<'a>(lua: *mut ffi::lua_State) -> Result<&'a mut File, String> {
+    let raw_lua = LuaContext::new_unchecked(lua);
+    match userdata_mut::<FsFile>(raw_lua, 1) {
+        Some(FsFile { file: Some(file) }) => Ok(file),
+        Some(FsFile { file: None }) => Err("attempt to use a closed file".to_owned()),
+        None => Err("bad argument #1 (file expected)".to_owned()),
+    }
+}
+
+extern "C" fn file_read(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let file = file_arg(lua)?;
+        let mut data = Vec::new();
+        let read = match ffi::lua_isnoneornil(lua, 2) {
+            true => file.read_to_end(&mut data),
+            false => {
+                let mut success = 0;
+                let count = ffi::lua_tointegerx(lua, 2, &mut success);
+                if success == 0 || count < 0 {
+                    return Err("bad argument #2 to 'read' (count expected)".to_owned());
+                }
+                file.take(count as u64).read_to_end(&mut data)
+            },
+        };
+
+        let result = read.map_err(|err| err.to_string()).map(|read| {
+            // Like `io.read`, reading a count at the end of the file returns nil.
+            match read == 0 && !ffi::lua_isnoneornil(lua, 2) {
+                true => ffi::lua_pushnil(lua),
+                false => push_bytes(lua, &data),
+            }
+            1
+        });
+        Ok(results(lua, result))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn file_write(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let file = file_arg(lua)?;
+        let data = string_arg(lua, 2).ok_or("bad argument #2 to 'write' (string expected)")?;
+        let result = file.write_all(data).map_err(|err| err.to_string()).map(|_| {
+            ffi::lua_pushvalue(lua, 1);
+            1
+        });
+        Ok(results(lua, result))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn file_close(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        file_arg(lua)?;
+        let raw_lua = LuaContext::new_unchecked(lua);
+        if let Some(file) = userdata_mut::<FsFile>(raw_lua, 1) {
+            file.file = None;
+        }
+        ffi::lua_pushboolean(lua, 1);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::{FsRoots, Lua};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hlua_fs_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("assets/a.txt"), "alpha").unwrap();
+        dir
+    }
+
+    #[test]
+    fn read_write_list() {
+        let dir = temp_dir("rw");
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_fs(
+            FsRoots::new()
+                .root("assets", dir.join("assets"))
+                .writable_root("data", dir.join("data")),
+        );
+
+        let code = "assert(fs.mkdir('data/sub/dir'))
+                    assert(fs.write('data/sub/b.txt', fs.read('assets/a.txt') .. '!'))
+                    local f = assert(fs.open('data/sub/b.txt', 'a'))
+                    f:write('?'):close()
+                    f = assert(fs.open('data/sub/b.txt'))
+                    local head, rest = f:read(2), f:read()
+                    f:close()
+                    assert(not pcall(f.read, f, 1))
+                    return head .. ' ' .. rest .. ' ' .. table.concat(fs.list('data/sub'), ',')";
+        let summary: String = lua.execute(code).unwrap();
+        assert_eq!(summary, "al pha!? b.txt,dir");
+        assert!(lua.execute::<bool>("return fs.exists('data/sub/dir')").unwrap());
+        assert!(lua.execute::<bool>("return fs.remove('data/sub/dir')").unwrap());
+        assert!(!dir.join("data/sub/dir").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn confinement() {
+        let dir = temp_dir("confinement");
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_fs(FsRoots::new().root("assets", dir.join("assets")));
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&dir, dir.join("assets/escape")).unwrap();
+
+        let error = |lua: &mut Lua, code: &str| -> String {
+            lua.execute(&format!("return select(2, {})", code)).unwrap()
+        };
+        assert_eq!(error(&mut lua, "fs.read('data/x')"), "unknown root data");
+        assert_eq!(
+            error(&mut lua, "fs.read('assets/../data/x')"),
+            "path assets/../data/x is outside of the roots"
+        );
+        assert_eq!(
+            error(&mut lua, "fs.read('assets/../../x')"),
+            "path assets/../../x is outside of the roots"
+        );
+        assert_eq!(
+            error(&mut lua, "fs.read('/etc/passwd')"),
+            "path /etc/passwd is outside of the roots"
+        );
+        assert_eq!(error(&mut lua, "fs.write('assets/a.txt', '')"), "root assets is read-only");
+        assert_eq!(error(&mut lua, "fs.open('assets/a.txt', 'w')"), "root assets is read-only");
+        #[cfg(unix)]
+        assert_eq!(
+            error(&mut lua, "fs.list('assets/escape/data')"),
+            "path assets/escape/data is outside of the roots"
+        );
+        assert_eq!(lua.execute::<String>("return fs.read('assets/./a.txt')").unwrap(), "alpha");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn dangling_symlinks() {
+        let dir = temp_dir("dangling_symlinks");
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_fs(FsRoots::new().writable_root("data", dir.join("data")));
+        std::os::unix::fs::symlink(dir.join("outside.txt"), dir.join("data/link")).unwrap();
+
+        let code = "return select(2, fs.write('data/link', 'x'))";
+        assert_eq!(lua.execute::<String>(code).unwrap(), "path data/link is outside of the roots");
+        let code = "return select(2, fs.open('data/link', 'a'))";
+        assert_eq!(lua.execute::<String>(code).unwrap(), "path data/link is outside of the roots");
+        assert!(!dir.join("outside.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,488 @@
+use std::{collections::HashMap, error::Error, ffi::CStr, fmt, marker::PhantomData, slice, str};
+
+use crate::format::type_name;
+use crate::{AsMutLua, Lua, LuaContext, LuaError, LuaFunction, LuaFunctionCallError};
+use crate::{LuaRead, LuaRef, Push};
+
+/// Finite state machine whose states are defined by a Lua table, and that is driven from Rust
+/// with events of type `E`.
+///
+/// The definition table contains the name of the `initial` state and the `states` table, whose
+/// keys are the names of the states. Each state is a table that can contain:
+///
+/// - `on_enter(from, event)`, called when the machine enters the state, with the name of the
+///   previous state and of the event. Both are nil when the initial state is entered.
+/// - `on_exit(to, event)`, called when the machine leaves the state.
+/// - `update(dt)`, called by `Fsm::update`. It can return the name of an event, which is then
+///   fired.
+/// - `transitions`, a table whose keys are names of events and whose values are the names of the
+///   states that these events lead to. The events without a transition are ignored.
+///
+/// The definition is validated when the machine is created, so that misspelled fields, events
+/// and states are reported once instead of when they are used. The names of the events are
+/// checked against `FsmEvent::NAMES`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Fsm, FsmEvent, LuaRef};
+///
+/// enum Event { SeePlayer, LosePlayer }
+///
+/// impl FsmEvent for Event {
+///     const NAMES: &'static [&'static str] = &["see_player", "lose_player"];
+///
+///     fn name(&self) -> &'static str {
+///         match self {
+///             Event::SeePlayer => "see_player",
+///             Event::LosePlayer => "lose_player",
+///         }
+///     }
+/// }
+///
+/// let mut lua = hlua::Lua::new();
+/// let definition: LuaRef = lua.execute(r#"
+///     log = ""
+///     return {
+///         initial = "idle",
+///         states = {
+///             idle = { transitions = { see_player = "chase" } },
+///             chase = {
+///                 on_enter = function(from) log = log .. "chasing after " .. from end,
+///                 update = function(dt) if dt > 1 then return "lose_player" end end,
+///                 transitions = { lose_player = "idle" },
+///             },
+///         },
+///     }
+/// "#).unwrap();
+///
+/// let mut guard = Fsm::<Event>::new(&mut lua, &definition).unwrap();
+/// assert!(!guard.fire(&mut lua, Event::LosePlayer).unwrap());
+/// assert!(guard.fire(&mut lua, Event::SeePlayer).unwrap());
+/// assert_eq!(guard.state(), "chase");
+/// assert!(guard.update(&mut lua, 2.0).unwrap());
+/// assert_eq!(guard.state(), "idle");
+/// assert_eq!(lua.get::<String, _>("log").unwrap(), "chasing after idle");
+/// ```
+#[derive(Debug)]
+pub struct Fsm<E> {
+    states: HashMap<String, State>,
+    current: String,
+    marker: PhantomData<fn(E)>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    on_enter: Option<LuaRef>,
+    on_exit: Option<LuaRef>,
+    update: Option<LuaRef>,
+    transitions: HashMap<&'static str, String>,
+}
+
+/// Events that drive an `Fsm`.
+///
+/// This is usually implemented by an enum whose variants each have a name.
+pub trait FsmEvent {
+    /// Names of all the events, which are the keys of the `transitions` tables.
+    const NAMES: &'static [&'static str];
+
+    /// Returns the name of the event, which must be one of `NAMES`.
+    fn name(&self) -> &'static str;
+}
+
+/// Error that can happen when creating or driving an `Fsm`.
+#[derive(Debug)]
+pub enum FsmError {
+    /// The definition table is invalid. The message starts with the path of the invalid field,
+    /// for example `states.idle.transitions.jump: unknown event 'jump'`.
+    InvalidDefinition(String),
+    /// An `update` function returned a name that isn't the name of an event.
+    UnknownEvent(String),
+    /// A function of the definition raised an error, or `update` returned a value that isn't a
+    /// string.
+    LuaError(LuaError),
+}
+
+impl fmt::Display for FsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsmError::InvalidDefinition(msg) => write!(f, "invalid state machine: {}", msg),
+            FsmError::UnknownEvent(name) => write!(f, "unknown event '{}'", name),
+            FsmError::LuaError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for FsmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FsmError::LuaError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<LuaError> for FsmError {
+    #[inline]
+    fn from(err: LuaError) -> FsmError {
+        FsmError::LuaError(err)
+    }
+}
+
+impl<E> Fsm<E>
+where
+    E: FsmEvent,
+{
+    /// Validates a definition table and enters its initial state.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `definition` doesn't belong to `lua`.
+    pub fn new(lua: &mut Lua, definition: &LuaRef) -> Result<Fsm<E>, FsmError> {
+        let (initial, states) = unsafe {
+            let raw_lua = lua.as_mut_lua();
+            let top = ffi::lua_gettop(raw_lua.as_ptr());
+            definition.push_no_err(raw_lua).forget();
+            let result = read_definition(raw_lua, E::NAMES);
+            ffi::lua_settop(raw_lua.as_ptr(), top);
+            result.map_err(FsmError::InvalidDefinition)?
+        };
+
+        let fsm = Fsm { states, current: initial, marker: PhantomData };
+        if let Some(on_enter) = &fsm.state_data().on_enter {
+            call_transition(lua, on_enter, None, None)?;
+        }
+        Ok(fsm)
+    }
+
+    /// Returns the name of the current state.
+    #[inline]
+    pub fn state(&self) -> &str {
+        &self.current
+    }
+
+    /// Returns true if the current state has a transition for `event`.
+    #[inline]
+    pub fn can_fire(&self, event: &E) -> bool {
+        self.state_data().transitions.contains_key(event.name())
+    }
+
+    /// Makes the transition of the current state for `event`, if there is one, and returns
+    /// whether there was one.
+    ///
+    /// The `on_exit` function of the current state is called, then the `on_enter` function of
+    /// the next state. If `on_exit` fails, the machine stays in the current state.
+    pub fn fire(&mut self, lua: &mut Lua, event: E) -> Result<bool, FsmError> {
+        self.fire_named(lua, event.name())
+    }
+
+    /// Calls the `update` function of the current state with `dt`, and fires the event that it
+    /// returns, if any. Returns whether the machine made a transition.
+    pub fn update(&mut self, lua: &mut Lua, dt: f64) -> Result<bool, FsmError> {
+        let update = match &self.state_data().update {
+            Some(update) => update,
+            None => return Ok(false),
+        };
+        let mut update: LuaFunction<_> = update.get(&mut *lua).expect("the value is a function");
+        let event: Option<String> = update.call_with_args(dt).map_err(call_error)?;
+        drop(update);
+        match event {
+            Some(event) => match E::NAMES.iter().find(|name| **name == event) {
+                Some(name) => self.fire_named(lua, name),
+                None => Err(FsmError::UnknownEvent(event)),
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn fire_named(&mut self, lua: &mut Lua, event: &'static str) -> Result<bool, FsmError> {
+        let next = match self.state_data().transitions.get(event) {
+            Some(next) => next.clone(),
+            None => return Ok(false),
+        };
+
+        if let Some(on_exit) = &self.state_data().on_exit {
+            call_transition(lua, on_exit, Some(&next), Some(event))?;
+        }
+        let previous = std::mem::replace(&mut self.current, next);
+        if let Some(on_enter) = &self.state_data().on_enter {
+            call_transition(lua, on_enter, Some(&previous), Some(event))?;
+        }
+        Ok(true)
+    }
+
+    fn state_data(&self) -> &State {
+        &self.states[&self.current]
+    }
+}
+
+// Calls `on_enter` or `on_exit`.
+fn call_transition(
+    lua: &mut Lua,
+    function: &LuaRef,
+    state: Option<&str>,
+    event: Option<&str>,
+) -> Result<(), LuaError> {
+    let mut function: LuaFunction<_> = function.get(lua).expect("the value is a function");
+    function.call_with_args((state, event)).map_err(call_error)
+}
+
+fn call_error<E>(err: LuaFunctionCallError<E>) -> LuaError {
+    match err {
+        LuaFunctionCallError::LuaError(err) => err,
+        LuaFunctionCallError::PushError(_) => unreachable!(),
+    }
+}
+
+type Definition = (String, HashMap<String, State>);
+
+// Reads the definition at the top of the stack, leaving values on the stack.
+unsafe fn read_definition(lua: LuaContext, events: &[&'static str]) -> Result<Definition, String> {
+    let l = lua.as_ptr();
+    let definition = ffi::lua_gettop(l);
+    expect_type(l, definition, ffi::LUA_TTABLE, "definition")?;
+    check_fields(l, definition, &["initial", "states"], "definition")?;
+
+    ffi::lua_getfield(l, definition, c"initial".as_ptr());
+    expect_type(l, -1, ffi::LUA_TSTRING, "initial")?;
+    let initial = to_str(l, -1).to_owned();
+
+    ffi::lua_getfield(l, definition, c"states".as_ptr());
+    expect_type(l, -1, ffi::LUA_TTABLE, "states")?;
+    let mut states = HashMap::new();
+    ffi::lua_pushnil(l);
+    while ffi::lua_next(l, -2) != 0 {
+        if ffi::lua_type(l, -2) != ffi::LUA_TSTRING {
+            return Err(format!("states: the name of a state is a {}", type_name(l, -2)));
+        }
+        let name = to_str(l, -2).to_owned();
+        let path = format!("states.{}", name);
+        let state = read_state(lua, events, &path)?;
+        states.insert(name, state);
+        ffi::lua_pop(l, 1);
+    }
+
+    if !states.contains_key(&initial) {
+        return Err(format!("initial: unknown state '{}'", initial));
+    }
+    for (name, state) in &states {
+        for (event, next) in &state.transitions {
+            if !states.contains_key(next) {
+                return Err(format!(
+                    "states.{}.transitions.{}: unknown state '{}'",
+                    name, event, next
+                ));
+            }
+        }
+    }
+    Ok((initial, states))
+}
+
+// Reads the state at the top of the stack, leaving the stack as it was.
+unsafe fn read_state(
+    lua: LuaContext,
+    events: &[&'static str],
+    path: &str,
+) -> Result<State, String> {
+    let l = lua.as_ptr();
+    let table = ffi::lua_gettop(l);
+    expect_type(l, table, ffi::LUA_TTABLE, path)?;
+    check_fields(l, table, &["on_enter", "on_exit", "update", "transitions"], path)?;
+
+    let mut state = State::default();
+    let callbacks = [
+        (c"on_enter", &mut state.on_enter),
+        (c"on_exit", &mut state.on_exit),
+        (c"update", &mut state.update),
+    ];
+    for (field, callback) in callbacks {
+        ffi::lua_getfield(l, table, field.as_ptr());
+        if !ffi::lua_isnil(l, -1) {
+            let field_path = format!("{}.{}", path, field.to_str().unwrap());
+            expect_type(l, -1, ffi::LUA_TFUNCTION, &field_path)?;
+            *callback = LuaRef::lua_read_at_position(lua, -1).ok();
+        }
+        ffi::lua_pop(l, 1);
+    }
+
+    ffi::lua_getfield(l, table, c"transitions".as_ptr());
+    if !ffi::lua_isnil(l, -1) {
+        let path = format!("{}.transitions", path);
+        expect_type(l, -1, ffi::LUA_TTABLE, &path)?;
+        ffi::lua_pushnil(l);
+        while ffi::lua_next(l, -2) != 0 {
+            if ffi::lua_type(l, -2) != ffi::LUA_TSTRING {
+                return Err(format!("{}: the name of an event is a {}", path, type_name(l, -2)));
+            }
+            let event = to_str(l, -2);
+            let event = match events.iter().find(|name| **name == event) {
+                Some(name) => *name,
+                None => return Err(format!("{}.{}: unknown event '{}'", path, event, event)),
+            };
+            expect_type(l, -1, ffi::LUA_TSTRING, &format!("{}.{}", path, event))?;
+            state.transitions.insert(event, to_str(l, -1).to_owned());
+            ffi::lua_pop(l, 1);
+        }
+    }
+    ffi::lua_settop(l, table);
+    Ok(state)
+}
+
+// Returns an error if the table at `index` has a key that isn't one of `fields`.
+unsafe fn check_fields(
+    l: *mut ffi::lua_State,
+    index: libc::c_int,
+    fields: &[&str],
+    path: &str,
+) -> Result<(), String> {
+    ffi::lua_pushnil(l);
+    while ffi::lua_next(l, index) != 0 {
+        ffi::lua_pop(l, 1);
+        let known = ffi::lua_type(l, -1) == ffi::LUA_TSTRING && fields.contains(&to_str(l, -1));
+        if !known {
+            let key = match ffi::lua_type(l, -1) {
+                ffi::LUA_TSTRING => format!("field '{}'", to_str(l, -1)),
+                _ => format!("{} key", type_name(l, -1)),
+            };
+            return Err(format!("{}: unknown {}, expected {}", path, key, fields.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+unsafe fn expect_type(
+    l: *mut ffi::lua_State,
+    index: libc::c_int,
+    expected: libc::c_int,
+    path: &str,
+) -> Result<(), String> {
+    match ffi::lua_type(l, index) == expected {
+        true => Ok(()),
+        false => {
+            let expected = CStr::from_ptr(ffi::lua_typename(l, expected)).to_string_lossy();
+            Err(format!("{}: {} expected, got {}", path, expected, type_name(l, index)))
+        },
+    }
+}
+
+// Returns the string at `index`, which must be a string. Strings that aren't valid UTF-8 are
+// replaced with an empty string, which is never a valid name.
+unsafe fn to_str<'a>(l: *mut ffi::lua_State, index: libc::c_int) -> &'a str {
+    let mut len = 0;
+    let ptr = ffi::lua_tolstring(l, index, &mut len);
+    str::from_utf8(slice::from_raw_parts(ptr.cast(), len)).unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Fsm, FsmError, FsmEvent, Lua, LuaError, LuaRef};
+
+    #[derive(Debug, Clone, Copy)]
+    enum Door {
+        Open,
+        Close,
+    }
+
+    impl FsmEvent for Door {
+        const NAMES: &'static [&'static str] = &["open", "close"];
+
+        fn name(&self) -> &'static str {
+            match self {
+                Door::Open => "open",
+                Door::Close => "close",
+            }
+        }
+    }
+
+    fn load(lua: &mut Lua, code: &str) -> Result<Fsm<Door>, FsmError> {
+        let definition: LuaRef = lua.execute(code).unwrap();
+        Fsm::new(lua, &definition)
+    }
+
+    #[test]
+    fn callbacks_and_transitions() {
+        let mut lua = Lua::new();
+        let code = r#"
+            calls = ""
+            local function log(text) calls = calls .. text .. ";" end
+            return {
+                initial = "closed",
+                states = {
+                    closed = {
+                        on_enter = function(from, event)
+                            log("enter closed " .. (from or "-") .. " " .. (event or "-"))
+                        end,
+                        on_exit = function(to, event) log("exit closed " .. to .. " " .. event) end,
+                        transitions = { open = "opened" },
+                    },
+                    opened = {
+                        update = function(dt)
+                            log("update " .. dt)
+                            if dt > 5 then return "close" end
+                        end,
+                        transitions = { close = "closed", open = "opened" },
+                    },
+                },
+            }
+        "#;
+        let mut door = load(&mut lua, code).unwrap();
+        assert_eq!(door.state(), "closed");
+        assert!(door.can_fire(&Door::Open));
+        assert!(!door.can_fire(&Door::Close));
+
+        assert!(!door.fire(&mut lua, Door::Close).unwrap());
+        assert!(!door.update(&mut lua, 1.0).unwrap());
+        assert!(door.fire(&mut lua, Door::Open).unwrap());
+        assert!(door.fire(&mut lua, Door::Open).unwrap());
+        assert!(!door.update(&mut lua, 2.5).unwrap());
+        assert!(door.update(&mut lua, 8.5).unwrap());
+        assert_eq!(door.state(), "closed");
+
+        let expected = "enter closed - -;exit closed opened open;update 2.5;update 8.5;\
+                        enter closed opened close;";
+        assert_eq!(lua.get::<String, _>("calls").unwrap(), expected);
+    }
+
+    #[test]
+    fn invalid_definitions() {
+        let mut lua = Lua::new();
+        let cases = [
+            ("return 1", "definition: table expected, got number"),
+            ("return { states = {} }", "initial: string expected, got nil"),
+            (
+                "return { initial = 'a', states = { a = {} }, extra = 1 }",
+                "definition: unknown field 'extra', expected initial, states",
+            ),
+            ("return { initial = 'b', states = { a = {} } }", "initial: unknown state 'b'"),
+            (
+                "return { initial = 'a', states = { a = { on_entre = 1 } } }",
+                "states.a: unknown field 'on_entre', expected on_enter, on_exit, update, \
+                 transitions",
+            ),
+            (
+                "return { initial = 'a', states = { a = { update = 1 } } }",
+                "states.a.update: function expected, got number",
+            ),
+            (
+                "return { initial = 'a', states = { a = { transitions = { slam = 'a' } } } }",
+                "states.a.transitions.slam: unknown event 'slam'",
+            ),
+            (
+                "return { initial = 'a', states = { a = { transitions = { open = 'b' } } } }",
+                "states.a.transitions.open: unknown state 'b'",
+            ),
+        ];
+        for (code, expected) in cases {
+            match load(&mut lua, code) {
+                Err(FsmError::InvalidDefinition(msg)) => assert_eq!(msg, expected, "{}", code),
+                other => panic!("{}: {:?}", code, other),
+            }
+        }
+
+        let code = "return { initial = 'a', states = { a = {
+                        update = function(dt) if dt > 1 then return 'slam' end return {} end } } }";
+        let mut fsm = load(&mut lua, code).unwrap();
+        assert!(matches!(fsm.update(&mut lua, 2.0), Err(FsmError::UnknownEvent(e)) if e == "slam"));
+        assert!(matches!(fsm.update(&mut lua, 0.0), Err(FsmError::LuaError(LuaError::WrongType))));
+    }
+}
@@ -1,9 +1,17 @@
+use crate::flight_recorder;
+use crate::panic_policy::catch_panic;
+#[cfg(feature = "async")]
+use crate::userdata::push_userdata;
+use crate::userdata::BorrowFrame;
+use crate::userdata_methods::raise;
 use crate::{
-    ffix, values::LuaNil, AsLua, AsMutLua, LuaContext, LuaRead, Push, PushGuard, PushOne, Void,
+    ffix, values::LuaNil, AsLua, AsMutLua, Lua, LuaContext, LuaRead, Push, PushGuard, PushOne, Void,
 };
 
 use ptr::NonNull;
-use std::{fmt::Display, marker::PhantomData, mem, ptr};
+use std::{cell::Cell, fmt::Display, marker::PhantomData, mem, ptr};
+#[cfg(feature = "async")]
+use std::{future::Future, pin::Pin};
 
 macro_rules! impl_function {
     ($name:ident, $($p:ident),*) => (
@@ -171,13 +179,16 @@ impl_function!(function10, A, B, C, D, E, F, G, H, I, J);
 /// let ret = lua.execute::<()>("res = assert(err())");
 /// assert!(ret.is_err());
 /// ```
+///
+/// To raise the error instead, keeping its Rust value, return a `Result` whose error is a
+/// `Raise`.
 #[derive(Debug)]
 pub struct Function<F, P, R> {
     function: F,
     marker: PhantomData<(P, R)>,
 }
 
-type RawFunction = extern "C" fn(*mut ffi::lua_State) -> libc::c_int;
+pub(crate) type RawFunction = extern "C" fn(*mut ffi::lua_State) -> libc::c_int;
 
 /// Trait implemented on `Function` to mimic `FnMut`.
 ///
@@ -198,6 +209,36 @@ extern "C" fn closure_destructor_wrapper<T>(lua: *mut ffi::lua_State) -> libc::c
     }
 }
 
+// Pushes a C closure calling `wrapper`, with `function` as its upvalue if it isn't zero-sized.
+pub(crate) unsafe fn push_closure<Z>(raw_lua_ctx: LuaContext, function: Z, wrapper: RawFunction) {
+    let raw_lua_ptr = raw_lua_ctx.as_ptr();
+    // TODO: What more exactly is Z, and do we need to ensure alignment?
+
+    // We can skip pushing the pointer when it's zero-sized.
+    let has_data = mem::size_of::<Z>() != 0;
+    if has_data {
+        // Pushing the function pointer as a userdata.
+        let lua_data = ffi::lua_newuserdata(raw_lua_ptr, mem::size_of::<Z>() as libc::size_t);
+
+        let lua_data = lua_data.cast::<Z>();
+        ptr::write(lua_data, function);
+    }
+
+    // Only assign "__gc" if Z needs to be dropped.
+    if mem::needs_drop::<Z>() {
+        ffi::lua_newtable(raw_lua_ptr);
+
+        "__gc".push_no_err(raw_lua_ctx).forget_internal();
+        ffi::lua_pushcfunction(raw_lua_ptr, Some(closure_destructor_wrapper::<Z>));
+        ffi::lua_rawset(raw_lua_ptr, -3);
+
+        ffi::lua_setmetatable(raw_lua_ptr, -2);
+    }
+
+    // pushing wrapper as a closure
+    ffi::lua_pushcclosure(raw_lua_ptr, Some(wrapper), has_data as libc::c_int);
+}
+
 macro_rules! impl_function_ext {
     ($($p:ident),*) => (
         impl<Z, R $(,$p)*> FunctionExt<($($p,)*)> for Function<Z, ($($p,)*), R>
@@ -225,38 +266,9 @@ macro_rules! impl_function_ext {
             #[inline]
             fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
                 unsafe {
-                    let raw_lua_ctx = lua.as_mut_lua();
-                    let raw_lua_ptr = raw_lua_ctx.as_ptr();
-                    // TODO: What more exactly is Z, and do we need to ensure alignment?
-
-                    // We can skip pushing the pointer when it's zero-sized.
-                    let has_data = mem::size_of::<Z>() != 0;
-                    if has_data {
-                        // Pushing the function pointer as a userdata.
-                        let lua_data = ffi::lua_newuserdata(
-                            raw_lua_ptr,
-                            mem::size_of::<Z>() as libc::size_t
-                        );
-
-                        let lua_data = lua_data.cast::<Z>();
-                        ptr::write(lua_data, self.function);
-                    }
-
-                    // Only assign "__gc" if Z needs to be dropped.
-                    if mem::needs_drop::<Z>() {
-                        ffi::lua_newtable(raw_lua_ptr);
-
-                        "__gc".push_no_err(raw_lua_ctx).forget_internal();
-                        ffi::lua_pushcfunction(raw_lua_ptr, Some(closure_destructor_wrapper::<Z>));
-                        ffi::lua_rawset(raw_lua_ptr, -3);
-
-                        ffi::lua_setmetatable(raw_lua_ptr, -2);
-                    }
-
-                    // pushing wrapper as a closure
-                    let wrapper: RawFunction = wrapper::<Self, _, R>;
-                    ffi::lua_pushcclosure(raw_lua_ptr, Some(wrapper), has_data as libc::c_int);
-                    Ok(PushGuard { lua, size: 1, raw_lua: raw_lua_ctx })
+                    let raw_lua = lua.as_mut_lua();
+                    push_closure(raw_lua, self.function, wrapper::<Self, _, R>);
+                    Ok(PushGuard { lua, size: 1, raw_lua })
                 }
             }
         }
@@ -283,13 +295,169 @@ impl_function_ext!(A, B, C, D, E, F, G, H);
 impl_function_ext!(A, B, C, D, E, F, G, H, I);
 impl_function_ext!(A, B, C, D, E, F, G, H, I, J);
 
+macro_rules! impl_context_function {
+    ($($name:ident, $($p:ident),*;)*) => ($(
+        /// Wraps a closure whose first parameter is the Lua context of the call, so that it can
+        /// be used by hlua. See `ContextFunction`.
+        #[inline]
+        pub fn $name<Z, R $(, $p)*>(f: Z) -> ContextFunction<Z, ($($p,)*), R>
+            where Z: FnMut(&mut Lua<'_> $(, $p)*) -> R
+        {
+            ContextFunction {
+                function: f,
+                marker: PhantomData,
+            }
+        }
+
+        impl<Z, R $(,$p)*> FunctionExt<($($p,)*)> for ContextFunction<Z, ($($p,)*), R>
+        where
+            Z: FnMut(&mut Lua<'_> $(, $p)*) -> R
+        {
+            type Output = R;
+
+            #[allow(non_snake_case)]
+            #[inline]
+            fn call_mut(&mut self, params: ($($p,)*)) -> Self::Output {
+                let ($($p,)*) = params;
+                let lua = CURRENT_CALLBACK
+                    .with(Cell::get)
+                    .expect("context function called outside of a callback");
+                // The context doesn't own the state. It is never dropped, so that nothing can be
+                // closed even if the closure swaps it with another `Lua`.
+                let mut lua = mem::ManuallyDrop::new(Lua {
+                    lua,
+                    must_be_closed: false,
+                    marker: PhantomData,
+                });
+                (self.function)(&mut lua $(, $p)*)
+            }
+        }
+
+        impl<'lua, L, Z, R $(,$p: 'static)*> Push<L> for ContextFunction<Z, ($($p,)*), R>
+        where
+            L: AsMutLua<'lua>,
+            Z: 'lua + FnMut(&mut Lua<'_> $(, $p)*) -> R,
+            ($($p,)*): for<'p> LuaRead<&'p mut InsideCallback>,
+            R: for<'a> Push<&'a mut InsideCallback> + 'static
+        {
+            type Err = Void;
+            #[inline]
+            fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+                unsafe {
+                    let raw_lua = lua.as_mut_lua();
+                    push_closure(raw_lua, self, wrapper::<Self, _, R>);
+                    Ok(PushGuard { lua, size: 1, raw_lua })
+                }
+            }
+        }
+
+        impl<'lua, L, Z, R $(,$p: 'static)*> PushOne<L> for ContextFunction<Z, ($($p,)*), R>
+            where L: AsMutLua<'lua>,
+                  Z: 'lua + FnMut(&mut Lua<'_> $(, $p)*) -> R,
+                  ($($p,)*): for<'p> LuaRead<&'p mut InsideCallback>,
+                  R: for<'a> Push<&'a mut InsideCallback> + 'static
+        {
+        }
+    )*)
+}
+
+impl_context_function! {
+    function_with_lua0,;
+    function_with_lua1, A;
+    function_with_lua2, A, B;
+    function_with_lua3, A, B, C;
+    function_with_lua4, A, B, C, D;
+    function_with_lua5, A, B, C, D, E;
+    function_with_lua6, A, B, C, D, E, F;
+    function_with_lua7, A, B, C, D, E, F, G;
+    function_with_lua8, A, B, C, D, E, F, G, H;
+    function_with_lua9, A, B, C, D, E, F, G, H, I;
+}
+
+/// Opaque type containing a Rust function or closure that receives the Lua context of the call.
+///
+/// It is built with one of the `function_with_luaN` functions, where `N` is the number of
+/// parameters after the context, and is pushed like a `Function`. When Lua code calls it, the
+/// closure receives a `&mut Lua` for the state that runs the call, followed by the arguments.
+/// The context can be used like any `Lua` to read and write globals, create tables, call Lua
+/// functions or run code, before the closure returns its results.
+///
+/// The context doesn't own the state, and is only valid for the duration of the call.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, LuaFunction, LuaTable};
+///
+/// let mut lua = Lua::new();
+/// lua.set("scale", 3);
+/// lua.set("apply", hlua::function_with_lua2(|lua: &mut Lua, name: String, x: i32| {
+///     let scale: i32 = lua.get("scale").unwrap();
+///     let mut f: LuaFunction<_> = lua.get(name).unwrap();
+///     let result: i32 = f.call_with_args(x * scale).unwrap();
+///     result
+/// }));
+/// lua.set("record", hlua::function_with_lua1(|lua: &mut Lua, x: i32| {
+///     let mut log: LuaTable<_> = lua.empty_array("log");
+///     log.set(1, x);
+/// }));
+///
+/// lua.execute::<()>("function double(x) return x * 2 end").unwrap();
+/// assert_eq!(lua.execute::<i32>("return apply('double', 5)").unwrap(), 30);
+///
+/// lua.execute::<()>("record(7)").unwrap();
+/// assert_eq!(lua.execute::<i32>("return log[1]").unwrap(), 7);
+/// ```
+#[derive(Debug)]
+pub struct ContextFunction<F, P, R> {
+    function: F,
+    marker: PhantomData<(P, R)>,
+}
+
 /// Opaque type that represents the Lua context when inside a callback.
 ///
 /// Some types (like `Result`) can only be returned from a callback and not written inside a
 /// Lua variable. This type is here to enforce this restriction.
 #[derive(Debug)]
 pub struct InsideCallback {
-    lua: LuaContext,
+    pub(crate) lua: LuaContext,
+}
+
+thread_local! {
+    // Context of the innermost Rust callback running on this thread.
+    static CURRENT_CALLBACK: Cell<Option<LuaContext>> = const { Cell::new(None) };
+}
+
+impl InsideCallback {
+    /// Calls `f` with the context of the Rust callback that is running on this thread, and
+    /// returns its result. Returns `None` if no callback is running.
+    ///
+    /// This is mostly useful to reach the values stored with `Lua::set_app_data` from the
+    /// callback, with `InsideCallback::app_data`.
+    pub fn with_current<F, R>(f: F) -> Option<R>
+    where
+        F: FnOnce(&mut InsideCallback) -> R,
+    {
+        let lua = CURRENT_CALLBACK.with(Cell::get)?;
+        Some(f(&mut InsideCallback { lua }))
+    }
+}
+
+// Makes a context the current one of `InsideCallback::with_current` while a callback runs.
+pub(crate) struct CurrentCallback(Option<LuaContext>);
+
+impl CurrentCallback {
+    #[inline]
+    pub(crate) fn enter(lua: LuaContext) -> CurrentCallback {
+        CurrentCallback(CURRENT_CALLBACK.with(|current| current.replace(Some(lua))))
+    }
+}
+
+impl Drop for CurrentCallback {
+    #[inline]
+    fn drop(&mut self) {
+        CURRENT_CALLBACK.with(|current| current.set(self.0));
+    }
 }
 
 unsafe impl<'a, 'lua> AsLua<'lua> for &'a InsideCallback {
@@ -341,6 +509,34 @@ where
 {
 }
 
+thread_local! {
+    // Why the last parameter of a callback couldn't be read, when the type gives a reason.
+    static CONVERSION_ERROR: Cell<Option<String>> = const { Cell::new(None) };
+}
+
+/// Records why a value couldn't be read, so that the error raised by the callback whose
+/// parameters are being read includes it.
+pub(crate) fn set_conversion_error(message: String) {
+    CONVERSION_ERROR.with(|error| error.set(Some(message)));
+}
+
+/// Returns and forgets the reason recorded by `set_conversion_error`.
+pub(crate) fn take_conversion_error() -> Option<String> {
+    CONVERSION_ERROR.with(Cell::take)
+}
+
+#[cold]
+#[inline(never)]
+fn err_wrong_type(lua: LuaContext) -> ! {
+    match take_conversion_error() {
+        Some(reason) => format!("wrong parameter types for callback function: {}", reason)
+            .push_no_err(lua)
+            .forget_internal(),
+        None => "wrong parameter types for callback function".push_no_err(lua).forget_internal(),
+    };
+    unsafe { ffix::lua_error(lua.as_ptr()) };
+}
+
 // this function is called when Lua wants to call one of our functions
 #[inline]
 extern "C" fn wrapper<T, P, R>(lua: *mut ffi::lua_State) -> libc::c_int
@@ -349,44 +545,292 @@ where
     P: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
     R: for<'p> Push<&'p mut InsideCallback>,
 {
-    #[cold]
-    #[inline(never)]
-    fn err_wrong_type(lua: LuaContext) -> ! {
-        "wrong parameter types for callback function".push_no_err(lua).forget_internal();
-        unsafe { ffix::lua_error(lua.as_ptr()) };
-    }
-
     // loading the object that we want to call from the Lua context
     let data_raw = match std::mem::size_of::<T>() {
         0 => NonNull::dangling().as_ptr(),
         _ => unsafe { ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) },
     };
 
+    unsafe { call_function::<T, P, R>(lua, data_raw.cast::<T>()) }
+}
+
+// Reads the parameters of a callback from its `argc` arguments. The parameters after the last
+// argument, or all of them if there is none, are read as out of bounds, so that the `Option`
+// parameters missing from the call are `None`.
+#[inline]
+fn read_args<P>(lua: &mut InsideCallback, argc: libc::c_int) -> Result<P, ()>
+where
+    P: for<'p> LuaRead<&'p mut InsideCallback>,
+{
+    let read = match argc {
+        0 => LuaRead::lua_read_out_of_bounds(lua),
+        argc => LuaRead::lua_read_at_position(lua, -argc),
+    };
+    read.map_err(|_| ())
+}
+
+// Reads the arguments, calls the function pointed to by `data` and pushes its return value.
+pub(crate) unsafe fn call_function<T, P, R>(lua: *mut ffi::lua_State, data: *mut T) -> libc::c_int
+where
+    T: FunctionExt<P, Output = R>,
+    P: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+    R: for<'p> Push<&'p mut InsideCallback>,
+{
+    match unsafe { try_call_function(lua, data) } {
+        Some(nb) => nb,
+        None => err_wrong_type(unsafe { NonNull::new_unchecked(lua) }),
+    }
+}
+
+// Like `call_function`, but returns `None` without calling the function if the arguments can't
+// be read as its parameters.
+pub(crate) unsafe fn try_call_function<T, P, R>(
+    lua: *mut ffi::lua_State,
+    data: *mut T,
+) -> Option<libc::c_int>
+where
+    T: FunctionExt<P, Output = R>,
+    P: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+    R: for<'p> Push<&'p mut InsideCallback>,
+{
     // creating a temporary Lua context in order to pass it to push & read functions
     let mut tmp_lua = InsideCallback { lua: unsafe { NonNull::new_unchecked(lua) } };
 
     // trying to read the arguments
     let argc = unsafe { ffi::lua_gettop(lua) };
-    let args = match LuaRead::lua_read_at_position(&mut tmp_lua, -argc as libc::c_int) {
-        Ok(a) => a,
-        Err(_) => err_wrong_type(tmp_lua.lua),
+    let frame = BorrowFrame::enter();
+    take_conversion_error();
+    let args = read_args(&mut tmp_lua, argc).ok()?;
+
+    unsafe { flight_recorder::record_callback(tmp_lua.lua) };
+
+    let data = unsafe { &mut *data };
+    let ret_value = catch_panic(tmp_lua.lua, || {
+        let _current = CurrentCallback::enter(tmp_lua.lua);
+        data.call_mut(args)
+    });
+    drop(frame);
+    let ret_value = match ret_value {
+        Ok(ret_value) => ret_value,
+        Err(message) => raise(tmp_lua.lua, message),
     };
 
-    let data = unsafe { &mut *data_raw.cast::<T>() };
-    let ret_value = data.call_mut(args);
-
     // pushing back the result of the function on the stack
     let nb = match ret_value.push_to_lua(&mut tmp_lua) {
         Ok(p) => p.forget_internal(),
         Err(_) => panic!(), // TODO: wrong
     };
 
-    nb as libc::c_int
+    Some(nb as libc::c_int)
+}
+
+#[cfg(feature = "async")]
+macro_rules! impl_function_async {
+    ($($name:ident, $($p:ident),*;)*) => ($(
+        /// Wraps a closure that returns a future so that Lua code running in a coroutine can
+        /// await it. See `AsyncFunction`.
+        #[inline]
+        pub fn $name<Z, R $(, $p)*>(f: Z) -> AsyncFunction<Z, ($($p,)*), R>
+            where Z: FnMut($($p),*) -> R,
+                  R: Future
+        {
+            AsyncFunction {
+                function: f,
+                marker: PhantomData,
+            }
+        }
+
+        impl<Z, R $(,$p)*> FunctionExt<($($p,)*)> for AsyncFunction<Z, ($($p,)*), R>
+        where
+            Z: FnMut($($p),*) -> R
+        {
+            type Output = R;
+
+            #[allow(non_snake_case)]
+            #[inline]
+            fn call_mut(&mut self, params: ($($p,)*)) -> Self::Output {
+                let ($($p,)*) = params;
+                (self.function)($($p),*)
+            }
+        }
+
+        impl<'lua, L, Z, R $(,$p: 'static)*> Push<L> for AsyncFunction<Z, ($($p,)*), R>
+        where
+            L: AsMutLua<'lua>,
+            Z: 'lua + FnMut($($p),*) -> R,
+            ($($p,)*): for<'p> LuaRead<&'p mut InsideCallback>,
+            R: Future + 'static,
+            R::Output: for<'a> Push<&'a mut InsideCallback> + 'static
+        {
+            type Err = Void;
+            #[inline]
+            fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+                unsafe {
+                    let raw_lua = lua.as_mut_lua();
+                    push_closure(raw_lua, self.function, async_wrapper::<Self, _, R>);
+                    Ok(PushGuard { lua, size: 1, raw_lua })
+                }
+            }
+        }
+
+        impl<'lua, L, Z, R $(,$p: 'static)*> PushOne<L> for AsyncFunction<Z, ($($p,)*), R>
+            where L: AsMutLua<'lua>,
+                  Z: 'lua + FnMut($($p),*) -> R,
+                  ($($p,)*): for<'p> LuaRead<&'p mut InsideCallback>,
+                  R: Future + 'static,
+                  R::Output: for<'a> Push<&'a mut InsideCallback> + 'static
+        {
+        }
+    )*)
+}
+
+#[cfg(feature = "async")]
+impl_function_async! {
+    function_async0,;
+    function_async1, A;
+    function_async2, A, B;
+    function_async3, A, B, C;
+    function_async4, A, B, C, D;
+    function_async5, A, B, C, D, E;
+    function_async6, A, B, C, D, E, F;
+    function_async7, A, B, C, D, E, F, G;
+    function_async8, A, B, C, D, E, F, G, H;
+    function_async9, A, B, C, D, E, F, G, H, I;
+    function_async10, A, B, C, D, E, F, G, H, I, J;
+}
+
+/// Opaque type containing a Rust function or closure that returns a future.
+///
+/// It is built with one of the `function_asyncN` functions, and pushed like a `Function`. When
+/// Lua code calls it, the closure is called with the arguments and the coroutine running the
+/// code is suspended until the future completes. The output of the future is then returned to
+/// the Lua code, like the return value of a `Function`, so the call looks synchronous from Lua.
+///
+/// The coroutine must be driven by `LuaCoroutine::resume_async`, which awaits the futures.
+/// Calling the function outside of a coroutine raises a Lua error, and the values it yields to
+/// other kinds of resumes are meaningless.
+///
+/// # Example
+///
+/// ```
+/// # fn block_on<F: std::future::Future>(future: F) -> F::Output {
+/// #     use std::task::{Context, Poll, Wake, Waker};
+/// #     struct Thread(std::thread::Thread);
+/// #     impl Wake for Thread {
+/// #         fn wake(self: std::sync::Arc<Self>) { self.0.unpark() }
+/// #     }
+/// #     let waker = Waker::from(std::sync::Arc::new(Thread(std::thread::current())));
+/// #     let mut future = std::pin::pin!(future);
+/// #     loop {
+/// #         match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+/// #             Poll::Ready(output) => return output,
+/// #             Poll::Pending => std::thread::park(),
+/// #         }
+/// #     }
+/// # }
+/// use hlua::{CoroutineResult, LuaCoroutine, LuaFunction};
+///
+/// let mut lua = hlua::Lua::new();
+/// // With an async runtime, this could be a database query or an HTTP request.
+/// lua.set("fetch", hlua::function_async1(|id: i32| async move { format!("user {}", id) }));
+///
+/// let script = LuaFunction::load(&mut lua, "return fetch(7) .. '!'").unwrap();
+/// let mut coroutine = LuaCoroutine::new(script);
+/// // With an async runtime: `coroutine.resume_async(()).await`
+/// let result = block_on(coroutine.resume_async::<String, _, _>(())).unwrap();
+/// assert_eq!(result, CoroutineResult::Returned("user 7".to_owned() + "!"));
+/// ```
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncFunction<F, P, R> {
+    function: F,
+    marker: PhantomData<(P, R)>,
+}
+
+/// Pushes the results of a call to an `AsyncFunction` on the stack of the coroutine, and
+/// returns their number.
+#[cfg(feature = "async")]
+pub(crate) type PushResults = Box<dyn FnOnce(LuaContext) -> libc::c_int>;
+
+/// Call to an `AsyncFunction` waiting for its future to complete.
+///
+/// The coroutine yields the address of `ASYNC_CALL_MARKER` and this userdata, so that
+/// `LuaCoroutine::resume_async` can tell these yields apart from the ones of the Lua code.
+#[cfg(feature = "async")]
+pub(crate) struct PendingCall {
+    pub(crate) future: Option<Pin<Box<dyn Future<Output = PushResults>>>>,
+}
+
+// The future stays in the context, and is polled on the thread that uses the context, like the
+// closures of `Function`.
+#[cfg(feature = "async")]
+unsafe impl Send for PendingCall {}
+
+#[cfg(feature = "async")]
+pub(crate) static ASYNC_CALL_MARKER: u8 = 0;
+
+// this function is called when Lua calls one of our async functions
+#[cfg(feature = "async")]
+extern "C" fn async_wrapper<T, P, R>(lua: *mut ffi::lua_State) -> libc::c_int
+where
+    T: FunctionExt<P, Output = R>,
+    P: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+    R: Future + 'static,
+    R::Output: for<'p> Push<&'p mut InsideCallback> + 'static,
+{
+    let data_raw = match std::mem::size_of::<T>() {
+        0 => NonNull::dangling().as_ptr(),
+        _ => unsafe { ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) },
+    };
+
+    let mut tmp_lua = InsideCallback { lua: unsafe { NonNull::new_unchecked(lua) } };
+
+    let argc = unsafe { ffi::lua_gettop(lua) };
+    // The future outlives the call, so the borrows only keep the parameters from aliasing
+    // each other.
+    let frame = BorrowFrame::enter();
+    take_conversion_error();
+    let args = match read_args(&mut tmp_lua, argc) {
+        Ok(a) => a,
+        Err(_) => {
+            drop(frame);
+            err_wrong_type(tmp_lua.lua)
+        },
+    };
+    drop(frame);
+
+    unsafe { flight_recorder::record_callback(tmp_lua.lua) };
+
+    let data = unsafe { &mut *data_raw.cast::<T>() };
+    let future = match catch_panic(tmp_lua.lua, || data.call_mut(args)) {
+        Ok(future) => future,
+        Err(message) => raise(tmp_lua.lua, message),
+    };
+    let future = Box::pin(async move {
+        let output = future.await;
+        Box::new(move |lua: LuaContext| {
+            let mut tmp_lua = InsideCallback { lua };
+            let pushed = output.push_to_lua(&mut tmp_lua);
+            match pushed {
+                Ok(p) => p.forget_internal(),
+                Err(_) => panic!(), // TODO: wrong
+            }
+        }) as PushResults
+    });
+
+    unsafe {
+        ffi::lua_pushlightuserdata(lua, ptr::addr_of!(ASYNC_CALL_MARKER) as *mut libc::c_void);
+        push_userdata(PendingCall { future: Some(future) }, tmp_lua.lua, |_| {}).forget();
+        ffi::lua_yield(lua, 2)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{function0, function1, function2, Lua, LuaError};
+    use crate::{
+        function0, function1, function2, function_with_lua0, function_with_lua2, Lua, LuaError,
+        LuaFunction,
+    };
 
     use std::sync::Arc;
 
@@ -444,6 +888,53 @@ mod tests {
         }
     }
 
+    #[test]
+    fn optional_arguments() {
+        let mut lua = Lua::new();
+        lua.set(
+            "greet",
+            function2(|times: i32, name: Option<String>| {
+                name.unwrap_or_else(|| "you".to_owned()).repeat(times as usize)
+            }),
+        );
+        lua.set("maybe", function1(|n: Option<i32>| n.map_or(-1, |n| n * 2)));
+
+        assert_eq!(lua.execute::<String>("return greet(2, 'ab')").unwrap(), "abab");
+        assert_eq!(lua.execute::<String>("return greet(1)").unwrap(), "you");
+        assert_eq!(lua.execute::<String>("return greet(1, nil)").unwrap(), "you");
+        assert_eq!(lua.execute::<i32>("return maybe()").unwrap(), -1);
+        assert_eq!(lua.execute::<i32>("return maybe(4)").unwrap(), 8);
+        assert!(lua.execute::<String>("return greet()").is_err());
+    }
+
+    #[test]
+    fn context_parameter() {
+        let mut lua = Lua::new();
+        lua.set("inc", function1(|n: i32| n + 1));
+        lua.set(
+            "call_twice",
+            function_with_lua2(|lua: &mut Lua, name: String, n: i32| {
+                let mut f: LuaFunction<_> = lua.get(name).unwrap();
+                let once: i32 = f.call_with_args(n).unwrap();
+                let twice: i32 = f.call_with_args(once).unwrap();
+                // The arguments are still available after the context has been used.
+                format!("{} {}", twice, n)
+            }),
+        );
+        lua.set(
+            "run",
+            function_with_lua0(|lua: &mut Lua| {
+                lua.set("ran", true);
+                lua.execute::<()>("local inner; inner()").map_err(|e| e.to_string())
+            }),
+        );
+
+        assert_eq!(lua.execute::<String>("return call_twice('inc', 5)").unwrap(), "7 5");
+        let error: String = lua.execute("local _, err = run(); return err").unwrap();
+        assert!(error.contains("inner"), "{}", error);
+        assert_eq!(lua.get::<bool, _>("ran"), Some(true));
+    }
+
     #[test]
     fn return_result() {
         let mut lua = Lua::new();
@@ -0,0 +1,478 @@
+use std::{
+    ffi::CStr,
+    time::{Duration, Instant},
+};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, lua_ref, Lua, LuaContext, LuaError, LuaRef, Push};
+
+// Key of the registry entry holding the `GcObserver` of a context.
+const OBSERVER_KEY: &CStr = c"hlua.gc_observer";
+
+// Key of the registry entry holding the weak-keyed table of callbacks registered with
+// `on_collect`. Each key is a Lua value, and each value a list of `CollectCallback`s.
+const FINALIZERS_KEY: &CStr = c"hlua.finalizers";
+
+/// Information about a completed garbage collection cycle.
+///
+/// See `Lua::set_gc_callback` and `Lua::collect_garbage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcCycleStats {
+    /// Number of bytes in use before the cycle started.
+    ///
+    /// Only known for collections triggered with `Lua::collect_garbage`. Cycles run by the
+    /// collector on its own are interleaved with the execution of the script, so there is no
+    /// single point where they start.
+    pub memory_before: Option<usize>,
+    /// Number of bytes in use after the cycle.
+    pub memory_after: usize,
+    /// Time spent inside the collector.
+    ///
+    /// Just like `memory_before`, only known for collections triggered with
+    /// `Lua::collect_garbage`.
+    pub pause: Option<Duration>,
+}
+
+impl GcCycleStats {
+    /// Returns the number of bytes freed by the cycle, if known.
+    #[inline]
+    pub fn freed_bytes(&self) -> Option<usize> {
+        self.memory_before.map(|before| before.saturating_sub(self.memory_after))
+    }
+}
+
+struct GcObserver {
+    callback: Box<dyn FnMut(&GcCycleStats) + Send>,
+    // True while `collect_garbage` is running, in which case the callback is invoked by
+    // `collect_garbage` itself with more precise information.
+    explicit: bool,
+}
+
+/// What was accomplished by `Lua::gc_step_for`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcStepReport {
+    /// Number of incremental steps performed.
+    pub steps: u32,
+    /// Time spent inside the collector.
+    pub elapsed: Duration,
+    /// True if the last step finished a collection cycle.
+    pub cycle_completed: bool,
+    /// Number of bytes in use before the first step.
+    pub memory_before: usize,
+    /// Number of bytes in use after the last step.
+    pub memory_after: usize,
+}
+
+impl GcStepReport {
+    /// Returns the number of bytes freed by the steps.
+    #[inline]
+    pub fn freed_bytes(&self) -> usize {
+        self.memory_before.saturating_sub(self.memory_after)
+    }
+}
+
+// Userdata that runs a closure when it is destroyed.
+struct CollectCallback(Option<Box<dyn FnOnce() + Send>>);
+
+impl Drop for CollectCallback {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(callback) = self.0.take() {
+            callback();
+        }
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Returns the total amount of memory in use by the Lua context, in bytes.
+    ///
+    /// This is the equivalent of `collectgarbage("count")`, except in bytes instead of kilobytes.
+    #[inline]
+    pub fn memory_used(&self) -> usize {
+        unsafe { memory_used(self.lua) }
+    }
+
+    /// Performs a full garbage collection cycle.
+    ///
+    /// This is the equivalent of `collectgarbage("collect")`. If a callback has been registered
+    /// with `set_gc_callback`, it is invoked with the returned statistics.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("local t = {} for i = 1, 1000 do t[i] = {} end").unwrap();
+    ///
+    /// let stats = lua.collect_garbage();
+    /// assert!(stats.freed_bytes().unwrap() > 0);
+    /// ```
+    pub fn collect_garbage(&mut self) -> GcCycleStats {
+        unsafe {
+            lua_ref::release_dropped(self.lua);
+            let has_observer = set_explicit(self.lua, true);
+
+            let memory_before = memory_used(self.lua);
+            let start = Instant::now();
+            ffi::lua_gc(self.lua.as_ptr(), ffi::LUA_GCCOLLECT, 0);
+            let pause = start.elapsed();
+
+            let stats = GcCycleStats {
+                memory_before: Some(memory_before),
+                memory_after: memory_used(self.lua),
+                pause: Some(pause),
+            };
+
+            if has_observer {
+                set_explicit(self.lua, false);
+                with_observer(self.lua, |observer| (observer.callback)(&stats));
+            }
+
+            stats
+        }
+    }
+
+    /// Performs an incremental step of garbage collection.
+    ///
+    /// This is the equivalent of `collectgarbage("step", kbytes)`. Returns true if the step
+    /// finished a collection cycle.
+    #[inline]
+    pub fn gc_step(&mut self, kbytes: i32) -> bool {
+        unsafe { ffi::lua_gc(self.lua.as_ptr(), ffi::LUA_GCSTEP, kbytes) != 0 }
+    }
+
+    /// Performs incremental steps of garbage collection until `budget` is exhausted or a
+    /// collection cycle completes, whichever comes first.
+    ///
+    /// This is meant to be called once per frame with whatever time is left in the frame, so
+    /// that the collector doesn't have to run in the middle of the script's execution. Each step
+    /// is a basic step of the collector, so the budget may be slightly exceeded. Does nothing if
+    /// `budget` is zero.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("for i = 1, 1000 do local t = {} end").unwrap();
+    ///
+    /// let report = lua.gc_step_for(Duration::from_millis(2));
+    /// assert!(report.cycle_completed || report.elapsed >= Duration::from_millis(2));
+    /// ```
+    pub fn gc_step_for(&mut self, budget: Duration) -> GcStepReport {
+        unsafe {
+            let memory_before = memory_used(self.lua);
+            let start = Instant::now();
+            let mut steps = 0;
+            let mut cycle_completed = false;
+
+            while !cycle_completed && start.elapsed() < budget {
+                cycle_completed = ffi::lua_gc(self.lua.as_ptr(), ffi::LUA_GCSTEP, 0) != 0;
+                steps += 1;
+            }
+
+            GcStepReport {
+                steps,
+                elapsed: start.elapsed(),
+                cycle_completed,
+                memory_before,
+                memory_after: memory_used(self.lua),
+            }
+        }
+    }
+
+    /// Registers a function that is called every time the garbage collector finishes a cycle.
+    ///
+    /// This lets you attribute frame time spikes to the Lua garbage collector. Cycles run by
+    /// the collector on its own are detected with a sentinel object whose finalizer reports the
+    /// cycle and creates a new sentinel. Replaces the previous callback, if any.
+    ///
+    /// The callback must not panic.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    ///
+    /// let cycles = Arc::new(AtomicUsize::new(0));
+    /// let counter = cycles.clone();
+    /// lua.set_gc_callback(move |_| { counter.fetch_add(1, Ordering::SeqCst); });
+    ///
+    /// lua.collect_garbage();
+    /// assert!(cycles.load(Ordering::SeqCst) >= 1);
+    /// ```
+    pub fn set_gc_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(&GcCycleStats) + Send + 'static,
+    {
+        unsafe {
+            let raw_lua = self.lua.as_ptr();
+            let existed = with_observer(self.lua, |_| ()).is_some();
+
+            let observer = GcObserver { callback: Box::new(callback), explicit: false };
+            push_userdata(observer, self.lua, |_| {}).forget();
+            ffi::lua_setfield(raw_lua, ffi::LUA_REGISTRYINDEX, OBSERVER_KEY.as_ptr());
+
+            if !existed {
+                push_sentinel(self.lua);
+            }
+        }
+    }
+
+    /// Removes the callback registered with `set_gc_callback`, if any.
+    #[inline]
+    pub fn remove_gc_callback(&mut self) {
+        unsafe { remove_observer(self.lua) }
+    }
+
+    /// Registers a function that is called when the referenced value is collected.
+    ///
+    /// The value is not kept alive by the callback, but it is of course kept alive by the
+    /// `LuaRef` itself. The callback is invoked during a garbage collection cycle once both the
+    /// `LuaRef` and all the references to the value from Lua have been dropped, or when the Lua
+    /// context is closed. Several callbacks can be registered for the same value.
+    ///
+    /// Returns `LuaError::WrongType` if the value can't be collected, which is the case of
+    /// everything except tables, functions, userdata and coroutines.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("obj = {}").unwrap();
+    ///
+    /// let collected = Arc::new(AtomicBool::new(false));
+    /// let flag = collected.clone();
+    ///
+    /// let obj: hlua::LuaRef = lua.get("obj").unwrap();
+    /// lua.on_collect(&obj, move || flag.store(true, Ordering::SeqCst)).unwrap();
+    /// drop(obj);
+    ///
+    /// lua.execute::<()>("obj = nil").unwrap();
+    /// lua.collect_garbage();
+    /// assert!(collected.load(Ordering::SeqCst));
+    /// ```
+    pub fn on_collect<F>(&mut self, value: &LuaRef, callback: F) -> Result<(), LuaError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        unsafe {
+            let raw_lua = self.lua.as_ptr();
+
+            // Load or create the table of finalizers.
+            ffi::lua_getfield(raw_lua, ffi::LUA_REGISTRYINDEX, FINALIZERS_KEY.as_ptr());
+            if ffi::lua_isnil(raw_lua, -1) {
+                ffi::lua_pop(raw_lua, 1);
+                ffi::lua_newtable(raw_lua);
+                ffi::lua_newtable(raw_lua);
+                "k".push_no_err(self.lua).forget();
+                ffi::lua_setfield(raw_lua, -2, c"__mode".as_ptr());
+                ffi::lua_setmetatable(raw_lua, -2);
+                ffi::lua_pushvalue(raw_lua, -1);
+                ffi::lua_setfield(raw_lua, ffi::LUA_REGISTRYINDEX, FINALIZERS_KEY.as_ptr());
+            }
+
+            value.push_no_err(self.lua).forget();
+            match ffi::lua_type(raw_lua, -1) {
+                ffi::LUA_TTABLE | ffi::LUA_TFUNCTION | ffi::LUA_TUSERDATA | ffi::LUA_TTHREAD => (),
+                _ => {
+                    ffi::lua_pop(raw_lua, 2);
+                    return Err(LuaError::WrongType);
+                },
+            }
+
+            // Load or create the list of callbacks of this value.
+            ffi::lua_pushvalue(raw_lua, -1);
+            ffi::lua_rawget(raw_lua, -3);
+            if ffi::lua_isnil(raw_lua, -1) {
+                ffi::lua_pop(raw_lua, 1);
+                ffi::lua_newtable(raw_lua);
+                ffi::lua_pushvalue(raw_lua, -2);
+                ffi::lua_pushvalue(raw_lua, -2);
+                ffi::lua_rawset(raw_lua, -5);
+            }
+
+            let position = ffix::lua_rawlen(self.lua, -1) + 1;
+            push_userdata(CollectCallback(Some(Box::new(callback))), self.lua, |_| {}).forget();
+            ffi::lua_rawseti(raw_lua, -2, position as _);
+            ffi::lua_pop(raw_lua, 3);
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes the GC observer from the registry, which stops the chain of sentinels.
+///
+/// Must be called before closing the context, otherwise the last sentinel could access the
+/// observer after it was destroyed.
+pub(crate) unsafe fn remove_observer(lua: LuaContext) {
+    ffi::lua_pushnil(lua.as_ptr());
+    ffi::lua_setfield(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, OBSERVER_KEY.as_ptr());
+}
+
+unsafe fn memory_used(lua: LuaContext) -> usize {
+    let kbytes = ffi::lua_gc(lua.as_ptr(), ffi::LUA_GCCOUNT, 0) as usize;
+    let bytes = ffi::lua_gc(lua.as_ptr(), ffi::LUA_GCCOUNTB, 0) as usize;
+    kbytes * 1024 + bytes
+}
+
+// Calls `f` with the observer of the context, if there is one.
+unsafe fn with_observer<R>(lua: LuaContext, f: impl FnOnce(&mut GcObserver) -> R) -> Option<R> {
+    ffi::lua_getfield(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, OBSERVER_KEY.as_ptr());
+    let result = userdata_mut::<GcObserver>(lua, -1).map(f);
+    ffi::lua_pop(lua.as_ptr(), 1);
+    result
+}
+
+// Returns false if there is no observer.
+unsafe fn set_explicit(lua: LuaContext, explicit: bool) -> bool {
+    with_observer(lua, |observer| observer.explicit = explicit).is_some()
+}
+
+// Creates an unreferenced object whose finalizer reports the end of the current cycle.
+unsafe fn push_sentinel(lua: LuaContext) {
+    let raw_lua = lua.as_ptr();
+    ffi::lua_newuserdata(raw_lua, 1);
+    ffi::lua_newtable(raw_lua);
+    ffi::lua_pushcfunction(raw_lua, Some(sentinel_gc));
+    ffi::lua_setfield(raw_lua, -2, c"__gc".as_ptr());
+    ffi::lua_setmetatable(raw_lua, -2);
+    ffi::lua_pop(raw_lua, 1);
+}
+
+extern "C" fn sentinel_gc(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let lua = LuaContext::new_unchecked(lua);
+        let stats =
+            GcCycleStats { memory_before: None, memory_after: memory_used(lua), pause: None };
+
+        let found = with_observer(lua, |observer| {
+            if !observer.explicit {
+                (observer.callback)(&stats);
+            }
+        });
+
+        // Only keep the chain going while there is someone to notify.
+        if found.is_some() {
+            push_sentinel(lua);
+        }
+
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{Lua, LuaError, LuaRef};
+
+    #[test]
+    fn memory_used() {
+        let mut lua = Lua::new();
+        let before = lua.memory_used();
+        lua.execute::<()>("t = {} for i = 1, 1000 do t[i] = i end").unwrap();
+        assert!(lua.memory_used() > before);
+    }
+
+    #[test]
+    fn collect_garbage_stats() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("local t = {} for i = 1, 1000 do t[i] = {} end").unwrap();
+
+        let stats = lua.collect_garbage();
+        assert!(stats.pause.is_some());
+        assert!(stats.freed_bytes().unwrap() > 0);
+        assert_eq!(stats.memory_after, lua.memory_used());
+    }
+
+    #[test]
+    fn gc_step_for() {
+        let mut lua = Lua::new();
+        assert_eq!(lua.gc_step_for(Duration::ZERO).steps, 0);
+
+        lua.execute::<()>("for i = 1, 10000 do local t = { i } end").unwrap();
+        let report = lua.gc_step_for(Duration::from_secs(5));
+        assert!(report.steps >= 1);
+        assert!(report.cycle_completed);
+    }
+
+    #[test]
+    fn callback_explicit_and_automatic() {
+        let mut lua = Lua::new();
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        lua.set_gc_callback(move |stats| sink.lock().unwrap().push(*stats));
+
+        lua.collect_garbage();
+        {
+            let reports = reports.lock().unwrap();
+            assert_eq!(reports.len(), 1);
+            assert!(reports[0].pause.is_some());
+        }
+
+        // Let the collector run on its own.
+        lua.execute::<()>("for i = 1, 100000 do local t = { i } end").unwrap();
+        assert!(reports.lock().unwrap().iter().any(|s| s.pause.is_none()));
+
+        lua.remove_gc_callback();
+        let count = reports.lock().unwrap().len();
+        lua.collect_garbage();
+        lua.collect_garbage();
+        assert_eq!(reports.lock().unwrap().len(), count);
+    }
+
+    #[test]
+    fn on_collect() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = {}; b = function() end").unwrap();
+
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        for name in ["a", "b"] {
+            let value: LuaRef = lua.get(name).unwrap();
+            for n in 0..2 {
+                let collected = collected.clone();
+                lua.on_collect(&value, move || collected.lock().unwrap().push((name, n))).unwrap();
+            }
+        }
+
+        lua.collect_garbage();
+        assert!(collected.lock().unwrap().is_empty());
+
+        lua.execute::<()>("a = nil").unwrap();
+        lua.collect_garbage();
+        lua.collect_garbage();
+        let mut result = collected.lock().unwrap().clone();
+        result.sort();
+        assert_eq!(result, vec![("a", 0), ("a", 1)]);
+
+        drop(lua);
+        assert_eq!(collected.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn on_collect_wrong_type() {
+        let mut lua = Lua::new();
+        let value = LuaRef::new(&mut lua, 12);
+        assert!(matches!(lua.on_collect(&value, || ()), Err(LuaError::WrongType)));
+    }
+
+    #[test]
+    fn close_with_callback() {
+        let mut lua = Lua::new();
+        lua.set_gc_callback(|_| ());
+        lua.collect_garbage();
+        drop(lua);
+    }
+}
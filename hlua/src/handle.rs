@@ -0,0 +1,536 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    ffi::CStr,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, ThreadId},
+};
+
+use crate::lua_functions::GuardedCalls;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{Lua, LuaContext, LuaStateId};
+
+// Key of the registry entry holding the `StateInfo` of a context.
+const STATE_INFO_KEY: &CStr = c"hlua.state_info";
+
+// Operation queued with `defer`.
+type Deferred = Box<dyn FnOnce(&mut Lua<'static>) + Send>;
+
+// Information shared between a Lua context and its handles.
+struct StateInfo {
+    lua: LuaContext,
+    id: LuaStateId,
+    // Thread the context was created on. `Lua` isn't `Send`, so it can only be used from there.
+    thread: ThreadId,
+    // Number of calls into Lua currently running, plus the number of live `LuaGuard`s.
+    depth: AtomicUsize,
+    closed: AtomicBool,
+    // Set if the `Lua` was dropped while a `LuaGuard` was alive. The last guard closes it.
+    close_on_release: AtomicBool,
+    // Operations to run once the context stops being busy.
+    deferred: Mutex<VecDeque<Deferred>>,
+    _guarded: GuardedCalls,
+}
+
+// `lua` is only ever accessed from `thread`.
+unsafe impl Send for StateInfo {}
+unsafe impl Sync for StateInfo {}
+
+/// Handle to a Lua context that can be stored anywhere and used to access the context later.
+///
+/// # Re-entrancy
+///
+/// The Lua context is considered *busy* while Lua code is running (for example while
+/// `execute` is running, which includes the time spent in Rust callbacks called by the code),
+/// while values read from it such as `LuaTable`s are still on its stack, and while a `LuaGuard`
+/// is alive. A `LuaHandle` never gives access to a busy context, which makes it possible to use
+/// it from code that doesn't know whether it is called from inside a callback, such as
+/// destructors.
+///
+/// The context itself is always kept by the `Lua` object. Once it is dropped, `with` and
+/// `try_lock` return `LockError::Closed`.
+///
+/// # Example
+///
+/// ```
+/// let mut lua = hlua::Lua::new();
+/// let handle = lua.handle();
+/// handle.with(|lua| lua.set("a", 5)).unwrap();
+///
+/// // Inside of a callback, the context is busy.
+/// let inner = handle.clone();
+/// lua.set("is_busy", hlua::function0(move || inner.with(|_| ()).is_err()));
+/// let busy: bool = lua.execute("return is_busy()").unwrap();
+/// assert!(busy);
+/// ```
+#[derive(Clone)]
+pub struct LuaHandle {
+    info: Arc<StateInfo>,
+}
+
+impl fmt::Debug for LuaHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LuaHandle").field("lua", &self.info.lua).finish()
+    }
+}
+
+impl LuaHandle {
+    /// Returns true if the context is running Lua code or is locked. Unlike `with`, this doesn't
+    /// look at the values on the stack, since it can be called from any thread.
+    #[inline]
+    pub fn is_busy(&self) -> bool {
+        self.info.depth.load(Ordering::SeqCst) != 0
+    }
+
+    /// Returns the identifier of the context. See `LuaStateId`.
+    #[inline]
+    pub fn state_id(&self) -> LuaStateId {
+        self.info.id
+    }
+
+    /// Returns true if the `Lua` this handle was created from has been dropped.
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.info.closed.load(Ordering::SeqCst)
+    }
+
+    /// Calls `f` with the context, unless it is busy or closed or if called from a thread other
+    /// than the one owning the context.
+    ///
+    /// The values that `f` leaves on the stack are removed once it returns. `f` can't borrow
+    /// anything, which guarantees that it doesn't use values read from the context by its owner.
+    pub fn with<F, R>(&self, f: F) -> Result<R, LockError>
+    where
+        F: FnOnce(&mut Lua<'static>) -> R + 'static,
+    {
+        let mut guard = unsafe { self.try_lock()? };
+        Ok(f(&mut guard))
+    }
+
+    /// Gives access to the context, unless it is busy or closed or if called from a thread
+    /// other than the one owning the context.
+    ///
+    /// The context stays busy for as long as the returned `LuaGuard` is alive, and the values
+    /// left on its stack are removed when the guard is dropped.
+    ///
+    /// # Safety
+    ///
+    /// The `Lua` that owns the context isn't aware of the guard. While the guard is alive, the
+    /// caller must not use that `Lua`, nor any value read from the context that isn't owned by
+    /// the guard, such as a `LuaTable` or a `LuaFunction`. They share the stack with the guard,
+    /// and using them would make Lua read the wrong values. Prefer `with`, which enforces this.
+    pub unsafe fn try_lock(&self) -> Result<LuaGuard, LockError> {
+        if self.info.thread != thread::current().id() {
+            return Err(LockError::WrongThread);
+        }
+        if self.is_closed() {
+            return Err(LockError::Closed);
+        }
+        // Values read by the owner are still on the stack, and would be confused with the ones
+        // pushed through the guard.
+        if ffi::lua_gettop(self.info.lua.as_ptr()) != 0 {
+            return Err(LockError::Busy);
+        }
+        if self.info.depth.compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(LockError::Busy);
+        }
+
+        let lua = Lua::from_existing_state(self.info.lua.as_ptr(), false);
+        Ok(LuaGuard { lua, info: self.info.clone(), marker: PhantomData })
+    }
+
+    /// Schedules an operation to run on the context once it stops being busy.
+    ///
+    /// This gives callbacks a way to modify the context, for example to set global variables
+    /// or run the garbage collector, once the Lua code that called them has returned. If the
+    /// context isn't busy, the operation runs immediately. Operations queued from another thread
+    /// run the next time a call into Lua finishes, or when `Lua::run_deferred` is called.
+    /// Operations still queued when the context is closed are dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// let handle = lua.handle();
+    ///
+    /// lua.set("spawn", hlua::function1(move |name: String| {
+    ///     handle.defer(move |lua| lua.set(&name[..], true));
+    /// }));
+    ///
+    /// lua.execute::<()>("spawn('enemy')").unwrap();
+    /// let spawned: bool = lua.get("enemy").unwrap();
+    /// assert!(spawned);
+    /// ```
+    pub fn defer<F>(&self, operation: F)
+    where
+        F: FnOnce(&mut Lua<'static>) + Send + 'static,
+    {
+        if let Ok(mut deferred) = self.info.deferred.lock() {
+            deferred.push_back(Box::new(operation));
+        }
+
+        if !self.is_busy() {
+            run_deferred(&self.info);
+        }
+    }
+}
+
+/// Access to a Lua context obtained through a `LuaHandle`.
+///
+/// Dereferences to a `Lua`.
+#[derive(Debug)]
+pub struct LuaGuard {
+    lua: Lua<'static>,
+    info: Arc<StateInfo>,
+    // Not `Send`, just like `Lua`.
+    marker: PhantomData<*mut ()>,
+}
+
+impl fmt::Debug for StateInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StateInfo").field("lua", &self.lua).finish()
+    }
+}
+
+impl Deref for LuaGuard {
+    type Target = Lua<'static>;
+
+    #[inline]
+    fn deref(&self) -> &Lua<'static> {
+        &self.lua
+    }
+}
+
+impl DerefMut for LuaGuard {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Lua<'static> {
+        &mut self.lua
+    }
+}
+
+impl Drop for LuaGuard {
+    #[inline]
+    fn drop(&mut self) {
+        // The stack was empty when the guard was created.
+        unsafe { ffi::lua_settop(self.info.lua.as_ptr(), 0) };
+        leave(&self.info);
+    }
+}
+
+/// Error returned by `LuaHandle::with` and `LuaHandle::try_lock`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LockError {
+    /// The context is running Lua code, values read from it are still on its stack, or it is
+    /// already locked.
+    Busy,
+    /// The `Lua` has been dropped.
+    Closed,
+    /// The handle was used from a thread other than the one owning the context.
+    WrongThread,
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockError::Busy => write!(f, "The Lua context is busy"),
+            LockError::Closed => write!(f, "The Lua context has been closed"),
+            LockError::WrongThread => write!(f, "The Lua context belongs to another thread"),
+        }
+    }
+}
+
+impl Error for LockError {}
+
+impl<'lua> Lua<'lua> {
+    /// Returns a handle to this context. See `LuaHandle`.
+    pub fn handle(&mut self) -> LuaHandle {
+        unsafe {
+            if let Some(info) = state_info(self.lua) {
+                return LuaHandle { info };
+            }
+
+            let info = Arc::new(StateInfo {
+                lua: self.lua,
+                id: crate::state_id::state_id(self.lua),
+                thread: thread::current().id(),
+                depth: AtomicUsize::new(0),
+                closed: AtomicBool::new(false),
+                close_on_release: AtomicBool::new(false),
+                deferred: Mutex::new(VecDeque::new()),
+                _guarded: GuardedCalls::new(),
+            });
+
+            push_userdata(info.clone(), self.lua, |_| {}).forget();
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, STATE_INFO_KEY.as_ptr());
+            LuaHandle { info }
+        }
+    }
+
+    /// Schedules an operation to run once the context stops being busy.
+    ///
+    /// See `LuaHandle::defer`.
+    #[inline]
+    pub fn defer<F>(&mut self, operation: F)
+    where
+        F: FnOnce(&mut Lua<'static>) + Send + 'static,
+    {
+        self.handle().defer(operation)
+    }
+
+    /// Runs the operations queued with `defer`, if the context isn't busy.
+    pub fn run_deferred(&mut self) {
+        if let Some(info) = unsafe { state_info(self.lua) } {
+            if info.depth.load(Ordering::SeqCst) == 0 {
+                run_deferred(&info);
+            }
+        }
+    }
+}
+
+unsafe fn state_info(lua: LuaContext) -> Option<Arc<StateInfo>> {
+    ffi::lua_getfield(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, STATE_INFO_KEY.as_ptr());
+    let info = userdata_mut::<Arc<StateInfo>>(lua, -1).map(|info| info.clone());
+    ffi::lua_pop(lua.as_ptr(), 1);
+    info
+}
+
+fn leave(info: &StateInfo) {
+    if info.depth.fetch_sub(1, Ordering::SeqCst) != 1 {
+        return;
+    }
+
+    if info.close_on_release.swap(false, Ordering::SeqCst) {
+        unsafe { crate::close_state(info.lua) };
+    } else {
+        run_deferred(info);
+    }
+}
+
+// Runs the deferred operations. Must only be called when the context isn't busy.
+fn run_deferred(info: &StateInfo) {
+    if info.thread != thread::current().id() || info.closed.load(Ordering::SeqCst) {
+        return;
+    }
+
+    loop {
+        let operation = match info.deferred.lock().ok().and_then(|mut d| d.pop_front()) {
+            Some(op) => op,
+            None => return,
+        };
+
+        // The context is busy while the operation runs, so that operations deferred by the
+        // operation itself run after it.
+        info.depth.fetch_add(1, Ordering::SeqCst);
+        let top = unsafe { ffi::lua_gettop(info.lua.as_ptr()) };
+        let mut lua = unsafe { Lua::from_existing_state(info.lua.as_ptr(), false) };
+        operation(&mut lua);
+        // The operation may run while the owner has values on the stack, which must stay at the
+        // same positions.
+        unsafe { ffi::lua_settop(info.lua.as_ptr(), top) };
+        info.depth.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Marks the context as busy for as long as it is alive.
+///
+/// Used around every call into Lua.
+pub(crate) struct BusyGuard(Option<Arc<StateInfo>>);
+
+impl BusyGuard {
+    #[inline]
+    pub(crate) unsafe fn enter(lua: LuaContext) -> BusyGuard {
+        if !GuardedCalls::any() {
+            return BusyGuard(None);
+        }
+        let info = state_info(lua);
+        if let Some(info) = &info {
+            info.depth.fetch_add(1, Ordering::SeqCst);
+        }
+        BusyGuard(info)
+    }
+}
+
+impl Drop for BusyGuard {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(info) = &self.0 {
+            leave(info);
+        }
+    }
+}
+
+/// Called when the `Lua` is dropped. Returns false if the context must not be closed yet
+/// because a `LuaGuard` is still alive, in which case the last guard will close it.
+pub(crate) unsafe fn on_close(lua: LuaContext) -> bool {
+    match state_info(lua) {
+        None => true,
+        Some(info) => {
+            info.closed.store(true, Ordering::SeqCst);
+            if info.depth.load(Ordering::SeqCst) == 0 {
+                return true;
+            }
+            info.close_on_release.store(true, Ordering::SeqCst);
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{function0, LockError, Lua, LuaFunction, LuaTable};
+
+    #[test]
+    fn lock_when_idle() {
+        let mut lua = Lua::new();
+        let handle = lua.handle();
+
+        assert!(!handle.is_busy());
+        handle.with(|lua| lua.set("a", 12)).unwrap();
+        let a: i32 = lua.get("a").unwrap();
+        assert_eq!(a, 12);
+    }
+
+    #[test]
+    fn busy_inside_callback() {
+        let mut lua = Lua::new();
+        let handle = lua.handle();
+
+        let result = Arc::new(Mutex::new(None));
+        let (inner, sink) = (handle.clone(), result.clone());
+        lua.set("f", function0(move || *sink.lock().unwrap() = inner.with(|_| ()).err()));
+        lua.execute::<()>("f()").unwrap();
+
+        assert_eq!(*result.lock().unwrap(), Some(LockError::Busy));
+        assert!(!handle.is_busy());
+    }
+
+    #[test]
+    fn nested_lock_is_busy() {
+        let mut lua = Lua::new();
+        let handle = lua.handle();
+
+        let _guard = unsafe { handle.try_lock() }.unwrap();
+        assert_eq!(handle.with(|_| ()).err(), Some(LockError::Busy));
+    }
+
+    #[test]
+    fn busy_while_values_are_on_the_stack() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("t = { a = 1 } function f() end").unwrap();
+        let handle = lua.handle();
+
+        {
+            let mut t: LuaTable<_> = lua.get("t").unwrap();
+            assert_eq!(handle.with(|_| ()).err(), Some(LockError::Busy));
+            assert_eq!(unsafe { handle.try_lock() }.err(), Some(LockError::Busy));
+            assert_eq!(t.get::<i32, _, _>("a"), Some(1));
+        }
+
+        // The values left on the stack by the closure don't shift the ones of the owner.
+        let leaked = handle.with(|lua| {
+            let f: LuaFunction<_> = lua.get("f").unwrap();
+            std::mem::forget(f);
+        });
+        assert!(leaked.is_ok());
+        let mut t: LuaTable<_> = lua.get("t").unwrap();
+        assert_eq!(t.get::<i32, _, _>("a"), Some(1));
+    }
+
+    #[test]
+    fn closed() {
+        let mut lua = Lua::new();
+        let handle = lua.handle();
+        drop(lua);
+
+        assert!(handle.is_closed());
+        assert_eq!(handle.with(|_| ()).err(), Some(LockError::Closed));
+    }
+
+    #[test]
+    fn dropped_while_locked() {
+        let mut lua = Lua::new();
+        let handle = lua.handle();
+
+        let mut guard = unsafe { handle.try_lock() }.unwrap();
+        drop(lua);
+        guard.set("a", 1);
+        let a: i32 = guard.get("a").unwrap();
+        assert_eq!(a, 1);
+        drop(guard);
+
+        assert_eq!(handle.with(|_| ()).err(), Some(LockError::Closed));
+    }
+
+    #[test]
+    fn defer_from_callback() {
+        let mut lua = Lua::new();
+        let handle = lua.handle();
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (inner, log) = (handle.clone(), order.clone());
+        lua.set(
+            "f",
+            function0(move || {
+                let log2 = log.clone();
+                inner.defer(move |lua| {
+                    log2.lock().unwrap().push("deferred");
+                    lua.set("x", 3);
+                });
+                log.lock().unwrap().push("callback");
+            }),
+        );
+
+        let x: Option<i32> = lua.execute("f() return x").unwrap();
+        assert_eq!(x, None);
+        assert_eq!(*order.lock().unwrap(), vec!["callback", "deferred"]);
+        let x: i32 = lua.get("x").unwrap();
+        assert_eq!(x, 3);
+    }
+
+    #[test]
+    fn defer_while_locked_and_nested() {
+        let mut lua = Lua::new();
+        let handle = lua.handle();
+
+        {
+            let mut guard = unsafe { handle.try_lock() }.unwrap();
+            guard.defer(|lua| {
+                lua.set("a", 1);
+                lua.defer(|lua| lua.set("b", 2));
+                lua.execute::<()>("c = 3").unwrap();
+            });
+            assert_eq!(guard.get::<i32, _>("a"), None);
+        }
+
+        let values: (i32, i32, i32) = lua.execute_multi("return a, b, c").unwrap();
+        assert_eq!(values, (1, 2, 3));
+    }
+
+    #[test]
+    fn defer_from_other_thread() {
+        let mut lua = Lua::new();
+        let handle = lua.handle();
+
+        std::thread::spawn(move || handle.defer(|lua| lua.set("a", 5))).join().unwrap();
+        assert_eq!(lua.get::<i32, _>("a"), None);
+        lua.run_deferred();
+        assert_eq!(lua.get::<i32, _>("a"), Some(5));
+    }
+
+    #[test]
+    fn wrong_thread() {
+        let mut lua = Lua::new();
+        let handle = lua.handle();
+
+        let result = std::thread::spawn(move || handle.with(|_| ()).err()).join().unwrap();
+        assert_eq!(result, Some(LockError::WrongThread));
+    }
+}
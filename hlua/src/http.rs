@@ -0,0 +1,642 @@
+use std::{ffi::CStr, fmt, str, time::Duration};
+
+use crate::patterns::string_arg;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsMutLua, Lua, LuaContext, Push};
+
+/// Restrictions applied to the requests made by scripts through the `http` library.
+///
+/// A new policy doesn't allow any host. The limits are enforced before giving the request to
+/// the `HttpTransport`, and again on the response it returns.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// let policy = hlua::HttpPolicy::new()
+///     .allow_host("api.example.com")
+///     .allow_host("*.cdn.example.com")
+///     .timeout(Duration::from_secs(2))
+///     .max_response_size(64 * 1024);
+/// assert!(policy.is_host_allowed("img.cdn.example.com"));
+/// assert!(!policy.is_host_allowed("example.com"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpPolicy {
+    allowed_hosts: Vec<String>,
+    timeout: Duration,
+    max_request_size: usize,
+    max_response_size: usize,
+}
+
+impl Default for HttpPolicy {
+    #[inline]
+    fn default() -> HttpPolicy {
+        HttpPolicy {
+            allowed_hosts: Vec::new(),
+            timeout: Duration::from_secs(10),
+            max_request_size: 1024 * 1024,
+            max_response_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl HttpPolicy {
+    /// Builds a policy that doesn't allow any host, with a timeout of 10 seconds, and bodies
+    /// limited to 1 MiB for requests and 8 MiB for responses.
+    #[inline]
+    pub fn new() -> HttpPolicy {
+        HttpPolicy::default()
+    }
+
+    /// Allows requests to `host`. A host starting with `*.` allows all of its subdomains, but
+    /// not the domain itself.
+    #[inline]
+    pub fn allow_host(mut self, host: &str) -> HttpPolicy {
+        self.allowed_hosts.push(host.to_ascii_lowercase());
+        self
+    }
+
+    /// Sets the maximum duration of a request, from the connection to the end of the response.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> HttpPolicy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum size, in bytes, of the body of a request.
+    #[inline]
+    pub fn max_request_size(mut self, size: usize) -> HttpPolicy {
+        self.max_request_size = size;
+        self
+    }
+
+    /// Sets the maximum size, in bytes, of the body of a response.
+    #[inline]
+    pub fn max_response_size(mut self, size: usize) -> HttpPolicy {
+        self.max_response_size = size;
+        self
+    }
+
+    /// Returns true if requests to `host` are allowed.
+    pub fn is_host_allowed(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts.iter().any(|allowed| match allowed.strip_prefix("*.") {
+            Some(domain) => {
+                host.len() > domain.len() + 1
+                    && host.ends_with(domain)
+                    && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+            },
+            None => *allowed == host,
+        })
+    }
+}
+
+/// Request made by a script, checked against the `HttpPolicy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    /// Method of the request, in uppercase, such as `GET`.
+    pub method: String,
+    /// Either `http` or `https`.
+    pub scheme: String,
+    /// Host of the URL, in lowercase.
+    pub host: String,
+    /// Port of the URL, or the default port of the scheme.
+    pub port: u16,
+    /// Path and query of the URL, starting with `/`.
+    pub path: String,
+    /// Headers given by the script.
+    pub headers: Vec<(String, String)>,
+    /// Body of the request.
+    pub body: Vec<u8>,
+    /// Time after which the request must be abandoned.
+    pub timeout: Duration,
+    /// Size after which the body of the response must not be read any further.
+    pub max_response_size: usize,
+}
+
+/// Response returned to a script.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpResponse {
+    /// Status code.
+    pub status: u16,
+    /// Headers of the response.
+    pub headers: Vec<(String, String)>,
+    /// Body of the response.
+    pub body: Vec<u8>,
+}
+
+/// Sends the requests of the `http` library.
+///
+/// `UreqTransport` is used by default. Implement this trait to send requests differently, for
+/// example with another HTTP client or through a test double. Requests have already been
+/// checked against the policy when `send` is called, and the transport must respect their
+/// `timeout` and `max_response_size`, and must not follow redirections.
+pub trait HttpTransport: Send + 'static {
+    /// Sends the request and returns the response, or a message describing the error.
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, String>;
+}
+
+/// Transport sending the requests over `http` and `https` with `ureq`, used by default.
+///
+/// Redirections aren't followed, since their target wouldn't be checked against the policy, and
+/// are returned to the script like other responses. The timeout includes resolving the host.
+#[derive(Debug, Clone)]
+pub struct UreqTransport {
+    agent: ureq::Agent,
+}
+
+impl UreqTransport {
+    /// Builds a transport with its own pool of connections.
+    pub fn new() -> UreqTransport {
+        let config =
+            ureq::Agent::config_builder().http_status_as_error(false).max_redirects(0).build();
+        UreqTransport { agent: config.into() }
+    }
+}
+
+impl Default for UreqTransport {
+    #[inline]
+    fn default() -> UreqTransport {
+        UreqTransport::new()
+    }
+}
+
+// Stored as an upvalue of the functions of the library.
+struct HttpClient {
+    policy: HttpPolicy,
+    transport: Box<dyn HttpTransport>,
+}
+
+impl fmt::Debug for HttpClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HttpClient").field("policy", &self.policy).finish_non_exhaustive()
+    }
+}
+
+type Method = (&'static CStr, ffi::lua_CFunction);
+
+impl<'lua> Lua<'lua> {
+    /// Opens an `http` library that lets scripts make HTTP requests, within the limits of
+    /// `policy`.
+    ///
+    /// - `http.request(options)` sends a request. `options` is a table with the `url` of the
+    ///   request and optionally its `method` (`GET` by default), its `headers`, as a table
+    ///   associating names to values, and its `body`.
+    /// - `http.get(url [, headers])` sends a `GET` request.
+    ///
+    /// Both return a table containing the `status` code, the `headers` of the response, with
+    /// lowercase names, and its `body`. If the request isn't allowed by the policy or fails,
+    /// they return nil and a message instead.
+    ///
+    /// Requests are sent with a `UreqTransport`. While a request is running, the context is
+    /// blocked.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::HttpPolicy;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// lua.open_http(HttpPolicy::new().allow_host("localhost"));
+    ///
+    /// let code = "local response, err = http.get('http://example.com/') return err";
+    /// let err: String = lua.execute(code).unwrap();
+    /// assert_eq!(err, "host example.com is not allowed");
+    /// ```
+    #[inline]
+    pub fn open_http(&mut self, policy: HttpPolicy) {
+        self.open_http_with_transport(policy, UreqTransport::new())
+    }
+
+    /// Opens the `http` library like `open_http`, but sends the requests with `transport`.
+    pub fn open_http_with_transport<T>(&mut self, policy: HttpPolicy, transport: T)
+    where
+        T: HttpTransport,
+    {
+        let functions: [Method; 2] = [(c"request", Some(request)), (c"get", Some(get))];
+        let client = HttpClient { policy, transport: Box::new(transport) };
+
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_createtable(l, 0, functions.len() as _);
+            push_userdata(client, raw_lua, |_| {}).forget();
+            for (name, function) in functions {
+                ffi::lua_pushvalue(l, -1);
+                ffi::lua_pushcclosure(l, function, 1);
+                ffi::lua_setfield(l, -3, name.as_ptr());
+            }
+            ffi::lua_pop(l, 1);
+
+            ffi::lua_getfield(l, -2, c"package".as_ptr());
+            if ffi::lua_istable(l, -1) {
+                ffi::lua_getfield(l, -1, c"loaded".as_ptr());
+                if ffi::lua_istable(l, -1) {
+                    ffi::lua_pushvalue(l, -3);
+                    ffi::lua_setfield(l, -2, c"http".as_ptr());
+                }
+                ffi::lua_pop(l, 1);
+            }
+            ffi::lua_pop(l, 1);
+
+            ffi::lua_setfield(l, -2, c"http".as_ptr());
+            ffi::lua_pop(l, 1);
+        }
+    }
+}
+
+impl HttpTransport for UreqTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, String> {
+        let url = format!("{}://{}:{}{}", request.scheme, request.host, request.port, request.path);
+        let mut builder = ureq::http::Request::builder().method(request.method.as_str()).uri(url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        let built = builder
+            .body(request.body.as_slice())
+            .map_err(|err| format!("invalid request to {}: {}", request.host, err))?;
+        let built =
+            self.agent.configure_request(built).timeout_global(Some(request.timeout)).build();
+
+        let error = |err: ureq::Error| match err {
+            ureq::Error::Timeout(_) => format!("request to {} timed out", request.host),
+            ureq::Error::BodyExceedsLimit(_) => {
+                format!("response from {} is too large", request.host)
+            },
+            err => format!("request to {} failed: {}", request.host, err),
+        };
+        let mut response = self.agent.run(built).map_err(error)?;
+
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect();
+        let body = response
+            .body_mut()
+            .with_config()
+            .limit(request.max_response_size as u64)
+            .read_to_vec()
+            .map_err(error)?;
+
+        Ok(HttpResponse { status: response.status().as_u16(), headers, body })
+    }
+}
+
+// Splits a URL into its scheme, host, port and path.
+fn parse_url(url: &str) -> Result<(String, String, u16, String), String> {
+    let (scheme, rest) = url.split_once("://").ok_or_else(|| format!("invalid URL {}", url))?;
+    let scheme = scheme.to_ascii_lowercase();
+    let default_port = match scheme.as_str() {
+        "http" => 80,
+        "https" => 443,
+        _ => return Err(format!("unsupported scheme {}", scheme)),
+    };
+
+    let (authority, path) = match rest.find(['/', '?', '#']) {
+        Some(position) => rest.split_at(position),
+        None => (rest, "/"),
+    };
+    let path = path.split('#').next().unwrap_or_default();
+    let path = match path.starts_with('/') {
+        true => path.to_owned(),
+        false => format!("/{}", path),
+    };
+    // The path is written as is in the request line, where spaces and line breaks would make it
+    // possible to inject headers or requests.
+    if path.contains(|c: char| c.is_control() || c.is_whitespace()) {
+        return Err(format!("invalid path in URL {:?}", url));
+    }
+
+    // Credentials in URLs are refused, since they make it easy to disguise the host.
+    if authority.contains('@') {
+        return Err(format!("invalid URL {}", url));
+    }
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => {
+            (host, port.parse().map_err(|_| format!("invalid port in URL {}", url))?)
+        },
+        None => (authority, default_port),
+    };
+    let valid_host = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '-';
+    if host.is_empty() || !host.chars().all(valid_host) {
+        return Err(format!("invalid host in URL {}", url));
+    }
+
+    Ok((scheme, host.to_ascii_lowercase(), port, path))
+}
+
+// Returns the number of results of a function, or raises the error.
+unsafe fn finish(lua: *mut ffi::lua_State, result: Result<libc::c_int, String>) -> libc::c_int {
+    match result {
+        Ok(count) => count,
+        Err(msg) => {
+            msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+            ffix::lua_error(lua);
+        },
+    }
+}
+
+unsafe fn client<'a>(lua: *mut ffi::lua_State) -> &'a HttpClient {
+    let raw_lua = LuaContext::new_unchecked(lua);
+    userdata_mut::<HttpClient>(raw_lua, ffi::lua_upvalueindex(1))
+        .expect("the upvalue of the http functions is an HttpClient")
+}
+
+unsafe fn text_arg(
+    lua: *mut ffi::lua_State,
+    index: libc::c_int,
+    what: &str,
+) -> Result<String, String> {
+    match string_arg(lua, index).map(str::from_utf8) {
+        Some(Ok(text)) => Ok(text.to_owned()),
+        _ => Err(format!("{} must be a string", what)),
+    }
+}
+
+// Reads the table of headers at `index`, if it isn't nil.
+unsafe fn headers_arg(
+    lua: *mut ffi::lua_State,
+    index: libc::c_int,
+) -> Result<Vec<(String, String)>, String> {
+    let mut headers = Vec::new();
+    if ffi::lua_isnoneornil(lua, index) {
+        return Ok(headers);
+    }
+    if !ffi::lua_istable(lua, index) {
+        return Err("headers must be a table".to_owned());
+    }
+
+    ffi::lua_pushnil(lua);
+    while ffi::lua_next(lua, index) != 0 {
+        // Converting a key that isn't a string in place would break `lua_next`.
+        let name = match ffi::lua_type(lua, -2) == ffi::LUA_TSTRING {
+            true => text_arg(lua, -2, "header names"),
+            false => Err("header names must be strings".to_owned()),
+        };
+        let header =
+            name.and_then(|name| text_arg(lua, -1, "header values").map(|value| (name, value)));
+        ffi::lua_pop(lua, 1);
+        match header {
+            // Line breaks would make it possible to inject headers or requests.
+            Ok((name, value))
+                if !name.contains(['\r', '\n', ':']) && !value.contains(['\r', '\n']) =>
+            {
+                headers.push((name, value));
+            },
+            Ok((name, _)) => {
+                ffi::lua_pop(lua, 1);
+                return Err(format!("invalid header {:?}", name));
+            },
+            Err(err) => {
+                ffi::lua_pop(lua, 1);
+                return Err(err);
+            },
+        }
+    }
+    Ok(headers)
+}
+
+// Checks the request against the policy, sends it and pushes the results.
+unsafe fn send(
+    lua: *mut ffi::lua_State,
+    method: String,
+    url: &str,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+) -> libc::c_int {
+    let client = client(lua);
+    let response = parse_url(url).and_then(|(scheme, host, port, path)| {
+        if !client.policy.is_host_allowed(&host) {
+            return Err(format!("host {} is not allowed", host));
+        }
+        if body.len() > client.policy.max_request_size {
+            return Err("request body is too large".to_owned());
+        }
+
+        let request = HttpRequest {
+            method,
+            scheme,
+            host,
+            port,
+            path,
+            headers,
+            body,
+            timeout: client.policy.timeout,
+            max_response_size: client.policy.max_response_size,
+        };
+        let response = client.transport.send(&request)?;
+        match response.body.len() > client.policy.max_response_size {
+            true => Err(format!("response from {} is too large", request.host)),
+            false => Ok(response),
+        }
+    });
+
+    match response {
+        Ok(response) => {
+            push_response(lua, &response);
+            1
+        },
+        Err(msg) => {
+            ffi::lua_pushnil(lua);
+            msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+            2
+        },
+    }
+}
+
+unsafe fn push_response(lua: *mut ffi::lua_State, response: &HttpResponse) {
+    ffi::lua_createtable(lua, 0, 3);
+    ffi::lua_pushinteger(lua, response.status as ffi::lua_Integer);
+    ffi::lua_setfield(lua, -2, c"status".as_ptr());
+
+    ffi::lua_createtable(lua, 0, response.headers.len() as _);
+    for (name, value) in &response.headers {
+        ffi::lua_pushlstring(lua, name.as_ptr().cast(), name.len());
+        ffi::lua_pushlstring(lua, value.as_ptr().cast(), value.len());
+        ffi::lua_rawset(lua, -3);
+    }
+    ffi::lua_setfield(lua, -2, c"headers".as_ptr());
+
+    ffi::lua_pushlstring(lua, response.body.as_ptr().cast(), response.body.len());
+    ffi::lua_setfield(lua, -2, c"body".as_ptr());
+}
+
+extern "C" fn request(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        if !ffi::lua_istable(lua, 1) {
+            return Err("bad argument #1 to 'request' (table expected)".to_owned());
+        }
+        ffi::lua_settop(lua, 1);
+        ffi::lua_getfield(lua, 1, c"url".as_ptr());
+        ffi::lua_getfield(lua, 1, c"method".as_ptr());
+        ffi::lua_getfield(lua, 1, c"headers".as_ptr());
+        ffi::lua_getfield(lua, 1, c"body".as_ptr());
+
+        let url = text_arg(lua, 2, "url")?;
+        let method = match ffi::lua_isnoneornil(lua, 3) {
+            true => "GET".to_owned(),
+            false => text_arg(lua, 3, "method")?.to_ascii_uppercase(),
+        };
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+            return Err(format!("invalid method {:?}", method));
+        }
+        let headers = headers_arg(lua, 4)?;
+        let body = match ffi::lua_isnoneornil(lua, 5) {
+            true => Vec::new(),
+            false => string_arg(lua, 5).ok_or("body must be a string")?.to_vec(),
+        };
+
+        Ok(send(lua, method, &url, headers, body))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn get(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let url = text_arg(lua, 1, "url")?;
+        let headers = headers_arg(lua, 2)?;
+        Ok(send(lua, "GET".to_owned(), &url, headers, Vec::new()))
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+        time::Duration,
+    };
+
+    use crate::{HttpPolicy, HttpRequest, HttpResponse, HttpTransport, Lua};
+
+    // Answers a single request with `response`, and returns what was received.
+    fn serve_once(response: &'static [u8]) -> (u16, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            while !received.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..read]);
+            }
+            stream.write_all(response).unwrap();
+            String::from_utf8(received).unwrap()
+        });
+        (port, server)
+    }
+
+    #[test]
+    fn ureq_transport() {
+        let (port, server) =
+            serve_once(b"HTTP/1.1 201 Created\r\nX-Id: 7\r\nContent-Length: 5\r\n\r\nhello");
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_http(HttpPolicy::new().allow_host("127.0.0.1"));
+
+        let code = format!(
+            "local headers = {{ Accept = 'text/plain' }}
+             local r = assert(http.get('http://127.0.0.1:{}/items?page=2', headers))
+             return r.status .. ' ' .. r.headers['x-id'] .. ' ' .. r.body",
+            port
+        );
+        assert_eq!(lua.execute::<String>(&code).unwrap(), "201 7 hello");
+        let received = server.join().unwrap();
+        assert!(received.starts_with("GET /items?page=2 HTTP/1.1\r\n"), "{}", received);
+        assert!(received.to_ascii_lowercase().contains("\r\naccept: text/plain\r\n"));
+
+        // Redirections are returned to the script, since their target isn't checked.
+        let (port, server) =
+            serve_once(b"HTTP/1.1 302 Found\r\nLocation: http://evil.com/\r\n\r\n");
+        let code = format!("return assert(http.get('http://127.0.0.1:{}/')).status", port);
+        assert_eq!(lua.execute::<i32>(&code).unwrap(), 302);
+        server.join().unwrap();
+
+        let (port, server) = serve_once(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n0123456789");
+        lua.open_http(HttpPolicy::new().allow_host("127.0.0.1").max_response_size(4));
+        let code = format!("return select(2, http.get('http://127.0.0.1:{}/'))", port);
+        assert_eq!(lua.execute::<String>(&code).unwrap(), "response from 127.0.0.1 is too large");
+        server.join().unwrap();
+
+        // Nothing listens on this port, but the scheme is supported.
+        let code = "return select(2, http.get('https://127.0.0.1:1/'))";
+        let err = lua.execute::<String>(code).unwrap();
+        assert!(err.starts_with("request to 127.0.0.1 failed"), "{}", err);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let policy = HttpPolicy::new().allow_host("127.0.0.1").timeout(Duration::from_millis(100));
+        lua.open_http(policy);
+        let port = listener.local_addr().unwrap().port();
+        let code = format!("return select(2, http.get('http://127.0.0.1:{}/'))", port);
+        assert_eq!(lua.execute::<String>(&code).unwrap(), "request to 127.0.0.1 timed out");
+    }
+
+    struct Echo;
+
+    impl HttpTransport for Echo {
+        fn send(&self, request: &HttpRequest) -> Result<HttpResponse, String> {
+            let body = format!(
+                "{} {}:{}{} {}",
+                request.method,
+                request.host,
+                request.port,
+                request.path,
+                String::from_utf8_lossy(&request.body)
+            );
+            Ok(HttpResponse { status: 200, headers: Vec::new(), body: body.into_bytes() })
+        }
+    }
+
+    #[test]
+    fn policy() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let policy = HttpPolicy::new().allow_host("*.example.com").max_request_size(8);
+        lua.open_http_with_transport(policy, Echo);
+
+        let run = |lua: &mut Lua, options: &str| -> String {
+            let code =
+                format!("local r, err = http.request({}) return r and r.body or err", options);
+            lua.execute(&code).unwrap()
+        };
+        assert_eq!(
+            run(
+                &mut lua,
+                "{ url = 'https://API.example.com/v1#top', method = 'post', body = 'x=1' }"
+            ),
+            "POST api.example.com:443/v1 x=1"
+        );
+        assert_eq!(
+            run(&mut lua, "{ url = 'http://example.com' }"),
+            "host example.com is not allowed"
+        );
+        assert_eq!(
+            run(&mut lua, "{ url = 'http://evil.com@a.example.com/' }"),
+            "invalid URL http://evil.com@a.example.com/"
+        );
+        assert_eq!(run(&mut lua, "{ url = 'ftp://a.example.com' }"), "unsupported scheme ftp");
+        assert_eq!(
+            run(&mut lua, "{ url = 'http://a.example.com', body = '123456789' }"),
+            "request body is too large"
+        );
+        assert!(lua
+            .execute::<()>("http.get('http://a.example.com', { ['X-A'] = 'b\\r\\nX-B: c' })")
+            .is_err());
+        let err = lua.execute::<()>("http.get('http://a.example.com', { [1] = 'x' })");
+        assert!(err.unwrap_err().to_string().contains("header names must be strings"));
+        assert_eq!(
+            run(&mut lua, "{ url = 'http://a.example.com/x HTTP/1.1\\r\\nHost: internal' }"),
+            "invalid path in URL \"http://a.example.com/x HTTP/1.1\\r\\nHost: internal\""
+        );
+        assert!(run(&mut lua, "{ url = 'http://a.example.com/a b' }").starts_with("invalid path"));
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    cell::Cell,
+    sync::{Arc, RwLock},
+};
+
+use crate::Lua;
+
+type InitHook = Arc<dyn for<'lua> Fn(&mut Lua<'lua>) + Send + Sync>;
+
+static GLOBAL_INIT: RwLock<Option<InitHook>> = RwLock::new(None);
+
+thread_local! {
+    // True while the hook runs on this thread, so that contexts it creates don't run it again.
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sets a function that is called on every context created with `Lua::new` afterwards, in the
+/// whole process.
+///
+/// This includes the contexts created by hlua itself, like the ones of `LuaActor` and `LuaPool`,
+/// so that large applications can install their panic handlers, print redirections and base
+/// modules in one place. The hook runs on the thread that creates the context, right after its
+/// creation. Contexts built with `Lua::from_existing_state` aren't affected, and neither are the
+/// ones that the hook itself creates.
+///
+/// Replaces the previous hook, if any.
+///
+/// # Example
+///
+/// ```
+/// hlua::set_global_init(|lua| {
+///     lua.openlibs();
+///     lua.set("APP_VERSION", "1.2.0");
+/// });
+///
+/// let mut lua = hlua::Lua::new();
+/// assert_eq!(lua.execute::<String>("return string.upper(APP_VERSION)").unwrap(), "1.2.0");
+/// # hlua::clear_global_init();
+/// ```
+pub fn set_global_init<F>(init: F)
+where
+    F: for<'lua> Fn(&mut Lua<'lua>) + Send + Sync + 'static,
+{
+    *GLOBAL_INIT.write().unwrap_or_else(|err| err.into_inner()) = Some(Arc::new(init));
+}
+
+/// Removes the function set with `set_global_init`. The contexts created afterwards are empty
+/// again.
+pub fn clear_global_init() {
+    *GLOBAL_INIT.write().unwrap_or_else(|err| err.into_inner()) = None;
+}
+
+/// Runs the hook set with `set_global_init` on a newly created context.
+pub(crate) fn run_global_init(lua: &mut Lua) {
+    if RUNNING.with(Cell::get) {
+        return;
+    }
+    // The hook is cloned out of the lock so that it can call `set_global_init` itself.
+    let hook = GLOBAL_INIT.read().unwrap_or_else(|err| err.into_inner()).clone();
+    let Some(hook) = hook else { return };
+
+    struct Running;
+    impl Drop for Running {
+        fn drop(&mut self) {
+            RUNNING.with(|running| running.set(false));
+        }
+    }
+
+    RUNNING.with(|running| running.set(true));
+    let _running = Running;
+    hook(lua);
+}
@@ -104,23 +104,53 @@
 //!   the return type of [`execute`](struct.Lua.html#method.execute).
 //! - TODO: userdata
 //!
+//! # Low-level access
+//!
+//! The raw bindings to the Lua C API are re-exported as the `ffi` module, and the raw state of a
+//! context can be obtained with `AsLua::as_lua`. This makes it possible to perform the
+//! occasional operation that hlua doesn't support without depending on a second, possibly
+//! mismatched version of the bindings.
+//!
+//! The content of `ffi` depends on the selected Lua version: items that only exist in some
+//! versions of Lua are only available when one of these versions is selected. The `ffix` module
+//! contains wrappers for common functions whose signature differs between versions.
+//!
+//! All of these functions are unsafe, and the stack must be left in the state it was found.
+//!
+//! ```
+//! let mut lua = hlua::Lua::new();
+//! lua.set("enabled", true);
+//!
+//! let raw = hlua::AsLua::as_lua(&lua).as_ptr();
+//! let value = unsafe {
+//!     hlua::ffix::lua_pushglobaltable(hlua::AsLua::as_lua(&lua));
+//!     hlua::ffi::lua_getfield(raw, -1, c"enabled".as_ptr());
+//!     let value = hlua::ffi::lua_toboolean(raw, -1);
+//!     hlua::ffi::lua_pop(raw, 2);
+//!     value
+//! };
+//! assert_eq!(value, 1);
+//! ```
+//!
 #![allow(clippy::missing_safety_doc)] // TODO: Document instead
 #![warn(clippy::ptr_as_ptr)]
 
-// Export the version of lua52_sys in use by this crate. This allows clients to perform low-level
-// Lua operations without worrying about semver.
 extern crate libc;
 
 #[cfg(not(any(feature = "lua52", feature = "lua54", feature = "luajit2")))]
 compile_error!("no lua version specified");
 
-#[doc(hidden)]
+// Export the version of the bindings in use by this crate. This allows clients to perform
+// low-level Lua operations without worrying about semver, or about depending on a different
+// version of the bindings than the one hlua is linked with.
+
+/// Raw bindings to the Lua 5.2 C API. See [the crate documentation](index.html#low-level-access).
 #[cfg(feature = "lua52")]
 pub extern crate lua52_sys as ffi;
-#[doc(hidden)]
+/// Raw bindings to the Lua 5.4 C API. See [the crate documentation](index.html#low-level-access).
 #[cfg(feature = "lua54")]
 pub extern crate lua54_sys as ffi;
-#[doc(hidden)]
+/// Raw bindings to the LuaJIT C API. See [the crate documentation](index.html#low-level-access).
 #[cfg(feature = "luajit2")]
 pub extern crate luajit2_sys as ffi;
 
@@ -132,31 +162,210 @@ use std::{
     fmt, io,
     io::{Error as IoError, Read},
     marker::PhantomData,
+    panic::Location,
     ptr::NonNull,
+    sync::Arc,
+};
+
+#[cfg(feature = "derive")]
+pub use hlua_derive::{LuaPush, LuaRead, LuaReadMulti};
+
+#[cfg(feature = "arrow")]
+pub use record_batch::{ColumnData, LuaRecordBatch, RecordBatchError};
+
+#[cfg(feature = "async")]
+pub use blocking::{BlockingExecute, SendLua, SendLuaGuard};
+#[cfg(feature = "async")]
+pub use coroutine::ResumeAsync;
+#[cfg(feature = "async")]
+pub use functions_write::{
+    function_async0, function_async1, function_async10, function_async2, function_async3,
+    function_async4, function_async5, function_async6, function_async7, function_async8,
+    function_async9, AsyncFunction,
 };
 
-pub use any::{AnyHashableLuaValue, AnyLuaString, AnyLuaValue};
+#[cfg(feature = "crash-report")]
+pub use crash_report::{CrashFrame, CrashReport, CrashReporter};
+
+#[cfg(feature = "fs")]
+pub use fs::FsRoots;
+
+#[cfg(feature = "http")]
+pub use http::{HttpPolicy, HttpRequest, HttpResponse, HttpTransport, UreqTransport};
+
+#[cfg(feature = "impl-bitflags")]
+pub use flags::{read_flags, FlagBits, FlagNames, FlagsError};
+
+#[cfg(feature = "proc")]
+pub use process::{ProcCommand, ProcPolicy};
+
+#[cfg(feature = "serde")]
+pub use serialize::{from_lua, to_lua, SerdeError};
+
+#[cfg(feature = "teal")]
+pub use teal::{TealDiagnostic, TealDiagnosticKind, TealError};
+
+pub use actor::{ActorError, ActorReply, LuaActor, LuaMessage};
+pub use any::{AnyHashableLuaValue, AnyLuaString, AnyLuaValue, AnyLuaValueConversionError};
+pub use app_data::{AppDataError, AppDataErrorKind};
+#[cfg(not(feature = "_luaapi_51"))]
+pub use arith::{ArithOp, CompareOp};
+pub use arrays::{LuaArrayD, PackedLuaArrayD};
+pub use broadcast::{Broadcast, SubscriptionId};
+pub use builder::{LibSet, LuaOptions};
+pub use bundle::{BundleError, ScriptBundle};
+pub use byte_buffer::ByteBuffer;
+pub use bytecode::{verify_bytecode, BytecodeError};
+pub use commands::{
+    CommandArg, CommandError, CommandHandler, CommandOutput, CommandRegistry, Completion,
+};
+pub use completion::{ApiSchema, CompletionCandidate, CompletionKind};
+pub use coroutine::{CoroutineResult, LuaCoroutine};
+pub use database::{DatabaseRow, QueryParams, RowTable, SqlValue};
+pub use env::EnvVars;
+pub use flight_recorder::{FlightEvent, FlightRecord, FlightRecorder};
+pub use format::FormatError;
+pub use fsm::{Fsm, FsmError, FsmEvent};
 pub use functions_write::{
     function, function0, function1, function10, function2, function3, function4, function5,
-    function6, function7, function8, function9, Function, InsideCallback,
+    function6, function7, function8, function9, function_with_lua0, function_with_lua1,
+    function_with_lua2, function_with_lua3, function_with_lua4, function_with_lua5,
+    function_with_lua6, function_with_lua7, function_with_lua8, function_with_lua9,
+    ContextFunction, Function, InsideCallback,
+};
+pub use gc::{GcCycleStats, GcStepReport};
+pub use handle::{LockError, LuaGuard, LuaHandle};
+pub use init::{clear_global_init, set_global_init};
+pub use light_userdata::{push_light_userdata, LightUserdata};
+pub use lua_functions::{
+    LuaCode, LuaCodeFromReader, LuaFunction, LuaFunctionCallError, OwnedLuaFunction,
 };
-pub use lua_functions::{LuaCode, LuaCodeFromReader, LuaFunction, LuaFunctionCallError};
-pub use lua_tables::{LuaTable, LuaTableIterator};
+pub use lua_ref::{LuaRef, WeakLuaRef};
+pub use lua_tables::{LuaTable, LuaTableIterator, NotANumberError, OverrideError};
+pub use matrix::{LuaMatrix, PackedLuaMatrix};
+pub use ordered_callbacks::HandlerId;
+pub use overload::{overload, Overload, OverloadCandidate, OverloadSet};
+pub use panic_policy::PanicPolicy;
+pub use path::{LuaPath, PathError, PathErrorKind, PathKey};
+pub use pool::{lua_par_map, LuaPool, ParMapError};
+pub use pretty::PrettyFormat;
+pub use profiling::{ConversionDirection, ConversionStats};
+pub use protected::{ErrorContext, RecoveryAction};
+pub use raise::Raise;
+pub use ranges::{Bounded, NonNegative, RangeError, RangeErrorKind, RangeValue};
+#[doc(hidden)]
+pub use raw_stack::RawStack;
+pub use read_struct::read_struct_at;
+pub use read_struct::{FieldGuard, LuaReadStruct, NamedArgs, StructReadError, StructReader};
 pub use rust_tables::IntoIteratorWrapper;
+#[doc(hidden)]
+pub use rust_tables::{push_struct_table, set_struct_element, set_struct_field};
+pub use scope::Scope;
+pub use session::Session;
+pub use shutdown::ShutdownHookError;
+pub use slice_view::SliceView;
+pub use snapshot::{LuaSnapshot, SnapshotReader};
+pub use state_id::LuaStateId;
+pub use strings::{LuaBytes, LuaStr, LuaString, Utf8Policy};
+pub use test_runner::{TestOutcome, TestReport, TestResult, TestRunner};
+pub use transform::TransformedSource;
+pub use transparent::TransparentLua;
 pub use tuples::TuplePushError;
-pub use userdata::{push_userdata, read_userdata, UserdataOnStack};
-pub use values::{LuaNil, StringInLua};
+pub use userdata::{
+    push_userdata, read_userdata, read_userdata_ref, UserdataOnStack, UserdataPool,
+};
+pub use userdata_methods::{add_user_data_methods, MetaMethod, MethodsBuilder, UserData};
+pub use values::{LuaNil, OrDefault, StringInLua};
+pub use varargs::Varargs;
 
+mod actor;
 mod any;
-mod ffix;
+mod app_data;
+#[cfg(not(feature = "_luaapi_51"))]
+mod arith;
+mod arrays;
+#[cfg(feature = "async")]
+mod blocking;
+mod broadcast;
+mod builder;
+mod bundle;
+mod byte_buffer;
+mod bytecode;
+mod commands;
+mod compat;
+mod completion;
+mod coroutine;
+#[cfg(feature = "crash-report")]
+mod crash_report;
+mod database;
+mod env;
+mod exit;
+#[cfg(feature = "fennel")]
+mod fennel;
+pub mod ffix;
+#[cfg(feature = "impl-bitflags")]
+mod flags;
+mod flight_recorder;
+mod format;
+#[cfg(feature = "fs")]
+mod fs;
+mod fsm;
 mod functions_write;
+mod gc;
+mod handle;
+#[cfg(feature = "http")]
+mod http;
+mod init;
+mod light_userdata;
+mod locale;
+#[cfg(feature = "log")]
+mod logging;
 mod lua_functions;
+mod lua_ref;
 mod lua_tables;
 mod macros;
+mod matrix;
+mod ordered_callbacks;
+mod overload;
+mod panic_policy;
+mod path;
+mod patterns;
+mod pool;
+mod pretty;
+mod print;
+#[cfg(feature = "proc")]
+mod process;
+mod profiling;
+mod protected;
+mod raise;
+mod ranges;
+mod raw_stack;
+mod read_struct;
+#[cfg(feature = "arrow")]
+mod record_batch;
+#[cfg(feature = "regex")]
+mod regex;
 mod rust_tables;
+mod scope;
+#[cfg(feature = "serde")]
+mod serialize;
+mod session;
+mod shutdown;
+mod slice_view;
+mod snapshot;
+mod sorted_iteration;
+mod state_id;
+mod strings;
+#[cfg(feature = "teal")]
+mod teal;
+mod test_runner;
+mod transform;
+mod transparent;
 mod tuples;
 mod userdata;
+mod userdata_methods;
 mod values;
+mod varargs;
 
 /// Main object of the library.
 ///
@@ -440,51 +649,217 @@ pub trait LuaRead<L>: Sized {
 }
 
 /// Error that can happen when executing Lua code.
+///
+/// The errors raised by Lua code are `ScriptError`s, which give access to the message, the
+/// location and the traceback of the error. The error is `Send` and `Sync`, so it can be
+/// returned with `?` from functions returning a boxed error or an error of an error-reporting
+/// crate.
 #[derive(Debug)]
 pub enum LuaError {
     /// There was a syntax error when parsing the Lua code.
-    SyntaxError(String),
+    SyntaxError(ScriptError),
 
     /// There was an error during execution of the Lua code
     /// (for example not enough parameters for a function call).
-    ExecutionError(String),
+    ExecutionError(ScriptError),
 
     /// There was an IoError while reading the source code to execute.
     ReadError(IoError),
 
-    /// The call to `execute` has requested the wrong type of data.
+    /// The call to `execute` has requested the wrong type of data: the values returned by Lua
+    /// can't be converted to it.
     WrongType,
+
+    /// The code called `os.exit` with the given code, after `Lua::intercept_exit`.
+    ExitRequested(i32),
+
+    /// Lua couldn't allocate memory, usually because of the limit set with
+    /// `Lua::set_memory_limit`.
+    MemoryLimitExceeded,
+
+    /// A Rust callback raised an error with `Raise`. `downcast_ref` gives back its value.
+    RustError(Arc<dyn Error + Send + Sync>),
+}
+
+impl LuaError {
+    /// Returns the syntax or execution error raised by Lua, if it is one.
+    #[inline]
+    pub fn script_error(&self) -> Option<&ScriptError> {
+        match self {
+            LuaError::SyntaxError(err) | LuaError::ExecutionError(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Returns the error raised by a Rust callback with `Raise`, if it is one of type `E`.
+    #[inline]
+    pub fn downcast_ref<E: Error + 'static>(&self) -> Option<&E> {
+        match self {
+            LuaError::RustError(err) => err.downcast_ref(),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for LuaError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             LuaError::SyntaxError(s) => write!(f, "Syntax error: {}", s),
+            LuaError::ExecutionError(s) if f.alternate() => write!(f, "Execution error: {:#}", s),
             LuaError::ExecutionError(s) => write!(f, "Execution error: {}", s),
             LuaError::ReadError(e) => write!(f, "Read error: {}", e),
             LuaError::WrongType => write!(f, "Wrong type returned by Lua"),
+            LuaError::ExitRequested(code) => write!(f, "Exit requested with code {}", code),
+            LuaError::MemoryLimitExceeded => write!(f, "Memory limit exceeded"),
+            LuaError::RustError(e) => write!(f, "Callback error: {}", e),
         }
     }
 }
 
 impl Error for LuaError {
-    fn description(&self) -> &str {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            LuaError::SyntaxError(ref s) => s,
-            LuaError::ExecutionError(ref s) => s,
-            LuaError::ReadError(_) => "read error",
-            LuaError::WrongType => "wrong type returned by Lua",
+            LuaError::SyntaxError(e) | LuaError::ExecutionError(e) => Some(e),
+            LuaError::ReadError(e) => Some(e),
+            LuaError::RustError(e) => Some(&**e),
+            LuaError::WrongType | LuaError::ExitRequested(_) | LuaError::MemoryLimitExceeded => {
+                None
+            },
         }
     }
+}
 
-    fn cause(&self) -> Option<&dyn Error> {
-        match self {
-            LuaError::SyntaxError(_) => None,
-            LuaError::ExecutionError(_) => None,
-            LuaError::ReadError(e) => Some(e),
-            LuaError::WrongType => None,
+/// Error raised by Lua code, or by a Rust function called by Lua code.
+///
+/// When the message starts with a location, such as `script.lua:12: attempt to call a nil
+/// value`, which is the case of the errors raised by `error` and by the Lua runtime, the name of
+/// the chunk and the line are extracted from it. A traceback is also extracted from the message
+/// if it contains one, like the messages created by `debug.traceback`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, LuaError, LuaFunction};
+///
+/// let mut lua = Lua::new();
+/// let code = "local speed = 2\n\nreturn speed + nil";
+/// let mut function = LuaFunction::load_named(&mut lua, "@game.lua", code).unwrap();
+///
+/// match function.call::<i32>() {
+///     Err(LuaError::ExecutionError(err)) => {
+///         assert_eq!(err.chunk_name(), Some("game.lua"));
+///         assert_eq!(err.line(), Some(3));
+///         assert!(err.reason().starts_with("attempt to perform arithmetic"));
+///     },
+///     _ => unreachable!(),
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    message: String,
+    location: Option<(usize, u32)>,
+    traceback: Option<String>,
+}
+
+impl ScriptError {
+    /// Builds an error from the message of a Lua error, which can contain a location and a
+    /// traceback.
+    pub fn new(message: impl Into<String>) -> ScriptError {
+        let mut message = message.into();
+        let traceback = message.find("\nstack traceback:\n").map(|start| {
+            let traceback = message[start + 1..].to_owned();
+            message.truncate(start);
+            traceback
+        });
+        let location = parse_location(&message);
+        ScriptError { message, location, traceback }
+    }
+
+    /// Returns the message, including its location, but not the traceback.
+    #[inline]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns the message without its location.
+    #[inline]
+    pub fn reason(&self) -> &str {
+        match self.location {
+            Some((end, _)) => self.message[end..].trim_start_matches(':').trim_start(),
+            None => &self.message,
         }
     }
+
+    /// Returns the name of the chunk where the error was raised, for example `script.lua` or
+    /// `[string "return f()"]`, if the message contains it.
+    #[inline]
+    pub fn chunk_name(&self) -> Option<&str> {
+        let (end, _) = self.location?;
+        let location = &self.message[..end];
+        location.rfind(':').map(|colon| &location[..colon])
+    }
+
+    /// Returns the line where the error was raised, if the message contains it.
+    #[inline]
+    pub fn line(&self) -> Option<u32> {
+        self.location.map(|(_, line)| line)
+    }
+
+    /// Returns the traceback of the error, which starts with `stack traceback:`, if there is one.
+    #[inline]
+    pub fn traceback(&self) -> Option<&str> {
+        self.traceback.as_deref()
+    }
+
+    /// Sets the traceback of the error.
+    #[inline]
+    pub fn with_traceback(mut self, traceback: impl Into<String>) -> ScriptError {
+        self.traceback = Some(traceback.into());
+        self
+    }
+}
+
+// Returns the end of the `chunk:line` prefix of a message and the line.
+fn parse_location(message: &str) -> Option<(usize, u32)> {
+    // The chunk names of code loaded from strings can contain colons.
+    let search_from = match message.starts_with("[string \"") {
+        true => message.find("\"]:")? + 2,
+        false => 0,
+    };
+    message[search_from..].match_indices(':').find_map(|(colon, _)| {
+        let start = search_from + colon + 1;
+        let digits = message[start..].bytes().take_while(u8::is_ascii_digit).count();
+        match message[start + digits..].starts_with(':') && digits > 0 {
+            true => Some((start + digits, message[start..start + digits].parse().ok()?)),
+            false => None,
+        }
+    })
+}
+
+// The alternate format, `{:#}`, includes the traceback.
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.traceback {
+            Some(traceback) if f.alternate() => write!(f, "{}\n{}", self.message, traceback),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for ScriptError {}
+
+impl From<String> for ScriptError {
+    #[inline]
+    fn from(message: String) -> ScriptError {
+        ScriptError::new(message)
+    }
+}
+
+impl From<&str> for ScriptError {
+    #[inline]
+    fn from(message: &str) -> ScriptError {
+        ScriptError::new(message)
+    }
 }
 
 impl From<io::Error> for LuaError {
@@ -503,7 +878,8 @@ impl<'lua> Lua<'lua> {
     /// Builds a new empty Lua context.
     ///
     /// There are no global variables and the registry is totally empty. Even the functions from
-    /// the standard library can't be used.
+    /// the standard library can't be used. The only exception is the function set with
+    /// `set_global_init`, which is called on the context before it is returned.
     ///
     /// If you want to use the Lua standard library in the scripts of this context, see
     /// [the openlibs method](#method.openlibs)
@@ -517,11 +893,18 @@ impl<'lua> Lua<'lua> {
     ///
     /// # Panic
     ///
-    /// The function panics if the underlying call to `luaL_newstate` fails
+    /// The function panics if the underlying call to `lua_newstate` fails
     /// (which indicates lack of memory).
     #[inline]
     #[must_use]
     pub fn new() -> Lua<'lua> {
+        let mut lua = unsafe { Lua::from_new_state(builder::new_state(None)) };
+        init::run_global_init(&mut lua);
+        lua
+    }
+
+    // Wraps a state that was just created, panicking if its creation failed.
+    pub(crate) unsafe fn from_new_state(lua: *mut ffi::lua_State) -> Lua<'lua> {
         // called whenever lua encounters an unexpected error.
         extern "C" fn panic(lua: *mut ffi::lua_State) -> libc::c_int {
             let err = unsafe { ffi::lua_tostring(lua, -1) };
@@ -530,10 +913,9 @@ impl<'lua> Lua<'lua> {
             panic!("PANIC: unprotected error in call to Lua API ({})\n", err);
         }
 
-        let lua = NonNull::new(unsafe { ffi::luaL_newstate() });
-        let lua = lua.expect("luaL_newstate failed");
-
-        unsafe { ffi::lua_atpanic(lua.as_ptr(), Some(panic)) };
+        let lua = NonNull::new(lua).expect("lua_newstate failed");
+        ffi::lua_atpanic(lua.as_ptr(), Some(panic));
+        state_id::state_id(lua);
 
         Lua { lua, must_be_closed: true, marker: PhantomData }
     }
@@ -729,6 +1111,7 @@ impl<'lua> Lua<'lua> {
     /// let sixty = lua.execute::<i32>("return 6 * 10;").unwrap();
     /// ```
     #[inline]
+    #[track_caller]
     pub fn execute<'a, T>(&'a mut self, code: &str) -> Result<T, LuaError>
     where
         T: for<'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'lua>>>>,
@@ -737,6 +1120,51 @@ impl<'lua> Lua<'lua> {
         f.call()
     }
 
+    /// Executes some Lua code in the context, giving a name to the chunk.
+    ///
+    /// This does the same thing as `execute`, except that `name` appears in error messages and
+    /// tracebacks in place of `chunk`. See `LuaFunction::load_named`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// let err = lua.execute_named::<()>("@player.lua", "\n\nlocal a = b.c").unwrap_err();
+    /// assert!(err.to_string().contains("player.lua:3:"));
+    /// ```
+    #[inline]
+    #[track_caller]
+    pub fn execute_named<'a, T>(&'a mut self, name: &str, code: &str) -> Result<T, LuaError>
+    where
+        T: for<'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'lua>>>>,
+    {
+        let mut f = lua_functions::LuaFunction::load_named(self, name, code)?;
+        f.call()
+    }
+
+    /// Executes some Lua code in the context and reads all the values it returns.
+    ///
+    /// The values are read starting from the first one, which makes it possible to read them
+    /// into a tuple or into a struct that derives `LuaReadMulti`, whose fields are mapped to the
+    /// returned values in order.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// let (a, b, c): (i32, String, bool) = lua.execute_multi("return 1, 'two', true").unwrap();
+    /// assert_eq!((a, b.as_str(), c), (1, "two", true));
+    /// ```
+    #[inline]
+    #[track_caller]
+    pub fn execute_multi<'a, T>(&'a mut self, code: &str) -> Result<T, LuaError>
+    where
+        T: for<'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'lua>>>>,
+    {
+        let mut f = lua_functions::LuaFunction::load(self, code)?;
+        f.call_multi_with_args(()).map_err(LuaError::from)
+    }
+
     /// Executes some Lua code on the context.
     ///
     /// This does the same thing as [the `execute` method](#method.execute), but the code to
@@ -757,6 +1185,7 @@ impl<'lua> Lua<'lua> {
     /// lua.execute_from_reader::<(), _>(script).unwrap();
     /// ```
     #[inline]
+    #[track_caller]
     pub fn execute_from_reader<'a, T, R>(&'a mut self, code: R) -> Result<T, LuaError>
     where
         T: for<'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'lua>>>>,
@@ -784,6 +1213,7 @@ impl<'lua> Lua<'lua> {
     /// assert_eq!(a, 5);
     /// ```
     #[inline]
+    #[track_caller]
     #[allow(clippy::needless_lifetimes)] // clippy: false positive
     pub fn get<'l, V, I>(&'l mut self, index: I) -> Option<V>
     where
@@ -791,6 +1221,7 @@ impl<'lua> Lua<'lua> {
         V: LuaRead<PushGuard<&'l mut Lua<'lua>>>,
     {
         let raw_lua = self.as_mut_lua();
+        unsafe { profiling::record::<V>(raw_lua, ConversionDirection::Read, Location::caller()) };
 
         let index = CString::new(index.borrow()).unwrap();
         unsafe { ffi::lua_getglobal(raw_lua.as_ptr(), index.as_ptr()) };
@@ -841,6 +1272,7 @@ impl<'lua> Lua<'lua> {
     /// assert_eq!(six, 6);
     /// ```
     #[inline]
+    #[track_caller]
     pub fn set<I, V, E>(&mut self, index: I, value: V)
     where
         I: Borrow<str>,
@@ -856,12 +1288,15 @@ impl<'lua> Lua<'lua> {
     /// Modifies the value of a global variable.
     // TODO: docs
     #[inline]
+    #[track_caller]
     pub fn checked_set<I, V, E>(&mut self, index: I, value: V) -> Result<(), E>
     where
         I: Borrow<str>,
         for<'a> V: PushOne<&'a mut Lua<'lua>, Err = E>,
     {
         unsafe {
+            profiling::record::<V>(self.lua, ConversionDirection::Push, Location::caller());
+
             // TODO: can be simplified
             let mut me = self;
 
@@ -981,11 +1416,21 @@ impl<'lua> Drop for Lua<'lua> {
     #[inline]
     fn drop(&mut self) {
         if self.must_be_closed {
-            unsafe { ffi::lua_close(self.lua.as_ptr()) }
+            unsafe {
+                if handle::on_close(self.lua) {
+                    close_state(self.lua);
+                }
+            }
         }
     }
 }
 
+/// Closes a Lua context created by `Lua::new`.
+unsafe fn close_state(lua: LuaContext) {
+    gc::remove_observer(lua);
+    builder::close(lua)
+}
+
 impl<L> Drop for PushGuard<L> {
     #[inline]
     fn drop(&mut self) {
@@ -997,7 +1442,9 @@ impl<L> Drop for PushGuard<L> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{Lua, LuaError};
+    use std::error::Error;
+
+    use crate::{Lua, LuaError, ScriptError};
 
     #[test]
     fn open_base_opens_base_library() {
@@ -1012,6 +1459,43 @@ mod tests {
         assert_eq!(result, true);
     }
 
+    #[test]
+    fn script_error_locations() {
+        let err = ScriptError::new("[string \"f(':1: x')\"]:12: attempt to call a nil value");
+        assert_eq!(err.chunk_name(), Some("[string \"f(':1: x')\"]"));
+        assert_eq!(err.line(), Some(12));
+        assert_eq!(err.reason(), "attempt to call a nil value");
+
+        let err = ScriptError::new("C:\\game\\main.lua:3: oops\nstack traceback:\n\t[C]: in ?");
+        assert_eq!(err.chunk_name(), Some("C:\\game\\main.lua"));
+        assert_eq!(err.line(), Some(3));
+        assert_eq!(err.message(), "C:\\game\\main.lua:3: oops");
+        assert_eq!(err.traceback(), Some("stack traceback:\n\t[C]: in ?"));
+        assert_eq!(err.to_string(), "C:\\game\\main.lua:3: oops");
+        assert_eq!(
+            format!("{:#}", err),
+            "C:\\game\\main.lua:3: oops\nstack traceback:\n\t[C]: in ?"
+        );
+
+        let err = ScriptError::new("no location: 12");
+        assert_eq!((err.chunk_name(), err.line(), err.reason()), (None, None, "no location: 12"));
+    }
+
+    #[test]
+    fn errors_compose() {
+        fn run(lua: &mut Lua) -> Result<i32, Box<dyn Error + Send + Sync>> {
+            Ok(lua.execute::<i32>("return 1 +")?)
+        }
+
+        let mut lua = Lua::new();
+        let err = run(&mut lua).unwrap_err();
+        let err = err.downcast::<LuaError>().unwrap();
+        let source = err.source().unwrap().downcast_ref::<ScriptError>().unwrap();
+        assert_eq!(source.line(), Some(1));
+        assert!(matches!(*err, LuaError::SyntaxError(_)));
+        assert_eq!(err.script_error(), Some(source));
+    }
+
     #[test]
     fn opening_all_libraries_doesnt_panic() {
         let mut lua = Lua::new();
@@ -0,0 +1,106 @@
+use std::ffi::c_void;
+
+use crate::{AsLua, AsMutLua, LuaRead, Push, PushGuard, PushOne, Void};
+
+/// Raw pointer stored in Lua as a light user data.
+///
+/// Light user data are how C libraries usually exchange pointers through Lua. Unlike the user
+/// data pushed with `push_userdata`, Lua doesn't own what they point to, doesn't know its type
+/// and never frees it, and all light user data share a single metatable. Two light user data are
+/// equal in Lua if they hold the same address.
+///
+/// Nothing is unsafe about pushing or reading one, but turning the pointer back into a reference
+/// is only correct if the value it points to is still alive and has the type that you expect,
+/// which Lua code can't be prevented from breaking.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, LightUserdata};
+///
+/// let mut value = 42_i32;
+/// let pointer = LightUserdata(&mut value as *mut i32 as *mut _);
+///
+/// let mut lua = Lua::new();
+/// lua.openlibs();
+/// lua.set("handle", pointer);
+/// assert_eq!(lua.execute::<String>("return type(handle)").unwrap(), "userdata");
+///
+/// let read: LightUserdata = lua.get("handle").unwrap();
+/// assert_eq!(read, pointer);
+/// assert_eq!(unsafe { *read.0.cast::<i32>() }, 42);
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct LightUserdata(pub *mut c_void);
+
+/// Pushes `pointer` as a light user data. Same as pushing a `LightUserdata`.
+#[inline]
+#[allow(clippy::not_unsafe_ptr_arg_deref)] // The pointer is only stored, never dereferenced.
+pub fn push_light_userdata<'lua, L>(pointer: *mut c_void, mut lua: L) -> PushGuard<L>
+where
+    L: AsMutLua<'lua>,
+{
+    let raw_lua = lua.as_mut_lua();
+    unsafe { ffi::lua_pushlightuserdata(raw_lua.as_ptr(), pointer) };
+    PushGuard { lua, size: 1, raw_lua }
+}
+
+impl<'lua, L> Push<L> for LightUserdata
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void; // TODO: use `!` instead (https://github.com/rust-lang/rust/issues/35121)
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        Ok(push_light_userdata(self.0, lua))
+    }
+}
+
+impl<'lua, L> PushOne<L> for LightUserdata where L: AsMutLua<'lua> {}
+
+/// Only reads light user data, not the full user data pushed with `push_userdata`, whose
+/// address is managed by Lua.
+impl<'lua, L> LuaRead<L> for LightUserdata
+where
+    L: AsLua<'lua>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<LightUserdata, L> {
+        unsafe {
+            let raw_lua = lua.as_lua().as_ptr();
+            if ffi::lua_type(raw_lua, index) != ffi::LUA_TLIGHTUSERDATA {
+                return Err(lua);
+            }
+            Ok(LightUserdata(ffi::lua_touserdata(raw_lua, index)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use crate::{function1, LightUserdata, Lua};
+
+    #[test]
+    fn round_trip() {
+        let mut values = [10_u8, 20, 30];
+        let base = values.as_mut_ptr();
+
+        let mut lua = Lua::new();
+        lua.set("base", LightUserdata(base.cast()));
+        lua.set("null", LightUserdata(ptr::null_mut()));
+        lua.set(
+            "at",
+            function1(move |offset: u32| LightUserdata(base.wrapping_add(offset as usize).cast())),
+        );
+        lua.set("table", vec![1]);
+
+        assert!(lua.execute::<bool>("return at(0) == base and at(1) ~= base").unwrap());
+        let second: LightUserdata = lua.execute("return at(1)").unwrap();
+        assert_eq!(unsafe { *second.0.cast::<u8>() }, 20);
+        assert!(lua.get::<LightUserdata, _>("null").unwrap().0.is_null());
+        assert!(lua.get::<LightUserdata, _>("table").is_none());
+    }
+}
@@ -0,0 +1,159 @@
+use std::ffi::CStr;
+
+use crate::lua_functions::GuardedCalls;
+use crate::userdata::push_userdata;
+use crate::{Lua, LuaContext};
+
+// Key of the registry entry set to a `GuardedCalls` when the context formats and parses numbers
+// with the C locale, absent otherwise.
+const KEY: &CStr = c"hlua.c_numeric_locale";
+
+impl<'lua> Lua<'lua> {
+    /// Makes the conversions between numbers and strings independent of the locale of the
+    /// process.
+    ///
+    /// Lua converts numbers with the C library, which honors the `LC_NUMERIC` category of the
+    /// current locale. If the program called `setlocale` with a locale that uses a comma as the
+    /// decimal separator, `tostring(1.5)` returns `"1,5"` and `tonumber("1,5")` succeeds. When
+    /// enabled, the C locale is installed for the current thread while Lua code runs and while
+    /// hlua reads numbers as strings, so `.` is always the decimal separator. The locale of the
+    /// process and of the other threads isn't touched.
+    ///
+    /// Disabled by default. This has no effect on platforms other than Unix.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// lua.set_c_numeric_locale(true);
+    /// assert_eq!(lua.execute::<String>("return tostring(1.5)").unwrap(), "1.5");
+    /// ```
+    pub fn set_c_numeric_locale(&mut self, enabled: bool) {
+        unsafe {
+            match enabled {
+                true => {
+                    // Boxed since zero-sized user data aren't stored, and would be dropped early.
+                    push_userdata(Box::new(GuardedCalls::new()), self.lua, |_| {}).forget();
+                },
+                false => ffi::lua_pushnil(self.lua.as_ptr()),
+            }
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, KEY.as_ptr());
+        }
+    }
+
+    /// Returns true if `set_c_numeric_locale` was enabled.
+    #[inline]
+    pub fn c_numeric_locale(&self) -> bool {
+        unsafe { enabled(self.lua) }
+    }
+}
+
+unsafe fn enabled(lua: LuaContext) -> bool {
+    if !GuardedCalls::any() {
+        return false;
+    }
+    ffi::lua_getfield(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, KEY.as_ptr());
+    let enabled = ffi::lua_toboolean(lua.as_ptr(), -1) != 0;
+    ffi::lua_pop(lua.as_ptr(), 1);
+    enabled
+}
+
+/// Installs the C locale for the current thread while it is alive, if the context asked for it
+/// with `Lua::set_c_numeric_locale`.
+pub(crate) struct NumericLocaleGuard {
+    #[cfg(unix)]
+    previous: Option<libc::locale_t>,
+}
+
+impl NumericLocaleGuard {
+    #[cfg(unix)]
+    pub(crate) unsafe fn enter(lua: LuaContext) -> NumericLocaleGuard {
+        if !enabled(lua) {
+            return NumericLocaleGuard { previous: None };
+        }
+        match c_locale() {
+            Some(locale) => NumericLocaleGuard { previous: Some(libc::uselocale(locale)) },
+            None => NumericLocaleGuard { previous: None },
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) unsafe fn enter(_: LuaContext) -> NumericLocaleGuard {
+        NumericLocaleGuard {}
+    }
+}
+
+#[cfg(unix)]
+impl Drop for NumericLocaleGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous {
+            unsafe { libc::uselocale(previous) };
+        }
+    }
+}
+
+// The C locale object, created once and never freed since `uselocale` needs it to stay valid.
+#[cfg(unix)]
+fn c_locale() -> Option<libc::locale_t> {
+    use std::sync::OnceLock;
+
+    struct Locale(libc::locale_t);
+    unsafe impl Send for Locale {}
+    unsafe impl Sync for Locale {}
+
+    static LOCALE: OnceLock<Locale> = OnceLock::new();
+    let locale = LOCALE.get_or_init(|| unsafe {
+        Locale(libc::newlocale(libc::LC_ALL_MASK, c"C".as_ptr(), std::ptr::null_mut()))
+    });
+    Some(locale.0).filter(|locale| !locale.is_null())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Lua;
+
+    #[test]
+    fn option_round_trips() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        assert!(!lua.c_numeric_locale());
+        lua.set_c_numeric_locale(true);
+        assert!(lua.c_numeric_locale());
+        assert_eq!(lua.execute::<String>("return tostring(1.5)").unwrap(), "1.5");
+        lua.set("x", 2.25);
+        assert_eq!(lua.get::<String, _>("x").unwrap(), "2.25");
+        lua.set_c_numeric_locale(false);
+        assert!(!lua.c_numeric_locale());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ignores_thread_locale() {
+        use std::ptr;
+
+        // Uses whichever comma locale is installed; the test can't check anything without one.
+        let names = [c"de_DE.UTF-8", c"fr_FR.UTF-8", c"de_DE", c"fr_FR"];
+        let comma = names.iter().find_map(|name| unsafe {
+            let locale = libc::newlocale(libc::LC_NUMERIC_MASK, name.as_ptr(), ptr::null_mut());
+            Some(locale).filter(|locale| !locale.is_null())
+        });
+        let Some(comma) = comma else { return };
+
+        let previous = unsafe { libc::uselocale(comma) };
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let localized = lua.execute::<String>("return tostring(1.5)").unwrap();
+        lua.set_c_numeric_locale(true);
+        let fixed = lua.execute::<String>("return tostring(1.5)").unwrap();
+        let parsed = lua.execute::<Option<f64>>("return tonumber('1,5')").unwrap();
+        unsafe {
+            libc::uselocale(previous);
+            libc::freelocale(comma);
+        }
+
+        assert_eq!(localized, "1,5");
+        assert_eq!(fixed, "1.5");
+        assert_eq!(parsed, None);
+    }
+}
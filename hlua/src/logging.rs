@@ -0,0 +1,201 @@
+use std::{ffi::CStr, mem, str::FromStr};
+
+use log::Level;
+
+use crate::{ffix, print, AsMutLua, Lua, LuaContext, Push};
+
+impl<'lua> Lua<'lua> {
+    /// Opens a `log` library whose functions forward messages to the `log` crate.
+    ///
+    /// Scripts can call `log.trace`, `log.debug`, `log.info`, `log.warn` and `log.error`, whose
+    /// arguments are formatted the same way as with `print`, or `log.log(level, ...)` where
+    /// `level` is the name of a level such as `"warn"`. Unknown level names raise a Lua error.
+    ///
+    /// The target of the records is the name of the chunk that called the function, and the
+    /// file and line of the records are set to the location of the call.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.open_log();
+    /// lua.execute_named::<()>("=player", "log.warn('low health:', 5)").unwrap();
+    /// // Logs "low health:\t5" at the `Warn` level, with `player` as target.
+    /// ```
+    pub fn open_log(&mut self) {
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_newtable(raw_lua.as_ptr());
+
+            let functions: [(&CStr, ffi::lua_CFunction); 6] = [
+                (c"error", Some(log_at::<1>)),
+                (c"warn", Some(log_at::<2>)),
+                (c"info", Some(log_at::<3>)),
+                (c"debug", Some(log_at::<4>)),
+                (c"trace", Some(log_at::<5>)),
+                (c"log", Some(log_with_level)),
+            ];
+            for (name, function) in functions {
+                ffi::lua_pushcfunction(raw_lua.as_ptr(), function);
+                ffi::lua_setfield(raw_lua.as_ptr(), -2, name.as_ptr());
+            }
+
+            ffi::lua_setfield(raw_lua.as_ptr(), -2, c"log".as_ptr());
+            ffi::lua_pop(raw_lua.as_ptr(), 1);
+        }
+    }
+}
+
+// `LEVEL` is the numeric value of a `log::Level`.
+extern "C" fn log_at<const LEVEL: usize>(lua: *mut ffi::lua_State) -> libc::c_int {
+    let level = match LEVEL {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    };
+
+    unsafe { emit(LuaContext::new_unchecked(lua), level, 1) };
+    0
+}
+
+extern "C" fn log_with_level(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        let name = match ffi::lua_type(lua, 1) {
+            ffi::LUA_TSTRING => print::to_display_string(raw_lua, 1),
+            _ => String::new(),
+        };
+
+        match Level::from_str(&name) {
+            Ok(level) => emit(raw_lua, level, 2),
+            Err(_) => {
+                let msg = format!("invalid log level '{}'", name);
+                msg.push_no_err(raw_lua).forget();
+                drop(name);
+                ffix::lua_error(lua);
+            },
+        }
+
+        0
+    }
+}
+
+// Logs the arguments of the running function starting at `first`.
+unsafe fn emit(lua: LuaContext, level: Level, first: libc::c_int) {
+    if level > log::max_level() {
+        return;
+    }
+
+    let (source, line) = caller_location(lua);
+    let message = print::format_arguments(lua, first);
+
+    log::logger().log(
+        &log::Record::builder()
+            .args(format_args!("{}", message))
+            .level(level)
+            .target(&source)
+            .file(Some(&source))
+            .line(line)
+            .build(),
+    );
+}
+
+// Returns the chunk name and line of the Lua code calling the running function.
+unsafe fn caller_location(lua: LuaContext) -> (String, Option<u32>) {
+    let mut ar: ffi::lua_Debug = mem::zeroed();
+    if ffi::lua_getstack(lua.as_ptr(), 1, &mut ar) == 0 {
+        return ("lua".to_owned(), None);
+    }
+    ffi::lua_getinfo(lua.as_ptr(), c"Sl".as_ptr(), &mut ar);
+
+    let source = CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy().into_owned();
+    (source, u32::try_from(ar.currentline).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, Once};
+
+    use log::{Level, Log, Metadata, Record};
+
+    use crate::Lua;
+
+    // (level, target, line, message)
+    type Captured = (Level, String, Option<u32>, String);
+
+    struct TestLogger(Mutex<Vec<Captured>>);
+
+    impl Log for TestLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push((
+                record.level(),
+                record.target().to_owned(),
+                record.line(),
+                record.args().to_string(),
+            ));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger(Mutex::new(Vec::new()));
+
+    // Returns the records whose target is `target`. Tests run in parallel, so each test uses its
+    // own chunk name.
+    fn records(target: &str) -> Vec<Captured> {
+        let records = LOGGER.0.lock().unwrap();
+        records.iter().filter(|r| r.1 == target).cloned().collect()
+    }
+
+    fn init() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+    }
+
+    #[test]
+    fn levels_and_location() {
+        init();
+        let mut lua = Lua::new();
+        lua.open_log();
+
+        lua.execute_named::<()>("=levels", "log.info('a', 1)\nlog.error(true)\nlog.trace()")
+            .unwrap();
+        assert_eq!(
+            records("levels"),
+            vec![
+                (Level::Info, "levels".to_owned(), Some(1), "a\t1".to_owned()),
+                (Level::Error, "levels".to_owned(), Some(2), "true".to_owned()),
+                (Level::Trace, "levels".to_owned(), Some(3), "".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parsed_level() {
+        init();
+        let mut lua = Lua::new();
+        lua.open_log();
+
+        lua.execute_named::<()>("=parsed", "log.log('WARN', 'x') log.log('debug')").unwrap();
+        assert_eq!(
+            records("parsed"),
+            vec![
+                (Level::Warn, "parsed".to_owned(), Some(1), "x".to_owned()),
+                (Level::Debug, "parsed".to_owned(), Some(1), "".to_owned()),
+            ]
+        );
+
+        let err = lua.execute::<()>("log.log('loud', 'x')").unwrap_err();
+        assert!(err.to_string().contains("invalid log level 'loud'"));
+    }
+}
@@ -1,14 +1,28 @@
 use std::{
     error::Error,
+    ffi::{CStr, CString},
     fmt,
-    io::{Cursor, Error as IoError, Read},
+    io::{Cursor, Error as IoError, ErrorKind, Read, Write},
     mem,
+    panic::Location,
     ptr::addr_of_mut,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use crate::{AsLua, AsMutLua};
 
-use crate::{LuaContext, LuaError, LuaRead, Push, PushGuard, PushOne, Void};
+use crate::builder::InstructionBudget;
+use crate::exit;
+use crate::ffix;
+use crate::flight_recorder::{self, FlightEvent};
+use crate::handle::BusyGuard;
+use crate::locale::NumericLocaleGuard;
+use crate::profiling::{self, ConversionDirection};
+use crate::raise;
+use crate::snapshot::SnapshotGuard;
+use crate::transform;
+use crate::userdata::BorrowWatermark;
+use crate::{LuaContext, LuaError, LuaRead, LuaRef, Push, PushGuard, PushOne, ScriptError, Void};
 
 /// Wrapper around a `&str`. When pushed, the content will be parsed as Lua code and turned into a
 /// function.
@@ -78,85 +92,126 @@ where
     type Err = LuaError;
 
     #[inline]
-    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (LuaError, L)> {
-        unsafe {
-            struct ReadData<R> {
-                reader: R,
-                buffer: [u8; 128],
-                triggered_error: Option<IoError>,
-            }
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (LuaError, L)> {
+        unsafe { load_chunk(lua, self.0, c"chunk") }
+    }
+}
 
-            let mut read_data =
-                ReadData { reader: self.0, buffer: mem::zeroed(), triggered_error: None };
+impl<'lua, L, R> PushOne<L> for LuaCodeFromReader<R>
+where
+    L: AsMutLua<'lua>,
+    R: Read,
+{
+}
 
-            extern "C" fn reader<R>(
-                _: *mut ffi::lua_State,
-                data: *mut libc::c_void,
-                size: *mut libc::size_t,
-            ) -> *const libc::c_char
-            where
-                R: Read,
-            {
-                unsafe {
-                    let data: *mut ReadData<R> = data.cast();
-                    let data: &mut ReadData<R> = &mut *data;
-
-                    if data.triggered_error.is_some() {
-                        *size = 0;
-                        return data.buffer.as_ptr().cast::<libc::c_char>();
-                    }
-
-                    match data.reader.read(&mut data.buffer) {
-                        Ok(len) => *size = len as libc::size_t,
-                        Err(e) => {
-                            *size = 0;
-                            data.triggered_error = Some(e);
-                        },
-                    };
-
-                    data.buffer.as_ptr().cast::<libc::c_char>()
-                }
+// Loads the code of a reader as a function, pushed on top of the stack.
+unsafe fn load_chunk<'lua, L, R>(
+    mut lua: L,
+    mut code: R,
+    chunkname: &CStr,
+) -> Result<PushGuard<L>, (LuaError, L)>
+where
+    L: AsMutLua<'lua>,
+    R: Read,
+{
+    let raw_lua = lua.as_mut_lua();
+    if !transform::has_transforms(raw_lua) {
+        return load_source(lua, code, chunkname);
+    }
+
+    let mut source = Vec::new();
+    if let Err(err) = code.read_to_end(&mut source) {
+        return Err((LuaError::ReadError(err), lua));
+    }
+    let source = transform::apply(raw_lua, chunkname, source);
+    load_source(lua, Cursor::new(source), chunkname)
+}
+
+// Loads the code of a reader as is.
+unsafe fn load_source<'lua, L, R>(
+    mut lua: L,
+    code: R,
+    chunkname: &CStr,
+) -> Result<PushGuard<L>, (LuaError, L)>
+where
+    L: AsMutLua<'lua>,
+    R: Read,
+{
+    struct ReadData<R> {
+        reader: R,
+        buffer: [u8; 128],
+        triggered_error: Option<IoError>,
+    }
+
+    let mut read_data = ReadData { reader: code, buffer: mem::zeroed(), triggered_error: None };
+
+    extern "C" fn reader<R>(
+        _: *mut ffi::lua_State,
+        data: *mut libc::c_void,
+        size: *mut libc::size_t,
+    ) -> *const libc::c_char
+    where
+        R: Read,
+    {
+        unsafe {
+            let data: *mut ReadData<R> = data.cast();
+            let data: &mut ReadData<R> = &mut *data;
+
+            if data.triggered_error.is_some() {
+                *size = 0;
+                return data.buffer.as_ptr().cast::<libc::c_char>();
             }
 
-            let (load_retval, pushed_value) = {
-                let raw_lua = lua.as_mut_lua();
-                let code = ffi::lua_load(
-                    raw_lua.as_ptr(),
-                    Some(reader::<R>),
-                    addr_of_mut!(read_data).cast(),
-                    b"chunk\0".as_ptr().cast(),
-                    #[cfg(any(feature = "_luaapi_52", feature = "_luaapi_54"))]
-                    std::ptr::null(),
-                );
-                (code, PushGuard { lua, size: 1, raw_lua })
+            match data.reader.read(&mut data.buffer) {
+                Ok(len) => *size = len as libc::size_t,
+                Err(e) => {
+                    *size = 0;
+                    data.triggered_error = Some(e);
+                },
             };
 
-            if read_data.triggered_error.is_some() {
-                let error = read_data.triggered_error.unwrap();
-                return Err((LuaError::ReadError(error), pushed_value.into_inner()));
-            }
+            data.buffer.as_ptr().cast::<libc::c_char>()
+        }
+    }
 
-            if load_retval == 0 {
-                return Ok(pushed_value);
-            }
+    let (load_retval, pushed_value) = {
+        let raw_lua = lua.as_mut_lua();
+        let code = ffi::lua_load(
+            raw_lua.as_ptr(),
+            Some(reader::<R>),
+            addr_of_mut!(read_data).cast(),
+            chunkname.as_ptr(),
+            #[cfg(any(feature = "_luaapi_52", feature = "_luaapi_54"))]
+            std::ptr::null(),
+        );
+        (code, PushGuard { lua, size: 1, raw_lua })
+    };
+
+    if read_data.triggered_error.is_some() {
+        let error = read_data.triggered_error.unwrap();
+        return Err((LuaError::ReadError(error), pushed_value.into_inner()));
+    }
 
-            let error_msg = LuaRead::lua_read(&pushed_value)
-                .ok()
-                .expect("can't find error message at the top of the Lua stack");
+    if load_retval == 0 {
+        flight_recorder::record(pushed_value.raw_lua, || {
+            FlightEvent::Chunk(chunkname.to_string_lossy().into_owned())
+        });
+        return Ok(pushed_value);
+    }
 
-            assert_ne!(load_retval, ffi::LUA_ERRMEM, "memory allocation error");
-            assert_eq!(load_retval, ffi::LUA_ERRSYNTAX, "unknown lua error");
+    let error_msg: String = LuaRead::lua_read(&pushed_value)
+        .ok()
+        .expect("can't find error message at the top of the Lua stack");
+    let error_msg = transform::remap_lines(pushed_value.raw_lua, error_msg);
+    flight_recorder::record(pushed_value.raw_lua, || FlightEvent::Error(error_msg.clone()));
 
-            Err((LuaError::SyntaxError(error_msg), pushed_value.into_inner()))
-        }
+    // Contexts with a memory limit can fail to allocate while parsing.
+    if load_retval == ffi::LUA_ERRMEM {
+        return Err((LuaError::MemoryLimitExceeded, pushed_value.into_inner()));
     }
-}
+    assert_eq!(load_retval, ffi::LUA_ERRSYNTAX, "unknown lua error");
 
-impl<'lua, L, R> PushOne<L> for LuaCodeFromReader<R>
-where
-    L: AsMutLua<'lua>,
-    R: Read,
-{
+    Err((LuaError::SyntaxError(ScriptError::new(error_msg)), pushed_value.into_inner()))
 }
 
 /// Handle to a function in the Lua context.
@@ -215,6 +270,7 @@ where
     ///
     /// > **Note**: In order to pass parameters, see `call_with_args` instead.
     #[inline]
+    #[track_caller]
     pub fn call<'a, V>(&'a mut self) -> Result<V, LuaError>
     where
         V: LuaRead<PushGuard<&'a mut L>>,
@@ -250,6 +306,7 @@ where
     /// assert_eq!(result, 14);
     /// ```
     #[inline]
+    #[track_caller]
     pub fn call_with_args<'a, V, A, E>(&'a mut self, args: A) -> Result<V, LuaFunctionCallError<E>>
     where
         A: for<'r> Push<&'r mut LuaFunction<L>, Err = E>,
@@ -259,12 +316,13 @@ where
         let (pcall_return_value, pushed_value) = unsafe {
             // lua_pcall pops the function, so we have to make a copy of it
             let raw_lua = self.variable.as_mut_lua();
+            record_call::<A, V>(raw_lua, Location::caller());
             ffi::lua_pushvalue(raw_lua.as_ptr(), -1);
             let num_pushed = match args.push_to_lua(self) {
                 Ok(g) => g.forget_internal(),
                 Err((err, _)) => return Err(LuaFunctionCallError::PushError(err)),
             };
-            let pcall_return_value = ffi::lua_pcall(raw_lua.as_ptr(), num_pushed, 1, 0); // TODO: num ret values
+            let pcall_return_value = pcall(raw_lua, num_pushed, 1); // TODO: num ret values
             let guard = PushGuard { lua: &mut self.variable, size: 1, raw_lua };
 
             (pcall_return_value, guard)
@@ -275,17 +333,174 @@ where
                 Err(_) => Err(LuaFunctionCallError::LuaError(LuaError::WrongType)),
                 Ok(x) => Ok(x),
             },
-            ffi::LUA_ERRMEM => panic!("lua_pcall returned LUA_ERRMEM"),
-            ffi::LUA_ERRRUN => {
-                let error_msg = LuaRead::lua_read(pushed_value)
-                    .ok()
-                    .expect("can't find error message at the top of the Lua stack");
-                Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(error_msg)))
+            ffi::LUA_ERRMEM => Err(LuaFunctionCallError::LuaError(LuaError::MemoryLimitExceeded)),
+            ffi::LUA_ERRRUN | ffi::LUA_ERRERR => {
+                Err(LuaFunctionCallError::LuaError(read_error(pushed_value)))
+            },
+            _ => panic!("Unknown error code returned by lua_pcall: {}", pcall_return_value),
+        }
+    }
+
+    /// Calls the function with parameters, keeping all of its return values.
+    ///
+    /// Contrary to `call_with_args`, which only keeps the first value returned by the function,
+    /// the values are read starting from the first one. This is meant to be used with tuples or
+    /// with structs that derive `LuaReadMulti`. If the function doesn't return anything, the
+    /// value is read with `LuaRead::lua_read_out_of_bounds`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("function sumdiff(a, b) return a + b, a - b end").unwrap();
+    ///
+    /// let mut sumdiff: hlua::LuaFunction<_> = lua.get("sumdiff").unwrap();
+    /// let (sum, diff): (i32, i32) = sumdiff.call_multi_with_args((17, 5)).unwrap();
+    /// assert_eq!((sum, diff), (22, 12));
+    /// ```
+    #[inline]
+    #[track_caller]
+    pub fn call_multi_with_args<'a, V, A, E>(
+        &'a mut self,
+        args: A,
+    ) -> Result<V, LuaFunctionCallError<E>>
+    where
+        A: for<'r> Push<&'r mut LuaFunction<L>, Err = E>,
+        V: LuaRead<PushGuard<&'a mut L>>,
+    {
+        let (pcall_return_value, pushed_value) = unsafe {
+            let raw_lua = self.variable.as_mut_lua();
+            let top = ffi::lua_gettop(raw_lua.as_ptr());
+            record_call::<A, V>(raw_lua, Location::caller());
+
+            // lua_pcall pops the function, so we have to make a copy of it
+            ffi::lua_pushvalue(raw_lua.as_ptr(), -1);
+            let num_pushed = match args.push_to_lua(self) {
+                Ok(g) => g.forget_internal(),
+                Err((err, _)) => return Err(LuaFunctionCallError::PushError(err)),
+            };
+            let pcall_return_value = pcall(raw_lua, num_pushed, ffi::LUA_MULTRET);
+            let size = ffi::lua_gettop(raw_lua.as_ptr()) - top;
+            let guard = PushGuard { lua: &mut self.variable, size, raw_lua };
+
+            (pcall_return_value, guard)
+        };
+
+        match pcall_return_value {
+            0 => {
+                let read = match pushed_value.size {
+                    0 => LuaRead::lua_read_out_of_bounds(pushed_value),
+                    size => LuaRead::lua_read_at_position(pushed_value, -size),
+                };
+                read.map_err(|_| LuaFunctionCallError::LuaError(LuaError::WrongType))
+            },
+            ffi::LUA_ERRMEM => Err(LuaFunctionCallError::LuaError(LuaError::MemoryLimitExceeded)),
+            ffi::LUA_ERRRUN | ffi::LUA_ERRERR => {
+                Err(LuaFunctionCallError::LuaError(read_error(pushed_value)))
             },
             _ => panic!("Unknown error code returned by lua_pcall: {}", pcall_return_value),
         }
     }
 
+    /// Calls the function without arguments, with the function at the absolute index `msgh` as
+    /// message handler.
+    ///
+    /// Returns the code returned by `lua_pcall`, and a guard holding either the first value
+    /// returned by the function or the error object.
+    pub(crate) unsafe fn call_with_message_handler(
+        &mut self,
+        msgh: libc::c_int,
+    ) -> (libc::c_int, PushGuard<&mut L>) {
+        // lua_pcall pops the function, so we have to make a copy of it
+        let raw_lua = self.variable.as_mut_lua();
+        ffi::lua_pushvalue(raw_lua.as_ptr(), -1);
+        let pcall_return_value = pcall_with_handler(raw_lua, 0, 1, msgh);
+        (pcall_return_value, PushGuard { lua: &mut self.variable, size: 1, raw_lua })
+    }
+
+    /// Writes the bytecode of the function to `writer`, as Lua produces it.
+    ///
+    /// The bytecode can be loaded back with `load_from_reader`, and doesn't need to be kept in
+    /// memory at once. If `strip_debug` is true, the debug information, such as line numbers and
+    /// the names of local variables, is left out, which makes the output smaller but the error
+    /// messages less precise. The C API of Lua 5.2 and LuaJIT can't strip the debug information,
+    /// so the flag is ignored with them.
+    ///
+    /// Returns an error if writing fails, or if the function isn't a Lua function.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// let mut bytecode = Vec::new();
+    /// {
+    ///     let mut f = hlua::LuaFunction::load(&mut lua, "return 6 * 7").unwrap();
+    ///     f.dump_to(&mut bytecode, true).unwrap();
+    /// }
+    ///
+    /// let mut f = hlua::LuaFunction::load_from_reader(&mut lua, Cursor::new(bytecode)).unwrap();
+    /// assert_eq!(f.call::<i32>().unwrap(), 42);
+    /// ```
+    pub fn dump_to<W>(&mut self, writer: W, strip_debug: bool) -> Result<(), IoError>
+    where
+        W: Write,
+    {
+        struct WriteData<W> {
+            writer: W,
+            triggered_error: Option<IoError>,
+        }
+
+        unsafe extern "C" fn write<W>(
+            _: *mut ffi::lua_State,
+            p: *const libc::c_void,
+            size: libc::size_t,
+            data: *mut libc::c_void,
+        ) -> libc::c_int
+        where
+            W: Write,
+        {
+            let data = &mut *data.cast::<WriteData<W>>();
+            let chunk = std::slice::from_raw_parts(p.cast::<u8>(), size);
+            match data.writer.write_all(chunk) {
+                Ok(()) => 0,
+                Err(err) => {
+                    data.triggered_error = Some(err);
+                    1
+                },
+            }
+        }
+
+        let mut data = WriteData { writer, triggered_error: None };
+        let raw_lua = self.variable.as_mut_lua();
+        let status = unsafe {
+            if !ffi::lua_isfunction(raw_lua.as_ptr(), -1)
+                || ffi::lua_iscfunction(raw_lua.as_ptr(), -1) != 0
+            {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    "only Lua functions can be dumped",
+                ));
+            }
+            #[cfg(not(feature = "_luaapi_54"))]
+            let _ = strip_debug;
+            ffi::lua_dump(
+                raw_lua.as_ptr(),
+                Some(write::<W>),
+                addr_of_mut!(data).cast(),
+                #[cfg(feature = "_luaapi_54")]
+                (strip_debug as libc::c_int),
+            )
+        };
+
+        match (data.triggered_error, status) {
+            (Some(err), _) => Err(err),
+            (None, 0) => Ok(()),
+            (None, _) => Err(IoError::other("the function can't be dumped")),
+        }
+    }
+
     /// Builds a new `LuaFunction` from the code of a reader.
     ///
     /// Returns an error if reading from the `Read` object fails or if there is a syntax error in
@@ -323,8 +538,158 @@ where
         let reader = Cursor::new(code.as_bytes());
         LuaFunction::load_from_reader(lua, reader)
     }
+
+    /// Builds a new `LuaFunction` from a raw string, giving a name to the chunk.
+    ///
+    /// The name appears in error messages and tracebacks instead of `chunk`. Following the Lua
+    /// conventions, names starting with `@` are displayed as file names and names starting with
+    /// `=` are displayed as they are.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    ///
+    /// let mut f = hlua::LuaFunction::load_named(&mut lua, "@init.lua", "return x.y").unwrap();
+    /// let err = f.call::<()>().unwrap_err();
+    /// assert!(err.to_string().contains("init.lua:1:"));
+    /// ```
+    #[inline]
+    pub fn load_named(
+        lua: L,
+        name: &str,
+        code: &str,
+    ) -> Result<LuaFunction<PushGuard<L>>, LuaError> {
+        let name = CString::new(name.replace('\0', "")).unwrap();
+        match unsafe { load_chunk(lua, Cursor::new(code.as_bytes()), &name) } {
+            Ok(pushed) => Ok(LuaFunction { variable: pushed }),
+            Err((err, _)) => Err(err),
+        }
+    }
+}
+
+impl<'lua, L> LuaFunction<L>
+where
+    L: AsMutLua<'lua>,
+{
+    /// Stores the function in the registry and returns a handle to it that doesn't borrow the
+    /// context. See `OwnedLuaFunction`.
+    #[inline]
+    pub fn into_owned(mut self) -> OwnedLuaFunction {
+        match LuaRead::lua_read(&mut self.variable) {
+            Ok(reference) => OwnedLuaFunction { reference },
+            Err(_) => unreachable!("reading a LuaRef never fails"),
+        }
+    }
+}
+
+/// Handle to a Lua function that doesn't borrow the Lua context, created with
+/// `LuaFunction::into_owned` or by reading a function.
+///
+/// The function is stored in the registry and kept alive for as long as the handle exists, and
+/// the context is only needed to call it. This makes it possible to keep Lua callbacks in the
+/// structures of the application, such as an event system. It must only be called with the
+/// context it was created from.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, OwnedLuaFunction};
+///
+/// let mut lua = Lua::new();
+/// lua.execute::<()>("function on_event(name) return 'got ' .. name end").unwrap();
+///
+/// let mut listeners: Vec<OwnedLuaFunction> = Vec::new();
+/// listeners.push(lua.get("on_event").unwrap());
+/// lua.execute::<()>("on_event = nil").unwrap();
+///
+/// for listener in &listeners {
+///     let reply: String = listener.call_with_args(&mut lua, "click").unwrap();
+///     assert_eq!(reply, "got click");
+/// }
+/// ```
+#[derive(Debug)]
+pub struct OwnedLuaFunction {
+    reference: LuaRef,
 }
 
+impl OwnedLuaFunction {
+    /// Calls the function without arguments. See `LuaFunction::call`.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `lua` isn't the context the function was created from.
+    #[inline]
+    #[track_caller]
+    pub fn call<'lua, L, V>(&self, lua: L) -> Result<V, LuaError>
+    where
+        L: AsMutLua<'lua>,
+        V: for<'a> LuaRead<PushGuard<&'a mut PushGuard<L>>>,
+    {
+        self.function(lua).call()
+    }
+
+    /// Calls the function with arguments. See `LuaFunction::call_with_args`.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `lua` isn't the context the function was created from.
+    #[inline]
+    #[track_caller]
+    pub fn call_with_args<'lua, L, V, A, E>(
+        &self,
+        lua: L,
+        args: A,
+    ) -> Result<V, LuaFunctionCallError<E>>
+    where
+        L: AsMutLua<'lua>,
+        A: for<'r> Push<&'r mut LuaFunction<PushGuard<L>>, Err = E>,
+        V: for<'a> LuaRead<PushGuard<&'a mut PushGuard<L>>>,
+    {
+        self.function(lua).call_with_args(args)
+    }
+
+    /// Pushes the function and returns it as a `LuaFunction`.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `lua` isn't the context the function was created from.
+    #[inline]
+    pub fn function<'lua, L>(&self, lua: L) -> LuaFunction<PushGuard<L>>
+    where
+        L: AsMutLua<'lua>,
+    {
+        LuaFunction { variable: self.reference.push_no_err(lua) }
+    }
+}
+
+impl<'lua, L> LuaRead<L> for OwnedLuaFunction
+where
+    L: AsMutLua<'lua>,
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<OwnedLuaFunction, L> {
+        if !unsafe { ffi::lua_isfunction(lua.as_mut_lua().as_ptr(), index) } {
+            return Err(lua);
+        }
+        LuaRead::lua_read_at_position(lua, index).map(|reference| OwnedLuaFunction { reference })
+    }
+}
+
+impl<'lua, L> Push<L> for &OwnedLuaFunction
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void; // TODO: use `!` instead (https://github.com/rust-lang/rust/issues/35121)
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        (&self.reference).push_to_lua(lua)
+    }
+}
+
+impl<'lua, L> PushOne<L> for &OwnedLuaFunction where L: AsMutLua<'lua> {}
+
 /// Error that can happen when calling a `LuaFunction`.
 // TODO: implement Error on this
 #[derive(Debug)]
@@ -385,6 +750,127 @@ where
     }
 }
 
+// Counts the conversions of the arguments and of the results of a call.
+#[inline]
+unsafe fn record_call<A, V>(lua: LuaContext, location: &'static Location<'static>) {
+    profiling::record::<A>(lua, ConversionDirection::Push, location);
+    profiling::record::<V>(lua, ConversionDirection::Read, location);
+}
+
+// Reads the error raised by a call, at the top of the stack.
+pub(crate) fn read_error<'lua, L>(pushed_value: PushGuard<L>) -> LuaError
+where
+    L: AsMutLua<'lua>,
+{
+    if let Some(code) = unsafe { exit::requested_exit(pushed_value.as_lua(), -1) } {
+        return LuaError::ExitRequested(code);
+    }
+    if let Some(err) = unsafe { raise::raised_error(pushed_value.as_lua(), -1) } {
+        return LuaError::RustError(err);
+    }
+    let error_msg: String = LuaRead::lua_read(pushed_value)
+        .ok()
+        .expect("can't find error message at the top of the Lua stack");
+    LuaError::ExecutionError(ScriptError::new(error_msg))
+}
+
+// Calls the function below the arguments at the top of the stack, marking the context as busy
+// for the duration of the call.
+//
+// The error messages are given a traceback of the stack frames that raised them, which
+// `ScriptError` splits from the message.
+#[inline]
+pub(crate) unsafe fn pcall(
+    lua: LuaContext,
+    nargs: libc::c_int,
+    nresults: libc::c_int,
+) -> libc::c_int {
+    let l = lua.as_ptr();
+    let msgh = ffi::lua_gettop(l) - nargs;
+    ffi::lua_pushcfunction(l, Some(traceback_handler));
+    ffix::lua_insert(l, msgh);
+    let pcall_return_value = pcall_with_handler(lua, nargs, nresults, msgh);
+    ffix::lua_remove(l, msgh);
+    pcall_return_value
+}
+
+// Message handler that appends a traceback to error messages. The other error objects, and the
+// exit requests, are left as they are.
+extern "C" fn traceback_handler(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let requested_exit = exit::requested_exit(LuaContext::new_unchecked(lua), 1).is_some();
+        match ffi::lua_type(lua, 1) {
+            ffi::LUA_TSTRING | ffi::LUA_TNUMBER if !requested_exit => {
+                ffi::luaL_traceback(lua, lua, ffi::lua_tostring(lua, 1), 1);
+            },
+            _ => ffi::lua_pushvalue(lua, 1),
+        }
+        1
+    }
+}
+
+// Same as `pcall`, but with `msgh` as the index of the message handler.
+unsafe fn pcall_with_handler(
+    lua: LuaContext,
+    nargs: libc::c_int,
+    nresults: libc::c_int,
+    msgh: libc::c_int,
+) -> libc::c_int {
+    let _busy = BusyGuard::enter(lua);
+    let _snapshot = SnapshotGuard::enter(lua);
+    let _locale = NumericLocaleGuard::enter(lua);
+    let _budget = InstructionBudget::enter(lua, lua);
+    let _borrows = BorrowWatermark::enter();
+    let exit_pending = exit::exit_pending(lua);
+    let base = ffi::lua_gettop(lua.as_ptr()) - nargs - 1;
+    let mut pcall_return_value = ffi::lua_pcall(lua.as_ptr(), nargs, nresults, msgh);
+
+    // The script may have caught an exit request, which must still stop the call.
+    if !exit_pending {
+        let count = match pcall_return_value {
+            0 => ffi::lua_gettop(lua.as_ptr()) - base,
+            _ => 1,
+        };
+        pcall_return_value = exit::take_swallowed_exit(lua, pcall_return_value, count);
+    }
+
+    if pcall_return_value != 0 {
+        transform::remap_error(lua);
+        flight_recorder::record(lua, || FlightEvent::Error(flight_recorder::error_message(lua)));
+    }
+
+    pcall_return_value
+}
+
+// Number of `GuardedCalls` alive. Lets the guards entered around calls into Lua skip their
+// registry lookups in the common case where no context uses the features that need them.
+static GUARDED_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Kept by a context for as long as it uses a feature that needs a guard around calls into Lua:
+/// handles, exported globals, the C numeric locale and instruction limits.
+pub(crate) struct GuardedCalls(());
+
+impl GuardedCalls {
+    #[inline]
+    pub(crate) fn new() -> GuardedCalls {
+        GUARDED_CALLS.fetch_add(1, Ordering::Relaxed);
+        GuardedCalls(())
+    }
+
+    /// Returns false if no context needs the guards, which can then do nothing.
+    #[inline]
+    pub(crate) fn any() -> bool {
+        GUARDED_CALLS.load(Ordering::Relaxed) != 0
+    }
+}
+
+impl Drop for GuardedCalls {
+    #[inline]
+    fn drop(&mut self) {
+        GUARDED_CALLS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 impl<'lua, L> LuaRead<L> for LuaFunction<L>
 where
     L: AsMutLua<'lua>,
@@ -409,6 +895,23 @@ mod tests {
         io::{Error as IoError, ErrorKind as IoErrorKind, Read},
     };
 
+    #[test]
+    fn owned_function() {
+        let mut lua = Lua::new();
+        let mut counter = 0;
+        let f = LuaFunction::load(&mut lua, "local n = ... return n * 2").unwrap().into_owned();
+        for n in 0..3 {
+            counter += f.call_with_args::<_, i32, _, _>(&mut lua, n).unwrap();
+        }
+        assert_eq!(counter, 6);
+
+        lua.set("f", &f);
+        assert_eq!(lua.execute::<i32>("return f(5)").unwrap(), 10);
+        assert!(lua.get::<crate::OwnedLuaFunction, _>("missing").is_none());
+        lua.set("n", 1);
+        assert!(lua.get::<crate::OwnedLuaFunction, _>("n").is_none());
+    }
+
     #[test]
     fn basic() {
         let mut lua = Lua::new();
@@ -508,4 +1011,75 @@ mod tests {
         _assert(LuaFunctionCallError::LuaError::<Void>(LuaError::WrongType));
         _assert(LuaFunctionCallError::PushError(IoError::new(IoErrorKind::Other, "Test")));
     }
+
+    #[test]
+    fn dump_and_reload() {
+        use std::io::Cursor;
+
+        let mut lua = Lua::new();
+        let mut full = Vec::new();
+        let mut stripped = Vec::new();
+        {
+            let code = "local a = 5\nreturn function(b) return a + b end";
+            let mut f = LuaFunction::load(&mut lua, code).unwrap();
+            f.dump_to(&mut full, false).unwrap();
+            f.dump_to(&mut stripped, true).unwrap();
+        }
+        #[cfg(feature = "_luaapi_54")]
+        assert!(stripped.len() < full.len());
+
+        for bytecode in [full, stripped] {
+            let mut f = LuaFunction::load_from_reader(&mut lua, Cursor::new(bytecode)).unwrap();
+            let mut adder: LuaFunction<_> = f.call().unwrap();
+            assert_eq!(adder.call_with_args::<i32, _, _>(1).unwrap(), 6);
+        }
+    }
+
+    #[test]
+    fn dump_errors() {
+        struct FailingWriter;
+
+        impl std::io::Write for FailingWriter {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(IoError::new(IoErrorKind::Other, "disk full"))
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut lua = Lua::new();
+        {
+            let mut f = LuaFunction::load(&mut lua, "return 1").unwrap();
+            assert_eq!(f.dump_to(FailingWriter, false).unwrap_err().to_string(), "disk full");
+        }
+
+        lua.set("native", crate::function0(|| 1));
+        let mut native: LuaFunction<_> = lua.get("native").unwrap();
+        assert_eq!(
+            native.dump_to(Vec::new(), false).unwrap_err().kind(),
+            IoErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn execution_errors_have_tracebacks() {
+        let mut lua = Lua::new();
+        let code = "local function explode(n) return n .. {} end
+                    function update(n) local result = explode(n) return result end";
+        lua.execute::<()>(code).unwrap();
+
+        let mut update: LuaFunction<_> = lua.get("update").unwrap();
+        let err = match update.call_with_args::<(), _, _>(5) {
+            Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(err))) => err,
+            _ => panic!("update didn't fail"),
+        };
+        assert!(err.message().contains("attempt to concatenate"), "{}", err);
+        assert!(!err.message().contains("stack traceback"), "{}", err);
+        let traceback = err.traceback().unwrap();
+        assert!(traceback.starts_with("stack traceback:\n"), "{}", traceback);
+        assert!(traceback.contains("'explode'"), "{}", traceback);
+        assert!(traceback.contains("[string \"chunk\"]:2:"), "{}", traceback);
+    }
 }
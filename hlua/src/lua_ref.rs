@@ -0,0 +1,456 @@
+use std::{
+    ffi::CStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{AsMutLua, LuaContext, LuaRead, Push, PushGuard, PushOne, Void};
+
+// Key of the registry entry holding the references released by dropped `LuaRef`s.
+const RELEASED_KEY: &CStr = c"hlua.released_refs";
+
+// Key of the registry entry holding the weak-valued table used by `WeakLuaRef`.
+const WEAK_KEY: &CStr = c"hlua.weak_refs";
+
+// References whose `LuaRef` or `WeakLuaRef` has been dropped, but that haven't been removed from
+// the registry yet. Dropping a reference doesn't require access to the Lua context, so the actual
+// cleanup is done the next time a reference is created.
+pub(crate) type ReleasedRefs = Arc<Mutex<Released>>;
+
+#[derive(Debug, Default)]
+pub(crate) struct Released {
+    strong: Vec<libc::c_int>,
+    weak: Vec<libc::c_int>,
+    // Last key used in the weak-valued table. Keys aren't reused, since a collected value leaves
+    // no trace that `luaL_ref` could use to tell that a `WeakLuaRef` still holds its key.
+    last_weak: libc::c_int,
+}
+
+/// Handle to a Lua value stored in the registry.
+///
+/// Contrary to `LuaTable` or `LuaFunction`, a `LuaRef` doesn't borrow the Lua context. This
+/// makes it possible to store it in a Rust struct and push the value back later. The value is
+/// kept alive for as long as the `LuaRef` exists.
+///
+/// A `LuaRef` can be obtained by reading any value, and is pushed back by pushing a reference
+/// to it. It must only be pushed to the Lua context it was created from.
+///
+/// # Example
+///
+/// ```
+/// let mut lua = hlua::Lua::new();
+/// lua.execute::<()>("a = { 1, 2, 3 }").unwrap();
+///
+/// let table: hlua::LuaRef = lua.get("a").unwrap();
+/// lua.set("a", hlua::LuaNil);
+///
+/// lua.set("b", &table);
+/// let value: i32 = lua.execute("return b[2]").unwrap();
+/// assert_eq!(value, 2);
+/// ```
+#[derive(Debug)]
+pub struct LuaRef {
+    reference: libc::c_int,
+    released: ReleasedRefs,
+}
+
+impl LuaRef {
+    /// Pushes `value` and stores it in the registry.
+    #[inline]
+    pub fn new<'lua, L, V, E>(lua: L, value: V) -> LuaRef
+    where
+        L: AsMutLua<'lua>,
+        V: PushOne<L, Err = E>,
+        E: Into<Void>,
+    {
+        match LuaRead::lua_read(value.push_no_err(lua)) {
+            Ok(r) => r,
+            Err(_) => unreachable!("reading a LuaRef never fails"),
+        }
+    }
+
+    /// Pushes the value of the reference and reads it as a `V`.
+    ///
+    /// This gives back a `LuaTable` or a `LuaFunction` borrowing `lua`, from a reference that can
+    /// be stored anywhere.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, LuaFunction, LuaRef};
+    ///
+    /// struct Handlers {
+    ///     on_click: LuaRef,
+    /// }
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.execute::<()>("function on_click(x) return x * 2 end").unwrap();
+    /// let handlers = Handlers { on_click: lua.get("on_click").unwrap() };
+    ///
+    /// let mut on_click: LuaFunction<_> = handlers.on_click.get(&mut lua).unwrap();
+    /// assert_eq!(on_click.call_with_args::<i32, _, _>(21).unwrap(), 42);
+    /// ```
+    ///
+    /// # Panic
+    ///
+    /// Panics if `lua` isn't the context this reference was created from.
+    #[inline]
+    pub fn get<'lua, L, V>(&self, lua: L) -> Option<V>
+    where
+        L: AsMutLua<'lua>,
+        V: LuaRead<PushGuard<L>>,
+    {
+        LuaRead::lua_read(self.push_no_err(lua)).ok()
+    }
+
+    /// Creates another reference to the same value.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `lua` isn't the context this reference was created from.
+    #[inline]
+    pub fn duplicate<'lua, L>(&self, lua: L) -> LuaRef
+    where
+        L: AsMutLua<'lua>,
+    {
+        LuaRef::new(lua, self)
+    }
+
+    /// Returns true if the value is nil.
+    #[inline]
+    pub fn is_nil(&self) -> bool {
+        self.reference == ffi::LUA_REFNIL
+    }
+
+    /// Returns the list of released references of this Lua context, creating it if necessary.
+    unsafe fn released_refs(lua: LuaContext) -> ReleasedRefs {
+        let raw_lua = lua.as_ptr();
+        ffi::lua_getfield(raw_lua, ffi::LUA_REGISTRYINDEX, RELEASED_KEY.as_ptr());
+        let existing = userdata_mut::<ReleasedRefs>(lua, -1).map(|r| r.clone());
+        ffi::lua_pop(raw_lua, 1);
+
+        existing.unwrap_or_else(|| {
+            let released = ReleasedRefs::default();
+            push_userdata(released.clone(), lua, |_| {}).forget();
+            ffi::lua_setfield(raw_lua, ffi::LUA_REGISTRYINDEX, RELEASED_KEY.as_ptr());
+            released
+        })
+    }
+
+    /// Panics if this reference doesn't belong to the given context.
+    unsafe fn check_context(&self, lua: LuaContext) {
+        assert!(
+            Arc::ptr_eq(&self.released, &LuaRef::released_refs(lua)),
+            "LuaRef pushed to a different Lua context"
+        );
+    }
+
+    /// Creates a weak reference to the same value.
+    ///
+    /// Contrary to the `LuaRef`, the `WeakLuaRef` doesn't keep the value alive.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `lua` isn't the context this reference was created from.
+    pub fn downgrade<'lua, L>(&self, mut lua: L) -> WeakLuaRef
+    where
+        L: AsMutLua<'lua>,
+    {
+        unsafe {
+            let raw_lua = lua.as_mut_lua();
+            self.check_context(raw_lua);
+            release_dropped(raw_lua);
+
+            let reference = {
+                let mut released = self.released.lock().unwrap();
+                released.last_weak = released.last_weak.checked_add(1).expect("too many weak refs");
+                released.last_weak
+            };
+
+            push_weak_table(raw_lua);
+            ffi::lua_rawgeti(raw_lua.as_ptr(), ffi::LUA_REGISTRYINDEX, self.reference as _);
+            ffi::lua_rawseti(raw_lua.as_ptr(), -2, reference as _);
+            ffi::lua_pop(raw_lua.as_ptr(), 1);
+
+            WeakLuaRef { reference, released: self.released.clone() }
+        }
+    }
+}
+
+/// Weak handle to a Lua value, created with `LuaRef::downgrade`.
+///
+/// A `WeakLuaRef` doesn't keep the value alive, which makes it possible to cache values coming
+/// from scripts without preventing them from being collected. Use `upgrade` to get access to the
+/// value again.
+///
+/// Only tables, functions, userdata and coroutines can be collected. Other values such as
+/// numbers and strings stay accessible for as long as the `WeakLuaRef` exists.
+///
+/// # Example
+///
+/// ```
+/// let mut lua = hlua::Lua::new();
+/// lua.execute::<()>("obj = {}").unwrap();
+///
+/// let obj: hlua::LuaRef = lua.get("obj").unwrap();
+/// let weak = obj.downgrade(&mut lua);
+/// assert!(weak.upgrade(&mut lua).is_some());
+///
+/// drop(obj);
+/// lua.set("obj", hlua::LuaNil);
+/// lua.collect_garbage();
+/// assert!(weak.upgrade(&mut lua).is_none());
+/// ```
+#[derive(Debug)]
+pub struct WeakLuaRef {
+    reference: libc::c_int,
+    released: ReleasedRefs,
+}
+
+impl WeakLuaRef {
+    /// Returns a strong reference to the value if it is still alive.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `lua` isn't the context this reference was created from.
+    pub fn upgrade<'lua, L>(&self, mut lua: L) -> Option<LuaRef>
+    where
+        L: AsMutLua<'lua>,
+    {
+        unsafe {
+            let raw_lua = lua.as_mut_lua();
+            assert!(
+                Arc::ptr_eq(&self.released, &LuaRef::released_refs(raw_lua)),
+                "WeakLuaRef upgraded with a different Lua context"
+            );
+
+            push_weak_table(raw_lua);
+            ffi::lua_rawgeti(raw_lua.as_ptr(), -1, self.reference as _);
+            let mut guard = PushGuard { lua: raw_lua, size: 2, raw_lua };
+
+            match ffi::lua_isnil(raw_lua.as_ptr(), -1) {
+                true => None,
+                false => LuaRead::lua_read(&mut guard).ok(),
+            }
+        }
+    }
+}
+
+impl Drop for WeakLuaRef {
+    #[inline]
+    fn drop(&mut self) {
+        if let Ok(mut released) = self.released.lock() {
+            released.weak.push(self.reference);
+        }
+    }
+}
+
+/// Removes the values of the `LuaRef`s that have been dropped from the registry.
+///
+/// Returns the list of released references of the context for convenience.
+pub(crate) unsafe fn release_dropped(lua: LuaContext) -> ReleasedRefs {
+    let released = LuaRef::released_refs(lua);
+    let mut lists = released.lock().unwrap();
+
+    for reference in lists.strong.drain(..) {
+        ffi::luaL_unref(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, reference);
+    }
+
+    if !lists.weak.is_empty() {
+        push_weak_table(lua);
+        for reference in lists.weak.drain(..) {
+            ffi::lua_pushnil(lua.as_ptr());
+            ffi::lua_rawseti(lua.as_ptr(), -2, reference as _);
+        }
+        ffi::lua_pop(lua.as_ptr(), 1);
+    }
+
+    drop(lists);
+    released
+}
+
+// Pushes the weak-valued table of the context, creating it if necessary.
+unsafe fn push_weak_table(lua: LuaContext) {
+    let raw_lua = lua.as_ptr();
+    ffi::lua_getfield(raw_lua, ffi::LUA_REGISTRYINDEX, WEAK_KEY.as_ptr());
+
+    if ffi::lua_isnil(raw_lua, -1) {
+        ffi::lua_pop(raw_lua, 1);
+        ffi::lua_newtable(raw_lua);
+        ffi::lua_newtable(raw_lua);
+        "v".push_no_err(lua).forget();
+        ffi::lua_setfield(raw_lua, -2, c"__mode".as_ptr());
+        ffi::lua_setmetatable(raw_lua, -2);
+        ffi::lua_pushvalue(raw_lua, -1);
+        ffi::lua_setfield(raw_lua, ffi::LUA_REGISTRYINDEX, WEAK_KEY.as_ptr());
+    }
+}
+
+impl<'lua, L> LuaRead<L> for LuaRef
+where
+    L: AsMutLua<'lua>,
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<LuaRef, L> {
+        unsafe {
+            let raw_lua = lua.as_mut_lua();
+            let released = release_dropped(raw_lua);
+
+            ffi::lua_pushvalue(raw_lua.as_ptr(), index);
+            let reference = ffi::luaL_ref(raw_lua.as_ptr(), ffi::LUA_REGISTRYINDEX);
+            Ok(LuaRef { reference, released })
+        }
+    }
+}
+
+impl<'lua, L> Push<L> for &LuaRef
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void; // TODO: use `!` instead (https://github.com/rust-lang/rust/issues/35121)
+
+    #[inline]
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        unsafe {
+            let raw_lua = lua.as_mut_lua();
+            self.check_context(raw_lua);
+            ffi::lua_rawgeti(raw_lua.as_ptr(), ffi::LUA_REGISTRYINDEX, self.reference as _);
+            Ok(PushGuard { lua, size: 1, raw_lua })
+        }
+    }
+}
+
+impl<'lua, L> PushOne<L> for &LuaRef where L: AsMutLua<'lua> {}
+
+impl Drop for LuaRef {
+    #[inline]
+    fn drop(&mut self) {
+        // `LUA_REFNIL` is a constant that doesn't need to be released.
+        if self.reference != ffi::LUA_REFNIL {
+            if let Ok(mut released) = self.released.lock() {
+                released.strong.push(self.reference);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, LuaFunction, LuaRef, LuaTable};
+
+    #[test]
+    fn outlives_stack_slot() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = { 5 }").unwrap();
+
+        let table: LuaRef = lua.get("a").unwrap();
+        lua.set("a", crate::LuaNil);
+        lua.collect_garbage();
+
+        lua.set("b", &table);
+        let mut b: LuaTable<_> = lua.get("b").unwrap();
+        assert_eq!(b.get::<i32, _, _>(1), Some(5));
+    }
+
+    #[test]
+    fn stored_function() {
+        let mut lua = Lua::new();
+        let f = LuaRef::new(&mut lua, crate::function1(|a: i32| a * 2));
+
+        lua.set("f", &f);
+        let mut f: LuaFunction<_> = lua.get("f").unwrap();
+        assert_eq!(f.call_with_args::<i32, _, _>(21).unwrap(), 42);
+    }
+
+    #[test]
+    fn released_refs_are_reused() {
+        let mut lua = Lua::new();
+
+        let first = LuaRef::new(&mut lua, "hello");
+        let reference = first.reference;
+        drop(first);
+
+        let second = LuaRef::new(&mut lua, "world");
+        assert_eq!(second.reference, reference);
+    }
+
+    #[test]
+    fn weak_upgrade() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = { 7 }").unwrap();
+
+        let strong: LuaRef = lua.get("a").unwrap();
+        let weak = strong.downgrade(&mut lua);
+        lua.set("a", crate::LuaNil);
+        lua.collect_garbage();
+
+        // Still alive thanks to `strong`.
+        let upgraded = weak.upgrade(&mut lua).unwrap();
+        lua.set("b", &upgraded);
+        let value: i32 = lua.execute("return b[1]").unwrap();
+        assert_eq!(value, 7);
+
+        drop((strong, upgraded));
+        lua.set("b", crate::LuaNil);
+        lua.collect_garbage();
+        assert!(weak.upgrade(&mut lua).is_none());
+    }
+
+    #[test]
+    fn weak_keys_not_reused() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = { name = 'a' } b = { name = 'b' } d = { name = 'd' }").unwrap();
+
+        let a: LuaRef = lua.get("a").unwrap();
+        let b: LuaRef = lua.get("b").unwrap();
+        let wa = a.downgrade(&mut lua);
+        let wb = b.downgrade(&mut lua);
+
+        drop(b);
+        lua.set("b", crate::LuaNil);
+        lua.collect_garbage();
+
+        let d: LuaRef = lua.get("d").unwrap();
+        let wd = d.downgrade(&mut lua);
+        assert!(wb.upgrade(&mut lua).is_none());
+
+        // Releasing the stale handle doesn't affect the live ones.
+        drop(wb);
+        lua.set("a", crate::LuaNil);
+        lua.execute::<()>("d = nil").unwrap();
+        lua.collect_garbage();
+        let d = wd.upgrade(&mut lua).unwrap();
+        lua.set("d", &d);
+        assert_eq!(lua.execute::<String>("return d.name").unwrap(), "d");
+        assert!(wa.upgrade(&mut lua).is_some());
+    }
+
+    #[test]
+    fn typed_access() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = { 3, 4 }").unwrap();
+        let table: LuaRef = lua.get("a").unwrap();
+        let copy = table.duplicate(&mut lua);
+        drop(table);
+        lua.set("a", crate::LuaNil);
+        lua.collect_garbage();
+
+        let mut a: LuaTable<_> = copy.get(&mut lua).unwrap();
+        assert_eq!(a.get::<i32, _, _>(2), Some(4));
+        drop(a);
+        assert_eq!(copy.get::<_, i32>(&mut lua), None);
+
+        let nil = LuaRef::new(&mut lua, crate::LuaNil);
+        assert!(nil.is_nil() && !copy.is_nil());
+    }
+
+    #[test]
+    #[should_panic]
+    fn wrong_context() {
+        let mut lua1 = Lua::new();
+        let mut lua2 = Lua::new();
+
+        let value = LuaRef::new(&mut lua1, 5);
+        lua2.set("a", &value);
+    }
+}
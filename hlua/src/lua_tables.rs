@@ -1,6 +1,7 @@
-use std::marker::PhantomData;
+use std::{error::Error, fmt, marker::PhantomData, panic::Location};
 
-use crate::LuaContext;
+use crate::profiling::{self, ConversionDirection};
+use crate::{ffix, sorted_iteration, LuaContext};
 
 use crate::{AsLua, AsMutLua, LuaRead, Push, PushGuard, PushOne, Void};
 
@@ -99,8 +100,19 @@ where
     pub fn iter<K, V>(&mut self) -> LuaTableIterator<L, K, V> {
         unsafe {
             let raw_lua = self.table.as_mut_lua();
-            ffi::lua_pushnil(raw_lua.as_ptr());
-            LuaTableIterator { table: self, finished: false, raw_lua, marker: PhantomData }
+            let sorted = if sorted_iteration::sorted_iteration(raw_lua) {
+                let index = self.offset(0);
+                let index = match index >= 0 || index == ffi::LUA_REGISTRYINDEX {
+                    true => index,
+                    false => ffi::lua_gettop(raw_lua.as_ptr()) + index + 1,
+                };
+                let order = sorted_iteration::push_sorted_keys(raw_lua, index);
+                Some(SortedKeys { table: index, order, next: 0 })
+            } else {
+                ffi::lua_pushnil(raw_lua.as_ptr());
+                None
+            };
+            LuaTableIterator { table: self, finished: false, raw_lua, sorted, marker: PhantomData }
         }
     }
 
@@ -130,6 +142,7 @@ where
     /// ```
     ///
     #[inline]
+    #[track_caller]
     pub fn get<'a, R, I, E>(&'a mut self, index: I) -> Option<R>
     where
         R: LuaRead<PushGuard<&'a mut LuaTable<L>>>,
@@ -144,6 +157,7 @@ where
             //       because of the empty_array method
             let mut me = self;
             let raw_lua = me.as_mut_lua();
+            profiling::record::<R>(raw_lua, ConversionDirection::Read, Location::caller());
 
             index.push_no_err(&mut me).assert_one_and_forget();
             ffi::lua_gettable(raw_lua.as_ptr(), me.offset(-1));
@@ -183,6 +197,7 @@ where
     /// information.
     // TODO: doc
     #[inline]
+    #[track_caller]
     pub fn set<I, V, Ei, Ev>(&mut self, index: I, value: V)
     where
         I: for<'r> PushOne<&'r mut LuaTable<L>, Err = Ei>,
@@ -201,6 +216,7 @@ where
     /// limited set of types. You are encouraged to use the `set` method if writing cannot fail.
     // TODO: doc
     #[inline]
+    #[track_caller]
     pub fn checked_set<I, V, Ke, Ve>(
         &mut self,
         index: I,
@@ -213,6 +229,7 @@ where
         unsafe {
             let raw_lua = self.as_mut_lua();
             let my_offset = self.offset(-2);
+            profiling::record::<V>(raw_lua, ConversionDirection::Push, Location::caller());
 
             let mut guard = match index.push_to_lua(self) {
                 Err((err, _)) => return Err(CheckedSetError::KeyPushError(err)),
@@ -233,6 +250,49 @@ where
         }
     }
 
+    /// Reads the numbers of the array part of the table into `buffer`, and returns how many
+    /// elements have been written.
+    ///
+    /// The elements `1` to `#table` are read, without calling any metamethod, until either the
+    /// end of the array or the end of the buffer is reached. This is much faster than reading
+    /// the table as a `Vec<f32>` for scripts producing large numeric buffers, such as meshes or
+    /// audio samples, and lets the caller reuse the same buffer.
+    ///
+    /// Returns an error if an element isn't a number. The elements before it have been written
+    /// to the buffer.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("samples = { 0.5, -0.25, 1 }").unwrap();
+    ///
+    /// let mut samples: hlua::LuaTable<_> = lua.get("samples").unwrap();
+    /// let mut buffer = [0.0f32; 16];
+    /// let read = samples.read_into_slice_f32(&mut buffer).unwrap();
+    /// assert_eq!(&buffer[..read], &[0.5, -0.25, 1.0]);
+    /// ```
+    pub fn read_into_slice_f32(&mut self, buffer: &mut [f32]) -> Result<usize, NotANumberError> {
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let lua = raw_lua.as_ptr();
+            let len = ffix::lua_rawlen(raw_lua, self.index).min(buffer.len());
+
+            for (position, slot) in buffer[..len].iter_mut().enumerate() {
+                ffi::lua_rawgeti(lua, self.index, (position + 1) as _);
+                let mut success = 0;
+                let value = ffi::lua_tonumberx(lua, -1, &mut success);
+                ffi::lua_pop(lua, 1);
+                if success == 0 {
+                    return Err(NotANumberError { index: position + 1 });
+                }
+                *slot = value as f32;
+            }
+
+            Ok(len)
+        }
+    }
+
     /// Inserts an empty array, then loads it.
     #[inline]
     pub fn empty_array<'s, I, E>(&'s mut self, index: I) -> LuaTable<PushGuard<&'s mut LuaTable<L>>>
@@ -344,8 +404,282 @@ where
     pub fn registry(lua: L) -> LuaTable<L> {
         LuaTable { table: lua, index: ffi::LUA_REGISTRYINDEX }
     }
+
+    /// Overrides an entry of a configuration table, given a dot-separated path and the new value
+    /// as a string.
+    ///
+    /// The string is trimmed, then converted to the type of the value it replaces: booleans
+    /// accept `true`/`false`, `yes`/`no`, `on`/`off` and `1`/`0`, numbers must parse as numbers,
+    /// and strings are taken as is. If the entry doesn't exist yet, its type is guessed from the
+    /// string. Intermediate tables that don't exist are created. The tables are accessed with
+    /// raw gets and sets, so their metatables are ignored.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("config = { server = { port = 80, verbose = false } }").unwrap();
+    ///
+    /// let mut config: hlua::LuaTable<_> = lua.get("config").unwrap();
+    /// config.apply_override("server.port", "8080").unwrap();
+    /// config.apply_override("server.verbose", "yes").unwrap();
+    /// assert!(config.apply_override("server.port", "eighty").is_err());
+    /// ```
+    pub fn apply_override(&mut self, path: &str, value: &str) -> Result<(), OverrideError> {
+        let segments: Vec<&str> = path.split('.').collect();
+        self.apply_override_segments(None, &segments, value)
+    }
+
+    /// Applies a list of overrides of the form `section.key=value`, as typically passed on the
+    /// command line with `--set section.key=value`.
+    ///
+    /// See `apply_override` for how values are converted.
+    pub fn apply_set_overrides<I, S>(&mut self, overrides: I) -> Result<(), OverrideError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for entry in overrides {
+            let entry = entry.as_ref();
+            match entry.split_once('=') {
+                Some((path, value)) => self.apply_override(path.trim(), value)?,
+                None => return Err(OverrideError::Malformed(entry.to_owned())),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies overrides from environment variables of the form `PREFIX_SECTION__KEY=value`.
+    ///
+    /// The part after `PREFIX_` is split on `__` and lowercased to obtain the path of the entry,
+    /// so `APP_SERVER__PORT=8080` overrides `server.port` when `prefix` is `"APP"`. Variables
+    /// that don't start with the prefix are ignored.
+    ///
+    /// The variables are usually obtained with `std::env::vars()`. See `apply_override` for how
+    /// values are converted. A variable that can't be applied doesn't prevent the others from
+    /// being applied, and the errors of all of them are returned. The errors contain the path
+    /// of the entry and the name of the variable.
+    pub fn apply_env_overrides<I, K, V>(
+        &mut self,
+        prefix: &str,
+        vars: I,
+    ) -> Result<(), Vec<OverrideError>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let mut errors = Vec::new();
+        for (name, value) in vars {
+            let name = name.as_ref();
+            let key = match name.strip_prefix(prefix).and_then(|n| n.strip_prefix('_')) {
+                Some(key) if !key.is_empty() => key.to_lowercase(),
+                _ => continue,
+            };
+
+            let segments: Vec<&str> = key.split("__").collect();
+            if let Err(err) = self.apply_override_segments(Some(name), &segments, value.as_ref()) {
+                errors.push(err);
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    // `variable` is the name of the environment variable that the override comes from, if any.
+    fn apply_override_segments(
+        &mut self,
+        variable: Option<&str>,
+        segments: &[&str],
+        value: &str,
+    ) -> Result<(), OverrideError> {
+        let (last, parents) = match segments.split_last() {
+            Some(split) if !segments.iter().any(|s| s.is_empty()) => split,
+            _ => {
+                let input = variable.map_or_else(|| segments.join("."), str::to_owned);
+                return Err(OverrideError::Malformed(input));
+            },
+        };
+        let variable = variable.map(str::to_owned);
+        let value = value.trim();
+
+        unsafe {
+            let raw_lua = self.as_mut_lua().as_ptr();
+
+            // Every table traversed stays on the stack until the end, which avoids having to
+            // remove elements from the middle of the stack.
+            ffi::lua_pushvalue(raw_lua, self.offset(0));
+            let mut pushed = 1;
+
+            let push_key = |key: &str| {
+                ffi::lua_pushlstring(raw_lua, key.as_ptr().cast(), key.len() as libc::size_t);
+            };
+
+            for (num, key) in parents.iter().enumerate() {
+                push_key(key);
+                ffi::lua_rawget(raw_lua, -2);
+                pushed += 1;
+
+                if ffi::lua_isnil(raw_lua, -1) {
+                    ffi::lua_pop(raw_lua, 1);
+                    ffi::lua_newtable(raw_lua);
+                    push_key(key);
+                    ffi::lua_pushvalue(raw_lua, -2);
+                    ffi::lua_rawset(raw_lua, -4);
+                } else if !ffi::lua_istable(raw_lua, -1) {
+                    ffi::lua_pop(raw_lua, pushed);
+                    let path = segments[..=num].join(".");
+                    return Err(OverrideError::NotATable { path, variable });
+                }
+            }
+
+            push_key(last);
+            ffi::lua_rawget(raw_lua, -2);
+            let existing = ffi::lua_type(raw_lua, -1);
+            ffi::lua_pop(raw_lua, 1);
+
+            let pushed_value = match existing {
+                ffi::LUA_TNIL => {
+                    if let Some(b) = parse_override_bool(value) {
+                        ffi::lua_pushboolean(raw_lua, libc::c_int::from(b));
+                    } else if let Ok(n) = value.parse::<i32>() {
+                        ffi::lua_pushinteger(raw_lua, n as ffi::lua_Integer);
+                    } else if let Ok(n) = value.parse::<f64>() {
+                        ffi::lua_pushnumber(raw_lua, n);
+                    } else {
+                        ffi::lua_pushlstring(
+                            raw_lua,
+                            value.as_ptr().cast(),
+                            value.len() as libc::size_t,
+                        );
+                    }
+                    Ok(())
+                },
+                ffi::LUA_TBOOLEAN => match parse_override_bool(value) {
+                    Some(b) => {
+                        ffi::lua_pushboolean(raw_lua, libc::c_int::from(b));
+                        Ok(())
+                    },
+                    None => Err("a boolean"),
+                },
+                ffi::LUA_TNUMBER => {
+                    if let Ok(n) = value.parse::<i32>() {
+                        ffi::lua_pushinteger(raw_lua, n as ffi::lua_Integer);
+                        Ok(())
+                    } else if let Ok(n) = value.parse::<f64>() {
+                        ffi::lua_pushnumber(raw_lua, n);
+                        Ok(())
+                    } else {
+                        Err("a number")
+                    }
+                },
+                ffi::LUA_TSTRING => {
+                    ffi::lua_pushlstring(
+                        raw_lua,
+                        value.as_ptr().cast(),
+                        value.len() as libc::size_t,
+                    );
+                    Ok(())
+                },
+                _ => Err("a boolean, number or string entry"),
+            };
+
+            if let Err(expected) = pushed_value {
+                ffi::lua_pop(raw_lua, pushed);
+                let path = segments.join(".");
+                return Err(OverrideError::InvalidValue { path, variable, expected });
+            }
+
+            push_key(last);
+            ffix::lua_insert(raw_lua, -2);
+            ffi::lua_rawset(raw_lua, -3);
+            ffi::lua_pop(raw_lua, pushed);
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_override_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Error returned when applying configuration overrides to a table.
+///
+/// See `LuaTable::apply_override`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverrideError {
+    /// The override or its path is malformed, for example an empty path component or a missing
+    /// `=` sign. Contains the override, or the name of the environment variable.
+    Malformed(String),
+    /// An intermediate entry of the path exists but isn't a table.
+    NotATable {
+        /// Path of that entry.
+        path: String,
+        /// Name of the environment variable that the override comes from, if any.
+        variable: Option<String>,
+    },
+    /// The value couldn't be converted to the type of the entry it replaces.
+    InvalidValue {
+        /// Path of the entry.
+        path: String,
+        /// Name of the environment variable that the override comes from, if any.
+        variable: Option<String>,
+        /// Description of what was expected.
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for OverrideError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OverrideError::Malformed(s) => write!(f, "Malformed override: {}", s),
+            OverrideError::NotATable { path, variable } => {
+                write!(f, "Entry {} is not a table", path)?;
+                write_variable(f, variable)
+            },
+            OverrideError::InvalidValue { path, variable, expected } => {
+                write!(f, "Invalid value for {}", path)?;
+                write_variable(f, variable)?;
+                write!(f, ": expected {}", expected)
+            },
+        }
+    }
+}
+
+fn write_variable(f: &mut fmt::Formatter, variable: &Option<String>) -> fmt::Result {
+    match variable {
+        Some(variable) => write!(f, " (from {})", variable),
+        None => Ok(()),
+    }
+}
+
+impl Error for OverrideError {}
+
+/// Error returned by `LuaTable::read_into_slice_f32` when an element of the table isn't a
+/// number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotANumberError {
+    /// Index in the table of the element, starting from 1.
+    pub index: usize,
+}
+
+impl fmt::Display for NotANumberError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Element {} of the table is not a number", self.index)
+    }
 }
 
+impl Error for NotANumberError {}
+
 /// Error returned by the `checked_set` function.
 // TODO: implement `Error` on this type
 #[derive(Debug, Copy, Clone)]
@@ -360,15 +694,27 @@ pub enum CheckedSetError<K, V> {
 ///
 /// See `LuaTable::iter` for more info.
 // Implementation note: While the LuaTableIterator is active, the current key is constantly
-// pushed over the table. The destructor takes care of removing it.
+// pushed over the table, or the table of the keys when they are sorted. The destructor takes
+// care of removing it.
 #[derive(Debug)]
 pub struct LuaTableIterator<'t, L: 't, K, V> {
     table: &'t mut LuaTable<L>,
     finished: bool, // if true, the key is not on the stack anymore
     raw_lua: LuaContext,
+    sorted: Option<SortedKeys>,
     marker: PhantomData<(K, V)>,
 }
 
+// State of an iteration in sorted order. See `Lua::set_sorted_iteration`.
+#[derive(Debug)]
+struct SortedKeys {
+    // Absolute index of the table.
+    table: i32,
+    // Indices of the keys in the table of the keys, in iteration order.
+    order: Vec<i32>,
+    next: usize,
+}
+
 unsafe impl<'t, 'lua, L, K, V> AsLua<'lua> for LuaTableIterator<'t, L, K, V>
 where
     L: AsMutLua<'lua>,
@@ -408,6 +754,36 @@ where
 
             let raw_lua = self.table.as_mut_lua();
 
+            if let Some(sorted) = &mut self.sorted {
+                // Pushes the next key whose value is still there, and its value.
+                loop {
+                    let Some(&n) = sorted.order.get(sorted.next) else {
+                        ffi::lua_pop(raw_lua.as_ptr(), 1);
+                        self.finished = true;
+                        return None;
+                    };
+                    sorted.next += 1;
+
+                    ffi::lua_rawgeti(raw_lua.as_ptr(), -1, n as _);
+                    ffi::lua_pushvalue(raw_lua.as_ptr(), -1);
+                    ffi::lua_rawget(raw_lua.as_ptr(), sorted.table);
+                    if !ffi::lua_isnil(raw_lua.as_ptr(), -1) {
+                        break;
+                    }
+                    ffi::lua_pop(raw_lua.as_ptr(), 2);
+                }
+
+                let mut me = self;
+                let k = LuaRead::lua_read_at_position(&mut me, -2).ok();
+                let v = LuaRead::lua_read_at_position(&mut me, -1).ok();
+                ffi::lua_pop(raw_lua.as_ptr(), 2);
+
+                return match (k, v) {
+                    (Some(key), Some(value)) => Some(Some((key, value))),
+                    _ => Some(None),
+                };
+            }
+
             // This call pops the current key and pushes the next key and value at the top.
             if ffi::lua_next(raw_lua.as_ptr(), self.table.offset(-1)) == 0 {
                 self.finished = true;
@@ -443,7 +819,7 @@ impl<'t, L, K, V> Drop for LuaTableIterator<'t, L, K, V> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{function0, Lua, LuaTable, PushGuard};
+    use crate::{function0, Lua, LuaTable, OverrideError, PushGuard};
 
     #[test]
     fn iterable() {
@@ -550,7 +926,7 @@ mod tests {
         }
 
         let mut table: LuaTable<_> = lua.get("a").unwrap();
-        assert!(3 == table.get("b").unwrap());
+        assert!(3 == table.get::<i32, _, _>("b").unwrap());
     }
 
     #[test]
@@ -568,12 +944,12 @@ mod tests {
         let table: LuaTable<PushGuard<Lua>> = lua.into_get("a").ok().unwrap();
         let mut table2: LuaTable<PushGuard<LuaTable<PushGuard<Lua>>>> =
             table.into_get("b").ok().unwrap();
-        assert!(3 == table2.get("c").unwrap());
+        assert!(3 == table2.get::<i32, _, _>("c").unwrap());
         let table: LuaTable<PushGuard<Lua>> = table2.into_inner().into_inner();
         // do it again to make sure the stack is still sane
         let mut table2: LuaTable<PushGuard<LuaTable<PushGuard<Lua>>>> =
             table.into_get("b").ok().unwrap();
-        assert!(3 == table2.get("c").unwrap());
+        assert!(3 == table2.get::<i32, _, _>("c").unwrap());
         let table: LuaTable<PushGuard<Lua>> = table2.into_inner().into_inner();
         let _lua: Lua = table.into_inner().into_inner();
     }
@@ -588,6 +964,176 @@ mod tests {
         assert_eq!(y, "hello");
     }
 
+    #[test]
+    fn set_overrides() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("config = { port = 80, ratio = 0.5, debug = false, name = 'a' }")
+            .unwrap();
+
+        {
+            let mut config: LuaTable<_> = lua.get("config").unwrap();
+            config
+                .apply_set_overrides(&[
+                    "port=8080",
+                    "ratio=0.25",
+                    "debug=on",
+                    "name=42",
+                    "db.host=x",
+                ])
+                .unwrap();
+        }
+
+        let ok: bool = lua
+            .execute(
+                "return config.port == 8080 and config.ratio == 0.25 and config.debug == true \
+                 and config.name == '42' and config.db.host == 'x'",
+            )
+            .unwrap();
+        assert!(ok);
+    }
+
+    #[test]
+    fn env_overrides() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("config = { server = { port = 80 } }").unwrap();
+
+        {
+            let mut config: LuaTable<_> = lua.get("config").unwrap();
+            let vars = vec![
+                ("APP_SERVER__PORT", "9000"),
+                ("APP_SERVER__TLS", "true"),
+                ("OTHER_SERVER__PORT", "1"),
+                ("PATH", "/bin"),
+            ];
+            config.apply_env_overrides("APP", vars).unwrap();
+        }
+
+        let port: i32 = lua.execute("return config.server.port").unwrap();
+        assert_eq!(port, 9000);
+        let tls: bool = lua.execute("return config.server.tls").unwrap();
+        assert!(tls);
+    }
+
+    #[test]
+    fn env_overrides_errors() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("config = { name = 'x', port = 80 }").unwrap();
+
+        {
+            let mut config: LuaTable<_> = lua.get("config").unwrap();
+            let vars =
+                vec![("APP_NAME__FIRST", "a"), ("APP_PORT", "eighty"), ("APP_DEBUG", " true ")];
+            assert_eq!(
+                config.apply_env_overrides("APP", vars),
+                Err(vec![
+                    OverrideError::NotATable {
+                        path: "name".to_owned(),
+                        variable: Some("APP_NAME__FIRST".to_owned()),
+                    },
+                    OverrideError::InvalidValue {
+                        path: "port".to_owned(),
+                        variable: Some("APP_PORT".to_owned()),
+                        expected: "a number"
+                    },
+                ])
+            );
+            let errors = config.apply_env_overrides("APP", vec![("APP_NAME__FIRST", "a")]);
+            assert_eq!(
+                errors.unwrap_err()[0].to_string(),
+                "Entry name is not a table (from APP_NAME__FIRST)"
+            );
+            assert_eq!(
+                config.apply_env_overrides("APP", vec![("APP_A____B", "1")]),
+                Err(vec![OverrideError::Malformed("APP_A____B".to_owned())])
+            );
+        }
+
+        let debug: bool = lua.execute("return config.debug").unwrap();
+        assert!(debug);
+    }
+
+    #[test]
+    fn overrides_trim_and_ignore_metatables() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.execute::<()>(
+            "config = setmetatable({ port = 80, debug = false, name = 'a' }, \
+             { __index = function() error('no __index') end, \
+               __newindex = function() error('no __newindex') end })",
+        )
+        .unwrap();
+
+        {
+            let mut config: LuaTable<_> = lua.get("config").unwrap();
+            config
+                .apply_set_overrides(&["port= 8080", "debug= true ", "name= b ", "db.port= 5 "])
+                .unwrap();
+        }
+
+        let ok: bool = lua
+            .execute(
+                "return rawget(config, 'port') == 8080 and rawget(config, 'debug') == true \
+                 and rawget(config, 'name') == 'b' and rawget(config, 'db').port == 5",
+            )
+            .unwrap();
+        assert!(ok);
+    }
+
+    #[test]
+    fn override_errors() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("config = { port = 80, server = 'x' }").unwrap();
+
+        let mut config: LuaTable<_> = lua.get("config").unwrap();
+        assert_eq!(
+            config.apply_override("port", "eighty"),
+            Err(OverrideError::InvalidValue {
+                path: "port".to_owned(),
+                variable: None,
+                expected: "a number"
+            })
+        );
+        assert_eq!(
+            config.apply_override("server.port", "1"),
+            Err(OverrideError::NotATable { path: "server".to_owned(), variable: None })
+        );
+        assert_eq!(
+            config.apply_override("port", "eighty").unwrap_err().to_string(),
+            "Invalid value for port: expected a number"
+        );
+        assert_eq!(
+            config.apply_set_overrides(&["port"]),
+            Err(OverrideError::Malformed("port".to_owned()))
+        );
+        assert_eq!(
+            config.apply_override("a..b", "1"),
+            Err(OverrideError::Malformed("a..b".to_owned()))
+        );
+        assert_eq!(config.get::<i32, _, _>("port"), Some(80));
+    }
+
+    #[test]
+    fn read_into_slice_f32() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("values = { 1, 2.5, '3', 4 } broken = { 1, {}, 3 }").unwrap();
+
+        let mut values: LuaTable<_> = lua.get("values").unwrap();
+        let mut small = [0.0; 2];
+        assert_eq!(values.read_into_slice_f32(&mut small), Ok(2));
+        assert_eq!(small, [1.0, 2.5]);
+        let mut large = [-1.0; 6];
+        assert_eq!(values.read_into_slice_f32(&mut large), Ok(4));
+        assert_eq!(large, [1.0, 2.5, 3.0, 4.0, -1.0, -1.0]);
+        drop(values);
+
+        let mut broken: LuaTable<_> = lua.get("broken").unwrap();
+        let err = broken.read_into_slice_f32(&mut large).unwrap_err();
+        assert_eq!(err.index, 2);
+        assert_eq!(err.to_string(), "Element 2 of the table is not a number");
+        // The stack is left untouched.
+        assert_eq!(broken.get::<i32, _, _>(3), Some(3));
+    }
+
     #[test]
     fn registry_metatable() {
         let mut lua = Lua::new();
@@ -1,5 +1,8 @@
 #[macro_export]
 macro_rules! implement_lua_push {
+    ($ty:ty) => {
+        $crate::implement_lua_push!($ty, $crate::add_user_data_methods::<$ty>);
+    };
     ($ty:ty, $cb:expr) => {
         impl<'lua, L> $crate::Push<L> for $ty
         where
@@ -37,7 +40,7 @@ macro_rules! implement_lua_read {
                 index: i32,
             ) -> Result<&'s $ty, &'c mut $crate::InsideCallback> {
                 // FIXME:
-                unsafe { ::std::mem::transmute($crate::read_userdata::<$ty>(lua, index)) }
+                unsafe { ::std::mem::transmute($crate::read_userdata_ref::<$ty>(lua, index)) }
             }
         }
 
@@ -0,0 +1,307 @@
+use std::ffi::CStr;
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsLua, AsMutLua, LuaContext, LuaRead, Push, PushGuard, PushOne, Void};
+
+/// Matrix of numbers exchanged with Lua, stored in column-major order.
+///
+/// In Lua, a matrix is a table of the form `{ rows = 2, cols = 3, data = { ... } }`, where `data`
+/// contains the `rows * cols` elements column after column. Pushing a `LuaMatrix` creates such a
+/// table, and reading one accepts it. Because the elements are stored in a single flat array,
+/// converting a matrix only costs one table operation per element, and the elements can be
+/// accessed from Rust as a `&[f32]`.
+///
+/// Matrices that Lua code only passes around without looking at the elements can instead be
+/// pushed as a `PackedLuaMatrix`, which doesn't convert the elements at all.
+///
+/// # Example
+///
+/// ```
+/// use hlua::LuaMatrix;
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("m", LuaMatrix::new(2, 2, vec![1.0, 2.0, 3.0, 4.0]));
+/// lua.execute::<()>("m.data[4] = m.data[4] * 10").unwrap();
+///
+/// let m: LuaMatrix = lua.get("m").unwrap();
+/// assert_eq!(m.get(1, 1), 40.0);
+/// assert_eq!(m.as_slice(), &[1.0, 2.0, 3.0, 40.0]);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct LuaMatrix {
+    rows: usize,
+    columns: usize,
+    data: Vec<f32>,
+}
+
+/// Wrapper around a `LuaMatrix` that is pushed to Lua as a userdata instead of a table.
+///
+/// The elements aren't accessible from Lua, but pushing and reading the matrix doesn't convert
+/// them. Reading a `LuaMatrix` accepts both forms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackedLuaMatrix(pub LuaMatrix);
+
+impl LuaMatrix {
+    /// Builds a matrix from its elements, in column-major order.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `data` doesn't contain `rows * columns` elements.
+    #[inline]
+    pub fn new(rows: usize, columns: usize, data: Vec<f32>) -> LuaMatrix {
+        assert_eq!(data.len(), rows * columns, "wrong number of elements for the matrix");
+        LuaMatrix { rows, columns, data }
+    }
+
+    /// Builds a matrix whose elements are all 0.
+    #[inline]
+    pub fn zeros(rows: usize, columns: usize) -> LuaMatrix {
+        LuaMatrix { rows, columns, data: vec![0.0; rows * columns] }
+    }
+
+    /// Returns the number of rows.
+    #[inline]
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Returns the number of columns.
+    #[inline]
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    /// Returns the element at the given row and column, starting from 0.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the row or the column is out of bounds.
+    #[inline]
+    pub fn get(&self, row: usize, column: usize) -> f32 {
+        self.data[self.position(row, column)]
+    }
+
+    /// Modifies the element at the given row and column, starting from 0.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the row or the column is out of bounds.
+    #[inline]
+    pub fn set(&mut self, row: usize, column: usize, value: f32) {
+        let position = self.position(row, column);
+        self.data[position] = value;
+    }
+
+    /// Returns the elements, in column-major order.
+    #[inline]
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
+
+    /// Returns the elements, in column-major order.
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    /// Destroys the matrix and returns its elements, in column-major order.
+    #[inline]
+    pub fn into_vec(self) -> Vec<f32> {
+        self.data
+    }
+
+    #[inline]
+    fn position(&self, row: usize, column: usize) -> usize {
+        assert!(row < self.rows && column < self.columns, "matrix index out of bounds");
+        column * self.rows + row
+    }
+}
+
+impl<'lua, L> Push<L> for LuaMatrix
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        (&self).push_to_lua(lua)
+    }
+}
+
+impl<'lua, L> PushOne<L> for LuaMatrix where L: AsMutLua<'lua> {}
+
+impl<'lua, L> Push<L> for &LuaMatrix
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        unsafe {
+            let raw_lua = lua.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffi::lua_createtable(l, 0, 3);
+            ffi::lua_pushinteger(l, self.rows as ffi::lua_Integer);
+            ffi::lua_setfield(l, -2, c"rows".as_ptr());
+            ffi::lua_pushinteger(l, self.columns as ffi::lua_Integer);
+            ffi::lua_setfield(l, -2, c"cols".as_ptr());
+
+            ffi::lua_createtable(l, self.data.len() as _, 0);
+            for (position, &value) in self.data.iter().enumerate() {
+                ffi::lua_pushnumber(l, value as ffi::lua_Number);
+                ffi::lua_rawseti(l, -2, (position + 1) as _);
+            }
+            ffi::lua_setfield(l, -2, c"data".as_ptr());
+
+            Ok(PushGuard { lua, size: 1, raw_lua })
+        }
+    }
+}
+
+impl<'lua, L> PushOne<L> for &LuaMatrix where L: AsMutLua<'lua> {}
+
+impl<'lua, L> Push<L> for PackedLuaMatrix
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        Ok(push_userdata(self.0, lua, |_| {}))
+    }
+}
+
+impl<'lua, L> PushOne<L> for PackedLuaMatrix where L: AsMutLua<'lua> {}
+
+impl<'lua, L> LuaRead<L> for LuaMatrix
+where
+    L: AsLua<'lua>,
+{
+    fn lua_read_at_position(lua: L, index: i32) -> Result<LuaMatrix, L> {
+        let raw_lua = lua.as_lua();
+        if let Some(matrix) = unsafe { userdata_mut::<LuaMatrix>(raw_lua, index) } {
+            return Ok(matrix.clone());
+        }
+
+        match unsafe { read_table(raw_lua, index) } {
+            Some(matrix) => Ok(matrix),
+            None => Err(lua),
+        }
+    }
+}
+
+impl<'lua, L> LuaRead<L> for PackedLuaMatrix
+where
+    L: AsLua<'lua>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<PackedLuaMatrix, L> {
+        LuaMatrix::lua_read_at_position(lua, index).map(PackedLuaMatrix)
+    }
+}
+
+// Reads a matrix stored as a table. The stack is left unchanged.
+unsafe fn read_table(lua: LuaContext, index: i32) -> Option<LuaMatrix> {
+    let l = lua.as_ptr();
+    if !ffi::lua_istable(l, index) {
+        return None;
+    }
+    let table = match index < 0 && index > ffi::LUA_REGISTRYINDEX {
+        true => ffi::lua_gettop(l) + index + 1,
+        false => index,
+    };
+
+    let rows = read_dimension(lua, table, c"rows")?;
+    let columns = read_dimension(lua, table, c"cols")?;
+
+    ffi::lua_getfield(l, table, c"data".as_ptr());
+    let data = match ffi::lua_istable(l, -1) && ffix::lua_rawlen(lua, -1) == rows * columns {
+        true => {
+            let mut data = Vec::with_capacity(rows * columns);
+            for position in 1..=rows * columns {
+                ffi::lua_rawgeti(l, -1, position as _);
+                let mut success = 0;
+                let value = ffi::lua_tonumberx(l, -1, &mut success);
+                ffi::lua_pop(l, 1);
+                if success == 0 {
+                    break;
+                }
+                data.push(value as f32);
+            }
+            Some(data).filter(|data| data.len() == rows * columns)
+        },
+        false => None,
+    };
+    ffi::lua_pop(l, 1);
+
+    data.map(|data| LuaMatrix { rows, columns, data })
+}
+
+// Reads a non-negative integer field of the table at the absolute index `table`.
+unsafe fn read_dimension(lua: LuaContext, table: i32, name: &CStr) -> Option<usize> {
+    let l = lua.as_ptr();
+    ffi::lua_getfield(l, table, name.as_ptr());
+    let mut success = 0;
+    let value = ffi::lua_tonumberx(l, -1, &mut success);
+    ffi::lua_pop(l, 1);
+
+    match success != 0 && value >= 0.0 && value.fract() == 0.0 {
+        true => Some(value as usize),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, LuaMatrix, PackedLuaMatrix};
+
+    #[test]
+    fn table_round_trip() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let mut matrix = LuaMatrix::zeros(2, 3);
+        matrix.set(1, 2, 5.0);
+        assert_eq!(matrix.as_slice(), &[0.0, 0.0, 0.0, 0.0, 0.0, 5.0]);
+        lua.set("m", &matrix);
+
+        let summary: String = lua
+            .execute("return m.rows .. 'x' .. m.cols .. ':' .. tostring(m.data[6] == 5)")
+            .unwrap();
+        assert_eq!(summary, "2x3:true");
+
+        let read: LuaMatrix = lua.get("m").unwrap();
+        assert_eq!(read, matrix);
+        let columns: Vec<LuaMatrix> =
+            lua.execute("return { { rows = 1, cols = 2, data = { 7, 8 } } }").unwrap();
+        assert_eq!(columns[0].get(0, 1), 8.0);
+    }
+
+    #[test]
+    fn invalid_tables() {
+        let mut lua = Lua::new();
+        for code in [
+            "return { rows = 2, cols = 2, data = { 1, 2, 3 } }",
+            "return { rows = 1, cols = 2, data = { 1, 'x' } }",
+            "return { rows = -1, cols = 0, data = {} }",
+            "return { cols = 1, data = { 1 } }",
+            "return 5",
+        ] {
+            assert!(lua.execute::<LuaMatrix>(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn packed() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let matrix = LuaMatrix::new(1, 2, vec![1.5, 2.5]);
+        lua.set("m", PackedLuaMatrix(matrix.clone()));
+
+        let kind: String = lua.execute("return type(m)").unwrap();
+        assert_eq!(kind, "userdata");
+        assert_eq!(lua.get::<LuaMatrix, _>("m"), Some(matrix.clone()));
+        assert_eq!(lua.get::<PackedLuaMatrix, _>("m"), Some(PackedLuaMatrix(matrix)));
+    }
+}
@@ -0,0 +1,347 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use libc::c_int;
+
+use crate::lua_functions::OwnedLuaFunction;
+use crate::{ffix, InsideCallback, Lua, LuaContext, LuaRead, PushOne, Void};
+
+// Key of the registry table holding the lists: `{ lists = { [name] = list }, entries = { [id] =
+// entry }, next_id = n }`. A list is `{ handlers = { entry... }, dispatch = function }` and an
+// entry is `{ fn = handler, priority = p, id = id, list = list, removed = bool }`.
+const LISTS_KEY: &CStr = c"hlua.ordered_callbacks";
+
+/// Identifies a handler added with `Lua::register_ordered`, to remove it with
+/// `Lua::unregister_ordered`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct HandlerId(u32);
+
+impl<'lua> Lua<'lua> {
+    /// Adds `handler` to the list of handlers called `name`, and returns an identifier to remove
+    /// it with `unregister_ordered`.
+    ///
+    /// The first registration in a list sets the global variable `name` to a function that
+    /// calls all the handlers of the list with its arguments, by decreasing priority, and in the
+    /// order of registration between the handlers of the same priority. The first handler that
+    /// returns a value other than `nil` stops the dispatch, and its return values are returned.
+    /// `ordered_dispatcher` returns the same function, to dispatch from Rust.
+    ///
+    /// The handlers added or removed while a dispatch is running don't change the handlers that
+    /// this dispatch calls, except that removed handlers that weren't called yet are skipped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("log = ''").unwrap();
+    /// lua.execute::<()>("function armor(_, n) log = log .. 'armor ' end").unwrap();
+    /// lua.execute::<()>("function shield(_, n) if n < 5 then return 0 end end").unwrap();
+    ///
+    /// let armor: hlua::LuaRef = lua.get("armor").unwrap();
+    /// let shield: hlua::LuaRef = lua.get("shield").unwrap();
+    /// lua.register_ordered("on_damage", 0, &armor);
+    /// lua.register_ordered("on_damage", 10, &shield);
+    ///
+    /// // The shield absorbs small hits before the armor sees them.
+    /// assert_eq!(lua.execute::<i32>("return on_damage('player', 3)").unwrap(), 0);
+    /// let dispatch = lua.ordered_dispatcher("on_damage");
+    /// let result: Option<i32> = dispatch.call_with_args(&mut lua, ("player", 8)).unwrap();
+    /// assert_eq!(result, None);
+    /// assert_eq!(lua.get::<String, _>("log").unwrap(), "armor ");
+    /// ```
+    pub fn register_ordered<V>(&mut self, name: &str, priority: i32, handler: V) -> HandlerId
+    where
+        V: for<'a> PushOne<&'a mut Lua<'lua>, Err = Void>,
+    {
+        let lua = self.lua;
+        unsafe { register(lua, name, priority, || handler.push_no_err(self).forget()) }
+    }
+
+    /// Removes a handler added with `register_ordered`. Returns false if it was already removed.
+    ///
+    /// The global function of the list stays, and does nothing once the list is empty.
+    pub fn unregister_ordered(&mut self, id: HandlerId) -> bool {
+        unsafe { unregister(self.lua, id) }
+    }
+
+    /// Returns the function that dispatches to the handlers called `name`, the one stored in
+    /// the global variable of the same name. See `register_ordered`.
+    ///
+    /// If no handler was registered with this name, the list is created empty, and the global
+    /// variable is set.
+    pub fn ordered_dispatcher(&mut self, name: &str) -> OwnedLuaFunction {
+        unsafe {
+            let raw_lua = self.lua.as_ptr();
+            let top = ffi::lua_gettop(raw_lua);
+            push_lists(self.lua);
+            push_list(self.lua, top + 1, name);
+            ffi::lua_getfield(raw_lua, -1, c"dispatch".as_ptr());
+            let function = match OwnedLuaFunction::lua_read_at_position(&mut *self, -1) {
+                Ok(function) => function,
+                Err(_) => unreachable!("the dispatcher of a list is a function"),
+            };
+            ffi::lua_settop(raw_lua, top);
+            function
+        }
+    }
+}
+
+impl InsideCallback {
+    /// Adds a handler to the list called `name` from a Rust callback, which can run during a
+    /// dispatch of the list. See `Lua::register_ordered`.
+    pub fn register_ordered<V>(&mut self, name: &str, priority: i32, handler: V) -> HandlerId
+    where
+        V: for<'a> PushOne<&'a mut InsideCallback, Err = Void>,
+    {
+        let lua = self.lua;
+        unsafe { register(lua, name, priority, || handler.push_no_err(self).forget()) }
+    }
+
+    /// Removes a handler from a Rust callback. See `Lua::unregister_ordered`.
+    pub fn unregister_ordered(&mut self, id: HandlerId) -> bool {
+        unsafe { unregister(self.lua, id) }
+    }
+}
+
+// Adds a handler to a list. `push_handler` pushes the handler on the stack.
+unsafe fn register(
+    lua: LuaContext,
+    name: &str,
+    priority: i32,
+    push_handler: impl FnOnce() -> i32,
+) -> HandlerId {
+    let raw_lua = lua.as_ptr();
+    let top = ffi::lua_gettop(raw_lua);
+    push_lists(lua);
+    let state = top + 1;
+    push_list(lua, state, name);
+    let list = top + 3;
+
+    ffi::lua_getfield(raw_lua, state, c"next_id".as_ptr());
+    let id = ffi::lua_tonumberx(raw_lua, -1, ptr::null_mut()) as u32 + 1;
+    ffi::lua_pop(raw_lua, 1);
+    ffi::lua_pushnumber(raw_lua, id as _);
+    ffi::lua_setfield(raw_lua, state, c"next_id".as_ptr());
+
+    ffi::lua_newtable(raw_lua);
+    let entry = top + 4;
+    push_handler();
+    ffi::lua_setfield(raw_lua, entry, c"fn".as_ptr());
+    ffi::lua_pushnumber(raw_lua, priority as _);
+    ffi::lua_setfield(raw_lua, entry, c"priority".as_ptr());
+    ffi::lua_pushvalue(raw_lua, list);
+    ffi::lua_setfield(raw_lua, entry, c"list".as_ptr());
+
+    ffi::lua_getfield(raw_lua, state, c"entries".as_ptr());
+    ffi::lua_pushvalue(raw_lua, entry);
+    ffi::lua_rawseti(raw_lua, -2, id as _);
+    ffi::lua_pop(raw_lua, 1);
+
+    // The handlers are copied instead of modified, so that the running dispatches keep
+    // going through the handlers they started with.
+    ffi::lua_getfield(raw_lua, list, c"handlers".as_ptr());
+    let old = top + 5;
+    ffi::lua_newtable(raw_lua);
+    let mut position = 1;
+    let mut inserted = false;
+    for i in 1..=ffix::lua_rawlen(lua, old) {
+        ffi::lua_rawgeti(raw_lua, old, i as _);
+        ffi::lua_getfield(raw_lua, -1, c"priority".as_ptr());
+        let other = ffi::lua_tonumberx(raw_lua, -1, ptr::null_mut());
+        ffi::lua_pop(raw_lua, 1);
+        if !inserted && other < priority as f64 {
+            ffi::lua_pushvalue(raw_lua, entry);
+            ffi::lua_rawseti(raw_lua, -3, position);
+            position += 1;
+            inserted = true;
+        }
+        ffi::lua_rawseti(raw_lua, -2, position);
+        position += 1;
+    }
+    if !inserted {
+        ffi::lua_pushvalue(raw_lua, entry);
+        ffi::lua_rawseti(raw_lua, -2, position);
+    }
+    ffi::lua_setfield(raw_lua, list, c"handlers".as_ptr());
+
+    ffi::lua_settop(raw_lua, top);
+    HandlerId(id)
+}
+
+// Removes a handler from its list.
+unsafe fn unregister(lua: LuaContext, id: HandlerId) -> bool {
+    let raw_lua = lua.as_ptr();
+    let top = ffi::lua_gettop(raw_lua);
+    push_lists(lua);
+    let state = top + 1;
+    ffi::lua_getfield(raw_lua, state, c"entries".as_ptr());
+    ffi::lua_rawgeti(raw_lua, -1, id.0 as _);
+    if ffi::lua_isnil(raw_lua, -1) {
+        ffi::lua_settop(raw_lua, top);
+        return false;
+    }
+    let entry = top + 3;
+
+    ffi::lua_pushnil(raw_lua);
+    ffi::lua_rawseti(raw_lua, top + 2, id.0 as _);
+    ffi::lua_pushboolean(raw_lua, 1);
+    ffi::lua_setfield(raw_lua, entry, c"removed".as_ptr());
+
+    ffi::lua_getfield(raw_lua, entry, c"list".as_ptr());
+    let list = top + 4;
+    ffi::lua_getfield(raw_lua, list, c"handlers".as_ptr());
+    let old = top + 5;
+    ffi::lua_newtable(raw_lua);
+    let mut position = 1;
+    for i in 1..=ffix::lua_rawlen(lua, old) {
+        ffi::lua_rawgeti(raw_lua, old, i as _);
+        if ffi::lua_rawequal(raw_lua, -1, entry) != 0 {
+            ffi::lua_pop(raw_lua, 1);
+        } else {
+            ffi::lua_rawseti(raw_lua, -2, position);
+            position += 1;
+        }
+    }
+    ffi::lua_setfield(raw_lua, list, c"handlers".as_ptr());
+
+    ffi::lua_settop(raw_lua, top);
+    true
+}
+
+// Pushes the registry table of the lists, creating it if needed.
+unsafe fn push_lists(lua: LuaContext) {
+    let raw_lua = lua.as_ptr();
+    ffi::lua_getfield(raw_lua, ffi::LUA_REGISTRYINDEX, LISTS_KEY.as_ptr());
+    if ffi::lua_istable(raw_lua, -1) {
+        return;
+    }
+
+    ffi::lua_pop(raw_lua, 1);
+    ffi::lua_newtable(raw_lua);
+    ffi::lua_newtable(raw_lua);
+    ffi::lua_setfield(raw_lua, -2, c"lists".as_ptr());
+    ffi::lua_newtable(raw_lua);
+    ffi::lua_setfield(raw_lua, -2, c"entries".as_ptr());
+    ffi::lua_pushvalue(raw_lua, -1);
+    ffi::lua_setfield(raw_lua, ffi::LUA_REGISTRYINDEX, LISTS_KEY.as_ptr());
+}
+
+// Pushes the table of the lists and the list called `name`, creating the list and its global
+// function if needed. `state` is the absolute index of the registry table of the lists.
+unsafe fn push_list(lua: LuaContext, state: c_int, name: &str) {
+    let raw_lua = lua.as_ptr();
+    let name = CString::new(name).unwrap_or_else(|_| panic!("name of list contains a nul byte"));
+    ffi::lua_getfield(raw_lua, state, c"lists".as_ptr());
+    ffi::lua_getfield(raw_lua, -1, name.as_ptr());
+    if ffi::lua_istable(raw_lua, -1) {
+        return;
+    }
+
+    ffi::lua_pop(raw_lua, 1);
+    ffi::lua_newtable(raw_lua);
+    ffi::lua_newtable(raw_lua);
+    ffi::lua_setfield(raw_lua, -2, c"handlers".as_ptr());
+    ffi::lua_pushvalue(raw_lua, -1);
+    ffi::lua_pushcclosure(raw_lua, Some(dispatch), 1);
+    ffi::lua_pushvalue(raw_lua, -1);
+    ffi::lua_setglobal(raw_lua, name.as_ptr());
+    ffi::lua_setfield(raw_lua, -2, c"dispatch".as_ptr());
+    ffi::lua_pushvalue(raw_lua, -1);
+    ffi::lua_setfield(raw_lua, -3, name.as_ptr());
+}
+
+// The function of a list. Its upvalue is the list. The handlers are called with `lua_call`, which
+// lets their errors go through this function, so nothing here may need to be dropped.
+extern "C" fn dispatch(raw_lua: *mut ffi::lua_State) -> c_int {
+    unsafe {
+        let nargs = ffi::lua_gettop(raw_lua);
+        ffi::lua_getfield(raw_lua, ffi::lua_upvalueindex(1), c"handlers".as_ptr());
+        let handlers = nargs + 1;
+        let entry = nargs + 2;
+        let len = ffix::lua_rawlen(LuaContext::new_unchecked(raw_lua), handlers);
+
+        for i in 1..=len {
+            ffi::lua_rawgeti(raw_lua, handlers, i as _);
+            ffi::lua_getfield(raw_lua, entry, c"removed".as_ptr());
+            let removed = ffi::lua_toboolean(raw_lua, -1) != 0;
+            ffi::lua_pop(raw_lua, 1);
+            if !removed {
+                ffi::lua_getfield(raw_lua, entry, c"fn".as_ptr());
+                for arg in 1..=nargs {
+                    ffi::lua_pushvalue(raw_lua, arg);
+                }
+                ffi::lua_call(raw_lua, nargs, ffi::LUA_MULTRET);
+
+                let results = ffi::lua_gettop(raw_lua) - entry;
+                if results > 0 && !ffi::lua_isnil(raw_lua, entry + 1) {
+                    return results;
+                }
+            }
+            ffi::lua_settop(raw_lua, handlers);
+        }
+
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{function0, InsideCallback, Lua, LuaRef};
+
+    fn handler(lua: &mut Lua, code: &str) -> LuaRef {
+        lua.execute(&format!("return function(x) {} end", code)).unwrap()
+    }
+
+    #[test]
+    fn priorities_and_early_exit() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("log = ''").unwrap();
+        for (name, priority) in [("a", 1), ("b", 5), ("c", 1), ("d", -2)] {
+            let handler = handler(&mut lua, &format!("log = log .. '{}' .. x", name));
+            lua.register_ordered("event", priority, &handler);
+        }
+        lua.execute::<()>("event(1)").unwrap();
+        assert_eq!(lua.get::<String, _>("log").unwrap(), "b1a1c1d1");
+
+        let stop = handler(&mut lua, "return x * 2, 'stopped'");
+        let id = lua.register_ordered("event", 3, &stop);
+        let result: String = lua.execute("local n, tag = event(4); return tag .. n").unwrap();
+        assert_eq!(result, "stopped8");
+        assert_eq!(lua.get::<String, _>("log").unwrap(), "b1a1c1d1b4");
+
+        assert!(lua.unregister_ordered(id));
+        assert!(!lua.unregister_ordered(id));
+        let dispatch = lua.ordered_dispatcher("event");
+        let result: Option<i32> = dispatch.call_with_args(&mut lua, 2).unwrap();
+        assert_eq!(result, None);
+        assert_eq!(lua.get::<String, _>("log").unwrap(), "b1a1c1d1b4b2a2c2d2");
+    }
+
+    #[test]
+    fn changes_during_dispatch() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("log = ''").unwrap();
+        let third = handler(&mut lua, "log = log .. 'third '");
+        let first = handler(&mut lua, "log = log .. 'first '; change()");
+        let second = handler(&mut lua, "log = log .. 'second '");
+        lua.register_ordered("tick", 3, &first);
+        let second = lua.register_ordered("tick", 2, &second);
+
+        lua.set(
+            "change",
+            function0(move || {
+                InsideCallback::with_current(|lua| {
+                    if lua.unregister_ordered(second) {
+                        lua.register_ordered("tick", 1, &third);
+                    }
+                });
+            }),
+        );
+
+        // The second handler is skipped, and the third one only runs from the next dispatch.
+        lua.execute::<()>("tick()").unwrap();
+        assert_eq!(lua.get::<String, _>("log").unwrap(), "first ");
+        lua.execute::<()>("tick()").unwrap();
+        assert_eq!(lua.get::<String, _>("log").unwrap(), "first first third ");
+    }
+}
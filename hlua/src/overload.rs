@@ -0,0 +1,242 @@
+use std::ptr::NonNull;
+
+use crate::functions_write::{push_closure, try_call_function, ContextFunction, FunctionExt};
+use crate::read_struct::short_type_name;
+use crate::{ffix, AsMutLua, Function, InsideCallback, LuaRead, Push, PushGuard, PushOne, Void};
+
+/// Builds a Lua function that calls the first of several Rust functions whose parameters match
+/// the arguments of the call.
+///
+/// `functions` is a tuple of up to eight `Function`s or `ContextFunction`s, which are tried in
+/// order. The first one whose parameters can be read from the arguments is called, and its
+/// results are returned. If none of them matches, the call raises an error that lists the
+/// accepted parameter types.
+///
+/// The arguments that follow the parameters of a function are ignored, like they are for a
+/// single `Function`, so functions with more parameters should come first.
+///
+/// # Example
+///
+/// ```
+/// use hlua::Lua;
+///
+/// let mut lua = Lua::new();
+/// lua.set("area", hlua::overload((
+///     hlua::function2(|w: f64, h: f64| w * h),
+///     hlua::function1(|side: f64| side * side),
+///     hlua::function1(|name: String| if name == "unit" { 1.0 } else { 0.0 }),
+/// )));
+///
+/// assert_eq!(lua.execute::<f64>("return area(2, 3)").unwrap(), 6.0);
+/// assert_eq!(lua.execute::<f64>("return area(4)").unwrap(), 16.0);
+/// assert_eq!(lua.execute::<f64>("return area('unit')").unwrap(), 1.0);
+///
+/// let err = lua.execute::<f64>("return area({})").unwrap_err().to_string();
+/// assert!(err.contains("expected (f64, f64), (f64) or (String)"), "{}", err);
+/// ```
+#[inline]
+pub fn overload<T: OverloadSet>(functions: T) -> Overload<T> {
+    Overload(functions)
+}
+
+/// Lua function built by `overload`, which dispatches each call to one of several Rust
+/// functions.
+#[derive(Debug)]
+pub struct Overload<T>(T);
+
+/// Function that can be one of the alternatives of `overload`.
+///
+/// Implemented for `Function` and `ContextFunction`.
+pub trait OverloadCandidate {
+    /// Calls the function if the arguments on the stack match its parameters, pushing its results
+    /// and returning their number.
+    #[doc(hidden)]
+    unsafe fn try_call(&mut self, lua: *mut ffi::lua_State) -> Option<libc::c_int>;
+
+    /// Types of the parameters, for error messages.
+    #[doc(hidden)]
+    fn signature() -> String;
+}
+
+/// Tuple of functions given to `overload`.
+pub trait OverloadSet {
+    /// Calls the first function whose parameters match the arguments on the stack.
+    #[doc(hidden)]
+    unsafe fn try_call(&mut self, lua: *mut ffi::lua_State) -> Option<libc::c_int>;
+
+    /// Signatures of the functions, in order.
+    #[doc(hidden)]
+    fn signatures() -> Vec<String>;
+}
+
+fn signature<P>() -> String {
+    // One-element tuples are written like the parameter lists of the other ones.
+    short_type_name(std::any::type_name::<P>()).replace(",)", ")")
+}
+
+impl<Z, P, R> OverloadCandidate for Function<Z, P, R>
+where
+    Function<Z, P, R>: FunctionExt<P, Output = R>,
+    P: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+    R: for<'p> Push<&'p mut InsideCallback>,
+{
+    #[inline]
+    unsafe fn try_call(&mut self, lua: *mut ffi::lua_State) -> Option<libc::c_int> {
+        try_call_function::<Self, P, R>(lua, self)
+    }
+
+    fn signature() -> String {
+        signature::<P>()
+    }
+}
+
+impl<Z, P, R> OverloadCandidate for ContextFunction<Z, P, R>
+where
+    ContextFunction<Z, P, R>: FunctionExt<P, Output = R>,
+    P: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+    R: for<'p> Push<&'p mut InsideCallback>,
+{
+    #[inline]
+    unsafe fn try_call(&mut self, lua: *mut ffi::lua_State) -> Option<libc::c_int> {
+        try_call_function::<Self, P, R>(lua, self)
+    }
+
+    fn signature() -> String {
+        signature::<P>()
+    }
+}
+
+macro_rules! impl_overload_set {
+    ($($f:ident),*) => (
+        impl<$($f: OverloadCandidate),*> OverloadSet for ($($f,)*) {
+            #[allow(non_snake_case)]
+            #[inline]
+            unsafe fn try_call(&mut self, lua: *mut ffi::lua_State) -> Option<libc::c_int> {
+                let ($($f,)*) = self;
+                $(
+                    if let Some(nb) = $f.try_call(lua) {
+                        return Some(nb);
+                    }
+                )*
+                None
+            }
+
+            fn signatures() -> Vec<String> {
+                vec![$($f::signature()),*]
+            }
+        }
+    )
+}
+
+impl_overload_set!(A);
+impl_overload_set!(A, B);
+impl_overload_set!(A, B, C);
+impl_overload_set!(A, B, C, D);
+impl_overload_set!(A, B, C, D, E);
+impl_overload_set!(A, B, C, D, E, F);
+impl_overload_set!(A, B, C, D, E, F, G);
+impl_overload_set!(A, B, C, D, E, F, G, H);
+
+impl<'lua, L, T> Push<L> for Overload<T>
+where
+    L: AsMutLua<'lua>,
+    T: 'lua + OverloadSet,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        unsafe {
+            let raw_lua = lua.as_mut_lua();
+            push_closure(raw_lua, self, wrapper::<T>);
+            Ok(PushGuard { lua, size: 1, raw_lua })
+        }
+    }
+}
+
+impl<'lua, L, T> PushOne<L> for Overload<T>
+where
+    L: AsMutLua<'lua>,
+    T: 'lua + OverloadSet,
+{
+}
+
+extern "C" fn wrapper<T: OverloadSet>(lua: *mut ffi::lua_State) -> libc::c_int {
+    let data = match std::mem::size_of::<Overload<T>>() {
+        0 => NonNull::dangling().as_ptr(),
+        _ => unsafe { ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) },
+    };
+    let functions = unsafe { &mut (*data.cast::<Overload<T>>()).0 };
+    if let Some(nb) = unsafe { functions.try_call(lua) } {
+        return nb;
+    }
+
+    let message = no_match_message(lua, T::signatures());
+    unsafe {
+        ffi::lua_pushlstring(lua, message.as_ptr().cast(), message.len());
+        drop(message);
+        ffix::lua_error(lua)
+    }
+}
+
+// Describes the arguments of a call that matches none of the signatures.
+fn no_match_message(lua: *mut ffi::lua_State, signatures: Vec<String>) -> String {
+    let argc = unsafe { ffi::lua_gettop(lua) };
+    let arguments: Vec<_> = (1..=argc)
+        .map(|index| unsafe {
+            let name = ffi::lua_typename(lua, ffi::lua_type(lua, index));
+            std::ffi::CStr::from_ptr(name).to_string_lossy()
+        })
+        .collect();
+
+    let expected = match signatures.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    };
+    format!(
+        "no overload of the callback function accepts ({}), expected {}",
+        arguments.join(", "),
+        expected
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{function0, function1, function2, function_with_lua1, overload, Lua, Varargs};
+
+    #[test]
+    fn first_match_is_called() {
+        let mut lua = Lua::new();
+        lua.set(
+            "describe",
+            overload((
+                function2(|a: i32, b: i32| format!("pair {} {}", a, b)),
+                function1(|s: String| format!("string {}", s)),
+                function_with_lua1(|lua: &mut Lua, t: Varargs| {
+                    lua.set("fallback_used", true);
+                    format!("rest {}", t.len())
+                }),
+            )),
+        );
+
+        assert_eq!(lua.execute::<String>("return describe(1, 2)").unwrap(), "pair 1 2");
+        // Numbers can be read as strings, so the order of the functions matters.
+        assert_eq!(lua.execute::<String>("return describe(3)").unwrap(), "string 3");
+        assert_eq!(lua.execute::<String>("return describe({}, {})").unwrap(), "rest 2");
+        assert_eq!(lua.get::<bool, _>("fallback_used"), Some(true));
+    }
+
+    #[test]
+    fn no_match_lists_signatures() {
+        let mut lua = Lua::new();
+        lua.set("f", overload((function1(|n: i32| n), function0(|| 0))));
+        lua.set("g", overload((function2(|a: bool, b: i32| b + a as i32),)));
+
+        // Calls with extra arguments still match, so `f` accepts anything.
+        assert_eq!(lua.execute::<i32>("return f({})").unwrap(), 0);
+        let err = lua.execute::<i32>("return g('x', {})").unwrap_err().to_string();
+        let expected = "accepts (string, table), expected (bool, i32)";
+        assert!(err.contains(expected), "{}", err);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    any::Any,
+    ffi::CStr,
+    panic::{self, AssertUnwindSafe},
+    process,
+};
+
+use crate::{AsMutLua, Lua, LuaContext};
+
+// Key of the registry entry that is true if the process aborts when a callback panics.
+const ABORT_KEY: &CStr = c"hlua.abort_on_panic";
+
+/// What happens when a Rust callback called by Lua panics. See `Lua::set_panic_policy`.
+///
+/// A panic can't unwind through the frames of the Lua interpreter, so it is always stopped at
+/// the boundary between Lua and the callback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum PanicPolicy {
+    /// The panic is caught and raised as a Lua error with the message of the panic, such as
+    /// `callback panicked: index out of bounds`. Lua code can catch it with `pcall`, and the
+    /// Rust code running the script gets it as a `LuaError::ExecutionError`.
+    #[default]
+    RaiseError,
+
+    /// The process is aborted, after the panic hook reported the panic. This is for applications
+    /// that consider the state of the program unreliable after a panic.
+    Abort,
+}
+
+impl<'lua> Lua<'lua> {
+    /// Sets what happens when a Rust callback panics, by default `PanicPolicy::RaiseError`.
+    ///
+    /// This applies to the functions, methods and metamethods pushed with hlua, including the
+    /// ones of `Scope` and `overload`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// let divide = |a: i32, b: i32| a.checked_div(b).expect("division by zero");
+    /// lua.set("divide", hlua::function2(divide));
+    ///
+    /// let err = lua.execute::<i32>("return divide(1, 0)").unwrap_err();
+    /// assert!(err.to_string().contains("callback panicked: division by zero"));
+    ///
+    /// lua.set_panic_policy(hlua::PanicPolicy::Abort);
+    /// assert_eq!(lua.panic_policy(), hlua::PanicPolicy::Abort);
+    /// ```
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        unsafe {
+            let l = self.as_mut_lua().as_ptr();
+            ffi::lua_pushboolean(l, (policy == PanicPolicy::Abort) as libc::c_int);
+            ffi::lua_setfield(l, ffi::LUA_REGISTRYINDEX, ABORT_KEY.as_ptr());
+        }
+    }
+
+    /// Returns the policy set with `set_panic_policy`.
+    pub fn panic_policy(&self) -> PanicPolicy {
+        unsafe { panic_policy(self.lua) }
+    }
+}
+
+unsafe fn panic_policy(lua: LuaContext) -> PanicPolicy {
+    let l = lua.as_ptr();
+    ffi::lua_getfield(l, ffi::LUA_REGISTRYINDEX, ABORT_KEY.as_ptr());
+    let abort = ffi::lua_toboolean(l, -1) != 0;
+    ffi::lua_pop(l, 1);
+    match abort {
+        true => PanicPolicy::Abort,
+        false => PanicPolicy::RaiseError,
+    }
+}
+
+/// Calls the Rust function `f` of a callback, stopping the panics according to the policy of the
+/// context. Returns the message of the error to raise if `f` panicked.
+///
+/// The error must be raised once the values that need to be dropped are, since `lua_error`
+/// doesn't unwind the Rust frames.
+pub(crate) fn catch_panic<R>(lua: LuaContext, f: impl FnOnce() -> R) -> Result<R, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        if unsafe { panic_policy(lua) } == PanicPolicy::Abort {
+            process::abort();
+        }
+        format!("callback panicked: {}", payload_message(&*payload))
+    })
+}
+
+// Returns the message given to `panic!`.
+fn payload_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&'static str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map_or("Box<dyn Any>", |message| &message[..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{function1, Lua, LuaError, MethodsBuilder, PanicPolicy, UserData};
+
+    struct Gauge(u8);
+
+    impl UserData for Gauge {
+        fn add_methods(methods: &mut MethodsBuilder<Self>) {
+            methods.add_method_mut("fill", |this, amount: u8| {
+                this.0 = this.0.checked_add(amount).expect("gauge overflow");
+            });
+        }
+    }
+
+    crate::implement_lua_push!(Gauge);
+
+    #[test]
+    fn panics_become_lua_errors() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        assert_eq!(lua.panic_policy(), PanicPolicy::RaiseError);
+        lua.set("fail", function1(|code: i32| -> i32 { panic!("failed with {}", code) }));
+        lua.set("gauge", Gauge(250));
+
+        match lua.execute::<()>("fail(3)") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert_eq!(err.message(), "callback panicked: failed with 3")
+            },
+            other => panic!("{:?}", other),
+        }
+
+        let code = "
+            local ok, err = pcall(fail, 1)
+            assert(not ok and err == 'callback panicked: failed with 1')
+            gauge:fill(5)
+            return pcall(gauge.fill, gauge, 1)
+        ";
+        let (ok, err): (bool, String) = lua.execute_multi(code).unwrap();
+        assert!(!ok);
+        assert_eq!(err, "callback panicked: gauge overflow");
+
+        // The borrow of the user data was released.
+        lua.execute::<()>("gauge:fill(0)").unwrap();
+    }
+}
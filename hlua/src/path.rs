@@ -0,0 +1,317 @@
+use std::{error::Error, fmt};
+
+use crate::{ffix, AsLua, AsMutLua, Lua, LuaRead, LuaTable, Push, PushGuard, PushOne, Void};
+
+/// Key of one of the levels of a `LuaPath`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathKey {
+    /// A string key, such as `inventory` in `player.inventory`.
+    Name(String),
+    /// An integer key, such as `1` in `inventory[1]`.
+    Index(i32),
+}
+
+impl From<&str> for PathKey {
+    #[inline]
+    fn from(name: &str) -> PathKey {
+        PathKey::Name(name.to_owned())
+    }
+}
+
+impl From<String> for PathKey {
+    #[inline]
+    fn from(name: String) -> PathKey {
+        PathKey::Name(name)
+    }
+}
+
+impl From<i32> for PathKey {
+    #[inline]
+    fn from(index: i32) -> PathKey {
+        PathKey::Index(index)
+    }
+}
+
+/// Accessor for a value nested inside tables, created with `Lua::g` or `LuaTable::at`.
+///
+/// Nothing is looked up until `get` or `set` is called. If the lookup fails, the error contains
+/// the path of the value that caused the failure.
+///
+/// # Example
+///
+/// ```
+/// let mut lua = hlua::Lua::new();
+/// lua.execute::<()>("player = { name = 'Ann', inventory = { 'sword', 'shield' } }").unwrap();
+///
+/// let item: String = lua.g("player").at("inventory").at(2).get().unwrap();
+/// assert_eq!(item, "shield");
+///
+/// lua.g("player").at("name").set("Bob").unwrap();
+///
+/// let err = lua.g("player").at("stats").at("hp").get::<i32>().unwrap_err();
+/// assert_eq!(err.to_string(), "player.stats is not a table");
+/// ```
+#[derive(Debug)]
+pub struct LuaPath<L> {
+    lua: L,
+    // Absolute index of the table the path starts from, or `None` for the globals table.
+    root: Option<libc::c_int>,
+    keys: Vec<PathKey>,
+}
+
+/// Error returned when a `LuaPath` can't be resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathError {
+    /// Path of the value that caused the error, for example `player.inventory[1]`.
+    pub path: String,
+    /// What went wrong.
+    pub kind: PathErrorKind,
+}
+
+/// Kind of a `PathError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathErrorKind {
+    /// The value was expected to be a table because the path continues after it.
+    NotATable,
+    /// The value is nil.
+    Missing,
+    /// The value doesn't have the requested type.
+    WrongType,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            PathErrorKind::NotATable => write!(f, "{} is not a table", self.path),
+            PathErrorKind::Missing => write!(f, "{} is nil", self.path),
+            PathErrorKind::WrongType => write!(f, "{} has the wrong type", self.path),
+        }
+    }
+}
+
+impl Error for PathError {}
+
+impl<'lua, L> LuaPath<L>
+where
+    L: AsMutLua<'lua>,
+{
+    /// Adds a level to the path.
+    #[inline]
+    pub fn at<K>(mut self, key: K) -> LuaPath<L>
+    where
+        K: Into<PathKey>,
+    {
+        self.keys.push(key.into());
+        self
+    }
+
+    /// Reads the value at the end of the path.
+    pub fn get<'a, V>(&'a mut self) -> Result<V, PathError>
+    where
+        V: LuaRead<PushGuard<&'a mut L>>,
+    {
+        let size = self.resolve(self.keys.len())?;
+        let raw_lua = self.lua.as_mut_lua();
+
+        unsafe {
+            if ffi::lua_isnil(raw_lua.as_ptr(), -1) {
+                ffi::lua_pop(raw_lua.as_ptr(), size);
+                return Err(path_error(&self.keys, PathErrorKind::Missing));
+            }
+        }
+
+        let keys = &self.keys;
+        let guard = PushGuard { lua: &mut self.lua, size, raw_lua };
+        LuaRead::lua_read(guard).map_err(|_| path_error(keys, PathErrorKind::WrongType))
+    }
+
+    /// Modifies the value at the end of the path.
+    ///
+    /// All the levels of the path except the last one must already exist.
+    pub fn set<V, E>(&mut self, value: V) -> Result<(), PathError>
+    where
+        for<'b> V: PushOne<&'b mut L, Err = E>,
+        E: Into<Void>,
+    {
+        let parent_depth = self.keys.len() - 1;
+        let size = self.resolve(parent_depth)?;
+        let raw_lua = self.lua.as_mut_lua();
+
+        unsafe {
+            if !ffi::lua_istable(raw_lua.as_ptr(), -1) {
+                ffi::lua_pop(raw_lua.as_ptr(), size);
+                return Err(path_error(&self.keys[..parent_depth], PathErrorKind::NotATable));
+            }
+
+            push_key(raw_lua, &self.keys[parent_depth]);
+            value.push_no_err(&mut self.lua).assert_one_and_forget();
+            ffi::lua_settable(raw_lua.as_ptr(), -3);
+            ffi::lua_pop(raw_lua.as_ptr(), size);
+        }
+
+        Ok(())
+    }
+
+    // Pushes the root, then the values of the first `depth` levels of the path. Returns the
+    // number of pushed values, or pops them and returns an error if a level isn't a table.
+    fn resolve(&mut self, depth: usize) -> Result<i32, PathError> {
+        let raw_lua = self.lua.as_mut_lua();
+
+        unsafe {
+            match self.root {
+                Some(index) => ffi::lua_pushvalue(raw_lua.as_ptr(), index),
+                None => ffix::lua_pushglobaltable(raw_lua),
+            }
+
+            for (n, key) in self.keys[..depth].iter().enumerate() {
+                if !ffi::lua_istable(raw_lua.as_ptr(), -1) {
+                    ffi::lua_pop(raw_lua.as_ptr(), n as i32 + 1);
+                    return Err(path_error(&self.keys[..n], PathErrorKind::NotATable));
+                }
+
+                push_key(raw_lua, key);
+                ffi::lua_gettable(raw_lua.as_ptr(), -2);
+            }
+        }
+
+        Ok(depth as i32 + 1)
+    }
+}
+
+unsafe impl<'lua, L> AsLua<'lua> for LuaPath<L>
+where
+    L: AsLua<'lua>,
+{
+    #[inline]
+    fn as_lua(&self) -> crate::LuaContext {
+        self.lua.as_lua()
+    }
+}
+
+unsafe impl<'lua, L> AsMutLua<'lua> for LuaPath<L>
+where
+    L: AsMutLua<'lua>,
+{
+    #[inline]
+    fn as_mut_lua(&mut self) -> crate::LuaContext {
+        self.lua.as_mut_lua()
+    }
+}
+
+unsafe fn push_key(raw_lua: crate::LuaContext, key: &PathKey) {
+    match key {
+        PathKey::Name(name) => (&name[..]).push_no_err(raw_lua).forget(),
+        PathKey::Index(index) => (*index).push_no_err(raw_lua).forget(),
+    };
+}
+
+fn path_error(keys: &[PathKey], kind: PathErrorKind) -> PathError {
+    let mut path = String::new();
+    for key in keys {
+        match key {
+            PathKey::Name(name) if path.is_empty() => path.push_str(name),
+            PathKey::Name(name) => {
+                path.push('.');
+                path.push_str(name);
+            },
+            PathKey::Index(index) => path.push_str(&format!("[{}]", index)),
+        }
+    }
+
+    PathError { path, kind }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Returns an accessor for the global variable `name`, which can be used to access the
+    /// content of nested tables. See `LuaPath`.
+    #[inline]
+    pub fn g(&mut self, name: &str) -> LuaPath<&mut Lua<'lua>> {
+        LuaPath { lua: self, root: None, keys: vec![PathKey::from(name)] }
+    }
+}
+
+impl<'lua, L> LuaTable<L>
+where
+    L: AsMutLua<'lua>,
+{
+    /// Returns an accessor for the element `key` of the table, which can be used to access the
+    /// content of nested tables. See `LuaPath`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("config = { window = { size = { 800, 600 } } }").unwrap();
+    ///
+    /// let mut config: hlua::LuaTable<_> = lua.get("config").unwrap();
+    /// let width: i32 = config.at("window").at("size").at(1).get().unwrap();
+    /// assert_eq!(width, 800);
+    /// ```
+    #[inline]
+    pub fn at<K>(&mut self, key: K) -> LuaPath<&mut LuaTable<L>>
+    where
+        K: Into<PathKey>,
+    {
+        let root = unsafe { ffi::lua_gettop(self.as_mut_lua().as_ptr()) };
+        LuaPath { lua: self, root: Some(root), keys: vec![key.into()] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, LuaTable, PathError, PathErrorKind};
+
+    #[test]
+    fn nested_get_and_set() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("world = { players = { { name = 'a' }, { name = 'b' } } }").unwrap();
+
+        let name: String = lua.g("world").at("players").at(2).at("name").get().unwrap();
+        assert_eq!(name, "b");
+
+        lua.g("world").at("players").at(1).at("score").set(10).unwrap();
+        let score: i32 = lua.execute("return world.players[1].score").unwrap();
+        assert_eq!(score, 10);
+
+        let mut path = lua.g("world").at("players");
+        let mut players: LuaTable<_> = path.get().unwrap();
+        assert!(players.get::<LuaTable<_>, _, _>(1).is_some());
+    }
+
+    #[test]
+    fn error_paths() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("world = { players = { 'a' } }").unwrap();
+
+        let err = lua.g("world").at("players").at(3).get::<String>().unwrap_err();
+        assert_eq!(
+            err,
+            PathError { path: "world.players[3]".to_owned(), kind: PathErrorKind::Missing }
+        );
+
+        let err = lua.g("world").at("players").at(1).get::<i32>().unwrap_err();
+        assert_eq!(err.kind, PathErrorKind::WrongType);
+
+        let err = lua.g("world").at("players").at(1).at("x").set(5).unwrap_err();
+        assert_eq!(
+            err,
+            PathError { path: "world.players[1]".to_owned(), kind: PathErrorKind::NotATable }
+        );
+
+        let err = lua.g("missing").at("x").get::<i32>().unwrap_err();
+        assert_eq!(err.to_string(), "missing is not a table");
+    }
+
+    #[test]
+    fn stack_is_balanced() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = { b = { c = 1 } }").unwrap();
+        let top = unsafe { ffi::lua_gettop(lua.lua.as_ptr()) };
+
+        let _ = lua.g("a").at("b").at("c").get::<i32>();
+        let _ = lua.g("a").at("x").at("y").get::<i32>();
+        let _ = lua.g("a").at("b").at("d").set("e");
+        let _ = lua.g("a").at("x").at("y").set("e");
+        assert_eq!(unsafe { ffi::lua_gettop(lua.lua.as_ptr()) }, top);
+    }
+}
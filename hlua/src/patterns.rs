@@ -0,0 +1,670 @@
+use std::{ffi::CStr, slice};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, Lua, LuaContext, Push};
+
+// Maximum number of captures in a pattern, and maximum recursion depth of the matcher. These are
+// the values used by Lua.
+const MAX_CAPTURES: usize = 32;
+const MAX_DEPTH: usize = 200;
+
+// Special values of the length of a capture.
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
+
+// Stored as an upvalue of the functions installed by `guard_string_patterns`.
+struct PatternGuard {
+    kind: Kind,
+    max_steps: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Find,
+    Match,
+    Gmatch,
+    Gsub,
+}
+
+impl<'lua> Lua<'lua> {
+    /// Limits the work that the pattern-matching functions of the `string` library can do.
+    ///
+    /// Lua patterns can take a time that grows exponentially with the number of repetition
+    /// items, such as `(.-)(.-)(.-)x`. When running untrusted scripts, or when matching untrusted
+    /// patterns, this can be used to make a script hang. After calling this method,
+    /// `string.find`, `string.match`, `string.gmatch` and `string.gsub` first count the number
+    /// of steps the matching requires, and raise a Lua error instead of matching if it exceeds
+    /// `max_steps`. A step is roughly one comparison of a character with a pattern item.
+    ///
+    /// The string library must have been opened before calling this method. Otherwise this
+    /// method does nothing.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// lua.guard_string_patterns(100_000);
+    ///
+    /// let key: String = lua.execute("return ('key=value'):match('(%w+)=')").unwrap();
+    /// assert_eq!(key, "key");
+    ///
+    /// let err = lua
+    ///     .execute::<()>("return string.rep('a', 100):match(string.rep('(.-)', 10) .. 'b')")
+    ///     .unwrap_err();
+    /// assert!(err.to_string().contains("pattern too complex"));
+    /// ```
+    pub fn guard_string_patterns(&mut self, max_steps: u64) {
+        let functions = [
+            (c"find", Kind::Find),
+            (c"match", Kind::Match),
+            (c"gmatch", Kind::Gmatch),
+            (c"gsub", Kind::Gsub),
+        ];
+
+        unsafe {
+            let raw_lua = self.lua.as_ptr();
+            ffix::lua_pushglobaltable(self.lua);
+            ffi::lua_getfield(raw_lua, -1, c"string".as_ptr());
+
+            if ffi::lua_istable(raw_lua, -1) {
+                for (name, kind) in functions {
+                    guard_function(self.lua, name, PatternGuard { kind, max_steps });
+                }
+            }
+
+            ffi::lua_pop(raw_lua, 2);
+        }
+    }
+}
+
+// Replaces the function `name` of the table at the top of the stack with a guarded version.
+unsafe fn guard_function(lua: LuaContext, name: &CStr, guard: PatternGuard) {
+    let raw_lua = lua.as_ptr();
+    ffi::lua_getfield(raw_lua, -1, name.as_ptr());
+
+    // Guarding the function a second time only replaces the limit.
+    if !ffi::lua_getupvalue(raw_lua, -1, 2).is_null() {
+        match userdata_mut::<PatternGuard>(lua, -1) {
+            Some(existing) => {
+                existing.max_steps = guard.max_steps;
+                ffi::lua_pop(raw_lua, 2);
+                return;
+            },
+            None => ffi::lua_pop(raw_lua, 1),
+        }
+    }
+
+    if !ffi::lua_isfunction(raw_lua, -1) {
+        ffi::lua_pop(raw_lua, 1);
+        return;
+    }
+
+    // The original function is kept as first upvalue.
+    push_userdata(guard, lua, |_| {}).forget();
+    ffi::lua_pushcclosure(raw_lua, Some(guarded_function), 2);
+    ffi::lua_setfield(raw_lua, -2, name.as_ptr());
+}
+
+extern "C" fn guarded_function(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        let nargs = ffi::lua_gettop(lua);
+
+        let exceeded = match userdata_mut::<PatternGuard>(raw_lua, ffi::lua_upvalueindex(2)) {
+            Some(guard) => exceeds_limit(lua, guard),
+            None => None,
+        };
+
+        if let Some(max_steps) = exceeded {
+            let msg = format!("pattern too complex (more than {} steps)", max_steps);
+            msg.push_no_err(raw_lua).forget();
+            ffix::lua_error(lua);
+        }
+
+        ffi::lua_pushvalue(lua, ffi::lua_upvalueindex(1));
+        for index in 1..=nargs {
+            ffi::lua_pushvalue(lua, index);
+        }
+        ffi::lua_call(lua, nargs, ffi::LUA_MULTRET);
+        ffi::lua_gettop(lua) - nargs
+    }
+}
+
+// Returns the limit if matching the arguments of the running function would exceed it.
+unsafe fn exceeds_limit(lua: *mut ffi::lua_State, guard: &PatternGuard) -> Option<u64> {
+    let (subject, pattern) = match (string_arg(lua, 1), string_arg(lua, 2)) {
+        (Some(subject), Some(pattern)) => (subject, pattern),
+        // The original function raises the appropriate error.
+        _ => return None,
+    };
+
+    // `string.find` doesn't use patterns in plain mode.
+    if guard.kind == Kind::Find && ffi::lua_toboolean(lua, 4) != 0 {
+        return None;
+    }
+
+    let init = match guard.kind {
+        Kind::Gsub => 1,
+        _ if ffi::lua_gettop(lua) < 3 => 1,
+        _ => match ffi::lua_type(lua, 3) {
+            ffi::LUA_TNUMBER => ffi::lua_tonumberx(lua, 3, std::ptr::null_mut()) as i64,
+            _ => 1,
+        },
+    };
+    let max_replacements = match (guard.kind, ffi::lua_type(lua, 4)) {
+        (Kind::Gsub, ffi::LUA_TNUMBER) => Some(ffi::lua_tonumberx(lua, 4, std::ptr::null_mut())),
+        _ => None,
+    };
+
+    let mut matcher = Matcher::new(subject, pattern, guard.max_steps);
+    let result = match guard.kind {
+        Kind::Find | Kind::Match => matcher.find(start_position(init, subject.len())),
+        Kind::Gmatch => matcher.gmatch(start_position(init, subject.len())),
+        Kind::Gsub => matcher.gsub(max_replacements),
+    };
+
+    match result {
+        Err(Stop::TooManySteps) => Some(guard.max_steps),
+        // The original function raises the appropriate error for malformed patterns.
+        Ok(()) | Err(Stop::Malformed) => None,
+    }
+}
+
+/// Returns the argument at `index` if it is a string or a number.
+pub(crate) unsafe fn string_arg<'a>(
+    lua: *mut ffi::lua_State,
+    index: libc::c_int,
+) -> Option<&'a [u8]> {
+    match ffi::lua_type(lua, index) {
+        ffi::LUA_TSTRING | ffi::LUA_TNUMBER => {
+            let mut len = 0;
+            let ptr = ffi::lua_tolstring(lua, index, &mut len);
+            Some(slice::from_raw_parts(ptr.cast(), len))
+        },
+        _ => None,
+    }
+}
+
+/// Converts the `init` argument of `string.find` to an offset, or `None` if it is past the end.
+pub(crate) fn start_position(init: i64, len: usize) -> Option<usize> {
+    let len = len as i64;
+    let position = match init {
+        i if i > 0 => i,
+        0 => 1,
+        i if i < -len => 1,
+        i => len + i + 1,
+    };
+
+    match position > len + 1 {
+        true => None,
+        false => Some(position as usize - 1),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    TooManySteps,
+    Malformed,
+}
+
+// Port of the matcher of the Lua string library, which counts the steps it performs instead of
+// producing results.
+struct Matcher<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    captures: [(usize, isize); MAX_CAPTURES],
+    depth: usize,
+    steps: u64,
+    max_steps: u64,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(src: &'a [u8], pat: &'a [u8], max_steps: u64) -> Matcher<'a> {
+        Matcher {
+            src,
+            pat,
+            level: 0,
+            captures: [(0, 0); MAX_CAPTURES],
+            depth: 0,
+            steps: 0,
+            max_steps,
+        }
+    }
+
+    // Simulates `string.find` and `string.match`.
+    fn find(&mut self, init: Option<usize>) -> Result<(), Stop> {
+        let mut s = match init {
+            Some(init) => init,
+            None => return Ok(()),
+        };
+        let (anchor, p) = self.anchor();
+
+        loop {
+            self.level = 0;
+            if self.do_match(s, p)?.is_some() || anchor || s >= self.src.len() {
+                return Ok(());
+            }
+            s += 1;
+        }
+    }
+
+    // Simulates iterating over all the results of `string.gmatch`.
+    fn gmatch(&mut self, init: Option<usize>) -> Result<(), Stop> {
+        let mut s = match init {
+            Some(init) => init,
+            None => return Ok(()),
+        };
+        let mut last_match = None;
+
+        while s <= self.src.len() {
+            self.level = 0;
+            match self.do_match(s, 0)? {
+                Some(e) if Some(e) != last_match => {
+                    s = e;
+                    last_match = Some(e);
+                },
+                _ => s += 1,
+            }
+        }
+        Ok(())
+    }
+
+    // Simulates `string.gsub`.
+    fn gsub(&mut self, max_replacements: Option<f64>) -> Result<(), Stop> {
+        let (anchor, p) = self.anchor();
+        let max = max_replacements.unwrap_or(self.src.len() as f64 + 1.0);
+        let mut replacements = 0.0;
+        let mut s = 0;
+        let mut last_match = None;
+
+        while replacements < max {
+            self.level = 0;
+            match self.do_match(s, p)? {
+                Some(e) if Some(e) != last_match => {
+                    replacements += 1.0;
+                    s = e;
+                    last_match = Some(e);
+                },
+                _ if s < self.src.len() => s += 1,
+                _ => break,
+            }
+            if anchor {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn anchor(&self) -> (bool, usize) {
+        match self.pat.first() {
+            Some(b'^') => (true, 1),
+            _ => (false, 0),
+        }
+    }
+
+    #[inline]
+    fn step(&mut self) -> Result<(), Stop> {
+        self.steps += 1;
+        match self.steps > self.max_steps {
+            true => Err(Stop::TooManySteps),
+            false => Ok(()),
+        }
+    }
+
+    fn do_match(&mut self, s: usize, p: usize) -> Result<Option<usize>, Stop> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Stop::Malformed);
+        }
+        self.depth += 1;
+        let result = self.match_items(s, p);
+        self.depth -= 1;
+        result
+    }
+
+    fn match_items(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, Stop> {
+        loop {
+            self.step()?;
+            if p == self.pat.len() {
+                return Ok(Some(s));
+            }
+
+            match self.pat[p] {
+                b'(' if self.pat.get(p + 1) == Some(&b')') => {
+                    return self.start_capture(s, p + 2, CAP_POSITION)
+                },
+                b'(' => return self.start_capture(s, p + 1, CAP_UNFINISHED),
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pat.len() => {
+                    return Ok(if s == self.src.len() { Some(s) } else { None })
+                },
+                b'%' if self.pat.get(p + 1) == Some(&b'b') => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                        continue;
+                    },
+                    None => return Ok(None),
+                },
+                b'%' if self.pat.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pat.get(p) != Some(&b'[') {
+                        return Err(Stop::Malformed);
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(previous, p, ep - 1)
+                        && self.match_bracket_class(current, p, ep - 1)
+                    {
+                        p = ep;
+                        continue;
+                    }
+                    return Ok(None);
+                },
+                b'%' if self.pat.get(p + 1).is_some_and(u8::is_ascii_digit) => {
+                    match self.match_capture(s, self.pat[p + 1])? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        },
+                        None => return Ok(None),
+                    }
+                },
+                _ => {
+                    let ep = self.class_end(p)?;
+                    let repeat = self.pat.get(ep).copied();
+
+                    if !self.single_match(s, p, ep) {
+                        match repeat {
+                            Some(b'*') | Some(b'?') | Some(b'-') => {
+                                p = ep + 1;
+                                continue;
+                            },
+                            _ => return Ok(None),
+                        }
+                    }
+
+                    match repeat {
+                        Some(b'?') => match self.do_match(s + 1, ep + 1)? {
+                            Some(end) => return Ok(Some(end)),
+                            None => {
+                                p = ep + 1;
+                                continue;
+                            },
+                        },
+                        Some(b'+') => return self.max_expand(s + 1, p, ep),
+                        Some(b'*') => return self.max_expand(s, p, ep),
+                        Some(b'-') => return self.min_expand(s, p, ep),
+                        _ => {
+                            s += 1;
+                            p = ep;
+                        },
+                    }
+                },
+            }
+        }
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, Stop> {
+        let mut count = 0;
+        while self.single_match(s + count, p, ep) {
+            self.step()?;
+            count += 1;
+        }
+
+        loop {
+            if let Some(end) = self.do_match(s + count, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, Stop> {
+        loop {
+            if let Some(end) = self.do_match(s, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if !self.single_match(s, p, ep) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> Result<Option<usize>, Stop> {
+        if self.level >= MAX_CAPTURES {
+            return Err(Stop::Malformed);
+        }
+        self.captures[self.level] = (s, what);
+        self.level += 1;
+
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.level -= 1;
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, Stop> {
+        let open = (0..self.level).rev().find(|&l| self.captures[l].1 == CAP_UNFINISHED);
+        let l = open.ok_or(Stop::Malformed)?;
+        self.captures[l].1 = (s - self.captures[l].0) as isize;
+
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[l].1 = CAP_UNFINISHED;
+        }
+        Ok(result)
+    }
+
+    fn match_capture(&mut self, s: usize, digit: u8) -> Result<Option<usize>, Stop> {
+        let l = (digit as usize).checked_sub(b'1' as usize).ok_or(Stop::Malformed)?;
+        if l >= self.level || self.captures[l].1 == CAP_UNFINISHED {
+            return Err(Stop::Malformed);
+        }
+
+        let (start, len) = self.captures[l];
+        let len = match len {
+            CAP_POSITION => 0,
+            len => len as usize,
+        };
+        self.steps += len as u64;
+        self.step()?;
+
+        let matches =
+            self.src.len() - s >= len && self.src[start..start + len] == self.src[s..s + len];
+        Ok(if matches { Some(s + len) } else { None })
+    }
+
+    fn match_balance(&mut self, mut s: usize, p: usize) -> Result<Option<usize>, Stop> {
+        if p + 1 >= self.pat.len() {
+            return Err(Stop::Malformed);
+        }
+        if self.src.get(s) != Some(&self.pat[p]) {
+            return Ok(None);
+        }
+
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        let mut depth = 1;
+        s += 1;
+        while s < self.src.len() {
+            self.step()?;
+            if self.src[s] == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(s + 1));
+                }
+            } else if self.src[s] == open {
+                depth += 1;
+            }
+            s += 1;
+        }
+        Ok(None)
+    }
+
+    // Returns the index following the single-character class starting at `p`.
+    fn class_end(&self, mut p: usize) -> Result<usize, Stop> {
+        let c = self.pat[p];
+        p += 1;
+
+        match c {
+            b'%' if p == self.pat.len() => Err(Stop::Malformed),
+            b'%' => Ok(p + 1),
+            b'[' => {
+                if self.pat.get(p) == Some(&b'^') {
+                    p += 1;
+                }
+                // The first character of the set is never the end of the set.
+                loop {
+                    let c = *self.pat.get(p).ok_or(Stop::Malformed)?;
+                    p += 1;
+                    if c == b'%' && p < self.pat.len() {
+                        p += 1;
+                    }
+                    match self.pat.get(p) {
+                        Some(b']') => return Ok(p + 1),
+                        Some(_) => (),
+                        None => return Err(Stop::Malformed),
+                    }
+                }
+            },
+            _ => Ok(p),
+        }
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let c = match self.src.get(s) {
+            Some(&c) => c,
+            None => return false,
+        };
+
+        match self.pat[p] {
+            b'.' => true,
+            b'%' => match_class(c, self.pat[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    // `p` is the index of the `[` and `ec` the index of the `]` of the set.
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let mut sig = true;
+        if self.pat[p + 1] == b'^' {
+            sig = false;
+            p += 1;
+        }
+
+        p += 1;
+        while p < ec {
+            if self.pat[p] == b'%' {
+                p += 1;
+                if match_class(c, self.pat[p]) {
+                    return sig;
+                }
+            } else if self.pat[p + 1] == b'-' && p + 2 < ec {
+                if self.pat[p] <= c && c <= self.pat[p + 2] {
+                    return sig;
+                }
+                p += 2;
+            } else if self.pat[p] == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
+    }
+}
+
+fn match_class(c: u8, class: u8) -> bool {
+    let result = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => c.is_ascii_whitespace() || c == 0x0b,
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+
+    match class.is_ascii_uppercase() {
+        true => !result,
+        false => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Matcher;
+    use crate::Lua;
+
+    fn lua() -> Lua<'static> {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.guard_string_patterns(1_000_000);
+        lua
+    }
+
+    #[test]
+    fn results_are_unchanged() {
+        let mut lua = lua();
+        let found: String = lua
+            .execute("return table.concat({ string.find('hello world', '(o%s*w)') }, ' ')")
+            .unwrap();
+        assert_eq!(found, "5 7 o w");
+
+        let replaced: String =
+            lua.execute("return (('a,b,,c'):gsub('%f[^,\\0](%a)', '<%1>'))").unwrap();
+        assert_eq!(replaced, "<a>,<b>,,<c>");
+
+        let joined: String = lua
+            .execute(
+                "local t = {}
+                 for k, v in string.gmatch('a=1, b=2', '(%w+)=(%w+)') do t[#t + 1] = k .. v end
+                 return table.concat(t, ' ')",
+            )
+            .unwrap();
+        assert_eq!(joined, "a1 b2");
+
+        let plain: i32 = lua.execute("return string.find('a.b', '.', 1, true)").unwrap();
+        assert_eq!(plain, 2);
+    }
+
+    #[test]
+    fn catastrophic_patterns_are_rejected() {
+        let mut lua = lua();
+        let code = "return string.find(string.rep('a', 60), string.rep('(.-)', 12) .. 'b')";
+        let err = lua.execute::<()>(code).unwrap_err();
+        assert!(err.to_string().contains("pattern too complex"), "{}", err);
+
+        let code = "return ('x'):rep(5000):gsub('x*y', '')";
+        assert!(lua.execute::<()>(code).is_err());
+
+        // Malformed patterns still produce the usual error.
+        let err = lua.execute::<()>("return ('a'):match('[a')").unwrap_err();
+        assert!(err.to_string().contains("malformed pattern"), "{}", err);
+    }
+
+    #[test]
+    fn step_counts() {
+        let count = |src: &str, pat: &str| {
+            let mut matcher = Matcher::new(src.as_bytes(), pat.as_bytes(), u64::MAX);
+            matcher.find(Some(0)).unwrap();
+            matcher.steps
+        };
+
+        assert!(count("aaaa", "^a+$") < 20);
+        assert!(count(&"a".repeat(20), "(.-)(.-)(.-)b") > 1000);
+        assert!(count("(foo(bar))baz", "%b()") < 40);
+    }
+}
@@ -0,0 +1,167 @@
+use std::{error::Error, fmt, sync::Arc};
+
+use crate::{Lua, LuaActor, LuaError, LuaFunction, LuaRead, Push, PushGuard, Void};
+
+/// Set of Lua contexts, each running on its own thread, initialized the same way.
+///
+/// Work can be distributed across the contexts with `lua_par_map`, or sent to a specific one
+/// with `actor`.
+#[derive(Debug)]
+pub struct LuaPool {
+    actors: Vec<LuaActor>,
+}
+
+/// Error returned by `lua_par_map` when some of the items couldn't be processed.
+#[derive(Debug)]
+pub struct ParMapError {
+    /// Index of each item that failed, with the corresponding error.
+    pub errors: Vec<(usize, LuaError)>,
+}
+
+impl fmt::Display for ParMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} item(s) failed", self.errors.len())?;
+        if let Some((index, err)) = self.errors.first() {
+            write!(f, ", the first one is item {}: {}", index, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParMapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.errors.first().map(|(_, err)| err as &(dyn Error + 'static))
+    }
+}
+
+impl LuaPool {
+    /// Creates `size` contexts and calls `init` on each of them, on their own thread.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `size` is 0.
+    pub fn new<F>(size: usize, init: F) -> LuaPool
+    where
+        F: Fn(&mut Lua<'static>) + Send + Sync + 'static,
+    {
+        assert!(size > 0, "a LuaPool needs at least one context");
+
+        let init = Arc::new(init);
+        let actors = (0..size)
+            .map(|_| {
+                let init = init.clone();
+                LuaActor::spawn(move |lua| init(lua))
+            })
+            .collect();
+        LuaPool { actors }
+    }
+
+    /// Returns the number of contexts of the pool.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.actors.len()
+    }
+
+    /// Always returns false, since a pool contains at least one context.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.actors.is_empty()
+    }
+
+    /// Returns the actor owning the context at `index`.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `index` is out of bounds.
+    #[inline]
+    pub fn actor(&self, index: usize) -> &LuaActor {
+        &self.actors[index]
+    }
+}
+
+/// Calls the global function `function` on each item, distributing the items across the
+/// contexts of `pool`, and returns the results in the order of the items.
+///
+/// All the items are processed even if some of them fail. In that case, the error contains the
+/// index and the error of each failed item.
+///
+/// # Example
+///
+/// ```
+/// let pool = hlua::LuaPool::new(4, |lua| {
+///     lua.execute::<()>("function square(x) return x * x end").unwrap();
+/// });
+///
+/// let squares: Vec<i32> = hlua::lua_par_map(&pool, 1..=5, "square").unwrap();
+/// assert_eq!(squares, [1, 4, 9, 16, 25]);
+/// ```
+pub fn lua_par_map<I, R, A, E>(
+    pool: &LuaPool,
+    items: I,
+    function: &str,
+) -> Result<Vec<R>, ParMapError>
+where
+    I: IntoIterator<Item = A>,
+    A: for<'a, 'r> Push<&'r mut LuaFunction<PushGuard<&'a mut Lua<'static>>>, Err = E>,
+    A: Send + 'static,
+    E: Into<Void>,
+    R: for<'a, 'f> LuaRead<PushGuard<&'f mut PushGuard<&'a mut Lua<'static>>>>,
+    R: Send + 'static,
+{
+    // Items are sent round-robin, and the replies are collected once everything is sent.
+    let size = pool.actors.len();
+    let replies: Vec<_> = items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| pool.actors[index % size].call::<R, _, _>(function, item))
+        .collect();
+
+    let mut results = Vec::with_capacity(replies.len());
+    let mut errors = Vec::new();
+    for (index, reply) in replies.into_iter().enumerate() {
+        match reply.wait() {
+            Ok(Ok(result)) => results.push(result),
+            Ok(Err(err)) => errors.push((index, err)),
+            Err(err) => errors.push((index, LuaError::ExecutionError(err.to_string().into()))),
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(results),
+        false => Err(ParMapError { errors }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lua_par_map, LuaPool};
+
+    #[test]
+    fn results_and_errors() {
+        let pool = LuaPool::new(3, |lua| {
+            lua.openlibs();
+            lua.execute::<()>(
+                "function describe(n)
+                     if n % 4 == 0 then error('multiple of four', 0) end
+                     return 'item ' .. n
+                 end",
+            )
+            .unwrap();
+        });
+        assert_eq!(pool.len(), 3);
+
+        let items: Vec<i32> = (1..=3).collect();
+        let results: Vec<String> = lua_par_map(&pool, items, "describe").unwrap();
+        assert_eq!(results, ["item 1", "item 2", "item 3"]);
+
+        let err = lua_par_map::<_, String, _, _>(&pool, 1..=9, "describe").unwrap_err();
+        let indices: Vec<usize> = err.errors.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indices, [3, 7]);
+        let first = err.errors[0].1.script_error().unwrap();
+        assert_eq!(first.message(), "multiple of four");
+        assert_eq!(
+            err.to_string(),
+            "2 item(s) failed, the first one is item 3: Execution error: multiple of four"
+        );
+    }
+}
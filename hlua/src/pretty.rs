@@ -0,0 +1,371 @@
+use std::{cmp::Ordering, slice};
+
+use crate::{print, AsMutLua, Lua, LuaContext, PushOne, Void};
+
+// Names that can't be used as table keys without brackets.
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// Renders Lua values as text for consoles, like the interactive interpreter combined with a
+/// table inspector.
+///
+/// Strings are quoted and escaped, tables are expanded into Lua constructors, with the fields
+/// sorted, up to a depth, and the values whose metatable has a `__tostring` metamethod, such as
+/// user data, are converted with it. Tables wider than the line width are split on several
+/// indented lines. A table containing itself is shown as `<cycle>` where it repeats.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{LuaRef, PrettyFormat};
+///
+/// let mut lua = hlua::Lua::new();
+/// let value: LuaRef = lua.execute("return { 1, 2, name = 'ada', pos = { x = 1 } }").unwrap();
+///
+/// let text = PrettyFormat::new().format(&mut lua, &value);
+/// assert_eq!(text, r#"{ 1, 2, name = "ada", pos = { x = 1 } }"#);
+///
+/// let text = PrettyFormat::new().depth(1).format(&mut lua, &value);
+/// assert_eq!(text, r#"{ 1, 2, name = "ada", pos = {...} }"#);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrettyFormat {
+    depth: usize,
+    max_entries: usize,
+    width: usize,
+}
+
+impl PrettyFormat {
+    /// Creates the default format, which expands 3 levels of tables, shows up to 50 entries per
+    /// table, and splits the tables wider than 80 characters.
+    #[inline]
+    pub fn new() -> PrettyFormat {
+        PrettyFormat { depth: 3, max_entries: 50, width: 80 }
+    }
+
+    /// Sets the number of levels of nested tables that are expanded. The deeper tables are
+    /// shown as `{...}`.
+    #[inline]
+    pub fn depth(mut self, depth: usize) -> PrettyFormat {
+        self.depth = depth;
+        self
+    }
+
+    /// Sets the number of entries shown for each table, after which `...` is shown.
+    #[inline]
+    pub fn max_entries(mut self, max_entries: usize) -> PrettyFormat {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Sets the width over which a table is split on several lines.
+    #[inline]
+    pub fn width(mut self, width: usize) -> PrettyFormat {
+        self.width = width;
+        self
+    }
+
+    /// Renders `value`.
+    pub fn format<'lua, V, E>(&self, lua: &mut Lua<'lua>, value: V) -> String
+    where
+        V: for<'a> PushOne<&'a mut Lua<'lua>, Err = E>,
+        E: Into<Void>,
+    {
+        let raw_lua = lua.as_mut_lua();
+        let pushed = value.push_no_err(lua);
+        let text = unsafe { self.format_at(raw_lua, -1) };
+        drop(pushed);
+        text
+    }
+
+    /// Renders the value at `index`.
+    pub(crate) unsafe fn format_at(&self, lua: LuaContext, index: libc::c_int) -> String {
+        let l = lua.as_ptr();
+        let index = match index < 0 {
+            true => ffi::lua_gettop(l) + index + 1,
+            false => index,
+        };
+        self.render(lua, index, 0, 0, &mut Vec::new())
+    }
+
+    // Renders the value at the absolute `index`, which is `depth` tables deep and starts on a
+    // line indented `indent` times. `seen` holds the tables being rendered.
+    unsafe fn render(
+        &self,
+        lua: LuaContext,
+        index: libc::c_int,
+        depth: usize,
+        indent: usize,
+        seen: &mut Vec<*const libc::c_void>,
+    ) -> String {
+        let l = lua.as_ptr();
+        match ffi::lua_type(l, index) {
+            ffi::LUA_TSTRING => quote(string_at(l, index)),
+            ffi::LUA_TTABLE | ffi::LUA_TUSERDATA => match to_string(l, index) {
+                Some(text) => text,
+                None if ffi::lua_istable(l, index) => {
+                    self.render_table(lua, index, depth, indent, seen)
+                },
+                None => print::to_display_string(lua, index),
+            },
+            _ => print::to_display_string(lua, index),
+        }
+    }
+
+    unsafe fn render_table(
+        &self,
+        lua: LuaContext,
+        index: libc::c_int,
+        depth: usize,
+        indent: usize,
+        seen: &mut Vec<*const libc::c_void>,
+    ) -> String {
+        let l = lua.as_ptr();
+        let pointer = ffi::lua_topointer(l, index);
+        if seen.contains(&pointer) {
+            return "<cycle>".to_owned();
+        }
+        if depth >= self.depth {
+            ffi::lua_pushnil(l);
+            if ffi::lua_next(l, index) == 0 {
+                return "{}".to_owned();
+            }
+            ffi::lua_pop(l, 2);
+            return "{...}".to_owned();
+        }
+        seen.push(pointer);
+
+        // The sequence from 1, then the other fields, sorted.
+        let mut entries = Vec::new();
+        let mut length = 0;
+        let mut truncated = loop {
+            ffi::lua_rawgeti(l, index, (length + 1) as _);
+            if ffi::lua_isnil(l, -1) || entries.len() == self.max_entries {
+                let truncated = !ffi::lua_isnil(l, -1);
+                ffi::lua_pop(l, 1);
+                break truncated;
+            }
+            entries.push(self.render(lua, ffi::lua_gettop(l), depth + 1, indent + 1, seen));
+            ffi::lua_pop(l, 1);
+            length += 1;
+        };
+
+        let mut fields = Vec::new();
+        ffi::lua_pushnil(l);
+        while !truncated && ffi::lua_next(l, index) != 0 {
+            let top = ffi::lua_gettop(l);
+            if !is_sequence_key(l, top - 1, length) {
+                if entries.len() + fields.len() == self.max_entries {
+                    truncated = true;
+                    ffi::lua_pop(l, 2);
+                    break;
+                }
+                let value = self.render(lua, top, depth + 1, indent + 1, seen);
+                fields.push(self.field(lua, top - 1, value, seen));
+            }
+            ffi::lua_pop(l, 1);
+        }
+        seen.pop();
+
+        fields.sort_by(|a: &(Key, String), b| a.0.cmp(&b.0));
+        entries.extend(fields.into_iter().map(|(_, text)| text));
+        if truncated {
+            entries.push("...".to_owned());
+        }
+        if entries.is_empty() {
+            return "{}".to_owned();
+        }
+
+        let inline = format!("{{ {} }}", entries.join(", "));
+        if indent * 2 + inline.len() <= self.width && !inline.contains('\n') {
+            return inline;
+        }
+        let padding = "  ".repeat(indent + 1);
+        let lines: Vec<_> = entries.iter().map(|entry| format!("{}{}", padding, entry)).collect();
+        format!("{{\n{}\n{}}}", lines.join(",\n"), "  ".repeat(indent))
+    }
+
+    // Renders the field whose key is at `index`, and returns it with its sorting key.
+    unsafe fn field(
+        &self,
+        lua: LuaContext,
+        index: libc::c_int,
+        value: String,
+        seen: &mut Vec<*const libc::c_void>,
+    ) -> (Key, String) {
+        let l = lua.as_ptr();
+        match ffi::lua_type(l, index) {
+            ffi::LUA_TSTRING => {
+                let name = String::from_utf8_lossy(string_at(l, index)).into_owned();
+                let text = match is_identifier(&name) {
+                    true => format!("{} = {}", name, value),
+                    false => format!("[{}] = {}", quote(string_at(l, index)), value),
+                };
+                (Key::String(name), text)
+            },
+            ty => {
+                // Rendering a number converts it in place, which would break `lua_next`.
+                ffi::lua_pushvalue(l, index);
+                let key = self.render(lua, ffi::lua_gettop(l), self.depth, 0, seen);
+                let number = ffi::lua_tonumberx(l, -1, std::ptr::null_mut());
+                ffi::lua_pop(l, 1);
+                let sort = match ty {
+                    ffi::LUA_TNUMBER => Key::Number(number),
+                    _ => Key::Other(key.clone()),
+                };
+                (sort, format!("[{}] = {}", key, value))
+            },
+        }
+    }
+}
+
+impl Default for PrettyFormat {
+    #[inline]
+    fn default() -> PrettyFormat {
+        PrettyFormat::new()
+    }
+}
+
+// Order of the fields of a table: numbers, then strings, then the other keys.
+#[derive(Debug, PartialEq)]
+enum Key {
+    Number(f64),
+    String(String),
+    Other(String),
+}
+
+impl Eq for Key {}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        match (self, other) {
+            (Key::Number(a), Key::Number(b)) => a.total_cmp(b),
+            (Key::String(a), Key::String(b)) | (Key::Other(a), Key::Other(b)) => a.cmp(b),
+            (Key::Number(_), _) | (Key::String(_), Key::Other(_)) => Ordering::Less,
+            _ => Ordering::Greater,
+        }
+    }
+}
+
+// Calls the `__tostring` metamethod of the value at `index`, if it has one. The errors it raises
+// are shown in place of the value.
+unsafe fn to_string(l: *mut ffi::lua_State, index: libc::c_int) -> Option<String> {
+    if ffi::luaL_getmetafield(l, index, c"__tostring".as_ptr()) == 0 {
+        return None;
+    }
+    ffi::lua_pushvalue(l, index);
+    let text = match ffi::lua_pcall(l, 1, 1, 0) {
+        0 if ffi::lua_type(l, -1) == ffi::LUA_TSTRING => {
+            String::from_utf8_lossy(string_at(l, -1)).into_owned()
+        },
+        0 => "<__tostring must return a string>".to_owned(),
+        _ => format!("<error in __tostring: {}>", String::from_utf8_lossy(string_at(l, -1))),
+    };
+    ffi::lua_pop(l, 1);
+    Some(text)
+}
+
+// Returns true if the key at `index` is an integer of the sequence `1..=length`.
+unsafe fn is_sequence_key(l: *mut ffi::lua_State, index: libc::c_int, length: usize) -> bool {
+    if ffi::lua_type(l, index) != ffi::LUA_TNUMBER {
+        return false;
+    }
+    let key = ffi::lua_tonumberx(l, index, std::ptr::null_mut());
+    key >= 1.0 && key <= length as f64 && key.fract() == 0.0
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        && !KEYWORDS.contains(&name)
+}
+
+// Returns the bytes of the string at `index`, or nothing if it isn't a string.
+unsafe fn string_at<'a>(l: *mut ffi::lua_State, index: libc::c_int) -> &'a [u8] {
+    if ffi::lua_type(l, index) != ffi::LUA_TSTRING {
+        return &[];
+    }
+    let mut len = 0;
+    let data = ffi::lua_tolstring(l, index, &mut len);
+    slice::from_raw_parts(data.cast::<u8>(), len)
+}
+
+// Quotes a string, escaping the quotes, the backslashes and the control characters.
+fn quote(bytes: &[u8]) -> String {
+    let mut quoted = String::with_capacity(bytes.len() + 2);
+    quoted.push('"');
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\{:03}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, LuaRef, PrettyFormat};
+
+    fn pretty(lua: &mut Lua, format: &PrettyFormat, code: &str) -> String {
+        let value: LuaRef = lua.execute(code).unwrap();
+        format.format(lua, &value)
+    }
+
+    #[test]
+    fn values_are_rendered() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let format = PrettyFormat::new();
+
+        assert_eq!(pretty(&mut lua, &format, "return 'a\"b\\n'"), "\"a\\\"b\\n\"");
+        assert_eq!(pretty(&mut lua, &format, "return 2.5"), "2.5");
+        assert_eq!(pretty(&mut lua, &format, "return nil"), "nil");
+        assert_eq!(pretty(&mut lua, &format, "return {}"), "{}");
+        assert!(pretty(&mut lua, &format, "return print").starts_with("function: "));
+
+        let code = "return { 'x', [3.5] = 1, ['end'] = 2, b = true, a = {}, [false] = 0 }";
+        let text = pretty(&mut lua, &format, code);
+        assert_eq!(text, r#"{ "x", [3.5] = 1, a = {}, b = true, ["end"] = 2, [false] = 0 }"#);
+
+        let code = "local t = { name = 'loop' } t.self = t return t";
+        assert_eq!(pretty(&mut lua, &format, code), r#"{ name = "loop", self = <cycle> }"#);
+
+        let code = "
+            local point = setmetatable({}, { __tostring = function() return '(1, 2)' end })
+            local broken = setmetatable({}, { __tostring = function() error('no', 0) end })
+            return { point, broken }
+        ";
+        let text = pretty(&mut lua, &format, code);
+        assert_eq!(text, "{ (1, 2), <error in __tostring: no> }");
+    }
+
+    #[test]
+    fn limits_and_lines() {
+        let mut lua = Lua::new();
+        let format = PrettyFormat::new().max_entries(3).width(10);
+
+        let text = pretty(&mut lua, &format, "return { 1, 2, 3, 4, 5 }");
+        assert_eq!(text, "{\n  1,\n  2,\n  3,\n  ...\n}");
+
+        let code = "return { inner = { deeper = { 'value' } }, n = 1 }";
+        let text = pretty(&mut lua, &PrettyFormat::new().width(30), code);
+        assert_eq!(text, "{\n  inner = { deeper = { \"value\" } },\n  n = 1\n}");
+        let text = pretty(&mut lua, &PrettyFormat::new().depth(2), code);
+        assert_eq!(text, "{ inner = { deeper = {...} }, n = 1 }");
+    }
+}
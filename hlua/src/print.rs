@@ -0,0 +1,160 @@
+use std::ffi::CStr;
+
+use crate::flight_recorder::{self, FlightEvent};
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, Lua, LuaContext};
+
+// Stored as an upvalue of the `print` function installed by `redirect_print`.
+struct PrintHandler(Box<dyn FnMut(&str) + Send>);
+
+impl<'lua> Lua<'lua> {
+    /// Replaces the global `print` function with one that passes its output to `handler`.
+    ///
+    /// Just like the standard `print`, the arguments are converted to strings and separated with
+    /// tabs. The handler receives one call per call to `print`, without the trailing newline.
+    /// This works whether or not the standard libraries have been opened.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// let output = Arc::new(Mutex::new(Vec::new()));
+    /// let sink = output.clone();
+    /// lua.redirect_print(move |line| sink.lock().unwrap().push(line.to_owned()));
+    ///
+    /// lua.execute::<()>("print('hello', 42, nil)").unwrap();
+    /// assert_eq!(*output.lock().unwrap(), vec!["hello\t42\tnil"]);
+    /// ```
+    pub fn redirect_print<F>(&mut self, handler: F)
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        unsafe {
+            let raw_lua = self.lua;
+            ffix::lua_pushglobaltable(raw_lua);
+            push_userdata(PrintHandler(Box::new(handler)), raw_lua, |_| {}).forget();
+            ffi::lua_pushcclosure(raw_lua.as_ptr(), Some(print_wrapper), 1);
+            ffi::lua_setfield(raw_lua.as_ptr(), -2, c"print".as_ptr());
+            ffi::lua_pop(raw_lua.as_ptr(), 1);
+        }
+    }
+}
+
+extern "C" fn print_wrapper(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        let line = format_arguments(raw_lua, 1);
+        flight_recorder::record(raw_lua, || FlightEvent::Print(line.clone()));
+
+        if let Some(PrintHandler(handler)) = userdata_mut(raw_lua, ffi::lua_upvalueindex(1)) {
+            handler(&line);
+        }
+
+        0
+    }
+}
+
+/// Formats the arguments of the running function, starting at `first`, the same way `print`
+/// does.
+pub(crate) unsafe fn format_arguments(lua: LuaContext, first: libc::c_int) -> String {
+    (first..=ffi::lua_gettop(lua.as_ptr()))
+        .map(|index| to_display_string(lua, index))
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+/// Returns true if the value at `index` is a function installed by `redirect_print`.
+#[inline]
+pub(crate) unsafe fn is_redirected(lua: LuaContext, index: libc::c_int) -> bool {
+    is_cfunction(lua, index, print_wrapper)
+}
+
+/// Returns true if the value at `index` is a C function or C closure calling `function`.
+pub(crate) unsafe fn is_cfunction(
+    lua: LuaContext,
+    index: libc::c_int,
+    function: extern "C" fn(*mut ffi::lua_State) -> libc::c_int,
+) -> bool {
+    match ffi::lua_tocfunction(lua.as_ptr(), index) {
+        Some(f) => f as usize == function as usize,
+        None => false,
+    }
+}
+
+/// Converts the value at `index` to a string the same way the standard `tostring` does,
+/// including calling the `__tostring` metamethod.
+pub(crate) unsafe fn to_display_string(lua: LuaContext, index: libc::c_int) -> String {
+    let raw_lua = lua.as_ptr();
+
+    if ffi::luaL_callmeta(raw_lua, index, c"__tostring".as_ptr()) != 0 {
+        let string = string_at(lua, -1);
+        ffi::lua_pop(raw_lua, 1);
+        return string;
+    }
+
+    match ffi::lua_type(raw_lua, index) {
+        ffi::LUA_TNIL => "nil".to_owned(),
+        ffi::LUA_TBOOLEAN => (ffi::lua_toboolean(raw_lua, index) != 0).to_string(),
+        ffi::LUA_TNUMBER | ffi::LUA_TSTRING => {
+            // Converting a number modifies the value in place, so convert a copy.
+            ffi::lua_pushvalue(raw_lua, index);
+            let string = string_at(lua, -1);
+            ffi::lua_pop(raw_lua, 1);
+            string
+        },
+        ty => {
+            let name = CStr::from_ptr(ffi::lua_typename(raw_lua, ty)).to_string_lossy();
+            format!("{}: {:p}", name, ffi::lua_topointer(raw_lua, index))
+        },
+    }
+}
+
+// Returns the string at `index`, or an empty string if the value isn't a string or a number.
+unsafe fn string_at(lua: LuaContext, index: libc::c_int) -> String {
+    let mut len = 0;
+    let ptr = ffi::lua_tolstring(lua.as_ptr(), index, &mut len);
+    if ptr.is_null() {
+        return String::new();
+    }
+
+    let bytes = std::slice::from_raw_parts(ptr.cast::<u8>(), len);
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::Lua;
+
+    #[test]
+    fn arguments_are_joined() {
+        let mut lua = Lua::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let sink = output.clone();
+        lua.redirect_print(move |line| sink.lock().unwrap().push(line.to_owned()));
+
+        lua.execute::<()>("print('a', true, nil, 'b') print() print(1.5)").unwrap();
+        assert_eq!(*output.lock().unwrap(), vec!["a\ttrue\tnil\tb", "", "1.5"]);
+    }
+
+    #[test]
+    fn tostring_metamethod() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let sink = output.clone();
+        lua.redirect_print(move |line| sink.lock().unwrap().push(line.to_owned()));
+
+        lua.execute::<()>(
+            "local t = setmetatable({}, { __tostring = function() return 'custom' end })
+             print(t, {})",
+        )
+        .unwrap();
+
+        let output = output.lock().unwrap();
+        assert!(output[0].starts_with("custom\ttable: "), "{}", output[0]);
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    ffi::CStr,
+    fmt,
+    io::{self, Read, Write},
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    str,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::patterns::string_arg;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsMutLua, Lua, LuaContext, Push};
+
+/// Commands that scripts can run through the `proc` library, and the limits applied to them.
+///
+/// A new policy doesn't allow any command. Commands are started directly, without a shell, with
+/// an empty environment apart from the variables given to `env`.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use hlua::{ProcCommand, ProcPolicy};
+///
+/// let policy = ProcPolicy::new()
+///     .command(ProcCommand::new("convert", "/usr/bin/convert").max_args(4))
+///     .command(ProcCommand::new("git", "/usr/bin/git").validate(|args| {
+///         match args.first().map(String::as_str) {
+///             Some("status") | Some("log") => Ok(()),
+///             _ => Err("only `git status` and `git log` are allowed".to_owned()),
+///         }
+///     }))
+///     .timeout(Duration::from_secs(5))
+///     .max_output_size(64 * 1024);
+/// assert!(policy.is_allowed("git"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProcPolicy {
+    commands: Vec<ProcCommand>,
+    env: Vec<(String, String)>,
+    timeout: Duration,
+    max_output_size: usize,
+}
+
+/// Command that scripts are allowed to run, and the validation of its arguments.
+#[derive(Clone)]
+pub struct ProcCommand {
+    name: String,
+    program: PathBuf,
+    max_args: usize,
+    validate: Option<Arc<ArgsValidator>>,
+}
+
+type ArgsValidator = dyn Fn(&[String]) -> Result<(), String> + Send + Sync;
+
+impl fmt::Debug for ProcCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProcCommand")
+            .field("name", &self.name)
+            .field("program", &self.program)
+            .field("max_args", &self.max_args)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ProcPolicy {
+    #[inline]
+    fn default() -> ProcPolicy {
+        ProcPolicy {
+            commands: Vec::new(),
+            env: Vec::new(),
+            timeout: Duration::from_secs(10),
+            max_output_size: 1024 * 1024,
+        }
+    }
+}
+
+impl ProcPolicy {
+    /// Builds a policy that doesn't allow any command, with a timeout of 10 seconds, and
+    /// outputs limited to 1 MiB.
+    #[inline]
+    pub fn new() -> ProcPolicy {
+        ProcPolicy::default()
+    }
+
+    /// Allows scripts to run `command`. A previous command with the same name is replaced.
+    #[inline]
+    pub fn command(mut self, command: ProcCommand) -> ProcPolicy {
+        self.commands.retain(|c| c.name != command.name);
+        self.commands.push(command);
+        self
+    }
+
+    /// Sets an environment variable of the commands.
+    #[inline]
+    pub fn env(mut self, name: &str, value: &str) -> ProcPolicy {
+        self.env.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Sets the maximum duration of a command, after which it is killed.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> ProcPolicy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum size, in bytes, of the standard output and of the standard error of a
+    /// command. A command that writes more is killed.
+    #[inline]
+    pub fn max_output_size(mut self, size: usize) -> ProcPolicy {
+        self.max_output_size = size;
+        self
+    }
+
+    /// Returns true if scripts can run the command named `name`.
+    #[inline]
+    pub fn is_allowed(&self, name: &str) -> bool {
+        self.commands.iter().any(|command| command.name == name)
+    }
+}
+
+impl ProcCommand {
+    /// Builds a command that scripts call `name`, and which runs `program` with at most 32
+    /// arguments.
+    #[inline]
+    pub fn new(name: &str, program: impl Into<PathBuf>) -> ProcCommand {
+        ProcCommand { name: name.to_owned(), program: program.into(), max_args: 32, validate: None }
+    }
+
+    /// Sets the maximum number of arguments.
+    #[inline]
+    pub fn max_args(mut self, max_args: usize) -> ProcCommand {
+        self.max_args = max_args;
+        self
+    }
+
+    /// Sets a function that checks the arguments before running the command, and returns a
+    /// message describing why they are refused.
+    #[inline]
+    pub fn validate<F>(mut self, validate: F) -> ProcCommand
+    where
+        F: Fn(&[String]) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.validate = Some(Arc::new(validate));
+        self
+    }
+}
+
+// Stored as an upvalue of the functions of the library.
+#[derive(Debug)]
+struct ProcContext {
+    policy: ProcPolicy,
+}
+
+// Result of a command that ran until its end.
+struct Output {
+    status: Option<i32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+type Method = (&'static CStr, ffi::lua_CFunction);
+
+impl<'lua> Lua<'lua> {
+    /// Opens a `proc` library that lets scripts run the commands allowed by `policy`.
+    ///
+    /// - `proc.run(name [, args [, options]])` runs a command with the array of strings `args`,
+    ///   and waits for its end. `options` can contain the string `stdin`, which is written to
+    ///   the standard input of the command.
+    /// - `proc.allowed(name)` returns true if the command is allowed.
+    ///
+    /// `proc.run` returns a table containing the exit `status`, which is nil if the command was
+    /// killed by a signal, `success`, and the captured `stdout` and `stderr`. If the command isn't
+    /// allowed, or can't run within the limits of the policy, it returns nil and a message
+    /// instead.
+    ///
+    /// While a command is running, the context is blocked.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.open_proc(hlua::ProcPolicy::new());
+    ///
+    /// let code = "local output, err = proc.run('rm', { '-rf', '/' }) return err";
+    /// let err: String = lua.execute(code).unwrap();
+    /// assert_eq!(err, "command rm is not allowed");
+    /// ```
+    pub fn open_proc(&mut self, policy: ProcPolicy) {
+        let functions: [Method; 2] = [(c"run", Some(run)), (c"allowed", Some(allowed))];
+
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_createtable(l, 0, functions.len() as _);
+            push_userdata(ProcContext { policy }, raw_lua, |_| {}).forget();
+            for (name, function) in functions {
+                ffi::lua_pushvalue(l, -1);
+                ffi::lua_pushcclosure(l, function, 1);
+                ffi::lua_setfield(l, -3, name.as_ptr());
+            }
+            ffi::lua_pop(l, 1);
+
+            ffi::lua_getfield(l, -2, c"package".as_ptr());
+            if ffi::lua_istable(l, -1) {
+                ffi::lua_getfield(l, -1, c"loaded".as_ptr());
+                if ffi::lua_istable(l, -1) {
+                    ffi::lua_pushvalue(l, -3);
+                    ffi::lua_setfield(l, -2, c"proc".as_ptr());
+                }
+                ffi::lua_pop(l, 1);
+            }
+            ffi::lua_pop(l, 1);
+
+            ffi::lua_setfield(l, -2, c"proc".as_ptr());
+            ffi::lua_pop(l, 1);
+        }
+    }
+}
+
+impl ProcPolicy {
+    // Runs the command `name` if the policy allows it.
+    fn run(&self, name: &str, args: Vec<String>, stdin: Option<Vec<u8>>) -> Result<Output, String> {
+        let command = self
+            .commands
+            .iter()
+            .find(|command| command.name == name)
+            .ok_or_else(|| format!("command {} is not allowed", name))?;
+        if args.len() > command.max_args {
+            return Err(format!("too many arguments for command {}", name));
+        }
+        if args.iter().any(|arg| arg.contains('\0')) {
+            return Err(format!("invalid argument for command {}", name));
+        }
+        if let Some(validate) = &command.validate {
+            validate(&args)?;
+        }
+
+        let mut child = Command::new(&command.program)
+            .args(&args)
+            .env_clear()
+            .envs(self.env.iter().map(|(name, value)| (name, value)))
+            .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("can't run command {}: {}", name, err))?;
+
+        if let (Some(mut pipe), Some(data)) = (child.stdin.take(), stdin) {
+            // Ignoring errors, since commands may exit without reading their input.
+            thread::spawn(move || pipe.write_all(&data));
+        }
+        let limit = self.max_output_size;
+        let overflow = Arc::new(AtomicBool::new(false));
+        let stdout = capture(child.stdout.take().expect("stdout is piped"), limit, &overflow);
+        let stderr = capture(child.stderr.take().expect("stderr is piped"), limit, &overflow);
+
+        let deadline = Instant::now() + self.timeout;
+        let status = wait(&mut child, deadline, &overflow)
+            .map_err(|err| format!("can't run command {}: {}", name, err))?;
+        // Processes started by the command can keep the pipes open after its end.
+        while !(stdout.is_finished() && stderr.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        if overflow.load(Ordering::Relaxed) {
+            return Err(format!("output of command {} is too large", name));
+        }
+        let status = match status {
+            Some(status) if stdout.is_finished() && stderr.is_finished() => status,
+            _ => return Err(format!("command {} timed out", name)),
+        };
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+
+        Ok(Output { status: status.code(), stdout, stderr })
+    }
+}
+
+// Reads the output of a command, and raises `overflow` if it is larger than `limit`.
+fn capture<R>(pipe: R, limit: usize, overflow: &Arc<AtomicBool>) -> thread::JoinHandle<Vec<u8>>
+where
+    R: Read + Send + 'static,
+{
+    let overflow = overflow.clone();
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        let _ = pipe.take(limit as u64 + 1).read_to_end(&mut data);
+        if data.len() > limit {
+            overflow.store(true, Ordering::Relaxed);
+        }
+        data
+    })
+}
+
+// Waits for the end of the command. It is killed if it doesn't finish before the deadline or
+// if its output overflows, in which case `None` is returned.
+fn wait(
+    child: &mut Child,
+    deadline: Instant,
+    overflow: &AtomicBool,
+) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline || overflow.load(Ordering::Relaxed) {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+// Returns the number of results of a function, or raises the error.
+unsafe fn finish(lua: *mut ffi::lua_State, result: Result<libc::c_int, String>) -> libc::c_int {
+    match result {
+        Ok(count) => count,
+        Err(msg) => {
+            msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+            ffix::lua_error(lua);
+        },
+    }
+}
+
+unsafe fn policy<'a>(lua: *mut ffi::lua_State) -> &'a ProcPolicy {
+    let raw_lua = LuaContext::new_unchecked(lua);
+    let context = userdata_mut::<ProcContext>(raw_lua, ffi::lua_upvalueindex(1))
+        .expect("the upvalue of the proc functions is a ProcContext");
+    &context.policy
+}
+
+unsafe fn name_arg<'a>(lua: *mut ffi::lua_State) -> Result<&'a str, String> {
+    match string_arg(lua, 1).map(str::from_utf8) {
+        Some(Ok(name)) => Ok(name),
+        _ => Err("bad argument #1 (command name expected)".to_owned()),
+    }
+}
+
+// Reads the array of arguments at `index`, if it isn't nil.
+unsafe fn args_arg(lua: *mut ffi::lua_State, index: libc::c_int) -> Result<Vec<String>, String> {
+    if ffi::lua_isnoneornil(lua, index) {
+        return Ok(Vec::new());
+    }
+    if !ffi::lua_istable(lua, index) {
+        return Err("bad argument #2 to 'run' (table expected)".to_owned());
+    }
+
+    let mut args = Vec::new();
+    for position in 1..=ffix::lua_rawlen(LuaContext::new_unchecked(lua), index) {
+        ffi::lua_rawgeti(lua, index, position as _);
+        let arg = match string_arg(lua, -1).map(str::from_utf8) {
+            Some(Ok(arg)) => arg.to_owned(),
+            _ => {
+                ffi::lua_pop(lua, 1);
+                return Err(format!("argument {} must be a string", position));
+            },
+        };
+        ffi::lua_pop(lua, 1);
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+unsafe fn push_output(lua: *mut ffi::lua_State, output: &Output) {
+    ffi::lua_createtable(lua, 0, 4);
+    match output.status {
+        Some(status) => ffi::lua_pushinteger(lua, status as ffi::lua_Integer),
+        None => ffi::lua_pushnil(lua),
+    }
+    ffi::lua_setfield(lua, -2, c"status".as_ptr());
+    ffi::lua_pushboolean(lua, (output.status == Some(0)) as libc::c_int);
+    ffi::lua_setfield(lua, -2, c"success".as_ptr());
+    ffi::lua_pushlstring(lua, output.stdout.as_ptr().cast(), output.stdout.len());
+    ffi::lua_setfield(lua, -2, c"stdout".as_ptr());
+    ffi::lua_pushlstring(lua, output.stderr.as_ptr().cast(), output.stderr.len());
+    ffi::lua_setfield(lua, -2, c"stderr".as_ptr());
+}
+
+extern "C" fn run(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let name = name_arg(lua)?;
+        let args = args_arg(lua, 2)?;
+        let stdin = match ffi::lua_istable(lua, 3) {
+            true => {
+                ffi::lua_getfield(lua, 3, c"stdin".as_ptr());
+                let stdin = match ffi::lua_isnil(lua, -1) {
+                    true => None,
+                    false => Some(string_arg(lua, -1).ok_or("stdin must be a string")?.to_vec()),
+                };
+                ffi::lua_pop(lua, 1);
+                stdin
+            },
+            false if ffi::lua_isnoneornil(lua, 3) => None,
+            false => return Err("bad argument #3 to 'run' (table expected)".to_owned()),
+        };
+
+        match policy(lua).run(name, args, stdin) {
+            Ok(output) => {
+                push_output(lua, &output);
+                Ok(1)
+            },
+            Err(msg) => {
+                ffi::lua_pushnil(lua);
+                msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+                Ok(2)
+            },
+        }
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn allowed(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let name = name_arg(lua)?;
+        ffi::lua_pushboolean(lua, policy(lua).is_allowed(name) as libc::c_int);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{Lua, ProcCommand, ProcPolicy};
+
+    fn shell_policy() -> ProcPolicy {
+        ProcPolicy::new()
+            .command(ProcCommand::new("sh", "/bin/sh").max_args(3))
+            .env("GREETING", "hello")
+            .timeout(Duration::from_millis(500))
+            .max_output_size(16)
+    }
+
+    #[test]
+    fn run_commands() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_proc(shell_policy());
+
+        let code = r#"
+            local script = 'read l; printf "$GREETING $l"; echo e >&2; exit 3'
+            local out = assert(proc.run('sh', { '-c', script }, { stdin = 'world\n' }))
+            return table.concat({ out.status, tostring(out.success), out.stdout, out.stderr }, '|')
+        "#;
+        assert_eq!(lua.execute::<String>(code).unwrap(), "3|false|hello world|e\n");
+        assert!(lua.execute::<bool>("return proc.allowed('sh')").unwrap());
+        assert!(!lua.execute::<bool>("return proc.allowed('bash')").unwrap());
+    }
+
+    #[test]
+    fn limits() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let policy =
+            shell_policy().command(ProcCommand::new("echo", "/bin/echo").validate(|args| {
+                match args.iter().all(|arg| !arg.starts_with('-')) {
+                    true => Ok(()),
+                    false => Err("options are not allowed".to_owned()),
+                }
+            }));
+        lua.open_proc(policy);
+
+        let error = |lua: &mut Lua, args: &str| -> String {
+            lua.execute(&format!("return select(2, proc.run({}))", args)).unwrap()
+        };
+        assert_eq!(error(&mut lua, "'bash'"), "command bash is not allowed");
+        assert_eq!(error(&mut lua, "'sh', { '-c', 'sleep 5' }"), "command sh timed out");
+        assert_eq!(
+            error(&mut lua, "'sh', { '-c', 'echo 0123456789abcdefghij' }"),
+            "output of command sh is too large"
+        );
+        assert_eq!(
+            error(&mut lua, "'sh', { '-c', '', 'a', 'b' }"),
+            "too many arguments for command sh"
+        );
+        assert_eq!(error(&mut lua, "'echo', { '-e', 'x' }"), "options are not allowed");
+        assert_eq!(
+            lua.execute::<String>("return proc.run('echo', { 'a', 1 }).stdout").unwrap(),
+            "a 1\n"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    any,
+    cmp::Reverse,
+    collections::HashMap,
+    ffi::CStr,
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{Lua, LuaContext};
+
+// Key of the registry entry holding the `ProfileHolder` of a context.
+const PROFILE_KEY: &CStr = c"hlua.conversion_profile";
+
+// Number of contexts with conversion profiling enabled. Lets `record` skip the registry lookup in
+// the common case where profiling is disabled.
+static ACTIVE_PROFILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Direction of a conversion counted by the conversion profiler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ConversionDirection {
+    /// A Rust value has been pushed to Lua.
+    Push,
+    /// A Lua value has been read into a Rust value.
+    Read,
+}
+
+/// Number of conversions of a type made from a given place of the code, as returned by
+/// `Lua::conversion_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionStats {
+    /// Place of the code that called hlua.
+    pub location: &'static Location<'static>,
+    /// Name of the Rust type, as returned by `std::any::type_name`.
+    pub type_name: &'static str,
+    /// Whether the values were pushed or read.
+    pub direction: ConversionDirection,
+    /// Number of conversions.
+    pub count: u64,
+}
+
+impl fmt::Display for ConversionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            ConversionDirection::Push => "pushes",
+            ConversionDirection::Read => "reads",
+        };
+        write!(f, "{}: {} {} of {}", self.location, self.count, direction, self.type_name)
+    }
+}
+
+type ProfileKey = (&'static Location<'static>, &'static str, ConversionDirection);
+
+// Stored in the registry by `enable_conversion_profiling`.
+struct ProfileHolder(HashMap<ProfileKey, u64>);
+
+impl Drop for ProfileHolder {
+    #[inline]
+    fn drop(&mut self) {
+        ACTIVE_PROFILES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Starts counting the values converted between Rust and Lua, per type and per place of the
+    /// code that asked for the conversion.
+    ///
+    /// The conversions made by `get`, `set` and `checked_set` on the context and on tables, and
+    /// the arguments and results of `execute` and of calls to Lua functions, are counted and
+    /// attributed to the code calling these methods. The report, obtained with
+    /// `conversion_report`, shows where a cached `LuaRef`, a `LuaString` or a userdata could
+    /// avoid converting the same values over and over.
+    ///
+    /// Enabling profiling again clears the counters.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.enable_conversion_profiling();
+    ///
+    /// for i in 0..100 {
+    ///     lua.set("frame", i);
+    /// }
+    ///
+    /// let report = lua.conversion_report(1);
+    /// assert_eq!(report[0].type_name, "i32");
+    /// assert_eq!(report[0].count, 100);
+    /// ```
+    pub fn enable_conversion_profiling(&mut self) {
+        unsafe {
+            ACTIVE_PROFILES.fetch_add(1, Ordering::Relaxed);
+            push_userdata(ProfileHolder(HashMap::new()), self.lua, |_| {}).forget();
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, PROFILE_KEY.as_ptr());
+        }
+    }
+
+    /// Stops counting conversions and discards the counters.
+    pub fn disable_conversion_profiling(&mut self) {
+        unsafe {
+            ffi::lua_pushnil(self.lua.as_ptr());
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, PROFILE_KEY.as_ptr());
+        }
+    }
+
+    /// Returns the `top` places of the code with the most conversions, the largest first.
+    ///
+    /// Returns an empty list if profiling isn't enabled.
+    pub fn conversion_report(&self, top: usize) -> Vec<ConversionStats> {
+        let mut report = unsafe {
+            with_profile(self.lua, |counts| {
+                counts
+                    .iter()
+                    .map(|(&(location, type_name, direction), &count)| ConversionStats {
+                        location,
+                        type_name,
+                        direction,
+                        count,
+                    })
+                    .collect::<Vec<_>>()
+            })
+        }
+        .unwrap_or_default();
+
+        report.sort_by_key(|stats| {
+            let location = (stats.location.file(), stats.location.line(), stats.location.column());
+            (Reverse(stats.count), location, stats.type_name, stats.direction)
+        });
+        report.truncate(top);
+        report
+    }
+}
+
+// Calls `f` with the counters of the context, if profiling is enabled.
+unsafe fn with_profile<R>(
+    lua: LuaContext,
+    f: impl FnOnce(&mut HashMap<ProfileKey, u64>) -> R,
+) -> Option<R> {
+    let raw_lua = lua.as_ptr();
+    ffi::lua_getfield(raw_lua, ffi::LUA_REGISTRYINDEX, PROFILE_KEY.as_ptr());
+    let result = userdata_mut::<ProfileHolder>(lua, -1).map(|holder| f(&mut holder.0));
+    ffi::lua_pop(raw_lua, 1);
+    result
+}
+
+/// Counts a conversion of a `T` made on behalf of the code at `location`, if profiling is
+/// enabled. Conversions of `()` aren't counted, since nothing is converted.
+#[inline]
+pub(crate) unsafe fn record<T: ?Sized>(
+    lua: LuaContext,
+    direction: ConversionDirection,
+    location: &'static Location<'static>,
+) {
+    if ACTIVE_PROFILES.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    let type_name = any::type_name::<T>();
+    if type_name == "()" {
+        return;
+    }
+    with_profile(lua, |counts| *counts.entry((location, type_name, direction)).or_insert(0) += 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ConversionDirection, Lua, LuaFunction, LuaTable};
+
+    #[test]
+    fn counts_per_call_site() {
+        let mut lua = Lua::new();
+        assert!(lua.conversion_report(10).is_empty());
+        lua.enable_conversion_profiling();
+
+        lua.execute::<()>("values = { 1, 2, 3 } function double(x) return x * 2 end").unwrap();
+        for _ in 0..3 {
+            let mut double: LuaFunction<_> = lua.get("double").unwrap();
+            let _: i32 = double.call_with_args(4).unwrap();
+        }
+        let call_line = line!() - 2;
+        for _ in 0..2 {
+            let mut values: LuaTable<_> = lua.get("values").unwrap();
+            let _: Option<u8> = values.get(2);
+        }
+
+        let report = lua.conversion_report(10);
+        assert_eq!(report.len(), 5);
+        assert!(report.windows(2).all(|pair| pair[0].count >= pair[1].count));
+
+        let pushes = report.iter().find(|stats| stats.direction == ConversionDirection::Push);
+        let pushes = pushes.unwrap();
+        assert_eq!((pushes.type_name, pushes.count), ("i32", 3));
+        assert_eq!(pushes.location.line(), call_line);
+        assert!(pushes.to_string().ends_with(": 3 pushes of i32"), "{}", pushes);
+
+        let reads = report.iter().find(|stats| stats.type_name == "u8").unwrap();
+        assert_eq!((reads.direction, reads.count), (ConversionDirection::Read, 2));
+        assert_eq!(lua.conversion_report(1).len(), 1);
+
+        lua.disable_conversion_profiling();
+        lua.set("a", 5);
+        assert!(lua.conversion_report(10).is_empty());
+    }
+}
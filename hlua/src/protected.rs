@@ -0,0 +1,273 @@
+use std::{ffi::CStr, mem, ptr};
+
+use crate::exit;
+use crate::lua_functions::{read_error, LuaFunction};
+use crate::{AnyLuaValue, AsMutLua, Lua, LuaContext, LuaError, LuaRead, Push, PushGuard};
+
+/// What to do with an error caught by `Lua::execute_protected`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecoveryAction {
+    /// Return the error to the caller of `execute_protected`, with a traceback.
+    Propagate,
+    /// Return an error with a different message, for example one that includes a traceback.
+    ReplaceMessage(String),
+    /// Ignore the error and make `execute_protected` return this value instead.
+    ReturnValue(AnyLuaValue),
+}
+
+/// Access to a Lua error and to the stack frames that raised it.
+///
+/// This is passed to the handler of `Lua::execute_protected`, which is called before the stack
+/// unwinds. It is only valid for the duration of the call.
+#[derive(Debug)]
+pub struct ErrorContext {
+    pub(crate) lua: LuaContext,
+}
+
+impl ErrorContext {
+    /// Returns the error object, usually the error message.
+    #[inline]
+    pub fn value(&self) -> AnyLuaValue {
+        self.value_at(1)
+    }
+
+    /// Returns the error message, if the error object is a string or a number.
+    pub fn message(&self) -> Option<String> {
+        let mut lua = self.lua;
+        LuaRead::lua_read_at_position(&mut lua, 1).ok()
+    }
+
+    /// Returns a traceback of the stack frames that raised the error.
+    pub fn traceback(&self) -> String {
+        unsafe {
+            let raw_lua = self.lua.as_ptr();
+            ffi::luaL_traceback(raw_lua, raw_lua, ptr::null(), 1);
+            let traceback = CStr::from_ptr(ffi::lua_tostring(raw_lua, -1)).to_string_lossy();
+            let traceback = traceback.into_owned();
+            ffi::lua_pop(raw_lua, 1);
+            traceback
+        }
+    }
+
+    /// Returns the names and values of the local variables of a stack frame.
+    ///
+    /// Level 1 is the function that raised the error, level 2 the function that called it, and
+    /// so on. Returns `None` if there is no such level.
+    pub fn locals(&self, level: i32) -> Option<Vec<(String, AnyLuaValue)>> {
+        unsafe {
+            let raw_lua = self.lua.as_ptr();
+            let mut ar: ffi::lua_Debug = mem::zeroed();
+            if ffi::lua_getstack(raw_lua, level, &mut ar) == 0 {
+                return None;
+            }
+
+            let mut locals = Vec::new();
+            for n in 1.. {
+                let name = ffi::lua_getlocal(raw_lua, &ar, n);
+                if name.is_null() {
+                    break;
+                }
+
+                // Names starting with `(` are internal values such as temporaries.
+                let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+                let value = self.value_at(-1);
+                ffi::lua_pop(raw_lua, 1);
+                if !name.starts_with('(') {
+                    locals.push((name, value));
+                }
+            }
+
+            Some(locals)
+        }
+    }
+
+    fn value_at(&self, index: i32) -> AnyLuaValue {
+        let mut lua = self.lua;
+        match LuaRead::lua_read_at_position(&mut lua, index) {
+            Ok(value) => value,
+            Err(_) => unreachable!("reading an AnyLuaValue never fails"),
+        }
+    }
+}
+
+// State shared between `execute_protected` and the message handler.
+struct HandlerState<'a> {
+    handler: &'a mut dyn FnMut(&ErrorContext) -> RecoveryAction,
+    // True if the handler returned `RecoveryAction::ReturnValue`.
+    recovered: bool,
+}
+
+extern "C" fn message_handler(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let state = ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)).cast::<HandlerState>();
+        let state = &mut *state;
+        let mut lua = LuaContext::new_unchecked(lua);
+        let context = ErrorContext { lua };
+
+        // Exit requests are forwarded untouched, since they aren't errors of the script.
+        if exit::requested_exit(lua, 1).is_some() {
+            ffi::lua_pushvalue(lua.as_ptr(), 1);
+            return 1;
+        }
+
+        match (state.handler)(&context) {
+            RecoveryAction::Propagate => match context.message() {
+                Some(_) => {
+                    let l = lua.as_ptr();
+                    ffi::luaL_traceback(l, l, ffi::lua_tostring(l, 1), 1);
+                },
+                None => {
+                    let msg = format!("error object is a {} value", context.value().type_name());
+                    msg.push_no_err(&mut lua).forget();
+                },
+            },
+            RecoveryAction::ReplaceMessage(msg) => {
+                msg.push_no_err(&mut lua).forget();
+            },
+            RecoveryAction::ReturnValue(value) => {
+                state.recovered = true;
+                value.push_no_err(&mut lua).forget();
+            },
+        }
+
+        1
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Executes some Lua code, calling `handler` if an error happens.
+    ///
+    /// Contrary to checking the result of `execute`, the handler is called before the stack
+    /// unwinds. This makes it possible to capture a traceback or the local variables of the
+    /// function that raised the error, for example for crash reports. The handler then decides
+    /// what `execute_protected` returns.
+    ///
+    /// The handler isn't called for syntax errors, which are returned as usual.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, LuaError, RecoveryAction};
+    ///
+    /// let mut lua = Lua::new();
+    /// let result = lua.execute_protected::<(), _>("local answer = 42; return answer + {}", |err| {
+    ///     let locals = err.locals(1).unwrap();
+    ///     RecoveryAction::ReplaceMessage(format!("{} with {:?}", err.message().unwrap(), locals))
+    /// });
+    ///
+    /// match result {
+    ///     Err(LuaError::ExecutionError(err)) => assert!(err.message().contains("\"answer\"")),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn execute_protected<'a, T, F>(
+        &'a mut self,
+        code: &str,
+        mut handler: F,
+    ) -> Result<T, LuaError>
+    where
+        T: for<'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'lua>>>>,
+        F: FnMut(&ErrorContext) -> RecoveryAction,
+    {
+        let mut state = HandlerState { handler: &mut handler, recovered: false };
+
+        let raw_lua = self.as_mut_lua();
+        let msgh = unsafe {
+            let state: *mut HandlerState = &mut state;
+            ffi::lua_pushlightuserdata(raw_lua.as_ptr(), state.cast());
+            ffi::lua_pushcclosure(raw_lua.as_ptr(), Some(message_handler), 1);
+            ffi::lua_gettop(raw_lua.as_ptr())
+        };
+
+        let result = match LuaFunction::load(self, code) {
+            Ok(mut f) => {
+                let (pcall_return_value, pushed_value) =
+                    unsafe { f.call_with_message_handler(msgh) };
+
+                match pcall_return_value {
+                    0 => LuaRead::lua_read(pushed_value).map_err(|_| LuaError::WrongType),
+                    ffi::LUA_ERRRUN if state.recovered => {
+                        LuaRead::lua_read(pushed_value).map_err(|_| LuaError::WrongType)
+                    },
+                    ffi::LUA_ERRMEM => Err(LuaError::MemoryLimitExceeded),
+                    ffi::LUA_ERRRUN | ffi::LUA_ERRERR => Err(read_error(pushed_value)),
+                    _ => panic!("Unknown error code returned by lua_pcall: {}", pcall_return_value),
+                }
+            },
+            Err(err) => Err(err),
+        };
+
+        unsafe { ffi::lua_pop(raw_lua.as_ptr(), 1) };
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AnyLuaValue, Lua, LuaError, RecoveryAction};
+
+    #[test]
+    fn propagate() {
+        let mut lua = Lua::new();
+        let mut called = false;
+        let result = lua.execute_protected::<(), _>("local a = nil; a()", |_| {
+            called = true;
+            RecoveryAction::Propagate
+        });
+
+        assert!(called);
+        assert!(matches!(result, Err(LuaError::ExecutionError(_))));
+    }
+
+    #[test]
+    fn success_skips_handler() {
+        let mut lua = Lua::new();
+        let value: i32 = lua.execute_protected("return 5", |_| unreachable!()).unwrap();
+        assert_eq!(value, 5);
+    }
+
+    #[test]
+    fn syntax_error() {
+        let mut lua = Lua::new();
+        let result = lua.execute_protected::<(), _>("a = ", |_| unreachable!());
+        assert!(matches!(result, Err(LuaError::SyntaxError(_))));
+    }
+
+    #[test]
+    fn locals_traceback_and_recovery() {
+        let mut lua = Lua::new();
+        let mut captured = None;
+
+        let value: String = lua
+            .execute_protected(
+                "local function inner(x) local y = x + 1; return y .. {} end
+                 local result = inner(2)
+                 return result",
+                |err| {
+                    captured = Some((err.locals(1).unwrap(), err.traceback()));
+                    RecoveryAction::ReturnValue(AnyLuaValue::LuaString("recovered".to_owned()))
+                },
+            )
+            .unwrap();
+        assert_eq!(value, "recovered");
+
+        let (locals, traceback) = captured.unwrap();
+        assert_eq!(
+            locals,
+            vec![
+                ("x".to_owned(), AnyLuaValue::LuaNumber(2.0)),
+                ("y".to_owned(), AnyLuaValue::LuaNumber(3.0)),
+            ]
+        );
+        assert!(traceback.contains("inner"));
+    }
+
+    #[test]
+    fn stack_is_balanced() {
+        let mut lua = Lua::new();
+        let top = unsafe { ffi::lua_gettop(lua.lua.as_ptr()) };
+        let _ = lua.execute_protected::<(), _>("error_here()", |_| RecoveryAction::Propagate);
+        let _ = lua.execute_protected::<(), _>("a = ", |_| RecoveryAction::Propagate);
+        assert_eq!(unsafe { ffi::lua_gettop(lua.lua.as_ptr()) }, top);
+    }
+}
@@ -0,0 +1,173 @@
+use std::{error::Error, fmt, sync::Arc};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsMutLua, InsideCallback, LuaContext, Push, PushGuard, PushOne};
+
+/// Error of a Rust callback that is raised as a Lua error, keeping the Rust value.
+///
+/// A callback returning `Result<T, E>` gives `nil` and the message of the error to Lua.
+/// Returning `Result<T, Raise<E>>` instead raises the error, as a user data that converts to
+/// the message with `tostring`. Lua code can catch it with `pcall` and raise it again, and the
+/// Rust code running the script gets a `LuaError::RustError` that `LuaError::downcast_ref`
+/// converts back to `E`.
+///
+/// `Raise<E>` can be created from `E` with `?` or `into`.
+///
+/// # Example
+///
+/// ```
+/// use std::fmt;
+///
+/// #[derive(Debug, PartialEq)]
+/// struct OutOfAmmo { weapon: String }
+///
+/// impl fmt::Display for OutOfAmmo {
+///     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+///         write!(f, "{} is out of ammo", self.weapon)
+///     }
+/// }
+///
+/// impl std::error::Error for OutOfAmmo {}
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("fire", hlua::function1(|weapon: String| -> Result<(), hlua::Raise<OutOfAmmo>> {
+///     Err(OutOfAmmo { weapon }.into())
+/// }));
+///
+/// let err = lua.execute::<()>("fire('laser')").unwrap_err();
+/// assert_eq!(err.downcast_ref(), Some(&OutOfAmmo { weapon: "laser".to_owned() }));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Raise<E>(pub E);
+
+impl<E> From<E> for Raise<E> {
+    #[inline]
+    fn from(err: E) -> Raise<E> {
+        Raise(err)
+    }
+}
+
+// Error object raised for a `Raise`.
+struct RaisedError(Arc<dyn Error + Send + Sync>);
+
+impl<'a, T, E, P> Push<&'a mut InsideCallback> for Result<T, Raise<E>>
+where
+    T: Push<&'a mut InsideCallback, Err = P>
+        + for<'b> Push<&'b mut &'a mut InsideCallback, Err = P>,
+    E: Error + Send + Sync + 'static,
+{
+    type Err = P;
+
+    #[inline]
+    fn push_to_lua(
+        self,
+        lua: &'a mut InsideCallback,
+    ) -> Result<PushGuard<&'a mut InsideCallback>, (P, &'a mut InsideCallback)> {
+        match self {
+            Ok(val) => val.push_to_lua(lua),
+            Err(Raise(err)) => unsafe { raise(lua.lua, Arc::new(err)) },
+        }
+    }
+}
+
+impl<'a, T, E, P> PushOne<&'a mut InsideCallback> for Result<T, Raise<E>>
+where
+    T: PushOne<&'a mut InsideCallback, Err = P>
+        + for<'b> PushOne<&'b mut &'a mut InsideCallback, Err = P>,
+    E: Error + Send + Sync + 'static,
+{
+}
+
+// Raises `err` as a Lua error.
+#[cold]
+#[inline(never)]
+unsafe fn raise(lua: LuaContext, err: Arc<dyn Error + Send + Sync>) -> ! {
+    push_userdata(RaisedError(err), lua, |mut metatable| {
+        let raw_lua = metatable.as_mut_lua().as_ptr();
+        ffi::lua_pushcfunction(raw_lua, Some(to_string));
+        ffi::lua_setfield(raw_lua, -2, c"__tostring".as_ptr());
+    })
+    .forget();
+    ffix::lua_error(lua.as_ptr());
+}
+
+extern "C" fn to_string(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let lua = LuaContext::new_unchecked(lua);
+        let message = userdata_mut::<RaisedError>(lua, 1).map(|raised| raised.0.to_string());
+        message.unwrap_or_default().push_no_err(lua).forget();
+        1
+    }
+}
+
+/// Returns the Rust error if the value at `index` was raised for a `Raise`.
+pub(crate) unsafe fn raised_error(
+    lua: LuaContext,
+    index: libc::c_int,
+) -> Option<Arc<dyn Error + Send + Sync>> {
+    userdata_mut::<RaisedError>(lua, index).map(|raised| raised.0.clone())
+}
+
+impl fmt::Debug for RaisedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("RaisedError").field(&self.0).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, fmt, io};
+
+    use crate::{function1, Lua, LuaError, Raise};
+
+    #[derive(Debug, PartialEq)]
+    struct NotFound(String);
+
+    impl fmt::Display for NotFound {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{} not found", self.0)
+        }
+    }
+
+    impl Error for NotFound {}
+
+    fn lua() -> Lua<'static> {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set(
+            "find",
+            function1(|name: String| -> Result<i32, Raise<NotFound>> {
+                match &name[..] {
+                    "ada" => Ok(1),
+                    _ => Err(NotFound(name))?,
+                }
+            }),
+        );
+        lua
+    }
+
+    #[test]
+    fn raised_errors_keep_their_type() {
+        let mut lua = lua();
+        assert_eq!(lua.execute::<i32>("return find('ada')").unwrap(), 1);
+
+        let err = lua.execute::<()>("find('bob')").unwrap_err();
+        assert!(matches!(err, LuaError::RustError(_)));
+        assert_eq!(err.downcast_ref(), Some(&NotFound("bob".to_owned())));
+        assert!(err.downcast_ref::<io::Error>().is_none());
+        assert_eq!(err.to_string(), "Callback error: bob not found");
+        assert!(err.source().unwrap().is::<NotFound>());
+    }
+
+    #[test]
+    fn lua_can_catch_and_rethrow() {
+        let mut lua = lua();
+        let code = "
+            local ok, err = pcall(find, 'eve')
+            assert(not ok and tostring(err) == 'eve not found')
+            error(err)
+        ";
+        let err = lua.execute::<()>(code).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&NotFound("eve".to_owned())));
+    }
+}
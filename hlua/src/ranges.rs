@@ -0,0 +1,380 @@
+use std::{error::Error, fmt, ops::Deref};
+
+use crate::functions_write::set_conversion_error;
+use crate::{AsLua, AsMutLua, LuaContext, LuaRead, Push, PushGuard, PushOne};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Number type that can be wrapped in a `Bounded` or a `NonNegative`.
+///
+/// Implemented on the integer types up to `i64` and `u32`, and on `f32` and `f64`.
+pub trait RangeValue: Copy + fmt::Display + sealed::Sealed {
+    #[doc(hidden)]
+    type Wide: Copy + PartialOrd + fmt::Display;
+
+    // Reads the number at `index`, with no loss.
+    #[doc(hidden)]
+    unsafe fn read_wide(lua: LuaContext, index: i32) -> Option<Self::Wide>;
+
+    // Largest value of the type, if it is an integer type.
+    #[doc(hidden)]
+    const TYPE_MAX: Option<i64>;
+
+    // Clamps a limit to the range of the type.
+    #[doc(hidden)]
+    fn clamp_limit(limit: i64) -> i64;
+
+    #[doc(hidden)]
+    fn widen_limit(limit: i64) -> Self::Wide;
+
+    // Converts a value that is in the range of the type.
+    #[doc(hidden)]
+    fn narrow(value: Self::Wide) -> Self;
+
+    #[doc(hidden)]
+    fn widen(self) -> Self::Wide;
+}
+
+macro_rules! integer_range_value {
+    ($($t:ident),*) => ($(
+        impl sealed::Sealed for $t {}
+
+        impl RangeValue for $t {
+            type Wide = i64;
+            const TYPE_MAX: Option<i64> = Some($t::MAX as i64);
+
+            #[inline]
+            unsafe fn read_wide(lua: LuaContext, index: i32) -> Option<i64> {
+                let mut success = 0;
+                let value = ffi::lua_tointegerx(lua.as_ptr(), index, &mut success);
+                (success != 0).then_some(value as i64)
+            }
+
+            #[inline]
+            fn clamp_limit(limit: i64) -> i64 {
+                limit.clamp($t::MIN as i64, $t::MAX as i64)
+            }
+
+            #[inline]
+            fn widen_limit(limit: i64) -> i64 {
+                limit
+            }
+
+            #[inline]
+            fn narrow(value: i64) -> $t {
+                value as $t
+            }
+
+            #[inline]
+            fn widen(self) -> i64 {
+                self as i64
+            }
+        }
+    )*)
+}
+
+integer_range_value!(i8, i16, i32, i64, u8, u16, u32);
+
+macro_rules! float_range_value {
+    ($($t:ident),*) => ($(
+        impl sealed::Sealed for $t {}
+
+        impl RangeValue for $t {
+            type Wide = f64;
+            const TYPE_MAX: Option<i64> = None;
+
+            #[inline]
+            unsafe fn read_wide(lua: LuaContext, index: i32) -> Option<f64> {
+                let mut success = 0;
+                let value = ffi::lua_tonumberx(lua.as_ptr(), index, &mut success);
+                (success != 0).then_some(value)
+            }
+
+            #[inline]
+            fn clamp_limit(limit: i64) -> i64 {
+                limit
+            }
+
+            #[inline]
+            fn widen_limit(limit: i64) -> f64 {
+                limit as f64
+            }
+
+            #[inline]
+            fn narrow(value: f64) -> $t {
+                value as $t
+            }
+
+            #[inline]
+            fn widen(self) -> f64 {
+                self as f64
+            }
+        }
+    )*)
+}
+
+float_range_value!(f32, f64);
+
+// Checks that `value` is between `min` and `max`, included, and in the range of `T`. NaN is
+// never in the range.
+fn check<T: RangeValue>(value: T::Wide, min: i64, max: Option<i64>) -> Result<T, RangeError> {
+    let error = |kind| Err(RangeError { value: value.to_string(), kind });
+    #[allow(clippy::eq_op)]
+    if value != value {
+        return error(RangeErrorKind::NotANumber);
+    }
+
+    let min = T::clamp_limit(min);
+    if value < T::widen_limit(min) {
+        return error(RangeErrorKind::BelowMinimum(min));
+    }
+    // Without a maximum, the one of the type still applies.
+    if let Some(max) = max.map(T::clamp_limit).or(T::TYPE_MAX) {
+        if value > T::widen_limit(max) {
+            return error(RangeErrorKind::AboveMaximum(max));
+        }
+    }
+    Ok(T::narrow(value))
+}
+
+// Reads a number and checks its range, recording the reason of a failure for the error of the
+// callback being called, if any.
+fn read<'lua, L, T>(lua: L, index: i32, min: i64, max: Option<i64>) -> Result<T, L>
+where
+    L: AsLua<'lua>,
+    T: RangeValue,
+{
+    let value = match unsafe { T::read_wide(lua.as_lua(), index) } {
+        Some(value) => value,
+        None => return Err(lua),
+    };
+    check::<T>(value, min, max).map_err(|err| {
+        set_conversion_error(err.to_string());
+        lua
+    })
+}
+
+/// A number between `MIN` and `MAX`, included.
+///
+/// Reading a `Bounded` from Lua fails if the value is out of range, in addition to the cases in
+/// which reading a `T` fails. When this happens while reading the parameters of a Rust
+/// callback, the error raised in Lua names the limit that was violated. The value is pushed to
+/// Lua like a `T`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Bounded, Lua};
+///
+/// let mut lua = Lua::new();
+/// lua.set("set_volume", hlua::function1(|volume: Bounded<i32, 0, 100>| volume.get()));
+///
+/// assert_eq!(lua.execute::<i32>("return set_volume(40)").unwrap(), 40);
+/// let err = lua.execute::<i32>("return set_volume(150)").unwrap_err();
+/// assert!(err.to_string().contains("150 is above the maximum of 100"));
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub struct Bounded<T, const MIN: i64, const MAX: i64>(T);
+
+impl<T: RangeValue, const MIN: i64, const MAX: i64> Bounded<T, MIN, MAX> {
+    /// Returns `value` as a `Bounded` if it is in the range.
+    pub fn new(value: T) -> Result<Bounded<T, MIN, MAX>, RangeError> {
+        check::<T>(value.widen(), MIN, Some(MAX)).map(Bounded)
+    }
+
+    /// Returns the number.
+    #[inline]
+    pub fn get(self) -> T {
+        self.0
+    }
+}
+
+impl<T, const MIN: i64, const MAX: i64> Deref for Bounded<T, MIN, MAX> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<'lua, L, T, const MIN: i64, const MAX: i64> LuaRead<L> for Bounded<T, MIN, MAX>
+where
+    L: AsLua<'lua>,
+    T: RangeValue,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<Bounded<T, MIN, MAX>, L> {
+        read(lua, index, MIN, Some(MAX)).map(Bounded)
+    }
+}
+
+impl<'lua, L, T, const MIN: i64, const MAX: i64> Push<L> for Bounded<T, MIN, MAX>
+where
+    L: AsMutLua<'lua>,
+    T: Push<L>,
+{
+    type Err = T::Err;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (T::Err, L)> {
+        self.0.push_to_lua(lua)
+    }
+}
+
+impl<'lua, L, T, const MIN: i64, const MAX: i64> PushOne<L> for Bounded<T, MIN, MAX>
+where
+    L: AsMutLua<'lua>,
+    T: PushOne<L>,
+{
+}
+
+/// A number that is zero or more. NaN isn't a valid value.
+///
+/// This works like a `Bounded` without maximum.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, NonNegative};
+///
+/// let mut lua = Lua::new();
+/// lua.set("wait", hlua::function1(|seconds: NonNegative<f64>| seconds.get() * 1000.0));
+///
+/// assert_eq!(lua.execute::<f64>("return wait(0.5)").unwrap(), 500.0);
+/// let err = lua.execute::<f64>("return wait(-1)").unwrap_err();
+/// assert!(err.to_string().contains("-1 is below the minimum of 0"));
+/// assert!(lua.execute::<f64>("return wait(0/0)").is_err());
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub struct NonNegative<T>(T);
+
+impl<T: RangeValue> NonNegative<T> {
+    /// Returns `value` as a `NonNegative` if it is zero or more.
+    pub fn new(value: T) -> Result<NonNegative<T>, RangeError> {
+        check::<T>(value.widen(), 0, None).map(NonNegative)
+    }
+
+    /// Returns the number.
+    #[inline]
+    pub fn get(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for NonNegative<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<'lua, L, T> LuaRead<L> for NonNegative<T>
+where
+    L: AsLua<'lua>,
+    T: RangeValue,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<NonNegative<T>, L> {
+        read(lua, index, 0, None).map(NonNegative)
+    }
+}
+
+impl<'lua, L, T> Push<L> for NonNegative<T>
+where
+    L: AsMutLua<'lua>,
+    T: Push<L>,
+{
+    type Err = T::Err;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (T::Err, L)> {
+        self.0.push_to_lua(lua)
+    }
+}
+
+impl<'lua, L, T> PushOne<L> for NonNegative<T>
+where
+    L: AsMutLua<'lua>,
+    T: PushOne<L>,
+{
+}
+
+/// Error returned when a number is out of the range of a `Bounded` or a `NonNegative`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeError {
+    /// The number, formatted.
+    pub value: String,
+    /// Which limit the number violates.
+    pub kind: RangeErrorKind,
+}
+
+/// Kind of a `RangeError`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RangeErrorKind {
+    /// The number is smaller than this minimum.
+    BelowMinimum(i64),
+    /// The number is greater than this maximum.
+    AboveMaximum(i64),
+    /// The number is NaN.
+    NotANumber,
+}
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            RangeErrorKind::BelowMinimum(min) => {
+                write!(f, "{} is below the minimum of {}", self.value, min)
+            },
+            RangeErrorKind::AboveMaximum(max) => {
+                write!(f, "{} is above the maximum of {}", self.value, max)
+            },
+            RangeErrorKind::NotANumber => write!(f, "expected a number, found NaN"),
+        }
+    }
+}
+
+impl Error for RangeError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{function2, Bounded, Lua, NonNegative, RangeErrorKind};
+
+    #[test]
+    fn checked_construction() {
+        assert_eq!(Bounded::<i32, -5, 5>::new(5).unwrap().get(), 5);
+        let err = Bounded::<i32, -5, 5>::new(-6).unwrap_err();
+        assert_eq!(err.kind, RangeErrorKind::BelowMinimum(-5));
+        assert_eq!(err.to_string(), "-6 is below the minimum of -5");
+
+        // The limits are clamped to the range of the type.
+        let err = Bounded::<u8, 0, 1000>::new(255).map(|b| *b);
+        assert_eq!(err, Ok(255));
+        assert_eq!(NonNegative::new(f64::INFINITY).unwrap().get(), f64::INFINITY);
+        assert_eq!(NonNegative::new(f64::NAN).unwrap_err().kind, RangeErrorKind::NotANumber);
+    }
+
+    #[test]
+    fn reading_from_lua() {
+        let mut lua = Lua::new();
+        lua.set("percent", 101);
+        lua.set("big", 300);
+        assert_eq!(lua.get::<Bounded<i32, 0, 100>, _>("percent"), None);
+        assert_eq!(lua.get::<Bounded<i32, 0, 101>, _>("percent").map(Bounded::get), Some(101));
+        // The value isn't truncated before being checked.
+        assert_eq!(lua.get::<Bounded<u8, 0, 1000>, _>("big"), None);
+        assert_eq!(lua.get::<NonNegative<u32>, _>("big").map(|n| *n), Some(300));
+
+        lua.set("scale", function2(|a: f64, b: Bounded<f64, 1, 10>| a * b.get()));
+        assert_eq!(lua.execute::<f64>("return scale(2, 1.5)").unwrap(), 3.0);
+        let err = lua.execute::<f64>("return scale(2, 10.5)").unwrap_err().to_string();
+        assert!(err.contains("callback function: 10.5 is above the maximum of 10"), "{}", err);
+        // Other errors don't keep the reason of a previous one.
+        let err = lua.execute::<f64>("return scale('x', 2)").unwrap_err().to_string();
+        assert!(err.ends_with("wrong parameter types for callback function"), "{}", err);
+    }
+}
@@ -0,0 +1,424 @@
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::{slice, str};
+
+use crate::{AsLua, AsMutLua, LuaContext, LuaRead, Push, PushGuard, Void};
+
+/// Safe access to the Lua stack, for implementing `Push` and `LuaRead` without the `ffi` crate.
+///
+/// A `RawStack` is made with `RawStack::push_with`, whose values become the pushed values of a
+/// `Push` implementation, or with `RawStack::inspect`, which restores the stack afterwards as a
+/// `LuaRead` implementation must. Its methods keep the stack consistent:
+///
+/// - Indices are either positive, counting from the bottom of the stack, or negative, counting
+///   from the top. They must refer to a value on the stack, otherwise the method panics.
+/// - The values that were on the stack when the `RawStack` was made can be read and modified,
+///   but not popped. Popping them panics.
+/// - Methods that push values grow the stack if needed, and panic if it can't grow.
+/// - Tables are accessed without calling metamethods, so that no Lua error can be raised.
+///   Accessing a value that isn't a table as one panics.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{AsMutLua, LuaRead, Push, PushGuard, PushOne, RawStack, Void};
+///
+/// #[derive(Debug, PartialEq)]
+/// struct Point { x: f64, y: f64 }
+///
+/// impl<'lua, L: AsMutLua<'lua>> Push<L> for Point {
+///     type Err = Void;
+///     fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+///         Ok(RawStack::push_with(lua, |stack| {
+///             stack.new_table();
+///             stack.push_number(self.x);
+///             stack.set_field(-2, "x");
+///             stack.push_number(self.y);
+///             stack.set_field(-2, "y");
+///         }))
+///     }
+/// }
+/// impl<'lua, L: AsMutLua<'lua>> PushOne<L> for Point {}
+///
+/// impl<'lua, L: AsMutLua<'lua>> LuaRead<L> for Point {
+///     fn lua_read_at_position(mut lua: L, index: i32) -> Result<Point, L> {
+///         let point = RawStack::inspect(&mut lua, |stack| {
+///             // Pushing values changes the meaning of negative indices.
+///             let table = stack.abs_index(index);
+///             if !stack.is_table(table) {
+///                 return None;
+///             }
+///             stack.get_field(table, "x");
+///             stack.get_field(table, "y");
+///             Some(Point { x: stack.to_number(-2)?, y: stack.to_number(-1)? })
+///         });
+///         point.ok_or(lua)
+///     }
+/// }
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("p", Point { x: 1.0, y: 2.5 });
+/// assert_eq!(lua.execute::<f64>("p.x = p.x + 1; return p.x + p.y").unwrap(), 4.5);
+/// assert_eq!(lua.get::<Point, _>("p").unwrap(), Point { x: 2.0, y: 2.5 });
+/// ```
+#[derive(Debug)]
+pub struct RawStack<'a> {
+    lua: LuaContext,
+    // Number of values on the stack that can't be popped.
+    base: i32,
+    marker: PhantomData<&'a mut ()>,
+}
+
+// Restores the top of the stack when a `RawStack` is done, or if its user panics.
+struct Restore {
+    lua: LuaContext,
+    top: i32,
+}
+
+impl Drop for Restore {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { ffi::lua_settop(self.lua.as_ptr(), self.top) };
+    }
+}
+
+impl<'a> RawStack<'a> {
+    /// Calls `f` with the stack of `lua`, and returns a guard of the values that `f` pushed.
+    ///
+    /// If `f` panics, the values it pushed are popped.
+    pub fn push_with<'lua, L, F>(mut lua: L, f: F) -> PushGuard<L>
+    where
+        L: AsMutLua<'lua>,
+        F: FnOnce(&mut RawStack<'_>),
+    {
+        let raw_lua = lua.as_mut_lua();
+        let base = unsafe { ffi::lua_gettop(raw_lua.as_ptr()) };
+        let restore = Restore { lua: raw_lua, top: base };
+        f(&mut RawStack { lua: raw_lua, base, marker: PhantomData });
+        std::mem::forget(restore);
+
+        let size = unsafe { ffi::lua_gettop(raw_lua.as_ptr()) } - base;
+        PushGuard { lua, size, raw_lua }
+    }
+
+    /// Calls `f` with the stack of `lua`, and pops the values that `f` pushed once it returns.
+    pub fn inspect<'lua, L, F, R>(lua: &mut L, f: F) -> R
+    where
+        L: AsMutLua<'lua>,
+        F: FnOnce(&mut RawStack<'_>) -> R,
+    {
+        let raw_lua = lua.as_mut_lua();
+        let base = unsafe { ffi::lua_gettop(raw_lua.as_ptr()) };
+        let _restore = Restore { lua: raw_lua, top: base };
+        f(&mut RawStack { lua: raw_lua, base, marker: PhantomData })
+    }
+
+    /// Returns the number of values on the stack, which is also the positive index of the top
+    /// value.
+    #[inline]
+    pub fn top(&self) -> i32 {
+        unsafe { ffi::lua_gettop(self.lua.as_ptr()) }
+    }
+
+    /// Makes sure that `extra` more values can be pushed. Returns false if the stack can't grow
+    /// that much. The methods that push values call this themselves.
+    #[inline]
+    pub fn check_stack(&mut self, extra: i32) -> bool {
+        unsafe { ffi::lua_checkstack(self.lua.as_ptr(), extra) != 0 }
+    }
+
+    /// Pops `n` values.
+    ///
+    /// # Panic
+    ///
+    /// Panics if this would pop values that were on the stack when the `RawStack` was made.
+    #[track_caller]
+    pub fn pop(&mut self, n: i32) {
+        assert!(n >= 0 && n <= self.top() - self.base, "can't pop {} values", n);
+        unsafe { ffi::lua_pop(self.lua.as_ptr(), n) };
+    }
+
+    /// Returns the name of the type of the value at `index`, as `type()` would in Lua.
+    #[track_caller]
+    pub fn type_name(&self, index: i32) -> &'static str {
+        let index = self.abs_index(index);
+        unsafe {
+            let name =
+                ffi::lua_typename(self.lua.as_ptr(), ffi::lua_type(self.lua.as_ptr(), index));
+            str::from_utf8(CStr::from_ptr(name).to_bytes()).unwrap_or("?")
+        }
+    }
+
+    /// Returns true if the value at `index` is nil.
+    #[inline]
+    #[track_caller]
+    pub fn is_nil(&self, index: i32) -> bool {
+        let index = self.abs_index(index);
+        unsafe { ffi::lua_isnil(self.lua.as_ptr(), index) }
+    }
+
+    /// Returns true if the value at `index` is a table.
+    #[inline]
+    #[track_caller]
+    pub fn is_table(&self, index: i32) -> bool {
+        let index = self.abs_index(index);
+        unsafe { ffi::lua_istable(self.lua.as_ptr(), index) }
+    }
+
+    /// Returns false if the value at `index` is nil or false, and true otherwise, like a
+    /// condition in Lua.
+    #[inline]
+    #[track_caller]
+    pub fn to_bool(&self, index: i32) -> bool {
+        let index = self.abs_index(index);
+        unsafe { ffi::lua_toboolean(self.lua.as_ptr(), index) != 0 }
+    }
+
+    /// Returns the value at `index` if it is a number, or a string that can be converted to a
+    /// number.
+    #[track_caller]
+    pub fn to_number(&self, index: i32) -> Option<f64> {
+        let index = self.abs_index(index);
+        let mut is_number = 0;
+        let number = unsafe { ffi::lua_tonumberx(self.lua.as_ptr(), index, &mut is_number) };
+        (is_number != 0).then_some(number as f64)
+    }
+
+    /// Returns the bytes of the value at `index` if it is a string. Numbers aren't converted.
+    #[track_caller]
+    pub fn to_bytes(&self, index: i32) -> Option<&[u8]> {
+        let index = self.abs_index(index);
+        unsafe {
+            if ffi::lua_type(self.lua.as_ptr(), index) != ffi::LUA_TSTRING {
+                return None;
+            }
+            let mut len = 0;
+            let data = ffi::lua_tolstring(self.lua.as_ptr(), index, &mut len);
+            Some(slice::from_raw_parts(data.cast(), len))
+        }
+    }
+
+    /// Returns the value at `index` if it is a string that is valid UTF-8.
+    #[inline]
+    #[track_caller]
+    pub fn to_str(&self, index: i32) -> Option<&str> {
+        self.to_bytes(index).and_then(|bytes| str::from_utf8(bytes).ok())
+    }
+
+    /// Returns the length of the value at `index` as the `#` operator would without calling
+    /// metamethods: the length of strings, the border of tables, and 0 for the other types.
+    #[inline]
+    #[track_caller]
+    pub fn raw_len(&self, index: i32) -> usize {
+        let index = self.abs_index(index);
+        unsafe { crate::ffix::lua_rawlen(self.lua, index) }
+    }
+
+    /// Reads the value at `index` with its `LuaRead` implementation.
+    #[track_caller]
+    pub fn read<V>(&mut self, index: i32) -> Option<V>
+    where
+        V: for<'s> LuaRead<&'s mut RawStack<'a>>,
+    {
+        let index = self.abs_index(index);
+        LuaRead::lua_read_at_position(self, index).ok()
+    }
+
+    /// Pushes nil.
+    #[inline]
+    pub fn push_nil(&mut self) {
+        self.reserve(1);
+        unsafe { ffi::lua_pushnil(self.lua.as_ptr()) };
+    }
+
+    /// Pushes a boolean.
+    #[inline]
+    pub fn push_bool(&mut self, value: bool) {
+        self.reserve(1);
+        unsafe { ffi::lua_pushboolean(self.lua.as_ptr(), value as libc::c_int) };
+    }
+
+    /// Pushes a number.
+    #[inline]
+    pub fn push_number(&mut self, value: f64) {
+        self.reserve(1);
+        unsafe { ffi::lua_pushnumber(self.lua.as_ptr(), value as _) };
+    }
+
+    /// Pushes a string, which can contain any bytes.
+    #[inline]
+    pub fn push_bytes(&mut self, value: &[u8]) {
+        self.reserve(1);
+        unsafe { ffi::lua_pushlstring(self.lua.as_ptr(), value.as_ptr().cast(), value.len() as _) };
+    }
+
+    /// Pushes a string.
+    #[inline]
+    pub fn push_str(&mut self, value: &str) {
+        self.push_bytes(value.as_bytes());
+    }
+
+    /// Pushes a copy of the value at `index`.
+    #[inline]
+    #[track_caller]
+    pub fn push_copy(&mut self, index: i32) {
+        let index = self.abs_index(index);
+        self.reserve(1);
+        unsafe { ffi::lua_pushvalue(self.lua.as_ptr(), index) };
+    }
+
+    /// Pushes a new empty table.
+    #[inline]
+    pub fn new_table(&mut self) {
+        self.reserve(1);
+        unsafe { ffi::lua_newtable(self.lua.as_ptr()) };
+    }
+
+    /// Pushes a value with its `Push` implementation, and returns the number of values pushed.
+    pub fn push<V>(&mut self, value: V) -> i32
+    where
+        V: for<'s> Push<&'s mut RawStack<'a>, Err = Void>,
+    {
+        self.reserve(1);
+        value.push_no_err(self).forget_internal()
+    }
+
+    /// Pushes the field `name` of the table at `index`.
+    #[track_caller]
+    pub fn get_field(&mut self, table: i32, name: &str) {
+        let table = self.table(table);
+        self.push_str(name);
+        unsafe { ffi::lua_rawget(self.lua.as_ptr(), table) };
+    }
+
+    /// Pops a value and stores it in the field `name` of the table at `index`.
+    #[track_caller]
+    pub fn set_field(&mut self, table: i32, name: &str) {
+        let table = self.table(table);
+        self.assert_poppable(1);
+        self.push_str(name);
+        self.push_copy(-2);
+        unsafe {
+            ffi::lua_rawset(self.lua.as_ptr(), table);
+            ffi::lua_pop(self.lua.as_ptr(), 1);
+        }
+    }
+
+    /// Pushes the element `n` of the table at `index`.
+    #[track_caller]
+    pub fn get_index(&mut self, table: i32, n: i32) {
+        let table = self.table(table);
+        self.reserve(1);
+        unsafe { ffi::lua_rawgeti(self.lua.as_ptr(), table, n as _) };
+    }
+
+    /// Pops a value and stores it as the element `n` of the table at `index`.
+    #[track_caller]
+    pub fn set_index(&mut self, table: i32, n: i32) {
+        let table = self.table(table);
+        self.assert_poppable(1);
+        unsafe { ffi::lua_rawseti(self.lua.as_ptr(), table, n as _) };
+    }
+
+    /// Returns the positive index of the value at `index`, which stays the same when values are
+    /// pushed.
+    #[track_caller]
+    pub fn abs_index(&self, index: i32) -> i32 {
+        let top = self.top();
+        let absolute = if index < 0 { top + index + 1 } else { index };
+        assert!(absolute >= 1 && absolute <= top, "invalid stack index {}", index);
+        absolute
+    }
+
+    #[track_caller]
+    fn table(&self, index: i32) -> i32 {
+        let index = self.abs_index(index);
+        assert!(self.is_table(index), "value at index {} is not a table", index);
+        index
+    }
+
+    #[track_caller]
+    fn assert_poppable(&self, n: i32) {
+        assert!(n <= self.top() - self.base, "not enough values pushed");
+    }
+
+    #[track_caller]
+    fn reserve(&mut self, extra: i32) {
+        assert!(self.check_stack(extra), "Lua stack overflow");
+    }
+}
+
+unsafe impl<'a, 'lua> AsLua<'lua> for RawStack<'a> {
+    #[inline]
+    fn as_lua(&self) -> LuaContext {
+        self.lua
+    }
+}
+
+unsafe impl<'a, 'lua> AsMutLua<'lua> for RawStack<'a> {
+    #[inline]
+    fn as_mut_lua(&mut self) -> LuaContext {
+        self.lua
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use crate::{AnyLuaValue, Lua, LuaTable, RawStack};
+
+    #[test]
+    fn push_and_inspect() {
+        let mut lua = Lua::new();
+        let pushed = RawStack::push_with(&mut lua, |stack| {
+            stack.new_table();
+            for (n, word) in ["a", "b", "c"].iter().enumerate() {
+                stack.push_str(word);
+                stack.set_index(-2, n as i32 + 1);
+            }
+            stack.push(12);
+            stack.set_field(1, "count");
+        });
+        assert_eq!(pushed.size(), 1);
+
+        let mut table: LuaTable<_> = crate::LuaRead::lua_read(pushed).ok().unwrap();
+        let (len, second, count) = RawStack::inspect(&mut table, |stack| {
+            stack.get_index(-1, 2);
+            stack.get_field(-2, "count");
+            let second = stack.to_str(-2).map(str::to_owned);
+            (stack.raw_len(-3), second, stack.read::<i32>(-1))
+        });
+        assert_eq!((len, second.as_deref(), count), (3, Some("b"), Some(12)));
+        assert_eq!(table.get::<String, _, _>(3).unwrap(), "c");
+    }
+
+    #[test]
+    fn invariants_are_checked() {
+        let mut lua = Lua::new();
+        lua.set("x", 1);
+        let top = RawStack::inspect(&mut lua, |stack| stack.top());
+
+        let pop = catch_unwind(AssertUnwindSafe(|| {
+            RawStack::inspect(&mut lua, |stack| {
+                stack.push_bool(true);
+                stack.pop(2);
+            })
+        }));
+        assert!(pop.is_err());
+        let index = catch_unwind(AssertUnwindSafe(|| {
+            RawStack::inspect(&mut lua, |stack| stack.type_name(stack.top() + 1))
+        }));
+        assert!(index.is_err());
+        let field = catch_unwind(AssertUnwindSafe(|| {
+            let _ = RawStack::push_with(&mut lua, |stack| {
+                stack.push_number(1.0);
+                stack.get_field(-1, "x");
+            });
+        }));
+        assert!(field.is_err());
+
+        assert_eq!(RawStack::inspect(&mut lua, |stack| stack.top()), top);
+        assert_eq!(lua.get::<AnyLuaValue, _>("x"), Some(AnyLuaValue::LuaNumber(1.0)));
+    }
+}
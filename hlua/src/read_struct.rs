@@ -0,0 +1,352 @@
+use std::{
+    any,
+    error::Error,
+    ffi::CStr,
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+use crate::functions_write::set_conversion_error;
+use crate::{AsLua, AsMutLua, Lua, LuaContext, LuaRead, LuaTable, PathErrorKind, PushGuard};
+
+/// Types that can be read from the fields of a Lua table, with an error that tells which field
+/// couldn't be read.
+///
+/// This is usually implemented with `#[derive(LuaRead)]`, which also implements `LuaRead` on top
+/// of it. The values are then read with `Lua::read_struct` or `LuaTable::read_struct` to get
+/// the error, or like any other value with `Lua::get` if the error doesn't matter.
+pub trait LuaReadStruct: Sized {
+    /// Reads the fields of the struct from the table of `reader`.
+    fn read_struct(reader: &mut StructReader) -> Result<Self, StructReadError>;
+}
+
+/// Error returned when a struct can't be read from a Lua table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructReadError {
+    /// Name of the struct that was read.
+    pub type_name: &'static str,
+    /// Key of the field that couldn't be read, or an empty string if the value isn't a table.
+    pub field: String,
+    /// What went wrong.
+    pub kind: PathErrorKind,
+    /// Rust type of the field, or `table` if the value isn't a table.
+    pub expected: String,
+    /// Lua type of the value that was found.
+    pub found: &'static str,
+}
+
+impl fmt::Display for StructReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot read {}: ", self.type_name)?;
+        match self.kind {
+            PathErrorKind::NotATable => write!(f, "expected a table, found {}", self.found),
+            PathErrorKind::Missing => write!(f, "field `{}` is missing", self.field),
+            PathErrorKind::WrongType => write!(
+                f,
+                "field `{}` has the wrong type (expected {}, found {})",
+                self.field, self.expected, self.found
+            ),
+        }
+    }
+}
+
+impl Error for StructReadError {}
+
+/// Value of a field read by `StructReader`, which the type of the field must be readable from.
+pub type FieldGuard = PushGuard<LuaContext>;
+
+/// Reads the fields of a table for a `LuaReadStruct` implementation.
+#[derive(Debug)]
+pub struct StructReader {
+    lua: LuaContext,
+    // Absolute index of the table.
+    index: libc::c_int,
+    type_name: &'static str,
+}
+
+impl StructReader {
+    /// Reads the value at the string key `key`.
+    ///
+    /// Fails with `PathErrorKind::Missing` if the value is nil and `V` can't be read from nil,
+    /// like it can for `Option`.
+    pub fn field<V>(&mut self, key: &str) -> Result<V, StructReadError>
+    where
+        V: LuaRead<FieldGuard>,
+    {
+        unsafe {
+            let l = self.lua.as_ptr();
+            ffi::lua_pushlstring(l, key.as_ptr().cast(), key.len());
+            ffi::lua_gettable(l, self.index);
+        }
+        self.read_top(key)
+    }
+
+    /// Reads the value at the string key `key`, or returns the result of `default` if the value
+    /// is nil.
+    pub fn field_or_else<V, F>(&mut self, key: &str, default: F) -> Result<V, StructReadError>
+    where
+        V: LuaRead<FieldGuard>,
+        F: FnOnce() -> V,
+    {
+        unsafe {
+            let l = self.lua.as_ptr();
+            ffi::lua_pushlstring(l, key.as_ptr().cast(), key.len());
+            ffi::lua_gettable(l, self.index);
+            if ffi::lua_isnil(l, -1) {
+                ffi::lua_pop(l, 1);
+                return Ok(default());
+            }
+        }
+        self.read_top(key)
+    }
+
+    /// Reads the value at the integer key `key`.
+    pub fn element<V>(&mut self, key: i32) -> Result<V, StructReadError>
+    where
+        V: LuaRead<FieldGuard>,
+    {
+        unsafe {
+            let l = self.lua.as_ptr();
+            ffi::lua_pushinteger(l, key as ffi::lua_Integer);
+            ffi::lua_gettable(l, self.index);
+        }
+        self.read_top(&key.to_string())
+    }
+
+    fn read_top<V>(&mut self, key: &str) -> Result<V, StructReadError>
+    where
+        V: LuaRead<FieldGuard>,
+    {
+        let found = unsafe { lua_type_name(self.lua, -1) };
+        let guard = PushGuard { lua: self.lua, size: 1, raw_lua: self.lua };
+        LuaRead::lua_read(guard).map_err(|_| StructReadError {
+            type_name: self.type_name,
+            field: key.to_owned(),
+            kind: match found {
+                "nil" => PathErrorKind::Missing,
+                _ => PathErrorKind::WrongType,
+            },
+            expected: short_type_name(any::type_name::<V>()),
+            found,
+        })
+    }
+}
+
+/// Reads a `T` from the table at `index`.
+///
+/// This is what `#[derive(LuaRead)]` uses to implement `LuaRead`.
+#[doc(hidden)]
+pub fn read_struct_at<'lua, T, L>(lua: &L, index: i32) -> Result<T, StructReadError>
+where
+    T: LuaReadStruct,
+    L: AsLua<'lua>,
+{
+    let raw_lua = lua.as_lua();
+    unsafe {
+        if !ffi::lua_istable(raw_lua.as_ptr(), index) {
+            return Err(StructReadError {
+                type_name: short_name::<T>(),
+                field: String::new(),
+                kind: PathErrorKind::NotATable,
+                expected: "table".to_owned(),
+                found: lua_type_name(raw_lua, index),
+            });
+        }
+        let index = match index {
+            index if index < 0 => ffi::lua_gettop(raw_lua.as_ptr()) + index + 1,
+            index => index,
+        };
+        let mut reader = StructReader { lua: raw_lua, index, type_name: short_name::<T>() };
+        T::read_struct(&mut reader)
+    }
+}
+
+/// Parameter of a Rust callback that reads a struct from a table, for Lua functions taking
+/// named arguments like `window{ width = 800, title = "demo" }`.
+///
+/// `T` is read with its `LuaReadStruct` implementation, usually derived with
+/// `#[derive(LuaRead)]`, whose `#[lua(default)]` fields are optional. When the table can't be
+/// read, the error raised by the call tells which field is missing or has the wrong type.
+/// Calling the function without arguments reads `T` from an empty table.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{NamedArgs, LuaReadStruct, StructReadError, StructReader};
+///
+/// struct Window {
+///     title: String,
+///     width: u32,
+/// }
+///
+/// impl LuaReadStruct for Window {
+///     fn read_struct(reader: &mut StructReader) -> Result<Window, StructReadError> {
+///         Ok(Window {
+///             title: reader.field("title")?,
+///             width: reader.field_or_else("width", || 640)?,
+///         })
+///     }
+/// }
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("window", hlua::function1(|NamedArgs(w): NamedArgs<Window>| {
+///     format!("{} ({})", w.title, w.width)
+/// }));
+///
+/// let desc: String = lua.execute("return window{ title = 'demo' }").unwrap();
+/// assert_eq!(desc, "demo (640)");
+///
+/// let err = lua.execute::<String>("return window{ width = 800 }").unwrap_err().to_string();
+/// assert!(err.contains("cannot read Window: field `title` is missing"), "{}", err);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamedArgs<T>(pub T);
+
+impl<T> Deref for NamedArgs<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for NamedArgs<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<'lua, L, T> LuaRead<L> for NamedArgs<T>
+where
+    L: AsLua<'lua>,
+    T: LuaReadStruct,
+{
+    fn lua_read_at_position(lua: L, index: i32) -> Result<NamedArgs<T>, L> {
+        match read_struct_at(&lua, index) {
+            Ok(value) => Ok(NamedArgs(value)),
+            Err(err) => {
+                set_conversion_error(err.to_string());
+                Err(lua)
+            },
+        }
+    }
+
+    fn lua_read_out_of_bounds(lua: L) -> Result<NamedArgs<T>, L> {
+        let raw_lua = lua.as_lua();
+        unsafe { ffi::lua_newtable(raw_lua.as_ptr()) };
+        let result = read_struct_at(&lua, -1);
+        unsafe { ffi::lua_pop(raw_lua.as_ptr(), 1) };
+        match result {
+            Ok(value) => Ok(NamedArgs(value)),
+            Err(err) => {
+                set_conversion_error(err.to_string());
+                Err(lua)
+            },
+        }
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Reads the global variable `name` as a struct, with an error that tells which field
+    /// couldn't be read.
+    ///
+    /// # Example
+    ///
+    /// Usually, `LuaReadStruct` is implemented with `#[derive(LuaRead)]`.
+    ///
+    /// ```
+    /// use hlua::{LuaReadStruct, StructReadError, StructReader};
+    ///
+    /// #[derive(Debug)]
+    /// struct Config {
+    ///     name: String,
+    ///     retries: u32,
+    /// }
+    ///
+    /// impl LuaReadStruct for Config {
+    ///     fn read_struct(reader: &mut StructReader) -> Result<Config, StructReadError> {
+    ///         Ok(Config { name: reader.field("name")?, retries: reader.field("retries")? })
+    ///     }
+    /// }
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("config = { name = 'server', retries = 'three' }").unwrap();
+    ///
+    /// let err = lua.read_struct::<Config>("config").unwrap_err();
+    /// assert_eq!(
+    ///     err.to_string(),
+    ///     "cannot read Config: field `retries` has the wrong type (expected u32, found string)"
+    /// );
+    /// ```
+    pub fn read_struct<T>(&mut self, name: &str) -> Result<T, StructReadError>
+    where
+        T: LuaReadStruct,
+    {
+        let raw_lua = self.as_mut_lua();
+        let name = std::ffi::CString::new(name).unwrap();
+        unsafe { ffi::lua_getglobal(raw_lua.as_ptr(), name.as_ptr()) };
+        let guard = PushGuard { lua: self, size: 1, raw_lua };
+        read_struct_at(&guard, -1)
+    }
+}
+
+impl<'lua, L> LuaTable<L>
+where
+    L: AsMutLua<'lua>,
+{
+    /// Reads the content of the table as a struct, with an error that tells which field
+    /// couldn't be read.
+    #[inline]
+    pub fn read_struct<T>(&mut self) -> Result<T, StructReadError>
+    where
+        T: LuaReadStruct,
+    {
+        read_struct_at(self, -1)
+    }
+}
+
+unsafe fn lua_type_name(lua: LuaContext, index: i32) -> &'static str {
+    // The names are static strings of the Lua library.
+    let name = ffi::lua_typename(lua.as_ptr(), ffi::lua_type(lua.as_ptr(), index));
+    CStr::from_ptr(name).to_str().unwrap_or("?")
+}
+
+pub(crate) fn short_name<T>() -> &'static str {
+    let name = any::type_name::<T>();
+    let path = name.split('<').next().unwrap_or(name);
+    &name[path.rfind("::").map_or(0, |n| n + 2)..path.len()]
+}
+
+// Removes the module paths from a type name, so that `alloc::string::String` becomes `String`.
+pub(crate) fn short_type_name(name: &str) -> String {
+    let mut short = String::with_capacity(name.len());
+    let mut segment = String::new();
+    for c in name.chars() {
+        match c {
+            ':' => segment.clear(),
+            c if c.is_alphanumeric() || c == '_' => segment.push(c),
+            c => {
+                short.push_str(&segment);
+                segment.clear();
+                short.push(c);
+            },
+        }
+    }
+    short.push_str(&segment);
+    short
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn short_type_names() {
+        assert_eq!(super::short_type_name("u32"), "u32");
+        assert_eq!(
+            super::short_type_name("core::option::Option<alloc::vec::Vec<alloc::string::String>>"),
+            "Option<Vec<String>>"
+        );
+        assert_eq!(super::short_type_name("(i32, &str)"), "(i32, &str)");
+    }
+}
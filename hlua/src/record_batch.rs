@@ -0,0 +1,641 @@
+use std::{convert::TryFrom, error::Error, ffi::CStr, fmt, sync::Arc};
+
+use arrow::{
+    array::{Array, ArrayRef, AsArray},
+    datatypes::{
+        DataType, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Int8Type, UInt16Type,
+        UInt32Type, UInt64Type, UInt8Type,
+    },
+    record_batch::RecordBatch,
+};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsMutLua, LuaContext, Push, PushGuard, PushOne, Void};
+
+type Method = (&'static CStr, ffi::lua_CFunction);
+
+const BATCH_METHODS: [Method; 2] = [(c"column", Some(batch_column)), (c"rows", Some(batch_rows))];
+
+/// Values of a column of a `LuaRecordBatch`. `None` elements and Arrow nulls are seen as `nil` by
+/// scripts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnData {
+    /// Arrow array, accessed by scripts without converting it first. Arrays of numbers, booleans
+    /// and strings are supported, other data types are refused by `LuaRecordBatch::new`.
+    Arrow(ArrayRef),
+    /// Floating-point numbers.
+    Float(Vec<Option<f64>>),
+    /// Integers.
+    Integer(Vec<Option<i64>>),
+    /// Strings.
+    Utf8(Vec<Option<String>>),
+    /// Booleans.
+    Boolean(Vec<Option<bool>>),
+}
+
+impl ColumnData {
+    /// Returns the number of values of the column.
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            ColumnData::Arrow(array) => array.len(),
+            ColumnData::Float(values) => values.len(),
+            ColumnData::Integer(values) => values.len(),
+            ColumnData::Utf8(values) => values.len(),
+            ColumnData::Boolean(values) => values.len(),
+        }
+    }
+
+    /// Returns true if the column doesn't contain any value.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Pushes the value at `row`, or nil if it is null.
+    unsafe fn push_value(&self, lua: *mut ffi::lua_State, row: usize) {
+        match self {
+            ColumnData::Arrow(array) => push_arrow_value(lua, array, row),
+            ColumnData::Float(values) => match values[row] {
+                Some(value) => ffi::lua_pushnumber(lua, value),
+                None => ffi::lua_pushnil(lua),
+            },
+            ColumnData::Integer(values) => match values[row] {
+                Some(value) => ffi::lua_pushinteger(lua, value as ffi::lua_Integer),
+                None => ffi::lua_pushnil(lua),
+            },
+            ColumnData::Utf8(values) => match &values[row] {
+                Some(value) => {
+                    ffi::lua_pushlstring(lua, value.as_ptr().cast(), value.len());
+                },
+                None => ffi::lua_pushnil(lua),
+            },
+            ColumnData::Boolean(values) => match values[row] {
+                Some(value) => ffi::lua_pushboolean(lua, value as libc::c_int),
+                None => ffi::lua_pushnil(lua),
+            },
+        }
+    }
+}
+
+/// Table of data stored column by column, exposed to scripts as a read-only userdata.
+///
+/// Scripts access the data without converting it to Lua tables first, which makes it possible
+/// to process large datasets from Lua. A batch is built from its columns with `new`, or from an
+/// Arrow `RecordBatch` with `TryFrom`, which shares the arrays of the record batch. The batch is
+/// cheap to clone and to push, since the columns are shared. Indices start from 1:
+///
+/// - `#batch` and `batch.num_rows` are the number of rows, `batch.num_columns` the number of
+///   columns, and `batch.column_names` a table containing the names of the columns.
+/// - `batch:column(name)` returns a column, given its name or its index, or nil if it doesn't
+///   exist. `column[row]` returns a value, `#column` the number of values and `column.name` the
+///   name of the column.
+/// - `for index, row in batch:rows() do ... end` iterates over the rows, each row being a table
+///   indexed by the names of the columns.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{ColumnData, LuaRecordBatch};
+///
+/// let batch = LuaRecordBatch::new(vec![
+///     ("city".to_owned(), ColumnData::Utf8(vec![Some("Oslo".into()), Some("Lima".into())])),
+///     ("population".to_owned(), ColumnData::Integer(vec![Some(709_000), Some(10_000_000)])),
+/// ])
+/// .unwrap();
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("cities", &batch);
+/// let code = "local total = 0
+///             for _, row in cities:rows() do total = total + row.population end
+///             return total / #cities";
+/// let average: f64 = lua.execute(code).unwrap();
+/// assert_eq!(average, 5_354_500.0);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct LuaRecordBatch {
+    inner: Arc<Batch>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Batch {
+    num_rows: usize,
+    columns: Vec<(String, ColumnData)>,
+}
+
+// Stored in the userdata of a column.
+struct ColumnRef {
+    batch: Arc<Batch>,
+    index: usize,
+}
+
+// Stored as an upvalue of the iterators returned by `rows`.
+struct RowCursor {
+    batch: Arc<Batch>,
+    next: usize,
+}
+
+/// Error returned by `LuaRecordBatch::new` when the columns are inconsistent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordBatchError {
+    /// A column doesn't have the same number of values as the first one.
+    LengthMismatch {
+        /// Name of the column.
+        column: String,
+        /// Number of values of the first column.
+        expected: usize,
+        /// Number of values of the column.
+        actual: usize,
+    },
+    /// Two columns have the same name.
+    DuplicateColumn(String),
+    /// The Arrow array of a column has a data type that can't be given to scripts.
+    UnsupportedType {
+        /// Name of the column.
+        column: String,
+        /// Data type of the array.
+        data_type: DataType,
+    },
+}
+
+impl fmt::Display for RecordBatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordBatchError::LengthMismatch { column, expected, actual } => {
+                write!(f, "Column {} has {} values instead of {}", column, actual, expected)
+            },
+            RecordBatchError::DuplicateColumn(column) => {
+                write!(f, "Column {} appears more than once", column)
+            },
+            RecordBatchError::UnsupportedType { column, data_type } => {
+                write!(f, "Column {} has the unsupported type {}", column, data_type)
+            },
+        }
+    }
+}
+
+impl Error for RecordBatchError {}
+
+impl LuaRecordBatch {
+    /// Builds a batch from its columns, given as names and values.
+    ///
+    /// All the columns must have the same number of values, and different names.
+    pub fn new(columns: Vec<(String, ColumnData)>) -> Result<LuaRecordBatch, RecordBatchError> {
+        let num_rows = columns.first().map(|(_, data)| data.len()).unwrap_or(0);
+        for (position, (name, data)) in columns.iter().enumerate() {
+            if data.len() != num_rows {
+                return Err(RecordBatchError::LengthMismatch {
+                    column: name.clone(),
+                    expected: num_rows,
+                    actual: data.len(),
+                });
+            }
+            if columns[..position].iter().any(|(other, _)| other == name) {
+                return Err(RecordBatchError::DuplicateColumn(name.clone()));
+            }
+            if let ColumnData::Arrow(array) = data {
+                if !is_supported(array.data_type()) {
+                    return Err(RecordBatchError::UnsupportedType {
+                        column: name.clone(),
+                        data_type: array.data_type().clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(LuaRecordBatch { inner: Arc::new(Batch { num_rows, columns }) })
+    }
+
+    /// Returns the number of rows.
+    #[inline]
+    pub fn num_rows(&self) -> usize {
+        self.inner.num_rows
+    }
+
+    /// Returns the number of columns.
+    #[inline]
+    pub fn num_columns(&self) -> usize {
+        self.inner.columns.len()
+    }
+
+    /// Returns the values of the column `name`.
+    #[inline]
+    pub fn column(&self, name: &str) -> Option<&ColumnData> {
+        self.inner.column_index(name).map(|index| &self.inner.columns[index].1)
+    }
+
+    /// Returns the names of the columns.
+    #[inline]
+    pub fn column_names(&self) -> impl Iterator<Item = &str> {
+        self.inner.columns.iter().map(|(name, _)| name.as_str())
+    }
+}
+
+impl TryFrom<&RecordBatch> for LuaRecordBatch {
+    type Error = RecordBatchError;
+
+    fn try_from(batch: &RecordBatch) -> Result<LuaRecordBatch, RecordBatchError> {
+        let columns = batch
+            .schema()
+            .fields()
+            .iter()
+            .zip(batch.columns())
+            .map(|(field, array)| (field.name().clone(), ColumnData::Arrow(array.clone())))
+            .collect();
+        LuaRecordBatch::new(columns)
+    }
+}
+
+impl TryFrom<RecordBatch> for LuaRecordBatch {
+    type Error = RecordBatchError;
+
+    #[inline]
+    fn try_from(batch: RecordBatch) -> Result<LuaRecordBatch, RecordBatchError> {
+        LuaRecordBatch::try_from(&batch)
+    }
+}
+
+// Returns true if `push_arrow_value` supports the arrays of this type.
+fn is_supported(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Float32
+            | DataType::Float64
+            | DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::UInt8
+            | DataType::UInt16
+            | DataType::UInt32
+            | DataType::UInt64
+            | DataType::Utf8
+            | DataType::LargeUtf8
+            | DataType::Boolean
+    )
+}
+
+// Pushes the value at `row` of an array whose type is supported, or nil if it is null.
+unsafe fn push_arrow_value(lua: *mut ffi::lua_State, array: &ArrayRef, row: usize) {
+    let push_integer = |value: i64| ffi::lua_pushinteger(lua, value as ffi::lua_Integer);
+    let push_string = |value: &str| {
+        ffi::lua_pushlstring(lua, value.as_ptr().cast(), value.len());
+    };
+
+    if array.is_null(row) {
+        ffi::lua_pushnil(lua);
+        return;
+    }
+    match array.data_type() {
+        DataType::Float32 => {
+            ffi::lua_pushnumber(lua, array.as_primitive::<Float32Type>().value(row) as f64)
+        },
+        DataType::Float64 => {
+            ffi::lua_pushnumber(lua, array.as_primitive::<Float64Type>().value(row))
+        },
+        DataType::Int8 => push_integer(array.as_primitive::<Int8Type>().value(row).into()),
+        DataType::Int16 => push_integer(array.as_primitive::<Int16Type>().value(row).into()),
+        DataType::Int32 => push_integer(array.as_primitive::<Int32Type>().value(row).into()),
+        DataType::Int64 => push_integer(array.as_primitive::<Int64Type>().value(row)),
+        DataType::UInt8 => push_integer(array.as_primitive::<UInt8Type>().value(row).into()),
+        DataType::UInt16 => push_integer(array.as_primitive::<UInt16Type>().value(row).into()),
+        DataType::UInt32 => push_integer(array.as_primitive::<UInt32Type>().value(row).into()),
+        // Values that don't fit in an integer are given as floats, like Lua does.
+        DataType::UInt64 => {
+            let value = array.as_primitive::<UInt64Type>().value(row);
+            match i64::try_from(value) {
+                Ok(value) => push_integer(value),
+                Err(_) => ffi::lua_pushnumber(lua, value as f64),
+            }
+        },
+        DataType::Utf8 => push_string(array.as_string::<i32>().value(row)),
+        DataType::LargeUtf8 => push_string(array.as_string::<i64>().value(row)),
+        DataType::Boolean => {
+            ffi::lua_pushboolean(lua, array.as_boolean().value(row) as libc::c_int)
+        },
+        _ => ffi::lua_pushnil(lua),
+    }
+}
+
+impl Batch {
+    #[inline]
+    fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|(column, _)| column == name)
+    }
+}
+
+impl<'lua, L> Push<L> for LuaRecordBatch
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        (&self).push_to_lua(lua)
+    }
+}
+
+impl<'lua, L> PushOne<L> for LuaRecordBatch where L: AsMutLua<'lua> {}
+
+impl<'lua, L> Push<L> for &LuaRecordBatch
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        let guard = push_userdata(self.inner.clone(), lua, |mut metatable| unsafe {
+            let raw_lua = metatable.as_mut_lua().as_ptr();
+            set_read_only_metamethods(raw_lua, batch_index, batch_len);
+        });
+        Ok(guard)
+    }
+}
+
+impl<'lua, L> PushOne<L> for &LuaRecordBatch where L: AsMutLua<'lua> {}
+
+// Fills the metatable at the top of the stack.
+unsafe fn set_read_only_metamethods(
+    lua: *mut ffi::lua_State,
+    index: extern "C" fn(*mut ffi::lua_State) -> libc::c_int,
+    len: extern "C" fn(*mut ffi::lua_State) -> libc::c_int,
+) {
+    ffi::lua_pushcfunction(lua, Some(index));
+    ffi::lua_setfield(lua, -2, c"__index".as_ptr());
+    ffi::lua_pushcfunction(lua, Some(len));
+    ffi::lua_setfield(lua, -2, c"__len".as_ptr());
+    ffi::lua_pushcfunction(lua, Some(read_only));
+    ffi::lua_setfield(lua, -2, c"__newindex".as_ptr());
+}
+
+unsafe fn finish(lua: *mut ffi::lua_State, result: Result<libc::c_int, String>) -> libc::c_int {
+    match result {
+        Ok(count) => count,
+        Err(msg) => {
+            msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+            ffix::lua_error(lua);
+        },
+    }
+}
+
+unsafe fn batch_arg<'a>(lua: *mut ffi::lua_State) -> Result<&'a Arc<Batch>, String> {
+    match userdata_mut::<Arc<Batch>>(LuaContext::new_unchecked(lua), 1) {
+        Some(batch) => Ok(batch),
+        None => Err("bad argument #1 (record batch expected, use the ':' syntax)".to_owned()),
+    }
+}
+
+unsafe fn string_key<'a>(lua: *mut ffi::lua_State, index: libc::c_int) -> Option<&'a str> {
+    match ffi::lua_type(lua, index) == ffi::LUA_TSTRING {
+        true => CStr::from_ptr(ffi::lua_tolstring(lua, index, std::ptr::null_mut())).to_str().ok(),
+        false => None,
+    }
+}
+
+// Reads the 1-based position at `index`, returning `None` if it is out of `0..len`.
+unsafe fn position_arg(lua: *mut ffi::lua_State, index: libc::c_int, len: usize) -> Option<usize> {
+    if ffi::lua_type(lua, index) != ffi::LUA_TNUMBER {
+        return None;
+    }
+    let value = ffi::lua_tonumberx(lua, index, std::ptr::null_mut());
+    match value >= 1.0 && value.fract() == 0.0 && value <= len as f64 {
+        true => Some(value as usize - 1),
+        false => None,
+    }
+}
+
+extern "C" fn batch_index(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let batch = batch_arg(lua)?;
+        match string_key(lua, 2) {
+            Some("num_rows") => ffi::lua_pushinteger(lua, batch.num_rows as ffi::lua_Integer),
+            Some("num_columns") => {
+                ffi::lua_pushinteger(lua, batch.columns.len() as ffi::lua_Integer)
+            },
+            Some("column_names") => {
+                ffi::lua_createtable(lua, batch.columns.len() as _, 0);
+                for (position, (name, _)) in batch.columns.iter().enumerate() {
+                    ffi::lua_pushlstring(lua, name.as_ptr().cast(), name.len());
+                    ffi::lua_rawseti(lua, -2, (position + 1) as _);
+                }
+            },
+            Some(key) => {
+                match BATCH_METHODS.iter().find(|(name, _)| name.to_bytes() == key.as_bytes()) {
+                    Some((_, method)) => ffi::lua_pushcfunction(lua, *method),
+                    None => ffi::lua_pushnil(lua),
+                }
+            },
+            None => ffi::lua_pushnil(lua),
+        }
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn batch_len(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let batch = batch_arg(lua)?;
+        ffi::lua_pushinteger(lua, batch.num_rows as ffi::lua_Integer);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn read_only(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe { finish(lua, Err("record batches are read-only".to_owned())) }
+}
+
+extern "C" fn batch_column(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let batch = batch_arg(lua)?;
+        let index = match string_key(lua, 2) {
+            Some(name) => batch.column_index(name),
+            None => position_arg(lua, 2, batch.columns.len()),
+        };
+        let index = match index {
+            Some(index) => index,
+            None => {
+                ffi::lua_pushnil(lua);
+                return Ok(1);
+            },
+        };
+
+        let column = ColumnRef { batch: batch.clone(), index };
+        push_userdata(column, LuaContext::new_unchecked(lua), |mut metatable| {
+            let raw_lua = metatable.as_mut_lua().as_ptr();
+            set_read_only_metamethods(raw_lua, column_index, column_len);
+        })
+        .forget();
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+unsafe fn column_arg<'a>(lua: *mut ffi::lua_State) -> Result<&'a ColumnRef, String> {
+    match userdata_mut::<ColumnRef>(LuaContext::new_unchecked(lua), 1) {
+        Some(column) => Ok(column),
+        None => Err("bad argument #1 (column expected)".to_owned()),
+    }
+}
+
+extern "C" fn column_index(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let column = column_arg(lua)?;
+        let (name, data) = &column.batch.columns[column.index];
+        if string_key(lua, 2) == Some("name") {
+            ffi::lua_pushlstring(lua, name.as_ptr().cast(), name.len());
+            return Ok(1);
+        }
+        match position_arg(lua, 2, data.len()) {
+            Some(row) => data.push_value(lua, row),
+            None => ffi::lua_pushnil(lua),
+        }
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn column_len(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let column = column_arg(lua)?;
+        ffi::lua_pushinteger(lua, column.batch.num_rows as ffi::lua_Integer);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn batch_rows(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let batch = batch_arg(lua)?;
+        let cursor = RowCursor { batch: batch.clone(), next: 0 };
+        push_userdata(cursor, LuaContext::new_unchecked(lua), |_| {}).forget();
+        ffi::lua_pushcclosure(lua, Some(next_row), 1);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn next_row(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        let cursor = match userdata_mut::<RowCursor>(raw_lua, ffi::lua_upvalueindex(1)) {
+            Some(cursor) => cursor,
+            None => return 0,
+        };
+        if cursor.next >= cursor.batch.num_rows {
+            return 0;
+        }
+
+        let row = cursor.next;
+        cursor.next += 1;
+        ffi::lua_pushinteger(lua, (row + 1) as ffi::lua_Integer);
+        ffi::lua_createtable(lua, 0, cursor.batch.columns.len() as _);
+        for (name, data) in &cursor.batch.columns {
+            ffi::lua_pushlstring(lua, name.as_ptr().cast(), name.len());
+            data.push_value(lua, row);
+            ffi::lua_rawset(lua, -3);
+        }
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ColumnData, Lua, LuaError, LuaRecordBatch, RecordBatchError};
+
+    fn batch() -> LuaRecordBatch {
+        LuaRecordBatch::new(vec![
+            ("id".to_owned(), ColumnData::Integer(vec![Some(1), Some(2), Some(3)])),
+            ("score".to_owned(), ColumnData::Float(vec![Some(0.5), None, Some(2.0)])),
+            ("name".to_owned(), ColumnData::Utf8(vec![Some("a".into()), Some("b".into()), None])),
+            ("ok".to_owned(), ColumnData::Boolean(vec![Some(true), Some(false), None])),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn columns_and_rows() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set("batch", batch());
+
+        let code = "local parts = {}
+                    local function add(...) parts[#parts + 1] = table.concat({ ... }) end
+                    add(#batch, batch.num_columns, table.concat(batch.column_names, ','))
+                    local score = batch:column('score')
+                    add(score.name, #score, tostring(score[2]), tostring(score[3] == 2))
+                    add(batch:column(3)[1], tostring(batch:column('x')))
+                    for i, row in batch:rows() do
+                        add(i, ':', row.id, tostring(row.name), tostring(row.ok))
+                    end
+                    return table.concat(parts, ' ')";
+        let summary: String = lua.execute(code).unwrap();
+        assert_eq!(summary, "34id,score,name,ok score3niltrue anil 1:1atrue 2:2bfalse 3:3nilnil");
+
+        match lua.execute::<()>("batch.num_rows = 5") {
+            Err(LuaError::ExecutionError(msg)) => {
+                assert!(msg.message().contains("read-only"), "{}", msg)
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_batches() {
+        let err = LuaRecordBatch::new(vec![
+            ("a".to_owned(), ColumnData::Integer(vec![Some(1)])),
+            ("b".to_owned(), ColumnData::Boolean(vec![])),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err,
+            RecordBatchError::LengthMismatch { column: "b".to_owned(), expected: 1, actual: 0 }
+        );
+
+        let err = LuaRecordBatch::new(vec![
+            ("a".to_owned(), ColumnData::Integer(vec![])),
+            ("a".to_owned(), ColumnData::Float(vec![])),
+        ])
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Column a appears more than once");
+        assert_eq!(batch().column("score").map(|column| column.len()), Some(3));
+    }
+
+    #[test]
+    fn arrow_batches() {
+        use std::{convert::TryFrom, sync::Arc};
+
+        use arrow::array::{ArrayRef, Date32Array, Float64Array, StringArray, UInt64Array};
+        use arrow::record_batch::RecordBatch;
+
+        let batch = RecordBatch::try_from_iter(vec![
+            ("id", Arc::new(UInt64Array::from(vec![1, u64::MAX])) as ArrayRef),
+            ("score", Arc::new(Float64Array::from(vec![Some(2.5), None])) as ArrayRef),
+            ("name", Arc::new(StringArray::from(vec!["a", "b"])) as ArrayRef),
+        ])
+        .unwrap();
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set("batch", LuaRecordBatch::try_from(&batch).unwrap());
+        let code = "local row = batch:column('id')[2]
+                    return tostring(batch:column('score')[2]) .. batch:column('score')[1]
+                        .. batch:column('name')[2] .. tostring(row > 1e19) .. #batch";
+        assert_eq!(lua.execute::<String>(code).unwrap(), "nil2.5btrue2");
+
+        let dates = RecordBatch::try_from_iter(vec![(
+            "day",
+            Arc::new(Date32Array::from(vec![19000])) as ArrayRef,
+        )])
+        .unwrap();
+        let err = LuaRecordBatch::try_from(dates).unwrap_err();
+        assert_eq!(err.to_string(), "Column day has the unsupported type Date32");
+    }
+}
@@ -0,0 +1,401 @@
+use std::{ffi::CStr, str};
+
+use ::regex::bytes::{Captures, Regex};
+
+use crate::patterns::{start_position, string_arg};
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AsMutLua, Lua, LuaContext, Push};
+
+// Stored in the userdata returned by `regex.new`.
+struct LuaRegex(Regex);
+
+// Stored as an upvalue of the iterators returned by `gmatch`.
+struct GmatchState {
+    // Offset where the next search starts, or `None` once the iteration is over.
+    position: Option<usize>,
+}
+
+type Method = (&'static CStr, ffi::lua_CFunction);
+
+const METHODS: [Method; 6] = [
+    (c"is_match", Some(is_match)),
+    (c"find", Some(find)),
+    (c"match", Some(match_captures)),
+    (c"gmatch", Some(gmatch)),
+    (c"replace", Some(replace)),
+    (c"split", Some(split)),
+];
+
+impl<'lua> Lua<'lua> {
+    /// Opens a `regex` library giving scripts access to the regular expressions of the `regex`
+    /// crate, which match in linear time whatever the pattern is.
+    ///
+    /// `regex.new(pattern)` compiles a pattern, using the syntax of the `regex` crate, and raises
+    /// a Lua error if it is invalid. `regex.escape(text)` escapes the special characters of a
+    /// string. Compiled patterns have the following methods, where `init` is an optional start
+    /// position that works like the one of `string.find`:
+    ///
+    /// - `re:is_match(s [, init])` returns whether `s` contains a match.
+    /// - `re:find(s [, init])` returns the start and end positions of the first match, or nil.
+    /// - `re:match(s [, init])` returns the captures of the first match, or nil.
+    /// - `re:gmatch(s)` returns an iterator over the captures of all the matches.
+    /// - `re:replace(s, replacement [, limit])` replaces the matches, all of them by default.
+    ///   `$1` or `${name}` in `replacement` insert a capture.
+    /// - `re:split(s [, limit])` returns the array of the parts of `s` between the matches.
+    ///
+    /// Captures are returned as tables, where index 0 is the whole match and the following
+    /// indices the groups of the pattern. Named groups can also be accessed by name. Groups that
+    /// didn't participate in the match are `false`.
+    ///
+    /// Lua strings are matched as bytes, so they don't have to be valid UTF-8. The library is
+    /// also added to `package.loaded` if the package library is open.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.open_regex();
+    ///
+    /// let code = "
+    ///     local date = regex.new('(?P<year>\\\\d{4})-(?P<month>\\\\d{2})')
+    ///     local captures = date:match('released 2023-05')
+    ///     return captures.year .. '/' .. captures[2]
+    /// ";
+    /// assert_eq!(lua.execute::<String>(code).unwrap(), "2023/05");
+    /// ```
+    pub fn open_regex(&mut self) {
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_newtable(raw_lua.as_ptr());
+
+            let functions: [Method; 2] = [(c"new", Some(new)), (c"escape", Some(escape))];
+            for (name, function) in functions {
+                ffi::lua_pushcfunction(raw_lua.as_ptr(), function);
+                ffi::lua_setfield(raw_lua.as_ptr(), -2, name.as_ptr());
+            }
+
+            ffi::lua_getfield(raw_lua.as_ptr(), -2, c"package".as_ptr());
+            if ffi::lua_istable(raw_lua.as_ptr(), -1) {
+                ffi::lua_getfield(raw_lua.as_ptr(), -1, c"loaded".as_ptr());
+                if ffi::lua_istable(raw_lua.as_ptr(), -1) {
+                    ffi::lua_pushvalue(raw_lua.as_ptr(), -3);
+                    ffi::lua_setfield(raw_lua.as_ptr(), -2, c"regex".as_ptr());
+                }
+                ffi::lua_pop(raw_lua.as_ptr(), 1);
+            }
+            ffi::lua_pop(raw_lua.as_ptr(), 1);
+
+            ffi::lua_setfield(raw_lua.as_ptr(), -2, c"regex".as_ptr());
+            ffi::lua_pop(raw_lua.as_ptr(), 1);
+        }
+    }
+}
+
+// Returns the number of results of a function, or raises the error.
+unsafe fn finish(lua: *mut ffi::lua_State, result: Result<libc::c_int, String>) -> libc::c_int {
+    match result {
+        Ok(count) => count,
+        Err(msg) => {
+            msg.push_no_err(LuaContext::new_unchecked(lua)).forget();
+            ffix::lua_error(lua);
+        },
+    }
+}
+
+unsafe fn regex_arg<'a>(lua: *mut ffi::lua_State) -> Result<&'a Regex, String> {
+    match userdata_mut::<LuaRegex>(LuaContext::new_unchecked(lua), 1) {
+        Some(regex) => Ok(&regex.0),
+        None => Err("bad argument #1 (regex expected, use the ':' syntax)".to_owned()),
+    }
+}
+
+unsafe fn bytes_arg<'a>(lua: *mut ffi::lua_State, index: libc::c_int) -> Result<&'a [u8], String> {
+    string_arg(lua, index).ok_or_else(|| format!("bad argument #{} (string expected)", index))
+}
+
+// Reads the optional integer argument at `index`.
+unsafe fn integer_arg(lua: *mut ffi::lua_State, index: libc::c_int) -> Option<i64> {
+    match ffi::lua_type(lua, index) {
+        ffi::LUA_TNUMBER => Some(ffi::lua_tonumberx(lua, index, std::ptr::null_mut()) as i64),
+        _ => None,
+    }
+}
+
+// Reads the subject and the optional start position of the methods that search for a match.
+unsafe fn subject_args<'a>(lua: *mut ffi::lua_State) -> Result<(&'a [u8], Option<usize>), String> {
+    let subject = bytes_arg(lua, 2)?;
+    let init = start_position(integer_arg(lua, 3).unwrap_or(1), subject.len());
+    Ok((subject, init))
+}
+
+unsafe fn push_bytes(lua: *mut ffi::lua_State, bytes: &[u8]) {
+    ffi::lua_pushlstring(lua, bytes.as_ptr().cast(), bytes.len());
+}
+
+// Pushes a table containing the captures of a match.
+unsafe fn push_captures(lua: *mut ffi::lua_State, regex: &Regex, captures: &Captures) {
+    ffi::lua_createtable(lua, captures.len() as libc::c_int - 1, 0);
+
+    for (index, name) in regex.capture_names().enumerate() {
+        match captures.get(index) {
+            Some(capture) => push_bytes(lua, capture.as_bytes()),
+            None => ffi::lua_pushboolean(lua, 0),
+        }
+        if let Some(name) = name {
+            ffi::lua_pushvalue(lua, -1);
+            ffi::lua_setfield(lua, -3, format!("{}\0", name).as_ptr().cast());
+        }
+        ffi::lua_rawseti(lua, -2, index as _);
+    }
+}
+
+extern "C" fn new(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let pattern = str::from_utf8(bytes_arg(lua, 1)?)
+            .map_err(|_| "bad argument #1 (pattern isn't valid UTF-8)".to_owned())?;
+        let regex = Regex::new(pattern).map_err(|err| err.to_string())?;
+
+        push_userdata(LuaRegex(regex), LuaContext::new_unchecked(lua), |mut metatable| {
+            let raw_lua = metatable.as_mut_lua().as_ptr();
+            ffi::lua_newtable(raw_lua);
+            for (name, method) in METHODS {
+                ffi::lua_pushcfunction(raw_lua, method);
+                ffi::lua_setfield(raw_lua, -2, name.as_ptr());
+            }
+            ffi::lua_setfield(raw_lua, -2, c"__index".as_ptr());
+
+            ffi::lua_pushcfunction(raw_lua, Some(to_string));
+            ffi::lua_setfield(raw_lua, -2, c"__tostring".as_ptr());
+        })
+        .forget();
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn escape(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let text = String::from_utf8_lossy(bytes_arg(lua, 1)?);
+        push_bytes(lua, ::regex::escape(&text).as_bytes());
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn to_string(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        push_bytes(lua, format!("regex: {}", regex_arg(lua)?.as_str()).as_bytes());
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn is_match(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let regex = regex_arg(lua)?;
+        let (subject, init) = subject_args(lua)?;
+        let found = init.is_some_and(|init| regex.find_at(subject, init).is_some());
+        ffi::lua_pushboolean(lua, found as libc::c_int);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn find(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let regex = regex_arg(lua)?;
+        let (subject, init) = subject_args(lua)?;
+        match init.and_then(|init| regex.find_at(subject, init)) {
+            Some(found) => {
+                ffi::lua_pushinteger(lua, found.start() as ffi::lua_Integer + 1);
+                ffi::lua_pushinteger(lua, found.end() as ffi::lua_Integer);
+                Ok(2)
+            },
+            None => {
+                ffi::lua_pushnil(lua);
+                Ok(1)
+            },
+        }
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn match_captures(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let regex = regex_arg(lua)?;
+        let (subject, init) = subject_args(lua)?;
+        match init.and_then(|init| regex.captures_at(subject, init)) {
+            Some(captures) => push_captures(lua, regex, &captures),
+            None => ffi::lua_pushnil(lua),
+        }
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn gmatch(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        regex_arg(lua)?;
+        bytes_arg(lua, 2)?;
+
+        ffi::lua_pushvalue(lua, 1);
+        ffi::lua_pushvalue(lua, 2);
+        let state = GmatchState { position: Some(0) };
+        push_userdata(state, LuaContext::new_unchecked(lua), |_| {}).forget();
+        ffi::lua_pushcclosure(lua, Some(gmatch_next), 3);
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+// Upvalues are the regex, the subject and the `GmatchState`.
+extern "C" fn gmatch_next(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        let regex = userdata_mut::<LuaRegex>(raw_lua, ffi::lua_upvalueindex(1));
+        let subject = string_arg(lua, ffi::lua_upvalueindex(2));
+        let state = userdata_mut::<GmatchState>(raw_lua, ffi::lua_upvalueindex(3));
+
+        let (regex, subject, state) = match (regex, subject, state) {
+            (Some(regex), Some(subject), Some(state)) => (&regex.0, subject, state),
+            _ => return 0,
+        };
+        let captures = state.position.and_then(|position| regex.captures_at(subject, position));
+
+        match captures {
+            Some(captures) => {
+                let whole = captures.get(0).unwrap();
+                // Empty matches would otherwise be found again at the same position.
+                let next = match whole.is_empty() {
+                    true => whole.end() + 1,
+                    false => whole.end(),
+                };
+                state.position = Some(next).filter(|&next| next <= subject.len());
+                push_captures(lua, regex, &captures);
+            },
+            None => {
+                state.position = None;
+                ffi::lua_pushnil(lua);
+            },
+        }
+        1
+    }
+}
+
+extern "C" fn replace(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let regex = regex_arg(lua)?;
+        let subject = bytes_arg(lua, 2)?;
+        let replacement = bytes_arg(lua, 3)?;
+        let limit = integer_arg(lua, 4).unwrap_or(0).max(0) as usize;
+
+        push_bytes(lua, &regex.replacen(subject, limit, replacement));
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+extern "C" fn split(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe fn inner(lua: *mut ffi::lua_State) -> Result<libc::c_int, String> {
+        let regex = regex_arg(lua)?;
+        let subject = bytes_arg(lua, 2)?;
+
+        ffi::lua_newtable(lua);
+        let parts: Vec<&[u8]> = match integer_arg(lua, 3) {
+            Some(limit) => regex.splitn(subject, limit.max(0) as usize).collect(),
+            None => regex.split(subject).collect(),
+        };
+        for (index, part) in parts.into_iter().enumerate() {
+            push_bytes(lua, part);
+            ffi::lua_rawseti(lua, -2, (index + 1) as _);
+        }
+        Ok(1)
+    }
+
+    unsafe { finish(lua, inner(lua)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Lua;
+
+    fn lua() -> Lua<'static> {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_regex();
+        lua
+    }
+
+    #[test]
+    fn methods() {
+        let mut lua = lua();
+        lua.execute::<()>("words = regex.new('[a-z]+')").unwrap();
+
+        let found: String =
+            lua.execute("return table.concat({ words:find('12 ab cd') }, ' ')").unwrap();
+        assert_eq!(found, "4 5");
+
+        let found: String =
+            lua.execute("return table.concat({ words:find('12 ab cd', -2) }, ' ')").unwrap();
+        assert_eq!(found, "7 8");
+
+        let results: String = lua
+            .execute(
+                "return tostring(words:is_match('ab')) .. tostring(words:is_match('12'))
+                    .. tostring(words:find('12')) .. tostring(words)",
+            )
+            .unwrap();
+        assert_eq!(results, "truefalsenilregex: [a-z]+");
+
+        let replaced: String = lua
+            .execute("return regex.new('(\\\\w+)@(\\\\w+)'):replace('a@b c@d', '$2@$1', 1)")
+            .unwrap();
+        assert_eq!(replaced, "b@a c@d");
+
+        let parts: String =
+            lua.execute("return table.concat(regex.new(',\\\\s*'):split('a, b,c'), '|')").unwrap();
+        assert_eq!(parts, "a|b|c");
+    }
+
+    #[test]
+    fn captures() {
+        let mut lua = lua();
+        let code = "
+            local pair = regex.new('(?P<key>\\\\w+)=(\\\\w+)?')
+            local out = {}
+            for captures in pair:gmatch('a=1 b= c=3') do
+                out[#out + 1] = captures[0] .. ':' .. captures.key .. ':' .. tostring(captures[2])
+            end
+            return table.concat(out, ' ')
+        ";
+        let joined: String = lua.execute(code).unwrap();
+        assert_eq!(joined, "a=1:a:1 b=:b:false c=3:c:3");
+
+        let count: i32 = lua
+            .execute("local n = 0 for _ in regex.new(''):gmatch('abc') do n = n + 1 end return n")
+            .unwrap();
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn errors() {
+        let mut lua = lua();
+        let err = lua.execute::<()>("regex.new('(')").unwrap_err();
+        assert!(err.to_string().contains("unclosed group"), "{}", err);
+
+        let err = lua.execute::<()>("regex.new('a').find('a')").unwrap_err();
+        assert!(err.to_string().contains("regex expected"), "{}", err);
+
+        let same: bool = lua.execute("return require('regex') == regex").unwrap();
+        assert!(same);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::any::{AnyHashableLuaValue, AnyLuaValue};
 
-use crate::{ffix, AsMutLua, LuaRead, Push, PushGuard, PushOne, TuplePushError};
+use crate::{ffix, AsMutLua, LuaRead, Push, PushGuard, PushOne, TuplePushError, Void};
 
 use std::{
     collections::{HashMap, HashSet},
@@ -71,6 +71,53 @@ where
     Ok(PushGuard { lua, size: 1, raw_lua })
 }
 
+/// Pushes an empty table with room for `fields` fields.
+///
+/// Used by the code generated by `#[derive(LuaPush)]`.
+#[doc(hidden)]
+#[inline]
+pub fn push_struct_table<'lua, L>(mut lua: L, fields: usize) -> PushGuard<L>
+where
+    L: AsMutLua<'lua>,
+{
+    let raw_lua = lua.as_mut_lua();
+    unsafe { ffi::lua_createtable(raw_lua.as_ptr(), 0, fields as i32) };
+    PushGuard { lua, size: 1, raw_lua }
+}
+
+/// Sets the field `key` of the table pushed by `push_struct_table`.
+#[doc(hidden)]
+#[inline]
+pub fn set_struct_field<'a, 'lua, L, V>(table: &'a mut PushGuard<L>, key: &str, value: V)
+where
+    L: AsMutLua<'lua>,
+    V: PushOne<&'a mut PushGuard<L>, Err = Void>,
+{
+    let raw_lua = table.raw_lua;
+    unsafe { ffi::lua_pushlstring(raw_lua.as_ptr(), key.as_ptr().cast(), key.len()) };
+    match value.push_to_lua(table) {
+        Ok(pushed) => pushed.assert_one_and_forget(),
+        Err(_) => unreachable!(),
+    };
+    unsafe { ffi::lua_rawset(raw_lua.as_ptr(), -3) };
+}
+
+/// Sets the element `index` of the table pushed by `push_struct_table`.
+#[doc(hidden)]
+#[inline]
+pub fn set_struct_element<'a, 'lua, L, V>(table: &'a mut PushGuard<L>, index: i32, value: V)
+where
+    L: AsMutLua<'lua>,
+    V: PushOne<&'a mut PushGuard<L>, Err = Void>,
+{
+    let raw_lua = table.raw_lua;
+    match value.push_to_lua(table) {
+        Ok(pushed) => pushed.assert_one_and_forget(),
+        Err(_) => unreachable!(),
+    };
+    unsafe { ffi::lua_rawseti(raw_lua.as_ptr(), -2, index as _) };
+}
+
 pub struct IntoIteratorWrapper<I: IntoIterator>(pub I);
 impl<I: IntoIterator> From<I> for IntoIteratorWrapper<I> {
     fn from(iter: I) -> Self {
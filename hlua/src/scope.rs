@@ -0,0 +1,233 @@
+use std::{
+    marker::PhantomData,
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Arc,
+    },
+};
+
+use crate::functions_write::{call_function, push_closure, FunctionExt};
+use crate::userdata_methods::raise;
+use crate::{
+    add_user_data_methods, push_userdata, AsMutLua, Function, InsideCallback, Lua, LuaRead, LuaRef,
+    Push, UserData,
+};
+
+type Invalidate<'scope> = Box<dyn FnOnce() + 'scope>;
+
+impl<'lua> Lua<'lua> {
+    /// Calls `f` with a `Scope` that can give Lua functions and user data that borrow values
+    /// living on the Rust stack, which can't be pushed in the usual way because they aren't
+    /// `'static`.
+    ///
+    /// When `f` returns or panics, the functions and user data created by the scope are
+    /// invalidated: the functions are dropped, and Lua code that still holds one of them gets
+    /// an error when it calls it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, MethodsBuilder, UserData};
+    ///
+    /// struct World {
+    ///     entities: Vec<String>,
+    /// }
+    ///
+    /// impl UserData for World {
+    ///     fn add_methods(methods: &mut MethodsBuilder<Self>) {
+    ///         methods.add_method_mut("spawn", |this, name: String| this.entities.push(name));
+    ///     }
+    /// }
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.openlibs();
+    /// let mut world = World { entities: vec![] };
+    /// let mut frames = 0;
+    ///
+    /// lua.scope(|scope| {
+    ///     let world = scope.userdata(&mut world);
+    ///     let tick = scope.function(hlua::function0(|| frames += 1));
+    ///     scope.lua().set("world", &world);
+    ///     scope.lua().set("tick", &tick);
+    ///     scope.lua().execute::<()>("world:spawn('goblin') tick() tick()").unwrap();
+    /// });
+    ///
+    /// assert_eq!(world.entities, ["goblin"]);
+    /// assert_eq!(frames, 2);
+    /// assert!(lua.execute::<()>("tick()").is_err());
+    /// ```
+    pub fn scope<'scope, F, R>(&'scope mut self, f: F) -> R
+    where
+        F: FnOnce(&mut Scope<'scope, 'lua>) -> R,
+    {
+        let mut scope = Scope { lua: self, invalidate: Vec::new(), marker: PhantomData };
+        f(&mut scope)
+    }
+}
+
+/// Creates Lua values that borrow data for the duration of `Lua::scope`.
+///
+/// The values are returned as `LuaRef`s, which can be stored in Lua variables with the context
+/// given by `lua`.
+pub struct Scope<'scope, 'lua> {
+    lua: &'scope mut Lua<'lua>,
+    // Called when the scope ends, in the order in which the values were created.
+    invalidate: Vec<Invalidate<'scope>>,
+    // Makes `'scope` invariant, so that the scope can't be given values that live less long.
+    marker: PhantomData<fn(&'scope ()) -> &'scope ()>,
+}
+
+impl<'scope, 'lua> Scope<'scope, 'lua> {
+    /// Returns the Lua context, to set variables and execute code inside the scope.
+    #[inline]
+    pub fn lua(&mut self) -> &mut Lua<'lua> {
+        self.lua
+    }
+
+    /// Creates a Lua function from a `Function`, such as one returned by `function1`, whose
+    /// closure borrows values that live as long as the scope.
+    pub fn function<Z, P, R>(&mut self, function: Function<Z, P, R>) -> LuaRef
+    where
+        Function<Z, P, R>: FunctionExt<P, Output = R> + 'scope,
+        P: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+        R: for<'p> Push<&'p mut InsideCallback> + 'static,
+    {
+        let data = Box::into_raw(Box::new(function));
+        let pointer = Arc::new(AtomicPtr::new(data));
+
+        let lua = self.lua.as_mut_lua();
+        unsafe {
+            push_closure(lua, pointer.clone(), scoped_wrapper::<Function<Z, P, R>, P, R>);
+        }
+        let reference = LuaRef::lua_read_at_position(&mut *self.lua, -1).ok().unwrap();
+        unsafe { ffi::lua_pop(lua.as_ptr(), 1) };
+
+        self.invalidate.push(Box::new(move || {
+            pointer.store(ptr::null_mut(), Ordering::Release);
+            drop(unsafe { Box::from_raw(data) });
+        }));
+        reference
+    }
+
+    /// Creates a user data that refers to `value`, with the methods of its type.
+    ///
+    /// The methods added with `add_method`, `add_method_mut` and `add_meta_method` work on it
+    /// as on a user data that holds the value, but callbacks and `add_meta_function` can't read
+    /// it as a `&T` parameter.
+    pub fn userdata<T>(&mut self, value: &'scope mut T) -> LuaRef
+    where
+        T: UserData,
+    {
+        let pointer = Arc::new(AtomicPtr::new(value as *mut T));
+        let guard =
+            push_userdata(ScopedRef(pointer.clone()), &mut *self.lua, add_user_data_methods::<T>);
+        let reference = LuaRef::lua_read_at_position(guard, -1).ok().unwrap();
+
+        self.invalidate.push(Box::new(move || pointer.store(ptr::null_mut(), Ordering::Release)));
+        reference
+    }
+
+    /// Registers `f` to be called when the scope ends.
+    #[inline]
+    pub(crate) fn on_end(&mut self, f: impl FnOnce() + 'scope) {
+        self.invalidate.push(Box::new(f));
+    }
+}
+
+impl Drop for Scope<'_, '_> {
+    fn drop(&mut self) {
+        for invalidate in self.invalidate.drain(..) {
+            invalidate();
+        }
+    }
+}
+
+/// User data created by `Scope::userdata`, pointing to the value until the scope ends.
+pub(crate) struct ScopedRef<T>(Arc<AtomicPtr<T>>);
+
+impl<T> ScopedRef<T> {
+    /// Returns the pointer to the value, or a null pointer if the scope ended.
+    #[inline]
+    pub(crate) fn get(&self) -> *mut T {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+// Called when Lua calls a function created by `Scope::function`.
+extern "C" fn scoped_wrapper<T, P, R>(lua: *mut ffi::lua_State) -> libc::c_int
+where
+    T: FunctionExt<P, Output = R>,
+    P: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+    R: for<'p> Push<&'p mut InsideCallback>,
+{
+    let pointer = unsafe { ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) };
+    let data = unsafe { &*pointer.cast::<Arc<AtomicPtr<T>>>() }.load(Ordering::Acquire);
+    if data.is_null() {
+        let lua = unsafe { ptr::NonNull::new_unchecked(lua) };
+        raise(lua, "function called after the end of its scope".to_owned());
+    }
+    unsafe { call_function::<T, P, R>(lua, data) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{function1, Lua, MethodsBuilder, UserData};
+
+    struct Player {
+        health: i32,
+    }
+
+    impl UserData for Player {
+        fn add_methods(methods: &mut MethodsBuilder<Self>) {
+            methods.add_method("health", |this, ()| this.health);
+            methods.add_method_mut("hit", |this, damage: i32| this.health -= damage);
+        }
+    }
+
+    #[test]
+    fn scoped_values_are_invalidated() {
+        let mut lua = Lua::new();
+        let mut player = Player { health: 10 };
+        let mut log = Vec::new();
+
+        let health: i32 = lua.scope(|scope| {
+            let player = scope.userdata(&mut player);
+            let log = scope.function(function1(|line: String| log.push(line)));
+            scope.lua().set("player", &player);
+            scope.lua().set("log", &log);
+            scope.lua().execute("player:hit(3) log('hit') return player:health()").unwrap()
+        });
+
+        assert_eq!(health, 7);
+        assert_eq!(player.health, 7);
+        assert_eq!(log, ["hit"]);
+
+        let err = lua.execute::<()>("player:hit(1)").unwrap_err().to_string();
+        assert!(
+            err.contains("method 'hit' of Player called after the end of its scope"),
+            "{}",
+            err
+        );
+        let err = lua.execute::<()>("log('again')").unwrap_err().to_string();
+        assert!(err.contains("function called after the end of its scope"), "{}", err);
+        assert_eq!(player.health, 7);
+    }
+
+    #[test]
+    fn invalidated_on_panic() {
+        let mut lua = Lua::new();
+        let mut count = 0;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lua.scope(|scope| {
+                let add = scope.function(function1(|n: i32| count += n));
+                scope.lua().set("add", &add);
+                scope.lua().execute::<()>("add(2)").unwrap();
+                panic!("scope panicked");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(count, 2);
+        assert!(lua.execute::<()>("add(1)").is_err());
+    }
+}
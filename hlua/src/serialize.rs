@@ -0,0 +1,737 @@
+//! Conversions between types implementing the serde traits and `AnyLuaValue`.
+//!
+//! Structs and maps become tables with string keys, sequences and tuples become tables with the
+//! keys `1..=n`, and `None` and `()` become nil. Enums are represented like serde_json does: a
+//! unit variant is the name of the variant, and the other variants are tables with the name of
+//! the variant as only key.
+
+use std::{error::Error, fmt};
+
+use serde::de::{
+    self, value::MapDeserializer, value::SeqDeserializer, DeserializeOwned, Deserializer,
+    EnumAccess, IntoDeserializer, VariantAccess, Visitor,
+};
+use serde::ser::{self, Serialize, Serializer};
+
+use crate::{AnyLuaString, AnyLuaValue};
+
+/// Error that can happen when converting between a Rust type and a Lua value with `to_lua` or
+/// `from_lua`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerdeError(String);
+
+impl fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for SerdeError {}
+
+impl ser::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> SerdeError {
+        SerdeError(msg.to_string())
+    }
+}
+
+impl de::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> SerdeError {
+        SerdeError(msg.to_string())
+    }
+}
+
+/// Converts a value into an `AnyLuaValue`, which can then be pushed.
+///
+/// Integers that don't fit in an `i32` are converted to floats, so integers above 2<sup>53</sup>
+/// lose precision.
+///
+/// # Example
+///
+/// ```
+/// #[derive(serde::Serialize)]
+/// struct Config {
+///     name: String,
+///     retries: u32,
+/// }
+///
+/// let mut lua = hlua::Lua::new();
+/// let config = Config { name: "worker".to_owned(), retries: 3 };
+/// lua.set("config", hlua::to_lua(&config).unwrap());
+///
+/// let retries: u32 = lua.execute("return config.retries").unwrap();
+/// assert_eq!(retries, 3);
+/// ```
+pub fn to_lua<T>(value: &T) -> Result<AnyLuaValue, SerdeError>
+where
+    T: Serialize + ?Sized,
+{
+    value.serialize(ValueSerializer)
+}
+
+/// Converts an `AnyLuaValue`, usually read from Lua, into a value.
+///
+/// # Example
+///
+/// ```
+/// #[derive(serde::Deserialize, Debug, PartialEq)]
+/// struct Config {
+///     name: String,
+///     ports: Vec<u16>,
+///     debug: Option<bool>,
+/// }
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.execute::<()>("config = { name = 'worker', ports = { 80, 443 } }").unwrap();
+///
+/// let config: Config = hlua::from_lua(lua.get("config").unwrap()).unwrap();
+/// assert_eq!(config, Config { name: "worker".to_owned(), ports: vec![80, 443], debug: None });
+/// ```
+pub fn from_lua<T>(value: AnyLuaValue) -> Result<T, SerdeError>
+where
+    T: DeserializeOwned,
+{
+    T::deserialize(value)
+}
+
+fn integer(value: i64) -> AnyLuaValue {
+    match i32::try_from(value) {
+        Ok(value) => AnyLuaValue::LuaInteger(value),
+        Err(_) => AnyLuaValue::LuaNumber(value as f64),
+    }
+}
+
+// Wraps `value` in a table whose only key is the name of the variant.
+fn variant(name: &'static str, value: AnyLuaValue) -> AnyLuaValue {
+    AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaString(name.to_owned()), value)])
+}
+
+struct ValueSerializer;
+
+impl Serializer for ValueSerializer {
+    type Ok = AnyLuaValue;
+    type Error = SerdeError;
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = SeqSerializer;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = MapSerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<AnyLuaValue, SerdeError> {
+        Ok(AnyLuaValue::LuaBoolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<AnyLuaValue, SerdeError> {
+        Ok(integer(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<AnyLuaValue, SerdeError> {
+        Ok(integer(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<AnyLuaValue, SerdeError> {
+        Ok(integer(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<AnyLuaValue, SerdeError> {
+        Ok(integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<AnyLuaValue, SerdeError> {
+        Ok(integer(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<AnyLuaValue, SerdeError> {
+        Ok(integer(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<AnyLuaValue, SerdeError> {
+        Ok(integer(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<AnyLuaValue, SerdeError> {
+        match i64::try_from(v) {
+            Ok(v) => Ok(integer(v)),
+            Err(_) => Ok(AnyLuaValue::LuaNumber(v as f64)),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<AnyLuaValue, SerdeError> {
+        Ok(AnyLuaValue::LuaNumber(v.into()))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<AnyLuaValue, SerdeError> {
+        Ok(AnyLuaValue::LuaNumber(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<AnyLuaValue, SerdeError> {
+        Ok(AnyLuaValue::LuaString(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<AnyLuaValue, SerdeError> {
+        Ok(AnyLuaValue::LuaString(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<AnyLuaValue, SerdeError> {
+        Ok(AnyLuaValue::LuaAnyString(AnyLuaString(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<AnyLuaValue, SerdeError> {
+        Ok(AnyLuaValue::LuaNil)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<AnyLuaValue, SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<AnyLuaValue, SerdeError> {
+        Ok(AnyLuaValue::LuaNil)
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<AnyLuaValue, SerdeError> {
+        Ok(AnyLuaValue::LuaNil)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<AnyLuaValue, SerdeError> {
+        Ok(AnyLuaValue::LuaString(variant.to_owned()))
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<AnyLuaValue, SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _: &'static str,
+        _: u32,
+        name: &'static str,
+        value: &T,
+    ) -> Result<AnyLuaValue, SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        Ok(variant(name, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, SerdeError> {
+        Ok(SeqSerializer { values: Vec::with_capacity(len.unwrap_or(0)), variant: None })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer, SerdeError> {
+        Ok(SeqSerializer { values: Vec::with_capacity(len), variant: Some(variant) })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<MapSerializer, SerdeError> {
+        let entries = Vec::with_capacity(len.unwrap_or(0));
+        Ok(MapSerializer { entries, key: None, variant: None })
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<MapSerializer, SerdeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<MapSerializer, SerdeError> {
+        let entries = Vec::with_capacity(len);
+        Ok(MapSerializer { entries, key: None, variant: Some(variant) })
+    }
+}
+
+struct SeqSerializer {
+    values: Vec<AnyLuaValue>,
+    variant: Option<&'static str>,
+}
+
+impl SeqSerializer {
+    fn push<T>(&mut self, value: &T) -> Result<(), SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.values.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<AnyLuaValue, SerdeError> {
+        let table =
+            AnyLuaValue::LuaArray((1..).map(AnyLuaValue::LuaInteger).zip(self.values).collect());
+        Ok(match self.variant {
+            Some(name) => variant(name, table),
+            None => table,
+        })
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = AnyLuaValue;
+    type Error = SerdeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<AnyLuaValue, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = AnyLuaValue;
+    type Error = SerdeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<AnyLuaValue, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = AnyLuaValue;
+    type Error = SerdeError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<AnyLuaValue, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqSerializer {
+    type Ok = AnyLuaValue;
+    type Error = SerdeError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<AnyLuaValue, SerdeError> {
+        self.finish()
+    }
+}
+
+struct MapSerializer {
+    entries: Vec<(AnyLuaValue, AnyLuaValue)>,
+    key: Option<AnyLuaValue>,
+    variant: Option<&'static str>,
+}
+
+impl MapSerializer {
+    fn insert(&mut self, key: AnyLuaValue, value: AnyLuaValue) -> Result<(), SerdeError> {
+        match key {
+            AnyLuaValue::LuaNil => Err(SerdeError("tables can't have nil keys".to_owned())),
+            // Lua tables can't hold nil values, the key is absent instead.
+            _ if value == AnyLuaValue::LuaNil => Ok(()),
+            key => {
+                self.entries.push((key, value));
+                Ok(())
+            },
+        }
+    }
+
+    fn finish(self) -> Result<AnyLuaValue, SerdeError> {
+        let table = AnyLuaValue::LuaArray(self.entries);
+        Ok(match self.variant {
+            Some(name) => variant(name, table),
+            None => table,
+        })
+    }
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = AnyLuaValue;
+    type Error = SerdeError;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.key = Some(key.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let key = self.key.take().expect("serialize_value is called after serialize_key");
+        self.insert(key, value.serialize(ValueSerializer)?)
+    }
+
+    fn end(self) -> Result<AnyLuaValue, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = AnyLuaValue;
+    type Error = SerdeError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.insert(AnyLuaValue::LuaString(key.to_owned()), value.serialize(ValueSerializer)?)
+    }
+
+    fn end(self) -> Result<AnyLuaValue, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for MapSerializer {
+    type Ok = AnyLuaValue;
+    type Error = SerdeError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.insert(AnyLuaValue::LuaString(key.to_owned()), value.serialize(ValueSerializer)?)
+    }
+
+    fn end(self) -> Result<AnyLuaValue, SerdeError> {
+        self.finish()
+    }
+}
+
+// If all the keys of a table are positive integers, returns its values in order. The missing
+// keys are considered nil, since Lua sequences can't contain nil.
+fn sequence(
+    content: Vec<(AnyLuaValue, AnyLuaValue)>,
+) -> Result<Vec<AnyLuaValue>, Vec<(AnyLuaValue, AnyLuaValue)>> {
+    let mut keys = Vec::with_capacity(content.len());
+    for (key, _) in &content {
+        match key.as_integral() {
+            Some(k) if k >= 1 && (k as usize) < 16 * content.len() + 16 => {
+                keys.push(k as usize - 1)
+            },
+            _ => return Err(content),
+        }
+    }
+
+    let mut values = vec![AnyLuaValue::LuaNil; keys.iter().max().map_or(0, |&max| max + 1)];
+    for (key, (_, value)) in keys.into_iter().zip(content) {
+        values[key] = value;
+    }
+    Ok(values)
+}
+
+fn unexpected(value: &AnyLuaValue, expected: &str) -> SerdeError {
+    SerdeError(format!("expected {}, found {}", expected, value.type_name()))
+}
+
+impl<'de> IntoDeserializer<'de, SerdeError> for AnyLuaValue {
+    type Deserializer = AnyLuaValue;
+
+    fn into_deserializer(self) -> AnyLuaValue {
+        self
+    }
+}
+
+impl<'de> Deserializer<'de> for AnyLuaValue {
+    type Error = SerdeError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            AnyLuaValue::LuaNil => visitor.visit_unit(),
+            AnyLuaValue::LuaBoolean(v) => visitor.visit_bool(v),
+            AnyLuaValue::LuaInteger(v) => visitor.visit_i32(v),
+            ref number @ AnyLuaValue::LuaNumber(v) => match number.as_integral() {
+                Some(v) => visitor.visit_i64(v),
+                None => visitor.visit_f64(v),
+            },
+            AnyLuaValue::LuaString(v) => visitor.visit_string(v),
+            AnyLuaValue::LuaAnyString(AnyLuaString(v)) => visitor.visit_byte_buf(v),
+            AnyLuaValue::LuaArray(content) if content.is_empty() => {
+                visitor.visit_map(MapDeserializer::new(content.into_iter()))
+            },
+            AnyLuaValue::LuaArray(content) => match sequence(content) {
+                Ok(values) => visitor.visit_seq(SeqDeserializer::new(values.into_iter())),
+                Err(content) => visitor.visit_map(MapDeserializer::new(content.into_iter())),
+            },
+            AnyLuaValue::LuaOther => Err(unexpected(&self, "a value representable in Rust")),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            AnyLuaValue::LuaNil => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        let content = match self {
+            AnyLuaValue::LuaArray(content) => content,
+            value => return Err(unexpected(&value, "a table")),
+        };
+        match sequence(content) {
+            Ok(values) => visitor.visit_seq(SeqDeserializer::new(values.into_iter())),
+            Err(_) => Err(SerdeError("expected a table with integer keys".to_owned())),
+        }
+    }
+
+    fn deserialize_tuple<V>(self, _: usize, visitor: V) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _: &'static str,
+        _: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            AnyLuaValue::LuaArray(content) => {
+                visitor.visit_map(MapDeserializer::new(content.into_iter()))
+            },
+            value => Err(unexpected(&value, "a table")),
+        }
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            AnyLuaValue::LuaString(name) => visitor.visit_enum(name.into_deserializer()),
+            AnyLuaValue::LuaArray(mut content) if content.len() == 1 => {
+                let (name, value) = content.pop().expect("the table has one entry");
+                visitor.visit_enum(Variant { name, value })
+            },
+            value => Err(unexpected(&value, "a string or a table with one key")),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct identifier ignored_any
+    }
+}
+
+// Variant of an enum represented as a table with the name of the variant as only key.
+struct Variant {
+    name: AnyLuaValue,
+    value: AnyLuaValue,
+}
+
+impl<'de> EnumAccess<'de> for Variant {
+    type Error = SerdeError;
+    type Variant = AnyLuaValue;
+
+    fn variant_seed<S>(self, seed: S) -> Result<(S::Value, AnyLuaValue), SerdeError>
+    where
+        S: de::DeserializeSeed<'de>,
+    {
+        Ok((seed.deserialize(self.name)?, self.value))
+    }
+}
+
+impl<'de> VariantAccess<'de> for AnyLuaValue {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        match self {
+            AnyLuaValue::LuaNil => Ok(()),
+            value => Err(unexpected(&value, "nil")),
+        }
+    }
+
+    fn newtype_variant_seed<S>(self, seed: S) -> Result<S::Value, SerdeError>
+    where
+        S: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V>(self, _: usize, visitor: V) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn struct_variant<V>(
+        self,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_map(visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::{from_lua, to_lua, AnyLuaValue, Lua};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Point(i32, i32),
+        Rect { width: u32, height: u32 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Scene {
+        name: String,
+        shapes: Vec<Shape>,
+        tags: BTreeMap<String, bool>,
+        parent: Option<Box<Scene>>,
+        weights: Vec<Option<u8>>,
+        limits: (i64, f32),
+    }
+
+    #[test]
+    fn round_trip_through_lua() {
+        let scene = Scene {
+            name: "main".to_owned(),
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle(1.5),
+                Shape::Point(-3, 4),
+                Shape::Rect { width: 2, height: 5 },
+            ],
+            tags: [("visible".to_owned(), true)].into_iter().collect(),
+            parent: None,
+            weights: vec![Some(1), None, Some(3)],
+            limits: (-10_000_000_000, 0.5),
+        };
+
+        let mut lua = Lua::new();
+        lua.set("scene", to_lua(&scene).unwrap());
+        let code =
+            "return scene.shapes[1] .. scene.shapes[4].Rect.height .. scene.shapes[3].Point[1]";
+        assert_eq!(lua.execute::<String>(code).unwrap(), "Empty5-3");
+        assert!(lua.execute::<bool>("return scene.parent == nil").unwrap());
+
+        let read: AnyLuaValue = lua.get("scene").unwrap();
+        assert_eq!(from_lua::<Scene>(read).unwrap(), scene);
+    }
+
+    #[test]
+    fn errors() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("scene = { name = 'x', shapes = { 'Hexagon' } }").unwrap();
+        let read: AnyLuaValue = lua.get("scene").unwrap();
+        let error = from_lua::<Scene>(read).unwrap_err().to_string();
+        assert!(error.contains("Hexagon"), "{}", error);
+
+        assert!(from_lua::<u8>(AnyLuaValue::LuaNumber(1.5)).is_err());
+        assert!(from_lua::<Vec<u8>>(AnyLuaValue::LuaString("a".to_owned())).is_err());
+        let map: BTreeMap<Option<u8>, u8> = [(None, 1)].into_iter().collect();
+        assert!(to_lua(&map).is_err());
+    }
+}
@@ -0,0 +1,414 @@
+use std::borrow::Cow;
+
+use crate::lua_functions;
+use crate::{
+    ffix, AsMutLua, Lua, LuaError, LuaFunction, LuaRead, LuaRef, PrettyFormat, Push, PushGuard,
+};
+
+/// Environment shared by successive pieces of code, to implement consoles and notebooks that
+/// behave like the interactive Lua interpreter.
+///
+/// Each call to `execute` compiles a new chunk, but all of them run with the same environment: a
+/// table owned by the session, whose missing entries are looked up in the global table. The
+/// global variables assigned by the code are stored in the session, and don't affect the global
+/// table or the other sessions.
+///
+/// Like the interactive interpreter, a line that is an expression is evaluated and its value
+/// returned, without needing a `return`.
+///
+/// Local variables only live as long as the chunk that declares them. `persistent_locals` makes
+/// the session rewrite the `local` statements at the top level of the code into assignments to
+/// the environment, so that they remain visible to the next calls.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, Session};
+///
+/// let mut lua = Lua::new();
+/// let session = Session::new(&mut lua).persistent_locals(true);
+///
+/// session.execute::<()>(&mut lua, "x = 1").unwrap();
+/// session.execute::<()>(&mut lua, "local y = x + 1").unwrap();
+/// assert_eq!(session.execute::<i32>(&mut lua, "x + y").unwrap(), 3);
+/// assert_eq!(lua.get::<i32, _>("x"), None);
+/// ```
+#[derive(Debug)]
+pub struct Session {
+    env: LuaRef,
+    persistent_locals: bool,
+    format: PrettyFormat,
+}
+
+impl Session {
+    /// Creates a session with an empty environment, falling back to the global table of `lua`.
+    pub fn new(lua: &mut Lua) -> Session {
+        let env = unsafe {
+            let raw_lua = lua.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            ffi::lua_newtable(l);
+            ffi::lua_newtable(l);
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_setfield(l, -2, c"__index".as_ptr());
+            ffi::lua_setmetatable(l, -2);
+
+            match LuaRef::lua_read(PushGuard { lua: &mut *lua, size: 1, raw_lua }) {
+                Ok(env) => env,
+                Err(_) => unreachable!("reading a LuaRef never fails"),
+            }
+        };
+
+        Session { env, persistent_locals: false, format: PrettyFormat::new() }
+    }
+
+    /// Sets whether the variables declared with `local` at the top level of the code are kept
+    /// in the environment. Disabled by default.
+    ///
+    /// Locals declared inside blocks and functions are unaffected, as are the Lua 5.4 locals
+    /// with an attribute such as `<close>`.
+    #[inline]
+    pub fn persistent_locals(mut self, enabled: bool) -> Session {
+        self.persistent_locals = enabled;
+        self
+    }
+
+    /// Sets the format of the values rendered by `eval`. `PrettyFormat::new()` by default.
+    #[inline]
+    pub fn pretty_format(mut self, format: PrettyFormat) -> Session {
+        self.format = format;
+        self
+    }
+
+    /// Returns the table holding the variables of the session.
+    #[inline]
+    pub fn env(&self) -> &LuaRef {
+        &self.env
+    }
+
+    /// Executes some Lua code in the environment of the session.
+    ///
+    /// If the code is an expression, its value is returned. Otherwise this does the same thing
+    /// as `Lua::execute`.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `lua` isn't the context the session was created with.
+    pub fn execute<'a, 'lua, T>(&self, lua: &'a mut Lua<'lua>, code: &str) -> Result<T, LuaError>
+    where
+        T: for<'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'lua>>>>,
+    {
+        self.load(lua, code)?.call()
+    }
+
+    /// Executes some Lua code like `execute`, and renders the values it returns for a console.
+    ///
+    /// The values are rendered with the `PrettyFormat` of the session and separated by tabs,
+    /// like the interactive interpreter prints them. The first one is stored in the variable
+    /// `_` of the session, so that the next lines can refer to the last result.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, Session};
+    ///
+    /// let mut lua = Lua::new();
+    /// let session = Session::new(&mut lua);
+    ///
+    /// let text = session.eval(&mut lua, "{ 1, 2, name = 'ada' }").unwrap();
+    /// assert_eq!(text, r#"{ 1, 2, name = "ada" }"#);
+    /// assert_eq!(session.eval(&mut lua, "_.name, #_").unwrap(), "\"ada\"\t2");
+    /// assert_eq!(session.eval(&mut lua, "x = 1").unwrap(), "");
+    /// ```
+    ///
+    /// # Panic
+    ///
+    /// Panics if `lua` isn't the context the session was created with.
+    pub fn eval(&self, lua: &mut Lua, code: &str) -> Result<String, LuaError> {
+        let function: LuaRef = match LuaRead::lua_read(self.load(&mut *lua, code)?) {
+            Ok(function) => function,
+            Err(_) => unreachable!("reading a LuaRef never fails"),
+        };
+
+        unsafe {
+            let raw_lua = lua.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            let base = ffi::lua_gettop(l);
+            (&function).push_no_err(&mut *lua).forget();
+            match lua_functions::pcall(raw_lua, 0, ffi::LUA_MULTRET) {
+                0 => {},
+                ffi::LUA_ERRMEM => {
+                    ffi::lua_pop(l, 1);
+                    return Err(LuaError::MemoryLimitExceeded);
+                },
+                _ => {
+                    let error = PushGuard { lua: &mut *lua, size: 1, raw_lua };
+                    return Err(lua_functions::read_error(error));
+                },
+            }
+
+            let top = ffi::lua_gettop(l);
+            let values: Vec<_> =
+                (base + 1..=top).map(|i| self.format.format_at(raw_lua, i)).collect();
+            if top > base {
+                (&self.env).push_no_err(&mut *lua).forget();
+                ffi::lua_pushvalue(l, base + 1);
+                ffi::lua_setfield(l, -2, c"_".as_ptr());
+            }
+            ffi::lua_settop(l, base);
+            Ok(values.join("\t"))
+        }
+    }
+
+    // Compiles `code` as a function running in the environment of the session.
+    fn load<'a, 'lua>(
+        &self,
+        lua: &'a mut Lua<'lua>,
+        code: &str,
+    ) -> Result<LuaFunction<PushGuard<&'a mut Lua<'lua>>>, LuaError> {
+        let code = match self.persistent_locals {
+            true => hoist_locals(code),
+            false => Cow::Borrowed(code),
+        };
+
+        let expression = format!("return {}", code);
+        let code = match LuaFunction::load_named(&mut *lua, "=session", &expression) {
+            Ok(_) => &expression[..],
+            Err(_) => &code[..],
+        };
+
+        let mut f = LuaFunction::load_named(lua, "=session", code)?;
+        unsafe {
+            (&self.env).push_no_err(&mut f).forget();
+            let l = f.as_mut_lua().as_ptr();
+            match () {
+                #[cfg(feature = "_luaapi_51")]
+                () => {
+                    ffi::lua_setfenv(l, -2);
+                },
+                #[cfg(not(feature = "_luaapi_51"))]
+                () => {
+                    // The only upvalue of a chunk is `_ENV`.
+                    ffi::lua_setupvalue(l, -2, 1);
+                },
+            }
+        }
+        Ok(f)
+    }
+}
+
+// Token of Lua code, as far as needed to find the `local` statements at the top level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'a> {
+    Name(&'a str),
+    Symbol(&'a str),
+    Other,
+}
+
+// Splits `code` into tokens with their byte ranges, skipping whitespace and comments.
+fn tokenize(code: &str) -> Vec<(Token<'_>, usize, usize)> {
+    let bytes = code.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let start = pos;
+        let c = bytes[pos];
+
+        if c.is_ascii_whitespace() {
+            pos += 1;
+        } else if code[pos..].starts_with("--") {
+            pos = match long_bracket(bytes, pos + 2) {
+                Some(level) => skip_long_bracket(code, pos + 2, level),
+                None => code[pos..].find('\n').map_or(bytes.len(), |n| pos + n),
+            };
+        } else if let Some(level) = long_bracket(bytes, pos) {
+            pos = skip_long_bracket(code, pos, level);
+            tokens.push((Token::Other, start, pos));
+        } else if c == b'"' || c == b'\'' {
+            pos += 1;
+            while pos < bytes.len() && bytes[pos] != c && bytes[pos] != b'\n' {
+                pos += if bytes[pos] == b'\\' { 2 } else { 1 };
+            }
+            pos = (pos + 1).min(bytes.len());
+            tokens.push((Token::Other, start, pos));
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            while pos < bytes.len() && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_') {
+                pos += 1;
+            }
+            tokens.push((Token::Name(&code[start..pos]), start, pos));
+        } else if c.is_ascii_digit() {
+            pos += 1;
+            while pos < bytes.len() {
+                let exponent = matches!(bytes[pos - 1], b'e' | b'E' | b'p' | b'P');
+                match bytes[pos] {
+                    b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'_' | b'.' => pos += 1,
+                    b'+' | b'-' if exponent => pos += 1,
+                    _ => break,
+                }
+            }
+            tokens.push((Token::Other, start, pos));
+        } else {
+            let two = ["==", "~=", "<=", ">=", "..", "::", "//", "<<", ">>"];
+            pos += match two.iter().any(|op| code[pos..].starts_with(op)) {
+                true => 2,
+                false => code[pos..].chars().next().map_or(1, char::len_utf8),
+            };
+            tokens.push((Token::Symbol(&code[start..pos]), start, pos));
+        }
+    }
+
+    tokens
+}
+
+// Returns the level of the opening long bracket at `pos`, such as 2 for `[==[`.
+fn long_bracket(bytes: &[u8], pos: usize) -> Option<usize> {
+    if bytes.get(pos) != Some(&b'[') {
+        return None;
+    }
+    let level = bytes[pos + 1..].iter().take_while(|&&b| b == b'=').count();
+    match bytes.get(pos + 1 + level) {
+        Some(b'[') => Some(level),
+        _ => None,
+    }
+}
+
+// Returns the position after the long bracket opened at `pos`.
+fn skip_long_bracket(code: &str, pos: usize, level: usize) -> usize {
+    let close = format!("]{}]", "=".repeat(level));
+    let body = pos + level + 2;
+    code[body..].find(&close).map_or(code.len(), |n| body + n + close.len())
+}
+
+// Rewrites the `local` statements at the top level of `code` into assignments to globals.
+fn hoist_locals(code: &str) -> Cow<'_, str> {
+    let tokens = tokenize(code);
+    let mut edits = Vec::new();
+    let mut depth = 0;
+
+    for (i, &(token, start, _)) in tokens.iter().enumerate() {
+        let name = match token {
+            Token::Name(name) => name,
+            _ => continue,
+        };
+        match name {
+            "function" | "do" | "if" | "repeat" => {
+                depth += 1;
+                continue;
+            },
+            "end" | "until" => {
+                depth -= 1;
+                continue;
+            },
+            "local" if depth == 0 => {},
+            _ => continue,
+        }
+        let after_local = match tokens.get(i + 1) {
+            Some(&(_, next, _)) => next,
+            None => continue,
+        };
+
+        if let Some(&(Token::Name("function"), ..)) = tokens.get(i + 1) {
+            edits.push((start, after_local, ""));
+            continue;
+        }
+
+        // `local a, b` is followed by `=` or by the next statement.
+        let mut last = i + 1;
+        while let (Some(&(Token::Name(_), ..)), Some(&(Token::Symbol(","), ..))) =
+            (tokens.get(last), tokens.get(last + 1))
+        {
+            last += 2;
+        }
+        match (tokens.get(last), tokens.get(last + 1)) {
+            (Some(&(Token::Name(_), ..)), Some(&(Token::Symbol("="), ..))) => {
+                edits.push((start, after_local, ""));
+            },
+            (Some(&(Token::Name(_), _, end)), next)
+                if next.map(|t| t.0) != Some(Token::Symbol("<")) =>
+            {
+                edits.push((start, after_local, ""));
+                edits.push((end, end, " = nil;"));
+            },
+            _ => {},
+        }
+    }
+
+    if edits.is_empty() {
+        return Cow::Borrowed(code);
+    }
+
+    let mut hoisted = String::with_capacity(code.len() + edits.len() * 8);
+    let mut copied = 0;
+    for (start, end, replacement) in edits {
+        hoisted.push_str(&code[copied..start]);
+        hoisted.push_str(replacement);
+        copied = end;
+    }
+    hoisted.push_str(&code[copied..]);
+    Cow::Owned(hoisted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hoist_locals;
+    use crate::{Lua, PrettyFormat, Session};
+
+    #[test]
+    fn shared_environment() {
+        let mut lua = Lua::new();
+        lua.set("base", 10);
+        let session = Session::new(&mut lua);
+
+        session.execute::<()>(&mut lua, "x = base + 1").unwrap();
+        assert_eq!(session.execute::<i32>(&mut lua, "return x + 1").unwrap(), 12);
+        assert_eq!(session.execute::<i32>(&mut lua, "x * 2").unwrap(), 22);
+        assert_eq!(lua.get::<i32, _>("x"), None);
+
+        // Locals are forgotten between calls unless they are hoisted.
+        session.execute::<()>(&mut lua, "local y = 5").unwrap();
+        assert!(session.execute::<Option<i32>>(&mut lua, "y").unwrap().is_none());
+
+        let other = Session::new(&mut lua);
+        assert!(other.execute::<Option<i32>>(&mut lua, "x").unwrap().is_none());
+        assert!(session.execute::<()>(&mut lua, "x = = 1").is_err());
+    }
+
+    #[test]
+    fn persistent_locals() {
+        let mut lua = Lua::new();
+        let session = Session::new(&mut lua).persistent_locals(true);
+
+        session.execute::<()>(&mut lua, "local a, b = 1, 2 local c").unwrap();
+        session.execute::<()>(&mut lua, "local function sum() return a + b end").unwrap();
+        session.execute::<()>(&mut lua, "do local hidden = 1 end").unwrap();
+        assert_eq!(session.execute::<i32>(&mut lua, "sum()").unwrap(), 3);
+        assert!(session.execute::<Option<i32>>(&mut lua, "c").unwrap().is_none());
+        assert!(session.execute::<Option<i32>>(&mut lua, "hidden").unwrap().is_none());
+    }
+
+    #[test]
+    fn eval_renders_results() {
+        let mut lua = Lua::new();
+        let session = Session::new(&mut lua).pretty_format(PrettyFormat::new().depth(1));
+
+        assert_eq!(session.eval(&mut lua, "'a', nil, 2.5").unwrap(), "\"a\"\tnil\t2.5");
+        assert_eq!(session.eval(&mut lua, "_ .. 'b'").unwrap(), "\"ab\"");
+        assert_eq!(
+            session.eval(&mut lua, "{ t = {} , u = { 1 } }").unwrap(),
+            "{ t = {}, u = {...} }"
+        );
+        assert_eq!(session.eval(&mut lua, "local x = 1").unwrap(), "");
+        assert_eq!(lua.get::<String, _>("_"), None);
+        let err = session.eval(&mut lua, "nil + 1").unwrap_err();
+        assert!(err.to_string().contains("arithmetic"), "{}", err);
+        assert_eq!(session.execute::<i32>(&mut lua, "#_.u").unwrap(), 1);
+    }
+
+    #[test]
+    fn hoisting_skips_strings_and_blocks() {
+        let code = "local s = 'local t' -- local u\nfunction f() local v end local w";
+        let expected = "s = 'local t' -- local u\nfunction f() local v end w = nil;";
+        assert_eq!(hoist_locals(code), expected);
+        assert_eq!(hoist_locals("local x <const> = [[local]]"), "local x <const> = [[local]]");
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    cell::Cell,
+    error::Error,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::builder::InstructionBudget;
+use crate::lua_functions::pcall;
+use crate::print::to_display_string;
+use crate::{ffix, AsMutLua, Lua, LuaContext, Push, PushOne, ScriptError, Void};
+
+// Registry field containing the array of the registered hooks.
+const HOOKS_KEY: &std::ffi::CStr = c"hlua.shutdown_hooks";
+
+// Number of instructions between two checks of the time limit of the running hook.
+const CHECK_INTERVAL: libc::c_int = 1000;
+
+thread_local! {
+    // Deadline of the hook running on this thread, and whether it was exceeded.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    static TIMED_OUT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Error returned by `Lua::run_shutdown_hooks` for a hook that didn't complete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShutdownHookError {
+    /// The hook was interrupted because it ran longer than the time limit.
+    TimedOut {
+        /// Position of the hook in the order of registration, starting from 0.
+        hook: usize,
+    },
+    /// The hook raised an error, or isn't a function.
+    Failed {
+        /// Position of the hook in the order of registration, starting from 0.
+        hook: usize,
+        /// Message of the error.
+        message: String,
+    },
+}
+
+impl fmt::Display for ShutdownHookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShutdownHookError::TimedOut { hook } => {
+                write!(f, "Shutdown hook {} exceeded its time limit", hook)
+            },
+            ShutdownHookError::Failed { hook, message } => {
+                write!(f, "Shutdown hook {} failed: {}", hook, message)
+            },
+        }
+    }
+}
+
+impl Error for ShutdownHookError {}
+
+impl<'lua> Lua<'lua> {
+    /// Registers a function to call when the host shuts down, with `run_shutdown_hooks`.
+    ///
+    /// `hook` is usually a Lua function read from the context, or a Rust function wrapped with
+    /// `function0`. Scripts can register their own hooks if `open_shutdown_hooks` was called.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// lua.open_shutdown_hooks();
+    /// lua.execute::<()>("
+    ///     pending = { 'a', 'b' }
+    ///     on_shutdown(function() flushed = #pending pending = {} end)
+    /// ").unwrap();
+    ///
+    /// // Typically called when the host receives SIGTERM.
+    /// assert!(lua.run_shutdown_hooks(Duration::from_secs(1)).is_empty());
+    /// assert_eq!(lua.get::<u32, _>("flushed"), Some(2));
+    /// ```
+    pub fn on_shutdown<V, E>(&mut self, hook: V)
+    where
+        for<'a> V: PushOne<&'a mut Lua<'lua>, Err = E>,
+        E: Into<Void>,
+    {
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            push_hooks(raw_lua);
+            let len = ffix::lua_rawlen(raw_lua, -1);
+            match hook.push_to_lua(&mut *self) {
+                Ok(pushed) => pushed.assert_one_and_forget(),
+                Err(_) => unreachable!(),
+            };
+            ffi::lua_rawseti(raw_lua.as_ptr(), -2, (len + 1) as _);
+            ffi::lua_pop(raw_lua.as_ptr(), 1);
+        }
+    }
+
+    /// Defines a global `on_shutdown(function)` that lets scripts register their own shutdown
+    /// hooks, like `Lua::on_shutdown` does.
+    pub fn open_shutdown_hooks(&mut self) {
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            ffix::lua_pushglobaltable(raw_lua);
+            ffi::lua_pushcfunction(raw_lua.as_ptr(), Some(register));
+            ffi::lua_setfield(raw_lua.as_ptr(), -2, c"on_shutdown".as_ptr());
+            ffi::lua_pop(raw_lua.as_ptr(), 1);
+        }
+    }
+
+    /// Calls the shutdown hooks, and returns the errors of the hooks that didn't complete.
+    ///
+    /// The hooks run in the reverse order of their registration, so that a hook registered
+    /// after another one can still rely on what the other one flushes. Each hook runs at most
+    /// once: the list of hooks is emptied before running them, and hooks registered while they
+    /// run are ignored. A hook that fails doesn't prevent the following ones from running.
+    ///
+    /// Each hook is called like any other call into Lua, and the context is busy while it runs.
+    /// A hook running longer than `time_limit` is interrupted with an error. The limit is
+    /// checked between Lua instructions, so it only applies to Lua code: a hook blocked in a
+    /// Rust function, for example one wrapped with `function0`, isn't interrupted, and delays the
+    /// hooks after it. The instruction limit of the context doesn't apply to the hooks, which
+    /// are limited by `time_limit` instead.
+    pub fn run_shutdown_hooks(&mut self, time_limit: Duration) -> Vec<ShutdownHookError> {
+        unsafe {
+            let raw_lua = self.as_mut_lua();
+            let l = raw_lua.as_ptr();
+            push_hooks(raw_lua);
+            let count = ffix::lua_rawlen(raw_lua, -1);
+            ffi::lua_newtable(l);
+            ffi::lua_setfield(l, ffi::LUA_REGISTRYINDEX, HOOKS_KEY.as_ptr());
+
+            let mut errors = Vec::new();
+            for hook in (0..count).rev() {
+                ffi::lua_rawgeti(l, -1, (hook + 1) as _);
+                if !ffi::lua_isfunction(l, -1) {
+                    ffi::lua_pop(l, 1);
+                    let message = "the hook isn't a function".to_owned();
+                    errors.push(ShutdownHookError::Failed { hook, message });
+                    continue;
+                }
+
+                DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + time_limit)));
+                TIMED_OUT.with(|timed_out| timed_out.set(false));
+                // Entered first so that the call doesn't replace the hook with the one of the
+                // instruction limit.
+                let budget = InstructionBudget::enter(raw_lua, raw_lua);
+                ffi::lua_sethook(l, Some(check_deadline), ffi::LUA_MASKCOUNT, CHECK_INTERVAL);
+                let status = pcall(raw_lua, 0, 0);
+                ffi::lua_sethook(l, None, 0, 0);
+                drop(budget);
+                DEADLINE.with(|deadline| deadline.set(None));
+
+                if status != 0 {
+                    // The traceback added to the message is left out.
+                    let message = to_display_string(raw_lua, -1);
+                    let message = ScriptError::new(message).message().to_owned();
+                    ffi::lua_pop(l, 1);
+                    errors.push(match TIMED_OUT.with(Cell::get) {
+                        true => ShutdownHookError::TimedOut { hook },
+                        false => ShutdownHookError::Failed { hook, message },
+                    });
+                }
+            }
+
+            ffi::lua_pop(l, 1);
+            errors
+        }
+    }
+}
+
+// Pushes the array of hooks, creating it if needed.
+unsafe fn push_hooks(lua: LuaContext) {
+    let l = lua.as_ptr();
+    ffi::lua_getfield(l, ffi::LUA_REGISTRYINDEX, HOOKS_KEY.as_ptr());
+    if !ffi::lua_istable(l, -1) {
+        ffi::lua_pop(l, 1);
+        ffi::lua_newtable(l);
+        ffi::lua_pushvalue(l, -1);
+        ffi::lua_setfield(l, ffi::LUA_REGISTRYINDEX, HOOKS_KEY.as_ptr());
+    }
+}
+
+extern "C" fn register(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let raw_lua = LuaContext::new_unchecked(lua);
+        if !ffi::lua_isfunction(lua, 1) {
+            "bad argument #1 to 'on_shutdown' (function expected)".push_no_err(raw_lua).forget();
+            ffix::lua_error(lua);
+        }
+        ffi::lua_settop(lua, 1);
+        push_hooks(raw_lua);
+        let len = ffix::lua_rawlen(raw_lua, -1);
+        ffi::lua_pushvalue(lua, 1);
+        ffi::lua_rawseti(lua, -2, (len + 1) as _);
+        0
+    }
+}
+
+unsafe extern "C" fn check_deadline(lua: *mut ffi::lua_State, _: *mut ffi::lua_Debug) {
+    let expired = DEADLINE.with(|deadline| deadline.get().is_some_and(|d| Instant::now() >= d));
+    if expired {
+        TIMED_OUT.with(|timed_out| timed_out.set(true));
+        "shutdown hook exceeded its time limit"
+            .push_no_err(LuaContext::new_unchecked(lua))
+            .forget();
+        ffix::lua_error(lua);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{function0, LockError, Lua, ShutdownHookError};
+
+    #[test]
+    fn hooks_run_in_reverse_order() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_shutdown_hooks();
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let sink = order.clone();
+        lua.on_shutdown(function0(move || sink.lock().unwrap().push("rust")));
+        lua.execute::<()>(
+            "on_shutdown(function() error('disk full') end)
+             on_shutdown(function() order = 'script' on_shutdown(function() end) end)",
+        )
+        .unwrap();
+
+        let errors = lua.run_shutdown_hooks(Duration::from_secs(1));
+        assert_eq!(errors.len(), 1);
+        match &errors[0] {
+            ShutdownHookError::Failed { hook: 1, message } => {
+                assert!(message.contains("disk full"))
+            },
+            error => panic!("unexpected error: {:?}", error),
+        }
+        assert_eq!(lua.get::<String, _>("order").unwrap(), "script");
+        assert_eq!(*order.lock().unwrap(), ["rust"]);
+
+        // The hooks registered while running aren't called, and the others don't run twice.
+        assert!(lua.run_shutdown_hooks(Duration::from_secs(1)).is_empty());
+        assert_eq!(order.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn busy_while_hooks_run() {
+        let mut lua = Lua::new();
+        let handle = lua.handle();
+        let result = Arc::new(Mutex::new(None));
+        let sink = result.clone();
+        lua.on_shutdown(function0(move || *sink.lock().unwrap() = handle.with(|_| ()).err()));
+
+        assert!(lua.run_shutdown_hooks(Duration::from_secs(1)).is_empty());
+        assert_eq!(*result.lock().unwrap(), Some(LockError::Busy));
+    }
+
+    #[test]
+    fn time_limit() {
+        let mut lua = Lua::new();
+        lua.open_shutdown_hooks();
+        lua.execute::<()>(
+            "on_shutdown(function() done = true end)
+             on_shutdown(function() while true do end end)",
+        )
+        .unwrap();
+
+        let errors = lua.run_shutdown_hooks(Duration::from_millis(50));
+        assert_eq!(errors, [ShutdownHookError::TimedOut { hook: 1 }]);
+        assert_eq!(lua.get::<bool, _>("done"), Some(true));
+    }
+}
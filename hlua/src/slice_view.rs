@@ -0,0 +1,244 @@
+use std::{
+    ffi::CStr,
+    marker::PhantomData,
+    ptr::{self, NonNull},
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Arc,
+    },
+};
+
+use crate::userdata::userdata_mut;
+use crate::userdata_methods::raise;
+use crate::{
+    push_userdata, AsMutLua, InsideCallback, LuaContext, LuaRead, LuaRef, Push, PushOne, Scope,
+    Void,
+};
+
+/// User data that gives scripts access to the elements of a Rust slice without copying them
+/// into a table.
+///
+/// Views are created by `Scope::slice_view`, which only allows reading the elements, and
+/// `Scope::slice_view_mut`, which also allows writing them. Like the other values of a scope,
+/// they stop working when the scope ends. Indices start from 1:
+///
+/// - `view[i]` returns the element at `i`, converted like a value returned by a callback.
+/// - `view[i] = value` converts the value like a callback argument and replaces the element.
+/// - `#view` is the number of elements.
+///
+/// Indices that aren't integers between 1 and `#view`, values that can't be converted, and
+/// writes to a read-only view raise an error. Elements are converted one by one, so this suits
+/// large buffers, such as images or audio samples, of which scripts only touch a part.
+///
+/// # Example
+///
+/// ```
+/// let mut lua = hlua::Lua::new();
+/// let pixels = vec![10u8, 20, 30, 40];
+/// let mut output = vec![0u8; 4];
+///
+/// lua.scope(|scope| {
+///     let input = scope.slice_view(&pixels);
+///     let output = scope.slice_view_mut(&mut output);
+///     scope.lua().set("input", &input);
+///     scope.lua().set("output", &output);
+///     scope.lua().execute::<()>("
+///         for i = 1, #input do output[i] = 255 - input[i] end
+///     ").unwrap();
+/// });
+///
+/// assert_eq!(output, [245, 235, 225, 215]);
+/// assert!(lua.execute::<()>("return input[1]").is_err());
+/// ```
+pub struct SliceView<T> {
+    // Null once the scope has ended.
+    pointer: Arc<AtomicPtr<T>>,
+    len: usize,
+    writable: bool,
+    marker: PhantomData<T>,
+}
+
+impl<'scope, 'lua> Scope<'scope, 'lua> {
+    /// Creates a read-only `SliceView` of `slice`.
+    pub fn slice_view<T>(&mut self, slice: &'scope [T]) -> LuaRef
+    where
+        T: Clone + Send + Sync + 'static + for<'a> PushOne<&'a mut InsideCallback, Err = Void>,
+    {
+        // The view never writes through the pointer of a read-only slice.
+        self.push_view(slice.as_ptr().cast_mut(), slice.len(), false)
+    }
+
+    /// Creates a `SliceView` of `slice` through which scripts can replace the elements.
+    pub fn slice_view_mut<T>(&mut self, slice: &'scope mut [T]) -> LuaRef
+    where
+        T: Clone + Send + Sync + 'static + for<'a> PushOne<&'a mut InsideCallback, Err = Void>,
+        T: for<'a> LuaRead<&'a mut InsideCallback>,
+    {
+        let view = self.push_view(slice.as_mut_ptr(), slice.len(), true);
+        unsafe {
+            // The metatable is shared by all the views of `T`, and only writable views give the
+            // bound that `__newindex` needs. It still checks that the view is writable.
+            let l = self.lua().as_mut_lua().as_ptr();
+            (&view).push_no_err(LuaContext::new_unchecked(l)).forget();
+            ffi::lua_getmetatable(l, -1);
+            ffi::lua_pushcfunction(l, Some(view_newindex::<T>));
+            ffi::lua_setfield(l, -2, c"__newindex".as_ptr());
+            ffi::lua_pop(l, 2);
+        }
+        view
+    }
+
+    fn push_view<T>(&mut self, data: *mut T, len: usize, writable: bool) -> LuaRef
+    where
+        T: Clone + Send + Sync + 'static + for<'a> PushOne<&'a mut InsideCallback, Err = Void>,
+    {
+        let pointer = Arc::new(AtomicPtr::new(data));
+        let view = SliceView { pointer: pointer.clone(), len, writable, marker: PhantomData };
+        let guard = push_userdata(view, &mut *self.lua(), |mut metatable| unsafe {
+            let l = metatable.as_mut_lua().as_ptr();
+            ffi::lua_pushcfunction(l, Some(view_index::<T>));
+            ffi::lua_setfield(l, -2, c"__index".as_ptr());
+            ffi::lua_pushcfunction(l, Some(view_len::<T>));
+            ffi::lua_setfield(l, -2, c"__len".as_ptr());
+            ffi::lua_pushcfunction(l, Some(read_only));
+            ffi::lua_setfield(l, -2, c"__newindex".as_ptr());
+        });
+        let reference = LuaRef::lua_read_at_position(guard, -1).ok().unwrap();
+
+        self.on_end(move || pointer.store(ptr::null_mut(), Ordering::Release));
+        reference
+    }
+}
+
+// Returns the view at index 1 and the pointer to its elements, or an error message.
+unsafe fn view_arg<'a, T: 'static>(lua: LuaContext) -> Result<(&'a SliceView<T>, *mut T), String> {
+    let view = match userdata_mut::<SliceView<T>>(lua, 1) {
+        Some(view) => view,
+        None => return Err("bad argument #1 (slice view expected)".to_owned()),
+    };
+    match view.pointer.load(Ordering::Acquire) {
+        data if data.is_null() => Err("slice view used after the end of its scope".to_owned()),
+        data => Ok((view, data)),
+    }
+}
+
+// Reads the 1-based index at position 2 and returns the offset of the element.
+unsafe fn offset_arg(lua: *mut ffi::lua_State, len: usize) -> Result<usize, String> {
+    if ffi::lua_type(lua, 2) != ffi::LUA_TNUMBER {
+        let name = CStr::from_ptr(ffi::lua_typename(lua, ffi::lua_type(lua, 2)));
+        return Err(format!(
+            "bad slice view index (integer expected, got {})",
+            name.to_string_lossy()
+        ));
+    }
+    let index = ffi::lua_tonumberx(lua, 2, ptr::null_mut());
+    match index >= 1.0 && index.fract() == 0.0 && index <= len as f64 {
+        true => Ok(index as usize - 1),
+        false => Err(format!("index {} out of bounds of slice view of length {}", index, len)),
+    }
+}
+
+extern "C" fn view_index<T>(lua: *mut ffi::lua_State) -> libc::c_int
+where
+    T: Clone + 'static + for<'a> PushOne<&'a mut InsideCallback, Err = Void>,
+{
+    let context = unsafe { NonNull::new_unchecked(lua) };
+    let result = unsafe {
+        view_arg::<T>(context).and_then(|(view, data)| {
+            let element = (*data.add(offset_arg(lua, view.len)?)).clone();
+            element.push_no_err(&mut InsideCallback { lua: context }).forget_internal();
+            Ok(1)
+        })
+    };
+    result.unwrap_or_else(|message| raise(context, message))
+}
+
+extern "C" fn view_newindex<T>(lua: *mut ffi::lua_State) -> libc::c_int
+where
+    T: 'static + for<'a> LuaRead<&'a mut InsideCallback>,
+{
+    let context = unsafe { NonNull::new_unchecked(lua) };
+    let result = unsafe {
+        view_arg::<T>(context).and_then(|(view, data)| {
+            if !view.writable {
+                return Err("slice view is read-only".to_owned());
+            }
+            let offset = offset_arg(lua, view.len)?;
+            let value = T::lua_read_at_position(&mut InsideCallback { lua: context }, 3)
+                .map_err(|_| "bad value for slice view element".to_owned())?;
+            *data.add(offset) = value;
+            Ok(0)
+        })
+    };
+    result.unwrap_or_else(|message| raise(context, message))
+}
+
+extern "C" fn view_len<T: 'static>(lua: *mut ffi::lua_State) -> libc::c_int {
+    let context = unsafe { NonNull::new_unchecked(lua) };
+    match unsafe { view_arg::<T>(context) } {
+        Ok((view, _)) => {
+            unsafe { ffi::lua_pushinteger(lua, view.len as ffi::lua_Integer) };
+            1
+        },
+        Err(message) => raise(context, message),
+    }
+}
+
+extern "C" fn read_only(lua: *mut ffi::lua_State) -> libc::c_int {
+    let context = unsafe { NonNull::new_unchecked(lua) };
+    raise(context, "slice view is read-only".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Lua;
+
+    #[test]
+    fn read_and_write() {
+        let mut lua = Lua::new();
+        let samples = [0.5f32, -0.25, 1.0];
+        let mut gains = vec![1.0f64; 3];
+
+        let sum: f64 = lua.scope(|scope| {
+            let samples = scope.slice_view(&samples);
+            let gains = scope.slice_view_mut(&mut gains);
+            scope.lua().set("samples", &samples);
+            scope.lua().set("gains", &gains);
+            let code = "local sum = 0
+                        for i = 1, #samples do
+                            gains[i] = gains[i] * 2
+                            sum = sum + samples[i] * gains[i]
+                        end
+                        return sum";
+            scope.lua().execute(code).unwrap()
+        });
+
+        assert_eq!(sum, 2.5);
+        assert_eq!(gains, [2.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn errors() {
+        let mut lua = Lua::new();
+        let values = [1i32, 2];
+        let mut flags = [false];
+
+        lua.scope(|scope| {
+            let values = scope.slice_view(&values);
+            let flags = scope.slice_view_mut(&mut flags);
+            scope.lua().set("values", &values);
+            scope.lua().set("flags", &flags);
+
+            let mut error = |code: &str| scope.lua().execute::<()>(code).unwrap_err().to_string();
+            assert!(error("return values[3]")
+                .contains("index 3 out of bounds of slice view of length 2"));
+            assert!(error("return values[1.5]").contains("out of bounds"));
+            assert!(error("return values.x").contains("integer expected, got string"));
+            assert!(error("values[1] = 5").contains("slice view is read-only"));
+            assert!(error("flags[1] = {}").contains("bad value for slice view element"));
+        });
+
+        let err = lua.execute::<()>("return #flags").unwrap_err().to_string();
+        assert!(err.contains("slice view used after the end of its scope"), "{}", err);
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use arc_swap::ArcSwap;
+
+use crate::lua_functions::GuardedCalls;
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{ffix, AnyLuaValue, Lua, LuaContext, LuaRead, Push};
+
+// Key of the registry entry holding the `Exports` of a context.
+const EXPORTS_KEY: &CStr = c"hlua.exports";
+
+// Information shared between a Lua context and its `SnapshotReader`s.
+struct Exports {
+    // Number of calls into Lua currently running. Snapshots are only taken between calls.
+    depth: AtomicUsize,
+    names: Mutex<Vec<String>>,
+    // Replaced as a whole by the thread of the context, and read without locking.
+    latest: ArcSwap<LuaSnapshot>,
+    _guarded: GuardedCalls,
+}
+
+/// Copy of the exported global variables of a Lua context, taken after a call into Lua
+/// finished. See `Lua::export_global`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LuaSnapshot {
+    values: HashMap<String, AnyLuaValue>,
+    generation: u64,
+}
+
+impl LuaSnapshot {
+    /// Returns the value of the exported variable `name`, or `None` if it isn't exported or is
+    /// nil.
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<&AnyLuaValue> {
+        self.values.get(name)
+    }
+
+    /// Returns the exported variables and their values.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &AnyLuaValue)> {
+        self.values.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// Number of snapshots taken before this one. Can be used to check whether anything
+    /// changed since the last time the snapshot was read.
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+/// Gives access to the latest `LuaSnapshot` of a context, from any thread.
+///
+/// Reading a snapshot never blocks, neither on Lua code nor on the publication of a new
+/// snapshot: the snapshot is swapped atomically as a whole once a call into Lua is done, and
+/// readers keep the version they obtained for as long as they need it.
+#[derive(Clone)]
+pub struct SnapshotReader {
+    exports: Arc<Exports>,
+}
+
+impl fmt::Debug for SnapshotReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SnapshotReader").field("latest", &self.latest()).finish()
+    }
+}
+
+impl SnapshotReader {
+    /// Returns the latest snapshot.
+    #[inline]
+    pub fn latest(&self) -> Arc<LuaSnapshot> {
+        self.exports.latest.load_full()
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Adds the global variable `name` to the variables copied into the snapshot of the context.
+    ///
+    /// Once at least one variable is exported, a new `LuaSnapshot` is taken every time a call
+    /// into Lua, such as `execute`, finishes. Calls made from inside Rust callbacks don't
+    /// produce snapshots, so that a snapshot never contains the values of a script that is
+    /// still running. Other threads can read the latest snapshot through a `SnapshotReader`,
+    /// without accessing the context.
+    ///
+    /// Values are copied as `AnyLuaValue`s, so tables are copied deeply. They must not contain
+    /// cycles.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.export_global("health");
+    /// let reader = lua.snapshot_reader();
+    ///
+    /// lua.execute::<()>("health = 100").unwrap();
+    /// let ui = std::thread::spawn(move || reader.latest().get("health").cloned());
+    /// assert_eq!(ui.join().unwrap(), Some(hlua::AnyLuaValue::LuaNumber(100.0)));
+    /// ```
+    pub fn export_global(&mut self, name: &str) {
+        let exports = self.exports();
+        {
+            let mut names = lock(&exports.names);
+            if !names.iter().any(|existing| existing == name) {
+                names.push(name.to_owned());
+            }
+        }
+        unsafe { take_snapshot(self.lua, &exports) };
+    }
+
+    /// Removes a variable added with `export_global` from the snapshot.
+    pub fn unexport_global(&mut self, name: &str) {
+        let exports = self.exports();
+        lock(&exports.names).retain(|existing| existing != name);
+        unsafe { take_snapshot(self.lua, &exports) };
+    }
+
+    /// Returns a reader for the snapshots of the exported variables.
+    #[inline]
+    pub fn snapshot_reader(&mut self) -> SnapshotReader {
+        SnapshotReader { exports: self.exports() }
+    }
+
+    /// Takes a snapshot of the exported variables now, for example after modifying them with
+    /// `set`.
+    pub fn refresh_snapshot(&mut self) {
+        if let Some(exports) = unsafe { exports(self.lua) } {
+            unsafe { take_snapshot(self.lua, &exports) };
+        }
+    }
+
+    // Returns the exports of the context, creating them if necessary.
+    fn exports(&mut self) -> Arc<Exports> {
+        unsafe {
+            if let Some(exports) = exports(self.lua) {
+                return exports;
+            }
+
+            let exports = Arc::new(Exports {
+                depth: AtomicUsize::new(0),
+                names: Mutex::new(Vec::new()),
+                latest: ArcSwap::from_pointee(LuaSnapshot::default()),
+                _guarded: GuardedCalls::new(),
+            });
+            push_userdata(exports.clone(), self.lua, |_| {}).forget();
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, EXPORTS_KEY.as_ptr());
+            exports
+        }
+    }
+}
+
+// The data behind the locks stays consistent even if a thread panicked while holding them.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+unsafe fn exports(lua: LuaContext) -> Option<Arc<Exports>> {
+    ffi::lua_getfield(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, EXPORTS_KEY.as_ptr());
+    let exports = userdata_mut::<Arc<Exports>>(lua, -1).map(|exports| exports.clone());
+    ffi::lua_pop(lua.as_ptr(), 1);
+    exports
+}
+
+// Reads the exported variables, without calling any metamethod, and publishes the snapshot.
+unsafe fn take_snapshot(lua: LuaContext, exports: &Exports) {
+    let mut values = HashMap::new();
+    for name in lock(&exports.names).iter() {
+        ffix::lua_pushglobaltable(lua);
+        name.as_str().push_no_err(lua).forget();
+        ffi::lua_rawget(lua.as_ptr(), -2);
+        match AnyLuaValue::lua_read(lua) {
+            Ok(AnyLuaValue::LuaNil) | Err(_) => (),
+            Ok(value) => {
+                values.insert(name.clone(), value);
+            },
+        }
+        ffi::lua_pop(lua.as_ptr(), 2);
+    }
+
+    // Only the thread of the context publishes snapshots, so the generation can't go backwards.
+    let generation = exports.latest.load().generation + 1;
+    exports.latest.store(Arc::new(LuaSnapshot { values, generation }));
+}
+
+/// Marks the duration of a call into Lua. Takes a snapshot when the outermost call finishes.
+pub(crate) struct SnapshotGuard(Option<(LuaContext, Arc<Exports>)>);
+
+impl SnapshotGuard {
+    #[inline]
+    pub(crate) unsafe fn enter(lua: LuaContext) -> SnapshotGuard {
+        if !GuardedCalls::any() {
+            return SnapshotGuard(None);
+        }
+        let exports = exports(lua);
+        if let Some(exports) = &exports {
+            exports.depth.fetch_add(1, Ordering::SeqCst);
+        }
+        SnapshotGuard(exports.map(|exports| (lua, exports)))
+    }
+}
+
+impl Drop for SnapshotGuard {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some((lua, exports)) = &self.0 {
+            if exports.depth.fetch_sub(1, Ordering::SeqCst) == 1 {
+                unsafe { take_snapshot(*lua, exports) };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{function0, AnyLuaValue, Lua};
+
+    #[test]
+    fn snapshots_follow_calls() {
+        let mut lua = Lua::new();
+        lua.export_global("state");
+        lua.export_global("state");
+        let reader = lua.snapshot_reader();
+        assert_eq!(reader.latest().get("state"), None);
+
+        lua.execute::<()>("state = { 'running' }").unwrap();
+        let snapshot = reader.latest();
+        let state = AnyLuaValue::LuaArray(vec![(
+            AnyLuaValue::LuaNumber(1.0),
+            AnyLuaValue::LuaString("running".to_owned()),
+        )]);
+        assert_eq!(snapshot.get("state"), Some(&state));
+
+        lua.execute::<()>("state = 'done'; other = 5").unwrap();
+        assert!(reader.latest().generation() > snapshot.generation());
+        assert_eq!(reader.latest().iter().count(), 1);
+        // Previous snapshots are unaffected.
+        assert_eq!(snapshot.get("state"), Some(&state));
+
+        lua.set("state", "modified");
+        lua.refresh_snapshot();
+        assert_eq!(reader.latest().get("state"), Some(&AnyLuaValue::LuaString("modified".into())));
+
+        lua.unexport_global("state");
+        assert_eq!(reader.latest().get("state"), None);
+    }
+
+    #[test]
+    fn no_snapshot_inside_callbacks() {
+        let mut lua = Lua::new();
+        lua.export_global("progress");
+        let reader = lua.snapshot_reader();
+
+        let inner = reader.clone();
+        lua.set("observe", function0(move || inner.latest().get("progress").cloned()));
+        lua.execute::<()>("progress = 1; seen = observe(); progress = 2").unwrap();
+
+        let seen: Option<i32> = lua.get("seen");
+        assert_eq!(seen, None);
+        assert_eq!(reader.latest().get("progress"), Some(&AnyLuaValue::LuaNumber(2.0)));
+    }
+}
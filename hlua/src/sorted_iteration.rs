@@ -0,0 +1,243 @@
+use std::{cmp::Ordering, ffi::CStr, ptr, slice};
+
+use crate::lua_functions::LuaFunction;
+use crate::{Lua, LuaContext, LuaError};
+
+// Key of the registry entry that is true when sorted iteration is enabled.
+const SORTED_KEY: &CStr = c"hlua.sorted_iteration";
+
+// Replaces `pairs` with a version that iterates in the order of `SortKey`.
+const SORTED_PAIRS: &str = r#"
+    local pairs, next, rawget, type, getmetatable, sort = pairs, next, rawget, type, getmetatable,
+        table.sort
+    local ranks = { number = 1, string = 2, boolean = 3 }
+
+    local function before(a, b)
+        local rank_a, rank_b = ranks[type(a.key)] or 4, ranks[type(b.key)] or 4
+        if rank_a ~= rank_b then
+            return rank_a < rank_b
+        elseif rank_a == 4 then
+            return a.n < b.n
+        elseif rank_a == 3 then
+            return not a.key and b.key
+        end
+        return a.key < b.key
+    end
+
+    function _G.pairs(t)
+        local mt = getmetatable(t)
+        if type(mt) == "table" and mt.__pairs then
+            return pairs(t)
+        end
+
+        local keys, n = {}, 0
+        for key in next, t do
+            n = n + 1
+            keys[n] = { key = key, n = n }
+        end
+        sort(keys, before)
+
+        local i = 0
+        return function()
+            while i < n do
+                i = i + 1
+                local key = keys[i].key
+                local value = rawget(t, key)
+                if value ~= nil then
+                    return key, value
+                end
+            end
+        end, t, nil
+    end
+"#;
+
+impl<'lua> Lua<'lua> {
+    /// Makes the iterations over Lua tables done by hlua go through the keys in a fixed order,
+    /// instead of the order of the hash table of Lua, which changes between runs and platforms.
+    ///
+    /// This affects `LuaTable::iter`, and through it the conversion of tables to `AnyLuaValue`
+    /// and with serde. Numbers come first in increasing order, then strings in byte order, then
+    /// `false` and `true`, then the other keys in the order of Lua. Sorting the keys of a table
+    /// takes time and memory proportional to its size, so this is meant for the programs that
+    /// need reproducible output more than speed. Use `install_sorted_pairs` to also sort the
+    /// iterations done by scripts.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{AnyLuaValue, Lua};
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.set_sorted_iteration(true);
+    /// lua.execute::<()>("t = { zeta = 1, alpha = 2, [10] = 3, [2] = 4 }").unwrap();
+    ///
+    /// let mut table: hlua::LuaTable<_> = lua.get("t").unwrap();
+    /// let keys: Vec<String> = table
+    ///     .iter::<AnyLuaValue, i32>()
+    ///     .flatten()
+    ///     .map(|(key, _)| match key {
+    ///         AnyLuaValue::LuaString(s) => s,
+    ///         other => format!("{:?}", other),
+    ///     })
+    ///     .collect();
+    /// assert_eq!(keys[2..], ["alpha", "zeta"]);
+    /// ```
+    pub fn set_sorted_iteration(&mut self, sorted: bool) {
+        unsafe {
+            ffi::lua_pushboolean(self.lua.as_ptr(), sorted as libc::c_int);
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, SORTED_KEY.as_ptr());
+        }
+    }
+
+    /// Returns true if sorted iteration is enabled. See `set_sorted_iteration`.
+    #[inline]
+    pub fn sorted_iteration(&self) -> bool {
+        unsafe { sorted_iteration(self.lua) }
+    }
+
+    /// Replaces the `pairs` function of the scripts with one that goes through the keys in the
+    /// order described in `set_sorted_iteration`.
+    ///
+    /// Strings are compared with the `<` operator of Lua, which follows the collation order of
+    /// the C locale, the byte order unless the program changed it. Tables with a `__pairs`
+    /// metamethod keep using it. The `table` library must be open.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// lua.install_sorted_pairs().unwrap();
+    ///
+    /// let code = r#"
+    ///     local out = {}
+    ///     for k, v in pairs({ c = 3, a = 1, b = 2, 10, 20 }) do out[#out + 1] = k .. "=" .. v end
+    ///     return table.concat(out, " ")
+    /// "#;
+    /// assert_eq!(lua.execute::<String>(code).unwrap(), "1=10 2=20 a=1 b=2 c=3");
+    /// ```
+    pub fn install_sorted_pairs(&mut self) -> Result<(), LuaError> {
+        LuaFunction::load_named(&mut *self, "=sorted_pairs", SORTED_PAIRS)?.call()
+    }
+}
+
+// Returns true if sorted iteration is enabled in the context.
+pub(crate) unsafe fn sorted_iteration(lua: LuaContext) -> bool {
+    ffi::lua_getfield(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, SORTED_KEY.as_ptr());
+    let sorted = ffi::lua_toboolean(lua.as_ptr(), -1) != 0;
+    ffi::lua_pop(lua.as_ptr(), 1);
+    sorted
+}
+
+/// Pushes a table holding the keys of the table at the absolute index `table`, in the order of
+/// `lua_next`, and returns the indices in this table of the keys in sorted order.
+pub(crate) unsafe fn push_sorted_keys(lua: LuaContext, table: i32) -> Vec<i32> {
+    let l = lua.as_ptr();
+    ffi::lua_newtable(l);
+    let keys = ffi::lua_gettop(l);
+
+    let mut order = Vec::new();
+    ffi::lua_pushnil(l);
+    while ffi::lua_next(l, table) != 0 {
+        ffi::lua_pop(l, 1);
+        let n = order.len() as i32 + 1;
+        order.push((SortKey::at(lua, -1), n));
+        ffi::lua_pushvalue(l, -1);
+        ffi::lua_rawseti(l, keys, n as _);
+    }
+
+    // The index breaks the ties between the keys that aren't sorted.
+    order.sort_by(|(a, n), (b, m)| a.cmp(b).then(n.cmp(m)));
+    order.into_iter().map(|(_, n)| n).collect()
+}
+
+// The part of a key that decides its position.
+enum SortKey {
+    Number(f64),
+    String(Vec<u8>),
+    Boolean(bool),
+    Other,
+}
+
+impl SortKey {
+    unsafe fn at(lua: LuaContext, index: i32) -> SortKey {
+        let l = lua.as_ptr();
+        match ffi::lua_type(l, index) {
+            ffi::LUA_TNUMBER => SortKey::Number(ffi::lua_tonumberx(l, index, ptr::null_mut())),
+            ffi::LUA_TSTRING => {
+                let mut len = 0;
+                let ptr = ffi::lua_tolstring(l, index, &mut len);
+                SortKey::String(slice::from_raw_parts(ptr.cast(), len).to_vec())
+            },
+            ffi::LUA_TBOOLEAN => SortKey::Boolean(ffi::lua_toboolean(l, index) != 0),
+            _ => SortKey::Other,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            SortKey::Number(_) => 0,
+            SortKey::String(_) => 1,
+            SortKey::Boolean(_) => 2,
+            SortKey::Other => 3,
+        }
+    }
+
+    fn cmp(&self, other: &SortKey) -> Ordering {
+        match (self, other) {
+            (SortKey::Number(a), SortKey::Number(b)) => a.total_cmp(b),
+            (SortKey::String(a), SortKey::String(b)) => a.cmp(b),
+            (SortKey::Boolean(a), SortKey::Boolean(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AnyLuaValue, Lua, LuaTable};
+
+    #[test]
+    fn sorted_table_iteration() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        assert!(!lua.sorted_iteration());
+        lua.set_sorted_iteration(true);
+        assert!(lua.sorted_iteration());
+        lua.execute::<()>("t = { [true] = 1, b = 2, [1.5] = 3, a = 4, [-1] = 5, [false] = 6 }")
+            .unwrap();
+
+        let mut table: LuaTable<_> = lua.get("t").unwrap();
+        let values: Vec<i32> = table.iter::<AnyLuaValue, i32>().flatten().map(|(_, v)| v).collect();
+        assert_eq!(values, [5, 3, 4, 2, 6, 1]);
+        // Stopping early leaves the stack as it was.
+        assert_eq!(table.iter::<AnyLuaValue, i32>().flatten().next().unwrap().1, 5);
+        drop(table);
+
+        let value: AnyLuaValue = lua.execute("return { c = 1, b = 2, a = 3 }").unwrap();
+        let keys: Vec<_> = match value {
+            AnyLuaValue::LuaArray(entries) => entries.into_iter().map(|(key, _)| key).collect(),
+            _ => unreachable!(),
+        };
+        let expected = ["a", "b", "c"].map(|s| AnyLuaValue::LuaString(s.to_owned()));
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn sorted_pairs() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.install_sorted_pairs().unwrap();
+
+        let code = r#"
+            local t = { [true] = "t", b = "b", [2] = "2", a = "a", [false] = "f", [1] = "1" }
+            local out = {}
+            for k, v in pairs(t) do
+                out[#out + 1] = v
+                t.b = nil
+            end
+            return table.concat(out)
+        "#;
+        assert_eq!(lua.execute::<String>(code).unwrap(), "12aft");
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::BTreeSet,
+    ffi::CStr,
+    fmt,
+    num::NonZeroU64,
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{AsLua, InsideCallback, Lua, LuaContext};
+
+// Key of the registry entry holding the `StateIdEntry` of a context.
+const STATE_ID_KEY: &CStr = c"hlua.state_id";
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Identifiers of the contexts that have one and haven't been closed.
+static LIVE_STATES: Mutex<BTreeSet<LuaStateId>> = Mutex::new(BTreeSet::new());
+
+/// Identifier of a Lua context, unique in the whole process.
+///
+/// Identifiers are never reused, even after the context is closed. This makes them usable as
+/// keys of the maps in which libraries store their per-context data, unlike the address of the
+/// `lua_State`, which can be reused for a new context. Coroutines share the identifier of the
+/// context they were created in.
+///
+/// # Example
+///
+/// ```
+/// let mut lua = hlua::Lua::new();
+/// let id = lua.state_id();
+///
+/// lua.set("state_id", hlua::function0(move || id.to_string()));
+/// assert_eq!(lua.execute::<String>("return state_id()").unwrap(), format!("lua#{}", id.as_u64()));
+/// assert!(id.is_alive());
+///
+/// drop(lua);
+/// assert!(!id.is_alive());
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LuaStateId(NonZeroU64);
+
+impl LuaStateId {
+    /// Returns the identifier of the context of `lua`, which can be the main thread of the
+    /// context or one of its coroutines.
+    ///
+    /// This is meant for raw C functions, which only receive the `lua_State`.
+    ///
+    /// # Safety
+    ///
+    /// `lua` must point to a valid `lua_State`, and the Lua stack must have room for two more
+    /// values.
+    #[inline]
+    pub unsafe fn from_raw(lua: *mut ffi::lua_State) -> LuaStateId {
+        state_id(NonNull::new(lua).expect("null lua_State"))
+    }
+
+    /// Returns the identifier as a number.
+    #[inline]
+    pub fn as_u64(self) -> u64 {
+        self.0.get()
+    }
+
+    /// Returns true if the context with this identifier hasn't been closed yet.
+    #[inline]
+    pub fn is_alive(self) -> bool {
+        live_states().contains(&self)
+    }
+
+    /// Returns the identifiers of the contexts that are still open, in creation order.
+    ///
+    /// This includes the contexts created with `Lua::new` or `Lua::builder`, and the ones
+    /// created by other means once their identifier has been asked for.
+    pub fn live() -> Vec<LuaStateId> {
+        live_states().iter().copied().collect()
+    }
+}
+
+impl fmt::Display for LuaStateId {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "lua#{}", self.0)
+    }
+}
+
+// Registry entry that removes the identifier from the live states when the context is closed.
+struct StateIdEntry(LuaStateId);
+
+impl Drop for StateIdEntry {
+    fn drop(&mut self) {
+        live_states().remove(&self.0);
+    }
+}
+
+fn live_states() -> MutexGuard<'static, BTreeSet<LuaStateId>> {
+    LIVE_STATES.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Returns the identifier of a context, assigning one if it doesn't have one yet.
+pub(crate) unsafe fn state_id(lua: LuaContext) -> LuaStateId {
+    ffi::lua_getfield(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, STATE_ID_KEY.as_ptr());
+    let id = userdata_mut::<StateIdEntry>(lua, -1).map(|entry| entry.0);
+    ffi::lua_pop(lua.as_ptr(), 1);
+    if let Some(id) = id {
+        return id;
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let id = LuaStateId(NonZeroU64::new(id).unwrap());
+    live_states().insert(id);
+    push_userdata(StateIdEntry(id), lua, |_| {}).forget();
+    ffi::lua_setfield(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, STATE_ID_KEY.as_ptr());
+    id
+}
+
+impl<'lua> Lua<'lua> {
+    /// Returns the identifier of this context. See `LuaStateId`.
+    #[inline]
+    pub fn state_id(&mut self) -> LuaStateId {
+        unsafe { state_id(self.lua) }
+    }
+}
+
+impl InsideCallback {
+    /// Returns the identifier of the context that called the callback. See `LuaStateId`.
+    #[inline]
+    pub fn state_id(&mut self) -> LuaStateId {
+        unsafe { state_id((&*self).as_lua()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AsLua, Lua, LuaStateId};
+
+    #[test]
+    fn ids_are_unique_and_tracked() {
+        let mut a = Lua::new();
+        let mut b = Lua::new();
+        let (id_a, id_b) = (a.state_id(), b.state_id());
+        assert_ne!(id_a, id_b);
+        assert_eq!(a.state_id(), id_a);
+        assert!(LuaStateId::live().contains(&id_a));
+        assert!(LuaStateId::live().contains(&id_b));
+
+        drop(a);
+        assert!(!id_a.is_alive());
+        assert!(id_b.is_alive());
+        assert!(id_a.to_string().starts_with("lua#"));
+    }
+
+    #[test]
+    fn raw_functions_and_coroutines() {
+        extern "C" fn raw_id(lua: *mut ffi::lua_State) -> libc::c_int {
+            unsafe {
+                let id = LuaStateId::from_raw(lua);
+                ffi::lua_pushnumber(lua, id.as_u64() as ffi::lua_Number);
+            }
+            1
+        }
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let id = lua.state_id();
+        let raw = lua.as_lua().as_ptr();
+        unsafe {
+            ffi::lua_pushcfunction(raw, Some(raw_id));
+            ffi::lua_setglobal(raw, c"raw_id".as_ptr());
+        }
+
+        assert_eq!(lua.execute::<f64>("return raw_id()").unwrap(), id.as_u64() as f64);
+        // Coroutines share the registry, and so the identifier, of their context.
+        let code = "return coroutine.wrap(raw_id)() == raw_id()";
+        assert!(lua.execute::<bool>(code).unwrap());
+
+        let mut other = unsafe { Lua::from_existing_state(raw, false) };
+        assert_eq!(other.state_id(), id);
+    }
+}
@@ -0,0 +1,531 @@
+use std::{
+    borrow::{Borrow, Cow},
+    ffi::CStr,
+    ops::Deref,
+    str::{self, Utf8Error},
+};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{
+    values, AnyLuaString, AsLua, AsMutLua, Lua, LuaContext, LuaRead, Push, PushGuard, PushOne, Void,
+};
+
+// Key of the registry entry holding the `Utf8Policy` of a context, absent for the default one.
+const POLICY_KEY: &CStr = c"hlua.utf8_policy";
+
+/// What reading a Lua string as a Rust `String` does when the string isn't valid UTF-8.
+///
+/// Lua strings are arbitrary bytes, so scripts can produce strings that can't be represented as
+/// a `String`. The policy is set per context with `Lua::set_utf8_policy`. It only applies to
+/// `String`: `StringInLua` always requires valid UTF-8, and `LuaString` and `AnyLuaValue` keep
+/// the bytes as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Utf8Policy {
+    /// The read fails, as if the value wasn't a string. This is the default.
+    #[default]
+    Error,
+    /// Invalid sequences are replaced with `U+FFFD REPLACEMENT CHARACTER`.
+    Lossy,
+    /// Each byte is converted to the character with the same value, as if the string was
+    /// encoded in Latin-1. No information is lost, and the original bytes can be recovered by
+    /// converting the characters back to bytes.
+    Bytes,
+}
+
+/// Lua string read or pushed as raw bytes, which don't have to be valid UTF-8.
+///
+/// # Example
+///
+/// ```
+/// let mut lua = hlua::Lua::new();
+/// lua.execute::<()>("data = 'caf\\xe9'").unwrap();
+///
+/// let data: hlua::LuaString = lua.get("data").unwrap();
+/// assert_eq!(data.as_bytes(), b"caf\xe9");
+/// assert!(data.to_str().is_err());
+/// assert_eq!(data.to_string_lossy(), "caf\u{fffd}");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LuaString(Vec<u8>);
+
+impl LuaString {
+    /// Builds a string from its bytes.
+    #[inline]
+    pub fn new<B>(bytes: B) -> LuaString
+    where
+        B: Into<Vec<u8>>,
+    {
+        LuaString(bytes.into())
+    }
+
+    /// Returns the bytes of the string.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the bytes of the string.
+    #[inline]
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// Returns the string if it is valid UTF-8.
+    #[inline]
+    pub fn to_str(&self) -> Result<&str, Utf8Error> {
+        str::from_utf8(&self.0)
+    }
+
+    /// Returns the string, with the invalid UTF-8 sequences replaced with `U+FFFD`.
+    #[inline]
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+impl Deref for LuaString {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for LuaString {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for LuaString {
+    #[inline]
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    #[inline]
+    fn from(bytes: Vec<u8>) -> LuaString {
+        LuaString(bytes)
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    #[inline]
+    fn from(bytes: &[u8]) -> LuaString {
+        LuaString(bytes.to_vec())
+    }
+}
+
+impl From<String> for LuaString {
+    #[inline]
+    fn from(string: String) -> LuaString {
+        LuaString(string.into_bytes())
+    }
+}
+
+impl From<&str> for LuaString {
+    #[inline]
+    fn from(string: &str) -> LuaString {
+        LuaString(string.as_bytes().to_vec())
+    }
+}
+
+impl From<AnyLuaString> for LuaString {
+    #[inline]
+    fn from(AnyLuaString(bytes): AnyLuaString) -> LuaString {
+        LuaString(bytes)
+    }
+}
+
+impl From<LuaString> for AnyLuaString {
+    #[inline]
+    fn from(LuaString(bytes): LuaString) -> AnyLuaString {
+        AnyLuaString(bytes)
+    }
+}
+
+impl From<LuaString> for Vec<u8> {
+    #[inline]
+    fn from(LuaString(bytes): LuaString) -> Vec<u8> {
+        bytes
+    }
+}
+
+impl<'lua, L> Push<L> for LuaString
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        (&self).push_to_lua(lua)
+    }
+}
+
+impl<'lua, L> PushOne<L> for LuaString where L: AsMutLua<'lua> {}
+
+impl<'lua, L> Push<L> for &LuaString
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        unsafe {
+            let raw_lua = lua.as_mut_lua();
+            ffi::lua_pushlstring(raw_lua.as_ptr(), self.0.as_ptr().cast(), self.0.len());
+            Ok(PushGuard { lua, size: 1, raw_lua })
+        }
+    }
+}
+
+impl<'lua, L> PushOne<L> for &LuaString where L: AsMutLua<'lua> {}
+
+impl<'lua, L> LuaRead<L> for LuaString
+where
+    L: AsLua<'lua>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<LuaString, L> {
+        match unsafe { values::string_bytes(lua.as_lua(), index) } {
+            Some(bytes) => Ok(LuaString(bytes.to_vec())),
+            None => Err(lua),
+        }
+    }
+}
+
+/// String type of another library converted to and from Lua strings without going through a
+/// `String`.
+///
+/// `SmolStr` and `CompactString` can be used directly with the `impl-smol_str` and
+/// `impl-compact_str` features. This wrapper is for the other types.
+///
+/// Pushing the wrapper pushes the text of `T`, and reading it builds `T` directly from the bytes
+/// of the Lua string, which avoids the allocation and copy of the intermediate `String` that
+/// `T::from(String)` would need. Like `String`, reading follows the `Utf8Policy` of the context
+/// for strings that aren't valid UTF-8.
+///
+/// # Example
+///
+/// ```
+/// use hlua::LuaStr;
+/// use std::rc::Rc;
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("upper", hlua::function1(|s: LuaStr<Rc<str>>| LuaStr(s.0.to_uppercase())));
+///
+/// let name: LuaStr<Rc<str>> = lua.execute("return upper('ada')").unwrap();
+/// assert_eq!(&*name.0, "ADA");
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LuaStr<T>(pub T);
+
+impl<'lua, L, T> Push<L> for LuaStr<T>
+where
+    L: AsMutLua<'lua>,
+    T: AsRef<str>,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        push_bytes(lua, self.0.as_ref().as_bytes())
+    }
+}
+
+impl<'lua, L, T> PushOne<L> for LuaStr<T>
+where
+    L: AsMutLua<'lua>,
+    T: AsRef<str>,
+{
+}
+
+impl<'lua, L, T> LuaRead<L> for LuaStr<T>
+where
+    L: AsLua<'lua>,
+    T: for<'a> From<&'a str>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<LuaStr<T>, L> {
+        let bytes = match unsafe { values::string_bytes(lua.as_lua(), index) } {
+            Some(bytes) => bytes,
+            None => return Err(lua),
+        };
+
+        match str::from_utf8(bytes) {
+            Ok(text) => Ok(LuaStr(T::from(text))),
+            Err(_) => match unsafe { decode_invalid_utf8(lua.as_lua(), bytes) } {
+                Some(text) => Ok(LuaStr(T::from(&text))),
+                None => Err(lua),
+            },
+        }
+    }
+}
+
+/// Byte buffer type of another library converted to and from Lua strings without going through
+/// a `Vec<u8>`.
+///
+/// `Bytes` and `BytesMut` can be used directly with the `impl-bytes` feature. This wrapper is
+/// for the other types.
+///
+/// Like `LuaString`, the bytes don't have to be valid UTF-8. Reading the wrapper builds `T`
+/// directly from the bytes of the Lua string.
+///
+/// # Example
+///
+/// ```
+/// use hlua::LuaBytes;
+/// use std::sync::Arc;
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("payload", LuaBytes(Arc::<[u8]>::from(&b"\x00\xff"[..])));
+///
+/// let payload: LuaBytes<Arc<[u8]>> = lua.get("payload").unwrap();
+/// assert_eq!(&*payload.0, b"\x00\xff");
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LuaBytes<T>(pub T);
+
+impl<'lua, L, T> Push<L> for LuaBytes<T>
+where
+    L: AsMutLua<'lua>,
+    T: AsRef<[u8]>,
+{
+    type Err = Void;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        push_bytes(lua, self.0.as_ref())
+    }
+}
+
+impl<'lua, L, T> PushOne<L> for LuaBytes<T>
+where
+    L: AsMutLua<'lua>,
+    T: AsRef<[u8]>,
+{
+}
+
+impl<'lua, L, T> LuaRead<L> for LuaBytes<T>
+where
+    L: AsLua<'lua>,
+    T: for<'a> From<&'a [u8]>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<LuaBytes<T>, L> {
+        match unsafe { values::string_bytes(lua.as_lua(), index) } {
+            Some(bytes) => Ok(LuaBytes(T::from(bytes))),
+            None => Err(lua),
+        }
+    }
+}
+
+#[inline]
+fn push_bytes<'lua, L>(mut lua: L, bytes: &[u8]) -> Result<PushGuard<L>, (Void, L)>
+where
+    L: AsMutLua<'lua>,
+{
+    unsafe {
+        let raw_lua = lua.as_mut_lua();
+        ffi::lua_pushlstring(raw_lua.as_ptr(), bytes.as_ptr().cast(), bytes.len());
+        Ok(PushGuard { lua, size: 1, raw_lua })
+    }
+}
+
+// Implementations for the string types of external crates, which go through `LuaStr` and
+// `LuaBytes` and so follow the same rules.
+#[allow(unused_macros)]
+macro_rules! impl_foreign_string {
+    ($ty:ty, $wrapper:ident, $inner:ty, $convert:expr) => {
+        impl<'lua, L> Push<L> for $ty
+        where
+            L: AsMutLua<'lua>,
+        {
+            type Err = Void;
+
+            #[inline]
+            fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+                $wrapper(self).push_to_lua(lua)
+            }
+        }
+
+        impl<'lua, L> PushOne<L> for $ty where L: AsMutLua<'lua> {}
+
+        impl<'lua, L> LuaRead<L> for $ty
+        where
+            L: AsLua<'lua>,
+        {
+            #[inline]
+            fn lua_read_at_position(lua: L, index: i32) -> Result<$ty, L> {
+                $wrapper::<$inner>::lua_read_at_position(lua, index).map(|read| $convert(read.0))
+            }
+        }
+    };
+}
+
+#[cfg(feature = "impl-smol_str")]
+impl_foreign_string!(smol_str::SmolStr, LuaStr, smol_str::SmolStr, |s| s);
+#[cfg(feature = "impl-compact_str")]
+impl_foreign_string!(compact_str::CompactString, LuaStr, compact_str::CompactString, |s| s);
+// `Bytes` can only be built from static slices, so the bytes are read into a `Vec` that the
+// `Bytes` then owns.
+#[cfg(feature = "impl-bytes")]
+impl_foreign_string!(bytes::Bytes, LuaBytes, Vec<u8>, bytes::Bytes::from);
+#[cfg(feature = "impl-bytes")]
+impl_foreign_string!(bytes::BytesMut, LuaBytes, bytes::BytesMut, |b| b);
+
+impl<'lua> Lua<'lua> {
+    /// Sets what reading a `String` does when the Lua string isn't valid UTF-8. See
+    /// `Utf8Policy`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("name = 'Ren\\xe9'").unwrap();
+    /// assert_eq!(lua.get::<String, _>("name"), None);
+    ///
+    /// lua.set_utf8_policy(hlua::Utf8Policy::Bytes);
+    /// assert_eq!(lua.get::<String, _>("name").unwrap(), "René");
+    /// ```
+    pub fn set_utf8_policy(&mut self, policy: Utf8Policy) {
+        unsafe {
+            match policy {
+                Utf8Policy::Error => ffi::lua_pushnil(self.lua.as_ptr()),
+                policy => {
+                    push_userdata(policy, self.lua, |_| {}).forget();
+                },
+            }
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, POLICY_KEY.as_ptr());
+        }
+    }
+
+    /// Returns the policy set with `set_utf8_policy`.
+    #[inline]
+    pub fn utf8_policy(&self) -> Utf8Policy {
+        unsafe { policy(self.lua) }
+    }
+
+    /// Reads the global variable `name` as a string, replacing the invalid UTF-8 sequences with
+    /// `U+FFFD` whatever the policy of the context is. Returns `None` if the variable isn't a
+    /// string or a number.
+    #[inline]
+    pub fn get_utf8_lossy<I>(&mut self, name: I) -> Option<String>
+    where
+        I: Borrow<str>,
+    {
+        self.get::<LuaString, _>(name).map(|string| string.to_string_lossy().into_owned())
+    }
+}
+
+unsafe fn policy(lua: LuaContext) -> Utf8Policy {
+    ffi::lua_getfield(lua.as_ptr(), ffi::LUA_REGISTRYINDEX, POLICY_KEY.as_ptr());
+    let policy = userdata_mut::<Utf8Policy>(lua, -1).copied().unwrap_or_default();
+    ffi::lua_pop(lua.as_ptr(), 1);
+    policy
+}
+
+/// Converts a string that isn't valid UTF-8 according to the policy of the context.
+pub(crate) unsafe fn decode_invalid_utf8(lua: LuaContext, bytes: &[u8]) -> Option<String> {
+    match policy(lua) {
+        Utf8Policy::Error => None,
+        Utf8Policy::Lossy => Some(String::from_utf8_lossy(bytes).into_owned()),
+        Utf8Policy::Bytes => Some(bytes.iter().map(|&byte| byte as char).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{function1, AnyLuaValue, Lua, LuaBytes, LuaStr, LuaString, Utf8Policy};
+
+    #[test]
+    fn policies() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("invalid = 'a\\xff'; valid = 'é'").unwrap();
+
+        assert_eq!(lua.utf8_policy(), Utf8Policy::Error);
+        assert_eq!(lua.get::<String, _>("invalid"), None);
+
+        lua.set_utf8_policy(Utf8Policy::Lossy);
+        assert_eq!(lua.get::<String, _>("invalid").unwrap(), "a\u{fffd}");
+        assert_eq!(lua.get::<String, _>("valid").unwrap(), "é");
+
+        lua.set_utf8_policy(Utf8Policy::Bytes);
+        assert_eq!(lua.utf8_policy(), Utf8Policy::Bytes);
+        assert_eq!(lua.get::<String, _>("invalid").unwrap(), "a\u{ff}");
+
+        // `AnyLuaValue` keeps the bytes whatever the policy is.
+        match lua.get::<AnyLuaValue, _>("invalid").unwrap() {
+            AnyLuaValue::LuaAnyString(string) => assert_eq!(string.0, b"a\xff"),
+            other => panic!("{:?}", other),
+        }
+
+        lua.set_utf8_policy(Utf8Policy::Error);
+        assert_eq!(lua.get::<String, _>("invalid"), None);
+        assert_eq!(lua.get_utf8_lossy("invalid").unwrap(), "a\u{fffd}");
+    }
+
+    #[test]
+    fn lua_string() {
+        let mut lua = Lua::new();
+        lua.set("data", LuaString::new(&b"\x00\x80"[..]));
+
+        let length: i32 = lua.execute("return #data").unwrap();
+        assert_eq!(length, 2);
+
+        let data: LuaString = lua.get("data").unwrap();
+        assert_eq!(&*data, b"\x00\x80");
+        assert_eq!(lua.get::<LuaString, _>("missing"), None);
+    }
+
+    #[test]
+    fn foreign_string_types() {
+        let mut lua = Lua::new();
+        lua.set_utf8_policy(Utf8Policy::Lossy);
+        lua.set("tag", function1(|s: LuaStr<Box<str>>| LuaStr(format!("<{}>", s.0))));
+        lua.execute::<()>("bad = 'caf\\xe9'").unwrap();
+
+        let tagged: LuaStr<Box<str>> = lua.execute("return tag(12)").unwrap();
+        assert_eq!(&*tagged.0, "<12>");
+        assert_eq!(lua.get::<LuaStr<Box<str>>, _>("bad").unwrap().0.as_ref(), "caf\u{fffd}");
+        assert_eq!(&*lua.get::<LuaBytes<Vec<u8>>, _>("bad").unwrap().0, b"caf\xe9");
+        assert!(lua.execute::<LuaStr<Box<str>>>("return {}").is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "impl-smol_str", feature = "impl-compact_str"))]
+    fn smol_and_compact_strings() {
+        use compact_str::CompactString;
+        use smol_str::SmolStr;
+
+        let mut lua = Lua::new();
+        lua.set("join", function1(|s: SmolStr| CompactString::from(format!("{}!", s))));
+        lua.set("name", SmolStr::new("ada"));
+
+        let joined: CompactString = lua.execute("return join(name)").unwrap();
+        assert_eq!(joined, "ada!");
+        assert_eq!(lua.get::<SmolStr, _>("name").unwrap(), "ada");
+        assert!(lua.execute::<SmolStr>("return {}").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "impl-bytes")]
+    fn bytes() {
+        use bytes::{Bytes, BytesMut};
+
+        let mut lua = Lua::new();
+        lua.set("data", Bytes::from_static(b"\x00\xff"));
+        lua.set("more", BytesMut::from(&b"abc"[..]));
+
+        let length: i32 = lua.execute("return #data + #more").unwrap();
+        assert_eq!(length, 5);
+        assert_eq!(lua.get::<Bytes, _>("data").unwrap(), &b"\x00\xff"[..]);
+        assert_eq!(lua.get::<BytesMut, _>("more").unwrap(), &b"abc"[..]);
+    }
+}
@@ -0,0 +1,371 @@
+use std::{error::Error, ffi::CStr, fmt, fs, path::Path};
+
+use crate::{
+    AnyLuaValue, Lua, LuaError, LuaFunction, LuaFunctionCallError, LuaRead, LuaRef, Push, PushGuard,
+};
+
+// Key of the registry entry holding the function that compiles Teal code.
+const COMPILER_KEY: &CStr = c"hlua.teal";
+
+// Receives the Teal module and returns a function that compiles a chunk of Teal code. The
+// function returns the generated Lua code, or nil and the list of diagnostics. Both the
+// `check_string` API of recent versions of Teal and the older `process_string` are supported.
+const COMPILER_GLUE: &str = r#"
+local tl = ...
+if type(tl) ~= "table" or not (tl.check_string or tl.process_string) then
+    error("the Teal compiler must return a module with a check_string function")
+end
+local target = _VERSION == "Lua 5.4" and "5.4" or "5.1"
+
+return function(code, name)
+    local result
+    if tl.check_string then
+        result = tl.check_string(code, nil, name)
+    else
+        result = tl.process_string(code, false, nil, name)
+    end
+
+    local diagnostics = {}
+    for _, kind in ipairs({ "syntax", "type" }) do
+        for _, err in ipairs(result[kind .. "_errors"] or {}) do
+            -- Malformed entries are passed as `false` and rejected by `read_diagnostics`.
+            diagnostics[#diagnostics + 1] = type(err) == "table"
+                and { kind, err.filename or name, err.y or 0, err.x or 0, err.msg } or false
+        end
+    end
+    if #diagnostics > 0 then
+        return nil, diagnostics
+    end
+
+    local generate = tl.generate or tl.pretty_print_ast
+    return (generate(result.ast, target)), nil
+end
+"#;
+
+/// Problem found by the Teal compiler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TealDiagnostic {
+    /// Whether the code couldn't be parsed or doesn't type-check.
+    pub kind: TealDiagnosticKind,
+    /// Name of the file containing the problem.
+    pub file: String,
+    /// Line of the problem, starting at 1.
+    pub line: u32,
+    /// Column of the problem, starting at 1.
+    pub column: u32,
+    /// Description of the problem.
+    pub message: String,
+}
+
+/// Kind of a `TealDiagnostic`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TealDiagnosticKind {
+    /// The code isn't syntactically valid.
+    Syntax,
+    /// The code doesn't type-check.
+    Type,
+}
+
+/// Error that can happen when compiling or running Teal code.
+#[derive(Debug)]
+pub enum TealError {
+    /// The Teal compiler hasn't been opened with `Lua::open_teal`.
+    NotOpened,
+    /// The compiler rejected the code.
+    Diagnostics(Vec<TealDiagnostic>),
+    /// The compiler itself failed, or the compiled code failed to load or run.
+    LuaError(LuaError),
+}
+
+impl fmt::Display for TealDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            TealDiagnosticKind::Syntax => "syntax error",
+            TealDiagnosticKind::Type => "type error",
+        };
+        write!(f, "{}:{}:{}: {}: {}", self.file, self.line, self.column, kind, self.message)
+    }
+}
+
+impl fmt::Display for TealError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TealError::NotOpened => write!(f, "the Teal compiler hasn't been opened"),
+            TealError::Diagnostics(diagnostics) => {
+                for (n, diagnostic) in diagnostics.iter().enumerate() {
+                    if n != 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            },
+            TealError::LuaError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for TealError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TealError::LuaError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<LuaError> for TealError {
+    #[inline]
+    fn from(err: LuaError) -> TealError {
+        TealError::LuaError(err)
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Loads the Teal compiler, which makes it possible to run Teal code with `execute_teal` and
+    /// `load_teal_file`.
+    ///
+    /// `compiler` is the source of the `tl.lua` module distributed with Teal, for example
+    /// obtained with `include_str!`. The compiler requires the standard libraries to be open.
+    ///
+    /// The compiler isn't bundled with hlua, so that the application picks the version of Teal
+    /// its scripts are written for, and updates it on its own schedule. Versions with either
+    /// `tl.check_string` or `tl.process_string` are supported.
+    pub fn open_teal(&mut self, compiler: &str) -> Result<(), LuaError> {
+        let tl: LuaRef = LuaFunction::load_named(&mut *self, "@tl.lua", compiler)?.call()?;
+        let mut glue = LuaFunction::load_named(&mut *self, "=teal", COMPILER_GLUE)?;
+        let compile: LuaRef = glue.call_with_args(&tl)?;
+        drop(glue);
+
+        unsafe {
+            (&compile).push_no_err(self.lua).forget();
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, COMPILER_KEY.as_ptr());
+        }
+        Ok(())
+    }
+
+    /// Type-checks some Teal code and returns the Lua code it compiles to.
+    ///
+    /// `name` is the file name used in diagnostics.
+    pub fn compile_teal(&mut self, name: &str, code: &str) -> Result<String, TealError> {
+        let guard = unsafe {
+            ffi::lua_getfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, COMPILER_KEY.as_ptr());
+            PushGuard::new(&mut *self, 1)
+        };
+        let mut compile: LuaFunction<_> = match LuaRead::lua_read(guard) {
+            Ok(compile) => compile,
+            Err(_) => return Err(TealError::NotOpened),
+        };
+
+        let output: (AnyLuaValue, AnyLuaValue) = match compile.call_multi_with_args((code, name)) {
+            Ok(output) => output,
+            Err(LuaFunctionCallError::LuaError(err)) => return Err(TealError::LuaError(err)),
+            Err(LuaFunctionCallError::PushError(_)) => unreachable!("pushing strings never fails"),
+        };
+
+        match output {
+            (AnyLuaValue::LuaString(code), _) => Ok(code),
+            (_, diagnostics) => Err(match read_diagnostics(diagnostics) {
+                Ok(diagnostics) => TealError::Diagnostics(diagnostics),
+                Err(err) => TealError::LuaError(err),
+            }),
+        }
+    }
+
+    /// Type-checks and executes some Teal code.
+    ///
+    /// `name` is the file name used in diagnostics and in error messages.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let mut lua = hlua::Lua::new();
+    /// lua.openlibs();
+    /// lua.open_teal(&std::fs::read_to_string("tl.lua").unwrap()).unwrap();
+    ///
+    /// let value: i32 = lua.execute_teal("main.tl", "local a: integer = 3\nreturn a * 2").unwrap();
+    /// assert_eq!(value, 6);
+    ///
+    /// match lua.execute_teal::<()>("main.tl", "local a: integer = 'three'") {
+    ///     Err(hlua::TealError::Diagnostics(diagnostics)) => assert_eq!(diagnostics[0].line, 1),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn execute_teal<'a, T>(&'a mut self, name: &str, code: &str) -> Result<T, TealError>
+    where
+        T: for<'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'lua>>>>,
+    {
+        let code = self.compile_teal(name, code)?;
+        Ok(self.execute_named(&format!("@{}", name), &code)?)
+    }
+
+    /// Reads, type-checks and loads a Teal file. Returns the chunk as a function.
+    pub fn load_teal_file<P>(
+        &mut self,
+        path: P,
+    ) -> Result<LuaFunction<PushGuard<&mut Lua<'lua>>>, TealError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(LuaError::ReadError)?;
+        let name = path.display().to_string();
+
+        let code = self.compile_teal(&name, &source)?;
+        Ok(LuaFunction::load_named(self, &format!("@{}", name), &code)?)
+    }
+}
+
+// Converts the diagnostics returned by the compiler glue. Fails with `WrongType` if they are
+// malformed or empty, since a failure without any diagnostic wouldn't say what went wrong.
+fn read_diagnostics(diagnostics: AnyLuaValue) -> Result<Vec<TealDiagnostic>, LuaError> {
+    let read = |diagnostic: AnyLuaValue| {
+        let mut fields = Vec::<AnyLuaValue>::try_from(diagnostic).ok()?.into_iter();
+        let kind = match String::try_from(fields.next()?).ok()?.as_str() {
+            "syntax" => TealDiagnosticKind::Syntax,
+            _ => TealDiagnosticKind::Type,
+        };
+        Some(TealDiagnostic {
+            kind,
+            file: String::try_from(fields.next()?).ok()?,
+            line: u32::try_from(fields.next()?).ok()?,
+            column: u32::try_from(fields.next()?).ok()?,
+            message: String::try_from(fields.next()?).ok()?,
+        })
+    };
+
+    let diagnostics = Vec::<AnyLuaValue>::try_from(diagnostics).map_err(|_| LuaError::WrongType)?;
+    match diagnostics.into_iter().map(read).collect::<Option<Vec<_>>>() {
+        Some(diagnostics) if !diagnostics.is_empty() => Ok(diagnostics),
+        _ => Err(LuaError::WrongType),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::{Lua, LuaError, TealDiagnosticKind, TealError};
+
+    // Minimal stand-in for the Teal compiler: annotations are removed, and assigning `true` to a
+    // number is a type error.
+    const FAKE_TL: &str = r#"
+        local tl = {}
+        function tl.check_string(code, env, name)
+            local result = { syntax_errors = {}, type_errors = {}, ast = code }
+            local y = 0
+            for line in (code .. "\n"):gmatch("(.-)\n") do
+                y = y + 1
+                local x = line:find("= true")
+                if line:find(": number") and x then
+                    table.insert(result.type_errors,
+                        { y = y, x = x, msg = "got boolean, expected number", filename = name })
+                end
+            end
+            return result
+        end
+        function tl.generate(ast, target)
+            return (ast:gsub(":%s*%a+", ""))
+        end
+        return tl
+    "#;
+
+    fn lua() -> Lua<'static> {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_teal(FAKE_TL).unwrap();
+        lua
+    }
+
+    #[test]
+    fn execute() {
+        let mut lua = lua();
+        let value: i32 = lua.execute_teal("main.tl", "local a: number = 4\nreturn a * 2").unwrap();
+        assert_eq!(value, 8);
+
+        match lua.execute_teal::<()>("main.tl", "local a: number = 1\nerror('x')") {
+            Err(TealError::LuaError(LuaError::ExecutionError(msg))) => {
+                assert!(msg.message().starts_with("main.tl:2:"))
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut lua = lua();
+        let err = lua.execute_teal::<()>("main.tl", "local a = 1\nlocal b: number = true");
+
+        match err {
+            Err(TealError::Diagnostics(diagnostics)) => {
+                assert_eq!(diagnostics.len(), 1);
+                assert_eq!(diagnostics[0].kind, TealDiagnosticKind::Type);
+                assert_eq!(diagnostics[0].line, 2);
+                assert_eq!(
+                    diagnostics[0].to_string(),
+                    "main.tl:2:17: type error: got boolean, expected number"
+                );
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_diagnostics() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let broken = "return { check_string = function() return { type_errors = { 'x' } } end }";
+        lua.open_teal(broken).unwrap();
+        let err = lua.compile_teal("main.tl", "return 1");
+        assert!(matches!(err, Err(TealError::LuaError(LuaError::WrongType))), "{:?}", err);
+
+        // The generated code is missing, but there is no diagnostic either.
+        let empty = "return { check_string = function() return {} end, generate = function() end }";
+        lua.open_teal(empty).unwrap();
+        let err = lua.compile_teal("main.tl", "return 1");
+        assert!(matches!(err, Err(TealError::LuaError(LuaError::WrongType))), "{:?}", err);
+    }
+
+    // Runs against the real compiler when `HLUA_TL_LUA` is the path of a `tl.lua`.
+    #[test]
+    fn real_compiler() {
+        let compiler = match std::env::var_os("HLUA_TL_LUA") {
+            Some(path) => std::fs::read_to_string(path).unwrap(),
+            None => return,
+        };
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_teal(&compiler).unwrap();
+
+        let value: i32 = lua.execute_teal("main.tl", "local a: integer = 3\nreturn a * 2").unwrap();
+        assert_eq!(value, 6);
+        match lua.execute_teal::<()>("main.tl", "local a = 1\nlocal b: integer = 'three'") {
+            Err(TealError::Diagnostics(diagnostics)) => {
+                assert_eq!(diagnostics[0].kind, TealDiagnosticKind::Type);
+                assert_eq!(diagnostics[0].line, 2);
+            },
+            other => panic!("{:?}", other),
+        }
+        match lua.execute_teal::<()>("main.tl", "local = 1") {
+            Err(TealError::Diagnostics(diagnostics)) => {
+                assert_eq!(diagnostics[0].kind, TealDiagnosticKind::Syntax)
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_file_and_not_opened() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("hlua-teal-{}.tl", std::process::id()));
+        std::fs::File::create(&path).unwrap().write_all(b"return 1 + 2").unwrap();
+
+        let mut lua = lua();
+        let value: i32 = lua.load_teal_file(&path).unwrap().call().unwrap();
+        assert_eq!(value, 3);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut lua = Lua::new();
+        assert!(matches!(lua.compile_teal("a.tl", "return 1"), Err(TealError::NotOpened)));
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{Lua, LuaError, RecoveryAction, ScriptBundle};
+
+type Setup = Box<dyn for<'lua> Fn(&mut Lua<'lua>)>;
+
+// Returns the names of the global `test_*` functions, separated by newlines.
+const DISCOVER: &str = r#"
+    local names = {}
+    for name, value in pairs(_G) do
+        if type(name) == "string" and name:sub(1, 5) == "test_" and type(value) == "function" then
+            names[#names + 1] = name
+        end
+    end
+    table.sort(names)
+    return table.concat(names, "\n")
+"#;
+
+/// Runs the unit tests written in Lua scripts.
+///
+/// The tests of a script are its global functions whose name starts with `test_`. They pass if
+/// they return, and fail if they raise an error, for example with `assert` or `error`. Each
+/// test runs in a context of its own, created with `Lua::new` and the standard libraries, then
+/// prepared by the `setup` function, in which the script is executed before the test function
+/// is called. Tests can't see what other tests did.
+///
+/// The `Display` implementation of the `TestReport` mimics the output of `cargo test`, so the
+/// script tests can run from a Rust test.
+///
+/// # Example
+///
+/// ```
+/// use hlua::TestRunner;
+///
+/// let report = TestRunner::new()
+///     .setup(|lua| lua.set("version", 3))
+///     .script("math_test.lua", r#"
+///         function test_addition() assert(1 + 1 == 2) end
+///         function test_version() assert(version == 4, "wrong version") end
+///     "#)
+///     .run();
+///
+/// assert_eq!(report.passed(), 1);
+/// let failure = &report.failures()[0];
+/// assert_eq!(failure.name, "test_version");
+/// assert!(report.to_string().contains("test math_test.lua::test_version ... FAILED"));
+/// ```
+pub struct TestRunner {
+    scripts: Vec<(String, String)>,
+    bundle: Option<ScriptBundle>,
+    setup: Option<Setup>,
+    filter: Option<String>,
+}
+
+impl TestRunner {
+    /// Creates a runner without scripts.
+    #[inline]
+    pub fn new() -> TestRunner {
+        TestRunner { scripts: Vec::new(), bundle: None, setup: None, filter: None }
+    }
+
+    /// Adds a script. `name` appears in the names of its tests and in error messages.
+    #[inline]
+    pub fn script(mut self, name: &str, source: impl Into<String>) -> TestRunner {
+        self.scripts.push((name.to_owned(), source.into()));
+        self
+    }
+
+    /// Makes the modules of `bundle` available to `require`, and adds its files whose name
+    /// starts with `test_` or ends with `_test.lua` as scripts.
+    pub fn bundle(mut self, bundle: ScriptBundle) -> TestRunner {
+        for path in bundle.paths() {
+            let file = path.rsplit('/').next().unwrap_or(path);
+            if file.starts_with("test_") || file.ends_with("_test.lua") {
+                let source = bundle.source(path).unwrap_or_default();
+                self.scripts.push((path.to_owned(), source.to_owned()));
+            }
+        }
+        self.bundle = Some(bundle);
+        self
+    }
+
+    /// Sets a function that prepares the context of each test before the script is executed,
+    /// for example to register the Rust functions that the scripts use.
+    #[inline]
+    pub fn setup(mut self, setup: impl for<'lua> Fn(&mut Lua<'lua>) + 'static) -> TestRunner {
+        self.setup = Some(Box::new(setup));
+        self
+    }
+
+    /// Only runs the tests whose full name, `script::test_name`, contains `filter`.
+    #[inline]
+    pub fn filter(mut self, filter: &str) -> TestRunner {
+        self.filter = Some(filter.to_owned());
+        self
+    }
+
+    /// Runs the tests of all the scripts, in the order in which the scripts were added and in
+    /// alphabetical order within a script.
+    pub fn run(&self) -> TestReport {
+        let start = Instant::now();
+        let mut report = TestReport::default();
+
+        for (script, source) in &self.scripts {
+            let names = match self.discover(script, source) {
+                Ok(names) => names,
+                Err(error) => {
+                    report.load_errors.push((script.clone(), error));
+                    continue;
+                },
+            };
+
+            for name in names {
+                let full_name = format!("{}::{}", script, name);
+                if self.filter.as_ref().is_some_and(|filter| !full_name.contains(&filter[..])) {
+                    report.filtered_out += 1;
+                    continue;
+                }
+
+                let test_start = Instant::now();
+                let outcome = self.run_test(script, source, &name);
+                report.results.push(TestResult {
+                    script: script.clone(),
+                    name,
+                    outcome,
+                    duration: test_start.elapsed(),
+                });
+            }
+        }
+
+        report.duration = start.elapsed();
+        report
+    }
+
+    // Creates the context of a test and executes the script in it.
+    fn load(&self, script: &str, source: &str) -> Result<Lua<'static>, LuaError> {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        if let Some(bundle) = &self.bundle {
+            lua.register_bundle(bundle.clone())?;
+        }
+        if let Some(setup) = &self.setup {
+            setup(&mut lua);
+        }
+        lua.execute_named::<()>(&format!("@{}", script), source)?;
+        Ok(lua)
+    }
+
+    fn discover(&self, script: &str, source: &str) -> Result<Vec<String>, LuaError> {
+        let mut lua = self.load(script, source)?;
+        let names: String = lua.execute_named("=discover", DISCOVER)?;
+        Ok(names.lines().map(str::to_owned).collect())
+    }
+
+    fn run_test(&self, script: &str, source: &str, name: &str) -> TestOutcome {
+        let mut lua = match self.load(script, source) {
+            Ok(lua) => lua,
+            Err(error) => return TestOutcome::Failed { error, traceback: None },
+        };
+
+        let mut traceback = None;
+        let result = lua.execute_protected::<(), _>(&format!("{}()", name), |err| {
+            traceback = Some(err.traceback());
+            RecoveryAction::Propagate
+        });
+
+        match result {
+            Ok(()) => TestOutcome::Passed,
+            Err(error) => TestOutcome::Failed { error, traceback },
+        }
+    }
+}
+
+impl Default for TestRunner {
+    #[inline]
+    fn default() -> TestRunner {
+        TestRunner::new()
+    }
+}
+
+impl fmt::Debug for TestRunner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TestRunner")
+            .field("scripts", &self.scripts.iter().map(|(name, _)| name).collect::<Vec<_>>())
+            .field("bundle", &self.bundle.is_some())
+            .field("filter", &self.filter)
+            .finish()
+    }
+}
+
+/// Results of `TestRunner::run`.
+#[derive(Debug, Default)]
+pub struct TestReport {
+    /// Results of the tests that ran, in the order in which they ran.
+    pub results: Vec<TestResult>,
+    /// Scripts whose tests couldn't be found because executing them failed, with the error.
+    pub load_errors: Vec<(String, LuaError)>,
+    /// Number of tests skipped because of `TestRunner::filter`.
+    pub filtered_out: usize,
+    /// Time taken by the whole run.
+    pub duration: Duration,
+}
+
+/// Result of one Lua test.
+#[derive(Debug)]
+pub struct TestResult {
+    /// Name of the script that defines the test.
+    pub script: String,
+    /// Name of the test function.
+    pub name: String,
+    /// Whether the test passed.
+    pub outcome: TestOutcome,
+    /// Time taken by the test, including the creation of its context.
+    pub duration: Duration,
+}
+
+/// Outcome of a Lua test.
+#[derive(Debug)]
+pub enum TestOutcome {
+    /// The test function returned.
+    Passed,
+    /// The test function raised an error.
+    Failed {
+        /// The error.
+        error: LuaError,
+        /// Traceback of the stack when the error was raised. `None` if the script itself failed.
+        traceback: Option<String>,
+    },
+}
+
+impl TestResult {
+    /// Returns true if the test passed.
+    #[inline]
+    pub fn passed(&self) -> bool {
+        matches!(self.outcome, TestOutcome::Passed)
+    }
+}
+
+impl TestReport {
+    /// Returns the number of tests that passed.
+    #[inline]
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|result| result.passed()).count()
+    }
+
+    /// Returns the tests that failed.
+    pub fn failures(&self) -> Vec<&TestResult> {
+        self.results.iter().filter(|result| !result.passed()).collect()
+    }
+
+    /// Returns true if all the scripts could be executed and all their tests passed.
+    #[inline]
+    pub fn is_success(&self) -> bool {
+        self.load_errors.is_empty() && self.results.iter().all(TestResult::passed)
+    }
+
+    /// Panics with the report if a test failed, which makes a Rust test fail with it.
+    #[track_caller]
+    pub fn assert_success(&self) {
+        if !self.is_success() {
+            panic!("Lua tests failed:\n{}", self);
+        }
+    }
+}
+
+impl fmt::Display for TestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let failures = self.failures();
+        let failed = failures.len() + self.load_errors.len();
+
+        writeln!(f, "running {} tests", self.results.len() + self.load_errors.len())?;
+        for (script, _) in &self.load_errors {
+            writeln!(f, "test {} ... FAILED", script)?;
+        }
+        for result in &self.results {
+            let status = if result.passed() { "ok" } else { "FAILED" };
+            writeln!(f, "test {}::{} ... {}", result.script, result.name, status)?;
+        }
+
+        if failed > 0 {
+            writeln!(f, "\nfailures:\n")?;
+            for (script, error) in &self.load_errors {
+                writeln!(f, "---- {} ----\nerror loading script: {}\n", script, error)?;
+            }
+            for result in &failures {
+                if let TestOutcome::Failed { error, traceback } = &result.outcome {
+                    writeln!(f, "---- {}::{} ----\n{}", result.script, result.name, error)?;
+                    if let Some(traceback) = traceback {
+                        writeln!(f, "{}", traceback)?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+
+            writeln!(f, "failures:")?;
+            for (script, _) in &self.load_errors {
+                writeln!(f, "    {}", script)?;
+            }
+            for result in &failures {
+                writeln!(f, "    {}::{}", result.script, result.name)?;
+            }
+        }
+
+        writeln!(
+            f,
+            "\ntest result: {}. {} passed; {} failed; 0 ignored; 0 measured; {} filtered out; \
+             finished in {:.2}s",
+            if failed == 0 { "ok" } else { "FAILED" },
+            self.passed(),
+            failed,
+            self.filtered_out,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ScriptBundle, TestOutcome, TestRunner};
+
+    #[test]
+    fn isolation_and_failures() {
+        let report = TestRunner::new()
+            .script(
+                "counter.lua",
+                r#"
+                count = 0
+                local function check(expected) assert(count == expected, "count is " .. count) end
+                function test_b() count = count + 1; check(1) end
+                function test_a() count = count + 2; check(1) end
+                function helper() error("not a test") end
+                "#,
+            )
+            .script("broken.lua", "this is not lua")
+            .run();
+
+        assert_eq!(report.results.len(), 2);
+        assert_eq!(report.results[0].name, "test_a");
+        assert_eq!(report.passed(), 1);
+        match &report.results[0].outcome {
+            TestOutcome::Failed { error, traceback } => {
+                assert!(error.to_string().contains("count is 2"), "{}", error);
+                assert!(traceback.as_ref().unwrap().contains("counter.lua:3"));
+            },
+            TestOutcome::Passed => panic!("test_a passed"),
+        }
+
+        assert_eq!(report.load_errors[0].0, "broken.lua");
+        assert!(!report.is_success());
+        let output = report.to_string();
+        assert!(output.contains("test counter.lua::test_b ... ok"), "{}", output);
+        assert!(output.contains("test result: FAILED. 1 passed; 2 failed;"), "{}", output);
+    }
+
+    #[test]
+    fn bundles_and_filters() {
+        let bundle = ScriptBundle::new()
+            .file("util/strings.lua", "return { shout = function(s) return s:upper() end }")
+            .file(
+                "util/strings_test.lua",
+                "local s = require('util.strings')\n\
+                 function test_shout() assert(s.shout('hi') == 'HI') end\n\
+                 function test_other() end",
+            )
+            .file("main.lua", "function test_not_run() error('main.lua is not a test file') end");
+
+        let report = TestRunner::new().bundle(bundle.clone()).run();
+        assert_eq!(report.results.len(), 2);
+        report.assert_success();
+
+        let report = TestRunner::new().bundle(bundle).filter("shout").run();
+        assert_eq!(report.results.len(), 1);
+        assert_eq!(report.filtered_out, 1);
+        assert!(report
+            .to_string()
+            .contains("1 passed; 0 failed; 0 ignored; 0 measured; 1 filtered"));
+    }
+}
@@ -0,0 +1,304 @@
+use std::{collections::HashMap, ffi::CStr};
+
+use crate::userdata::{push_userdata, userdata_mut};
+use crate::{Lua, LuaContext, Push};
+
+// Key of the registry entry holding the `SourceTransforms` of a context.
+const TRANSFORMS_KEY: &CStr = c"hlua.source_transforms";
+
+type Transform = Box<dyn FnMut(&str, &str) -> TransformedSource + Send>;
+
+/// Output of a source transform registered with `Lua::add_source_transform`.
+///
+/// Contains the transformed code and, optionally, the line of the original source that each line
+/// of the transformed code comes from. When present, the line numbers in error messages and
+/// tracebacks are translated back to the original source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransformedSource {
+    code: String,
+    lines: Option<Vec<u32>>,
+}
+
+impl TransformedSource {
+    /// Transformed code whose lines correspond to the lines of the original source.
+    #[inline]
+    pub fn new<S>(code: S) -> TransformedSource
+    where
+        S: Into<String>,
+    {
+        TransformedSource { code: code.into(), lines: None }
+    }
+
+    /// Transformed code with a line map. `lines[n]` is the line of the original source, starting
+    /// at 1, that line `n + 1` of the transformed code has been generated from.
+    #[inline]
+    pub fn with_line_map<S>(code: S, lines: Vec<u32>) -> TransformedSource
+    where
+        S: Into<String>,
+    {
+        TransformedSource { code: code.into(), lines: Some(lines) }
+    }
+}
+
+impl From<String> for TransformedSource {
+    #[inline]
+    fn from(code: String) -> TransformedSource {
+        TransformedSource::new(code)
+    }
+}
+
+impl From<&str> for TransformedSource {
+    #[inline]
+    fn from(code: &str) -> TransformedSource {
+        TransformedSource::new(code)
+    }
+}
+
+// Stored in the registry by `add_source_transform`.
+struct SourceTransforms {
+    transforms: Vec<Transform>,
+    // Line maps of the loaded chunks, indexed by the name Lua uses for them in messages.
+    line_maps: HashMap<String, Vec<u32>>,
+}
+
+impl<'lua> Lua<'lua> {
+    /// Registers a function that transforms the source of every chunk before it is loaded.
+    ///
+    /// The function receives the name of the chunk, as passed to `execute_named` or
+    /// `LuaFunction::load_named`, and its source, and returns the code to load instead. This can be
+    /// used to plug in a compiler for a language that targets Lua, or a simple preprocessor.
+    /// Transforms are applied in the order they have been registered. Precompiled chunks are
+    /// passed through unchanged.
+    ///
+    /// If a transform returns a `TransformedSource` with a line map, error messages raised by the
+    /// chunk refer to the lines of the original source.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.add_source_transform(|_name, source| source.replace("fn ", "function "));
+    ///
+    /// lua.execute::<()>("fn double(a) return a * 2 end").unwrap();
+    /// let value: i32 = lua.execute("return double(4)").unwrap();
+    /// assert_eq!(value, 8);
+    /// ```
+    pub fn add_source_transform<F, T>(&mut self, mut transform: F)
+    where
+        F: FnMut(&str, &str) -> T + Send + 'static,
+        T: Into<TransformedSource>,
+    {
+        let transform: Transform = Box::new(move |name, source| transform(name, source).into());
+
+        unsafe {
+            if with_transforms(self.lua, |_| ()).is_none() {
+                let transforms =
+                    SourceTransforms { transforms: Vec::new(), line_maps: HashMap::new() };
+                push_userdata(transforms, self.lua, |_| {}).forget();
+                ffi::lua_setfield(
+                    self.lua.as_ptr(),
+                    ffi::LUA_REGISTRYINDEX,
+                    TRANSFORMS_KEY.as_ptr(),
+                );
+            }
+            with_transforms(self.lua, |transforms| transforms.transforms.push(transform));
+        }
+    }
+
+    /// Removes all the source transforms registered with `add_source_transform`.
+    pub fn clear_source_transforms(&mut self) {
+        unsafe {
+            ffi::lua_pushnil(self.lua.as_ptr());
+            ffi::lua_setfield(self.lua.as_ptr(), ffi::LUA_REGISTRYINDEX, TRANSFORMS_KEY.as_ptr());
+        }
+    }
+}
+
+// Calls `f` with the transforms of the context, if any.
+unsafe fn with_transforms<R>(
+    lua: LuaContext,
+    f: impl FnOnce(&mut SourceTransforms) -> R,
+) -> Option<R> {
+    let raw_lua = lua.as_ptr();
+    ffi::lua_getfield(raw_lua, ffi::LUA_REGISTRYINDEX, TRANSFORMS_KEY.as_ptr());
+    let result = userdata_mut::<SourceTransforms>(lua, -1).map(f);
+    ffi::lua_pop(raw_lua, 1);
+    result
+}
+
+/// Returns true if source transforms have been registered in the context.
+#[inline]
+pub(crate) unsafe fn has_transforms(lua: LuaContext) -> bool {
+    with_transforms(lua, |t| !t.transforms.is_empty()).unwrap_or(false)
+}
+
+/// Applies the source transforms of the context to the source of a chunk.
+pub(crate) unsafe fn apply(lua: LuaContext, chunkname: &CStr, source: Vec<u8>) -> Vec<u8> {
+    // Precompiled chunks start with the escape character.
+    if source.first() == Some(&0x1b) {
+        return source;
+    }
+    let mut code = match String::from_utf8(source) {
+        Ok(code) => code,
+        Err(err) => return err.into_bytes(),
+    };
+
+    let name = chunkname.to_string_lossy();
+    with_transforms(lua, |transforms| {
+        let mut lines: Option<Vec<u32>> = None;
+        for transform in &mut transforms.transforms {
+            let output = transform(&name, &code);
+            code = output.code;
+            lines = match (lines, output.lines) {
+                (previous, None) => previous,
+                (None, Some(new)) => Some(new),
+                (Some(previous), Some(new)) => Some(
+                    new.into_iter()
+                        .map(|line| {
+                            let index = (line as usize).checked_sub(1);
+                            index.and_then(|i| previous.get(i)).copied().unwrap_or(line)
+                        })
+                        .collect(),
+                ),
+            };
+        }
+
+        let id = chunk_id(chunkname.to_bytes());
+        match lines {
+            Some(lines) => transforms.line_maps.insert(id, lines),
+            None => transforms.line_maps.remove(&id),
+        };
+    });
+
+    code.into_bytes()
+}
+
+/// Translates the line numbers of transformed chunks in `message` back to the original sources.
+pub(crate) unsafe fn remap_lines(lua: LuaContext, message: String) -> String {
+    with_transforms(lua, |transforms| {
+        transforms
+            .line_maps
+            .iter()
+            .fold(message.clone(), |message, (id, lines)| remap_chunk_lines(&message, id, lines))
+    })
+    .unwrap_or(message)
+}
+
+/// Translates the line numbers of the error message at the top of the stack, if it is a string.
+pub(crate) unsafe fn remap_error(lua: LuaContext) {
+    if ffi::lua_type(lua.as_ptr(), -1) != ffi::LUA_TSTRING {
+        return;
+    }
+
+    let mut len = 0;
+    let ptr = ffi::lua_tolstring(lua.as_ptr(), -1, &mut len);
+    let message = String::from_utf8_lossy(std::slice::from_raw_parts(ptr.cast(), len));
+    let remapped = remap_lines(lua, message.clone().into_owned());
+    if remapped != message {
+        ffi::lua_pop(lua.as_ptr(), 1);
+        remapped.push_no_err(lua).forget();
+    }
+}
+
+// Replaces the line numbers in the `id:line:` locations of `message`.
+fn remap_chunk_lines(message: &str, id: &str, lines: &[u32]) -> String {
+    let prefix = format!("{}:", id);
+    let mut output = String::with_capacity(message.len());
+    let mut rest = message;
+
+    while let Some(pos) = rest.find(&prefix) {
+        let (before, after) = rest.split_at(pos + prefix.len());
+        output.push_str(before);
+
+        let digits = after.bytes().take_while(u8::is_ascii_digit).count();
+        let line = after[..digits].parse::<usize>().ok();
+        match line.and_then(|line| lines.get(line.checked_sub(1)?)) {
+            Some(original) if after[digits..].starts_with(':') => {
+                output.push_str(&original.to_string())
+            },
+            _ => output.push_str(&after[..digits]),
+        }
+        rest = &after[digits..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+// Returns the name Lua uses for a chunk in messages, like `luaO_chunkid` does.
+fn chunk_id(source: &[u8]) -> String {
+    const IDSIZE: usize = ffi::LUA_IDSIZE as usize;
+
+    let id = match source.first() {
+        Some(b'=') => source[1..source.len().min(IDSIZE)].to_vec(),
+        Some(b'@') if source.len() <= IDSIZE => source[1..].to_vec(),
+        Some(b'@') => [&b"..."[..], &source[source.len() - (IDSIZE - 4)..]].concat(),
+        _ => {
+            let max = IDSIZE - 15;
+            let newline = source.iter().position(|&c| c == b'\n');
+            if source.len() < max && newline.is_none() {
+                [&b"[string \""[..], source, b"\"]"].concat()
+            } else {
+                let len = newline.unwrap_or(source.len()).min(max);
+                [&b"[string \""[..], &source[..len], b"...\"]"].concat()
+            }
+        },
+    };
+
+    String::from_utf8_lossy(&id).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, LuaError, TransformedSource};
+
+    // Removes the lines that start with `--!`, like a preprocessor for annotations would.
+    fn strip_annotations(source: &str) -> TransformedSource {
+        let (code, lines): (Vec<_>, Vec<_>) =
+            source.lines().zip(1..).filter(|(line, _)| !line.starts_with("--!")).unzip();
+        TransformedSource::with_line_map(code.join("\n"), lines)
+    }
+
+    #[test]
+    fn runtime_errors_use_original_lines() {
+        let mut lua = Lua::new();
+        lua.add_source_transform(|_, source| strip_annotations(source));
+
+        let code = "--! pure\n--! returns number\nlocal a = 1\nreturn a + nil";
+        match lua.execute_named::<()>("=script", code) {
+            Err(LuaError::ExecutionError(msg)) => {
+                assert!(msg.message().starts_with("script:4:"), "{}", msg)
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn syntax_errors_and_chained_transforms() {
+        let mut lua = Lua::new();
+        lua.add_source_transform(|_, source| strip_annotations(source));
+        lua.add_source_transform(|name: &str, source: &str| {
+            assert_eq!(name, "@game.lua");
+            source.replace("let ", "local ")
+        });
+
+        match lua.execute_named::<()>("@game.lua", "--! a\nlet a = 1\n--! b\na = ") {
+            Err(LuaError::SyntaxError(msg)) => {
+                assert!(msg.message().starts_with("game.lua:4:"), "{}", msg)
+            },
+            other => panic!("{:?}", other),
+        }
+
+        lua.clear_source_transforms();
+        assert!(lua.execute::<()>("let a = 1").is_err());
+    }
+
+    #[test]
+    fn chunk_ids() {
+        assert_eq!(super::chunk_id(b"=update"), "update");
+        assert_eq!(super::chunk_id(b"@scripts/main.lua"), "scripts/main.lua");
+        assert_eq!(super::chunk_id(b"chunk"), "[string \"chunk\"]");
+        assert_eq!(super::chunk_id(b"a = 1\nb = 2"), "[string \"a = 1...\"]");
+        assert_eq!(super::chunk_id(&[b'@'; 80]).len(), 59);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{AsLua, AsMutLua, LuaRead, Push, PushGuard, PushOne};
+
+/// Wrappers that convert to and from Lua like the type they wrap.
+///
+/// Implementing this trait for a newtype, such as an identifier or a quantity, gives it the
+/// `Push`, `PushOne` and `LuaRead` implementations of its `Inner` type, so that it can be passed
+/// to Lua, read from Lua, and used in the parameters and return values of callbacks without
+/// writing them. The conversions only move the inner value, and usually compile to nothing.
+///
+/// A type that implements this trait can't implement `Push` or `LuaRead` itself.
+///
+/// # Example
+///
+/// ```
+/// use hlua::TransparentLua;
+///
+/// #[derive(Debug, Clone, Copy, PartialEq)]
+/// #[repr(transparent)]
+/// struct EntityId(u32);
+///
+/// impl TransparentLua for EntityId {
+///     type Inner = u32;
+///     fn into_inner(self) -> u32 { self.0 }
+///     fn from_inner(inner: u32) -> EntityId { EntityId(inner) }
+/// }
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("next_id", hlua::function1(|id: EntityId| EntityId(id.0 + 1)));
+/// lua.set("first", EntityId(7));
+///
+/// assert_eq!(lua.execute::<EntityId>("return next_id(first)").unwrap(), EntityId(8));
+/// ```
+pub trait TransparentLua: Sized {
+    /// The wrapped type, whose conversions are used.
+    type Inner;
+
+    /// Unwraps the value.
+    fn into_inner(self) -> Self::Inner;
+
+    /// Wraps a value.
+    fn from_inner(inner: Self::Inner) -> Self;
+}
+
+impl<'lua, L, T, E> Push<L> for T
+where
+    L: AsMutLua<'lua>,
+    T: TransparentLua,
+    T::Inner: Push<L, Err = E>,
+{
+    type Err = E;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (E, L)> {
+        self.into_inner().push_to_lua(lua)
+    }
+}
+
+impl<'lua, L, T, E> PushOne<L> for T
+where
+    L: AsMutLua<'lua>,
+    T: TransparentLua,
+    T::Inner: PushOne<L, Err = E>,
+{
+}
+
+impl<'lua, L, T> LuaRead<L> for T
+where
+    L: AsLua<'lua>,
+    T: TransparentLua,
+    T::Inner: LuaRead<L>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<T, L> {
+        T::Inner::lua_read_at_position(lua, index).map(T::from_inner)
+    }
+
+    #[inline]
+    fn lua_read_out_of_bounds(lua: L) -> Result<T, L> {
+        T::Inner::lua_read_out_of_bounds(lua).map(T::from_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{function2, Lua, TransparentLua};
+
+    #[derive(Debug, PartialEq)]
+    struct Name(String);
+
+    impl TransparentLua for Name {
+        type Inner = String;
+        fn into_inner(self) -> String {
+            self.0
+        }
+        fn from_inner(inner: String) -> Name {
+            Name(inner)
+        }
+    }
+
+    // Wrappers can wrap other wrappers.
+    #[derive(Debug, PartialEq)]
+    struct Title(Name);
+
+    impl TransparentLua for Title {
+        type Inner = Name;
+        fn into_inner(self) -> Name {
+            self.0
+        }
+        fn from_inner(inner: Name) -> Title {
+            Title(inner)
+        }
+    }
+
+    #[test]
+    fn wrappers_convert_like_inner() {
+        let mut lua = Lua::new();
+        lua.set(
+            "titled",
+            function2(|name: Name, suffix: Option<Title>| {
+                let suffix = suffix.map_or(String::new(), |t| (t.0).0);
+                Title(Name(format!("{}{}", name.0, suffix)))
+            }),
+        );
+        lua.set("name", Name("ada".to_owned()));
+
+        let title: Title = lua.execute("return titled(name, ' the first')").unwrap();
+        assert_eq!(title, Title(Name("ada the first".to_owned())));
+        assert_eq!(lua.execute::<Name>("return titled('bo')").unwrap(), Name("bo".to_owned()));
+        assert!(lua.execute::<Name>("return titled({})").is_err());
+        assert_eq!(lua.get::<Name, _>("name"), Some(Name("ada".to_owned())));
+    }
+}
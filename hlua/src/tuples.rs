@@ -20,6 +20,11 @@ macro_rules! tuple_impl {
             fn lua_read_at_position(lua: LU, index: i32) -> Result<($ty,), LU> {
                 LuaRead::lua_read_at_position(lua, index).map(|v| (v,))
             }
+
+            #[inline]
+            fn lua_read_out_of_bounds(lua: LU) -> Result<($ty,), LU> {
+                LuaRead::lua_read_out_of_bounds(lua).map(|v| (v,))
+            }
         }
     );
 
@@ -102,6 +107,23 @@ macro_rules! tuple_impl {
                 Ok(($first, $($other),+))
 
             }
+
+            #[inline]
+            fn lua_read_out_of_bounds(mut lua: LU) -> Result<($first, $($other),+), LU> {
+                let $first: $first = match LuaRead::lua_read_out_of_bounds(&mut lua) {
+                    Ok(v) => v,
+                    Err(_) => return Err(lua)
+                };
+
+                $(
+                    let $other: $other = match LuaRead::lua_read_out_of_bounds(&mut lua) {
+                        Ok(v) => v,
+                        Err(_) => return Err(lua)
+                    };
+                )+
+
+                Ok(($first, $($other),+))
+            }
         }
 
         tuple_impl!($($other),+);
@@ -118,9 +140,13 @@ pub enum TuplePushError<C, O> {
     Other(O),
 }
 
-impl From<TuplePushError<Void, Void>> for Void {
+impl<C, O> From<TuplePushError<C, O>> for Void
+where
+    C: Into<Void>,
+    O: Into<Void>,
+{
     #[inline]
-    fn from(_: TuplePushError<Void, Void>) -> Void {
+    fn from(_: TuplePushError<C, O>) -> Void {
         unreachable!()
     }
 }
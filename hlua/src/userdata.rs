@@ -1,18 +1,25 @@
 use std::{
     any::{Any, TypeId},
+    cell::RefCell,
+    ffi::c_void,
     marker::PhantomData,
     mem,
     ops::{Deref, DerefMut},
-    ptr::{addr_of, NonNull},
+    ptr::{self, addr_of, NonNull},
+    sync::{mpsc::Sender, Arc, Mutex},
 };
 
+use crate::functions_write::set_conversion_error;
+use crate::read_struct::short_name;
 use crate::{
-    AsLua, AsMutLua, InsideCallback, LuaContext, LuaRead, LuaTable, OpaqueLua, Push, PushGuard,
+    ffix, AsLua, AsMutLua, InsideCallback, Lua, LuaContext, LuaRead, LuaTable, OpaqueLua, Push,
+    PushGuard, PushOne, Void,
 };
 
 mod raw {
     use std::{
         any::TypeId,
+        cell::Cell,
         mem::{align_of, size_of},
         os::raw::c_void,
         ptr::{self, NonNull},
@@ -20,11 +27,14 @@ mod raw {
 
     pub struct Head {
         pub type_id: TypeId,
+        // Like the flag of a `RefCell`: the number of shared borrows, or -1 when borrowed
+        // mutably.
+        pub borrow: Cell<isize>,
     }
 
     impl Head {
         pub fn of<T: 'static>() -> Head {
-            Head { type_id: TypeId::of::<T>() }
+            Head { type_id: TypeId::of::<T>(), borrow: Cell::new(0) }
         }
     }
 
@@ -142,9 +152,90 @@ mod raw {
         pub unsafe fn data_mut_checked<'a, T: 'static>(ptr: *mut c_void) -> Option<&'a mut T> {
             (!ptr.is_null() && validate_type::<T>(ptr)).then(|| data_mut::<T>(ptr))
         }
+
+        /// Borrows the inner data, mutably if `exclusive` is true. Returns false if that
+        /// conflicts with the borrows already taken.
+        pub unsafe fn try_borrow(ptr: *mut c_void, exclusive: bool) -> bool {
+            let borrow = &head_ref(ptr).borrow;
+            match (borrow.get(), exclusive) {
+                (0, true) => borrow.set(-1),
+                (count, false) if count >= 0 => borrow.set(count + 1),
+                _ => return false,
+            }
+            true
+        }
+
+        /// Releases a borrow taken with `try_borrow`.
+        pub unsafe fn release(ptr: *mut c_void, exclusive: bool) {
+            let borrow = &head_ref(ptr).borrow;
+            borrow.set(if exclusive { 0 } else { borrow.get() - 1 });
+        }
+    }
+}
+
+thread_local! {
+    // Borrows of user data taken for the callbacks that are running, one list per callback.
+    static BORROW_FRAMES: RefCell<Vec<Vec<(*mut c_void, bool)>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Keeps the user data borrowed while reading the parameters of a callback borrowed until it is
+/// dropped, once the callback has returned.
+///
+/// A callback interrupted by a Lua error never drops its frame, so the frames above the one being
+/// dropped are released as well. The protected calls do the same with a `BorrowWatermark`.
+pub(crate) struct BorrowFrame {
+    _watermark: BorrowWatermark,
+}
+
+impl BorrowFrame {
+    #[inline]
+    pub(crate) fn enter() -> BorrowFrame {
+        let watermark = BorrowWatermark::enter();
+        BORROW_FRAMES.with(|frames| frames.borrow_mut().push(Vec::new()));
+        BorrowFrame { _watermark: watermark }
+    }
+}
+
+/// Releases, when dropped, the borrows of the callbacks that were entered after it was created
+/// and that didn't release them because a Lua error jumped over their frames.
+pub(crate) struct BorrowWatermark(usize, PhantomData<*mut ()>);
+
+impl BorrowWatermark {
+    #[inline]
+    pub(crate) fn enter() -> BorrowWatermark {
+        BorrowWatermark(BORROW_FRAMES.with(|frames| frames.borrow().len()), PhantomData)
+    }
+}
+
+impl Drop for BorrowWatermark {
+    fn drop(&mut self) {
+        let stale = BORROW_FRAMES.with(|frames| {
+            let mut frames = frames.borrow_mut();
+            let depth = self.0.min(frames.len());
+            frames.split_off(depth)
+        });
+        for (ptr, exclusive) in stale.into_iter().flatten() {
+            unsafe { raw::util::release(ptr, exclusive) };
+        }
     }
 }
 
+/// Borrows the user data at `index`, which must have been checked to be one of ours, until
+/// the callback being called returns. Outside of callbacks, only checks that it isn't borrowed.
+/// Returns false if it is already borrowed in a way that conflicts.
+pub(crate) unsafe fn borrow_for_callback(lua: LuaContext, index: i32, exclusive: bool) -> bool {
+    let ptr = ffi::lua_touserdata(lua.as_ptr(), index);
+    if !raw::util::try_borrow(ptr, exclusive) {
+        return false;
+    }
+
+    BORROW_FRAMES.with(|frames| match frames.borrow_mut().last_mut() {
+        Some(frame) => frame.push((ptr, exclusive)),
+        None => raw::util::release(ptr, exclusive),
+    });
+    true
+}
+
 // Called when an object inside Lua that requires Drop is being dropped.
 #[inline]
 extern "C" fn destructor_wrapper<T: 'static>(lua: *mut ffi::lua_State) -> libc::c_int {
@@ -154,6 +245,153 @@ extern "C" fn destructor_wrapper<T: 'static>(lua: *mut ffi::lua_State) -> libc::
     }
 }
 
+/// Destination of the userdata values of a type registered with `Lua::register_pooled_type`.
+///
+/// Implemented on `Arc<Mutex<Vec<T>>>` and on `Sender<T>`, but you can implement it on your own
+/// pool types as well.
+pub trait UserdataPool<T>: Send + 'static {
+    /// Called with the content of a userdata that has been collected by Lua.
+    fn recycle(&mut self, value: T);
+}
+
+impl<T> UserdataPool<T> for Arc<Mutex<Vec<T>>>
+where
+    T: Send + 'static,
+{
+    #[inline]
+    fn recycle(&mut self, value: T) {
+        if let Ok(mut pool) = self.lock() {
+            pool.push(value);
+        }
+    }
+}
+
+impl<T> UserdataPool<T> for Sender<T>
+where
+    T: Send + 'static,
+{
+    #[inline]
+    fn recycle(&mut self, value: T) {
+        let _ = self.send(value);
+    }
+}
+
+// Stored in the registry by `register_pooled_type`.
+//
+// When the Lua context is closed, the holder can be destroyed before the userdata that use it.
+// `Drop` resets the content to `None`, which makes the remaining userdata drop their values.
+struct PoolHolder<T>(Option<Box<dyn UserdataPool<T>>>);
+
+impl<T> Drop for PoolHolder<T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.take();
+    }
+}
+
+// Pushes the registry key of the pool of `T`.
+unsafe fn push_pool_key<T: 'static>(raw_lua: LuaContext) {
+    let typeid = TypeId::of::<T>();
+    let mut key = b"hlua.pool:".to_vec();
+    key.extend_from_slice(std::slice::from_raw_parts(
+        addr_of!(typeid).cast::<u8>(),
+        mem::size_of::<TypeId>(),
+    ));
+    ffi::lua_pushlstring(raw_lua.as_ptr(), key.as_ptr().cast(), key.len());
+}
+
+// Pushes the `__gc` function that sends values of type `T` to their pool. Pushes nothing and
+// returns false if `T` hasn't been registered with `register_pooled_type`.
+unsafe fn push_pooled_destructor<T: 'static>(raw_lua: LuaContext) -> bool {
+    push_pool_key::<T>(raw_lua);
+    ffi::lua_rawget(raw_lua.as_ptr(), ffi::LUA_REGISTRYINDEX);
+
+    if ffi::lua_isnil(raw_lua.as_ptr(), -1) {
+        ffi::lua_pop(raw_lua.as_ptr(), 1);
+        return false;
+    }
+
+    ffi::lua_pushcclosure(raw_lua.as_ptr(), Some(pooled_destructor_wrapper::<T>), 1);
+    true
+}
+
+// Called instead of `destructor_wrapper` when the type of the object has a pool.
+extern "C" fn pooled_destructor_wrapper<T: 'static>(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let value = ptr::read(raw::data_ptr::<T>(ffi::lua_touserdata(lua, 1)));
+        let raw_lua = LuaContext::new_unchecked(lua);
+
+        match userdata_mut::<PoolHolder<T>>(raw_lua, ffi::lua_upvalueindex(1)) {
+            Some(PoolHolder(Some(pool))) => pool.recycle(value),
+            _ => drop(value),
+        }
+
+        0
+    }
+}
+
+impl<'lua> Lua<'lua> {
+    /// Sends the userdata of type `T` to a pool when they are collected, instead of dropping
+    /// them.
+    ///
+    /// This is useful for high-frequency short-lived objects, as the values can be taken back
+    /// from the pool and reused instead of allocating new ones. Registering a pool for a type
+    /// that already has one replaces it.
+    ///
+    /// Only the userdata pushed after this call are guaranteed to be recycled. Values that are
+    /// still alive when the Lua context is closed are dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// struct Bullet { pos: (f32, f32) }
+    /// hlua::implement_lua_push!(Bullet, |_| {});
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// let pool = Arc::new(Mutex::new(Vec::new()));
+    /// lua.register_pooled_type::<Bullet, _>(pool.clone());
+    ///
+    /// lua.set("b", Bullet { pos: (1.0, 2.0) });
+    /// lua.set("b", hlua::LuaNil);
+    /// lua.collect_garbage();
+    ///
+    /// assert_eq!(pool.lock().unwrap().len(), 1);
+    /// ```
+    pub fn register_pooled_type<T, P>(&mut self, pool: P)
+    where
+        T: Send + Any + 'static,
+        P: UserdataPool<T>,
+    {
+        unsafe {
+            let raw_lua = self.lua;
+
+            push_pool_key::<T>(raw_lua);
+            push_userdata(PoolHolder::<T>(Some(Box::new(pool))), raw_lua, |_| {}).forget();
+            ffi::lua_rawset(raw_lua.as_ptr(), ffi::LUA_REGISTRYINDEX);
+
+            // If values of this type have already been pushed, update the existing metatable.
+            let typeid = TypeId::of::<T>();
+            ffi::lua_pushlstring(
+                raw_lua.as_ptr(),
+                addr_of!(typeid).cast(),
+                mem::size_of::<TypeId>(),
+            );
+            ffi::lua_rawget(raw_lua.as_ptr(), ffi::LUA_REGISTRYINDEX);
+
+            if ffi::lua_istable(raw_lua.as_ptr(), -1) {
+                "__gc".push_no_err(raw_lua).forget();
+                let pushed = push_pooled_destructor::<T>(raw_lua);
+                debug_assert!(pushed);
+                ffi::lua_rawset(raw_lua.as_ptr(), -3);
+            }
+
+            ffi::lua_pop(raw_lua.as_ptr(), 1);
+        }
+    }
+}
+
 /// Pushes an object as a user data.
 ///
 /// In Lua, a user data is anything that is not recognized by Lua. When the script attempts to
@@ -209,11 +447,15 @@ where
             ffi::lua_pushvalue(raw_lua.as_ptr(), -2);
             ffi::lua_rawset(raw_lua.as_ptr(), ffi::LUA_REGISTRYINDEX);
 
-            // Only assign "__gc" if T needs to be dropped.
-            if mem::needs_drop::<T>() {
-                "__gc".push_no_err(raw_lua).forget();
+            // Only assign "__gc" if T needs to be dropped or has a pool.
+            "__gc".push_no_err(raw_lua).forget();
+            if push_pooled_destructor::<T>(raw_lua) {
+                ffi::lua_rawset(raw_lua.as_ptr(), -3);
+            } else if mem::needs_drop::<T>() {
                 ffi::lua_pushcfunction(raw_lua.as_ptr(), Some(destructor_wrapper::<T>));
                 ffi::lua_rawset(raw_lua.as_ptr(), -3);
+            } else {
+                ffi::lua_pop(raw_lua.as_ptr(), 1);
             }
 
             // Calling the metatable closure.
@@ -248,26 +490,79 @@ where
     PushGuard { lua, size: 1, raw_lua }
 }
 
+/// Reads the userdata of type `T` at `index`, from the parameters of a callback.
 ///
+/// The user data stays borrowed mutably until the callback returns, so reading the same value
+/// twice, for example because Lua called `f(a, a)`, fails instead of giving two mutable
+/// references to it. This is what `implement_lua_read!` uses for `&mut T` parameters.
 #[inline]
 pub fn read_userdata<'t, 'c, T>(
     lua: &'c mut InsideCallback,
     index: i32,
 ) -> Result<&'t mut T, &'c mut InsideCallback>
+where
+    T: 'static + Any,
+{
+    read_borrowed(lua, index, true)
+}
+
+/// Reads the userdata of type `T` at `index` like `read_userdata`, but only borrows it in a
+/// shared way, so that it can be read several times. This is what `implement_lua_read!` uses for
+/// `&T` parameters.
+#[inline]
+pub fn read_userdata_ref<'t, T>(
+    lua: &mut InsideCallback,
+    index: i32,
+) -> Result<&'t T, &mut InsideCallback>
+where
+    T: 'static + Any,
+{
+    read_borrowed(lua, index, false).map(|data| &*data)
+}
+
+fn read_borrowed<'t, T>(
+    lua: &mut InsideCallback,
+    index: i32,
+    exclusive: bool,
+) -> Result<&'t mut T, &mut InsideCallback>
 where
     T: 'static + Any,
 {
     unsafe {
         let ptr = ffi::lua_touserdata(lua.as_lua().as_ptr(), index);
-        raw::util::data_mut_checked::<T>(ptr).ok_or(lua)
+        let data = match raw::util::data_mut_checked::<T>(ptr) {
+            Some(data) => data,
+            None => return Err(lua),
+        };
+        if !borrow_for_callback(lua.as_lua(), index, exclusive) {
+            set_conversion_error(format!("{} is already borrowed", short_name::<T>()));
+            return Err(lua);
+        }
+        Ok(data)
     }
 }
 
+/// Returns a reference to the userdata of type `T` at `index`, if there is one.
+///
+/// Used internally by the library to access Rust state it stored inside the Lua context.
+#[inline]
+pub(crate) unsafe fn userdata_mut<'a, T: 'static>(
+    lua: LuaContext,
+    index: i32,
+) -> Option<&'a mut T> {
+    raw::util::data_mut_checked::<T>(ffi::lua_touserdata(lua.as_ptr(), index))
+}
+
 /// Represents a user data located inside the Lua context.
+///
+/// The user data is borrowed mutably for as long as this is alive, so reading the same value
+/// again in the meantime fails, like with `read_userdata`.
 #[derive(Debug)]
 pub struct UserdataOnStack<T, L> {
     variable: L,
     index: i32,
+    // The block of the user data, to release its borrow.
+    data: NonNull<c_void>,
     marker: PhantomData<T>,
 }
 
@@ -281,7 +576,11 @@ where
         unsafe {
             match NonNull::new(ffi::lua_touserdata(lua.as_lua().as_ptr(), index)) {
                 Some(x) if raw::util::validate_type::<T>(x.as_ptr()) => {
-                    Ok(UserdataOnStack { variable: lua, index, marker: PhantomData })
+                    if !raw::util::try_borrow(x.as_ptr(), true) {
+                        set_conversion_error(format!("{} is already borrowed", short_name::<T>()));
+                        return Err(lua);
+                    }
+                    Ok(UserdataOnStack { variable: lua, index, data: x, marker: PhantomData })
                 },
                 _ => Err(lua),
             }
@@ -289,6 +588,13 @@ where
     }
 }
 
+impl<T, L> Drop for UserdataOnStack<T, L> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { raw::util::release(self.data.as_ptr(), true) };
+    }
+}
+
 unsafe impl<'lua, T, L> AsLua<'lua> for UserdataOnStack<T, L>
 where
     L: AsLua<'lua>,
@@ -311,6 +617,67 @@ where
     }
 }
 
+impl<'lua, T, L> UserdataOnStack<T, L>
+where
+    L: AsMutLua<'lua>,
+    T: 'lua + Any,
+{
+    /// Makes `value` the user value of the userdata, a Lua value that stays alive as long as the
+    /// userdata, or removes it if `value` is `LuaNil`.
+    ///
+    /// This can hold a table of fields that scripts attach to a Rust object. Returns false if
+    /// the version of Lua can't store the value: Lua 5.2 and LuaJIT only accept tables and nil.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{LuaRead, LuaRef, LuaTable, UserdataOnStack};
+    ///
+    /// struct Player;
+    ///
+    /// let mut lua = hlua::Lua::new();
+    /// let fields: LuaRef = lua.execute("return { name = 'ana' }").unwrap();
+    ///
+    /// let pushed = hlua::push_userdata(Player, &mut lua, |_| {});
+    /// let mut player: UserdataOnStack<Player, _> = LuaRead::lua_read(pushed).ok().unwrap();
+    /// assert!(player.set_user_value(&fields));
+    ///
+    /// let mut table: LuaTable<_> = player.get_user_value().unwrap();
+    /// assert_eq!(table.get::<String, _, _>("name").unwrap(), "ana");
+    /// ```
+    pub fn set_user_value<V>(&mut self, value: V) -> bool
+    where
+        V: for<'a> PushOne<&'a mut UserdataOnStack<T, L>, Err = Void>,
+    {
+        let index = self.absolute_index();
+        unsafe {
+            value.push_no_err(&mut *self).forget();
+            ffix::lua_setuservalue(self.as_lua(), index)
+        }
+    }
+
+    /// Reads the user value of the userdata, which is nil if it was never set. Returns `None` if
+    /// it can't be read as `V`.
+    pub fn get_user_value<'a, V>(&'a mut self) -> Option<V>
+    where
+        V: LuaRead<PushGuard<&'a mut UserdataOnStack<T, L>>>,
+    {
+        let index = self.absolute_index();
+        let raw_lua = self.as_lua();
+        unsafe { ffix::lua_getuservalue(raw_lua, index) };
+        LuaRead::lua_read(PushGuard { lua: self, size: 1, raw_lua }).ok()
+    }
+
+    // Index of the userdata that stays valid when values are pushed.
+    fn absolute_index(&self) -> i32 {
+        if self.index < 0 && self.index > ffi::LUA_REGISTRYINDEX {
+            unsafe { ffi::lua_gettop(self.as_lua().as_ptr()) + self.index + 1 }
+        } else {
+            self.index
+        }
+    }
+}
+
 impl<'lua, T, L> Deref for UserdataOnStack<T, L>
 where
     L: AsLua<'lua>,
@@ -0,0 +1,652 @@
+use std::{fmt, marker::PhantomData, ptr::NonNull, sync::Arc};
+
+use crate::functions_write::{push_closure, take_conversion_error, CurrentCallback, RawFunction};
+use crate::panic_policy::catch_panic;
+use crate::read_struct::short_name;
+use crate::scope::ScopedRef;
+use crate::userdata::{borrow_for_callback, userdata_mut, BorrowFrame};
+use crate::{
+    ffix, flight_recorder, push_userdata, AsMutLua, InsideCallback, LuaContext, LuaRead, LuaTable,
+    OpaqueLua, Push, PushGuard, PushOne, Void,
+};
+
+/// Rust type whose values can be pushed as user data with methods callable from Lua.
+///
+/// The methods are registered once per type, the first time a value of the type is pushed, by
+/// `add_user_data_methods`. Use `implement_lua_push!(Type)` to make the type pushable, and
+/// `implement_lua_read!(Type)` if callbacks must be able to take it as a parameter.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, MetaMethod, MethodsBuilder, UserData};
+///
+/// struct Stack(Vec<i32>);
+///
+/// impl UserData for Stack {
+///     fn add_methods(methods: &mut MethodsBuilder<Self>) {
+///         methods.add_method("len", |this, ()| this.0.len() as i32);
+///         methods.add_method_mut("push", |this, value: i32| this.0.push(value));
+///         methods.add_meta_method(MetaMethod::ToString, |this, ()| format!("Stack{:?}", this.0));
+///     }
+/// }
+///
+/// hlua::implement_lua_push!(Stack);
+///
+/// let mut lua = Lua::new();
+/// lua.openlibs();
+/// lua.set("stack", Stack(vec![]));
+///
+/// let len: i32 = lua.execute("stack:push(4) stack:push(2) return stack:len()").unwrap();
+/// assert_eq!(len, 2);
+/// assert_eq!(lua.execute::<String>("return tostring(stack)").unwrap(), "Stack[4, 2]");
+/// ```
+pub trait UserData: Send + Sized + 'static {
+    /// Registers the methods of the type.
+    fn add_methods(methods: &mut MethodsBuilder<Self>);
+}
+
+/// Collects the methods of a `UserData` type into its metatable.
+///
+/// Methods are called from Lua with `value:method(args)`. Their first parameter is the value,
+/// and the second one the arguments, read like the parameters of a `Function`: a single value,
+/// a tuple for several ones, or `()` for none. Like the return value of a `Function`, the value
+/// they return is pushed back to Lua.
+#[derive(Debug)]
+pub struct MethodsBuilder<T> {
+    lua: LuaContext,
+    // Absolute stack indices of the metatable and of its `__index` table.
+    metatable: i32,
+    methods: i32,
+    marker: PhantomData<fn(T)>,
+}
+
+impl<T: UserData> MethodsBuilder<T> {
+    /// Adds a method that takes the value by reference.
+    #[inline]
+    pub fn add_method<F, A, R>(&mut self, name: &str, method: F)
+    where
+        F: Fn(&T, A) -> R + 'static,
+        A: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+        R: for<'p> Push<&'p mut InsideCallback> + 'static,
+    {
+        let method = move |this: *mut T, args| method(unsafe { &*this }, args);
+        unsafe { self.set(self.methods, name, false, method) }
+    }
+
+    /// Adds a method that takes the value by mutable reference.
+    ///
+    /// The value stays borrowed mutably while the method runs, so if Lua code called by the
+    /// method calls another method on it or passes it to a callback, that call raises an error.
+    #[inline]
+    pub fn add_method_mut<F, A, R>(&mut self, name: &str, mut method: F)
+    where
+        F: FnMut(&mut T, A) -> R + 'static,
+        A: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+        R: for<'p> Push<&'p mut InsideCallback> + 'static,
+    {
+        let method = move |this: *mut T, args| method(unsafe { &mut *this }, args);
+        unsafe { self.set(self.methods, name, true, method) }
+    }
+
+    /// Adds a metamethod that takes the value, the first parameter of the metamethod, by
+    /// reference.
+    ///
+    /// This fits the metamethods that Lua only calls on the value, such as `__call`, `__len`,
+    /// `__unm` or `__tostring`. The binary operators are also called when the value is their
+    /// right operand, so unless the other operand always has the same type, register them with
+    /// `add_meta_function`.
+    #[inline]
+    pub fn add_meta_method<F, A, R>(&mut self, meta: MetaMethod, method: F)
+    where
+        F: Fn(&T, A) -> R + 'static,
+        A: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+        R: for<'p> Push<&'p mut InsideCallback> + 'static,
+    {
+        let method = move |this: *mut T, args| method(unsafe { &*this }, args);
+        unsafe { self.set(self.metatable, meta.name(), false, method) }
+    }
+
+    /// Adds a metamethod that reads all its parameters as `A`.
+    ///
+    /// This is meant for the binary operators, which get the operands in the order in which they
+    /// appear in the expression, whichever of them is the value. For example the `__add` of a
+    /// `Vec2` can take a `(&Vec2, &Vec2)`, and adding anything else to a `Vec2` then raises an
+    /// error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, MetaMethod, MethodsBuilder, UserData};
+    ///
+    /// #[derive(Clone, Copy, PartialEq, PartialOrd)]
+    /// struct Money(f64);
+    ///
+    /// impl UserData for Money {
+    ///     fn add_methods(methods: &mut MethodsBuilder<Self>) {
+    ///         methods.add_meta_function(MetaMethod::Add, |(a, b): (&Money, &Money)| {
+    ///             Money(a.0 + b.0)
+    ///         });
+    ///         methods.add_meta_function(MetaMethod::Lt, |(a, b): (&Money, &Money)| a < b);
+    ///         methods.add_meta_method(MetaMethod::ToString, |this, ()| format!("${:.2}", this.0));
+    ///     }
+    /// }
+    ///
+    /// hlua::implement_lua_push!(Money);
+    /// hlua::implement_lua_read!(Money);
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.openlibs();
+    /// lua.set("price", Money(2.5));
+    /// lua.set("tax", Money(0.25));
+    ///
+    /// let total: String = lua.execute("return tostring(price + tax)").unwrap();
+    /// assert_eq!(total, "$2.75");
+    /// assert!(lua.execute::<bool>("return tax < price").unwrap());
+    /// // The operands are checked like the parameters of any callback.
+    /// assert!(lua.execute::<()>("return price + 1").is_err());
+    /// ```
+    #[inline]
+    pub fn add_meta_function<F, A, R>(&mut self, meta: MetaMethod, function: F)
+    where
+        F: Fn(A) -> R + 'static,
+        A: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+        R: for<'p> Push<&'p mut InsideCallback> + 'static,
+    {
+        unsafe {
+            let l = self.lua.as_ptr();
+            let name = meta.name();
+            ffi::lua_pushlstring(l, name.as_ptr().cast(), name.len());
+            let function = Method::<F, A, R> {
+                name: name.to_owned(),
+                mutable: false,
+                function,
+                marker: PhantomData,
+            };
+            let wrapper: RawFunction = function_wrapper::<F, A, R>;
+            push_closure(self.lua, function, wrapper);
+            ffi::lua_rawset(l, self.metatable);
+        }
+    }
+
+    /// Makes `tostring` and `print` show the value with its `Display` implementation, instead of
+    /// `userdata: 0x...`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::fmt;
+    /// use hlua::{Lua, MethodsBuilder, UserData};
+    ///
+    /// struct Player { name: String }
+    ///
+    /// impl fmt::Display for Player {
+    ///     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    ///         write!(f, "player {}", self.name)
+    ///     }
+    /// }
+    ///
+    /// impl UserData for Player {
+    ///     fn add_methods(methods: &mut MethodsBuilder<Self>) {
+    ///         methods.add_display_tostring();
+    ///     }
+    /// }
+    ///
+    /// hlua::implement_lua_push!(Player);
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.openlibs();
+    /// lua.set("player", Player { name: "Ann".to_owned() });
+    /// let message: String = lua.execute("return 'hello ' .. tostring(player)").unwrap();
+    /// assert_eq!(message, "hello player Ann");
+    /// ```
+    #[inline]
+    pub fn add_display_tostring(&mut self)
+    where
+        T: fmt::Display,
+    {
+        self.add_meta_method(MetaMethod::ToString, |this, ()| this.to_string());
+    }
+
+    /// Makes `tostring` and `print` show the value with its `Debug` implementation, instead of
+    /// `userdata: 0x...`.
+    #[inline]
+    pub fn add_debug_tostring(&mut self)
+    where
+        T: fmt::Debug,
+    {
+        self.add_meta_method(MetaMethod::ToString, |this, ()| format!("{:?}", this));
+    }
+
+    unsafe fn set<F, A, R>(&mut self, table: i32, name: &str, mutable: bool, method: F)
+    where
+        F: FnMut(*mut T, A) -> R + 'static,
+        A: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+        R: for<'p> Push<&'p mut InsideCallback> + 'static,
+    {
+        let l = self.lua.as_ptr();
+        ffi::lua_pushlstring(l, name.as_ptr().cast(), name.len());
+        let method = Method::<F, A, R> {
+            name: name.to_owned(),
+            mutable,
+            function: method,
+            marker: PhantomData,
+        };
+        let wrapper: RawFunction = method_wrapper::<T, F, A, R>;
+        push_closure(self.lua, method, wrapper);
+        ffi::lua_rawset(l, table);
+    }
+}
+
+/// Metamethod that can be added with `MethodsBuilder::add_meta_method` or
+/// `MethodsBuilder::add_meta_function`.
+///
+/// `__index` isn't one of them, because it holds the methods, and neither is `__gc`, which drops
+/// the value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MetaMethod {
+    /// `__add`, the `+` operator.
+    Add,
+    /// `__sub`, the `-` operator.
+    Sub,
+    /// `__mul`, the `*` operator.
+    Mul,
+    /// `__div`, the `/` operator.
+    Div,
+    /// `__mod`, the `%` operator.
+    Mod,
+    /// `__pow`, the `^` operator.
+    Pow,
+    /// `__unm`, the unary `-` operator.
+    Unm,
+    /// `__idiv`, the `//` operator. Only called by Lua 5.3 and later.
+    IDiv,
+    /// `__concat`, the `..` operator.
+    Concat,
+    /// `__len`, the `#` operator.
+    Len,
+    /// `__eq`, the `==` and `~=` operators. Lua only calls it when both operands are tables or
+    /// both are user data, and converts the result to a boolean.
+    Eq,
+    /// `__lt`, the `<` and `>` operators.
+    Lt,
+    /// `__le`, the `<=` and `>=` operators.
+    Le,
+    /// `__call`, called when the value is called like a function.
+    Call,
+    /// `__tostring`, used by `tostring` and `print`.
+    ToString,
+}
+
+impl MetaMethod {
+    /// Returns the name of the metamethod, such as `"__add"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            MetaMethod::Add => "__add",
+            MetaMethod::Sub => "__sub",
+            MetaMethod::Mul => "__mul",
+            MetaMethod::Div => "__div",
+            MetaMethod::Mod => "__mod",
+            MetaMethod::Pow => "__pow",
+            MetaMethod::Unm => "__unm",
+            MetaMethod::IDiv => "__idiv",
+            MetaMethod::Concat => "__concat",
+            MetaMethod::Len => "__len",
+            MetaMethod::Eq => "__eq",
+            MetaMethod::Lt => "__lt",
+            MetaMethod::Le => "__le",
+            MetaMethod::Call => "__call",
+            MetaMethod::ToString => "__tostring",
+        }
+    }
+}
+
+/// Pushes the `Arc` as a user data with the methods of `T`, which shares the value with the other
+/// clones of the `Arc`.
+///
+/// The methods that take the value by mutable reference raise an error when called on it, since
+/// the `Arc` only gives a shared reference. Use a `Mutex` or atomics inside of `T` to change it.
+/// `Rc` can't be pushed in the same way, because the values of a Lua context must be `Send`.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::{atomic::{AtomicI32, Ordering}, Arc};
+/// use hlua::{Lua, MethodsBuilder, UserData};
+///
+/// struct Score(AtomicI32);
+///
+/// impl UserData for Score {
+///     fn add_methods(methods: &mut MethodsBuilder<Self>) {
+///         methods.add_method("add", |this, n: i32| this.0.fetch_add(n, Ordering::Relaxed) + n);
+///     }
+/// }
+///
+/// let score = Arc::new(Score(AtomicI32::new(0)));
+/// let mut lua = Lua::new();
+/// lua.set("a", score.clone());
+/// lua.set("b", score.clone());
+/// lua.execute::<()>("a:add(2) b:add(3)").unwrap();
+/// assert_eq!(score.0.load(Ordering::Relaxed), 5);
+///
+/// let back: Arc<Score> = lua.get("a").unwrap();
+/// assert!(Arc::ptr_eq(&back, &score));
+/// ```
+impl<'lua, L, T> Push<L> for Arc<T>
+where
+    L: AsMutLua<'lua>,
+    T: UserData + Sync,
+{
+    type Err = Void; // TODO: use `!` instead (https://github.com/rust-lang/rust/issues/35121)
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        Ok(push_userdata(self, lua, add_user_data_methods::<T>))
+    }
+}
+
+impl<'lua, L, T> PushOne<L> for Arc<T>
+where
+    L: AsMutLua<'lua>,
+    T: UserData + Sync,
+{
+}
+
+/// Reads a user data pushed as an `Arc<T>`, by cloning the `Arc`.
+impl<'lua, L, T> LuaRead<L> for Arc<T>
+where
+    L: AsMutLua<'lua>,
+    T: UserData + Sync,
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<Arc<T>, L> {
+        match unsafe { userdata_mut::<Arc<T>>(lua.as_mut_lua(), index) } {
+            Some(shared) => Ok(shared.clone()),
+            None => Err(lua),
+        }
+    }
+}
+
+/// Fills the metatable of a `UserData` type with its methods.
+///
+/// This is the function that `implement_lua_push!(Type)` passes to `push_userdata`. It can also
+/// be called from a metatable function of your own, to add other entries to the metatable.
+pub fn add_user_data_methods<T: UserData>(mut metatable: LuaTable<OpaqueLua<'_>>) {
+    let lua = metatable.as_mut_lua();
+    let l = lua.as_ptr();
+    unsafe {
+        let top = ffi::lua_gettop(l);
+        ffi::lua_newtable(l);
+        let mut builder =
+            MethodsBuilder { lua, metatable: top, methods: top + 1, marker: PhantomData::<fn(T)> };
+        T::add_methods(&mut builder);
+
+        // The metatable has no metatable of its own, so this can't call a metamethod.
+        ffi::lua_setfield(l, top, c"__index".as_ptr());
+    }
+}
+
+// A method, with its name for the error messages.
+struct Method<F, A, R> {
+    name: String,
+    // True if the method takes the value by mutable reference.
+    mutable: bool,
+    function: F,
+    marker: PhantomData<fn(A) -> R>,
+}
+
+// Called when Lua calls a method.
+extern "C" fn method_wrapper<T, F, A, R>(lua: *mut ffi::lua_State) -> libc::c_int
+where
+    T: 'static,
+    F: FnMut(*mut T, A) -> R,
+    A: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+    R: for<'p> Push<&'p mut InsideCallback>,
+{
+    let method = unsafe { ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) };
+    let method = unsafe { &mut *method.cast::<Method<F, A, R>>() };
+    let lua = unsafe { NonNull::new_unchecked(lua) };
+
+    let frame = BorrowFrame::enter();
+    let this = match unsafe { this::<T>(lua, &method.name, method.mutable) } {
+        Ok(this) => this,
+        Err(message) => {
+            drop(frame);
+            raise(lua, message)
+        },
+    };
+    let function = &mut method.function;
+    call(lua, &method.name, 2, frame, |args| function(this, args))
+}
+
+// Returns a pointer to the value that the method `name` is called on, which is the first
+// parameter, or the error message to raise.
+unsafe fn this<T: 'static>(lua: LuaContext, name: &str, mutable: bool) -> Result<*mut T, String> {
+    let type_name = short_name::<T>();
+    if let Some(this) = userdata_mut::<T>(lua, 1) {
+        if !borrow_for_callback(lua, 1, mutable) {
+            return Err(format!(
+                "method '{}' of {} called on a value that is already borrowed",
+                name, type_name
+            ));
+        }
+        return Ok(this);
+    }
+
+    // A reference pushed by `Scope::userdata`.
+    if let Some(this) = userdata_mut::<ScopedRef<T>>(lua, 1) {
+        return match this.get() {
+            this if this.is_null() => {
+                Err(format!("method '{}' of {} called after the end of its scope", name, type_name))
+            },
+            this => Ok(this),
+        };
+    }
+
+    match userdata_mut::<Arc<T>>(lua, 1) {
+        Some(_) if mutable => Err(format!(
+            "method '{}' of {} needs a mutable value, but it is shared by an Arc",
+            name, type_name
+        )),
+        Some(this) => Ok(Arc::as_ptr(this).cast_mut()),
+        None => Err(format!("bad self for method '{}' of {}, call it with ':'", name, type_name)),
+    }
+}
+
+// Called when Lua calls a metamethod added with `add_meta_function`.
+extern "C" fn function_wrapper<F, A, R>(lua: *mut ffi::lua_State) -> libc::c_int
+where
+    F: FnMut(A) -> R,
+    A: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+    R: for<'p> Push<&'p mut InsideCallback>,
+{
+    let function = unsafe { ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) };
+    let function = unsafe { &mut *function.cast::<Method<F, A, R>>() };
+    let lua = unsafe { NonNull::new_unchecked(lua) };
+    call(lua, &function.name, 1, BorrowFrame::enter(), &mut function.function)
+}
+
+// Reads the arguments starting at `first`, calls `f` and pushes its return value. The borrows
+// of `frame` are released once `f` has returned.
+fn call<A, R>(
+    lua: LuaContext,
+    name: &str,
+    first: i32,
+    frame: BorrowFrame,
+    f: impl FnOnce(A) -> R,
+) -> libc::c_int
+where
+    A: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
+    R: for<'p> Push<&'p mut InsideCallback>,
+{
+    let mut tmp_lua = InsideCallback { lua };
+    take_conversion_error();
+    let read = match unsafe { ffi::lua_gettop(lua.as_ptr()) } {
+        top if top < first => A::lua_read_out_of_bounds(&mut tmp_lua),
+        _ => A::lua_read_at_position(&mut tmp_lua, first),
+    };
+    let args = match read {
+        Ok(args) => args,
+        Err(_) => {
+            let message = match take_conversion_error() {
+                Some(reason) => format!("wrong parameter types for method '{}': {}", name, reason),
+                None => format!("wrong parameter types for method '{}'", name),
+            };
+            drop(frame);
+            raise(lua, message)
+        },
+    };
+
+    unsafe { flight_recorder::record_callback(lua) };
+
+    let ret_value = catch_panic(lua, || {
+        let _current = CurrentCallback::enter(lua);
+        f(args)
+    });
+    drop(frame);
+    let ret_value = match ret_value {
+        Ok(ret_value) => ret_value,
+        Err(message) => raise(lua, message),
+    };
+
+    let nb = match ret_value.push_to_lua(&mut tmp_lua) {
+        Ok(pushed) => pushed.forget_internal() as libc::c_int,
+        Err(_) => panic!("failed to push the return value of method '{}'", name),
+    };
+    nb
+}
+
+#[cold]
+#[inline(never)]
+pub(crate) fn raise(lua: LuaContext, message: String) -> ! {
+    // Pushing the message consumes it, so nothing is left to drop when `lua_error` jumps out.
+    message.push_no_err(lua).forget_internal();
+    unsafe { ffix::lua_error(lua.as_ptr()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, MetaMethod, MethodsBuilder, UserData};
+
+    struct Counter {
+        count: i32,
+    }
+
+    impl UserData for Counter {
+        fn add_methods(methods: &mut MethodsBuilder<Self>) {
+            methods.add_method("get", |this, ()| this.count);
+            methods.add_method_mut("add", |this, (a, b): (i32, Option<i32>)| {
+                this.count += a + b.unwrap_or(0);
+                this.count
+            });
+            methods.add_meta_method(MetaMethod::Len, |this, ()| this.count);
+            methods.add_meta_method(MetaMethod::Call, |this, n: i32| this.count * n);
+            methods.add_meta_function(MetaMethod::Eq, |(a, b): (&Counter, &Counter)| {
+                a.count == b.count
+            });
+            methods.add_meta_function(MetaMethod::Le, |(a, b): (&Counter, &Counter)| {
+                a.count <= b.count
+            });
+            methods.add_meta_function(MetaMethod::Concat, |(a, b): (String, &Counter)| {
+                format!("{}{}", a, b.count)
+            });
+        }
+    }
+
+    crate::implement_lua_push!(Counter);
+    crate::implement_lua_read!(Counter);
+
+    #[test]
+    fn methods_and_metamethods() {
+        let mut lua = Lua::new();
+        lua.set("counter", Counter { count: 1 });
+        lua.set("other", Counter { count: 10 });
+
+        assert_eq!(lua.execute::<i32>("return counter:add(2, 3)").unwrap(), 6);
+        assert_eq!(lua.execute::<i32>("return counter:add(1)").unwrap(), 7);
+        assert_eq!(lua.execute::<i32>("return #counter + other:get()").unwrap(), 17);
+        assert_eq!(lua.execute::<i32>("return #other").unwrap(), 10);
+    }
+
+    #[test]
+    fn operators() {
+        let mut lua = Lua::new();
+        lua.set("a", Counter { count: 3 });
+        lua.set("b", Counter { count: 3 });
+        lua.set("c", Counter { count: 4 });
+
+        assert_eq!(lua.execute::<i32>("return a(5)").unwrap(), 15);
+        assert!(lua.execute::<bool>("return a == b and a ~= c").unwrap());
+        assert!(lua.execute::<bool>("return a <= b and c >= a and not (c <= a)").unwrap());
+        assert_eq!(lua.execute::<String>("return 'count: ' .. c").unwrap(), "count: 4");
+        // The operands are in the order of the expression.
+        let err = lua.execute::<String>("return c .. 'x'").unwrap_err().to_string();
+        assert!(err.contains("wrong parameter types for method '__concat'"), "{}", err);
+    }
+
+    #[test]
+    fn tostring_from_traits() {
+        #[derive(Debug)]
+        #[allow(dead_code)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        impl UserData for Point {
+            fn add_methods(methods: &mut MethodsBuilder<Self>) {
+                methods.add_debug_tostring();
+            }
+        }
+
+        crate::implement_lua_push!(Point);
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set("point", Point { x: 1, y: -2 });
+        lua.set("counter", Counter { count: 0 });
+        assert_eq!(
+            lua.execute::<String>("return tostring(point)").unwrap(),
+            "Point { x: 1, y: -2 }"
+        );
+        // Types without `__tostring` keep the default of Lua.
+        assert!(lua.execute::<String>("return tostring(counter)").unwrap().starts_with("userdata"));
+    }
+
+    #[test]
+    fn bad_calls_raise_errors() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set("counter", Counter { count: 0 });
+        lua.set("read", crate::function1(|counter: &Counter| counter.count));
+
+        let err = lua.execute::<()>("counter.add(1)").unwrap_err().to_string();
+        assert!(err.contains("bad self for method 'add' of Counter"), "{}", err);
+        let err = lua.execute::<()>("counter:add('x')").unwrap_err().to_string();
+        assert!(err.contains("wrong parameter types for method 'add'"), "{}", err);
+
+        lua.execute::<()>("counter:add(5)").unwrap();
+        assert_eq!(lua.execute::<i32>("return read(counter)").unwrap(), 5);
+    }
+
+    #[test]
+    fn shared_with_arc() {
+        use std::sync::Arc;
+
+        let counter = Arc::new(Counter { count: 4 });
+        let mut lua = Lua::new();
+        lua.set("a", counter.clone());
+        lua.set("b", counter.clone());
+        lua.set("count", crate::function1(|counter: Arc<Counter>| counter.count));
+
+        assert_eq!(lua.execute::<i32>("return a:get() + #b + count(a)").unwrap(), 12);
+        let err = lua.execute::<()>("b:add(1)").unwrap_err().to_string();
+        assert!(err.contains("method 'add' of Counter needs a mutable value"), "{}", err);
+
+        let back: Arc<Counter> = lua.get("b").unwrap();
+        assert!(Arc::ptr_eq(&back, &counter));
+        assert_eq!(Arc::strong_count(&counter), 4);
+        lua.set("c", Counter { count: 0 });
+        assert!(lua.get::<Arc<Counter>, _>("c").is_none());
+    }
+}
@@ -1,6 +1,17 @@
-use std::{borrow::Cow, marker::PhantomData, mem, ops::Deref, slice, str};
-
-use crate::{AnyLuaString, AsLua, AsMutLua, LuaRead, Push, PushGuard, PushOne, Void};
+use std::{
+    borrow::Cow,
+    marker::PhantomData,
+    mem,
+    num::{NonZeroI16, NonZeroI32, NonZeroI8, NonZeroU16, NonZeroU32, NonZeroU8},
+    ops::Deref,
+    slice, str,
+};
+
+use crate::functions_write::set_conversion_error;
+use crate::locale::NumericLocaleGuard;
+use crate::{
+    strings, AnyLuaString, AsLua, AsMutLua, LuaContext, LuaRead, Push, PushGuard, PushOne, Void,
+};
 
 macro_rules! integer_impl(
     ($t:ident) => (
@@ -115,6 +126,47 @@ unsigned_impl!(u32);
 numeric_impl!(f32);
 numeric_impl!(f64);
 
+// Zero can't be read as a `NonZero` type, and other values are read like the primitive type.
+macro_rules! nonzero_impl(
+    ($t:ident, $p:ident) => (
+        impl<'lua, L> Push<L> for $t where L: AsMutLua<'lua> {
+            type Err = Void;
+
+            #[inline]
+            fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+                self.get().push_to_lua(lua)
+            }
+        }
+
+        impl<'lua, L> PushOne<L> for $t where L: AsMutLua<'lua> {
+        }
+
+        impl<'lua, L> LuaRead<L> for $t where L: AsLua<'lua> {
+            #[inline]
+            fn lua_read_at_position(lua: L, index: i32) -> Result<$t, L> {
+                let value = match $p::lua_read_at_position(&lua, index) {
+                    Ok(value) => value,
+                    Err(_) => return Err(lua),
+                };
+                match $t::new(value) {
+                    Some(value) => Ok(value),
+                    None => {
+                        set_conversion_error("expected a non-zero number, found 0".to_owned());
+                        Err(lua)
+                    },
+                }
+            }
+        }
+    );
+);
+
+nonzero_impl!(NonZeroI8, i8);
+nonzero_impl!(NonZeroI16, i16);
+nonzero_impl!(NonZeroI32, i32);
+nonzero_impl!(NonZeroU8, u8);
+nonzero_impl!(NonZeroU16, u16);
+nonzero_impl!(NonZeroU32, u32);
+
 #[derive(Copy, Clone)]
 pub struct LuaNil;
 
@@ -165,21 +217,50 @@ where
 {
     #[inline]
     fn lua_read_at_position(lua: L, index: i32) -> Result<String, L> {
-        let mut size = mem::MaybeUninit::uninit();
-        let c_str = unsafe { ffi::lua_tolstring(lua.as_lua().as_ptr(), index, size.as_mut_ptr()) };
-        if c_str.is_null() {
-            return Err(lua);
-        }
+        let bytes = match unsafe { string_bytes(lua.as_lua(), index) } {
+            Some(bytes) => bytes,
+            None => return Err(lua),
+        };
 
-        let size = unsafe { size.assume_init() };
-        let c_slice = unsafe { slice::from_raw_parts(c_str.cast(), size) };
-        match std::str::from_utf8(c_slice) {
+        match str::from_utf8(bytes) {
             Ok(x) => Ok(x.to_string()),
-            Err(_) => Err(lua),
+            Err(_) => unsafe { strings::decode_invalid_utf8(lua.as_lua(), bytes) }.ok_or(lua),
         }
     }
 }
 
+/// Returns the bytes of the string at `index`, converting numbers to strings like Lua does.
+///
+/// The slice is valid as long as the value stays on the stack.
+#[inline]
+pub(crate) unsafe fn string_bytes<'a>(lua: LuaContext, index: i32) -> Option<&'a [u8]> {
+    let mut size = mem::MaybeUninit::uninit();
+    let c_str = match ffi::lua_type(lua.as_ptr(), index) {
+        ffi::LUA_TNUMBER => {
+            let _locale = NumericLocaleGuard::enter(lua);
+            ffi::lua_tolstring(lua.as_ptr(), index, size.as_mut_ptr())
+        },
+        _ => ffi::lua_tolstring(lua.as_ptr(), index, size.as_mut_ptr()),
+    };
+    if c_str.is_null() {
+        return None;
+    }
+
+    Some(slice::from_raw_parts(c_str.cast(), size.assume_init()))
+}
+
+/// Reads the string at `index` if it is valid UTF-8, ignoring the `Utf8Policy` of the context.
+#[inline]
+pub(crate) fn read_utf8_string<'lua, L>(lua: L, index: i32) -> Result<String, L>
+where
+    L: AsLua<'lua>,
+{
+    match unsafe { string_bytes(lua.as_lua(), index) }.map(str::from_utf8) {
+        Some(Ok(x)) => Ok(x.to_string()),
+        _ => Err(lua),
+    }
+}
+
 impl<'lua, L> Push<L> for AnyLuaString
 where
     L: AsMutLua<'lua>,
@@ -348,6 +429,11 @@ where
     fn lua_read_at_position(_: L, _: i32) -> Result<(), L> {
         Ok(())
     }
+
+    #[inline]
+    fn lua_read_out_of_bounds(_: L) -> Result<(), L> {
+        Ok(())
+    }
 }
 
 impl<'lua, L, T, E> Push<L> for Option<T>
@@ -393,6 +479,54 @@ where
     }
 }
 
+/// Callback parameter that is set to the `Default` value of `T` when the argument is nil or
+/// missing, instead of failing like `T` would.
+///
+/// This is meant for the trailing parameters of a callback, which Lua code can leave out of the
+/// call. A value of another type still fails to be read. For defaults that aren't the `Default`
+/// value, use an `Option` and `unwrap_or`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::OrDefault;
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("offset", hlua::function2(|x: i32, OrDefault(dx): OrDefault<i32>| x + dx));
+///
+/// assert_eq!(lua.execute::<i32>("return offset(5, 2)").unwrap(), 7);
+/// assert_eq!(lua.execute::<i32>("return offset(5)").unwrap(), 5);
+/// assert_eq!(lua.execute::<i32>("return offset(5, nil)").unwrap(), 5);
+/// assert!(lua.execute::<i32>("return offset(5, 'two')").is_err());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OrDefault<T>(pub T);
+
+impl<T> OrDefault<T> {
+    /// Returns the value.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<'lua, T, L> LuaRead<L> for OrDefault<T>
+where
+    T: LuaRead<L> + Default,
+    L: AsLua<'lua>,
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<OrDefault<T>, L> {
+        Option::<T>::lua_read_at_position(lua, index)
+            .map(|value| OrDefault(value.unwrap_or_default()))
+    }
+
+    #[inline]
+    fn lua_read_out_of_bounds(_: L) -> Result<Self, L> {
+        Ok(OrDefault(T::default()))
+    }
+}
+
 impl<'lua, 'str, L> Push<L> for Cow<'str, str>
 where
     L: AsMutLua<'lua>,
@@ -414,7 +548,26 @@ impl<'lua, 'str, L> PushOne<L> for Cow<'str, str> where L: AsMutLua<'lua> {}
 mod tests {
     use std::borrow::Cow;
 
-    use crate::{AnyLuaString, AnyLuaValue, Lua, StringInLua};
+    use crate::{AnyLuaString, AnyLuaValue, Lua, OrDefault, StringInLua};
+
+    #[test]
+    fn default_trailing_parameters() {
+        let mut lua = Lua::new();
+        lua.set(
+            "join",
+            crate::function3(
+                |a: String, OrDefault(b): OrDefault<String>, OrDefault(n): OrDefault<u32>| {
+                    format!("{}{}{}", a, b, n)
+                },
+            ),
+        );
+
+        assert_eq!(lua.execute::<String>("return join('a', 'b', 3)").unwrap(), "ab3");
+        assert_eq!(lua.execute::<String>("return join('a')").unwrap(), "a0");
+        assert_eq!(lua.execute::<String>("return join('a', nil, 2)").unwrap(), "a2");
+        assert!(lua.execute::<String>("return join('a', {})").is_err());
+        assert!(lua.execute::<String>("return join()").is_err());
+    }
 
     #[test]
     fn read_i32s() {
@@ -447,6 +600,22 @@ mod tests {
         assert_eq!(d, 2);
     }
 
+    #[test]
+    fn non_zero_numbers() {
+        use std::num::{NonZeroI8, NonZeroU32};
+
+        let mut lua = Lua::new();
+        lua.set("a", NonZeroU32::new(7).unwrap());
+        lua.set("zero", 0);
+        lua.set("half", crate::function1(|n: NonZeroI8| 100 / n.get()));
+
+        assert_eq!(lua.get::<NonZeroI8, _>("a"), NonZeroI8::new(7));
+        assert_eq!(lua.get::<NonZeroU32, _>("zero"), None);
+        assert_eq!(lua.execute::<i32>("return half(-2)").unwrap(), -50);
+        let err = lua.execute::<i32>("return half(zero)").unwrap_err().to_string();
+        assert!(err.contains("expected a non-zero number, found 0"), "{}", err);
+    }
+
     #[test]
     fn validate_extreme_numbers() {
         let mut lua = Lua::new();
@@ -0,0 +1,161 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::{AnyLuaValue, AsMutLua, LuaRead, Push, PushGuard, Void};
+
+/// Any number of Lua values, read from the arguments of a Rust callback or returned by one.
+///
+/// As the last parameter of a callback, it receives all the arguments that follow the other
+/// parameters, like `...` in a Lua function, possibly none. Returned by a callback, it gives all
+/// its values to Lua as separate results, so the number of results can be decided at runtime,
+/// which tuples can't do. A `Vec<AnyLuaValue>` would be returned as a single table instead.
+///
+/// It can be collected from an iterator of values that convert to `AnyLuaValue`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{AnyLuaValue, Varargs};
+///
+/// let mut lua = hlua::Lua::new();
+/// lua.set("count", hlua::function2(|name: String, rest: Varargs| format!("{}: {}", name, rest.len())));
+/// lua.set("swap", hlua::function1(|args: Varargs| args.into_iter().rev().collect::<Varargs>()));
+///
+/// assert_eq!(lua.execute::<String>("return count('args', 1, nil, 'x')").unwrap(), "args: 3");
+/// assert_eq!(lua.execute::<String>("return count('none')").unwrap(), "none: 0");
+/// assert_eq!(lua.execute::<String>("local a, b = swap('a', 'b'); return a .. b").unwrap(), "ba");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Varargs(pub Vec<AnyLuaValue>);
+
+impl Deref for Varargs {
+    type Target = Vec<AnyLuaValue>;
+
+    #[inline]
+    fn deref(&self) -> &Vec<AnyLuaValue> {
+        &self.0
+    }
+}
+
+impl DerefMut for Varargs {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Vec<AnyLuaValue> {
+        &mut self.0
+    }
+}
+
+impl From<Vec<AnyLuaValue>> for Varargs {
+    #[inline]
+    fn from(values: Vec<AnyLuaValue>) -> Varargs {
+        Varargs(values)
+    }
+}
+
+impl<T> FromIterator<T> for Varargs
+where
+    T: Into<AnyLuaValue>,
+{
+    #[inline]
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Varargs {
+        Varargs(iter.into_iter().map(Into::into).collect())
+    }
+}
+
+impl IntoIterator for Varargs {
+    type Item = AnyLuaValue;
+    type IntoIter = std::vec::IntoIter<AnyLuaValue>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'lua, L> LuaRead<L> for Varargs
+where
+    L: AsMutLua<'lua>,
+{
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<Varargs, L> {
+        let top = unsafe { ffi::lua_gettop(lua.as_mut_lua().as_ptr()) };
+        // Counting from the top, index 0 is past the last value.
+        let first = match index {
+            0 => return Ok(Varargs::default()),
+            index if index < 0 => top + index + 1,
+            index => index,
+        };
+
+        let mut values = Vec::with_capacity((top - first + 1).max(0) as usize);
+        for position in first..=top {
+            match LuaRead::lua_read_at_position(&mut lua, position) {
+                Ok(value) => values.push(value),
+                Err(_) => return Err(lua),
+            }
+        }
+        Ok(Varargs(values))
+    }
+
+    #[inline]
+    fn lua_read_out_of_bounds(_: L) -> Result<Varargs, L> {
+        Ok(Varargs::default())
+    }
+}
+
+impl<'lua, L> Push<L> for Varargs
+where
+    L: AsMutLua<'lua>,
+{
+    type Err = Void;
+
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        let mut size = 0;
+        for value in self.0 {
+            size += value.push_no_err(&mut lua).forget_internal();
+        }
+        let raw_lua = lua.as_lua();
+        Ok(PushGuard { lua, size, raw_lua })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{function0, function1, function2, AnyLuaValue, Lua, Varargs};
+
+    #[test]
+    fn trailing_arguments() {
+        let mut lua = Lua::new();
+        lua.set(
+            "describe",
+            function2(|first: i32, rest: Varargs| {
+                let types: Vec<_> = rest.iter().map(AnyLuaValue::type_name).collect();
+                format!("{} {}", first, types.join(","))
+            }),
+        );
+        lua.set("nothing", function0(Varargs::default));
+
+        let result: String = lua.execute("return describe(1, 'a', nil, {}, true)").unwrap();
+        assert_eq!(result, "1 string,nil,table,boolean");
+        assert_eq!(lua.execute::<String>("return describe(5)").unwrap(), "5 ");
+        let count: i32 = lua.execute("return #{ nothing() }").unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn runtime_result_count() {
+        let mut lua = Lua::new();
+        lua.set("range", function1(|n: u32| (1..=n).collect::<Varargs>()));
+        lua.set(
+            "checked",
+            function1(|n: i32| match n {
+                n if n >= 0 => Ok((0..n).map(|_| "x").collect::<Varargs>()),
+                _ => Err("negative count"),
+            }),
+        );
+
+        assert_eq!(lua.execute::<i32>("return #{ range(4) }").unwrap(), 4);
+        assert_eq!(lua.execute::<i32>("return #{ range(0) }").unwrap(), 0);
+        let third: i32 = lua.execute("local _, _, c = range(5); return c").unwrap();
+        assert_eq!(third, 3);
+        assert_eq!(lua.execute::<i32>("return #{ checked(2) }").unwrap(), 2);
+        let error: String = lua.execute("local _, err = checked(-1); return err").unwrap();
+        assert_eq!(error, "negative count");
+    }
+}
@@ -0,0 +1,209 @@
+#![cfg(feature = "derive")]
+
+use hlua::{Lua, LuaPush, LuaRead, LuaReadMulti, NamedArgs, PathErrorKind};
+
+#[test]
+fn read_multi_named() {
+    #[derive(Debug, PartialEq, LuaReadMulti)]
+    struct Outcome {
+        code: i32,
+        message: String,
+        retry: bool,
+    }
+
+    let mut lua = Lua::new();
+    let outcome: Outcome = lua.execute_multi("return 404, 'not found', false").unwrap();
+    assert_eq!(outcome, Outcome { code: 404, message: "not found".to_owned(), retry: false });
+}
+
+#[test]
+fn read_multi_tuple_struct_optional() {
+    #[derive(Debug, PartialEq, LuaReadMulti)]
+    struct Pair(i32, Option<i32>);
+
+    let mut lua = Lua::new();
+    assert_eq!(lua.execute_multi::<Pair>("return 1, 2").unwrap(), Pair(1, Some(2)));
+    assert_eq!(lua.execute_multi::<Pair>("return 1").unwrap(), Pair(1, None));
+    assert!(lua.execute_multi::<Pair>("return").is_err());
+    assert!(lua.execute_multi::<Pair>("return 'a', 2").is_err());
+}
+
+#[test]
+fn read_multi_from_function() {
+    #[derive(LuaReadMulti)]
+    struct MinMax {
+        min: i32,
+        max: i32,
+    }
+
+    let mut lua = Lua::new();
+    lua.execute::<()>("function minmax(a, b) if a < b then return a, b end return b, a end")
+        .unwrap();
+
+    let mut minmax: hlua::LuaFunction<_> = lua.get("minmax").unwrap();
+    let result: MinMax = minmax.call_multi_with_args((9, 3)).unwrap();
+    assert_eq!((result.min, result.max), (3, 9));
+}
+
+#[test]
+fn push_named_fields() {
+    #[derive(LuaPush)]
+    struct Player {
+        name: String,
+        #[lua(rename = "hp")]
+        health: u32,
+        #[lua(skip)]
+        #[allow(dead_code)]
+        session: Vec<u8>,
+        position: Position,
+        tags: Vec<&'static str>,
+    }
+
+    #[derive(LuaPush)]
+    struct Position(f64, f64);
+
+    let mut lua = Lua::new();
+    lua.openlibs();
+    let player = Player {
+        name: "ana".to_owned(),
+        health: 80,
+        session: vec![1, 2, 3],
+        position: Position(1.5, -2.0),
+        tags: vec!["admin", "beta"],
+    };
+    lua.set("player", player);
+
+    let code = "return player.name .. ' ' .. player.hp .. ' ' .. tostring(player.health)
+                .. ' ' .. tostring(player.session) .. ' ' .. player.position[1] + player.position[2]
+                .. ' ' .. #player.tags";
+    assert_eq!(lua.execute::<String>(code).unwrap(), "ana 80 nil nil -0.5 2");
+}
+
+#[test]
+fn push_generic_and_unit_structs() {
+    #[derive(LuaPush)]
+    struct Wrapper<T> {
+        value: T,
+        #[lua(skip)]
+        #[allow(dead_code)]
+        cache: Option<T>,
+    }
+
+    #[derive(LuaPush)]
+    struct Empty;
+
+    let mut lua = Lua::new();
+    lua.openlibs();
+    lua.set("wrapped", Wrapper { value: 7, cache: None });
+    lua.set("empty", Empty);
+    assert_eq!(lua.execute::<i32>("return wrapped.value").unwrap(), 7);
+    assert!(lua.execute::<bool>("return next(empty) == nil").unwrap());
+}
+
+#[test]
+fn read_round_trip() {
+    #[derive(Debug, PartialEq, LuaPush, LuaRead)]
+    struct Item {
+        name: String,
+        #[lua(rename = "qty")]
+        quantity: u32,
+        note: Option<String>,
+        #[lua(skip)]
+        cached: bool,
+        position: Position,
+    }
+
+    #[derive(Debug, PartialEq, LuaPush, LuaRead)]
+    struct Position(i32, i32);
+
+    let mut lua = Lua::new();
+    let item = Item {
+        name: "rope".to_owned(),
+        quantity: 3,
+        note: None,
+        cached: true,
+        position: Position(4, -2),
+    };
+    lua.set("item", item);
+    lua.execute::<()>("item.qty = item.qty + 1").unwrap();
+
+    let read: Item = lua.get("item").unwrap();
+    let expected = Item {
+        name: "rope".to_owned(),
+        quantity: 4,
+        note: None,
+        cached: false,
+        position: Position(4, -2),
+    };
+    assert_eq!(read, expected);
+}
+
+#[test]
+fn read_errors() {
+    #[derive(Debug, LuaRead)]
+    #[allow(dead_code)]
+    struct Config {
+        host: String,
+        port: u16,
+    }
+
+    let mut lua = Lua::new();
+    lua.execute::<()>("a = { host = 'localhost' }; b = { host = 'x', port = {} }; c = 5").unwrap();
+
+    let err = lua.read_struct::<Config>("a").unwrap_err();
+    assert_eq!(err.kind, PathErrorKind::Missing);
+    assert_eq!(err.to_string(), "cannot read Config: field `port` is missing");
+
+    let err = lua.read_struct::<Config>("b").unwrap_err();
+    assert_eq!(err.field, "port");
+    assert_eq!(
+        err.to_string(),
+        "cannot read Config: field `port` has the wrong type (expected u16, found table)"
+    );
+
+    let err = lua.read_struct::<Config>("c").unwrap_err();
+    assert_eq!(err.to_string(), "cannot read Config: expected a table, found number");
+    assert!(lua.get::<Config, _>("a").is_none());
+
+    let mut table: hlua::LuaTable<_> = lua.get("b").unwrap();
+    table.set("port", 8080);
+    assert_eq!(table.read_struct::<Config>().unwrap().port, 8080);
+}
+
+#[test]
+fn named_arguments_with_defaults() {
+    fn default_title() -> String {
+        "untitled".to_owned()
+    }
+
+    #[derive(LuaRead)]
+    struct Window {
+        #[lua(default)]
+        width: u32,
+        #[lua(default = "default_title")]
+        title: String,
+        resizable: bool,
+    }
+
+    let mut lua = Lua::new();
+    lua.set(
+        "window",
+        hlua::function1(|NamedArgs(w): NamedArgs<Window>| {
+            format!("{} {} {}", w.title, w.width, w.resizable)
+        }),
+    );
+
+    let desc: String = lua.execute("return window{ width = 800, resizable = true }").unwrap();
+    assert_eq!(desc, "untitled 800 true");
+    let desc: String = lua.execute("return window{ title = 'demo', resizable = false }").unwrap();
+    assert_eq!(desc, "demo 0 false");
+
+    let err = lua.execute::<String>("return window()").unwrap_err().to_string();
+    assert!(err.contains("field `resizable` is missing"), "{}", err);
+    let err = lua.execute::<String>("return window{ width = 'wide' }").unwrap_err().to_string();
+    assert!(
+        err.contains("field `width` has the wrong type (expected u32, found string)"),
+        "{}",
+        err
+    );
+}
@@ -0,0 +1,35 @@
+// The hook is global to the process, so these tests have their own binary and run one after
+// the other in the same test function.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use hlua::{Lua, LuaPool};
+
+#[test]
+fn global_init() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    hlua::set_global_init(move |lua| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        // Contexts created by the hook don't run it again.
+        drop(Lua::new());
+        lua.set("base", 10);
+    });
+
+    let mut lua = Lua::new();
+    assert_eq!(lua.execute::<i32>("return base + 1").unwrap(), 11);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    let pool = LuaPool::new(3, |lua| {
+        lua.execute::<()>("function add(x) return base + x end").unwrap();
+    });
+    let results: Vec<i32> = hlua::lua_par_map(&pool, vec![1, 2, 3], "add").unwrap();
+    assert_eq!(results, vec![11, 12, 13]);
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+    hlua::clear_global_init();
+    let mut lua = Lua::new();
+    assert_eq!(lua.get::<i32, _>("base"), None);
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+}
@@ -362,3 +362,137 @@ fn valid_zst_alignment() {
     validate_alignment!(lua, 8192);
     validate_alignment!(lua, 16384);
 }
+
+#[test]
+fn pooled_type() {
+    use std::sync::{Arc, Mutex};
+
+    struct Bullet {
+        id: u32,
+    }
+    implement_lua_push!(Bullet, |_| {});
+
+    let pool = Arc::new(Mutex::new(Vec::new()));
+
+    let mut lua = hlua::Lua::new();
+    lua.register_pooled_type::<Bullet, _>(pool.clone());
+
+    for id in 0..3 {
+        lua.set("a", Bullet { id });
+    }
+    lua.set("a", hlua::LuaNil);
+    lua.collect_garbage();
+
+    let mut ids: Vec<u32> = pool.lock().unwrap().iter().map(|b| b.id).collect();
+    ids.sort_unstable();
+    assert_eq!(ids, vec![0, 1, 2]);
+}
+
+#[test]
+fn pooled_type_registered_late() {
+    use std::sync::mpsc;
+
+    struct Foo;
+    implement_lua_push!(Foo, |_| {});
+
+    let mut lua = hlua::Lua::new();
+    lua.set("a", Foo);
+
+    // The metatable of `Foo` already exists at this point.
+    let (tx, rx) = mpsc::channel();
+    lua.register_pooled_type::<Foo, _>(tx);
+
+    lua.set("a", Foo);
+    lua.set("a", hlua::LuaNil);
+    lua.collect_garbage();
+    assert!(rx.try_recv().is_ok());
+
+    // Values still alive when the context is closed are dropped rather than recycled.
+    lua.set("a", Foo);
+    drop(lua);
+}
+
+#[test]
+fn aliasing_borrows() {
+    struct Cell(i32);
+    implement_lua_read!(Cell);
+    implement_lua_push!(Cell, |_| {});
+
+    let mut lua = hlua::Lua::new();
+    lua.set("a", Cell(1));
+    lua.set("b", Cell(2));
+    lua.set("swap", hlua::function2(|a: &mut Cell, b: &mut Cell| std::mem::swap(a, b)));
+    lua.set("sum", hlua::function2(|a: &Cell, b: &Cell| a.0 + b.0));
+
+    let err = lua.execute::<()>("swap(a, a)").unwrap_err().to_string();
+    assert!(err.contains("Cell is already borrowed"), "{}", err);
+    // The borrows of a call are released when it returns, even if it failed.
+    lua.execute::<()>("swap(a, b)").unwrap();
+    assert_eq!(lua.execute::<i32>("return sum(a, a) * 10 + sum(b, b)").unwrap(), 42);
+
+    lua.execute::<()>("x = a").unwrap();
+    let first: hlua::UserdataOnStack<Cell, _> = lua.get("a").unwrap();
+    assert_eq!(first.0, 2);
+    drop(first);
+    let again: Option<hlua::UserdataOnStack<Cell, _>> = lua.get("x");
+    assert!(again.is_some());
+}
+
+#[test]
+fn user_values() {
+    struct Entity;
+
+    let mut lua = hlua::Lua::new();
+    let fields: hlua::LuaRef = lua.execute("return { hp = 10 }").unwrap();
+
+    let pushed = hlua::push_userdata(Entity, &mut lua, |_| {});
+    let mut entity: hlua::UserdataOnStack<Entity, _> =
+        hlua::LuaRead::lua_read(pushed).ok().unwrap();
+    assert_eq!(entity.get_user_value::<Option<i32>>(), Some(None));
+    assert!(entity.set_user_value(&fields));
+    {
+        let mut table: hlua::LuaTable<_> = entity.get_user_value().unwrap();
+        assert_eq!(table.get::<i32, _, _>("hp"), Some(10));
+    }
+    assert!(entity.set_user_value(hlua::LuaNil));
+    assert!(entity.get_user_value::<hlua::LuaTable<_>>().is_none());
+}
+
+#[test]
+fn borrows_released_after_errors_reading_parameters() {
+    struct Cell(i32);
+    implement_lua_read!(Cell);
+    implement_lua_push!(Cell, |_| {});
+
+    // Raises a Lua error when read, like a conversion running out of memory would.
+    struct Raising;
+    impl<'lua, L> hlua::LuaRead<L> for Raising
+    where
+        L: hlua::AsLua<'lua>,
+    {
+        fn lua_read_at_position(lua: L, _: i32) -> Result<Raising, L> {
+            let raw = lua.as_lua().as_ptr();
+            unsafe {
+                hlua::ffi::lua_pushstring(raw, c"raised while reading".as_ptr());
+                hlua::ffi::lua_error(raw);
+            }
+            unreachable!()
+        }
+    }
+
+    let mut lua = hlua::Lua::new();
+    lua.openlibs();
+    lua.set("a", Cell(1));
+    lua.set("raising", hlua::function2(|_: &mut Cell, _: Raising| {}));
+    lua.set("set", hlua::function2(|a: &mut Cell, value: i32| a.0 = value));
+
+    let err = lua.execute::<()>("raising(a, 1)").unwrap_err().to_string();
+    assert!(err.contains("raised while reading"), "{}", err);
+    lua.execute::<()>("set(a, 2)").unwrap();
+
+    // Caught inside Lua, the borrows are released once the protected call from Rust returns.
+    assert!(!lua.execute::<bool>("return pcall(raising, a, 1)").unwrap());
+    lua.execute::<()>("set(a, 3)").unwrap();
+    let a: hlua::UserdataOnStack<Cell, _> = lua.get("a").unwrap();
+    assert_eq!(a.0, 3);
+}